@@ -0,0 +1,384 @@
+//! ZIP and TAR archives as input sources and as run deliverables.
+//!
+//! Archive members are addressed by virtual paths made of the archive path
+//! followed by the member name (`photos.zip/2024/beach.png`), so they flow
+//! through input expansion, the executor and the manifest like plain files
+//! and are only read out of the archive when their artifact is loaded.
+//!
+//! In the other direction, [`package`] bundles a run's outputs (plus an
+//! optional manifest and metadata sidecars) into a single archive.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow, bail};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use glob::Pattern;
+use zip::CompressionMethod;
+use zip::write::SimpleFileOptions;
+
+use crate::pipeline::PipelineResult;
+
+/// Metadata key holding the directory, relative to the output directory,
+/// that mirrors a member's location inside its archive.
+pub const OUTPUT_DIR_KEY: &str = "archive.output_dir";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveKind {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy().to_lowercase();
+        if name.ends_with(".zip") {
+            Some(Self::Zip)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Self::TarGz)
+        } else if name.ends_with(".tar") {
+            Some(Self::Tar)
+        } else {
+            None
+        }
+    }
+
+    /// File name without the archive extension(s).
+    fn stem(self, path: &Path) -> String {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let suffix = match self {
+            Self::Zip => 4,
+            Self::Tar => 4,
+            Self::TarGz if name.to_lowercase().ends_with(".tgz") => 4,
+            Self::TarGz => 7,
+        };
+        name[..name.len() - suffix].to_string()
+    }
+}
+
+/// A member resolved from a virtual input path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveMember {
+    pub archive: PathBuf,
+    pub kind: ArchiveKind,
+    /// Member name inside the archive, `/`-separated.
+    pub name: String,
+}
+
+impl ArchiveMember {
+    /// Splits `path` into an archive on disk and a member name, or returns
+    /// `None` for ordinary files.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        if path.is_file() {
+            return None;
+        }
+        let archive = path
+            .ancestors()
+            .skip(1)
+            .find(|ancestor| ArchiveKind::from_path(ancestor).is_some() && ancestor.is_file())?;
+        let kind = ArchiveKind::from_path(archive)?;
+        let name = member_name(path.strip_prefix(archive).ok()?)?;
+        Some(Self {
+            archive: archive.to_path_buf(),
+            kind,
+            name,
+        })
+    }
+
+    pub fn read(&self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        match self.kind {
+            ArchiveKind::Zip => {
+                let mut archive = open_zip(&self.archive)?;
+                let mut entry = archive.by_name(&self.name).with_context(|| {
+                    format!("{} has no member {}", self.archive.display(), self.name)
+                })?;
+                entry.read_to_end(&mut data)?;
+            }
+            ArchiveKind::Tar | ArchiveKind::TarGz => {
+                let mut archive = open_tar(&self.archive, self.kind)?;
+                let mut found = false;
+                for entry in archive.entries()? {
+                    let mut entry = entry?;
+                    if member_name(&entry.path()?).as_deref() == Some(self.name.as_str()) {
+                        entry.read_to_end(&mut data)?;
+                        found = true;
+                        break;
+                    }
+                }
+                if !found {
+                    bail!("{} has no member {}", self.archive.display(), self.name);
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    /// `<archive stem>/<member directory>`, where the member's output goes.
+    pub fn output_dir(&self) -> String {
+        let stem = self.kind.stem(&self.archive);
+        match self.name.rsplit_once('/') {
+            Some((parent, _)) => format!("{stem}/{parent}"),
+            None => stem,
+        }
+    }
+
+    pub fn file_stem(&self) -> String {
+        Path::new(&self.name)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_else(|| "artifact".to_string())
+    }
+}
+
+/// Lists the regular-file members of `archive` matching `filter` as virtual
+/// input paths, in archive order.
+pub fn expand(archive: &Path, kind: ArchiveKind, filter: Option<&Pattern>) -> Result<Vec<PathBuf>> {
+    let mut names = Vec::new();
+    match kind {
+        ArchiveKind::Zip => {
+            let mut zip = open_zip(archive)?;
+            for index in 0..zip.len() {
+                let entry = zip.by_index(index)?;
+                if entry.is_file()
+                    && let Some(name) = entry.enclosed_name().as_deref().and_then(member_name)
+                {
+                    names.push(name);
+                }
+            }
+        }
+        ArchiveKind::Tar | ArchiveKind::TarGz => {
+            let mut tar = open_tar(archive, kind)?;
+            for entry in tar.entries()? {
+                let entry = entry?;
+                if entry.header().entry_type().is_file()
+                    && let Some(name) = member_name(&entry.path()?)
+                {
+                    names.push(name);
+                }
+            }
+        }
+    }
+    Ok(names
+        .into_iter()
+        .filter(|name| filter.is_none_or(|pattern| pattern.matches(name)))
+        .map(|name| archive.join(name))
+        .collect())
+}
+
+/// Normalises a member path to `/`-separated form, rejecting absolute paths
+/// and `..` so members can never resolve outside the output directory.
+fn member_name(path: &Path) -> Option<String> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()?.to_string()),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+fn open_zip(path: &Path) -> Result<zip::ZipArchive<BufReader<File>>> {
+    let file =
+        File::open(path).with_context(|| format!("Failed to open archive: {}", path.display()))?;
+    zip::ZipArchive::new(BufReader::new(file))
+        .map_err(|err| anyhow!("Failed to read ZIP archive {}: {err}", path.display()))
+}
+
+fn open_tar(path: &Path, kind: ArchiveKind) -> Result<tar::Archive<Box<dyn Read>>> {
+    let file =
+        File::open(path).with_context(|| format!("Failed to open archive: {}", path.display()))?;
+    let reader: Box<dyn Read> = match kind {
+        ArchiveKind::TarGz => Box::new(GzDecoder::new(BufReader::new(file))),
+        _ => Box::new(BufReader::new(file)),
+    };
+    Ok(tar::Archive::new(reader))
+}
+
+/// One file to place in a delivery archive.
+#[derive(Debug, Clone)]
+pub struct PackageEntry {
+    /// `/`-separated path inside the archive.
+    pub name: String,
+    pub source: PackageSource,
+}
+
+#[derive(Debug, Clone)]
+pub enum PackageSource {
+    /// Copied from disk; stored without recompression since converted media
+    /// is already compressed.
+    File(PathBuf),
+    /// Generated content such as manifests and sidecars; deflated.
+    Bytes(Vec<u8>),
+}
+
+/// Lists every distinct output file of `results`, named relative to
+/// `output_dir`, each followed by a `<name>.json` metadata sidecar when
+/// `sidecars` is set. Outputs shared by several results (duplicates,
+/// combined documents) are packaged once.
+pub fn output_entries(
+    results: &[PipelineResult],
+    output_dir: &Path,
+    sidecars: bool,
+) -> Result<Vec<PackageEntry>> {
+    let mut seen = HashSet::new();
+    let mut entries = Vec::new();
+    for result in results {
+        if !result.output.is_file() || !seen.insert(result.output.clone()) {
+            continue;
+        }
+        let relative = result
+            .output
+            .strip_prefix(output_dir)
+            .ok()
+            .and_then(member_name)
+            .or_else(|| {
+                result
+                    .output
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+            })
+            .ok_or_else(|| anyhow!("Output has no file name: {}", result.output.display()))?;
+        entries.push(PackageEntry {
+            name: relative.clone(),
+            source: PackageSource::File(result.output.clone()),
+        });
+        if sidecars {
+            entries.push(PackageEntry {
+                name: format!("{relative}.json"),
+                source: PackageSource::Bytes(serde_json::to_vec_pretty(&result.metadata)?),
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Writes `entries` to a ZIP or TAR archive chosen by `path`'s extension.
+pub fn package(path: &Path, entries: &[PackageEntry]) -> Result<()> {
+    let kind = ArchiveKind::from_path(path).ok_or_else(|| {
+        anyhow!(
+            "Unsupported archive extension for {}; use .zip, .tar, .tar.gz or .tgz",
+            path.display()
+        )
+    })?;
+    if let Some(parent) = path.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create archive directory: {}", parent.display()))?;
+    }
+    let file = File::create(path)
+        .with_context(|| format!("Failed to create archive: {}", path.display()))?;
+    let writer = BufWriter::new(file);
+    match kind {
+        ArchiveKind::Zip => write_zip(writer, entries),
+        ArchiveKind::Tar => write_tar(writer, entries)?.flush().map_err(Into::into),
+        ArchiveKind::TarGz => write_tar(GzEncoder::new(writer, Compression::default()), entries)?
+            .finish()
+            .map(drop)
+            .map_err(Into::into),
+    }
+    .with_context(|| format!("Failed to write archive: {}", path.display()))
+}
+
+fn write_zip(writer: impl Write + io::Seek, entries: &[PackageEntry]) -> Result<()> {
+    let mut zip = zip::ZipWriter::new(writer);
+    for entry in entries {
+        match &entry.source {
+            PackageSource::File(source) => {
+                let options = SimpleFileOptions::default()
+                    .compression_method(CompressionMethod::Stored)
+                    .large_file(fs::metadata(source)?.len() >= u32::MAX as u64);
+                zip.start_file(entry.name.as_str(), options)?;
+                io::copy(&mut File::open(source)?, &mut zip)?;
+            }
+            PackageSource::Bytes(bytes) => {
+                let options =
+                    SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+                zip.start_file(entry.name.as_str(), options)?;
+                zip.write_all(bytes)?;
+            }
+        }
+    }
+    zip.finish()?.flush()?;
+    Ok(())
+}
+
+fn write_tar<W: Write>(writer: W, entries: &[PackageEntry]) -> Result<W> {
+    let mut tar = tar::Builder::new(writer);
+    for entry in entries {
+        match &entry.source {
+            PackageSource::File(source) => {
+                tar.append_path_with_name(source, &entry.name)?;
+            }
+            PackageSource::Bytes(bytes) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(bytes.len() as u64);
+                header.set_mode(0o644);
+                header.set_mtime(
+                    std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|elapsed| elapsed.as_secs())
+                        .unwrap_or(0),
+                );
+                header.set_cksum();
+                tar.append_data(&mut header, &entry.name, bytes.as_slice())?;
+            }
+        }
+    }
+    Ok(tar.into_inner()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tar_gz_members_expand_and_read_back() {
+        let temp = tempfile::tempdir().unwrap();
+        let archive = temp.path().join("scans.tar.gz");
+        {
+            let encoder = GzEncoder::new(File::create(&archive).unwrap(), Compression::fast());
+            let mut builder = tar::Builder::new(encoder);
+            for (name, body) in [("a/one.png", b"one".as_slice()), ("notes.txt", b"text")] {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(body.len() as u64);
+                header.set_mode(0o644);
+                header.set_cksum();
+                builder.append_data(&mut header, name, body).unwrap();
+            }
+            builder.into_inner().unwrap().finish().unwrap();
+        }
+
+        let pattern = Pattern::new("*.png").unwrap();
+        let inputs = expand(&archive, ArchiveKind::TarGz, Some(&pattern)).unwrap();
+        assert_eq!(inputs, vec![archive.join("a/one.png")]);
+
+        let member = ArchiveMember::from_path(&inputs[0]).unwrap();
+        assert_eq!(member.name, "a/one.png");
+        assert_eq!(member.output_dir(), "scans/a");
+        assert_eq!(member.file_stem(), "one");
+        assert_eq!(member.read().unwrap(), b"one");
+        assert!(ArchiveMember::from_path(&archive).is_none());
+    }
+
+    #[test]
+    fn member_names_cannot_escape() {
+        assert_eq!(
+            member_name(Path::new("./a/b.png")).as_deref(),
+            Some("a/b.png")
+        );
+        assert!(member_name(Path::new("../b.png")).is_none());
+        assert!(member_name(Path::new("/etc/passwd")).is_none());
+    }
+}
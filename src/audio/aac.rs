@@ -0,0 +1,46 @@
+//! AAC as MP4 and Matroska carry it: raw access units, set up by the
+//! AudioSpecificConfig the container stores. Decoding goes through
+//! libavcodec with the `aac` feature; there is no encoder.
+
+#[cfg(feature = "aac")]
+use anyhow::{Context, anyhow};
+use anyhow::{Result, bail};
+
+use crate::video::AudioStream;
+#[cfg(feature = "aac")]
+use crate::video::{AudioBuffer, AudioCodec, ChannelLayout};
+
+/// Decodes AAC access units into float samples, with `config` the
+/// stream's AudioSpecificConfig.
+pub fn decode(config: &[u8], packets: &[Vec<u8>]) -> Result<AudioStream> {
+    if config.len() < 2 {
+        bail!("AAC track has no AudioSpecificConfig");
+    }
+    decode_packets(config, packets)
+}
+
+#[cfg(feature = "aac")]
+fn decode_packets(config: &[u8], packets: &[Vec<u8>]) -> Result<AudioStream> {
+    let spec = super::ffmpeg::DecoderSpec {
+        name: "aac",
+        id: ffmpeg_next::codec::Id::AAC,
+        label: "AAC",
+    };
+    let decoded = super::ffmpeg::decode(spec, config, packets).context("failed to decode AAC")?;
+    let channels = decoded.channels;
+    let channel_layout = ChannelLayout::from_channel_count(channels)
+        .ok_or_else(|| anyhow!("AAC stream has {channels} channels"))?;
+    Ok(AudioStream {
+        codec: AudioCodec::Aac,
+        buffers: vec![AudioBuffer {
+            sample_rate: decoded.sample_rate,
+            channel_layout,
+            samples: decoded.samples,
+        }],
+    })
+}
+
+#[cfg(not(feature = "aac"))]
+fn decode_packets(_config: &[u8], _packets: &[Vec<u8>]) -> Result<AudioStream> {
+    bail!("AAC decoding requires building with the aac feature")
+}
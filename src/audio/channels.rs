@@ -0,0 +1,240 @@
+//! Channel layout conversion by mixing matrix. Channels without a place in
+//! the target layout fold into their nearest neighbours 3 dB down, after
+//! ITU-R BS.775; the LFE channel is dropped from downmixes.
+
+use std::f32::consts::FRAC_1_SQRT_2;
+
+use anyhow::{Result, bail};
+
+use crate::video::{AudioBuffer, ChannelLayout};
+
+/// A loudspeaker position, in WAV channel mask terms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    FrontLeft,
+    FrontRight,
+    FrontCenter,
+    LowFrequency,
+    BackLeft,
+    BackRight,
+    SideLeft,
+    SideRight,
+}
+
+impl Position {
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::FrontLeft => "FL",
+            Self::FrontRight => "FR",
+            Self::FrontCenter => "FC",
+            Self::LowFrequency => "LFE",
+            Self::BackLeft => "BL",
+            Self::BackRight => "BR",
+            Self::SideLeft => "SL",
+            Self::SideRight => "SR",
+        }
+    }
+}
+
+/// The positions of `layout`'s channels in their interleaved order, as
+/// WAV's default channel masks place them; `None` for custom layouts.
+pub fn positions(layout: ChannelLayout) -> Option<&'static [Position]> {
+    use Position::*;
+    match layout {
+        ChannelLayout::Mono => Some(&[FrontCenter]),
+        ChannelLayout::Stereo => Some(&[FrontLeft, FrontRight]),
+        ChannelLayout::Surround51 => Some(&[
+            FrontLeft,
+            FrontRight,
+            FrontCenter,
+            LowFrequency,
+            BackLeft,
+            BackRight,
+        ]),
+        ChannelLayout::Surround71 => Some(&[
+            FrontLeft,
+            FrontRight,
+            FrontCenter,
+            LowFrequency,
+            BackLeft,
+            BackRight,
+            SideLeft,
+            SideRight,
+        ]),
+        ChannelLayout::Custom(_) => None,
+    }
+}
+
+/// Parses `mono`, `stereo`, `5.1` or `7.1`.
+pub fn layout_from_name(name: &str) -> Option<ChannelLayout> {
+    match name.trim().to_ascii_lowercase().as_str() {
+        "mono" | "1.0" => Some(ChannelLayout::Mono),
+        "stereo" | "2.0" => Some(ChannelLayout::Stereo),
+        "5.1" => Some(ChannelLayout::Surround51),
+        "7.1" => Some(ChannelLayout::Surround71),
+        _ => None,
+    }
+}
+
+pub fn layout_name(layout: ChannelLayout) -> String {
+    match layout {
+        ChannelLayout::Mono => "mono".to_string(),
+        ChannelLayout::Stereo => "stereo".to_string(),
+        ChannelLayout::Surround51 => "5.1".to_string(),
+        ChannelLayout::Surround71 => "7.1".to_string(),
+        ChannelLayout::Custom(count) => format!("{count} channels"),
+    }
+}
+
+/// The gains that mix `from`'s channels into each of `to`'s: one row per
+/// output channel, one column per input channel. With `normalize`, the
+/// gains are scaled down so that no output can exceed full scale.
+pub fn matrix(from: ChannelLayout, to: ChannelLayout, normalize: bool) -> Result<Vec<Vec<f32>>> {
+    let (Some(sources), Some(targets)) = (positions(from), positions(to)) else {
+        if from.channel_count() == to.channel_count() {
+            return Ok(identity(usize::from(to.channel_count())));
+        }
+        bail!(
+            "cannot mix {} into {} without knowing where the channels belong",
+            layout_name(from),
+            layout_name(to)
+        );
+    };
+    let mono_source = sources == [Position::FrontCenter];
+    let mut rows = vec![vec![0.0; sources.len()]; targets.len()];
+    for (column, &position) in sources.iter().enumerate() {
+        for (row, gain) in route(position, targets, mono_source) {
+            rows[row][column] += gain;
+        }
+    }
+    if normalize {
+        let loudest = rows
+            .iter()
+            .map(|row| row.iter().map(|gain| gain.abs()).sum::<f32>())
+            .fold(0.0, f32::max);
+        if loudest > 1.0 {
+            for gain in rows.iter_mut().flatten() {
+                *gain /= loudest;
+            }
+        }
+    }
+    Ok(rows)
+}
+
+/// Where a channel at `position` goes among `targets`, with its gains.
+fn route(position: Position, targets: &[Position], mono_source: bool) -> Vec<(usize, f32)> {
+    use Position::*;
+    if let Some(index) = targets.iter().position(|&target| target == position) {
+        return vec![(index, 1.0)];
+    }
+    let has = |position| targets.contains(&position);
+    let spread = |positions: &[Position], gain: f32| -> Vec<(usize, f32)> {
+        positions
+            .iter()
+            .flat_map(|&position| route(position, targets, mono_source))
+            .map(|(index, routed)| (index, routed * gain))
+            .collect()
+    };
+    match position {
+        // A mono source plays at full level from both speakers; a centre
+        // speaker's share is split between them at equal power.
+        FrontCenter if mono_source => spread(&[FrontLeft, FrontRight], 1.0),
+        FrontCenter => spread(&[FrontLeft, FrontRight], FRAC_1_SQRT_2),
+        FrontLeft | FrontRight => spread(&[FrontCenter], FRAC_1_SQRT_2),
+        LowFrequency => Vec::new(),
+        BackLeft if has(SideLeft) => spread(&[SideLeft], FRAC_1_SQRT_2),
+        BackRight if has(SideRight) => spread(&[SideRight], FRAC_1_SQRT_2),
+        SideLeft if has(BackLeft) => spread(&[BackLeft], FRAC_1_SQRT_2),
+        SideRight if has(BackRight) => spread(&[BackRight], FRAC_1_SQRT_2),
+        BackLeft | SideLeft => spread(&[FrontLeft], FRAC_1_SQRT_2),
+        BackRight | SideRight => spread(&[FrontRight], FRAC_1_SQRT_2),
+    }
+}
+
+fn identity(size: usize) -> Vec<Vec<f32>> {
+    (0..size)
+        .map(|row| (0..size).map(|column| f32::from(row == column)).collect())
+        .collect()
+}
+
+/// Mixes `buffer` into `layout` through `matrix`.
+pub fn mix(buffer: &AudioBuffer, layout: ChannelLayout, matrix: &[Vec<f32>]) -> AudioBuffer {
+    let channels = usize::from(buffer.channel_layout.channel_count());
+    let mut samples = Vec::with_capacity(buffer.samples.len() / channels * matrix.len());
+    for frame in buffer.samples.chunks_exact(channels) {
+        for row in matrix {
+            samples.push(
+                row.iter()
+                    .zip(frame)
+                    .map(|(gain, sample)| gain * sample)
+                    .sum(),
+            );
+        }
+    }
+    AudioBuffer {
+        sample_rate: buffer.sample_rate,
+        channel_layout: layout,
+        samples,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HALF_POWER: f32 = FRAC_1_SQRT_2;
+
+    fn assert_rows(actual: &[Vec<f32>], expected: &[&[f32]]) {
+        assert_eq!(actual.len(), expected.len());
+        for (row, expected) in actual.iter().zip(expected) {
+            for (gain, expected) in row.iter().zip(*expected) {
+                assert!((gain - expected).abs() < 1e-6, "{actual:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn surround_folds_down_to_stereo_at_minus_three_db() {
+        let rows = matrix(ChannelLayout::Surround51, ChannelLayout::Stereo, false).unwrap();
+        assert_rows(
+            &rows,
+            &[
+                &[1.0, 0.0, HALF_POWER, 0.0, HALF_POWER, 0.0],
+                &[0.0, 1.0, HALF_POWER, 0.0, 0.0, HALF_POWER],
+            ],
+        );
+        // Normalised, a row of gains sums to one.
+        let rows = matrix(ChannelLayout::Surround51, ChannelLayout::Stereo, true).unwrap();
+        let total = 1.0 + 2.0 * HALF_POWER;
+        assert!((rows[0][0] - 1.0 / total).abs() < 1e-6);
+        assert!((rows[0].iter().sum::<f32>() - 1.0).abs() < 1e-6);
+
+        // 7.1 keeps its backs and folds its sides into them.
+        let rows = matrix(ChannelLayout::Surround71, ChannelLayout::Surround51, false).unwrap();
+        assert_eq!(rows[4], [0.0, 0.0, 0.0, 0.0, 1.0, 0.0, HALF_POWER, 0.0]);
+        assert_eq!(rows[3][3], 1.0);
+    }
+
+    #[test]
+    fn upmixes_without_inventing_channels() {
+        let rows = matrix(ChannelLayout::Mono, ChannelLayout::Stereo, true).unwrap();
+        assert_eq!(rows, [[1.0], [1.0]]);
+        let rows = matrix(ChannelLayout::Mono, ChannelLayout::Surround51, true).unwrap();
+        assert_eq!(rows.iter().flatten().sum::<f32>(), 1.0);
+        assert_eq!(rows[2], [1.0]);
+        let rows = matrix(ChannelLayout::Stereo, ChannelLayout::Surround71, true).unwrap();
+        assert_rows(&rows[..3], &[&[1.0, 0.0], &[0.0, 1.0], &[0.0, 0.0]]);
+
+        let stereo = AudioBuffer {
+            sample_rate: 8000,
+            channel_layout: ChannelLayout::Stereo,
+            samples: vec![0.5, -0.5, 1.0, 0.0],
+        };
+        let rows = matrix(ChannelLayout::Stereo, ChannelLayout::Mono, true).unwrap();
+        let mono = mix(&stereo, ChannelLayout::Mono, &rows);
+        assert_eq!(mono.samples, [0.0, 0.5]);
+
+        assert!(matrix(ChannelLayout::Custom(4), ChannelLayout::Stereo, true).is_err());
+        assert!(matrix(ChannelLayout::Custom(4), ChannelLayout::Custom(4), true).is_ok());
+    }
+}
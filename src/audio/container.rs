@@ -0,0 +1,56 @@
+//! Decoding the audio tracks of MP4 and Matroska files, from the packets
+//! their demuxers locate.
+
+use anyhow::{Result, anyhow, bail};
+
+use super::{aac, flac, mp3, opus, pcm};
+use crate::video::container::AudioSamples;
+use crate::video::{AudioCodec, AudioStream};
+
+/// Decodes `track` into float samples. PCM, MP3 and FLAC decode natively;
+/// Opus and AAC need the `opus` and `aac` features.
+pub fn decode(track: &AudioSamples<'_>) -> Result<AudioStream> {
+    if track.samples.is_empty() {
+        bail!("the audio track holds no samples");
+    }
+    let joined = || -> Vec<u8> {
+        track
+            .samples
+            .iter()
+            .flat_map(|sample| sample.data)
+            .copied()
+            .collect()
+    };
+    let packets = || -> Vec<Vec<u8>> {
+        track
+            .samples
+            .iter()
+            .map(|sample| sample.data.to_vec())
+            .collect()
+    };
+    let config = || {
+        track
+            .codec_config
+            .as_deref()
+            .ok_or_else(|| anyhow!("{} track has no decoder setup", track.codec.name()))
+    };
+    if let Some(format) = track.pcm {
+        return pcm::decode(&joined(), format, track.sample_rate, track.channels);
+    }
+    match track.codec {
+        // MP3 frames carry their own headers, and FLAC frames follow the
+        // stream header the setup holds, so the samples joined make a file.
+        AudioCodec::Mp3 => mp3::decode(&joined()),
+        AudioCodec::Flac => {
+            let mut file = config()?.to_vec();
+            if !flac::is_flac(&file) {
+                bail!("FLAC track setup does not start with the fLaC marker");
+            }
+            file.extend(joined());
+            flac::decode(&file)
+        }
+        AudioCodec::Opus => opus::decode_packets(config()?, &packets()),
+        AudioCodec::Aac => aac::decode(config()?, &packets()),
+        codec => bail!("no decoder for {} audio tracks", codec.name()),
+    }
+}
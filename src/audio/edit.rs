@@ -0,0 +1,167 @@
+//! Cutting and fading decoded audio. The buffers of a stream play one after
+//! another, so times here run across all of them.
+
+use std::time::Duration;
+
+use crate::video::AudioBuffer;
+
+/// The shape of a fade, from silence at 0 to full level at 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Curve {
+    Linear,
+    /// A quarter sine, which holds the level up longer and sounds even
+    /// where a linear fade seems to drop away early.
+    Sine,
+}
+
+impl Curve {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "linear" => Some(Self::Linear),
+            "sine" => Some(Self::Sine),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Linear => "linear",
+            Self::Sine => "sine",
+        }
+    }
+
+    fn gain(self, progress: f64) -> f64 {
+        let progress = progress.clamp(0.0, 1.0);
+        match self {
+            Self::Linear => progress,
+            Self::Sine => (progress * std::f64::consts::FRAC_PI_2).sin(),
+        }
+    }
+}
+
+fn frame_count(buffer: &AudioBuffer) -> usize {
+    buffer.samples.len() / usize::from(buffer.channel_layout.channel_count()).max(1)
+}
+
+fn buffer_duration(buffer: &AudioBuffer) -> f64 {
+    if buffer.sample_rate == 0 {
+        return 0.0;
+    }
+    frame_count(buffer) as f64 / f64::from(buffer.sample_rate)
+}
+
+/// How long `buffers` play for.
+pub fn duration(buffers: &[AudioBuffer]) -> Duration {
+    Duration::from_secs_f64(buffers.iter().map(buffer_duration).sum())
+}
+
+/// Keeps the sample frames between `start` and `end`, rounded to the
+/// nearest frame, dropping buffers left empty.
+pub fn trim(buffers: &mut Vec<AudioBuffer>, start: Duration, end: Duration) {
+    let (start, end) = (start.as_secs_f64(), end.as_secs_f64());
+    let mut elapsed = 0.0;
+    for buffer in buffers.iter_mut() {
+        let rate = f64::from(buffer.sample_rate);
+        let frames = frame_count(buffer);
+        let frame = |time: f64| (((time - elapsed) * rate).round().max(0.0) as usize).min(frames);
+        let (first, last) = (frame(start), frame(end).max(frame(start)));
+        elapsed += buffer_duration(buffer);
+        let channels = usize::from(buffer.channel_layout.channel_count());
+        buffer.samples.truncate(last * channels);
+        buffer.samples.drain(..first * channels);
+    }
+    buffers.retain(|buffer| !buffer.samples.is_empty());
+}
+
+/// Fades the first `fade_in` of `buffers` up from silence and the last
+/// `fade_out` down to it. Fades that overlap multiply.
+pub fn fade(buffers: &mut [AudioBuffer], fade_in: Duration, fade_out: Duration, curve: Curve) {
+    let total = duration(buffers).as_secs_f64();
+    let (fade_in, fade_out) = (fade_in.as_secs_f64(), fade_out.as_secs_f64());
+    let mut elapsed = 0.0;
+    for buffer in buffers {
+        let rate = f64::from(buffer.sample_rate);
+        let channels = usize::from(buffer.channel_layout.channel_count());
+        let length = buffer_duration(buffer);
+        for (index, frame) in buffer.samples.chunks_exact_mut(channels).enumerate() {
+            let time = elapsed + index as f64 / rate;
+            // The first frame starts from silence and the last one ends in
+            // it.
+            let mut gain = 1.0;
+            if time < fade_in {
+                gain *= curve.gain(time / fade_in);
+            }
+            let remaining = total - time - 1.0 / rate;
+            if remaining < fade_out {
+                gain *= curve.gain(remaining / fade_out);
+            }
+            if gain < 1.0 {
+                for sample in frame {
+                    *sample = (f64::from(*sample) * gain) as f32;
+                }
+            }
+        }
+        elapsed += length;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::ChannelLayout;
+
+    fn buffer(sample_rate: u32, samples: Vec<f32>) -> AudioBuffer {
+        AudioBuffer {
+            sample_rate,
+            channel_layout: ChannelLayout::Stereo,
+            samples,
+        }
+    }
+
+    #[test]
+    fn trims_across_buffers() {
+        let mut buffers = vec![
+            buffer(10, (0..20).map(|sample| sample as f32).collect()),
+            buffer(10, (20..40).map(|sample| sample as f32).collect()),
+        ];
+        assert_eq!(duration(&buffers), Duration::from_secs(2));
+        trim(
+            &mut buffers,
+            Duration::from_millis(800),
+            Duration::from_millis(1300),
+        );
+        assert_eq!(buffers.len(), 2);
+        assert_eq!(buffers[0].samples, [16.0, 17.0, 18.0, 19.0]);
+        assert_eq!(buffers[1].samples, [20.0, 21.0, 22.0, 23.0, 24.0, 25.0]);
+
+        trim(&mut buffers, Duration::ZERO, Duration::from_millis(100));
+        assert_eq!(buffers.len(), 1);
+        assert_eq!(buffers[0].samples, [16.0, 17.0]);
+    }
+
+    #[test]
+    fn fades_from_and_to_silence() {
+        let mut buffers = vec![buffer(4, vec![1.0; 16])];
+        fade(
+            &mut buffers,
+            Duration::from_secs(1),
+            Duration::from_millis(500),
+            Curve::Linear,
+        );
+        let left: Vec<f32> = buffers[0].samples.iter().step_by(2).copied().collect();
+        assert_eq!(left, [0.0, 0.25, 0.5, 0.75, 1.0, 1.0, 0.5, 0.0]);
+        assert_eq!(buffers[0].samples[1], 0.0);
+        assert_eq!(buffers[0].samples[3], 0.25);
+
+        let mut buffers = vec![buffer(4, vec![1.0; 8])];
+        fade(
+            &mut buffers,
+            Duration::from_secs(1),
+            Duration::ZERO,
+            Curve::Sine,
+        );
+        let left: Vec<f32> = buffers[0].samples.iter().step_by(2).copied().collect();
+        assert_eq!(left[0], 0.0);
+        assert!((left[2] - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,250 @@
+//! Encoding and decoding through FFmpeg's libavcodec, for the audio codecs
+//! bunker-convert has no native coder for. Only built with a feature that
+//! needs it.
+
+use anyhow::{Context, Result, anyhow, bail};
+use ffmpeg::format::Sample;
+use ffmpeg_next as ffmpeg;
+
+use crate::video::{AudioBuffer, AudioPacket};
+
+/// Which libavcodec encoder to open and how.
+pub(crate) struct EncoderSpec {
+    /// The encoder's name, such as `libmp3lame`.
+    pub name: &'static str,
+    /// The float sample layout the encoder takes, planar or interleaved.
+    pub format: Sample,
+    /// Bits per second.
+    pub bit_rate: u32,
+}
+
+/// What an encoder made of a buffer.
+pub(crate) struct Encoded {
+    /// The codec's setup header, for codecs that have one.
+    pub extradata: Vec<u8>,
+    /// Packets in order; their lengths count frames at the input rate.
+    pub packets: Vec<AudioPacket>,
+}
+
+/// Encodes `buffer` with the encoder `spec` names. Frames are cut to the
+/// encoder's frame size, the last one short.
+pub(crate) fn encode(spec: EncoderSpec, buffer: &AudioBuffer) -> Result<Encoded> {
+    let name = spec.name;
+    if buffer.samples.is_empty() {
+        bail!("no audio samples to encode");
+    }
+    let channels = usize::from(buffer.channel_layout.channel_count());
+    ffmpeg::init().context("failed to initialise FFmpeg")?;
+    let codec = ffmpeg::encoder::find_by_name(name)
+        .ok_or_else(|| anyhow!("the linked FFmpeg was built without {name}"))?;
+    let layout = ffmpeg::ChannelLayout::default(channels as i32);
+    let mut context = ffmpeg::codec::Context::new_with_codec(codec)
+        .encoder()
+        .audio()?;
+    context.set_rate(buffer.sample_rate as i32);
+    context.set_channel_layout(layout);
+    context.set_format(spec.format);
+    context.set_bit_rate(spec.bit_rate as usize);
+    context.set_time_base((1, buffer.sample_rate as i32));
+    let mut encoder = context
+        .open_as(codec)
+        .with_context(|| format!("failed to open {name}"))?;
+    // SAFETY: the context is open; libavcodec owns the extradata, which
+    // is copied out before the encoder is dropped.
+    let extradata = unsafe {
+        let context = encoder.as_ptr();
+        match (*context).extradata_size {
+            size if size > 0 && !(*context).extradata.is_null() => {
+                std::slice::from_raw_parts((*context).extradata, size as usize).to_vec()
+            }
+            _ => Vec::new(),
+        }
+    };
+
+    let frame_size = (encoder.frame_size() as usize).max(1);
+    let width = spec.format.bytes();
+    let mut packets = Vec::new();
+    for (index, chunk) in buffer.samples.chunks(frame_size * channels).enumerate() {
+        let samples = chunk.len() / channels;
+        let mut frame = ffmpeg::frame::Audio::new(spec.format, samples, layout);
+        frame.set_rate(buffer.sample_rate);
+        frame.set_pts(Some((index * frame_size) as i64));
+        if spec.format.is_planar() {
+            for channel in 0..channels {
+                let plane = frame.data_mut(channel).chunks_exact_mut(width);
+                for (bytes, frame_samples) in plane.zip(chunk.chunks_exact(channels)) {
+                    bytes.copy_from_slice(&frame_samples[channel].to_ne_bytes());
+                }
+            }
+        } else {
+            for (bytes, sample) in frame.data_mut(0).chunks_exact_mut(width).zip(chunk) {
+                bytes.copy_from_slice(&sample.to_ne_bytes());
+            }
+        }
+        encoder
+            .send_frame(&frame)
+            .with_context(|| format!("failed to encode audio with {name}"))?;
+        receive(&mut encoder, frame_size, &mut packets)?;
+    }
+    encoder
+        .send_eof()
+        .with_context(|| format!("failed to flush {name}"))?;
+    receive(&mut encoder, frame_size, &mut packets)?;
+    Ok(Encoded { extradata, packets })
+}
+
+/// Takes every packet the encoder has ready.
+fn receive(
+    encoder: &mut ffmpeg::encoder::Audio,
+    frame_size: usize,
+    packets: &mut Vec<AudioPacket>,
+) -> Result<()> {
+    let mut packet = ffmpeg::Packet::empty();
+    loop {
+        match encoder.receive_packet(&mut packet) {
+            Ok(()) => {
+                // Encoders report the short last packet's true length.
+                let frames = u32::try_from(packet.duration())
+                    .ok()
+                    .filter(|&frames| frames > 0)
+                    .unwrap_or(frame_size as u32);
+                packets.push(AudioPacket {
+                    data: packet.data().unwrap_or_default().to_vec(),
+                    frames,
+                });
+            }
+            Err(ffmpeg::Error::Eof) => return Ok(()),
+            Err(ffmpeg::Error::Other { errno }) if errno == ffmpeg::error::EAGAIN => {
+                return Ok(());
+            }
+            Err(error) => return Err(error).context("failed to encode audio"),
+        }
+    }
+}
+
+/// Which libavcodec decoder to open.
+#[cfg(any(feature = "opus", feature = "aac"))]
+pub(crate) struct DecoderSpec {
+    /// The decoder's name, such as `opus`, tried before any other decoder
+    /// for the codec.
+    pub name: &'static str,
+    pub id: ffmpeg::codec::Id,
+    /// The codec's name for messages, such as `Opus`.
+    pub label: &'static str,
+}
+
+/// What a decoder made of a stream's packets.
+#[cfg(any(feature = "opus", feature = "aac"))]
+pub(crate) struct Decoded {
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Interleaved.
+    pub samples: Vec<f32>,
+}
+
+/// Decodes `packets` with the decoder `spec` names, with `extradata` (an
+/// `OpusHead`, an AudioSpecificConfig) as its setup. libavcodec drops the
+/// priming an Opus head's pre-skip counts itself.
+#[cfg(any(feature = "opus", feature = "aac"))]
+pub(crate) fn decode(spec: DecoderSpec, extradata: &[u8], packets: &[Vec<u8>]) -> Result<Decoded> {
+    let label = spec.label;
+    ffmpeg::init().context("failed to initialise FFmpeg")?;
+    let codec = ffmpeg::decoder::find_by_name(spec.name)
+        .or_else(|| ffmpeg::decoder::find(spec.id))
+        .ok_or_else(|| anyhow!("the linked FFmpeg has no {label} decoder"))?;
+    let mut context = ffmpeg::codec::Context::new_with_codec(codec);
+    set_extradata(&mut context, extradata)?;
+    let mut decoder = context
+        .decoder()
+        .audio()
+        .with_context(|| format!("failed to open the {label} decoder"))?;
+
+    let mut decoded = Decoded {
+        sample_rate: 0,
+        channels: 0,
+        samples: Vec::new(),
+    };
+    for (index, data) in packets.iter().enumerate() {
+        let packet = ffmpeg::Packet::copy(data);
+        decoder
+            .send_packet(&packet)
+            .with_context(|| format!("{label} packet {} is malformed", index + 1))?;
+        receive_samples(&mut decoder, label, &mut decoded)?;
+    }
+    decoder.send_eof().context("failed to flush the decoder")?;
+    receive_samples(&mut decoder, label, &mut decoded)?;
+    Ok(decoded)
+}
+
+/// Appends every frame the decoder has ready to `decoded`, interleaved,
+/// taking the rate and channel count from the first.
+#[cfg(any(feature = "opus", feature = "aac"))]
+fn receive_samples(
+    decoder: &mut ffmpeg::decoder::Audio,
+    label: &str,
+    decoded: &mut Decoded,
+) -> Result<()> {
+    let mut frame = ffmpeg::frame::Audio::empty();
+    loop {
+        match decoder.receive_frame(&mut frame) {
+            Ok(()) => {}
+            Err(ffmpeg::Error::Eof) => return Ok(()),
+            Err(ffmpeg::Error::Other { errno }) if errno == ffmpeg::error::EAGAIN => {
+                return Ok(());
+            }
+            Err(error) => {
+                return Err(error).with_context(|| format!("failed to decode {label} audio"));
+            }
+        }
+        let read: fn(&[u8]) -> f32 = match frame.format() {
+            Sample::F32(_) => |bytes| f32::from_ne_bytes(bytes.try_into().expect("four bytes")),
+            Sample::I16(_) => |bytes| {
+                f32::from(i16::from_ne_bytes(bytes.try_into().expect("two bytes"))) / 32768.0
+            },
+            other => bail!(
+                "the {label} decoder returned unsupported {} samples",
+                other.name()
+            ),
+        };
+        let (channels, count) = (usize::from(frame.channels()), frame.samples());
+        if decoded.samples.is_empty() {
+            decoded.sample_rate = frame.rate();
+            decoded.channels = frame.channels();
+        } else if (frame.rate(), frame.channels()) != (decoded.sample_rate, decoded.channels) {
+            bail!("{label} frames change sample rate or channel count mid-stream");
+        }
+        let samples = &mut decoded.samples;
+        let width = frame.format().bytes();
+        if frame.is_planar() {
+            for index in 0..count {
+                for channel in 0..channels {
+                    let at = index * width;
+                    samples.push(read(&frame.data(channel)[at..at + width]));
+                }
+            }
+        } else {
+            let data = &frame.data(0)[..count * channels * width];
+            samples.extend(data.chunks_exact(width).map(read));
+        }
+    }
+}
+
+/// Copies `data` into the codec context's extradata, which libavcodec
+/// frees with the context.
+#[cfg(any(feature = "opus", feature = "aac"))]
+fn set_extradata(context: &mut ffmpeg::codec::Context, data: &[u8]) -> Result<()> {
+    let padded = data.len() + ffmpeg::ffi::AV_INPUT_BUFFER_PADDING_SIZE as usize;
+    // SAFETY: the buffer is allocated with av_mallocz, zero-padded as
+    // libavcodec requires and owned by the context from here on.
+    unsafe {
+        let buffer = ffmpeg::ffi::av_mallocz(padded).cast::<u8>();
+        if buffer.is_null() {
+            bail!("failed to allocate decoder extradata");
+        }
+        std::ptr::copy_nonoverlapping(data.as_ptr(), buffer, data.len());
+        let context = context.as_mut_ptr();
+        (*context).extradata = buffer;
+        (*context).extradata_size = data.len() as i32;
+    }
+    Ok(())
+}
@@ -0,0 +1,817 @@
+//! FLAC: losslessly compressed integer PCM. Decoding is symphonia's, with
+//! the stored MD5 checked; the encoder predicts each block with fixed
+//! polynomials or quantised LPC and Rice-codes what is left.
+
+use anyhow::{Result, anyhow, bail};
+use md5::{Digest, Md5};
+use symphonia::default::codecs::FlacDecoder;
+use symphonia::default::formats::FlacReader;
+
+use super::{TrackOptions, decode_track};
+use crate::video::{AudioCodec, AudioStream};
+
+const MARKER: &[u8; 4] = b"fLaC";
+/// The bit depths a frame header can name.
+pub const BIT_DEPTHS: [u32; 5] = [8, 12, 16, 20, 24];
+/// libFLAC's default compression level.
+pub const DEFAULT_LEVEL: u32 = 5;
+/// Rice parameters of 15 and up need the 5-bit parameter coding.
+const RICE_ESCAPE: u32 = 15;
+const MAX_RICE_PARAMETER: u32 = 30;
+
+/// What one compression level searches, after libFLAC's presets.
+struct Level {
+    block_size: usize,
+    /// Try coding stereo pairs as left/side, side/right or mid/side.
+    decorrelate: bool,
+    max_lpc_order: usize,
+    /// Try every LPC order up to the maximum, not only the maximum.
+    search_lpc_orders: bool,
+    max_partition_order: u32,
+}
+
+const LEVELS: [Level; 9] = [
+    Level::new(1152, false, 0, false, 3),
+    Level::new(1152, true, 0, false, 3),
+    Level::new(1152, true, 0, false, 4),
+    Level::new(4096, true, 6, false, 4),
+    Level::new(4096, true, 8, false, 4),
+    Level::new(4096, true, 8, false, 5),
+    Level::new(4096, true, 8, false, 6),
+    Level::new(4096, true, 12, false, 6),
+    Level::new(4096, true, 12, true, 6),
+];
+
+impl Level {
+    const fn new(
+        block_size: usize,
+        decorrelate: bool,
+        max_lpc_order: usize,
+        search_lpc_orders: bool,
+        max_partition_order: u32,
+    ) -> Self {
+        Self {
+            block_size,
+            decorrelate,
+            max_lpc_order,
+            search_lpc_orders,
+            max_partition_order,
+        }
+    }
+}
+
+pub fn is_flac(data: &[u8]) -> bool {
+    data.starts_with(MARKER)
+}
+
+/// Decodes a FLAC file into float samples, failing if they do not match the
+/// MD5 its stream info records.
+pub fn decode(data: &[u8]) -> Result<AudioStream> {
+    let options = TrackOptions {
+        name: "FLAC",
+        codec: AudioCodec::Flac,
+        skip_damaged: false,
+        verify: true,
+    };
+    decode_track::<FlacReader, FlacDecoder>(data.to_vec(), options)
+}
+
+/// The bit depth that keeps `stream`'s samples: 16 when every sample is a
+/// 16-bit value, 24 otherwise.
+pub fn fitting_bit_depth(stream: &AudioStream) -> u32 {
+    let exact = stream
+        .buffers
+        .iter()
+        .flat_map(|buffer| &buffer.samples)
+        .all(|&sample| {
+            let scaled = f64::from(sample) * 32768.0;
+            scaled.fract() == 0.0 && (-32768.0..32768.0).contains(&scaled)
+        });
+    if exact { 16 } else { 24 }
+}
+
+/// Encodes `stream`'s first buffer as a FLAC file of `bits_per_sample`
+/// samples, searching as hard as compression `level` (0-8) asks.
+pub fn encode(stream: &AudioStream, bits_per_sample: u32, level: u32) -> Result<Vec<u8>> {
+    let buffer = stream
+        .buffers
+        .first()
+        .ok_or_else(|| anyhow!("audio stream has no samples"))?;
+    let channels = usize::from(buffer.channel_layout.channel_count());
+    if !(1..=8).contains(&channels) {
+        bail!("FLAC carries 1 to 8 channels, not {channels}");
+    }
+    if !(1..=655_350).contains(&buffer.sample_rate) {
+        bail!("FLAC cannot carry audio at {} Hz", buffer.sample_rate);
+    }
+    if !BIT_DEPTHS.contains(&bits_per_sample) {
+        bail!("FLAC cannot write {bits_per_sample}-bit samples (expected one of {BIT_DEPTHS:?})");
+    }
+    let settings = LEVELS
+        .get(level as usize)
+        .ok_or_else(|| anyhow!("FLAC compression level {level} is not between 0 and 8"))?;
+
+    let scale = f64::from(1u32 << (bits_per_sample - 1));
+    let samples: Vec<i32> = buffer
+        .samples
+        .iter()
+        .map(|&sample| {
+            (f64::from(sample) * scale)
+                .round()
+                .clamp(-scale, scale - 1.0) as i32
+        })
+        .collect();
+    let frame_count = samples.len() / channels;
+
+    let mut frames = Vec::new();
+    let (mut min_frame, mut max_frame) = (u32::MAX, 0);
+    let block_len = settings.block_size * channels;
+    for (number, block) in samples[..frame_count * channels]
+        .chunks(block_len)
+        .enumerate()
+    {
+        let frame = encode_frame(
+            number as u64,
+            block,
+            channels,
+            buffer.sample_rate,
+            bits_per_sample,
+            settings,
+        );
+        min_frame = min_frame.min(frame.len() as u32);
+        max_frame = max_frame.max(frame.len() as u32);
+        frames.extend(frame);
+    }
+    if frames.is_empty() {
+        min_frame = 0;
+    }
+
+    let mut md5 = Md5::new();
+    let width = bits_per_sample.div_ceil(8) as usize;
+    for sample in &samples[..frame_count * channels] {
+        md5.update(&sample.to_le_bytes()[..width]);
+    }
+
+    let mut out = MARKER.to_vec();
+    // The only metadata block: STREAMINFO, flagged as the last.
+    out.push(0x80);
+    out.extend(&34u32.to_be_bytes()[1..]);
+    out.extend((settings.block_size as u16).to_be_bytes());
+    out.extend((settings.block_size as u16).to_be_bytes());
+    out.extend(&min_frame.to_be_bytes()[1..]);
+    out.extend(&max_frame.to_be_bytes()[1..]);
+    let packed = u64::from(buffer.sample_rate) << 44
+        | ((channels as u64 - 1) << 41)
+        | (u64::from(bits_per_sample - 1) << 36)
+        | frame_count as u64;
+    out.extend(packed.to_be_bytes());
+    out.extend(md5.finalize());
+    out.extend(frames);
+    Ok(out)
+}
+
+/// Encodes one block of interleaved `samples` as a frame.
+fn encode_frame(
+    number: u64,
+    samples: &[i32],
+    channels: usize,
+    sample_rate: u32,
+    bits_per_sample: u32,
+    settings: &Level,
+) -> Vec<u8> {
+    let len = samples.len() / channels;
+    let channel = |index: usize| -> Vec<i32> {
+        samples
+            .iter()
+            .skip(index)
+            .step_by(channels)
+            .copied()
+            .collect()
+    };
+
+    let (assignment, subframes) = if channels == 2 && settings.decorrelate {
+        let (left, right) = (channel(0), channel(1));
+        let side: Vec<i32> = left.iter().zip(&right).map(|(l, r)| l - r).collect();
+        let mid: Vec<i32> = left.iter().zip(&right).map(|(l, r)| (l + r) >> 1).collect();
+        let left = Subframe::plan(left, bits_per_sample, settings);
+        let right = Subframe::plan(right, bits_per_sample, settings);
+        let side = Subframe::plan(side, bits_per_sample + 1, settings);
+        let mid = Subframe::plan(mid, bits_per_sample, settings);
+        let options = [
+            (0b0001, left.bits + right.bits),
+            (0b1000, left.bits + side.bits),
+            (0b1001, side.bits + right.bits),
+            (0b1010, mid.bits + side.bits),
+        ];
+        let (assignment, _) = options
+            .into_iter()
+            .min_by_key(|&(_, bits)| bits)
+            .expect("four options");
+        let pair = match assignment {
+            0b0001 => [left, right],
+            0b1000 => [left, side],
+            0b1001 => [side, right],
+            _ => [mid, side],
+        };
+        (assignment, pair.into())
+    } else {
+        let subframes: Vec<Subframe> = (0..channels)
+            .map(|index| Subframe::plan(channel(index), bits_per_sample, settings))
+            .collect();
+        (channels as u8 - 1, subframes)
+    };
+
+    let mut header = vec![0xFF, 0xF8];
+    let (block_code, block_extra) = block_size_code(len);
+    header.push((block_code << 4) | sample_rate_code(sample_rate));
+    let size_code = match bits_per_sample {
+        8 => 1,
+        12 => 2,
+        16 => 4,
+        20 => 5,
+        _ => 6,
+    };
+    header.push((assignment << 4) | (size_code << 1));
+    write_utf8(number, &mut header);
+    header.extend(block_extra);
+    header.push(crc8(&header));
+
+    let mut writer = BitWriter {
+        bytes: header,
+        ..BitWriter::default()
+    };
+    for subframe in &subframes {
+        subframe.write(&mut writer);
+    }
+    let mut frame = writer.finish();
+    let crc = crc16(&frame);
+    frame.extend(crc.to_be_bytes());
+    frame
+}
+
+/// The 4-bit block size code and the bytes it defers the size to.
+fn block_size_code(len: usize) -> (u8, Vec<u8>) {
+    match len {
+        192 => (1, Vec::new()),
+        576 | 1152 | 2304 | 4608 => (2 + (len / 576).trailing_zeros() as u8, Vec::new()),
+        256 | 512 | 1024 | 2048 | 4096 | 8192 | 16384 | 32768 => {
+            (8 + (len / 256).trailing_zeros() as u8, Vec::new())
+        }
+        _ if len <= 256 => (6, vec![(len - 1) as u8]),
+        _ => (7, ((len - 1) as u16).to_be_bytes().to_vec()),
+    }
+}
+
+/// The 4-bit sample rate code; 0 defers to the stream info.
+fn sample_rate_code(sample_rate: u32) -> u8 {
+    match sample_rate {
+        88_200 => 1,
+        176_400 => 2,
+        192_000 => 3,
+        8_000 => 4,
+        16_000 => 5,
+        22_050 => 6,
+        24_000 => 7,
+        32_000 => 8,
+        44_100 => 9,
+        48_000 => 10,
+        96_000 => 11,
+        _ => 0,
+    }
+}
+
+/// Writes a frame number in FLAC's extension of UTF-8 to 36 bits.
+fn write_utf8(value: u64, out: &mut Vec<u8>) {
+    if value < 0x80 {
+        out.push(value as u8);
+        return;
+    }
+    // Each continuation byte carries six bits; the lead byte 7 - len.
+    let len = (2..=7u32)
+        .find(|&len| value < 1 << (5 * len + 1))
+        .expect("frame numbers fit 36 bits");
+    let lead = !(0xFFu8 >> len);
+    out.push(lead | (value >> (6 * (len - 1))) as u8);
+    for index in (0..len - 1).rev() {
+        out.push(0x80 | ((value >> (6 * index)) & 0x3F) as u8);
+    }
+}
+
+/// How a subframe predicts its samples.
+enum Model {
+    Constant,
+    Verbatim,
+    Fixed,
+    Lpc {
+        coefficients: Vec<i32>,
+        precision: u32,
+        shift: u32,
+    },
+}
+
+/// One channel of a frame, with the model chosen for it.
+struct Subframe {
+    samples: Vec<i32>,
+    bits_per_sample: u32,
+    model: Model,
+    /// Warm-up samples the model predicts from.
+    order: usize,
+    residual: Vec<i32>,
+    rice: Rice,
+    /// The estimated coded size.
+    bits: u64,
+}
+
+impl Subframe {
+    /// Chooses the cheapest model `settings` allows for `samples`.
+    fn plan(samples: Vec<i32>, bits_per_sample: u32, settings: &Level) -> Self {
+        let len = samples.len();
+        let verbatim = Self {
+            bits: 8 + (len as u64) * u64::from(bits_per_sample),
+            samples,
+            bits_per_sample,
+            model: Model::Verbatim,
+            order: 0,
+            residual: Vec::new(),
+            rice: Rice::default(),
+        };
+        if verbatim
+            .samples
+            .iter()
+            .all(|&sample| sample == verbatim.samples[0])
+        {
+            return Self {
+                model: Model::Constant,
+                bits: 8 + u64::from(bits_per_sample),
+                ..verbatim
+            };
+        }
+
+        let mut best = verbatim;
+        for order in 0..=4.min(len - 1) {
+            let residual = fixed_residual(&best.samples, order);
+            best = best.cheaper(Model::Fixed, order, residual, settings);
+        }
+        let max_order = settings.max_lpc_order.min(len - 1);
+        if max_order > 0 {
+            let orders = lpc_coefficients(&best.samples, max_order);
+            let precision = lpc_precision(len);
+            for (index, coefficients) in orders.iter().enumerate() {
+                let order = index + 1;
+                if !settings.search_lpc_orders && order != orders.len() {
+                    continue;
+                }
+                let Some((quantised, shift)) = quantise(coefficients, precision) else {
+                    continue;
+                };
+                let Some(residual) = lpc_residual(&best.samples, &quantised, shift) else {
+                    continue;
+                };
+                let model = Model::Lpc {
+                    coefficients: quantised,
+                    precision,
+                    shift,
+                };
+                best = best.cheaper(model, order, residual, settings);
+            }
+        }
+        best
+    }
+
+    /// `self`, or the subframe `model` makes of it if that codes smaller.
+    fn cheaper(self, model: Model, order: usize, residual: Vec<i32>, settings: &Level) -> Self {
+        let rice = Rice::plan(&residual, order, settings.max_partition_order);
+        let header = match &model {
+            Model::Lpc {
+                coefficients,
+                precision,
+                ..
+            } => 9 + u64::from(*precision) * coefficients.len() as u64,
+            _ => 0,
+        };
+        let bits = 8 + (order as u64) * u64::from(self.bits_per_sample) + header + rice.bits;
+        if bits >= self.bits {
+            return self;
+        }
+        Self {
+            model,
+            order,
+            residual,
+            rice,
+            bits,
+            ..self
+        }
+    }
+
+    fn write(&self, writer: &mut BitWriter) {
+        let kind = match &self.model {
+            Model::Constant => 0,
+            Model::Verbatim => 1,
+            Model::Fixed => 0b001000 | self.order as u64,
+            Model::Lpc { .. } => 0b100000 | (self.order as u64 - 1),
+        };
+        // A zero pad bit, the type, and no wasted bits.
+        writer.write(kind << 1, 8);
+        let width = self.bits_per_sample;
+        match &self.model {
+            Model::Constant => writer.write_signed(self.samples[0], width),
+            Model::Verbatim => {
+                for &sample in &self.samples {
+                    writer.write_signed(sample, width);
+                }
+            }
+            Model::Fixed | Model::Lpc { .. } => {
+                for &sample in &self.samples[..self.order] {
+                    writer.write_signed(sample, width);
+                }
+                if let Model::Lpc {
+                    coefficients,
+                    precision,
+                    shift,
+                } = &self.model
+                {
+                    writer.write(u64::from(precision - 1), 4);
+                    writer.write(u64::from(*shift), 5);
+                    for &coefficient in coefficients {
+                        writer.write_signed(coefficient, *precision);
+                    }
+                }
+                self.rice.write(&self.residual, self.order, writer);
+            }
+        }
+    }
+}
+
+/// The residual of fixed polynomial predictor `order` (0-4), after the
+/// warm-up samples.
+fn fixed_residual(samples: &[i32], order: usize) -> Vec<i32> {
+    const COEFFICIENTS: [&[i64]; 5] = [&[], &[1], &[2, -1], &[3, -3, 1], &[4, -6, 4, -1]];
+    let coefficients = COEFFICIENTS[order];
+    (order..samples.len())
+        .map(|index| {
+            let predicted: i64 = coefficients
+                .iter()
+                .enumerate()
+                .map(|(lag, &coefficient)| coefficient * i64::from(samples[index - lag - 1]))
+                .sum();
+            (i64::from(samples[index]) - predicted) as i32
+        })
+        .collect()
+}
+
+/// The linear predictors of every order up to `max_order`, from the
+/// Tukey-windowed autocorrelation by Levinson-Durbin recursion.
+fn lpc_coefficients(samples: &[i32], max_order: usize) -> Vec<Vec<f64>> {
+    let len = samples.len();
+    let taper = len / 4;
+    let windowed: Vec<f64> = samples
+        .iter()
+        .enumerate()
+        .map(|(index, &sample)| {
+            let edge = index.min(len - 1 - index);
+            let weight = if edge < taper {
+                0.5 - 0.5 * (std::f64::consts::PI * edge as f64 / taper as f64).cos()
+            } else {
+                1.0
+            };
+            f64::from(sample) * weight
+        })
+        .collect();
+    let autocorrelation: Vec<f64> = (0..=max_order)
+        .map(|lag| {
+            windowed[lag..]
+                .iter()
+                .zip(&windowed)
+                .map(|(a, b)| a * b)
+                .sum()
+        })
+        .collect();
+
+    let mut orders = Vec::new();
+    let mut error = autocorrelation[0];
+    let mut lpc = vec![0.0; max_order];
+    for order in 0..max_order {
+        if error <= 0.0 {
+            break;
+        }
+        let mut reflection = -autocorrelation[order + 1];
+        for lag in 0..order {
+            reflection -= lpc[lag] * autocorrelation[order - lag];
+        }
+        reflection /= error;
+        lpc[order] = reflection;
+        for lag in 0..order / 2 {
+            let (low, high) = (lpc[lag], lpc[order - 1 - lag]);
+            lpc[lag] = low + reflection * high;
+            lpc[order - 1 - lag] = high + reflection * low;
+        }
+        if order % 2 == 1 {
+            lpc[order / 2] += lpc[order / 2] * reflection;
+        }
+        error *= 1.0 - reflection * reflection;
+        orders.push(
+            lpc[..=order]
+                .iter()
+                .map(|coefficient| -coefficient)
+                .collect(),
+        );
+    }
+    orders
+}
+
+/// libFLAC's coefficient precision for a block length.
+fn lpc_precision(len: usize) -> u32 {
+    match len {
+        0..=192 => 7,
+        193..=384 => 8,
+        385..=576 => 9,
+        577..=1152 => 10,
+        1153..=2304 => 11,
+        2305..=4608 => 12,
+        _ => 13,
+    }
+}
+
+/// Rounds `coefficients` to signed `precision`-bit integers scaled by
+/// `2^shift`, carrying each rounding error into the next coefficient.
+fn quantise(coefficients: &[f64], precision: u32) -> Option<(Vec<i32>, u32)> {
+    let max = coefficients.iter().fold(0.0f64, |max, c| max.max(c.abs()));
+    if max <= 0.0 || !max.is_finite() {
+        return None;
+    }
+    let limit = 1i64 << (precision - 1);
+    // Decoders take shifts of 0 to 15.
+    let shift = (precision as i32 - 1) - (max.log2().floor() as i32 + 1);
+    let shift = u32::try_from(shift.min(15)).ok()?;
+    let mut error = 0.0;
+    let quantised = coefficients
+        .iter()
+        .map(|&coefficient| {
+            error += coefficient * f64::from(1u32 << shift);
+            let value = (error.round() as i64).clamp(-limit, limit - 1);
+            error -= value as f64;
+            value as i32
+        })
+        .collect();
+    Some((quantised, shift))
+}
+
+/// The residual of a quantised LPC predictor, or `None` where it overflows
+/// the 32 bits decoders hold it in.
+fn lpc_residual(samples: &[i32], coefficients: &[i32], shift: u32) -> Option<Vec<i32>> {
+    let order = coefficients.len();
+    (order..samples.len())
+        .map(|index| {
+            let predicted: i64 = coefficients
+                .iter()
+                .enumerate()
+                .map(|(lag, &c)| i64::from(c) * i64::from(samples[index - lag - 1]))
+                .sum();
+            i32::try_from(i64::from(samples[index]) - (predicted >> shift)).ok()
+        })
+        .collect()
+}
+
+/// A partitioned Rice coding of a residual.
+#[derive(Default)]
+struct Rice {
+    partition_order: u32,
+    parameters: Vec<u32>,
+    /// The estimated coded size.
+    bits: u64,
+}
+
+impl Rice {
+    /// Picks the partition order and parameters that code `residual`
+    /// smallest, for a block whose first `order` samples are warm-up.
+    fn plan(residual: &[i32], order: usize, max_partition_order: u32) -> Self {
+        let len = residual.len() + order;
+        // Partitions split the block evenly, and the first one must hold
+        // more than the warm-up.
+        let max_partition_order = (0..=max_partition_order)
+            .rev()
+            .find(|&p| len.is_multiple_of(1 << p) && (len >> p) > order)
+            .unwrap_or(0);
+        let mut sums: Vec<u64> = partition_bounds(len, order, max_partition_order)
+            .map(|range| residual[range].iter().map(|&r| zigzag(r)).sum())
+            .collect();
+
+        let mut best: Option<Self> = None;
+        for partition_order in (0..=max_partition_order).rev() {
+            let lengths = partition_bounds(len, order, partition_order).map(|range| range.len());
+            let mut bits = 6;
+            let mut parameters = Vec::new();
+            for (length, &sum) in lengths.zip(&sums) {
+                let (parameter, cost) = rice_parameter(length as u64, sum);
+                parameters.push(parameter);
+                bits += cost;
+            }
+            let escape = parameters.iter().any(|&p| p >= RICE_ESCAPE);
+            bits += parameters.len() as u64 * if escape { 5 } else { 4 };
+            if best.as_ref().is_none_or(|best| bits < best.bits) {
+                best = Some(Self {
+                    partition_order,
+                    parameters,
+                    bits,
+                });
+            }
+            sums = sums.chunks(2).map(|pair| pair.iter().sum()).collect();
+        }
+        best.expect("partition order 0 is always possible")
+    }
+
+    fn write(&self, residual: &[i32], order: usize, writer: &mut BitWriter) {
+        let escape = self.parameters.iter().any(|&p| p >= RICE_ESCAPE);
+        writer.write(u64::from(escape), 2);
+        writer.write(u64::from(self.partition_order), 4);
+        let len = residual.len() + order;
+        let ranges = partition_bounds(len, order, self.partition_order);
+        for (range, &parameter) in ranges.zip(&self.parameters) {
+            writer.write(u64::from(parameter), if escape { 5 } else { 4 });
+            for &value in &residual[range] {
+                let value = zigzag(value);
+                writer.write_unary(value >> parameter);
+                writer.write(value, parameter);
+            }
+        }
+    }
+}
+
+/// The residual indices of each partition of a `len`-sample block split
+/// `2^partition_order` ways; the first partition loses the warm-up.
+fn partition_bounds(
+    len: usize,
+    order: usize,
+    partition_order: u32,
+) -> impl Iterator<Item = std::ops::Range<usize>> {
+    let size = len >> partition_order;
+    (0..1usize << partition_order).map(move |index| {
+        let start = (index * size).saturating_sub(order);
+        start..(index + 1) * size - order
+    })
+}
+
+fn zigzag(value: i32) -> u64 {
+    let value = i64::from(value);
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// The Rice parameter that codes `count` values summing to `sum` in the
+/// fewest bits, estimating each value's quotient from the mean.
+fn rice_parameter(count: u64, sum: u64) -> (u32, u64) {
+    (0..=MAX_RICE_PARAMETER)
+        .map(|parameter| {
+            (
+                parameter,
+                count * u64::from(parameter + 1) + (sum >> parameter),
+            )
+        })
+        .min_by_key(|&(_, bits)| bits)
+        .expect("parameters to try")
+}
+
+/// Packs bits most significant first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    filled: u32,
+}
+
+impl BitWriter {
+    /// Writes the low `count` (at most 32) bits of `value`.
+    fn write(&mut self, value: u64, count: u32) {
+        if count == 0 {
+            return;
+        }
+        self.buffer = (self.buffer << count) | (value & ((1 << count) - 1));
+        self.filled += count;
+        while self.filled >= 8 {
+            self.filled -= 8;
+            self.bytes.push((self.buffer >> self.filled) as u8);
+        }
+    }
+
+    fn write_signed(&mut self, value: i32, count: u32) {
+        self.write(value as u64, count);
+    }
+
+    /// Writes `value` zeros and a one.
+    fn write_unary(&mut self, mut value: u64) {
+        while value >= 32 {
+            self.write(0, 32);
+            value -= 32;
+        }
+        self.write(1, value as u32 + 1);
+    }
+
+    /// Pads with zero bits to a byte boundary and returns the bytes.
+    fn finish(mut self) -> Vec<u8> {
+        if self.filled > 0 {
+            self.write(0, 8 - self.filled);
+        }
+        self.bytes
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ (u16::from(byte) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::{AudioBuffer, ChannelLayout};
+
+    /// A stereo stream of a decaying chord on the left and the left channel
+    /// slightly delayed on the right, at 16-bit precision.
+    fn stream(frames: usize) -> AudioStream {
+        let mut samples = Vec::new();
+        for index in 0..frames {
+            let tone = |at: usize| {
+                let t = at as f64 / 44_100.0;
+                let value = (0.4 * (t * 440.0 * std::f64::consts::TAU).sin()
+                    + 0.2 * (t * 660.0 * std::f64::consts::TAU).sin())
+                    * (-t).exp();
+                (value * 32768.0).round() / 32768.0
+            };
+            samples.push(tone(index) as f32);
+            samples.push(tone(index.saturating_sub(3)) as f32);
+        }
+        AudioStream {
+            codec: AudioCodec::PcmS16,
+            buffers: vec![AudioBuffer {
+                sample_rate: 44_100,
+                channel_layout: ChannelLayout::from_channel_count(2).unwrap(),
+                samples,
+            }],
+        }
+    }
+
+    #[test]
+    fn every_level_round_trips_losslessly() {
+        // Not a multiple of any block size, so the last block is short.
+        let input = stream(10_000);
+        assert_eq!(fitting_bit_depth(&input), 16);
+        let raw_size = 10_000 * 2 * 2;
+        let mut sizes = Vec::new();
+        for level in 0..=8 {
+            let file = encode(&input, 16, level).unwrap();
+            assert!(is_flac(&file));
+            let decoded = decode(&file).unwrap();
+            assert_eq!(decoded.codec.name(), "flac");
+            assert_eq!(decoded.buffers[0].sample_rate, 44_100);
+            assert_eq!(decoded.buffers[0].samples, input.buffers[0].samples);
+            sizes.push(file.len());
+        }
+        assert!(sizes.iter().all(|&size| size < raw_size / 2));
+        assert!(sizes[8] < sizes[0]);
+
+        // The stream info's MD5 follows the marker, block header and 18
+        // bytes of fields.
+        let mut file = encode(&input, 16, 0).unwrap();
+        file[26] ^= 1;
+        assert!(decode(&file).is_err());
+    }
+
+    #[test]
+    fn writes_deeper_and_odd_sized_streams() {
+        let mut input = stream(300);
+        input.buffers[0].samples[7] = 0.123_456_79;
+        assert_eq!(fitting_bit_depth(&input), 24);
+        let decoded = decode(&encode(&input, 24, 5).unwrap()).unwrap();
+        let error = (decoded.buffers[0].samples[7] - 0.123_456_79).abs();
+        assert!(error <= 0.5 / 8_388_608.0);
+
+        // Silence codes as constant subframes.
+        let mut silence = stream(5000);
+        silence.buffers[0].samples.fill(0.0);
+        let file = encode(&silence, 16, 5).unwrap();
+        assert!(file.len() < 100);
+        assert_eq!(decode(&file).unwrap().buffers[0].samples.len(), 10_000);
+
+        assert!(encode(&input, 32, 5).is_err());
+        assert!(encode(&input, 16, 9).is_err());
+        assert!(decode(b"fLaC but not really").is_err());
+    }
+}
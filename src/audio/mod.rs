@@ -0,0 +1,114 @@
+//! Audio decoders and encoders, between files and the
+//! [`AudioStream`](crate::video::AudioStream)s of
+//! [`MediaStreams`](crate::video::MediaStreams).
+
+pub mod aac;
+pub mod channels;
+pub mod container;
+pub mod edit;
+#[cfg(any(feature = "mp3lame", feature = "opus", feature = "aac"))]
+mod ffmpeg;
+pub mod flac;
+pub mod mp3;
+pub mod ogg;
+pub mod opus;
+pub mod pcm;
+pub mod resample;
+pub mod wav;
+
+use std::io::Cursor;
+
+use anyhow::{Context, Result, anyhow, bail};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::MediaSourceStream;
+
+use crate::video::{AudioBuffer, AudioCodec, AudioStream, ChannelLayout};
+
+/// How [`decode_track`] treats a file.
+struct TrackOptions {
+    /// The format's name for messages, such as `MP3`.
+    name: &'static str,
+    codec: AudioCodec,
+    /// Skip packets that fail to decode, as players do with lossy streams,
+    /// rather than failing the file.
+    skip_damaged: bool,
+    /// Check the decoded audio against the checksum the file stores.
+    verify: bool,
+}
+
+/// Decodes the default track of `data` with symphonia's reader `R` and
+/// decoder `D` into one float buffer.
+fn decode_track<R: FormatReader, D: Decoder>(
+    data: Vec<u8>,
+    options: TrackOptions,
+) -> Result<AudioStream> {
+    let name = options.name;
+    let source = MediaSourceStream::new(Box::new(Cursor::new(data)), Default::default());
+    let format_options = FormatOptions {
+        enable_gapless: true,
+        ..Default::default()
+    };
+    let mut reader =
+        R::try_new(source, &format_options).with_context(|| format!("no {name} frames found"))?;
+    let params = reader
+        .default_track()
+        .ok_or_else(|| anyhow!("{name} file has no audio track"))?
+        .codec_params
+        .clone();
+    let decoder_options = DecoderOptions {
+        verify: options.verify,
+    };
+    let mut decoder = D::try_new(&params, &decoder_options)?;
+
+    let mut samples = Vec::new();
+    let mut format = None;
+    loop {
+        let packet = match reader.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(error))
+                if error.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                break;
+            }
+            Err(error) => {
+                return Err(error).with_context(|| format!("failed to read a {name} frame"));
+            }
+        };
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            Err(SymphoniaError::DecodeError(_)) if options.skip_damaged => continue,
+            Err(error) => {
+                return Err(error).with_context(|| format!("failed to decode a {name} frame"));
+            }
+        };
+        let spec = *decoded.spec();
+        let frame_format = (spec.rate, spec.channels.count());
+        if *format.get_or_insert(frame_format) != frame_format {
+            bail!("{name} frames change sample rate or channel count mid-stream");
+        }
+        let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        buffer.copy_interleaved_ref(decoded);
+        samples.extend_from_slice(buffer.samples());
+    }
+    if decoder.finalize().verify_ok == Some(false) {
+        bail!("{name} audio does not match the checksum the file stores");
+    }
+    let Some((sample_rate, channels)) = format else {
+        bail!("{name} file has no decodable frames");
+    };
+    let channel_layout = u16::try_from(channels)
+        .ok()
+        .and_then(ChannelLayout::from_channel_count)
+        .ok_or_else(|| anyhow!("{name} stream has {channels} channels"))?;
+    Ok(AudioStream {
+        codec: options.codec,
+        buffers: vec![AudioBuffer {
+            sample_rate,
+            channel_layout,
+            samples,
+        }],
+    })
+}
@@ -0,0 +1,133 @@
+//! MP3: MPEG-1/2 Layer III streams, optionally led by an ID3v2 tag. Decoding
+//! is symphonia's; encoding goes through libmp3lame with the `mp3lame`
+//! feature.
+
+use anyhow::{Result, anyhow, bail};
+use symphonia::default::codecs::MpaDecoder;
+use symphonia::default::formats::MpaReader;
+
+use super::{TrackOptions, decode_track};
+use crate::video::{AudioCodec, AudioStream};
+
+/// Whether `data` starts like an MP3 file: with an ID3v2 tag or a Layer III
+/// frame header.
+pub fn is_mp3(data: &[u8]) -> bool {
+    if data.starts_with(b"ID3") {
+        return true;
+    }
+    let [first, second, third, ..] = *data else {
+        return false;
+    };
+    let sync = first == 0xFF && second & 0xE0 == 0xE0;
+    // Version 0b01 is reserved; layer 0b01 is Layer III.
+    let version_and_layer = (second >> 3) & 0b11 != 0b01 && (second >> 1) & 0b11 == 0b01;
+    let bitrate = third >> 4;
+    let sample_rate = (third >> 2) & 0b11;
+    sync && version_and_layer && bitrate != 0b1111 && sample_rate != 0b11
+}
+
+/// The length of the ID3v2 tag at the start of `data`, if there is one.
+fn id3v2_len(data: &[u8]) -> usize {
+    if data.len() < 10 || !data.starts_with(b"ID3") {
+        return 0;
+    }
+    // The size is syncsafe: 7 bits per byte, and leaves out the header and
+    // the footer the flags may announce.
+    let size = data[6..10]
+        .iter()
+        .fold(0usize, |size, &byte| (size << 7) | usize::from(byte & 0x7F));
+    let footer = if data[5] & 0x10 != 0 { 10 } else { 0 };
+    (10 + size + footer).min(data.len())
+}
+
+/// Decodes an MP3 file into float samples. Frames that fail to decode are
+/// skipped, as players do, rather than failing the file.
+pub fn decode(data: &[u8]) -> Result<AudioStream> {
+    let options = TrackOptions {
+        name: "MP3",
+        codec: AudioCodec::Mp3,
+        skip_damaged: true,
+        verify: false,
+    };
+    decode_track::<MpaReader, MpaDecoder>(data[id3v2_len(data)..].to_vec(), options)
+}
+
+/// The sample rates MPEG-1, MPEG-2 and MPEG-2.5 Layer III can carry.
+const SAMPLE_RATES: [u32; 9] = [8000, 11025, 12000, 16000, 22050, 24000, 32000, 44100, 48000];
+
+/// Encodes `stream`'s first buffer as a constant bit rate MP3 file of
+/// `bit_rate` bits per second.
+pub fn encode(stream: &AudioStream, bit_rate: u32) -> Result<Vec<u8>> {
+    let buffer = stream
+        .buffers
+        .first()
+        .ok_or_else(|| anyhow!("audio stream has no samples"))?;
+    let channels = buffer.channel_layout.channel_count();
+    if !(1..=2).contains(&channels) {
+        bail!("MP3 carries mono or stereo audio, not {channels} channels");
+    }
+    if !SAMPLE_RATES.contains(&buffer.sample_rate) {
+        bail!(
+            "MP3 cannot carry audio at {} Hz; resample it to one of {SAMPLE_RATES:?}",
+            buffer.sample_rate
+        );
+    }
+    #[cfg(feature = "mp3lame")]
+    {
+        use ffmpeg_next::format::{Sample, sample::Type};
+
+        let spec = super::ffmpeg::EncoderSpec {
+            name: "libmp3lame",
+            format: Sample::F32(Type::Planar),
+            bit_rate,
+        };
+        let encoded = super::ffmpeg::encode(spec, buffer)?;
+        // An MP3 file is its frames back to back.
+        Ok(encoded
+            .packets
+            .into_iter()
+            .flat_map(|packet| packet.data)
+            .collect())
+    }
+    #[cfg(not(feature = "mp3lame"))]
+    {
+        let _ = bit_rate;
+        bail!("MP3 encoding requires building with the mp3lame feature")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `count` silent MPEG-1 Layer III frames: 128 kbps, 44.1 kHz, mono.
+    fn silent_frames(count: usize) -> Vec<u8> {
+        let mut frame = vec![0; 417];
+        frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x90, 0xC0]);
+        frame.repeat(count)
+    }
+
+    #[test]
+    fn decodes_frames_after_an_id3_tag() {
+        let mut file = b"ID3\x04\0\0\0\0\0\x05\xFF\xFB\x90\xC0\0".to_vec();
+        file.extend(silent_frames(3));
+        assert!(is_mp3(&file));
+        let stream = decode(&file).unwrap();
+        assert_eq!(stream.codec.name(), "mp3");
+        let buffer = &stream.buffers[0];
+        assert_eq!(buffer.sample_rate, 44100);
+        assert_eq!(buffer.channel_layout.channel_count(), 1);
+        assert_eq!(buffer.samples.len(), 3 * 1152);
+        assert!(buffer.samples.iter().all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn sniffs_layer_three_headers_only() {
+        assert!(is_mp3(&silent_frames(1)));
+        // Layer II, then a free-format bit rate index of 15.
+        assert!(!is_mp3(&[0xFF, 0xFD, 0x90, 0xC0]));
+        assert!(!is_mp3(&[0xFF, 0xFB, 0xF0, 0xC0]));
+        assert!(!is_mp3(b"RIFF"));
+        assert!(decode(b"not an mp3 file").is_err());
+    }
+}
@@ -0,0 +1,256 @@
+//! Ogg pages: the framing `.opus` files store their packets in. Reads the
+//! first logical stream of a file and writes a single one.
+
+use anyhow::{Result, bail};
+
+const CAPTURE: &[u8; 4] = b"OggS";
+const HEADER_LEN: usize = 27;
+/// The page continues a packet from the page before it.
+const CONTINUED: u8 = 0x01;
+const BEGINNING_OF_STREAM: u8 = 0x02;
+const END_OF_STREAM: u8 = 0x04;
+/// Granule position of a page on which no packet ends.
+const NO_GRANULE: u64 = u64::MAX;
+const MAX_SEGMENTS: usize = 255;
+
+/// The packets of a logical stream.
+pub struct Stream {
+    pub packets: Vec<Vec<u8>>,
+    /// The last granule position a page recorded: for Opus, the 48 kHz
+    /// sample count up to the end of its last complete packet.
+    pub granule_position: Option<u64>,
+}
+
+/// A packet to write, with the granule position at its end.
+pub struct Packet<'a> {
+    pub data: &'a [u8],
+    pub granule_position: u64,
+    /// Close the page after this packet, as each header packet needs.
+    pub flush: bool,
+}
+
+pub fn is_ogg(data: &[u8]) -> bool {
+    data.starts_with(CAPTURE)
+}
+
+/// Reads the packets of the file's first logical stream. Pages of other
+/// streams are skipped, and reading stops at a truncated page.
+pub fn read(data: &[u8]) -> Result<Stream> {
+    let mut stream = Stream {
+        packets: Vec::new(),
+        granule_position: None,
+    };
+    let mut serial = None;
+    let mut packet = Vec::new();
+    let mut at = 0;
+    while data.len() - at >= HEADER_LEN {
+        let page = &data[at..];
+        if !page.starts_with(CAPTURE) || page[4] != 0 {
+            bail!("malformed Ogg page at byte {at}");
+        }
+        let segments = usize::from(page[26]);
+        let Some(table) = page.get(HEADER_LEN..HEADER_LEN + segments) else {
+            break;
+        };
+        let body_len: usize = table.iter().map(|&lacing| usize::from(lacing)).sum();
+        let page_len = HEADER_LEN + segments + body_len;
+        if page.len() < page_len {
+            break;
+        }
+        let page = &page[..page_len];
+        let stored = u32::from_le_bytes([page[22], page[23], page[24], page[25]]);
+        if checksum(page) != stored {
+            bail!("Ogg page at byte {at} fails its checksum");
+        }
+        at += page_len;
+
+        let flags = page[5];
+        let page_serial = u32::from_le_bytes([page[14], page[15], page[16], page[17]]);
+        if *serial.get_or_insert(page_serial) != page_serial {
+            continue;
+        }
+        // A packet left open before a page that does not continue it was
+        // cut short; drop it.
+        if flags & CONTINUED == 0 {
+            packet.clear();
+        }
+        let mut body = &page[HEADER_LEN + segments..];
+        for &lacing in table {
+            let (segment, rest) = body.split_at(usize::from(lacing));
+            packet.extend_from_slice(segment);
+            body = rest;
+            if lacing < 255 {
+                stream.packets.push(std::mem::take(&mut packet));
+            }
+        }
+        let granule = u64::from_le_bytes(page[6..14].try_into().expect("eight bytes"));
+        if granule != NO_GRANULE {
+            stream.granule_position = Some(granule);
+        }
+        if flags & END_OF_STREAM != 0 {
+            break;
+        }
+    }
+    if serial.is_none() {
+        bail!("no Ogg pages found");
+    }
+    Ok(stream)
+}
+
+/// Writes `packets` as logical stream `serial`, filling pages up to their
+/// 255 segments unless a packet asks to flush.
+pub fn write(serial: u32, packets: &[Packet<'_>]) -> Vec<u8> {
+    let mut pages = Vec::new();
+    let mut page = PageBuilder::default();
+    for (index, packet) in packets.iter().enumerate() {
+        let mut rest = packet.data;
+        loop {
+            while page.table.len() < MAX_SEGMENTS && rest.len() >= 255 {
+                page.table.push(255);
+                page.body.extend_from_slice(&rest[..255]);
+                rest = &rest[255..];
+            }
+            if page.table.len() < MAX_SEGMENTS {
+                // A packet ends on its first segment shorter than 255
+                // bytes, which may be empty.
+                page.table.push(rest.len() as u8);
+                page.body.extend_from_slice(rest);
+                page.granule_position = packet.granule_position;
+                break;
+            }
+            pages.push(std::mem::take(&mut page));
+            page.continued = true;
+        }
+        let last = index + 1 == packets.len();
+        if packet.flush || last || page.table.len() == MAX_SEGMENTS {
+            pages.push(std::mem::take(&mut page));
+        }
+    }
+
+    let mut out = Vec::new();
+    let count = pages.len();
+    for (sequence, page) in pages.into_iter().enumerate() {
+        let mut flags = if page.continued { CONTINUED } else { 0 };
+        if sequence == 0 {
+            flags |= BEGINNING_OF_STREAM;
+        }
+        if sequence + 1 == count {
+            flags |= END_OF_STREAM;
+        }
+        let start = out.len();
+        out.extend_from_slice(CAPTURE);
+        out.extend([0, flags]);
+        out.extend(page.granule_position.to_le_bytes());
+        out.extend(serial.to_le_bytes());
+        out.extend((sequence as u32).to_le_bytes());
+        out.extend([0; 4]);
+        out.push(page.table.len() as u8);
+        out.extend(page.table);
+        out.extend(page.body);
+        let crc = checksum(&out[start..]);
+        out[start + 22..start + 26].copy_from_slice(&crc.to_le_bytes());
+    }
+    out
+}
+
+struct PageBuilder {
+    continued: bool,
+    granule_position: u64,
+    table: Vec<u8>,
+    body: Vec<u8>,
+}
+
+impl Default for PageBuilder {
+    fn default() -> Self {
+        Self {
+            continued: false,
+            granule_position: NO_GRANULE,
+            table: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+}
+
+/// The page's CRC-32 (polynomial 0x04C11DB7, unreflected), computed with
+/// its own checksum field as zeros.
+fn checksum(page: &[u8]) -> u32 {
+    page.iter().enumerate().fold(0, |crc, (index, &byte)| {
+        let byte = if (22..26).contains(&index) { 0 } else { byte };
+        (crc << 8) ^ CRC_TABLE[usize::from((crc >> 24) as u8 ^ byte)]
+    })
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut remainder = (index as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            remainder = if remainder & 0x8000_0000 != 0 {
+                (remainder << 1) ^ 0x04C1_1DB7
+            } else {
+                remainder << 1
+            };
+            bit += 1;
+        }
+        table[index] = remainder;
+        index += 1;
+    }
+    table
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_round_trip_across_pages() {
+        let head = b"head".to_vec();
+        // Long enough to span two pages, and a multiple of 255 bytes.
+        let long = vec![7; 255 * 300];
+        let short = vec![9; 10];
+        let packets = [
+            Packet {
+                data: &head,
+                granule_position: 0,
+                flush: true,
+            },
+            Packet {
+                data: &long,
+                granule_position: 960,
+                flush: false,
+            },
+            Packet {
+                data: &short,
+                granule_position: 1920,
+                flush: false,
+            },
+        ];
+        let file = write(0x1234, &packets);
+        assert!(is_ogg(&file));
+        // The header page alone, then 255 segments, then the rest.
+        assert_eq!(file[5], BEGINNING_OF_STREAM);
+        assert_eq!(file[26], 1);
+
+        let stream = read(&file).unwrap();
+        assert_eq!(stream.packets, [head, long, short]);
+        assert_eq!(stream.granule_position, Some(1920));
+    }
+
+    #[test]
+    fn damaged_pages_are_rejected() {
+        let data = [1, 2, 3];
+        let packets = [Packet {
+            data: &data,
+            granule_position: 3,
+            flush: true,
+        }];
+        let mut file = write(1, &packets);
+        assert_eq!(read(&file).unwrap().packets, [data.to_vec()]);
+        let last = file.len() - 1;
+        file[last] ^= 0xFF;
+        assert!(read(&file).is_err());
+        assert!(read(b"not an ogg file at all, no").is_err());
+    }
+}
@@ -0,0 +1,277 @@
+//! Opus in Ogg, as `.opus` files store it: an `OpusHead` packet, an
+//! `OpusTags` packet, then the audio. The coding itself goes through
+//! libavcodec with the `opus` feature.
+
+use anyhow::{Context, Result, anyhow, bail};
+
+use super::ogg;
+#[cfg(feature = "opus")]
+use crate::video::{AudioBuffer, AudioCodec, ChannelLayout};
+use crate::video::{AudioStream, EncodedAudio};
+
+/// Opus always decodes at 48 kHz; granule positions count in it too.
+pub const SAMPLE_RATE: u32 = 48_000;
+/// The input rates libopus encodes at.
+const ENCODER_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000];
+/// Logical stream serial of written files; one stream needs no unique one.
+const SERIAL: u32 = 0x4F70_7573;
+const VENDOR: &[u8] = b"bunker-convert";
+
+/// The fields of an `OpusHead` packet that decoding needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpusHead {
+    pub channels: u8,
+    /// 48 kHz frames of encoder delay to drop from the start.
+    pub pre_skip: u16,
+    /// The rate of the audio before encoding, for information.
+    pub input_sample_rate: u32,
+}
+
+impl OpusHead {
+    pub fn parse(packet: &[u8]) -> Result<Self> {
+        if packet.len() < 19 || !packet.starts_with(b"OpusHead") {
+            bail!("not an OpusHead packet");
+        }
+        // Only the major version, in the top four bits, breaks parsing.
+        if packet[8] >> 4 != 0 {
+            bail!("unsupported OpusHead version {}", packet[8]);
+        }
+        let channels = packet[9];
+        // Mapping families other than 0 append a table of a stream count,
+        // a coupled count and a byte per channel.
+        let mapping_family = packet[18];
+        if channels == 0 || (mapping_family != 0 && packet.len() < 21 + usize::from(channels)) {
+            bail!("OpusHead channel mapping is malformed");
+        }
+        Ok(Self {
+            channels,
+            pre_skip: u16::from_le_bytes([packet[10], packet[11]]),
+            input_sample_rate: u32::from_le_bytes([packet[12], packet[13], packet[14], packet[15]]),
+        })
+    }
+}
+
+/// Whether `data` starts like an Ogg Opus file: an Ogg page whose first
+/// packet is an `OpusHead`.
+pub fn is_opus(data: &[u8]) -> bool {
+    ogg::is_ogg(data)
+        && data.get(26).is_some_and(|&segments| {
+            let start = 27 + usize::from(segments);
+            data.get(start..start + 8) == Some(b"OpusHead".as_slice())
+        })
+}
+
+/// Decodes an Ogg Opus file into float samples at 48 kHz, trimmed to the
+/// length its last granule position records.
+pub fn decode(data: &[u8]) -> Result<AudioStream> {
+    let stream = ogg::read(data)?;
+    let [head, tags, audio @ ..] = stream.packets.as_slice() else {
+        bail!("Ogg Opus file is missing its header packets");
+    };
+    let parsed = OpusHead::parse(head)?;
+    if !tags.starts_with(b"OpusTags") {
+        bail!("Ogg Opus file has no OpusTags packet");
+    }
+    let frames = stream
+        .granule_position
+        .map(|granule| granule.saturating_sub(u64::from(parsed.pre_skip)));
+    decode_parsed(head, parsed, audio, frames)
+}
+
+/// Decodes Opus `packets` as containers other than Ogg store them, with
+/// `head` the stream's `OpusHead` packet.
+pub fn decode_packets(head: &[u8], packets: &[Vec<u8>]) -> Result<AudioStream> {
+    decode_parsed(head, OpusHead::parse(head)?, packets, None)
+}
+
+#[cfg(feature = "opus")]
+fn decode_parsed(
+    head: &[u8],
+    parsed: OpusHead,
+    packets: &[Vec<u8>],
+    frames: Option<u64>,
+) -> Result<AudioStream> {
+    let channels = u16::from(parsed.channels);
+    let channel_layout = ChannelLayout::from_channel_count(channels)
+        .ok_or_else(|| anyhow!("Opus stream has {channels} channels"))?;
+    let spec = super::ffmpeg::DecoderSpec {
+        name: "opus",
+        id: ffmpeg_next::codec::Id::OPUS,
+        label: "Opus",
+    };
+    let mut samples = super::ffmpeg::decode(spec, head, packets)?.samples;
+    // The last packet decodes whole; the granule position says how much of
+    // it is audio.
+    if let Some(frames) = frames {
+        let len = usize::try_from(frames)
+            .unwrap_or(usize::MAX)
+            .saturating_mul(usize::from(channels));
+        samples.truncate(len);
+    }
+    Ok(AudioStream {
+        codec: AudioCodec::Opus,
+        buffers: vec![AudioBuffer {
+            sample_rate: SAMPLE_RATE,
+            channel_layout,
+            samples,
+        }],
+    })
+}
+
+#[cfg(not(feature = "opus"))]
+fn decode_parsed(
+    _head: &[u8],
+    _parsed: OpusHead,
+    _packets: &[Vec<u8>],
+    _frames: Option<u64>,
+) -> Result<AudioStream> {
+    bail!("Opus decoding requires building with the opus feature")
+}
+
+/// Encodes `stream`'s first buffer with libopus at `bit_rate` bits per
+/// second, into packets counted at 48 kHz.
+pub fn encode(stream: &AudioStream, bit_rate: u32) -> Result<EncodedAudio> {
+    let buffer = stream
+        .buffers
+        .first()
+        .ok_or_else(|| anyhow!("audio stream has no samples"))?;
+    let channels = buffer.channel_layout.channel_count();
+    if !(1..=8).contains(&channels) {
+        bail!("Opus carries 1 to 8 channels, not {channels}");
+    }
+    if !ENCODER_RATES.contains(&buffer.sample_rate) {
+        bail!(
+            "Opus cannot encode audio at {} Hz; resample it to 48000 Hz",
+            buffer.sample_rate
+        );
+    }
+    #[cfg(feature = "opus")]
+    {
+        use ffmpeg_next::format::{Sample, sample::Type};
+
+        let spec = super::ffmpeg::EncoderSpec {
+            name: "libopus",
+            format: Sample::F32(Type::Packed),
+            bit_rate,
+        };
+        let encoded = super::ffmpeg::encode(spec, buffer)?;
+        let head = OpusHead::parse(&encoded.extradata).context("libopus wrote no OpusHead")?;
+        let scale = SAMPLE_RATE / buffer.sample_rate;
+        let mut packets = encoded.packets;
+        for packet in &mut packets {
+            packet.frames *= scale;
+        }
+        Ok(EncodedAudio {
+            codec: AudioCodec::Opus,
+            sample_rate: SAMPLE_RATE,
+            channels,
+            config: encoded.extradata,
+            priming: u32::from(head.pre_skip),
+            packets,
+        })
+    }
+    #[cfg(not(feature = "opus"))]
+    {
+        let _ = bit_rate;
+        bail!("Opus encoding requires building with the opus feature")
+    }
+}
+
+/// Writes `encoded` Opus packets as an Ogg Opus file.
+pub fn write_ogg(encoded: &EncodedAudio) -> Result<Vec<u8>> {
+    OpusHead::parse(&encoded.config).context("Opus stream has no valid OpusHead")?;
+    let mut tags = b"OpusTags".to_vec();
+    tags.extend((VENDOR.len() as u32).to_le_bytes());
+    tags.extend(VENDOR);
+    // No user comments.
+    tags.extend(0u32.to_le_bytes());
+
+    let mut packets = vec![
+        ogg::Packet {
+            data: &encoded.config,
+            granule_position: 0,
+            flush: true,
+        },
+        ogg::Packet {
+            data: &tags,
+            granule_position: 0,
+            flush: true,
+        },
+    ];
+    let mut granule_position = u64::from(encoded.priming);
+    for packet in &encoded.packets {
+        granule_position += u64::from(packet.frames);
+        packets.push(ogg::Packet {
+            data: &packet.data,
+            granule_position,
+            flush: false,
+        });
+    }
+    Ok(ogg::write(SERIAL, &packets))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::{AudioCodec, AudioPacket};
+
+    /// A mono, family 0 `OpusHead` with 312 frames of pre-skip.
+    fn head() -> Vec<u8> {
+        let mut head = b"OpusHead\x01\x01".to_vec();
+        head.extend(312u16.to_le_bytes());
+        head.extend(16_000u32.to_le_bytes());
+        head.extend([0, 0, 0]);
+        head
+    }
+
+    #[test]
+    fn written_files_carry_headers_and_granules() {
+        // Two packets of TOC-only silence, the second cut short.
+        let encoded = EncodedAudio {
+            codec: AudioCodec::Opus,
+            sample_rate: SAMPLE_RATE,
+            channels: 1,
+            config: head(),
+            priming: 312,
+            packets: vec![
+                AudioPacket {
+                    data: vec![0x08],
+                    frames: 960,
+                },
+                AudioPacket {
+                    data: vec![0x08],
+                    frames: 500,
+                },
+            ],
+        };
+        let file = write_ogg(&encoded).unwrap();
+        assert!(is_opus(&file));
+
+        let stream = ogg::read(&file).unwrap();
+        assert_eq!(stream.packets.len(), 4);
+        assert!(stream.packets[1].starts_with(b"OpusTags"));
+        assert_eq!(stream.granule_position, Some(312 + 960 + 500));
+        let parsed = OpusHead::parse(&stream.packets[0]).unwrap();
+        assert_eq!(
+            parsed,
+            OpusHead {
+                channels: 1,
+                pre_skip: 312,
+                input_sample_rate: 16_000,
+            }
+        );
+    }
+
+    #[test]
+    fn malformed_heads_are_rejected() {
+        assert!(OpusHead::parse(&head()[..18]).is_err());
+        let mut version_two = head();
+        version_two[8] = 0x20;
+        assert!(OpusHead::parse(&version_two).is_err());
+        // Family 1 without its mapping table.
+        let mut surround = head();
+        surround[18] = 1;
+        assert!(OpusHead::parse(&surround).is_err());
+        assert!(!is_opus(b"OggS"));
+    }
+}
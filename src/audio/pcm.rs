@@ -0,0 +1,245 @@
+//! Interleaved integer and float PCM, the samples WAV files and headerless
+//! dumps store.
+
+use anyhow::{Result, anyhow, bail};
+
+use crate::video::{
+    AudioBuffer, AudioCodec, AudioPacket, AudioStream, ChannelLayout, EncodedAudio,
+};
+
+/// Sample frames per packet when float PCM is cut up for muxing.
+const PACKET_FRAMES: usize = 1024;
+
+/// How one PCM sample is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleFormat {
+    U8,
+    S16 { big_endian: bool },
+    S24 { big_endian: bool },
+    S32 { big_endian: bool },
+    F32 { big_endian: bool },
+    F64 { big_endian: bool },
+}
+
+impl SampleFormat {
+    /// Parses FFmpeg's names for the formats: `u8`, `s16le`, `s24be`,
+    /// `f32le` and so on.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.trim().to_ascii_lowercase();
+        if name == "u8" {
+            return Some(Self::U8);
+        }
+        let (kind, big_endian) = match name.len().checked_sub(2).map(|end| name.split_at(end)) {
+            Some((kind, "le")) => (kind, false),
+            Some((kind, "be")) => (kind, true),
+            _ => return None,
+        };
+        match kind {
+            "s16" => Some(Self::S16 { big_endian }),
+            "s24" => Some(Self::S24 { big_endian }),
+            "s32" => Some(Self::S32 { big_endian }),
+            "f32" => Some(Self::F32 { big_endian }),
+            "f64" => Some(Self::F64 { big_endian }),
+            _ => None,
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        match self {
+            Self::U8 => 1,
+            Self::S16 { .. } => 2,
+            Self::S24 { .. } => 3,
+            Self::S32 { .. } | Self::F32 { .. } => 4,
+            Self::F64 { .. } => 8,
+        }
+    }
+
+    pub fn codec(self) -> AudioCodec {
+        match self {
+            Self::U8 => AudioCodec::PcmU8,
+            Self::S16 { .. } => AudioCodec::PcmS16,
+            Self::S24 { .. } => AudioCodec::PcmS24,
+            Self::S32 { .. } => AudioCodec::PcmS32,
+            Self::F32 { .. } => AudioCodec::PcmF32,
+            Self::F64 { .. } => AudioCodec::PcmF64,
+        }
+    }
+
+    pub fn big_endian(self) -> bool {
+        match self {
+            Self::U8 => false,
+            Self::S16 { big_endian }
+            | Self::S24 { big_endian }
+            | Self::S32 { big_endian }
+            | Self::F32 { big_endian }
+            | Self::F64 { big_endian } => big_endian,
+        }
+    }
+
+    /// One sample, scaled so integers span -1.0 up to just below 1.0.
+    fn read(self, bytes: &[u8]) -> f32 {
+        let fold = |value: u64, &byte: &u8| (value << 8) | u64::from(byte);
+        let raw = if self.big_endian() {
+            bytes.iter().fold(0, fold)
+        } else {
+            bytes.iter().rev().fold(0, fold)
+        };
+        let bits = 8 * bytes.len() as u32;
+        let scale = (1u64 << (bits - 1)) as f64;
+        match self {
+            Self::U8 => ((raw as f64 - scale) / scale) as f32,
+            Self::F32 { .. } => f32::from_bits(raw as u32),
+            Self::F64 { .. } => f64::from_bits(raw) as f32,
+            _ => {
+                let shift = 64 - bits;
+                (((raw << shift) as i64 >> shift) as f64 / scale) as f32
+            }
+        }
+    }
+
+    /// Appends one sample, clamped to the format's range.
+    fn write(self, sample: f32, out: &mut Vec<u8>) {
+        let bits = 8 * self.bytes() as u32;
+        let scale = (1u64 << (bits - 1)) as f64;
+        let integer = || {
+            (f64::from(sample) * scale)
+                .round()
+                .clamp(-scale, scale - 1.0) as i64
+        };
+        let raw = match self {
+            Self::U8 => (integer() + 128) as u64,
+            Self::F32 { .. } => u64::from(sample.to_bits()),
+            Self::F64 { .. } => f64::from(sample).to_bits(),
+            _ => integer() as u64,
+        };
+        let bytes = &raw.to_le_bytes()[..self.bytes()];
+        if self.big_endian() {
+            out.extend(bytes.iter().rev());
+        } else {
+            out.extend(bytes);
+        }
+    }
+}
+
+/// Encodes interleaved float `samples` as `format`.
+pub fn encode(samples: &[f32], format: SampleFormat) -> Vec<u8> {
+    let mut out = Vec::with_capacity(samples.len() * format.bytes());
+    for &sample in samples {
+        format.write(sample, &mut out);
+    }
+    out
+}
+
+/// Cuts `stream` into packets of little-endian float samples, as Matroska
+/// stores PCM.
+pub fn float_packets(stream: &AudioStream) -> Result<EncodedAudio> {
+    let first = stream
+        .buffers
+        .first()
+        .ok_or_else(|| anyhow!("audio stream has no samples"))?;
+    let channels = first.channel_layout.channel_count();
+    if channels == 0 || first.sample_rate == 0 {
+        bail!("audio needs at least one channel and a sample rate");
+    }
+    let mut packets = Vec::new();
+    for buffer in &stream.buffers {
+        if buffer.sample_rate != first.sample_rate
+            || buffer.channel_layout.channel_count() != channels
+        {
+            bail!("audio buffers change sample rate or channel count mid-stream");
+        }
+        for chunk in buffer.samples.chunks(PACKET_FRAMES * usize::from(channels)) {
+            packets.push(AudioPacket {
+                data: encode(chunk, SampleFormat::F32 { big_endian: false }),
+                frames: (chunk.len() / usize::from(channels)) as u32,
+            });
+        }
+    }
+    Ok(EncodedAudio {
+        codec: AudioCodec::PcmF32,
+        sample_rate: first.sample_rate,
+        channels,
+        config: Vec::new(),
+        priming: 0,
+        packets,
+    })
+}
+
+/// Decodes interleaved `data` into a stream of one float buffer. A partial
+/// frame at the end, as truncated files have, is dropped.
+pub fn decode(
+    data: &[u8],
+    format: SampleFormat,
+    sample_rate: u32,
+    channels: u16,
+) -> Result<AudioStream> {
+    if sample_rate == 0 {
+        bail!("PCM audio needs a sample rate above zero");
+    }
+    let channel_layout = ChannelLayout::from_channel_count(channels)
+        .ok_or_else(|| anyhow!("PCM audio needs 1 to 255 channels, got {channels}"))?;
+    let frame_bytes = format.bytes() * usize::from(channels);
+    let usable = data.len() - data.len() % frame_bytes;
+    let samples = data[..usable]
+        .chunks_exact(format.bytes())
+        .map(|sample| format.read(sample))
+        .collect();
+    Ok(AudioStream {
+        codec: format.codec(),
+        buffers: vec![AudioBuffer {
+            sample_rate,
+            channel_layout,
+            samples,
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_scale_to_unit_range() {
+        let decode_one = |format: SampleFormat, bytes: &[u8]| {
+            decode(bytes, format, 8000, 1).unwrap().buffers[0].samples[0]
+        };
+        let s16 = SampleFormat::from_name("s16le").unwrap();
+        assert_eq!(decode_one(s16, &[0x00, 0x80]), -1.0);
+        assert_eq!(decode_one(s16, &[0x00, 0x40]), 0.5);
+        let s24 = SampleFormat::from_name("S24BE").unwrap();
+        assert_eq!(decode_one(s24, &[0xC0, 0x00, 0x00]), -0.5);
+        assert_eq!(decode_one(SampleFormat::U8, &[0x80]), 0.0);
+        assert_eq!(decode_one(SampleFormat::U8, &[0x00]), -1.0);
+        let f32 = SampleFormat::from_name("f32le").unwrap();
+        assert_eq!(decode_one(f32, &0.25f32.to_le_bytes()), 0.25);
+        let f64 = SampleFormat::from_name("f64be").unwrap();
+        assert_eq!(decode_one(f64, &(-0.75f64).to_be_bytes()), -0.75);
+        assert_eq!(SampleFormat::from_name("s8"), None);
+        assert_eq!(SampleFormat::from_name("le"), None);
+    }
+
+    #[test]
+    fn encoding_round_trips_and_clamps() {
+        let samples = [0.5, -1.0, 0.25, 2.0];
+        for name in ["u8", "s16be", "s24le", "s32be", "f32le", "f64be"] {
+            let format = SampleFormat::from_name(name).unwrap();
+            let encoded = encode(&samples, format);
+            assert_eq!(encoded.len(), 4 * format.bytes());
+            let decoded = decode(&encoded, format, 8000, 1).unwrap().buffers.remove(0);
+            assert_eq!(decoded.samples[..3], samples[..3], "{name}");
+        }
+        let s16 = SampleFormat::S16 { big_endian: false };
+        assert_eq!(encode(&[2.0, -2.0], s16), [0xFF, 0x7F, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn partial_frames_are_dropped() {
+        let stream = decode(&[0; 11], SampleFormat::S16 { big_endian: false }, 48000, 2).unwrap();
+        let buffer = &stream.buffers[0];
+        assert_eq!(buffer.samples.len(), 4);
+        assert_eq!(buffer.channel_layout.channel_count(), 2);
+        assert_eq!(stream.codec.name(), "pcm_s16");
+        assert!(decode(&[0; 4], SampleFormat::U8, 48000, 0).is_err());
+        assert!(decode(&[0; 4], SampleFormat::U8, 0, 1).is_err());
+    }
+}
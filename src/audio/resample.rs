@@ -0,0 +1,253 @@
+//! Sample rate conversion by band-limited interpolation: each output sample
+//! is a Kaiser-windowed sinc filter over the input around its position.
+
+use crate::video::AudioBuffer;
+
+/// The most filter phases precomputed; ratios that need more round each
+/// output position to the nearest one.
+const MAX_PHASES: u64 = 4096;
+
+/// How long and how steep the interpolation filter is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quality {
+    Low,
+    Medium,
+    High,
+}
+
+impl Quality {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "low" => Some(Self::Low),
+            "medium" => Some(Self::Medium),
+            "high" => Some(Self::High),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Low => "low",
+            Self::Medium => "medium",
+            Self::High => "high",
+        }
+    }
+
+    /// Input samples either side of an output position when upsampling,
+    /// the Kaiser window's beta, and the share of the lower Nyquist
+    /// frequency the passband keeps.
+    fn filter(self) -> (usize, f64, f64) {
+        match self {
+            Self::Low => (8, 6.0, 0.85),
+            Self::Medium => (16, 8.0, 0.91),
+            Self::High => (32, 10.0, 0.95),
+        }
+    }
+}
+
+/// Converts `buffer` to `sample_rate`. The output covers the same span of
+/// time, rounded up to a whole sample frame; input beyond either end is
+/// taken as silence.
+pub fn resample(buffer: &AudioBuffer, sample_rate: u32, quality: Quality) -> AudioBuffer {
+    let channels = usize::from(buffer.channel_layout.channel_count());
+    if buffer.sample_rate == sample_rate || buffer.sample_rate == 0 || channels == 0 {
+        return buffer.clone();
+    }
+    // Output sample n sits at input position n * step / phases.
+    let divisor = gcd(buffer.sample_rate, sample_rate);
+    let step = u64::from(buffer.sample_rate / divisor);
+    let phases = u64::from(sample_rate / divisor);
+    let filter = Filter::new(
+        quality,
+        f64::from(sample_rate) / f64::from(buffer.sample_rate),
+        phases,
+    );
+
+    let frames = buffer.samples.len() / channels;
+    let out_frames = (frames as u64 * phases).div_ceil(step) as usize;
+    let mut samples = Vec::with_capacity(out_frames * channels);
+    for n in 0..out_frames as u64 {
+        let position = n * step;
+        let (index, taps) = filter.taps(position / phases, position % phases, phases);
+        // The taps that land inside the input.
+        let first = index as i64 - filter.half as i64 + 1;
+        let start = first.max(0) as usize;
+        let end = ((first + taps.len() as i64).max(0) as usize).min(frames);
+        let taps = &taps[(start as i64 - first) as usize..];
+        for channel in 0..channels {
+            let input = buffer.samples[start * channels + channel..]
+                .iter()
+                .step_by(channels)
+                .take(end.saturating_sub(start));
+            let sum: f64 = input
+                .zip(taps)
+                .map(|(&sample, &tap)| f64::from(sample) * tap)
+                .sum();
+            samples.push(sum as f32);
+        }
+    }
+    AudioBuffer {
+        sample_rate,
+        channel_layout: buffer.channel_layout,
+        samples,
+    }
+}
+
+/// A windowed sinc low-pass filter, tabulated at evenly spaced phases
+/// between two input samples.
+struct Filter {
+    /// Taps either side of the output position.
+    half: usize,
+    phases: u64,
+    /// `phases` rows of `2 * half` taps.
+    table: Vec<f64>,
+}
+
+impl Filter {
+    fn new(quality: Quality, ratio: f64, phases: u64) -> Self {
+        let (half, beta, rolloff) = quality.filter();
+        // Downsampling narrows the passband, and the filter widens to keep
+        // as many zero crossings.
+        let narrowing = ratio.min(1.0);
+        let cutoff = narrowing * rolloff;
+        let half = (half as f64 / narrowing).ceil() as usize;
+        let phases = phases.min(MAX_PHASES);
+        let window_scale = bessel_i0(beta);
+
+        let mut table = Vec::with_capacity(phases as usize * 2 * half);
+        for phase in 0..phases {
+            let fraction = phase as f64 / phases as f64;
+            let row: Vec<f64> = (0..2 * half)
+                .map(|tap| {
+                    let distance = tap as f64 + 1.0 - half as f64 - fraction;
+                    let x = distance / half as f64;
+                    let window = bessel_i0(beta * (1.0 - x * x).max(0.0).sqrt()) / window_scale;
+                    cutoff * sinc(cutoff * distance) * window
+                })
+                .collect();
+            // Unity gain at DC whatever the phase.
+            let sum: f64 = row.iter().sum();
+            table.extend(row.iter().map(|tap| tap / sum));
+        }
+        Self {
+            half,
+            phases,
+            table,
+        }
+    }
+
+    /// The input index just before an output position `index + phase /
+    /// phases`, and the taps for it.
+    fn taps(&self, mut index: u64, phase: u64, phases: u64) -> (u64, &[f64]) {
+        let mut row = (phase * self.phases + phases / 2) / phases;
+        if row == self.phases {
+            index += 1;
+            row = 0;
+        }
+        let width = 2 * self.half;
+        let start = row as usize * width;
+        (index, &self.table[start..start + width])
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        let x = std::f64::consts::PI * x;
+        x.sin() / x
+    }
+}
+
+/// The zeroth-order modified Bessel function of the first kind, by its
+/// power series.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half = x / 2.0;
+    for k in 1..64 {
+        term *= half / f64::from(k);
+        let squared = term * term;
+        sum += squared;
+        if squared < sum * 1e-16 {
+            break;
+        }
+    }
+    sum
+}
+
+fn gcd(mut a: u32, mut b: u32) -> u32 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::ChannelLayout;
+
+    fn tone(frequency: f64, sample_rate: u32, frames: usize) -> AudioBuffer {
+        AudioBuffer {
+            sample_rate,
+            channel_layout: ChannelLayout::from_channel_count(1).unwrap(),
+            samples: (0..frames)
+                .map(|index| {
+                    let t = index as f64 / f64::from(sample_rate);
+                    (0.5 * (std::f64::consts::TAU * frequency * t).sin()) as f32
+                })
+                .collect(),
+        }
+    }
+
+    /// The largest difference from `expected` away from the edges.
+    fn interior_error(buffer: &AudioBuffer, expected: &AudioBuffer) -> f32 {
+        let len = buffer.samples.len();
+        buffer.samples[len / 8..len * 7 / 8]
+            .iter()
+            .zip(&expected.samples[len / 8..])
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn converts_tones_between_common_rates() {
+        let input = tone(1000.0, 44_100, 4410);
+        for (rate, quality, tolerance) in [
+            (48_000, Quality::High, 1e-4),
+            (48_000, Quality::Low, 1e-2),
+            (22_050, Quality::High, 1e-4),
+            // 44100 and 44101 share no factor, so phases are rounded.
+            (44_101, Quality::Medium, 1e-3),
+        ] {
+            let output = resample(&input, rate, quality);
+            assert_eq!(output.sample_rate, rate);
+            let expected_len = (4410u64 * u64::from(rate)).div_ceil(44_100);
+            assert_eq!(output.samples.len() as u64, expected_len);
+            let error = interior_error(&output, &tone(1000.0, rate, output.samples.len()));
+            assert!(error < tolerance, "{rate} Hz at {quality:?}: {error}");
+        }
+    }
+
+    #[test]
+    fn downsampling_removes_what_the_new_rate_cannot_carry() {
+        // 12 kHz is above the 8 kHz Nyquist frequency of 16 kHz audio.
+        let output = resample(&tone(12_000.0, 48_000, 4800), 16_000, Quality::High);
+        let len = output.samples.len();
+        let peak = output.samples[len / 8..len * 7 / 8]
+            .iter()
+            .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        assert!(peak < 1e-3, "{peak}");
+
+        let stereo = AudioBuffer {
+            sample_rate: 8000,
+            channel_layout: ChannelLayout::from_channel_count(2).unwrap(),
+            samples: [0.25, -0.5].repeat(800),
+        };
+        let output = resample(&stereo, 48_000, Quality::Medium);
+        assert_eq!(output.samples.len(), 2 * 4800);
+        assert!((output.samples[4800] - 0.25).abs() < 1e-4);
+        assert!((output.samples[4801] + 0.5).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,228 @@
+//! WAV files: RIFF WAVE with integer or float PCM in the `data` chunk, as
+//! the `fmt ` chunk describes it, including `WAVE_FORMAT_EXTENSIBLE`.
+
+use anyhow::{Result, anyhow, bail};
+
+use super::pcm::{self, SampleFormat};
+use crate::video::AudioStream;
+
+const WAVE_FORMAT_PCM: u16 = 0x0001;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 0x0003;
+/// The real format tag is the start of the chunk's `SubFormat` GUID.
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+pub fn is_wav(data: &[u8]) -> bool {
+    data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WAVE"
+}
+
+/// What the `fmt ` chunk says about the samples.
+struct Format {
+    sample: SampleFormat,
+    sample_rate: u32,
+    channels: u16,
+}
+
+/// Decodes the samples of a WAV file.
+pub fn decode(data: &[u8]) -> Result<AudioStream> {
+    if !is_wav(data) {
+        bail!("not a RIFF WAVE file");
+    }
+    let mut format = None;
+    let mut samples = None;
+    let mut rest = &data[12..];
+    while rest.len() >= 8 {
+        let size = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+        // Files written while streaming leave the data chunk's size at
+        // 0xFFFFFFFF; it then runs to the end of the file.
+        let body = &rest[8..8usize.saturating_add(size).min(rest.len())];
+        match &rest[..4] {
+            b"fmt " => format = Some(parse_format(body)?),
+            b"data" => samples = Some(body),
+            _ => {}
+        }
+        // Chunks are padded to an even length.
+        let next = 8usize.saturating_add(size).saturating_add(size & 1);
+        if next >= rest.len() {
+            break;
+        }
+        rest = &rest[next..];
+    }
+    let Some(format) = format else {
+        bail!("WAV file has no fmt chunk");
+    };
+    let Some(samples) = samples else {
+        bail!("WAV file has no data chunk");
+    };
+    pcm::decode(samples, format.sample, format.sample_rate, format.channels)
+}
+
+/// Writes `stream`'s first buffer as a WAV file of little-endian `format`
+/// samples.
+pub fn encode(stream: &AudioStream, format: SampleFormat) -> Result<Vec<u8>> {
+    let buffer = stream
+        .buffers
+        .first()
+        .ok_or_else(|| anyhow!("audio stream has no samples"))?;
+    let tag = match format {
+        SampleFormat::F32 { big_endian: false } | SampleFormat::F64 { big_endian: false } => {
+            WAVE_FORMAT_IEEE_FLOAT
+        }
+        SampleFormat::U8
+        | SampleFormat::S16 { big_endian: false }
+        | SampleFormat::S24 { big_endian: false }
+        | SampleFormat::S32 { big_endian: false } => WAVE_FORMAT_PCM,
+        _ => bail!("WAV files store little-endian samples"),
+    };
+    let channels = buffer.channel_layout.channel_count();
+    let block_align = format.bytes() as u16 * channels;
+    let samples = pcm::encode(&buffer.samples, format);
+    let data_size = u32::try_from(samples.len())
+        .ok()
+        .filter(|size| *size <= u32::MAX - 44)
+        .ok_or_else(|| anyhow!("audio is too long for a WAV file"))?;
+
+    let mut file = Vec::with_capacity(samples.len() + 45);
+    file.extend(b"RIFF");
+    file.extend((36 + data_size + (data_size & 1)).to_le_bytes());
+    file.extend(b"WAVEfmt ");
+    file.extend(16u32.to_le_bytes());
+    file.extend(tag.to_le_bytes());
+    file.extend(channels.to_le_bytes());
+    file.extend(buffer.sample_rate.to_le_bytes());
+    file.extend((buffer.sample_rate * u32::from(block_align)).to_le_bytes());
+    file.extend(block_align.to_le_bytes());
+    file.extend((8 * format.bytes() as u16).to_le_bytes());
+    file.extend(b"data");
+    file.extend(data_size.to_le_bytes());
+    file.extend(samples);
+    if data_size & 1 == 1 {
+        file.push(0);
+    }
+    Ok(file)
+}
+
+fn parse_format(body: &[u8]) -> Result<Format> {
+    if body.len() < 16 {
+        bail!("WAV fmt chunk is truncated");
+    }
+    let u16_at = |offset: usize| u16::from_le_bytes([body[offset], body[offset + 1]]);
+    let mut tag = u16_at(0);
+    let channels = u16_at(2);
+    let sample_rate = u32::from_le_bytes([body[4], body[5], body[6], body[7]]);
+    let block_align = u16_at(12);
+    let bits = u16_at(14);
+    if tag == WAVE_FORMAT_EXTENSIBLE {
+        if body.len() < 40 {
+            bail!("WAV extensible fmt chunk is truncated");
+        }
+        tag = u16_at(24);
+    }
+    // Samples occupy whole bytes; the bits per sample can be fewer, left
+    // justified in them.
+    let sample = match (tag, bits.div_ceil(8)) {
+        (WAVE_FORMAT_PCM, 1) => SampleFormat::U8,
+        (WAVE_FORMAT_PCM, 2) => SampleFormat::S16 { big_endian: false },
+        (WAVE_FORMAT_PCM, 3) => SampleFormat::S24 { big_endian: false },
+        (WAVE_FORMAT_PCM, 4) => SampleFormat::S32 { big_endian: false },
+        (WAVE_FORMAT_IEEE_FLOAT, 4) => SampleFormat::F32 { big_endian: false },
+        (WAVE_FORMAT_IEEE_FLOAT, 8) => SampleFormat::F64 { big_endian: false },
+        _ => bail!("unsupported WAV format {tag:#06x} with {bits} bits per sample"),
+    };
+    if usize::from(block_align) != sample.bytes() * usize::from(channels) {
+        bail!("WAV block align {block_align} does not fit {channels} channels of {bits} bits");
+    }
+    Ok(Format {
+        sample,
+        sample_rate,
+        channels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A WAV file with `fmt` as its format chunk, an odd-sized chunk
+    /// before it, and `samples` as data.
+    fn wav(fmt: &[u8], samples: &[u8]) -> Vec<u8> {
+        let mut chunks = b"WAVEjunk\x03\0\0\0abc\0".to_vec();
+        for (id, body) in [(b"fmt ", fmt), (b"data", samples)] {
+            chunks.extend(id);
+            chunks.extend((body.len() as u32).to_le_bytes());
+            chunks.extend(body);
+        }
+        let mut file = b"RIFF".to_vec();
+        file.extend((chunks.len() as u32).to_le_bytes());
+        file.extend(chunks);
+        file
+    }
+
+    fn fmt(tag: u16, channels: u16, sample_rate: u32, bits: u16) -> Vec<u8> {
+        let block_align = channels * bits.div_ceil(8);
+        let mut body = tag.to_le_bytes().to_vec();
+        body.extend(channels.to_le_bytes());
+        body.extend(sample_rate.to_le_bytes());
+        body.extend((sample_rate * u32::from(block_align)).to_le_bytes());
+        body.extend(block_align.to_le_bytes());
+        body.extend(bits.to_le_bytes());
+        body
+    }
+
+    #[test]
+    fn reads_interleaved_pcm() {
+        let samples = [0x00, 0x40, 0x00, 0xC0, 0xFF, 0x7F, 0x00, 0x80];
+        let stream = decode(&wav(&fmt(WAVE_FORMAT_PCM, 2, 44100, 16), &samples)).unwrap();
+        let buffer = &stream.buffers[0];
+        assert_eq!(buffer.sample_rate, 44100);
+        assert_eq!(buffer.channel_layout.channel_count(), 2);
+        assert_eq!(buffer.samples[..3], [0.5, -0.5, 32767.0 / 32768.0]);
+        assert_eq!(buffer.samples[3], -1.0);
+    }
+
+    #[test]
+    fn reads_extensible_float() {
+        let mut format = fmt(WAVE_FORMAT_EXTENSIBLE, 1, 48000, 32);
+        // cbSize, valid bits, channel mask, then the float SubFormat GUID.
+        format.extend([22, 0, 32, 0, 4, 0, 0, 0]);
+        format.extend(WAVE_FORMAT_IEEE_FLOAT.to_le_bytes());
+        format.extend([0, 0, 0, 0, 0x10, 0, 0x80, 0, 0, 0xAA, 0, 0x38, 0x9B, 0x71]);
+        let samples: Vec<u8> = [0.25f32, -1.0]
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        let stream = decode(&wav(&format, &samples)).unwrap();
+        assert_eq!(stream.codec.name(), "pcm_f32");
+        assert_eq!(stream.buffers[0].samples, [0.25, -1.0]);
+    }
+
+    #[test]
+    fn written_files_read_back() {
+        let stream = decode(&wav(
+            &fmt(WAVE_FORMAT_PCM, 1, 22050, 8),
+            &[0x80, 0xC0, 0x40],
+        ))
+        .unwrap();
+        let file = encode(&stream, SampleFormat::U8).unwrap();
+        // The odd-sized data chunk is padded.
+        assert_eq!(file.len(), 48);
+        assert_eq!(decode(&file).unwrap().buffers[0].samples, [0.0, 0.5, -0.5]);
+
+        let float = SampleFormat::F32 { big_endian: false };
+        let back = decode(&encode(&stream, float).unwrap()).unwrap();
+        assert_eq!(back.codec.name(), "pcm_f32");
+        assert_eq!(back.buffers[0].sample_rate, 22050);
+        assert!(encode(&stream, SampleFormat::S16 { big_endian: true }).is_err());
+    }
+
+    #[test]
+    fn rejects_unsupported_or_broken_files() {
+        // A-law.
+        assert!(decode(&wav(&fmt(6, 1, 8000, 8), &[0; 4])).is_err());
+        assert!(decode(&wav(&fmt(WAVE_FORMAT_PCM, 1, 8000, 16)[..12], &[0; 4])).is_err());
+        let mut mismatched = fmt(WAVE_FORMAT_PCM, 2, 8000, 16);
+        mismatched[12] = 2;
+        assert!(decode(&wav(&mismatched, &[0; 4])).is_err());
+        assert!(decode(b"RIFF\0\0\0\0WAVE").is_err());
+        assert!(!is_wav(b"RIFF\0\0\0\0AVI "));
+    }
+}
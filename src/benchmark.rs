@@ -56,7 +56,11 @@ pub fn run_benchmark(options: BenchmarkOptions) -> Result<BenchmarkReport> {
     let mut recipe = Recipe::load(&options.recipe_path)?;
 
     if let Some(glob) = options.inputs_override {
-        recipe.inputs = vec![InputSpec { path: glob }];
+        recipe.inputs = vec![InputSpec {
+            path: glob,
+            members: None,
+            include_hidden: false,
+        }];
     }
 
     if let Some(dir) = &options.output_dir {
@@ -0,0 +1,135 @@
+//! Per-thread scratch buffer pool.
+//!
+//! Every input otherwise allocates a fresh buffer for its raw bytes and
+//! another for its encoded output. Buffers handed back through [`recycle`]
+//! are kept on the current worker thread and reused by the next [`take`], so
+//! long batch runs settle on a small working set instead of churning the
+//! allocator.
+
+use std::cell::RefCell;
+
+const MAX_POOLED_BUFFERS: usize = 8;
+const MAX_POOLED_BYTES: usize = 512 * 1024 * 1024;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub pooled_buffers: usize,
+    pub pooled_bytes: usize,
+}
+
+#[derive(Default)]
+struct BufferPool {
+    buffers: Vec<Vec<u8>>,
+    hits: u64,
+    misses: u64,
+}
+
+impl BufferPool {
+    fn take(&mut self, min_capacity: usize) -> Vec<u8> {
+        let best = self
+            .buffers
+            .iter()
+            .enumerate()
+            .filter(|(_, buf)| buf.capacity() >= min_capacity)
+            .min_by_key(|(_, buf)| buf.capacity())
+            .map(|(index, _)| index);
+        match best {
+            Some(index) => {
+                self.hits += 1;
+                self.buffers.swap_remove(index)
+            }
+            None => {
+                self.misses += 1;
+                Vec::with_capacity(min_capacity)
+            }
+        }
+    }
+
+    fn recycle(&mut self, mut buffer: Vec<u8>) {
+        if buffer.capacity() == 0 || buffer.capacity() > MAX_POOLED_BYTES {
+            return;
+        }
+        buffer.clear();
+        if self.buffers.len() >= MAX_POOLED_BUFFERS {
+            // Keep the larger buffers; small ones are cheap to reallocate.
+            let smallest = self
+                .buffers
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, buf)| buf.capacity())
+                .map(|(index, buf)| (index, buf.capacity()));
+            match smallest {
+                Some((index, capacity)) if capacity < buffer.capacity() => {
+                    self.buffers.swap_remove(index);
+                }
+                _ => return,
+            }
+        }
+        self.buffers.push(buffer);
+    }
+
+    fn stats(&self) -> PoolStats {
+        PoolStats {
+            hits: self.hits,
+            misses: self.misses,
+            pooled_buffers: self.buffers.len(),
+            pooled_bytes: self.buffers.iter().map(Vec::capacity).sum(),
+        }
+    }
+}
+
+thread_local! {
+    static POOL: RefCell<BufferPool> = RefCell::new(BufferPool::default());
+}
+
+/// Returns an empty buffer with at least `min_capacity` bytes reserved,
+/// reusing a pooled allocation when one is large enough.
+pub fn take(min_capacity: usize) -> Vec<u8> {
+    POOL.with(|pool| pool.borrow_mut().take(min_capacity))
+}
+
+/// Hands a buffer back to the current thread's pool.
+pub fn recycle(buffer: Vec<u8>) {
+    POOL.with(|pool| pool.borrow_mut().recycle(buffer));
+}
+
+pub fn stats() -> PoolStats {
+    POOL.with(|pool| pool.borrow().stats())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BufferPool;
+
+    #[test]
+    fn reuses_smallest_fitting_buffer() {
+        let mut pool = BufferPool::default();
+        pool.recycle(Vec::with_capacity(64));
+        pool.recycle(Vec::with_capacity(1024));
+
+        let buffer = pool.take(100);
+        assert!(buffer.capacity() >= 1024);
+        assert!(buffer.is_empty());
+        let stats = pool.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 0));
+        assert_eq!(stats.pooled_buffers, 1);
+
+        let fresh = pool.take(4096);
+        assert!(fresh.capacity() >= 4096);
+        assert_eq!(pool.stats().misses, 1);
+    }
+
+    #[test]
+    fn evicts_smallest_when_full() {
+        let mut pool = BufferPool::default();
+        for size in 1..=super::MAX_POOLED_BUFFERS {
+            pool.recycle(Vec::with_capacity(size * 16));
+        }
+        pool.recycle(Vec::with_capacity(4096));
+        let stats = pool.stats();
+        assert_eq!(stats.pooled_buffers, super::MAX_POOLED_BUFFERS);
+        assert!(pool.buffers.iter().all(|buf| buf.capacity() > 16));
+    }
+}
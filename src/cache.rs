@@ -0,0 +1,504 @@
+//! Content-addressed output cache.
+//!
+//! With `run --cache`, every converted input is stored under a key derived
+//! from the input's bytes and name and the lockfile hash of each stage's
+//! parameters, so converting identical content with an identical pipeline
+//! again (in another checkout, output directory or run) copies the earlier
+//! output instead of decoding and re-encoding it. Layout:
+//!
+//! ```text
+//! <cache dir>/entries/<key[..2]>/<key>.json   output name, extension and metadata
+//! <cache dir>/objects/<sha[..2]>/<sha>        output bytes, shared by equal outputs
+//! ```
+//!
+//! Entries are touched whenever they are used, and [`OutputCache::evict`]
+//! drops the least recently used ones until the objects fit the size limit.
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::archive;
+use crate::collision::OutputClaims;
+use crate::lockfile::hash_params;
+use crate::overwrite::{self, OverwritePolicy};
+use crate::pipeline::{Artifact, OutputSpec, PipelineResult, StageSpec};
+use crate::recipe::QualityGateSpec;
+use crate::security::compute_sha256;
+use crate::structure;
+
+pub const CACHE_DIR_ENV: &str = "BUNKER_CONVERT_CACHE";
+pub const DEFAULT_MAX_BYTES: u64 = 1 << 30;
+
+/// Metadata describing where an input came from rather than what the
+/// pipeline made of it; it is taken from the current input on a hit.
+const INPUT_KEYS: [&str; 9] = [
+    "input_path",
+    "output_path",
+    "archive.path",
+    "archive.member",
+    archive::OUTPUT_DIR_KEY,
+    "input.size_bytes",
+    "memory.estimated_bytes",
+    structure::INPUT_INDEX_KEY,
+    structure::INPUT_MODIFIED_KEY,
+];
+
+/// `$BUNKER_CONVERT_CACHE`, else `bunker-convert` in `$XDG_CACHE_HOME` or
+/// `~/.cache`.
+pub fn default_cache_dir() -> PathBuf {
+    if let Some(path) = std::env::var_os(CACHE_DIR_ENV) {
+        return PathBuf::from(path);
+    }
+    std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir)
+        .join("bunker-convert")
+}
+
+/// Digest of the stages (via the lockfile parameter hashes) and quality
+/// gates, salted with the crate version since encoders change between
+/// releases.
+pub fn pipeline_key(stages: &[StageSpec], quality_gates: &[QualityGateSpec]) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+    for stage in stages {
+        hasher.update(hash_params(stage).as_bytes());
+    }
+    hasher.update(serde_json::to_vec(quality_gates)?);
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    /// Stem the stages gave the output (e.g. after `rename`).
+    stem: String,
+    extension: String,
+    object: String,
+    bytes: u64,
+    metadata: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CacheStats {
+    pub directory: PathBuf,
+    pub entries: usize,
+    pub objects: usize,
+    pub bytes: u64,
+    pub oldest_use: Option<chrono::DateTime<chrono::Utc>>,
+    pub newest_use: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Eviction {
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct OutputCache {
+    root: PathBuf,
+    pipeline_key: String,
+    max_bytes: u64,
+}
+
+impl OutputCache {
+    pub fn open(root: impl Into<PathBuf>, pipeline_key: String, max_bytes: u64) -> Result<Self> {
+        let root = root.into();
+        for dir in ["entries", "objects"] {
+            let path = root.join(dir);
+            fs::create_dir_all(&path)
+                .with_context(|| format!("Failed to create cache directory: {}", path.display()))?;
+        }
+        Ok(Self {
+            root,
+            pipeline_key,
+            max_bytes,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Key for `artifact` as loaded, before any stage has run.
+    pub fn key(&self, artifact: &Artifact) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.pipeline_key.as_bytes());
+        hasher.update(artifact.stem.as_bytes());
+        hasher.update([0]);
+        hasher.update(&artifact.data[..]);
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Writes the cached output for `key` to where `output` would place it
+    /// for `artifact`, returning the result the pipeline would have produced.
+    /// An existing output that `overwrite` keeps is left as it is.
+    pub fn restore(
+        &self,
+        key: &str,
+        artifact: &Artifact,
+        output: &OutputSpec,
+        claims: &OutputClaims,
+        overwrite: OverwritePolicy,
+    ) -> Result<Option<PipelineResult>> {
+        let entry_path = self.entry_path(key);
+        let Ok(content) = fs::read(&entry_path) else {
+            return Ok(None);
+        };
+        let Ok(entry) = serde_json::from_slice::<CacheEntry>(&content) else {
+            return Ok(None);
+        };
+        let object = self.object_path(&entry.object);
+        if !object.is_file() {
+            return Ok(None);
+        }
+        let mut metadata = entry.metadata;
+        for key in INPUT_KEYS {
+            match artifact.metadata.get(key) {
+                Some(value) => metadata.insert(key.to_string(), value.clone()),
+                None => metadata.remove(key),
+            };
+        }
+        let target = claims.claim(
+            output.resolve(&entry.stem, &entry.extension, &metadata),
+            &artifact.input_path,
+            &mut metadata,
+        )?;
+        if let Some(reason) = overwrite.keep_reason(&artifact.input_path, &target) {
+            tracing::info!(output = %target.display(), reason, "Keeping existing output");
+            metadata.insert(
+                overwrite::SKIPPED_KEY.to_string(),
+                Value::String(reason.to_string()),
+            );
+        } else {
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).with_context(|| {
+                    format!("Failed to create output directory: {}", parent.display())
+                })?;
+            }
+            fs::copy(&object, &target).with_context(|| {
+                format!("Failed to restore cached output: {}", target.display())
+            })?;
+        }
+        touch(&entry_path);
+        metadata.insert(
+            "output_path".to_string(),
+            Value::String(target.to_string_lossy().to_string()),
+        );
+        metadata.insert("cache.hit".to_string(), Value::Bool(true));
+        Ok(Some(PipelineResult {
+            input: artifact.input_path.clone(),
+            output: target,
+            metadata,
+            duration: Duration::ZERO,
+        }))
+    }
+
+    /// Stores `result`'s output under `key`; `stem` is the artifact's final
+    /// stem.
+    pub fn store(&self, key: &str, stem: &str, result: &PipelineResult) -> Result<()> {
+        if !result.output.is_file() {
+            return Ok(());
+        }
+        let object = compute_sha256(&result.output)?;
+        let object_path = self.object_path(&object);
+        if !object_path.exists() {
+            write_atomic(&object_path, &fs::read(&result.output)?)?;
+        }
+        let mut metadata = result.metadata.clone();
+        for key in INPUT_KEYS {
+            metadata.remove(key);
+        }
+        let entry = CacheEntry {
+            stem: stem.to_string(),
+            extension: result
+                .output
+                .extension()
+                .map(|ext| ext.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            object,
+            bytes: fs::metadata(&object_path)?.len(),
+            metadata,
+        };
+        write_atomic(&self.entry_path(key), &serde_json::to_vec(&entry)?)
+    }
+
+    /// Drops least recently used entries until the objects they reference
+    /// fit in the size limit, then deletes unreferenced objects.
+    pub fn evict(&self) -> Result<Eviction> {
+        evict(&self.root, self.max_bytes)
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.root
+            .join("entries")
+            .join(&key[..2])
+            .join(format!("{key}.json"))
+    }
+
+    fn object_path(&self, object: &str) -> PathBuf {
+        self.root.join("objects").join(&object[..2]).join(object)
+    }
+}
+
+pub fn stats(root: &Path) -> Result<CacheStats> {
+    let entries = list_entries(root)?;
+    let objects = files_under(&root.join("objects"))?;
+    let mut stats = CacheStats {
+        directory: root.to_path_buf(),
+        entries: entries.len(),
+        objects: objects.len(),
+        ..CacheStats::default()
+    };
+    for (_, size) in &objects {
+        stats.bytes += size;
+    }
+    let used = entries.iter().map(|(_, used, _)| *used);
+    stats.oldest_use = used.clone().min().map(chrono::DateTime::from);
+    stats.newest_use = used.max().map(chrono::DateTime::from);
+    Ok(stats)
+}
+
+/// Removes every entry and object, returning how much was freed.
+pub fn clear(root: &Path) -> Result<Eviction> {
+    let stats = stats(root)?;
+    for dir in ["entries", "objects"] {
+        let path = root.join(dir);
+        if path.exists() {
+            fs::remove_dir_all(&path)
+                .with_context(|| format!("Failed to clear cache directory: {}", path.display()))?;
+        }
+    }
+    Ok(Eviction {
+        entries: stats.entries,
+        bytes: stats.bytes,
+    })
+}
+
+fn evict(root: &Path, max_bytes: u64) -> Result<Eviction> {
+    let mut entries = list_entries(root)?;
+    // Newest first, so everything after the budget runs out is evicted.
+    entries.sort_by_key(|(_, used, _)| Reverse(*used));
+    let object_sizes: HashMap<String, u64> = files_under(&root.join("objects"))?
+        .into_iter()
+        .filter_map(|(path, size)| Some((path.file_name()?.to_string_lossy().to_string(), size)))
+        .collect();
+
+    let mut kept: HashSet<String> = HashSet::new();
+    let mut kept_bytes = 0u64;
+    let mut eviction = Eviction::default();
+    for (path, _, entry) in entries {
+        let size = object_sizes.get(&entry.object).copied().unwrap_or(0);
+        let extra = if kept.contains(&entry.object) {
+            0
+        } else {
+            size
+        };
+        if kept_bytes + extra <= max_bytes {
+            kept_bytes += extra;
+            kept.insert(entry.object);
+        } else {
+            fs::remove_file(&path).ok();
+            eviction.entries += 1;
+        }
+    }
+    for (object, size) in object_sizes {
+        if !kept.contains(&object) {
+            fs::remove_file(root.join("objects").join(&object[..2]).join(&object)).ok();
+            eviction.bytes += size;
+        }
+    }
+    Ok(eviction)
+}
+
+fn list_entries(root: &Path) -> Result<Vec<(PathBuf, SystemTime, CacheEntry)>> {
+    let mut entries = Vec::new();
+    for (path, _) in files_under(&root.join("entries"))? {
+        let Ok(content) = fs::read(&path) else {
+            continue;
+        };
+        let used = fs::metadata(&path)
+            .and_then(|meta| meta.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        match serde_json::from_slice::<CacheEntry>(&content) {
+            Ok(entry) => entries.push((path, used, entry)),
+            // Unreadable entries (e.g. from an older layout) are dropped.
+            Err(_) => {
+                fs::remove_file(&path).ok();
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Every file one shard directory below `dir`, with its size.
+fn files_under(dir: &Path) -> Result<Vec<(PathBuf, u64)>> {
+    let mut files = Vec::new();
+    let Ok(shards) = fs::read_dir(dir) else {
+        return Ok(files);
+    };
+    for shard in shards {
+        let shard = shard?.path();
+        if !shard.is_dir() {
+            continue;
+        }
+        for file in fs::read_dir(&shard)? {
+            let file = file?;
+            let meta = file.metadata()?;
+            if meta.is_file() && !file.file_name().to_string_lossy().ends_with(".tmp") {
+                files.push((file.path(), meta.len()));
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Writes through a temporary file so concurrent runs never see a partial
+/// entry or object.
+fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create cache directory: {}", parent.display()))?;
+    }
+    let temp = path.with_extension(format!("{}.tmp", std::process::id()));
+    fs::write(&temp, data)
+        .and_then(|()| fs::rename(&temp, path))
+        .with_context(|| format!("Failed to write cache file: {}", path.display()))
+}
+
+fn touch(path: &Path) {
+    if let Ok(file) = fs::File::options().append(true).open(path) {
+        let _ = file.set_modified(SystemTime::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn artifact(dir: &Path, name: &str, data: &[u8]) -> Artifact {
+        let path = dir.join(name);
+        fs::write(&path, data).unwrap();
+        Artifact::load(&path).unwrap()
+    }
+
+    #[test]
+    fn stores_restores_and_evicts_by_last_use() {
+        let temp = tempfile::tempdir().unwrap();
+        let cache = OutputCache::open(temp.path().join("cache"), "pipeline".into(), 10).unwrap();
+        let output = OutputSpec {
+            directory: temp.path().join("out"),
+            structure: "{stem}.{ext}".into(),
+        };
+
+        let first = artifact(temp.path(), "first.png", b"one");
+        let key = cache.key(&first);
+        assert!(
+            cache
+                .restore(
+                    &key,
+                    &first,
+                    &output,
+                    &OutputClaims::default(),
+                    OverwritePolicy::Always
+                )
+                .unwrap()
+                .is_none()
+        );
+        let converted = temp.path().join("converted.webp");
+        fs::write(&converted, b"0123456").unwrap();
+        let mut metadata = Map::new();
+        metadata.insert("image.width".into(), Value::from(4));
+        let result = PipelineResult {
+            input: first.input_path.clone(),
+            output: converted,
+            metadata,
+            duration: Duration::ZERO,
+        };
+        cache.store(&key, "first", &result).unwrap();
+
+        // Same name and bytes elsewhere hit; other bytes miss.
+        let other_dir = temp.path().join("checkout");
+        fs::create_dir_all(&other_dir).unwrap();
+        let copy = artifact(&other_dir, "first.png", b"one");
+        let restored = cache
+            .restore(
+                &cache.key(&copy),
+                &copy,
+                &output,
+                &OutputClaims::default(),
+                OverwritePolicy::Always,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(restored.output, temp.path().join("out/first.webp"));
+        assert_eq!(fs::read(&restored.output).unwrap(), b"0123456");
+        assert_eq!(restored.metadata["image.width"], 4);
+        assert_eq!(
+            restored.metadata["input_path"],
+            copy.input_path.to_string_lossy().as_ref()
+        );
+        let edited = artifact(&other_dir, "first.png", b"two");
+        assert_ne!(cache.key(&edited), key);
+
+        // A second 7-byte object pushes the cache over its 10-byte limit.
+        std::thread::sleep(Duration::from_millis(20));
+        let second = artifact(temp.path(), "second.png", b"two");
+        let second_key = cache.key(&second);
+        let converted = temp.path().join("second.webp");
+        fs::write(&converted, b"abcdefg").unwrap();
+        cache
+            .store(
+                &second_key,
+                "second",
+                &PipelineResult {
+                    input: second.input_path.clone(),
+                    output: converted,
+                    metadata: Map::new(),
+                    duration: Duration::ZERO,
+                },
+            )
+            .unwrap();
+        let eviction = cache.evict().unwrap();
+        assert_eq!(eviction.entries, 1);
+        assert_eq!(eviction.bytes, 7);
+        assert!(
+            cache
+                .restore(
+                    &key,
+                    &first,
+                    &output,
+                    &OutputClaims::default(),
+                    OverwritePolicy::Always
+                )
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            cache
+                .restore(
+                    &second_key,
+                    &second,
+                    &output,
+                    &OutputClaims::default(),
+                    OverwritePolicy::Always
+                )
+                .unwrap()
+                .is_some()
+        );
+
+        let stats = stats(cache.root()).unwrap();
+        assert_eq!((stats.entries, stats.objects, stats.bytes), (1, 1, 7));
+        clear(cache.root()).unwrap();
+        assert_eq!(super::stats(cache.root()).unwrap().entries, 0);
+    }
+}
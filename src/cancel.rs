@@ -0,0 +1,92 @@
+//! Cooperative cancellation of a run.
+//!
+//! A [`CancellationToken`] is shared by the executor and every stage (through
+//! [`crate::pipeline::PipelineContext`]). Once cancelled, the executor stops
+//! picking up new inputs while those already in flight finish; a stage that
+//! notices the token mid-input bails with [`Cancelled`] and its output is
+//! rolled back, since [`crate::sink::FileSink`] only moves an output into
+//! place once it is complete. `run` cancels on the first Ctrl+C and exits on
+//! the second.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{Context, Result};
+use thiserror::Error;
+use tracing::warn;
+
+/// Exit status for a process stopped by a second Ctrl+C (128 + SIGINT).
+pub const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+#[derive(Debug, Error)]
+#[error("Run cancelled")]
+pub struct Cancelled;
+
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Fails with [`Cancelled`] once the token is cancelled, for stages to
+    /// call between units of work.
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+        Ok(())
+    }
+}
+
+/// Whether `err` (or anything it wraps) reports a cancellation rather than a
+/// failure.
+pub fn is_cancelled(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| cause.is::<Cancelled>())
+}
+
+/// Cancels `token` on Ctrl+C, and exits immediately on a second one.
+pub fn cancel_on_interrupt(token: &CancellationToken) -> Result<()> {
+    let token = token.clone();
+    ctrlc::set_handler(move || {
+        if token.is_cancelled() {
+            warn!("Interrupted again; exiting without waiting for in-flight inputs");
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        warn!("Cancelling run: finishing in-flight inputs (press Ctrl+C again to exit now)");
+        token.cancel();
+    })
+    .context("Failed to install Ctrl+C handler")
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn cancellation_is_shared_and_recognised_through_context() {
+        let token = CancellationToken::new();
+        let stage_token = token.clone();
+        assert!(stage_token.check().is_ok());
+        token.cancel();
+        let err = stage_token
+            .check()
+            .context("Stage 'encode' failed")
+            .unwrap_err();
+        assert!(is_cancelled(&err));
+        assert!(!is_cancelled(&anyhow::anyhow!("disk full")));
+    }
+}
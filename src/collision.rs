@@ -0,0 +1,194 @@
+//! Detecting outputs that would overwrite each other.
+//!
+//! Two inputs with the same stem resolve to the same output path under most
+//! structures. Stages claim every output path before writing it; the first
+//! input to claim a path keeps it, and a later input is failed or steered to
+//! a free path depending on the [`CollisionStrategy`]. A steered output
+//! records the path it asked for in `output.collision.requested_path`.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Result, anyhow, bail};
+use clap::ValueEnum;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum CollisionStrategy {
+    /// Fail the input whose output would overwrite another input's.
+    #[default]
+    Error,
+    /// Append `-1`, `-2`, ... to the file stem until the path is free.
+    Suffix,
+    /// Append the first 8 hex digits of the input path's SHA256 to the stem.
+    Hash,
+}
+
+impl CollisionStrategy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CollisionStrategy::Error => "error",
+            CollisionStrategy::Suffix => "suffix",
+            CollisionStrategy::Hash => "hash",
+        }
+    }
+}
+
+/// Output paths claimed during one run, shared by every stage context.
+#[derive(Debug, Clone, Default)]
+pub struct OutputClaims {
+    strategy: CollisionStrategy,
+    claimed: Arc<Mutex<HashMap<PathBuf, PathBuf>>>,
+}
+
+impl OutputClaims {
+    pub fn new(strategy: CollisionStrategy) -> Self {
+        Self {
+            strategy,
+            claimed: Arc::default(),
+        }
+    }
+
+    pub fn strategy(&self) -> CollisionStrategy {
+        self.strategy
+    }
+
+    /// Claims `path` for `input`'s output and returns the path to write. It
+    /// only differs from `path` when another input claimed `path` first, in
+    /// which case the collision is recorded in `metadata`. Claiming again for
+    /// the same input (a retried stage) yields the same path.
+    pub fn claim(
+        &self,
+        path: PathBuf,
+        input: &Path,
+        metadata: &mut Map<String, Value>,
+    ) -> Result<PathBuf> {
+        let mut claimed = self
+            .claimed
+            .lock()
+            .map_err(|_| anyhow!("output claims poisoned"))?;
+        let is_free = |candidate: &Path| {
+            claimed
+                .get(candidate)
+                .is_none_or(|owner| owner.as_path() == input)
+        };
+        if is_free(&path) {
+            claimed.insert(path.clone(), input.to_path_buf());
+            return Ok(path);
+        }
+        let owner = claimed[&path].display().to_string();
+        let resolved = match self.strategy {
+            CollisionStrategy::Error => bail!(
+                "Output {} of {} collides with the output of {owner}; \
+                 use a structure that keeps them apart or --on-collision suffix|hash",
+                path.display(),
+                input.display()
+            ),
+            CollisionStrategy::Suffix => (1..)
+                .map(|n| with_stem_suffix(&path, &n.to_string()))
+                .find(|candidate| is_free(candidate))
+                .expect("unbounded suffixes"),
+            CollisionStrategy::Hash => {
+                let digest = Sha256::digest(input.as_os_str().as_encoded_bytes());
+                let digest = format!("{digest:x}");
+                let hashed = with_stem_suffix(&path, &digest[..8]);
+                if is_free(&hashed) {
+                    hashed
+                } else {
+                    (1..)
+                        .map(|n| with_stem_suffix(&hashed, &n.to_string()))
+                        .find(|candidate| is_free(candidate))
+                        .expect("unbounded suffixes")
+                }
+            }
+        };
+        claimed.insert(resolved.clone(), input.to_path_buf());
+        metadata.insert(
+            "output.collision.strategy".to_string(),
+            Value::String(self.strategy.as_str().to_string()),
+        );
+        metadata.insert(
+            "output.collision.requested_path".to_string(),
+            Value::String(path.to_string_lossy().to_string()),
+        );
+        metadata.insert("output.collision.with".to_string(), Value::String(owner));
+        Ok(resolved)
+    }
+
+    /// [`Self::claim`] for an extra file an input writes next to its main
+    /// output (a thumbnail, fallback or sidecar). Claims are per input, so a
+    /// steered side output is not recorded in the artifact's metadata,
+    /// which describes the main output.
+    pub fn claim_side_output(&self, path: PathBuf, input: &Path) -> Result<PathBuf> {
+        self.claim(path, input, &mut Map::new())
+    }
+}
+
+/// `path` with `-{suffix}` inserted between its file stem and extension.
+fn with_stem_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = OsString::from(path.file_stem().unwrap_or_default());
+    file_name.push("-");
+    file_name.push(suffix);
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steers_later_claims_to_free_paths() {
+        let target = PathBuf::from("out/photo.png");
+        let (a, b, c) = (
+            Path::new("a/photo.png"),
+            Path::new("b/photo.png"),
+            Path::new("c/photo.png"),
+        );
+        let mut metadata = Map::new();
+
+        let strict = OutputClaims::new(CollisionStrategy::Error);
+        assert_eq!(
+            strict.claim(target.clone(), a, &mut metadata).unwrap(),
+            target
+        );
+        assert_eq!(
+            strict.claim(target.clone(), a, &mut metadata).unwrap(),
+            target
+        );
+        let err = strict.claim(target.clone(), b, &mut metadata).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("collides with the output of a/photo.png")
+        );
+        assert!(metadata.is_empty());
+
+        let suffixed = OutputClaims::new(CollisionStrategy::Suffix);
+        suffixed.claim(target.clone(), a, &mut metadata).unwrap();
+        let second = suffixed.claim(target.clone(), b, &mut metadata).unwrap();
+        assert_eq!(second, PathBuf::from("out/photo-1.png"));
+        assert_eq!(
+            suffixed.claim(target.clone(), b, &mut Map::new()).unwrap(),
+            second
+        );
+        assert_eq!(
+            suffixed.claim_side_output(target.clone(), c).unwrap(),
+            PathBuf::from("out/photo-2.png")
+        );
+        assert_eq!(metadata["output.collision.strategy"], "suffix");
+        assert_eq!(metadata["output.collision.requested_path"], "out/photo.png");
+        assert_eq!(metadata["output.collision.with"], "a/photo.png");
+
+        let hashed = OutputClaims::new(CollisionStrategy::Hash);
+        hashed.claim(target.clone(), a, &mut Map::new()).unwrap();
+        let path = hashed.claim(target, b, &mut Map::new()).unwrap();
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        assert!(name.starts_with("photo-") && name.ends_with(".png") && name.len() == 18);
+    }
+}
@@ -0,0 +1,354 @@
+//! `when:` clauses that decide per input whether a stage runs.
+//!
+//! A clause compares artifact metadata against literals, e.g.
+//! `image.width > 2000`, `format == 'png'` or
+//! `image.width >= 1000 && !archive.member`. Keys are dotted metadata names
+//! (`format` falls back to the format decode detected); a bare key is true
+//! when it is present and not `false`, `0`, `""` or `null`. Operators are
+//! `==`, `!=`, `<`, `<=`, `>`, `>=`, `&&`/`and`, `||`/`or`, `!`/`not` and
+//! parentheses. Comparisons between values of different kinds are false.
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use anyhow::{Result, anyhow, bail};
+use serde_json::Value;
+
+use crate::pipeline::Artifact;
+
+#[derive(Debug, Clone)]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Operand, CompareOp, Operand),
+    Truthy(Operand),
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Key(String),
+    Literal(Value),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Literal(Value),
+    Compare(CompareOp),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Self> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.or()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            bail!("Unexpected {token:?} in when clause '{source}'");
+        }
+        Ok(Self {
+            source: source.to_string(),
+            expr,
+        })
+    }
+
+    pub fn matches(&self, artifact: &Artifact) -> bool {
+        self.expr.eval(artifact)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Expr {
+    fn eval(&self, artifact: &Artifact) -> bool {
+        match self {
+            Expr::And(lhs, rhs) => lhs.eval(artifact) && rhs.eval(artifact),
+            Expr::Or(lhs, rhs) => lhs.eval(artifact) || rhs.eval(artifact),
+            Expr::Not(inner) => !inner.eval(artifact),
+            Expr::Truthy(operand) => truthy(&operand.resolve(artifact)),
+            Expr::Compare(lhs, op, rhs) => {
+                compare(&lhs.resolve(artifact), *op, &rhs.resolve(artifact))
+            }
+        }
+    }
+}
+
+impl Operand {
+    fn resolve(&self, artifact: &Artifact) -> Value {
+        match self {
+            Operand::Literal(value) => value.clone(),
+            Operand::Key(key) => match artifact.metadata.get(key) {
+                Some(value) => value.clone(),
+                None if key == "format" => artifact
+                    .format
+                    .clone()
+                    .map(Value::String)
+                    .unwrap_or(Value::Null),
+                None => Value::Null,
+            },
+        }
+    }
+}
+
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(flag) => *flag,
+        Value::Number(number) => number.as_f64().is_some_and(|n| n != 0.0),
+        Value::String(text) => !text.is_empty(),
+        Value::Array(items) => !items.is_empty(),
+        Value::Object(map) => !map.is_empty(),
+    }
+}
+
+fn compare(lhs: &Value, op: CompareOp, rhs: &Value) -> bool {
+    let ordering = match (lhs, rhs) {
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Null, Value::Null) => Some(Ordering::Equal),
+        (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+        // Numbers compare numerically, including numeric strings such as
+        // values set by `annotate`.
+        _ => match (as_number(lhs), as_number(rhs)) {
+            (Some(a), Some(b)) => a.partial_cmp(&b),
+            _ => None,
+        },
+    };
+    let Some(ordering) = ordering else {
+        return op == CompareOp::Ne;
+    };
+    match op {
+        CompareOp::Eq => ordering == Ordering::Equal,
+        CompareOp::Ne => ordering != Ordering::Equal,
+        CompareOp::Lt => ordering == Ordering::Less,
+        CompareOp::Le => ordering != Ordering::Greater,
+        CompareOp::Gt => ordering == Ordering::Greater,
+        CompareOp::Ge => ordering != Ordering::Less,
+    }
+}
+
+fn as_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(text) => text.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let token = match c {
+            '(' => {
+                chars.next();
+                Token::Open
+            }
+            ')' => {
+                chars.next();
+                Token::Close
+            }
+            '\'' | '"' => {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some(end) if end == c => break,
+                        Some(ch) => text.push(ch),
+                        None => bail!("Unterminated string in when clause '{source}'"),
+                    }
+                }
+                Token::Literal(Value::String(text))
+            }
+            '=' | '!' | '<' | '>' | '&' | '|' => {
+                chars.next();
+                let double = chars.next_if(|&next| next == '=' || (next == c && "&|".contains(c)));
+                match (c, double) {
+                    ('=', Some('=')) => Token::Compare(CompareOp::Eq),
+                    ('!', Some('=')) => Token::Compare(CompareOp::Ne),
+                    ('!', None) => Token::Not,
+                    ('<', Some('=')) => Token::Compare(CompareOp::Le),
+                    ('<', None) => Token::Compare(CompareOp::Lt),
+                    ('>', Some('=')) => Token::Compare(CompareOp::Ge),
+                    ('>', None) => Token::Compare(CompareOp::Gt),
+                    ('&', Some('&')) => Token::And,
+                    ('|', Some('|')) => Token::Or,
+                    _ => bail!("Unknown operator near '{c}' in when clause '{source}'"),
+                }
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut text = String::new();
+                while let Some(ch) = chars.next_if(|ch| ch.is_ascii_digit() || ".-eE".contains(*ch))
+                {
+                    text.push(ch);
+                }
+                let number: f64 = text
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid number '{text}' in when clause '{source}'"))?;
+                Token::Literal(serde_json::json!(number))
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut word = String::new();
+                while let Some(ch) = chars.next_if(|ch| ch.is_alphanumeric() || "_.-".contains(*ch))
+                {
+                    word.push(ch);
+                }
+                match word.as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    "true" => Token::Literal(Value::Bool(true)),
+                    "false" => Token::Literal(Value::Bool(false)),
+                    "null" => Token::Literal(Value::Null),
+                    _ => Token::Ident(word),
+                }
+            }
+            other => bail!("Unexpected character '{other}' in when clause '{source}'"),
+        };
+        tokens.push(token);
+    }
+    if tokens.is_empty() {
+        bail!("when clause cannot be empty");
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, expected: &Token) -> bool {
+        if self.tokens.get(self.pos) == Some(expected) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+        while self.eat(&Token::Or) {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.unary()?;
+        while self.eat(&Token::And) {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.eat(&Token::Not) {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat(&Token::Open) {
+            let expr = self.or()?;
+            if !self.eat(&Token::Close) {
+                bail!("Missing ')' in when clause");
+            }
+            return Ok(expr);
+        }
+        let lhs = self.operand()?;
+        match self.tokens.get(self.pos) {
+            Some(Token::Compare(op)) => {
+                let op = *op;
+                self.pos += 1;
+                Ok(Expr::Compare(lhs, op, self.operand()?))
+            }
+            _ => Ok(Expr::Truthy(lhs)),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand> {
+        match self.next() {
+            Some(Token::Ident(key)) => Ok(Operand::Key(key)),
+            Some(Token::Literal(value)) => Ok(Operand::Literal(value)),
+            Some(token) => bail!("Expected a metadata key or value, found {token:?}"),
+            None => bail!("when clause ends unexpectedly"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn artifact() -> Artifact {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("photo.png");
+        std::fs::write(&path, b"png").unwrap();
+        let mut artifact = Artifact::load(&path).unwrap();
+        artifact.set_format("png");
+        artifact
+            .metadata
+            .insert("image.width".into(), serde_json::json!(2400));
+        artifact
+            .metadata
+            .insert("batch".into(), Value::String("7".into()));
+        artifact
+    }
+
+    #[test]
+    fn evaluates_comparisons_against_metadata() {
+        let artifact = artifact();
+        let holds = |source: &str| Condition::parse(source).unwrap().matches(&artifact);
+        assert!(holds("image.width > 2000"));
+        assert!(!holds("image.width <= 2000"));
+        assert!(holds("format == 'png'"));
+        assert!(holds("format != \"jpeg\" and not archive.member"));
+        assert!(holds(
+            "batch >= 7 && (image.height == null || image.height < 10)"
+        ));
+        assert!(!holds("image.height > 0"));
+        assert!(!holds("stem == 2"));
+        assert!(holds("stem"));
+    }
+
+    #[test]
+    fn rejects_malformed_clauses() {
+        for source in ["", "image.width >", "format == 'png", "(a", "a = 1", "a b"] {
+            assert!(Condition::parse(source).is_err(), "{source} parsed");
+        }
+    }
+}
@@ -0,0 +1,279 @@
+//! Long-running daemon accepting conversion jobs over a local socket.
+//!
+//! `bunker-convert daemon` builds the stage registry once and then serves
+//! newline-delimited JSON requests on a Unix domain socket; `bunker-convert
+//! submit` sends one request and waits for its response. Jobs run one at a
+//! time in submission order: each already fans out across the encode worker
+//! pool, and running them serially lets relative recipe paths resolve against
+//! the submitting client's working directory.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::pipeline::{StageRegistry, build_pipeline};
+use crate::recipe::Recipe;
+use crate::scheduler::DevicePolicy;
+
+pub const SOCKET_ENV: &str = "BUNKER_CONVERT_SOCKET";
+
+/// `$BUNKER_CONVERT_SOCKET`, else `bunker-convert.sock` in
+/// `$XDG_RUNTIME_DIR` or the system temp directory.
+pub fn default_socket_path() -> PathBuf {
+    if let Some(path) = std::env::var_os(SOCKET_ENV) {
+        return PathBuf::from(path);
+    }
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("bunker-convert.sock")
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum DaemonRequest {
+    Ping,
+    Shutdown,
+    Run {
+        recipe: PathBuf,
+        /// Directory relative recipe and input paths resolve against.
+        cwd: PathBuf,
+        #[serde(default)]
+        device_policy: DevicePolicy,
+        #[serde(default)]
+        encode_workers: Option<usize>,
+    },
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DaemonResponse {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<JobOutput>,
+    #[serde(default)]
+    pub duration_ms: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobOutput {
+    pub input: PathBuf,
+    pub output: PathBuf,
+}
+
+impl DaemonResponse {
+    fn ok() -> Self {
+        Self {
+            ok: true,
+            ..Self::default()
+        }
+    }
+
+    fn failed(err: &anyhow::Error) -> Self {
+        Self {
+            ok: false,
+            error: Some(format!("{err:#}")),
+            ..Self::default()
+        }
+    }
+}
+
+/// Runs one recipe with a registry that outlives the job.
+fn run_job(
+    registry: &StageRegistry,
+    recipe: &Path,
+    cwd: &Path,
+    device_policy: DevicePolicy,
+    encode_workers: Option<usize>,
+) -> Result<Vec<JobOutput>> {
+    let previous = std::env::current_dir().ok();
+    std::env::set_current_dir(cwd)
+        .with_context(|| format!("Failed to enter job directory: {}", cwd.display()))?;
+    let result = run_recipe(registry, recipe, device_policy, encode_workers);
+    if let Some(previous) = previous {
+        let _ = std::env::set_current_dir(previous);
+    }
+    result
+}
+
+fn run_recipe(
+    registry: &StageRegistry,
+    recipe: &Path,
+    device_policy: DevicePolicy,
+    encode_workers: Option<usize>,
+) -> Result<Vec<JobOutput>> {
+    let recipe = Recipe::load(recipe)?;
+    let inputs = recipe.expand_inputs()?;
+    let executor = build_pipeline(
+        registry,
+        &recipe.pipeline,
+        recipe.output.clone(),
+        recipe.quality_gates.clone(),
+        device_policy,
+    )?
+    .with_encode_workers(encode_workers)
+    .with_overwrite(recipe.overwrite.unwrap_or_default());
+    let results = executor.execute(&inputs)?;
+    Ok(results
+        .into_iter()
+        .map(|result| JobOutput {
+            input: result.input,
+            output: result.output,
+        })
+        .collect())
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::{Path, PathBuf};
+    use std::time::Instant;
+
+    use anyhow::{Context, Result, anyhow, bail};
+    use tracing::{info, warn};
+
+    use super::{DaemonRequest, DaemonResponse, run_job};
+    use crate::pipeline::StageRegistry;
+
+    pub struct Daemon {
+        registry: StageRegistry,
+        listener: UnixListener,
+        socket: PathBuf,
+    }
+
+    impl Daemon {
+        /// Binds `socket`, replacing a stale socket file left by a daemon
+        /// that exited without cleaning up.
+        pub fn bind(registry: StageRegistry, socket: &Path) -> Result<Self> {
+            if socket.exists() {
+                if UnixStream::connect(socket).is_ok() {
+                    bail!("A daemon is already listening on {}", socket.display());
+                }
+                std::fs::remove_file(socket).with_context(|| {
+                    format!("Failed to remove stale socket: {}", socket.display())
+                })?;
+            }
+            let listener = UnixListener::bind(socket)
+                .with_context(|| format!("Failed to bind daemon socket: {}", socket.display()))?;
+            Ok(Self {
+                registry,
+                listener,
+                socket: socket.to_path_buf(),
+            })
+        }
+
+        /// Serves connections until a `shutdown` request arrives.
+        pub fn serve(self) -> Result<()> {
+            info!(socket = %self.socket.display(), "Daemon listening");
+            for stream in self.listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!(error = %err, "Failed to accept daemon connection");
+                        continue;
+                    }
+                };
+                match self.handle(stream) {
+                    Ok(true) => break,
+                    Ok(false) => {}
+                    Err(err) => warn!(error = %err, "Daemon connection failed"),
+                }
+            }
+            info!("Daemon stopped");
+            Ok(())
+        }
+
+        /// Answers one request; returns whether the daemon should stop.
+        fn handle(&self, stream: UnixStream) -> Result<bool> {
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line)?;
+            let started = Instant::now();
+            let (response, stop) = match serde_json::from_str::<DaemonRequest>(&line) {
+                Ok(DaemonRequest::Ping) => (DaemonResponse::ok(), false),
+                Ok(DaemonRequest::Shutdown) => (DaemonResponse::ok(), true),
+                Ok(DaemonRequest::Run {
+                    recipe,
+                    cwd,
+                    device_policy,
+                    encode_workers,
+                }) => {
+                    info!(recipe = %recipe.display(), "Running submitted job");
+                    let response = match run_job(
+                        &self.registry,
+                        &recipe,
+                        &cwd,
+                        device_policy,
+                        encode_workers,
+                    ) {
+                        Ok(outputs) => DaemonResponse {
+                            outputs,
+                            ..DaemonResponse::ok()
+                        },
+                        Err(err) => {
+                            warn!(recipe = %recipe.display(), error = %err, "Submitted job failed");
+                            DaemonResponse::failed(&err)
+                        }
+                    };
+                    (response, false)
+                }
+                Err(err) => (
+                    DaemonResponse::failed(&anyhow!(err).context("Invalid daemon request")),
+                    false,
+                ),
+            };
+            let response = DaemonResponse {
+                duration_ms: started.elapsed().as_secs_f64() * 1_000.0,
+                ..response
+            };
+            let mut writer = &stream;
+            serde_json::to_writer(&mut writer, &response)?;
+            writer.write_all(b"\n")?;
+            Ok(stop)
+        }
+    }
+
+    impl Drop for Daemon {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.socket);
+        }
+    }
+
+    pub fn submit(socket: &Path, request: &DaemonRequest) -> Result<DaemonResponse> {
+        let mut stream = UnixStream::connect(socket).with_context(|| {
+            format!(
+                "No daemon listening on {}; start one with `bunker-convert daemon`",
+                socket.display()
+            )
+        })?;
+        serde_json::to_writer(&mut stream, request)?;
+        stream.write_all(b"\n")?;
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line)?;
+        serde_json::from_str(&line).context("Invalid daemon response")
+    }
+}
+
+#[cfg(unix)]
+pub use unix::{Daemon, submit};
+
+#[cfg(not(unix))]
+pub struct Daemon;
+
+#[cfg(not(unix))]
+impl Daemon {
+    pub fn bind(_registry: StageRegistry, _socket: &Path) -> Result<Self> {
+        anyhow::bail!("Daemon mode requires Unix domain sockets, which this platform lacks")
+    }
+
+    pub fn serve(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+pub fn submit(_socket: &Path, _request: &DaemonRequest) -> Result<DaemonResponse> {
+    anyhow::bail!("Daemon mode requires Unix domain sockets, which this platform lacks")
+}
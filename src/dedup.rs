@@ -0,0 +1,251 @@
+//! Content-hash deduplication of run inputs.
+//!
+//! Inputs are hashed up front and only the first input with a given SHA256 is
+//! sent through the pipeline. Every later copy is then served from that
+//! input's output as a hardlink, a file copy, or a manifest-only alias. With
+//! a [`Similarity`], inputs whose perceptual hash is close to an earlier
+//! one's count as copies too.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+use clap::ValueEnum;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::archive::ArchiveMember;
+use crate::perceptual::{self, HashAlgorithm};
+use crate::pipeline::{OutputSpec, PipelineResult};
+use crate::security::compute_sha256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DuplicateMode {
+    /// Hardlink the primary output, falling back to a copy across devices.
+    Link,
+    /// Copy the primary output.
+    Copy,
+    /// Write nothing; the manifest points the duplicate at the primary output.
+    Alias,
+}
+
+#[derive(Debug, Clone)]
+pub struct Duplicate {
+    pub input: PathBuf,
+    /// Index into the original input list.
+    pub position: usize,
+    /// Index into [`DedupPlan::unique`] of the input with the same content.
+    pub primary: usize,
+    /// Perceptual hash distance to the primary; `None` for identical bytes.
+    pub distance: Option<u32>,
+}
+
+/// Treats inputs whose `algorithm` hashes differ by at most `distance` bits
+/// as duplicates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Similarity {
+    pub algorithm: HashAlgorithm,
+    pub distance: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DedupPlan {
+    pub unique: Vec<PathBuf>,
+    pub duplicates: Vec<Duplicate>,
+    positions: Vec<usize>,
+}
+
+impl DedupPlan {
+    /// Hashes every input and keeps the first occurrence of each digest.
+    pub fn from_inputs(inputs: &[PathBuf]) -> Result<Self> {
+        let mut plan = Self::default();
+        let mut seen: HashMap<String, usize> = HashMap::new();
+        for (position, input) in inputs.iter().enumerate() {
+            let digest = match ArchiveMember::from_path(input) {
+                Some(member) => format!("{:x}", Sha256::digest(member.read()?)),
+                None => compute_sha256(input)?,
+            };
+            let primary = plan.exact(&mut seen, digest);
+            plan.push(input, position, primary.map(|primary| (primary, None)));
+        }
+        Ok(plan)
+    }
+
+    /// Like [`DedupPlan::from_inputs`], but an input is a duplicate of the
+    /// closest earlier unique input within `similarity.distance` bits.
+    /// Inputs that do not decode as images only match identical bytes.
+    pub fn from_similar_inputs(inputs: &[PathBuf], similarity: Similarity) -> Result<Self> {
+        let mut plan = Self::default();
+        let mut seen: HashMap<String, usize> = HashMap::new();
+        let mut hashes: Vec<(u64, usize)> = Vec::new();
+        for (position, input) in inputs.iter().enumerate() {
+            let data = match ArchiveMember::from_path(input) {
+                Some(member) => member.read()?,
+                None => fs::read(input)
+                    .with_context(|| format!("Failed to read input: {}", input.display()))?,
+            };
+            let primary = match image::load_from_memory(&data) {
+                Ok(image) => {
+                    let hash = similarity.algorithm.hash(&image);
+                    let nearest = hashes
+                        .iter()
+                        .map(|&(other, primary)| (primary, perceptual::distance(hash, other)))
+                        .filter(|&(_, distance)| distance <= similarity.distance)
+                        .min_by_key(|&(_, distance)| distance);
+                    if nearest.is_none() {
+                        hashes.push((hash, plan.unique.len()));
+                    }
+                    nearest.map(|(primary, distance)| (primary, Some(distance)))
+                }
+                Err(_) => {
+                    let digest = format!("{:x}", Sha256::digest(&data));
+                    plan.exact(&mut seen, digest).map(|primary| (primary, None))
+                }
+            };
+            plan.push(input, position, primary);
+        }
+        Ok(plan)
+    }
+
+    /// The unique input already seen with `digest`, recording it as the
+    /// next unique input otherwise.
+    fn exact(&self, seen: &mut HashMap<String, usize>, digest: String) -> Option<usize> {
+        match seen.get(&digest) {
+            Some(&primary) => Some(primary),
+            None => {
+                seen.insert(digest, self.unique.len());
+                None
+            }
+        }
+    }
+
+    fn push(&mut self, input: &Path, position: usize, primary: Option<(usize, Option<u32>)>) {
+        match primary {
+            Some((primary, distance)) => self.duplicates.push(Duplicate {
+                input: input.to_path_buf(),
+                position,
+                primary,
+                distance,
+            }),
+            None => {
+                self.unique.push(input.to_path_buf());
+                self.positions.push(position);
+            }
+        }
+    }
+
+    pub fn total_inputs(&self) -> usize {
+        self.unique.len() + self.duplicates.len()
+    }
+
+    /// Materializes the duplicates from `results` (one per unique input) and
+    /// returns a result for every original input, in the original order.
+    pub fn expand(
+        &self,
+        results: Vec<PipelineResult>,
+        output: &OutputSpec,
+        mode: DuplicateMode,
+    ) -> Result<Vec<PipelineResult>> {
+        if results.len() != self.unique.len() {
+            return Err(anyhow!(
+                "Expected {} result(s) for unique inputs but got {}",
+                self.unique.len(),
+                results.len()
+            ));
+        }
+
+        let mut slots: Vec<Option<PipelineResult>> =
+            (0..self.total_inputs()).map(|_| None).collect();
+        for duplicate in &self.duplicates {
+            let primary = &results[duplicate.primary];
+            slots[duplicate.position] = Some(materialize(duplicate, primary, output, mode)?);
+        }
+        for (result, &position) in results.into_iter().zip(&self.positions) {
+            slots[position] = Some(result);
+        }
+        Ok(slots.into_iter().flatten().collect())
+    }
+}
+
+fn materialize(
+    duplicate: &Duplicate,
+    primary: &PipelineResult,
+    output: &OutputSpec,
+    mode: DuplicateMode,
+) -> Result<PipelineResult> {
+    let stem = duplicate
+        .input
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_else(|| "artifact".to_string());
+    let extension = primary
+        .output
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let mut metadata = primary.metadata.clone();
+    metadata.insert(
+        "input_path".to_string(),
+        Value::String(duplicate.input.to_string_lossy().to_string()),
+    );
+    metadata.insert("stem".to_string(), Value::String(stem.clone()));
+    metadata.insert(
+        "dedup.source".to_string(),
+        Value::String(primary.input.to_string_lossy().to_string()),
+    );
+    if let Some(distance) = duplicate.distance {
+        metadata.insert("dedup.distance".to_string(), Value::from(distance));
+    }
+
+    let target = output.resolve(&stem, &extension, &metadata);
+    // Structures that ignore the input name would make the copy overwrite
+    // its own source, so those duplicates are always aliased.
+    let (output_path, action) = if mode == DuplicateMode::Alias || target == primary.output {
+        (primary.output.clone(), "alias")
+    } else {
+        (
+            target.clone(),
+            place_output(&primary.output, &target, mode)?,
+        )
+    };
+    metadata.insert(
+        "output_path".to_string(),
+        Value::String(output_path.to_string_lossy().to_string()),
+    );
+    metadata.insert(
+        "dedup.action".to_string(),
+        Value::String(action.to_string()),
+    );
+
+    Ok(PipelineResult {
+        input: duplicate.input.clone(),
+        output: output_path,
+        metadata,
+        duration: Duration::ZERO,
+    })
+}
+
+fn place_output(source: &Path, target: &Path, mode: DuplicateMode) -> Result<&'static str> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create output directory: {}", parent.display()))?;
+    }
+    if target.exists() {
+        fs::remove_file(target)
+            .with_context(|| format!("Failed to replace output file: {}", target.display()))?;
+    }
+    if mode == DuplicateMode::Link && fs::hard_link(source, target).is_ok() {
+        return Ok("link");
+    }
+    fs::copy(source, target).with_context(|| {
+        format!(
+            "Failed to copy {} to {}",
+            source.display(),
+            target.display()
+        )
+    })?;
+    Ok("copy")
+}
@@ -0,0 +1,191 @@
+//! Input discovery for recipe globs.
+//!
+//! Each pattern is split into a literal base directory and a glob. The base is
+//! walked in parallel with the `ignore` crate, which honours `.bunkerignore`
+//! files (gitignore syntax, inherited from parent directories) and skips hidden
+//! files and directories unless asked not to, and every file is matched
+//! against the glob. Walks without `**` stop at the pattern's depth.
+
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result, anyhow};
+use globset::GlobBuilder;
+use ignore::{WalkBuilder, WalkState};
+
+pub const IGNORE_FILE: &str = ".bunkerignore";
+
+/// Returns every file matching `pattern`, sorted by path.
+pub fn discover(pattern: &str, include_hidden: bool) -> Result<Vec<PathBuf>> {
+    let Some(split) = split_pattern(pattern) else {
+        // No wildcards: the pattern names a single file.
+        let path = PathBuf::from(pattern);
+        return Ok(if path.is_file() {
+            vec![path]
+        } else {
+            Vec::new()
+        });
+    };
+    if !split.base.is_dir() {
+        return Ok(Vec::new());
+    }
+    let matcher = GlobBuilder::new(&split.glob)
+        .literal_separator(true)
+        .build()
+        .with_context(|| format!("Invalid glob pattern: {pattern}"))?
+        .compile_matcher();
+
+    let mut walker = WalkBuilder::new(&split.base);
+    walker
+        .standard_filters(false)
+        .hidden(!include_hidden)
+        .parents(true)
+        .add_custom_ignore_filename(IGNORE_FILE)
+        .max_depth(split.depth);
+    let found = Mutex::new(Vec::new());
+    let failure = Mutex::new(None);
+    walker.build_parallel().run(|| {
+        Box::new(|entry| {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    failure.lock().unwrap().get_or_insert(err);
+                    return WalkState::Quit;
+                }
+            };
+            let matched = entry.file_type().is_some_and(|kind| kind.is_file())
+                && entry
+                    .path()
+                    .strip_prefix(&split.base)
+                    .is_ok_and(|relative| matcher.is_match(relative));
+            if matched {
+                found.lock().unwrap().push(entry.into_path());
+            }
+            WalkState::Continue
+        })
+    });
+    if let Some(err) = failure.into_inner().unwrap() {
+        return Err(anyhow!(err)).with_context(|| format!("Failed to walk inputs for {pattern}"));
+    }
+    let mut found = found.into_inner().unwrap();
+    found.sort();
+    Ok(found)
+}
+
+/// Directory holding every file `pattern` can match, and whether matches can
+/// sit deeper than its immediate children.
+pub fn watch_root(pattern: &str) -> (PathBuf, bool) {
+    match split_pattern(pattern) {
+        Some(split) => (split.base, split.depth != Some(1)),
+        None => {
+            let parent = Path::new(pattern)
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            (parent.to_path_buf(), false)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct SplitPattern {
+    /// Literal directory prefix to walk.
+    base: PathBuf,
+    /// The rest of the pattern, matched against paths relative to `base`.
+    glob: String,
+    /// Walk depth; unbounded when the glob contains `**`.
+    depth: Option<usize>,
+}
+
+/// Splits `pattern` at its first wildcard component, or returns `None` when
+/// it has none.
+fn split_pattern(pattern: &str) -> Option<SplitPattern> {
+    let mut base = PathBuf::new();
+    let mut components = Path::new(pattern).components();
+    for component in components.by_ref() {
+        if is_wildcard(component) {
+            let rest: Vec<String> = std::iter::once(component)
+                .chain(components)
+                .map(|c| c.as_os_str().to_string_lossy().to_string())
+                .collect();
+            let depth = (!rest.iter().any(|c| c == "**")).then_some(rest.len());
+            if base.as_os_str().is_empty() {
+                base.push(".");
+            }
+            return Some(SplitPattern {
+                base,
+                glob: rest.join("/"),
+                depth,
+            });
+        }
+        base.push(component);
+    }
+    None
+}
+
+fn is_wildcard(component: Component) -> bool {
+    component
+        .as_os_str()
+        .to_string_lossy()
+        .contains(['*', '?', '[', '{'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn splits_base_and_depth() {
+        let split = |base: &str, glob: &str, depth| SplitPattern {
+            base: PathBuf::from(base),
+            glob: glob.to_string(),
+            depth,
+        };
+        assert_eq!(
+            split_pattern("./images/*/*.png"),
+            Some(split("./images", "*/*.png", Some(2)))
+        );
+        assert_eq!(
+            split_pattern("images/**/*.png"),
+            Some(split("images", "**/*.png", None))
+        );
+        assert_eq!(split_pattern("*.png"), Some(split(".", "*.png", Some(1))));
+        assert_eq!(split_pattern("a/b.png"), None);
+        assert_eq!(watch_root("a/b.png"), (PathBuf::from("a"), false));
+        assert_eq!(watch_root("*.png"), (PathBuf::from("."), false));
+        assert_eq!(
+            watch_root("images/*/*.png"),
+            (PathBuf::from("images"), true)
+        );
+    }
+
+    #[test]
+    fn honours_bunkerignore_and_hidden_rules() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        for file in [
+            "a.png",
+            "skip.png",
+            "nested/b.png",
+            ".hidden/c.png",
+            "d.jpg",
+        ] {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+        fs::write(root.join(IGNORE_FILE), "skip.png\n").unwrap();
+
+        let pattern = format!("{}/**/*.png", root.display());
+        let found = discover(&pattern, false).unwrap();
+        assert_eq!(found, vec![root.join("a.png"), root.join("nested/b.png")]);
+
+        let found = discover(&pattern, true).unwrap();
+        assert!(found.contains(&root.join(".hidden/c.png")));
+        assert!(!found.contains(&root.join("skip.png")));
+
+        let shallow = discover(&format!("{}/*.png", root.display()), false).unwrap();
+        assert_eq!(shallow, vec![root.join("a.png")]);
+    }
+}
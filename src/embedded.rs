@@ -0,0 +1,480 @@
+//! EXIF and XMP packets embedded in image files.
+//!
+//! Decoders hand back pixels only, so decode reads the packets straight from
+//! the input container (JPEG `APP1` segments, PNG `eXIf`/`iTXt` chunks, WebP
+//! `EXIF`/`XMP ` chunks) into [`EmbeddedMetadata`], and encode splices them
+//! into the finished output when its `preserve_metadata` option asks for
+//! them. Packets are carried as opaque bytes; only the EXIF orientation is
+//! ever rewritten, once decode has turned the pixels upright.
+
+use std::io::Read;
+
+use anyhow::{Result, bail};
+use flate2::Crc;
+use flate2::read::ZlibDecoder;
+use image::ImageFormat;
+
+const JPEG_EXIF_PREFIX: &[u8] = b"Exif\0\0";
+const JPEG_XMP_PREFIX: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const PNG_XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+/// Largest payload of a JPEG marker segment.
+const MAX_JPEG_SEGMENT: usize = 65533;
+const EXIF_ORIENTATION_TAG: u16 = 0x0112;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EmbeddedMetadata {
+    /// TIFF-structured EXIF data, without the `Exif\0\0` prefix JPEG uses.
+    pub exif: Option<Vec<u8>>,
+    /// The XMP packet, UTF-8 XML.
+    pub xmp: Option<Vec<u8>>,
+}
+
+impl EmbeddedMetadata {
+    /// Reads the packets of an encoded `format` image. Containers without
+    /// any, or that cannot be parsed, yield nothing.
+    pub fn read(format: ImageFormat, data: &[u8]) -> Self {
+        let mut metadata = Self::default();
+        match format {
+            ImageFormat::Jpeg => metadata.read_jpeg(data),
+            ImageFormat::Png => metadata.read_png(data),
+            ImageFormat::WebP => metadata.read_webp(data),
+            _ => {}
+        }
+        metadata
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exif.is_none() && self.xmp.is_none()
+    }
+
+    /// Names of the packets present, for metadata records.
+    pub fn kinds(&self) -> Vec<&'static str> {
+        let mut kinds = Vec::new();
+        if self.exif.is_some() {
+            kinds.push("exif");
+        }
+        if self.xmp.is_some() {
+            kinds.push("xmp");
+        }
+        kinds
+    }
+
+    /// A copy holding only the requested packets.
+    pub fn select(&self, exif: bool, xmp: bool) -> Self {
+        Self {
+            exif: self.exif.clone().filter(|_| exif),
+            xmp: self.xmp.clone().filter(|_| xmp),
+        }
+    }
+
+    /// Marks the EXIF data as describing upright pixels, so viewers do not
+    /// rotate an already oriented image a second time.
+    pub fn reset_orientation(&mut self) {
+        if let Some(exif) = &mut self.exif {
+            set_orientation(exif, 1);
+        }
+    }
+
+    /// Whether outputs in `format` can carry the packets.
+    pub fn supported(format: ImageFormat) -> bool {
+        matches!(
+            format,
+            ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP
+        )
+    }
+
+    /// Splices the packets into an encoded `format` image.
+    pub fn embed(&self, format: ImageFormat, encoded: Vec<u8>) -> Result<Vec<u8>> {
+        if self.is_empty() {
+            return Ok(encoded);
+        }
+        match format {
+            ImageFormat::Jpeg => self.embed_jpeg(encoded),
+            ImageFormat::Png => self.embed_png(encoded),
+            ImageFormat::WebP => self.embed_webp(encoded),
+            other => bail!("{other:?} outputs cannot carry EXIF or XMP metadata"),
+        }
+    }
+
+    fn read_jpeg(&mut self, data: &[u8]) {
+        for (marker, payload) in jpeg_segments(data) {
+            if marker != 0xE1 {
+                continue;
+            }
+            if let Some(exif) = payload.strip_prefix(JPEG_EXIF_PREFIX) {
+                self.exif.get_or_insert_with(|| exif.to_vec());
+            } else if let Some(xmp) = payload.strip_prefix(JPEG_XMP_PREFIX) {
+                self.xmp.get_or_insert_with(|| xmp.to_vec());
+            }
+        }
+    }
+
+    fn read_png(&mut self, data: &[u8]) {
+        for (kind, chunk) in png_chunks(data) {
+            match kind {
+                b"eXIf" => {
+                    self.exif.get_or_insert_with(|| chunk.to_vec());
+                }
+                b"iTXt" if self.xmp.is_none() => self.xmp = png_xmp(chunk),
+                _ => {}
+            }
+        }
+    }
+
+    fn read_webp(&mut self, data: &[u8]) {
+        for (kind, chunk) in webp_chunks(data) {
+            match kind {
+                b"EXIF" => {
+                    // Some writers keep the JPEG prefix.
+                    let exif = chunk.strip_prefix(JPEG_EXIF_PREFIX).unwrap_or(chunk);
+                    self.exif.get_or_insert_with(|| exif.to_vec());
+                }
+                b"XMP " => {
+                    self.xmp.get_or_insert_with(|| chunk.to_vec());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Inserts `APP1` segments right after `SOI` and any `APP0` (JFIF)
+    /// segment, where readers look for them.
+    fn embed_jpeg(&self, encoded: Vec<u8>) -> Result<Vec<u8>> {
+        if !encoded.starts_with(&[0xFF, 0xD8]) {
+            bail!("Encoded JPEG has no start-of-image marker");
+        }
+        let mut at = 2;
+        if let Some((0xE0, payload)) = jpeg_segments(&encoded).next() {
+            at += 4 + payload.len();
+        }
+        let mut segments = Vec::new();
+        for (prefix, packet, kind) in [
+            (JPEG_EXIF_PREFIX, &self.exif, "EXIF"),
+            (JPEG_XMP_PREFIX, &self.xmp, "XMP"),
+        ] {
+            let Some(packet) = packet else {
+                continue;
+            };
+            let len = prefix.len() + packet.len();
+            if len > MAX_JPEG_SEGMENT {
+                bail!("{kind} metadata of {len} bytes does not fit in a JPEG segment");
+            }
+            segments.extend_from_slice(&[0xFF, 0xE1]);
+            segments.extend_from_slice(&((len + 2) as u16).to_be_bytes());
+            segments.extend_from_slice(prefix);
+            segments.extend_from_slice(packet);
+        }
+        let mut output = Vec::with_capacity(encoded.len() + segments.len());
+        output.extend_from_slice(&encoded[..at]);
+        output.extend_from_slice(&segments);
+        output.extend_from_slice(&encoded[at..]);
+        Ok(output)
+    }
+
+    /// Inserts `eXIf` and an uncompressed `iTXt` chunk before the first
+    /// `IDAT`, as the PNG specification requires for `eXIf`.
+    fn embed_png(&self, encoded: Vec<u8>) -> Result<Vec<u8>> {
+        let Some(at) = png_chunk_offsets(&encoded)
+            .find(|&(kind, _)| kind == b"IDAT")
+            .map(|(_, offset)| offset)
+        else {
+            bail!("Encoded PNG has no image data");
+        };
+        let mut chunks = Vec::new();
+        if let Some(exif) = &self.exif {
+            push_png_chunk(&mut chunks, b"eXIf", exif);
+        }
+        if let Some(xmp) = &self.xmp {
+            // Keyword, no compression, empty language tag and translation.
+            let mut text = PNG_XMP_KEYWORD.to_vec();
+            text.extend_from_slice(&[0, 0, 0, 0, 0]);
+            text.extend_from_slice(xmp);
+            push_png_chunk(&mut chunks, b"iTXt", &text);
+        }
+        let mut output = Vec::with_capacity(encoded.len() + chunks.len());
+        output.extend_from_slice(&encoded[..at]);
+        output.extend_from_slice(&chunks);
+        output.extend_from_slice(&encoded[at..]);
+        Ok(output)
+    }
+
+    /// Appends `EXIF` and `XMP ` chunks, first converting a simple (lossy
+    /// or lossless only) file to the extended format that allows them.
+    fn embed_webp(&self, encoded: Vec<u8>) -> Result<Vec<u8>> {
+        if encoded.len() < 20 || &encoded[..4] != b"RIFF" || &encoded[8..12] != b"WEBP" {
+            bail!("Encoded WebP has no RIFF header");
+        }
+        let mut flags = 0u8;
+        if self.exif.is_some() {
+            flags |= 0x08;
+        }
+        if self.xmp.is_some() {
+            flags |= 0x04;
+        }
+        let mut output = Vec::with_capacity(encoded.len() + 64);
+        output.extend_from_slice(&encoded[..12]);
+        let first = &encoded[12..16];
+        if first == b"VP8X" {
+            output.extend_from_slice(&encoded[12..]);
+            output[20] |= flags;
+        } else {
+            let Some((width, height, alpha)) = webp_canvas(first, &encoded[20..]) else {
+                bail!("Encoded WebP has an unrecognized bitstream");
+            };
+            if alpha {
+                flags |= 0x10;
+            }
+            output.extend_from_slice(b"VP8X");
+            output.extend_from_slice(&10u32.to_le_bytes());
+            output.extend_from_slice(&[flags, 0, 0, 0]);
+            output.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+            output.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+            output.extend_from_slice(&encoded[12..]);
+        }
+        for (kind, packet) in [(b"EXIF", &self.exif), (b"XMP ", &self.xmp)] {
+            if let Some(packet) = packet {
+                output.extend_from_slice(kind);
+                output.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+                output.extend_from_slice(packet);
+                if packet.len() % 2 == 1 {
+                    output.push(0);
+                }
+            }
+        }
+        let riff_size = (output.len() - 8) as u32;
+        output[4..8].copy_from_slice(&riff_size.to_le_bytes());
+        Ok(output)
+    }
+}
+
+/// Marker segments up to the start of the compressed scan, as `(marker,
+/// payload)`.
+fn jpeg_segments(data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut at = if data.starts_with(&[0xFF, 0xD8]) {
+        2
+    } else {
+        data.len()
+    };
+    std::iter::from_fn(move || {
+        loop {
+            if data.get(at) != Some(&0xFF) {
+                return None;
+            }
+            let marker = *data.get(at + 1)?;
+            match marker {
+                // Fill bytes before a marker.
+                0xFF => at += 1,
+                0x01 | 0xD0..=0xD7 => at += 2,
+                0xD9 | 0xDA => return None,
+                _ => {
+                    let len =
+                        usize::from(u16::from_be_bytes([*data.get(at + 2)?, *data.get(at + 3)?]));
+                    let payload = data.get(at + 4..at + 2 + len.max(2))?;
+                    at += 2 + len;
+                    return Some((marker, payload));
+                }
+            }
+        }
+    })
+}
+
+/// Chunks of a PNG file as `(type, offset of the chunk)`.
+fn png_chunk_offsets(data: &[u8]) -> impl Iterator<Item = (&[u8], usize)> {
+    let mut at = if data.starts_with(PNG_SIGNATURE) {
+        PNG_SIGNATURE.len()
+    } else {
+        data.len()
+    };
+    std::iter::from_fn(move || {
+        let len = u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?) as usize;
+        let kind = data.get(at + 4..at + 8)?;
+        let offset = at;
+        at += 12 + len;
+        (at <= data.len()).then_some((kind, offset))
+    })
+}
+
+fn png_chunks(data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    png_chunk_offsets(data).map(move |(kind, offset)| {
+        let len = u32::from_be_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ]) as usize;
+        (kind, &data[offset + 8..offset + 8 + len])
+    })
+}
+
+fn push_png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    out.extend_from_slice(&crc.sum().to_be_bytes());
+}
+
+/// The XMP packet of an `iTXt` chunk, if it holds one.
+fn png_xmp(chunk: &[u8]) -> Option<Vec<u8>> {
+    let rest = chunk.strip_prefix(PNG_XMP_KEYWORD)?.strip_prefix(&[0])?;
+    let (&compressed, rest) = rest.split_first()?;
+    // Skip the compression method, language tag and translated keyword.
+    let rest = rest.get(1..)?;
+    let language_end = rest.iter().position(|&byte| byte == 0)?;
+    let rest = &rest[language_end + 1..];
+    let translated_end = rest.iter().position(|&byte| byte == 0)?;
+    let text = &rest[translated_end + 1..];
+    if compressed == 0 {
+        return Some(text.to_vec());
+    }
+    let mut xmp = Vec::new();
+    ZlibDecoder::new(text).read_to_end(&mut xmp).ok()?;
+    Some(xmp)
+}
+
+fn webp_chunks(data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut at = if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        12
+    } else {
+        data.len()
+    };
+    std::iter::from_fn(move || {
+        let kind = data.get(at..at + 4)?;
+        let len = u32::from_le_bytes(data.get(at + 4..at + 8)?.try_into().ok()?) as usize;
+        let chunk = data.get(at + 8..at + 8 + len)?;
+        at += 8 + len + len % 2;
+        Some((kind, chunk))
+    })
+}
+
+/// Canvas width, height and whether alpha is used, from a simple WebP's
+/// `VP8 ` or `VP8L` bitstream.
+fn webp_canvas(kind: &[u8], bitstream: &[u8]) -> Option<(u32, u32, bool)> {
+    match kind {
+        b"VP8L" => {
+            if *bitstream.first()? != 0x2F {
+                return None;
+            }
+            let bits = u32::from_le_bytes(bitstream.get(1..5)?.try_into().ok()?);
+            Some((
+                (bits & 0x3FFF) + 1,
+                ((bits >> 14) & 0x3FFF) + 1,
+                bits & (1 << 28) != 0,
+            ))
+        }
+        b"VP8 " => {
+            if bitstream.get(3..6)? != [0x9D, 0x01, 0x2A] {
+                return None;
+            }
+            let dimension = |at: usize| {
+                bitstream
+                    .get(at..at + 2)
+                    .map(|bytes| u32::from(u16::from_le_bytes([bytes[0], bytes[1]]) & 0x3FFF))
+            };
+            Some((dimension(6)?, dimension(8)?, false))
+        }
+        _ => None,
+    }
+}
+
+/// Rewrites the orientation tag in the first IFD of TIFF-structured EXIF
+/// data, if it has one.
+fn set_orientation(exif: &mut [u8], orientation: u16) -> Option<()> {
+    let big_endian = match exif.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let read_u16 = |exif: &[u8], at: usize| -> Option<u16> {
+        let bytes = [*exif.get(at)?, *exif.get(at + 1)?];
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let ifd = exif.get(4..8)?;
+    let ifd = if big_endian {
+        u32::from_be_bytes(ifd.try_into().ok()?)
+    } else {
+        u32::from_le_bytes(ifd.try_into().ok()?)
+    } as usize;
+    let entries = usize::from(read_u16(exif, ifd)?);
+    for entry in 0..entries {
+        let at = ifd + 2 + entry * 12;
+        // A SHORT whose value sits in the entry itself.
+        if read_u16(exif, at)? == EXIF_ORIENTATION_TAG && read_u16(exif, at + 2)? == 3 {
+            let value = if big_endian {
+                orientation.to_be_bytes()
+            } else {
+                orientation.to_le_bytes()
+            };
+            exif.get_mut(at + 8..at + 10)?.copy_from_slice(&value);
+            return Some(());
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, RgbImage};
+
+    use super::*;
+
+    /// Big-endian EXIF with orientation 6 (rotate 90 degrees clockwise).
+    const EXIF: &[u8] = &[
+        b'M', b'M', 0, 42, 0, 0, 0, 8, 0, 1, 0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0, 0, 0, 0, 0,
+    ];
+    const XMP: &[u8] =
+        b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><dc:rights>CC-BY</dc:rights></x:xmpmeta>";
+
+    fn encode(format: ImageFormat) -> Vec<u8> {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(3, 2, image::Rgb([9, 99, 199])));
+        let mut encoded = Cursor::new(Vec::new());
+        image.write_to(&mut encoded, format).unwrap();
+        encoded.into_inner()
+    }
+
+    #[test]
+    fn packets_survive_a_round_trip_through_each_container() {
+        let metadata = EmbeddedMetadata {
+            exif: Some(EXIF.to_vec()),
+            xmp: Some(XMP.to_vec()),
+        };
+        for format in [ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::WebP] {
+            assert!(EmbeddedMetadata::read(format, &encode(format)).is_empty());
+            let embedded = metadata.embed(format, encode(format)).unwrap();
+            assert_eq!(
+                EmbeddedMetadata::read(format, &embedded),
+                metadata,
+                "{format:?}"
+            );
+            let decoded = image::load_from_memory_with_format(&embedded, format).unwrap();
+            assert_eq!(decoded.width(), 3, "{format:?}");
+        }
+        assert!(
+            metadata
+                .embed(ImageFormat::Bmp, encode(ImageFormat::Bmp))
+                .is_err()
+        );
+        assert_eq!(metadata.select(false, true).kinds(), ["xmp"]);
+    }
+
+    #[test]
+    fn reset_orientation_marks_exif_upright() {
+        let mut metadata = EmbeddedMetadata {
+            exif: Some(EXIF.to_vec()),
+            xmp: None,
+        };
+        metadata.reset_orientation();
+        let exif = metadata.exif.unwrap();
+        assert_eq!(&exif[18..20], &[0, 1]);
+        assert_eq!(exif.len(), EXIF.len());
+    }
+}
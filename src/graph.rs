@@ -0,0 +1,163 @@
+//! Branching pipelines.
+//!
+//! Each stage normally runs on the output of the stage listed before it. A
+//! stage with `after: <id>` instead continues from the earlier stage carrying
+//! that `id`, so one decode can fan out to several encodes, and `output` on a
+//! stage overrides the output layout for it and everything downstream of it.
+//! Since `after` may only name an earlier stage, the list order is always a
+//! valid execution order and the stages form a single tree rooted at the
+//! first one; every stage without successors yields its own result.
+
+use std::collections::HashMap;
+
+use anyhow::{Result, bail};
+
+use crate::pipeline::{OutputSpec, StageSpec};
+
+#[derive(Debug, Clone)]
+pub struct StageGraph {
+    parents: Vec<Option<usize>>,
+    children: Vec<usize>,
+    outputs: Vec<OutputSpec>,
+}
+
+impl StageGraph {
+    /// A plain chain of `len` stages writing to `output`.
+    pub fn linear(len: usize, output: &OutputSpec) -> Self {
+        Self {
+            parents: (0..len).map(|index| index.checked_sub(1)).collect(),
+            children: (0..len).map(|index| usize::from(index + 1 < len)).collect(),
+            outputs: vec![output.clone(); len],
+        }
+    }
+
+    pub fn resolve(specs: &[StageSpec], output: &OutputSpec) -> Result<Self> {
+        let mut ids: HashMap<&str, usize> = HashMap::new();
+        let mut graph = Self {
+            parents: Vec::with_capacity(specs.len()),
+            children: vec![0; specs.len()],
+            outputs: Vec::with_capacity(specs.len()),
+        };
+        for (index, spec) in specs.iter().enumerate() {
+            let parent = match spec.after.as_deref() {
+                Some(after) => match ids.get(after) {
+                    Some(&parent) => Some(parent),
+                    None => bail!(
+                        "Stage {} ('{}') runs after unknown stage id '{after}'; `after` must name an earlier stage",
+                        index + 1,
+                        spec.stage
+                    ),
+                },
+                None => index.checked_sub(1),
+            };
+            if let Some(id) = spec.id.as_deref()
+                && ids.insert(id, index).is_some()
+            {
+                bail!("Duplicate stage id '{id}'");
+            }
+            if let Some(parent) = parent {
+                graph.children[parent] += 1;
+            }
+            let stage_output = spec
+                .output
+                .clone()
+                .or_else(|| parent.map(|parent| graph.outputs[parent].clone()))
+                .unwrap_or_else(|| output.clone());
+            graph.parents.push(parent);
+            graph.outputs.push(stage_output);
+        }
+        Ok(graph)
+    }
+
+    pub fn len(&self) -> usize {
+        self.parents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parents.is_empty()
+    }
+
+    /// Whether every stage has at most one successor.
+    pub fn is_linear(&self) -> bool {
+        self.children.iter().all(|&children| children <= 1)
+    }
+
+    pub fn parent(&self, index: usize) -> Option<usize> {
+        self.parents[index]
+    }
+
+    pub fn children(&self, index: usize) -> usize {
+        self.children[index]
+    }
+
+    pub fn output(&self, index: usize) -> &OutputSpec {
+        &self.outputs[index]
+    }
+
+    /// Stages whose output becomes a result, in pipeline order.
+    pub fn leaves(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len()).filter(|&index| self.children[index] == 0)
+    }
+
+    /// The stages `index` runs after, nearest first.
+    pub fn ancestors(&self, index: usize) -> impl Iterator<Item = usize> + '_ {
+        std::iter::successors(self.parents[index], |&parent| self.parents[parent])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn spec(stage: &str, id: Option<&str>, after: Option<&str>) -> StageSpec {
+        StageSpec {
+            stage: stage.to_string(),
+            id: id.map(str::to_string),
+            after: after.map(str::to_string),
+            ..StageSpec::default()
+        }
+    }
+
+    fn output(directory: &str) -> OutputSpec {
+        OutputSpec {
+            directory: PathBuf::from(directory),
+            structure: "{stem}.{ext}".into(),
+        }
+    }
+
+    #[test]
+    fn fans_out_from_named_stages() {
+        let mut avif = spec("encode", None, Some("decoded"));
+        avif.output = Some(output("avif"));
+        let specs = [
+            spec("decode", Some("decoded"), None),
+            spec("encode", None, None),
+            avif,
+            spec("rename", None, None),
+        ];
+        let graph = StageGraph::resolve(&specs, &output("out")).unwrap();
+        assert!(!graph.is_linear());
+        assert_eq!(graph.parent(2), Some(0));
+        assert_eq!(graph.parent(3), Some(2));
+        assert_eq!(graph.leaves().collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(graph.ancestors(3).collect::<Vec<_>>(), vec![2, 0]);
+        assert_eq!(graph.output(1).directory, PathBuf::from("out"));
+        assert_eq!(graph.output(3).directory, PathBuf::from("avif"));
+    }
+
+    #[test]
+    fn rejects_unknown_and_duplicate_ids() {
+        let forward = [
+            spec("decode", None, Some("later")),
+            spec("encode", Some("later"), None),
+        ];
+        assert!(StageGraph::resolve(&forward, &output("out")).is_err());
+        let duplicate = [
+            spec("decode", Some("a"), None),
+            spec("encode", Some("a"), None),
+        ];
+        assert!(StageGraph::resolve(&duplicate, &output("out")).is_err());
+    }
+}
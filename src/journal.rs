@@ -0,0 +1,241 @@
+//! Append-only history of `run` invocations.
+//!
+//! Every run gets an ID and, once it finishes, one JSON line in the journal
+//! (`.bunker-convert/runs.jsonl` by default) recording which recipe and
+//! lockfile produced it, how many inputs and outputs it touched, how long it
+//! took, its quality gate results and where its manifest was written.
+//! `bunker-convert runs list/show` reads the journal back.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::cancel;
+use crate::manifest::RunManifest;
+use crate::security::compute_sha256;
+
+pub const DEFAULT_JOURNAL: &str = ".bunker-convert/runs.jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    Succeeded,
+    Failed,
+    /// Stopped by Ctrl+C with inputs left pending.
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub id: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub status: RunStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub recipe: PathBuf,
+    pub recipe_sha256: Option<String>,
+    /// `<recipe>.lock` next to the recipe, when one exists.
+    pub lockfile: Option<PathBuf>,
+    pub lockfile_sha256: Option<String>,
+    pub inputs: usize,
+    pub outputs: usize,
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+    pub duration_ms: f64,
+    pub quality_passed: usize,
+    pub quality_skipped: usize,
+    pub manifest: Option<PathBuf>,
+    #[serde(skip)]
+    started: Option<Instant>,
+}
+
+impl RunRecord {
+    pub fn begin(recipe: &Path) -> Self {
+        let started_at = Utc::now();
+        let lockfile = Some(recipe.with_extension("lock")).filter(|path| path.is_file());
+        Self {
+            id: run_id(started_at, recipe),
+            started_at,
+            finished_at: None,
+            status: RunStatus::Failed,
+            error: None,
+            recipe: recipe.to_path_buf(),
+            recipe_sha256: compute_sha256(recipe).ok(),
+            lockfile_sha256: lockfile
+                .as_deref()
+                .and_then(|path| compute_sha256(path).ok()),
+            lockfile,
+            inputs: 0,
+            outputs: 0,
+            input_bytes: 0,
+            output_bytes: 0,
+            duration_ms: 0.0,
+            quality_passed: 0,
+            quality_skipped: 0,
+            manifest: None,
+            started: Some(Instant::now()),
+        }
+    }
+
+    pub fn record_results(&mut self, manifest: &RunManifest, manifest_path: Option<&Path>) {
+        let totals = &manifest.totals;
+        self.inputs = totals.inputs;
+        self.outputs = totals.outputs;
+        self.input_bytes = totals.input_bytes;
+        self.output_bytes = totals.output_bytes;
+        self.quality_passed = totals.quality_passed;
+        self.quality_skipped = totals.quality_skipped;
+        self.manifest = manifest_path.map(Path::to_path_buf);
+    }
+
+    pub fn finish(&mut self, outcome: &Result<()>) {
+        self.finished_at = Some(Utc::now());
+        self.duration_ms = self
+            .started
+            .map(|started| started.elapsed().as_secs_f64() * 1_000.0)
+            .unwrap_or_default();
+        match outcome {
+            Ok(()) => self.status = RunStatus::Succeeded,
+            Err(err) => {
+                self.status = if cancel::is_cancelled(err) {
+                    RunStatus::Cancelled
+                } else {
+                    RunStatus::Failed
+                };
+                self.error = Some(format!("{err:#}"));
+            }
+        }
+    }
+}
+
+/// `YYYYMMDD-HHMMSS-xxxxxxxx`: sortable by start time, with a short hash of
+/// the start instant, recipe and process to keep concurrent runs apart.
+fn run_id(started_at: DateTime<Utc>, recipe: &Path) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(
+        started_at
+            .timestamp_nanos_opt()
+            .unwrap_or_default()
+            .to_le_bytes(),
+    );
+    hasher.update(recipe.to_string_lossy().as_bytes());
+    hasher.update(std::process::id().to_le_bytes());
+    let digest = format!("{:x}", hasher.finalize());
+    format!("{}-{}", started_at.format("%Y%m%d-%H%M%S"), &digest[..8])
+}
+
+pub struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn append(&self, record: &RunRecord) -> Result<()> {
+        if let Some(parent) = self.path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            fs::create_dir_all(parent).with_context(|| {
+                format!("Failed to create journal directory: {}", parent.display())
+            })?;
+        }
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(&line))
+            .with_context(|| format!("Failed to append to run journal: {}", self.path.display()))
+    }
+
+    /// Every record in the journal, oldest first; a missing journal is empty.
+    pub fn records(&self) -> Result<Vec<RunRecord>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("Failed to read run journal: {}", self.path.display())
+                });
+            }
+        };
+        let mut records = Vec::new();
+        for (index, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            records.push(serde_json::from_str(&line).with_context(|| {
+                format!(
+                    "Invalid record on line {} of {}",
+                    index + 1,
+                    self.path.display()
+                )
+            })?);
+        }
+        Ok(records)
+    }
+
+    /// Finds the run whose ID starts or ends with `id` (so the short hash on
+    /// its own works), which must be unambiguous.
+    pub fn find(&self, id: &str) -> Result<RunRecord> {
+        let mut matches: Vec<RunRecord> = self
+            .records()?
+            .into_iter()
+            .filter(|record| record.id.starts_with(id) || record.id.ends_with(id))
+            .collect();
+        match matches.len() {
+            0 => bail!("No run matching '{id}' in {}", self.path.display()),
+            1 => Ok(matches.remove(0)),
+            count => bail!("'{id}' matches {count} runs; use a longer prefix"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn journal_round_trips_and_finds_by_prefix() {
+        let temp = tempfile::tempdir().unwrap();
+        let recipe = temp.path().join("recipe.yaml");
+        fs::write(&recipe, "version: 1").unwrap();
+        fs::write(temp.path().join("recipe.lock"), "locked").unwrap();
+        let journal = Journal::new(temp.path().join("history/runs.jsonl"));
+        assert!(journal.records().unwrap().is_empty());
+
+        let mut ok = RunRecord::begin(&recipe);
+        ok.finish(&Ok(()));
+        let mut failed = RunRecord::begin(&recipe);
+        failed.id = format!("{}-other", failed.id);
+        failed.finish(&Err(anyhow::anyhow!("gate failed")));
+        journal.append(&ok).unwrap();
+        journal.append(&failed).unwrap();
+
+        let records = journal.records().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].status, RunStatus::Succeeded);
+        assert!(records[0].lockfile_sha256.is_some());
+        assert_eq!(records[1].error.as_deref(), Some("gate failed"));
+
+        assert_eq!(journal.find(&failed.id).unwrap().id, failed.id);
+        assert_eq!(journal.find(&ok.id[16..]).unwrap().id, ok.id);
+        assert!(journal.find(&ok.id[..9]).is_err());
+        assert!(journal.find("19700101").is_err());
+    }
+}
@@ -1,15 +1,43 @@
+pub mod archive;
+pub mod audio;
 pub mod benchmark;
+pub mod buffers;
+pub mod cache;
+pub mod cancel;
+pub mod collision;
+pub mod condition;
+pub mod daemon;
+pub mod dedup;
+pub mod discovery;
+pub mod embedded;
+pub mod graph;
+pub mod journal;
 pub mod lockfile;
+pub mod manifest;
+pub mod memory;
+pub mod notify;
 pub mod observability;
+pub mod overwrite;
+pub mod perceptual;
 pub mod pipeline;
+pub mod plan;
 pub mod presets;
 pub mod quality;
+pub mod quantize;
 pub mod recipe;
+pub mod resume;
+pub mod retry;
 pub mod scheduler;
 pub mod security;
+pub mod sink;
+pub mod smoke;
+pub mod source;
 pub mod stages;
+pub mod structure;
+pub mod summary;
 pub mod validation;
 pub mod video;
+pub mod watch;
 
-pub use pipeline::{Artifact, PipelineExecutor, PipelineResult, StageRegistry};
+pub use pipeline::{Artifact, PipelineExecutor, PipelineResult, PipelineResults, StageRegistry};
 pub use recipe::Recipe;
@@ -59,11 +59,16 @@ pub fn generate_lock(recipe: &Recipe, path: &Path) -> Result<()> {
     Ok(())
 }
 
-fn hash_params(spec: &StageSpec) -> String {
+/// Digest of a stage's name and parameters, as pinned in lockfiles.
+pub fn hash_params(spec: &StageSpec) -> String {
     let mut hasher = Sha256::new();
     let value = serde_json::to_value(spec.params.clone().unwrap_or_default()).unwrap_or_default();
     let serialized = serde_json::to_vec(&value).unwrap_or_default();
     hasher.update(spec.stage.as_bytes());
     hasher.update(serialized);
+    // Only hashed when set so existing lockfiles keep their hashes.
+    if let Some(when) = &spec.when {
+        hasher.update(when.as_bytes());
+    }
     format!("{:x}", hasher.finalize())
 }
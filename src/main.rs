@@ -1,28 +1,54 @@
 use std::env;
 use std::fs::{self, File};
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use anyhow::{Context, Result, anyhow, bail};
+use bunker_convert::archive::{self, PackageEntry, PackageSource};
+use bunker_convert::audio::{flac, mp3, opus, wav};
 use bunker_convert::benchmark::{BenchmarkOptions, run_benchmark};
+use bunker_convert::cache::{self, DEFAULT_MAX_BYTES, OutputCache};
+use bunker_convert::cancel::{self, CancellationToken, Cancelled};
+use bunker_convert::collision::CollisionStrategy;
+use bunker_convert::daemon::{Daemon, DaemonRequest, default_socket_path, submit};
+use bunker_convert::dedup::{DedupPlan, DuplicateMode, Similarity};
+use bunker_convert::journal::{DEFAULT_JOURNAL, Journal, RunRecord, RunStatus};
 use bunker_convert::lockfile::generate_lock;
+use bunker_convert::manifest::{
+    ManifestFormat, PARTIAL_MANIFEST_FILE, PartialManifest, RunManifest,
+};
+use bunker_convert::memory::{format_bytes, parse_byte_size};
+use bunker_convert::notify::{self, NotifyOn, NotifySpec, RunEvent};
 use bunker_convert::observability::log_snapshot;
 #[cfg(feature = "metrics-server")]
 use bunker_convert::observability::server::MetricsServer;
+use bunker_convert::overwrite::OverwritePolicy;
+use bunker_convert::perceptual::HashAlgorithm;
 use bunker_convert::pipeline::{
-    OutputSpec, StageParameters, StageProgress, StageRegistry, StageSpec, build_pipeline,
+    OutputSpec, PipelineResult, StageParameters, StageProgress, StageRegistry, StageSpec,
+    build_pipeline,
 };
+use bunker_convert::plan::RunPlan;
 use bunker_convert::presets::generate_preset;
+use bunker_convert::quality::{
+    ComparisonReport, ImageInfo, compute_metrics, diff_heatmap, load_for_comparison,
+};
 use bunker_convert::recipe::{QualityGateSpec, Recipe};
+use bunker_convert::resume::{self, LEDGER_FILE, ResumeLedger, ResumeMode};
 use bunker_convert::scheduler::DevicePolicy;
 use bunker_convert::security::{compute_sha256, generate_sbom, write_sha256};
+use bunker_convert::smoke;
 use bunker_convert::stages;
+use bunker_convert::summary::SummaryTemplate;
 use bunker_convert::validation::validate_recipe;
+use bunker_convert::video::probe;
+use bunker_convert::watch::{DEFAULT_DEBOUNCE, InputWatcher, changed_inputs};
 use clap::error::ErrorKind;
 use clap::{CommandFactory, Parser, Subcommand, ValueHint};
 use serde_json::Value;
 use serde_json::to_writer_pretty;
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::{EnvFilter, prelude::*};
 
 #[cfg(feature = "otel")]
@@ -62,30 +88,108 @@ fn main() -> Result<()> {
             Commands::Run {
                 recipe,
                 dry_run,
+                json,
                 print_metrics,
                 metrics_json,
                 metrics_prometheus,
                 metrics_listen,
                 otlp_endpoint,
                 device_policy,
+                max_memory,
+                encode_workers,
+                jobs,
+                resume,
+                cache,
+                cache_dir,
+                cache_max_size,
+                manifest,
+                dedup,
+                dedup_distance,
+                dedup_hash,
+                on_collision,
+                overwrite,
+                archive,
+                archive_manifest,
+                archive_sidecars,
+                summary_template,
+                journal,
+                no_journal,
+                notify_webhook,
+                watch,
+                debounce,
             } => {
                 let _ = otlp_endpoint; // already handled in tracing configuration
-                run_recipe(
-                    recipe,
+                let cancellation = CancellationToken::new();
+                cancel::cancel_on_interrupt(&cancellation)?;
+                let options = RunOptions {
+                    recipe_path: recipe,
                     dry_run,
+                    json,
                     print_metrics,
                     metrics_json,
                     metrics_prometheus,
                     metrics_listen,
                     device_policy,
-                )
+                    max_memory,
+                    encode_workers,
+                    jobs,
+                    resume,
+                    cache: cache.then(|| cache_dir.unwrap_or_else(cache::default_cache_dir)),
+                    cache_max_size,
+                    manifest,
+                    dedup,
+                    similarity: dedup_distance.map(|distance| Similarity {
+                        algorithm: dedup_hash.unwrap_or(HashAlgorithm::Phash),
+                        distance,
+                    }),
+                    on_collision,
+                    overwrite,
+                    archive,
+                    archive_manifest,
+                    archive_sidecars,
+                    summary_template,
+                    journal: (!no_journal)
+                        .then(|| journal.unwrap_or_else(|| PathBuf::from(DEFAULT_JOURNAL))),
+                    notify_webhook,
+                    inputs: None,
+                    cancellation,
+                };
+                if watch {
+                    watch_recipe(options, Duration::from_millis(debounce))
+                } else {
+                    run_recipe(options)
+                }
             }
             Commands::ListStages => {
                 list_stages();
                 Ok(())
             }
             Commands::Validate { recipe } => validate_recipe_cmd(recipe),
+            Commands::Compare {
+                reference,
+                candidate,
+                json,
+                heatmap,
+            } => compare_command(&reference, &candidate, json, heatmap.as_deref()),
+            Commands::Daemon { socket } => daemon_command(socket),
+            Commands::Submit {
+                recipe,
+                socket,
+                device_policy,
+                encode_workers,
+                json,
+                shutdown,
+            } => submit_command(
+                recipe,
+                socket,
+                device_policy,
+                encode_workers,
+                json,
+                shutdown,
+            ),
             Commands::Lock { recipe, output } => lock_recipe(recipe, output),
+            Commands::Runs { action } => runs_command(action),
+            Commands::Cache { action } => cache_command(action),
             Commands::Recipe { action } => recipe_command(action),
             Commands::Bench { action } => bench_command(action),
             Commands::Security { action } => security_command(action),
@@ -159,28 +263,197 @@ fn configure_tracing(otlp_endpoint: Option<&str>) -> Result<()> {
     Ok(())
 }
 
-fn run_recipe(
+#[derive(Clone)]
+struct RunOptions {
     recipe_path: PathBuf,
     dry_run: bool,
+    /// Prints the `--dry-run` plan as JSON.
+    json: bool,
     print_metrics: bool,
     metrics_json: Option<PathBuf>,
     metrics_prometheus: Option<PathBuf>,
     metrics_listen: Option<String>,
     device_policy: DevicePolicy,
-) -> Result<()> {
+    max_memory: Option<String>,
+    encode_workers: Option<usize>,
+    jobs: Option<usize>,
+    resume: Option<ResumeMode>,
+    /// Output cache directory when `--cache` is set.
+    cache: Option<PathBuf>,
+    cache_max_size: Option<String>,
+    manifest: Option<PathBuf>,
+    dedup: Option<DuplicateMode>,
+    /// Near-duplicate matching for `dedup` (`--dedup-distance`).
+    similarity: Option<Similarity>,
+    on_collision: CollisionStrategy,
+    /// Overrides the recipe's `overwrite` policy.
+    overwrite: Option<OverwritePolicy>,
+    archive: Option<PathBuf>,
+    archive_manifest: bool,
+    archive_sidecars: bool,
+    summary_template: Option<String>,
+    /// Journal to append the run's record to; `None` with `--no-journal`.
+    journal: Option<PathBuf>,
+    /// Overrides the recipe's `notify.webhook`.
+    notify_webhook: Option<String>,
+    /// Converts these inputs instead of the recipe's (set by `--watch`).
+    inputs: Option<Vec<PathBuf>>,
+    cancellation: CancellationToken,
+}
+
+fn run_recipe(options: RunOptions) -> Result<()> {
+    if options.dry_run {
+        return execute_run(options, None);
+    }
+    let journal = options.journal.clone().map(Journal::new);
+    let notify = match options.notify_webhook.clone() {
+        Some(webhook) => Some(NotifySpec {
+            webhook,
+            on: NotifyOn::Always,
+        }),
+        None => Recipe::load(&options.recipe_path)
+            .ok()
+            .and_then(|recipe| recipe.notify),
+    };
+    let mut record = RunRecord::begin(&options.recipe_path);
+    info!(run_id = %record.id, "Starting run");
+    let outcome = execute_run(options, Some(&mut record));
+    record.finish(&outcome);
+    if let Some(journal) = journal {
+        match journal.append(&record) {
+            Ok(()) => {
+                info!(run_id = %record.id, journal = %journal.path().display(), "Run recorded")
+            }
+            Err(err) => warn!(run_id = %record.id, error = %err, "Failed to record run"),
+        }
+    }
+    if let Some(notify) = notify.filter(|notify| notify.wants(record.status)) {
+        match notify::post(&notify.webhook, &RunEvent::from_record(&record)) {
+            Ok(()) => info!(webhook = %notify.webhook, "Run notification sent"),
+            Err(err) => warn!(error = %format!("{err:#}"), "Failed to send run notification"),
+        }
+    }
+    outcome
+}
+
+/// Runs the recipe, then converts inputs again as they change until Ctrl+C.
+/// A failed run is logged and the watch continues.
+fn watch_recipe(options: RunOptions, debounce: Duration) -> Result<()> {
+    let recipe = Recipe::load(&options.recipe_path)?;
+    let watcher = InputWatcher::new(&recipe, debounce)?;
+    match run_recipe(options.clone()) {
+        Err(err) if cancel::is_cancelled(&err) => return Err(err),
+        Err(err) => error!(error = %format!("{err:#}"), "Run failed; waiting for changes"),
+        Ok(()) => {}
+    }
+    let roots: Vec<_> = watcher.roots().iter().map(|root| root.display()).collect();
+    info!(directories = ?roots, "Watching inputs for changes (Ctrl+C to stop)");
+    while let Some(changed) = watcher.wait(&options.cancellation)? {
+        let inputs = match recipe.expand_inputs() {
+            Ok(inputs) => changed_inputs(&inputs, &changed)?,
+            Err(err) => {
+                warn!(error = %format!("{err:#}"), "Failed to resolve inputs");
+                continue;
+            }
+        };
+        if inputs.is_empty() {
+            debug!(changed = changed.len(), "Changed files match no input");
+            continue;
+        }
+        info!(inputs = inputs.len(), "Inputs changed; converting");
+        let run = RunOptions {
+            inputs: Some(inputs),
+            // An `exists` ledger would skip modified inputs whose output
+            // is still there.
+            resume: options.resume.filter(|mode| *mode == ResumeMode::Hash),
+            ..options.clone()
+        };
+        match run_recipe(run) {
+            Err(err) if cancel::is_cancelled(&err) => return Err(err),
+            Err(err) => error!(error = %format!("{err:#}"), "Run failed; waiting for changes"),
+            Ok(()) => {}
+        }
+    }
+    info!("Stopped watching");
+    Ok(())
+}
+
+fn execute_run(options: RunOptions, record: Option<&mut RunRecord>) -> Result<()> {
+    let RunOptions {
+        recipe_path,
+        dry_run,
+        json,
+        print_metrics,
+        metrics_json,
+        metrics_prometheus,
+        metrics_listen,
+        device_policy,
+        max_memory,
+        encode_workers,
+        jobs,
+        resume,
+        cache,
+        cache_max_size,
+        manifest,
+        dedup,
+        similarity,
+        on_collision,
+        overwrite,
+        archive,
+        archive_manifest,
+        archive_sidecars,
+        summary_template,
+        journal: _,
+        notify_webhook: _,
+        inputs,
+        cancellation,
+    } = options;
+    let summary_template = summary_template
+        .as_deref()
+        .map(SummaryTemplate::parse)
+        .transpose()
+        .context("Invalid --summary-template value")?;
+    let max_memory = max_memory
+        .as_deref()
+        .map(parse_byte_size)
+        .transpose()
+        .context("Invalid --max-memory value")?;
+    let cache_max_bytes = cache_max_size
+        .as_deref()
+        .map(parse_byte_size)
+        .transpose()
+        .context("Invalid --cache-max-size value")?
+        .unwrap_or(DEFAULT_MAX_BYTES);
     let recipe = Recipe::load(&recipe_path)?;
+    let overwrite = overwrite.or(recipe.overwrite).unwrap_or_default();
     let registry = build_registry();
 
+    let inputs = match inputs {
+        Some(inputs) => inputs,
+        None => recipe.expand_inputs()?,
+    };
     if dry_run {
-        info!(
-            "Loaded recipe with {} stage(s). Available inputs: {:?}",
-            recipe.pipeline.len(),
-            recipe.inputs.iter().map(|i| &i.path).collect::<Vec<_>>()
-        );
+        let executor = build_pipeline(
+            &registry,
+            &recipe.pipeline,
+            recipe.output.clone(),
+            recipe.quality_gates.clone(),
+            device_policy,
+        )?
+        .with_collisions(on_collision)
+        .with_overwrite(overwrite);
+        let plan = RunPlan::build(&recipe_path, &recipe.pipeline, &executor, &inputs)?;
+        if json {
+            to_writer_pretty(io::stdout().lock(), &plan)?;
+            println!();
+        } else {
+            print!("{}", plan.render());
+        }
+        if plan.errors() > 0 {
+            bail!("{} input(s) would fail", plan.errors());
+        }
         return Ok(());
     }
-
-    let inputs = recipe.expand_inputs()?;
     if inputs.is_empty() {
         warn!("No inputs resolved for recipe. Nothing to process.");
         return Ok(());
@@ -192,7 +465,41 @@ fn run_recipe(
         recipe.output.clone(),
         recipe.quality_gates.clone(),
         device_policy,
-    )?;
+    )?
+    .with_memory_limit(max_memory)
+    .with_encode_workers(encode_workers)
+    .with_jobs(jobs)
+    .with_collisions(on_collision)
+    .with_overwrite(overwrite);
+    let executor = match resume {
+        Some(mode) => {
+            let ledger = ResumeLedger::open(
+                &recipe.output.directory.join(LEDGER_FILE),
+                mode,
+                resume::fingerprint(&recipe.pipeline, &recipe.output, &recipe.quality_gates)?,
+            )?;
+            info!(ledger = %ledger.path().display(), "Resuming from earlier runs");
+            executor.with_resume(Some(ledger))
+        }
+        None => executor,
+    };
+    let output_cache = match cache {
+        Some(dir) => {
+            let cache = OutputCache::open(
+                dir,
+                cache::pipeline_key(&recipe.pipeline, &recipe.quality_gates)?,
+                cache_max_bytes,
+            )?;
+            info!(cache = %cache.root().display(), "Using output cache");
+            Some(cache)
+        }
+        None => None,
+    };
+    let executor = executor.with_cache(output_cache.clone());
+    if dedup.is_some() && executor.is_branched() {
+        bail!("--dedup cannot be combined with a pipeline that branches or splits inputs");
+    }
+    let executor = executor.with_cancellation(cancellation.clone());
 
     let metrics_handle = executor.metrics();
 
@@ -214,9 +521,74 @@ fn run_recipe(
         );
     }
 
-    let results = executor.execute(&inputs)?;
+    let results = match dedup {
+        Some(mode) => {
+            let plan = match similarity {
+                Some(similarity) => DedupPlan::from_similar_inputs(&inputs, similarity)?,
+                None => DedupPlan::from_inputs(&inputs)?,
+            };
+            if !plan.duplicates.is_empty() {
+                info!(
+                    unique = plan.unique.len(),
+                    duplicates = plan.duplicates.len(),
+                    "Skipping duplicate inputs"
+                );
+            }
+            let results = executor.execute(&plan.unique)?;
+            if cancellation.is_cancelled() {
+                results
+            } else {
+                plan.expand(results, &recipe.output, mode)?
+            }
+        }
+        None => executor.execute(&inputs)?,
+    };
 
-    for result in results {
+    if resume.is_some() {
+        let snapshot = metrics_handle.snapshot();
+        info!(
+            processed = snapshot.inputs_processed,
+            skipped = snapshot.inputs_skipped,
+            "Resume summary"
+        );
+    }
+    let kept = metrics_handle.snapshot().outputs_skipped;
+    if kept > 0 {
+        info!(kept, policy = overwrite.as_str(), "Kept existing outputs");
+    }
+    let snapshot = metrics_handle.snapshot();
+    if snapshot.outputs_optimized > 0 {
+        info!(
+            outputs = snapshot.outputs_optimized,
+            saved = %format_bytes(snapshot.optimize_bytes_saved),
+            "Optimized outputs losslessly"
+        );
+    }
+    if let Some(cache) = &output_cache {
+        let snapshot = metrics_handle.snapshot();
+        info!(
+            hits = snapshot.cache_hits,
+            misses = snapshot.cache_misses,
+            "Cache summary"
+        );
+        let eviction = cache.evict()?;
+        if eviction.entries > 0 {
+            info!(
+                entries = eviction.entries,
+                freed = %format_bytes(eviction.bytes),
+                "Evicted least recently used cache entries"
+            );
+        }
+    }
+
+    if cancellation.is_cancelled() {
+        return record_cancelled_run(&recipe, &recipe_path, &inputs, &results, resume.is_some());
+    }
+
+    // The run covered whatever an earlier cancelled run left pending.
+    let _ = fs::remove_file(recipe.output.directory.join(PARTIAL_MANIFEST_FILE));
+
+    for result in &results {
         info!(
             input = %result.input.display(),
             output = %result.output.display(),
@@ -224,6 +596,40 @@ fn run_recipe(
         );
     }
 
+    let run_manifest =
+        if manifest.is_some() || archive_manifest || summary_template.is_some() || record.is_some()
+        {
+            Some(RunManifest::from_results(&results, Some(&recipe_path))?)
+        } else {
+            None
+        };
+
+    if let (Some(path), Some(run_manifest)) = (&manifest, &run_manifest) {
+        run_manifest.write(path, ManifestFormat::from_path(path))?;
+        info!(manifest = %path.display(), "Run manifest written");
+    }
+
+    if let (Some(record), Some(run_manifest)) = (record, &run_manifest) {
+        record.record_results(run_manifest, manifest.as_deref());
+    }
+
+    if let Some(path) = archive {
+        let mut entries =
+            archive::output_entries(&results, &recipe.output.directory, archive_sidecars)?;
+        if let Some(run_manifest) = run_manifest.as_ref().filter(|_| archive_manifest) {
+            let mut buffer = Vec::new();
+            run_manifest
+                .write_to(&mut buffer, ManifestFormat::Json)
+                .context("Failed to render run manifest")?;
+            entries.push(PackageEntry {
+                name: "manifest.json".to_string(),
+                source: PackageSource::Bytes(buffer),
+            });
+        }
+        archive::package(&path, &entries)?;
+        info!(archive = %path.display(), entries = entries.len(), "Outputs packaged");
+    }
+
     if print_metrics || metrics_json.is_some() || metrics_prometheus.is_some() {
         let snapshot = metrics_handle.snapshot();
         if print_metrics {
@@ -264,9 +670,45 @@ fn run_recipe(
         server.stop();
     }
 
+    if let (Some(template), Some(run_manifest)) = (summary_template, &run_manifest) {
+        println!("{}", template.render(run_manifest));
+    }
+
     Ok(())
 }
 
+/// Writes the partial manifest of a cancelled run and records its completed
+/// inputs in the resume ledger (unless `--resume` already did), so rerunning
+/// with `--resume` converts only the pending ones.
+fn record_cancelled_run(
+    recipe: &Recipe,
+    recipe_path: &Path,
+    inputs: &[PathBuf],
+    results: &[PipelineResult],
+    resumed: bool,
+) -> Result<()> {
+    let partial = PartialManifest::new(inputs, results, Some(recipe_path));
+    if !resumed {
+        let ledger = ResumeLedger::open(
+            &recipe.output.directory.join(LEDGER_FILE),
+            ResumeMode::Exists,
+            resume::fingerprint(&recipe.pipeline, &recipe.output, &recipe.quality_gates)?,
+        )?;
+        for result in results {
+            ledger.record(result)?;
+        }
+    }
+    let path = recipe.output.directory.join(PARTIAL_MANIFEST_FILE);
+    partial.write(&path)?;
+    warn!(
+        completed = partial.completed.len(),
+        pending = partial.pending.len(),
+        manifest = %path.display(),
+        "Run cancelled; rerun with --resume to convert the pending inputs"
+    );
+    Err(Cancelled.into())
+}
+
 fn quick_convert_from_args(args: Vec<String>) -> Result<()> {
     if args.is_empty() {
         bail!("Quick convert usage: bunker-convert <input> to <format> [to <output_dir>]");
@@ -353,6 +795,7 @@ fn quick_convert(
             stages.push(StageSpec {
                 stage: "decode".to_string(),
                 params: None,
+                ..StageSpec::default()
             });
             let mut encode_params = StageParameters::new();
             encode_params.insert(
@@ -362,12 +805,26 @@ fn quick_convert(
             stages.push(StageSpec {
                 stage: "encode".to_string(),
                 params: Some(encode_params),
+                ..StageSpec::default()
+            });
+        }
+        QuickConvertKind::Video if is_audio_extension(&normalized_format) => {
+            let mut extract_params = StageParameters::new();
+            extract_params.insert(
+                "format".to_string(),
+                Value::String(normalized_format.clone()),
+            );
+            stages.push(StageSpec {
+                stage: "audio_extract".to_string(),
+                params: Some(extract_params),
+                ..StageSpec::default()
             });
         }
         QuickConvertKind::Video => {
             stages.push(StageSpec {
                 stage: "video_decode".to_string(),
                 params: None,
+                ..StageSpec::default()
             });
             let mut encode_params = StageParameters::new();
             encode_params.insert(
@@ -377,6 +834,24 @@ fn quick_convert(
             stages.push(StageSpec {
                 stage: "video_encode".to_string(),
                 params: Some(encode_params),
+                ..StageSpec::default()
+            });
+        }
+        QuickConvertKind::Audio => {
+            stages.push(StageSpec {
+                stage: "audio_decode".to_string(),
+                params: None,
+                ..StageSpec::default()
+            });
+            let mut encode_params = StageParameters::new();
+            encode_params.insert(
+                "format".to_string(),
+                Value::String(normalized_format.clone()),
+            );
+            stages.push(StageSpec {
+                stage: "audio_encode".to_string(),
+                params: Some(encode_params),
+                ..StageSpec::default()
             });
         }
     }
@@ -475,6 +950,7 @@ fn quick_convert(
 enum QuickConvertKind {
     Image,
     Video,
+    Audio,
 }
 
 fn classify_inputs(inputs: &[PathBuf]) -> Result<QuickConvertKind> {
@@ -482,31 +958,63 @@ fn classify_inputs(inputs: &[PathBuf]) -> Result<QuickConvertKind> {
         return Ok(QuickConvertKind::Image);
     }
 
-    let first_is_video = is_video_path(&inputs[0]);
+    let first = classify_path(&inputs[0]);
     for path in inputs.iter().skip(1) {
-        let is_video = is_video_path(path);
-        if is_video != first_is_video {
-            bail!("Mixed image and video inputs are not supported by quick convert");
+        if classify_path(path) != first {
+            bail!("Mixed image, video and audio inputs are not supported by quick convert");
         }
     }
 
-    Ok(if first_is_video {
+    Ok(first)
+}
+
+/// What kind of input `path` is, by its leading bytes wherever it is named,
+/// or else by its extension.
+fn classify_path(path: &Path) -> QuickConvertKind {
+    let mut head = Vec::with_capacity(SNIFF_BYTES as usize);
+    if File::open(path)
+        .and_then(|file| file.take(SNIFF_BYTES).read_to_end(&mut head))
+        .is_ok()
+    {
+        if probe::is_video(&head) {
+            return QuickConvertKind::Video;
+        }
+        let audio = [wav::is_wav, flac::is_flac, mp3::is_mp3, opus::is_opus];
+        if audio.iter().any(|is_audio| is_audio(&head)) {
+            return QuickConvertKind::Audio;
+        }
+    }
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default();
+    if is_video_extension(extension) {
         QuickConvertKind::Video
+    } else if is_audio_extension(extension) {
+        QuickConvertKind::Audio
     } else {
         QuickConvertKind::Image
-    })
+    }
 }
 
-fn is_video_path(path: &Path) -> bool {
-    path.extension()
-        .and_then(|ext| ext.to_str())
-        .map(is_video_extension)
-        .unwrap_or(false)
-}
+/// Enough of an input to see its container signature and MP4 brand, or the
+/// `OpusHead` after an Ogg page header.
+const SNIFF_BYTES: u64 = 36;
 
 fn is_video_extension(ext: &str) -> bool {
     let normalized = ext.trim_start_matches('.').to_lowercase();
-    matches!(normalized.as_str(), "h264" | "264" | "annexb" | "avc")
+    matches!(
+        normalized.as_str(),
+        "h264" | "264" | "annexb" | "avc" | "mkv" | "webm"
+    )
+}
+
+fn is_audio_extension(ext: &str) -> bool {
+    let normalized = ext.trim_start_matches('.').to_lowercase();
+    matches!(
+        normalized.as_str(),
+        "wav" | "wave" | "flac" | "mp3" | "opus"
+    )
 }
 
 fn list_stages() {
@@ -540,6 +1048,225 @@ fn validate_recipe_cmd(recipe_path: PathBuf) -> Result<()> {
     }
 }
 
+fn compare_command(
+    reference: &Path,
+    candidate: &Path,
+    json: bool,
+    heatmap: Option<&Path>,
+) -> Result<()> {
+    let (reference_image, reference) = load_for_comparison(reference)?;
+    let (candidate_image, candidate) = load_for_comparison(candidate)?;
+    let metrics = compute_metrics(&reference_image, &candidate_image)?;
+
+    if let Some(path) = heatmap {
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            fs::create_dir_all(parent).with_context(|| {
+                format!("Failed to create heatmap directory: {}", parent.display())
+            })?;
+        }
+        diff_heatmap(&reference_image, &candidate_image)?
+            .save(path)
+            .with_context(|| format!("Failed to write heatmap: {}", path.display()))?;
+    }
+
+    let report = ComparisonReport {
+        reference,
+        candidate,
+        metrics,
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_image_info("Reference", &report.reference);
+        print_image_info("Candidate", &report.candidate);
+        println!("SSIM: {:.5}", report.metrics.ssim);
+        println!("PSNR: {:.2} dB", report.metrics.psnr);
+        println!("MSE:  {:.4}", report.metrics.mse);
+        if let Some(path) = heatmap {
+            println!("Heatmap: {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+fn print_image_info(label: &str, info: &ImageInfo) {
+    println!(
+        "{label}: {} ({}, {}x{}, {}, {} bytes)",
+        info.path.display(),
+        info.format.as_deref().unwrap_or("unknown"),
+        info.width,
+        info.height,
+        info.color,
+        info.bytes
+    );
+}
+
+fn daemon_command(socket: Option<PathBuf>) -> Result<()> {
+    let socket = socket.unwrap_or_else(default_socket_path);
+    Daemon::bind(build_registry(), &socket)?.serve()
+}
+
+fn submit_command(
+    recipe: Option<PathBuf>,
+    socket: Option<PathBuf>,
+    device_policy: DevicePolicy,
+    encode_workers: Option<usize>,
+    json: bool,
+    shutdown: bool,
+) -> Result<()> {
+    let socket = socket.unwrap_or_else(default_socket_path);
+    let request = match recipe {
+        _ if shutdown => DaemonRequest::Shutdown,
+        Some(recipe) => DaemonRequest::Run {
+            recipe,
+            cwd: env::current_dir().context("Failed to determine current directory")?,
+            device_policy,
+            encode_workers,
+        },
+        None => bail!("submit needs a recipe unless --shutdown is given"),
+    };
+    let response = submit(&socket, &request)?;
+    if json {
+        serde_json::to_writer_pretty(io::stdout().lock(), &response)?;
+        println!();
+    } else {
+        for output in &response.outputs {
+            println!("{} -> {}", output.input.display(), output.output.display());
+        }
+    }
+    match response.error {
+        Some(error) if !response.ok => bail!("Daemon job failed: {error}"),
+        _ => Ok(()),
+    }
+}
+
+fn runs_command(command: RunsCommands) -> Result<()> {
+    let open = |path: Option<PathBuf>| Journal::new(path.unwrap_or_else(|| DEFAULT_JOURNAL.into()));
+    match command {
+        RunsCommands::List {
+            journal,
+            limit,
+            json,
+        } => {
+            let journal = open(journal);
+            let mut records = journal.records()?;
+            if let Some(limit) = limit {
+                records.drain(..records.len().saturating_sub(limit));
+            }
+            if json {
+                serde_json::to_writer_pretty(io::stdout().lock(), &records)?;
+                println!();
+                return Ok(());
+            }
+            if records.is_empty() {
+                println!("No runs recorded in {}", journal.path().display());
+                return Ok(());
+            }
+            for record in records.iter().rev() {
+                let status = match record.status {
+                    RunStatus::Succeeded => "ok",
+                    RunStatus::Failed => "failed",
+                    RunStatus::Cancelled => "cancelled",
+                };
+                println!(
+                    "{}  {}  {:<6}  {:>5} in  {:>5} out  {:>8.1}s  {}",
+                    record.id,
+                    record.started_at.format("%Y-%m-%d %H:%M:%S"),
+                    status,
+                    record.inputs,
+                    record.outputs,
+                    record.duration_ms / 1_000.0,
+                    record.recipe.display()
+                );
+            }
+            Ok(())
+        }
+        RunsCommands::Show { id, journal, json } => {
+            let record = open(journal).find(&id)?;
+            if json {
+                serde_json::to_writer_pretty(io::stdout().lock(), &record)?;
+                println!();
+                return Ok(());
+            }
+            let optional = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+            println!("Run:            {}", record.id);
+            println!("Status:         {:?}", record.status);
+            if let Some(error) = &record.error {
+                println!("Error:          {error}");
+            }
+            println!("Started:        {}", record.started_at.to_rfc3339());
+            println!(
+                "Finished:       {}",
+                optional(record.finished_at.map(|at| at.to_rfc3339()))
+            );
+            println!("Duration:       {:.1}s", record.duration_ms / 1_000.0);
+            println!("Recipe:         {}", record.recipe.display());
+            println!("Recipe SHA-256: {}", optional(record.recipe_sha256.clone()));
+            println!(
+                "Lockfile:       {}",
+                optional(record.lockfile.as_ref().map(|p| p.display().to_string()))
+            );
+            println!(
+                "Lock SHA-256:   {}",
+                optional(record.lockfile_sha256.clone())
+            );
+            println!("Inputs:         {}", record.inputs);
+            println!("Outputs:        {}", record.outputs);
+            println!(
+                "Bytes:          {} -> {}",
+                record.input_bytes, record.output_bytes
+            );
+            println!(
+                "Quality gates:  {} passed, {} skipped",
+                record.quality_passed, record.quality_skipped
+            );
+            println!(
+                "Manifest:       {}",
+                optional(record.manifest.as_ref().map(|p| p.display().to_string()))
+            );
+            Ok(())
+        }
+    }
+}
+
+fn cache_command(command: CacheCommands) -> Result<()> {
+    match command {
+        CacheCommands::Stats { cache_dir, json } => {
+            let stats = cache::stats(&cache_dir.unwrap_or_else(cache::default_cache_dir))?;
+            if json {
+                serde_json::to_writer_pretty(io::stdout().lock(), &stats)?;
+                println!();
+                return Ok(());
+            }
+            let optional = |value: Option<chrono::DateTime<chrono::Utc>>| {
+                value
+                    .map(|at| at.to_rfc3339())
+                    .unwrap_or_else(|| "-".to_string())
+            };
+            println!("Directory:  {}", stats.directory.display());
+            println!("Entries:    {}", stats.entries);
+            println!("Objects:    {}", stats.objects);
+            println!("Size:       {}", format_bytes(stats.bytes));
+            println!("Oldest use: {}", optional(stats.oldest_use));
+            println!("Newest use: {}", optional(stats.newest_use));
+            Ok(())
+        }
+        CacheCommands::Clear { cache_dir } => {
+            let dir = cache_dir.unwrap_or_else(cache::default_cache_dir);
+            let cleared = cache::clear(&dir)?;
+            println!(
+                "Removed {} entries ({}) from {}",
+                cleared.entries,
+                format_bytes(cleared.bytes),
+                dir.display()
+            );
+            Ok(())
+        }
+    }
+}
+
 fn lock_recipe(recipe_path: PathBuf, output_path: PathBuf) -> Result<()> {
     let recipe = Recipe::load(&recipe_path)?;
     let registry = build_registry();
@@ -590,6 +1317,19 @@ fn recipe_command(command: RecipeCommands) -> Result<()> {
             Ok(())
         }
         RecipeCommands::Lint { recipes } => lint_recipes(&recipes),
+        RecipeCommands::Test {
+            recipe,
+            fixtures,
+            output_dir,
+            device_policy,
+            json,
+        } => test_recipe(
+            &recipe,
+            fixtures.as_deref(),
+            output_dir.as_deref(),
+            device_policy,
+            json,
+        ),
         RecipeCommands::Diff { lhs, rhs } => diff_recipes(&lhs, &rhs),
     }
 }
@@ -699,6 +1439,88 @@ fn lint_recipes(recipes: &[PathBuf]) -> Result<()> {
     Ok(())
 }
 
+fn test_recipe(
+    recipe_path: &Path,
+    fixtures: Option<&Path>,
+    output_dir: Option<&Path>,
+    device_policy: DevicePolicy,
+    json: bool,
+) -> Result<()> {
+    let recipe = Recipe::load(recipe_path)?;
+    let registry = build_registry();
+    let report = validate_recipe(&recipe, &registry);
+    if !report.is_ok() {
+        for error_msg in &report.errors {
+            error!(file = %recipe_path.display(), "{error_msg}");
+        }
+        bail!(
+            "Cannot test recipe with {} validation error(s)",
+            report.errors.len()
+        );
+    }
+
+    let scratch = tempfile::tempdir().context("Failed to create scratch directory")?;
+    let fixtures = match fixtures {
+        Some(dir) => smoke::collect_fixtures(dir)?,
+        None => smoke::write_builtin_fixtures(&scratch.path().join("fixtures"))?,
+    };
+    let output_dir = output_dir
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| scratch.path().join("out"));
+    let report = smoke::run_smoke_test(
+        &registry,
+        &recipe,
+        recipe_path,
+        &fixtures,
+        &output_dir,
+        device_policy,
+    )?;
+
+    if json {
+        serde_json::to_writer_pretty(io::stdout().lock(), &report)?;
+        println!();
+    } else {
+        for fixture in &report.fixtures {
+            let verdict = if fixture.passed() { "PASS" } else { "FAIL" };
+            println!("{verdict} {}", fixture.fixture.display());
+            for check in &fixture.stages {
+                println!("  {:<16} {}", check.stage, check.status.label());
+            }
+            match fixture.ssim {
+                Some(ssim) => println!(
+                    "  {:<16} {} (SSIM {ssim:.4})",
+                    "quality gates",
+                    fixture.quality.label()
+                ),
+                None => println!("  {:<16} {}", "quality gates", fixture.quality.label()),
+            }
+            match &fixture.output {
+                Some(output) if fixture.output_exists => {
+                    println!("  {:<16} pass ({})", "output", output.display())
+                }
+                Some(output) => println!("  {:<16} FAIL (missing {})", "output", output.display()),
+                None => println!("  {:<16} not run", "output"),
+            }
+            if let Some(error) = &fixture.error {
+                println!("  error: {error}");
+            }
+        }
+    }
+
+    let failures = report.failures();
+    if failures > 0 {
+        bail!(
+            "Recipe test failed for {failures} of {} fixture(s)",
+            report.fixtures.len()
+        );
+    }
+    info!(
+        fixtures = report.fixtures.len(),
+        "Recipe test passed for every fixture"
+    );
+    Ok(())
+}
+
 fn diff_recipes(lhs: &Path, rhs: &Path) -> Result<()> {
     let left = Recipe::load(lhs)?;
     let right = Recipe::load(rhs)?;
@@ -882,12 +1704,18 @@ struct Cli {
     quick_args: Vec<String>,
 }
 
+// Parsed once per process, so the size of `Run` does not matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
     Run {
         recipe: PathBuf,
+        /// Print the execution plan (stages, devices, output paths) without converting
         #[arg(long)]
         dry_run: bool,
+        /// Print the --dry-run plan as JSON
+        #[arg(long, requires = "dry_run")]
+        json: bool,
         #[arg(long)]
         print_metrics: bool,
         #[arg(long = "metrics-json")]
@@ -900,6 +1728,72 @@ enum Commands {
         otlp_endpoint: Option<String>,
         #[arg(long = "device-policy", value_enum, default_value_t = DevicePolicy::Auto)]
         device_policy: DevicePolicy,
+        #[arg(long = "max-memory", value_name = "SIZE")]
+        max_memory: Option<String>,
+        #[arg(long = "encode-workers", value_name = "N")]
+        encode_workers: Option<usize>,
+        #[arg(long, short = 'j', value_name = "N", conflicts_with = "encode_workers")]
+        jobs: Option<usize>,
+        #[arg(
+            long,
+            value_enum,
+            value_name = "MODE",
+            num_args = 0..=1,
+            default_missing_value = "exists"
+        )]
+        resume: Option<ResumeMode>,
+        #[arg(long)]
+        cache: bool,
+        #[arg(long = "cache-dir", value_name = "DIR", requires = "cache")]
+        cache_dir: Option<PathBuf>,
+        #[arg(long = "cache-max-size", value_name = "SIZE", requires = "cache")]
+        cache_max_size: Option<String>,
+        #[arg(long, value_name = "PATH")]
+        manifest: Option<PathBuf>,
+        #[arg(long, value_enum, value_name = "MODE")]
+        dedup: Option<DuplicateMode>,
+        /// Also treat inputs whose perceptual hashes differ by at most BITS (0-64) as duplicates
+        #[arg(
+            long = "dedup-distance",
+            value_name = "BITS",
+            requires = "dedup",
+            value_parser = clap::value_parser!(u32).range(0..=64)
+        )]
+        dedup_distance: Option<u32>,
+        /// Perceptual hash compared by --dedup-distance [default: phash]
+        #[arg(
+            long = "dedup-hash",
+            value_enum,
+            value_name = "ALGORITHM",
+            requires = "dedup_distance"
+        )]
+        dedup_hash: Option<HashAlgorithm>,
+        /// What to do when two inputs resolve to the same output path
+        #[arg(long = "on-collision", value_enum, value_name = "STRATEGY", default_value_t = CollisionStrategy::Error)]
+        on_collision: CollisionStrategy,
+        /// Whether to replace output files that already exist [default: always]
+        #[arg(long, value_enum, value_name = "POLICY")]
+        overwrite: Option<OverwritePolicy>,
+        #[arg(long, value_name = "PATH")]
+        archive: Option<PathBuf>,
+        #[arg(long = "archive-manifest", requires = "archive")]
+        archive_manifest: bool,
+        #[arg(long = "archive-sidecars", requires = "archive")]
+        archive_sidecars: bool,
+        #[arg(long = "summary-template", value_name = "TEMPLATE")]
+        summary_template: Option<String>,
+        #[arg(long, value_name = "PATH")]
+        journal: Option<PathBuf>,
+        #[arg(long = "no-journal", conflicts_with = "journal")]
+        no_journal: bool,
+        #[arg(long = "notify-webhook", value_name = "URL")]
+        notify_webhook: Option<String>,
+        /// Keep running and convert inputs again as they are added or modified
+        #[arg(long, conflicts_with_all = ["dry_run", "archive"])]
+        watch: bool,
+        /// Quiet period before a batch of changes is converted
+        #[arg(long, value_name = "MS", default_value_t = DEFAULT_DEBOUNCE.as_millis() as u64, requires = "watch")]
+        debounce: u64,
     },
     ListStages,
     Validate {
@@ -909,6 +1803,40 @@ enum Commands {
         recipe: PathBuf,
         output: PathBuf,
     },
+    Compare {
+        reference: PathBuf,
+        candidate: PathBuf,
+        #[arg(long)]
+        json: bool,
+        #[arg(long, value_name = "PATH")]
+        heatmap: Option<PathBuf>,
+    },
+    Daemon {
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+    },
+    Submit {
+        #[arg(required_unless_present = "shutdown")]
+        recipe: Option<PathBuf>,
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+        #[arg(long = "device-policy", value_enum, default_value_t = DevicePolicy::Auto)]
+        device_policy: DevicePolicy,
+        #[arg(long = "encode-workers", value_name = "N")]
+        encode_workers: Option<usize>,
+        #[arg(long)]
+        json: bool,
+        #[arg(long)]
+        shutdown: bool,
+    },
+    Runs {
+        #[command(subcommand)]
+        action: RunsCommands,
+    },
+    Cache {
+        #[command(subcommand)]
+        action: CacheCommands,
+    },
     Recipe {
         #[command(subcommand)]
         action: RecipeCommands,
@@ -923,6 +1851,39 @@ enum Commands {
     },
 }
 
+#[derive(Subcommand)]
+enum RunsCommands {
+    List {
+        #[arg(long, value_name = "PATH")]
+        journal: Option<PathBuf>,
+        #[arg(long, value_name = "N")]
+        limit: Option<usize>,
+        #[arg(long)]
+        json: bool,
+    },
+    Show {
+        id: String,
+        #[arg(long, value_name = "PATH")]
+        journal: Option<PathBuf>,
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheCommands {
+    Stats {
+        #[arg(long = "cache-dir", value_name = "DIR")]
+        cache_dir: Option<PathBuf>,
+        #[arg(long)]
+        json: bool,
+    },
+    Clear {
+        #[arg(long = "cache-dir", value_name = "DIR")]
+        cache_dir: Option<PathBuf>,
+    },
+}
+
 #[derive(Subcommand)]
 enum RecipeCommands {
     New {
@@ -935,6 +1896,17 @@ enum RecipeCommands {
         #[arg(required = true)]
         recipes: Vec<PathBuf>,
     },
+    Test {
+        recipe: PathBuf,
+        #[arg(long, value_name = "DIR")]
+        fixtures: Option<PathBuf>,
+        #[arg(long = "output-dir", value_name = "DIR")]
+        output_dir: Option<PathBuf>,
+        #[arg(long = "device-policy", value_enum, default_value_t = DevicePolicy::Auto)]
+        device_policy: DevicePolicy,
+        #[arg(long)]
+        json: bool,
+    },
     Diff {
         lhs: PathBuf,
         rhs: PathBuf,
@@ -0,0 +1,361 @@
+//! Delivery manifest written after a run.
+//!
+//! The manifest lists every input with the output it produced, byte sizes,
+//! SHA256 digests, processing time, quality gate outcome and the full result
+//! metadata, followed by run totals, so a delivery can be checked file by file
+//! on the receiving end and downstream tooling can pick up what each stage
+//! recorded. JSONL manifests hold one entry per line and no totals, for
+//! consumers that stream them.
+//! A cancelled run writes a [`PartialManifest`] instead, splitting its inputs
+//! into completed and pending ones.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::pipeline::PipelineResult;
+use crate::security::compute_sha256;
+
+/// Partial manifest file name, in the output directory.
+pub const PARTIAL_MANIFEST_FILE: &str = ".bunker-partial.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestFormat {
+    Json,
+    Jsonl,
+    Csv,
+}
+
+impl ManifestFormat {
+    /// Picks CSV for `.csv` paths, JSONL for `.jsonl`/`.ndjson` and JSON for
+    /// everything else.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => Self::Csv,
+            Some(ext)
+                if ext.eq_ignore_ascii_case("jsonl") || ext.eq_ignore_ascii_case("ndjson") =>
+            {
+                Self::Jsonl
+            }
+            _ => Self::Json,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunManifest {
+    pub generated_at: String,
+    pub recipe: Option<PathBuf>,
+    pub entries: Vec<ManifestEntry>,
+    pub totals: ManifestTotals,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ManifestEntry {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub input_bytes: Option<u64>,
+    pub output_bytes: Option<u64>,
+    pub output_sha256: Option<String>,
+    pub duration_ms: f64,
+    pub quality: ManifestQuality,
+    /// Input whose output this entry reuses when it was skipped as a duplicate.
+    pub duplicate_of: Option<PathBuf>,
+    pub aliased: bool,
+    /// Everything the stages recorded for the input. Not included in CSV.
+    pub metadata: Map<String, Value>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ManifestQuality {
+    pub status: String,
+    pub ssim: Option<f64>,
+    pub psnr: Option<f64>,
+    pub mse: Option<f64>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ManifestTotals {
+    pub inputs: usize,
+    pub outputs: usize,
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+    pub duration_ms: f64,
+    pub quality_passed: usize,
+    pub quality_skipped: usize,
+    pub duplicates: usize,
+}
+
+impl RunManifest {
+    pub fn from_results(results: &[PipelineResult], recipe: Option<&Path>) -> Result<Self> {
+        let mut entries = Vec::with_capacity(results.len());
+        let mut totals = ManifestTotals::default();
+
+        for result in results {
+            let entry = ManifestEntry::from_result(result)?;
+            totals.inputs += 1;
+            totals.input_bytes += entry.input_bytes.unwrap_or(0);
+            if entry.duplicate_of.is_some() {
+                totals.duplicates += 1;
+            }
+            if let Some(bytes) = entry.output_bytes.filter(|_| !entry.aliased) {
+                totals.outputs += 1;
+                totals.output_bytes += bytes;
+            }
+            totals.duration_ms += entry.duration_ms;
+            match entry.quality.status.as_str() {
+                "passed" => totals.quality_passed += 1,
+                "skipped" => totals.quality_skipped += 1,
+                _ => {}
+            }
+            entries.push(entry);
+        }
+
+        Ok(Self {
+            generated_at: Utc::now().to_rfc3339(),
+            recipe: recipe.map(Path::to_path_buf),
+            entries,
+            totals,
+        })
+    }
+
+    pub fn write(&self, path: &Path, format: ManifestFormat) -> Result<()> {
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            fs::create_dir_all(parent).with_context(|| {
+                format!("Failed to create manifest directory: {}", parent.display())
+            })?;
+        }
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create manifest file: {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        self.write_to(&mut writer, format)
+            .with_context(|| format!("Failed to write manifest: {}", path.display()))?;
+        writer
+            .flush()
+            .with_context(|| format!("Failed to write manifest: {}", path.display()))?;
+        Ok(())
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write, format: ManifestFormat) -> std::io::Result<()> {
+        match format {
+            ManifestFormat::Json => Ok(serde_json::to_writer_pretty(writer, self)?),
+            ManifestFormat::Jsonl => self.write_jsonl(writer),
+            ManifestFormat::Csv => self.write_csv(writer),
+        }
+    }
+
+    fn write_jsonl(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        for entry in &self.entries {
+            serde_json::to_writer(&mut *writer, entry)?;
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
+
+    fn write_csv(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        writeln!(
+            writer,
+            "input,output,input_bytes,output_bytes,output_sha256,duration_ms,quality_status,ssim,psnr,mse,duplicate_of"
+        )?;
+        for entry in &self.entries {
+            let row = [
+                entry.input.display().to_string(),
+                entry.output.display().to_string(),
+                optional(entry.input_bytes),
+                optional(entry.output_bytes),
+                entry.output_sha256.clone().unwrap_or_default(),
+                format!("{:.3}", entry.duration_ms),
+                entry.quality.status.clone(),
+                optional(entry.quality.ssim),
+                optional(entry.quality.psnr),
+                optional(entry.quality.mse),
+                optional(entry.duplicate_of.as_ref().map(|path| path.display())),
+            ];
+            let escaped: Vec<String> = row.iter().map(|field| csv_field(field)).collect();
+            writeln!(writer, "{}", escaped.join(","))?;
+        }
+        let totals = &self.totals;
+        writeln!(
+            writer,
+            "TOTAL ({} inputs),{} outputs,{},{},,{:.3},{} passed / {} skipped,,,,{} duplicates",
+            totals.inputs,
+            totals.outputs,
+            totals.input_bytes,
+            totals.output_bytes,
+            totals.duration_ms,
+            totals.quality_passed,
+            totals.quality_skipped,
+            totals.duplicates
+        )
+    }
+}
+
+impl ManifestEntry {
+    fn from_result(result: &PipelineResult) -> Result<Self> {
+        let metadata = &result.metadata;
+        let input_bytes = fs::metadata(&result.input)
+            .ok()
+            .map(|meta| meta.len())
+            .or_else(|| metadata.get("input.size_bytes").and_then(Value::as_u64));
+        let output_exists = result.output.is_file();
+        let output_bytes = metadata
+            .get("output.size_bytes")
+            .and_then(Value::as_u64)
+            .or_else(|| {
+                output_exists
+                    .then(|| fs::metadata(&result.output).ok().map(|meta| meta.len()))
+                    .flatten()
+            });
+        let output_sha256 = match metadata.get("output.sha256").and_then(Value::as_str) {
+            Some(digest) => Some(digest.to_string()),
+            None if output_exists => Some(compute_sha256(&result.output)?),
+            None => None,
+        };
+        let status = metadata
+            .get("quality.status")
+            .and_then(Value::as_str)
+            .unwrap_or("not_configured")
+            .to_string();
+        let duplicate_of = metadata
+            .get("dedup.source")
+            .and_then(Value::as_str)
+            .map(PathBuf::from);
+        let aliased = metadata.get("dedup.action").and_then(Value::as_str) == Some("alias");
+
+        Ok(Self {
+            input: result.input.clone(),
+            output: result.output.clone(),
+            input_bytes,
+            output_bytes,
+            output_sha256,
+            duration_ms: result.duration.as_secs_f64() * 1_000.0,
+            quality: ManifestQuality {
+                status,
+                ssim: metadata.get("quality.ssim").and_then(Value::as_f64),
+                psnr: metadata.get("quality.psnr").and_then(Value::as_f64),
+                mse: metadata.get("quality.mse").and_then(Value::as_f64),
+            },
+            duplicate_of,
+            aliased,
+            metadata: metadata.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PartialManifest {
+    pub cancelled_at: String,
+    pub recipe: Option<PathBuf>,
+    pub completed: Vec<CompletedInput>,
+    /// Inputs the run never converted, in input order.
+    pub pending: Vec<PathBuf>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CompletedInput {
+    pub input: PathBuf,
+    pub output: PathBuf,
+}
+
+impl PartialManifest {
+    pub fn new(inputs: &[PathBuf], results: &[PipelineResult], recipe: Option<&Path>) -> Self {
+        let done: HashSet<&Path> = results
+            .iter()
+            .map(|result| result.input.as_path())
+            .collect();
+        Self {
+            cancelled_at: Utc::now().to_rfc3339(),
+            recipe: recipe.map(Path::to_path_buf),
+            completed: results
+                .iter()
+                .map(|result| CompletedInput {
+                    input: result.input.clone(),
+                    output: result.output.clone(),
+                })
+                .collect(),
+            pending: inputs
+                .iter()
+                .filter(|input| !done.contains(input.as_path()))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            fs::create_dir_all(parent).with_context(|| {
+                format!("Failed to create manifest directory: {}", parent.display())
+            })?;
+        }
+        let content = serde_json::to_vec_pretty(self)?;
+        fs::write(path, content)
+            .with_context(|| format!("Failed to write manifest: {}", path.display()))
+    }
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_fields_are_quoted_when_needed() {
+        assert_eq!(csv_field("plain.png"), "plain.png");
+        assert_eq!(csv_field("a,b.png"), "\"a,b.png\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn partial_manifest_lists_unconverted_inputs_as_pending() {
+        let inputs: Vec<PathBuf> = ["a.png", "b.png", "c.png"].map(PathBuf::from).to_vec();
+        let results = [PipelineResult {
+            input: PathBuf::from("b.png"),
+            output: PathBuf::from("out/b.webp"),
+            metadata: Default::default(),
+            duration: Default::default(),
+        }];
+        let manifest = PartialManifest::new(&inputs, &results, None);
+        assert_eq!(manifest.completed.len(), 1);
+        assert_eq!(
+            manifest.pending,
+            vec![PathBuf::from("a.png"), PathBuf::from("c.png")]
+        );
+    }
+
+    #[test]
+    fn format_follows_extension() {
+        assert_eq!(
+            ManifestFormat::from_path(Path::new("out/manifest.CSV")),
+            ManifestFormat::Csv
+        );
+        assert_eq!(
+            ManifestFormat::from_path(Path::new("out/manifest.json")),
+            ManifestFormat::Json
+        );
+        assert_eq!(
+            ManifestFormat::from_path(Path::new("out/manifest.jsonl")),
+            ManifestFormat::Jsonl
+        );
+    }
+}
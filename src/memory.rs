@@ -0,0 +1,198 @@
+//! Memory ceiling for in-flight artifacts.
+//!
+//! Each input reserves its estimated decoded footprint before it is loaded
+//! and releases it once its result has been produced. When the ceiling is
+//! reached, further admissions wait until earlier artifacts are released.
+
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+
+use anyhow::{Result, anyhow, bail};
+use image::{ImageDecoder, ImageReader};
+
+use crate::source::MAP_THRESHOLD_BYTES;
+
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: u64,
+    in_use: Mutex<u64>,
+    released: Condvar,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: u64) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            in_use: Mutex::new(0),
+            released: Condvar::new(),
+        })
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub fn in_use(&self) -> u64 {
+        self.in_use.lock().map(|guard| *guard).unwrap_or(0)
+    }
+
+    /// Blocks until `bytes` fit under the ceiling, then reserves them.
+    pub fn acquire(self: &Arc<Self>, bytes: u64) -> Result<MemoryReservation> {
+        if bytes > self.limit {
+            bail!(
+                "Input needs an estimated {} but the memory ceiling is {}",
+                format_bytes(bytes),
+                format_bytes(self.limit)
+            );
+        }
+        let mut in_use = self
+            .in_use
+            .lock()
+            .map_err(|_| anyhow!("memory budget lock poisoned"))?;
+        while *in_use + bytes > self.limit {
+            in_use = self
+                .released
+                .wait(in_use)
+                .map_err(|_| anyhow!("memory budget lock poisoned"))?;
+        }
+        *in_use += bytes;
+        Ok(MemoryReservation {
+            budget: Arc::clone(self),
+            bytes,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct MemoryReservation {
+    budget: Arc<MemoryBudget>,
+    bytes: u64,
+}
+
+impl MemoryReservation {
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        if let Ok(mut in_use) = self.budget.in_use.lock() {
+            *in_use = in_use.saturating_sub(self.bytes);
+        }
+        self.budget.released.notify_all();
+    }
+}
+
+/// Estimates the live memory an input will occupy while it moves through the
+/// pipeline: its raw bytes, the decoded original and the working images.
+/// `working_pixels` maps the decoded width and height to the pixels the
+/// pipeline's working images peak at, so stages that enlarge the image are
+/// covered. Inputs whose header cannot be read are charged their file size,
+/// except that memory-mapped inputs are paged in on demand and not charged.
+pub fn estimate_artifact_bytes(input: &Path, working_pixels: impl Fn(u32, u32) -> u64) -> u64 {
+    let file_len = fs::metadata(input)
+        .map(|meta| meta.len())
+        .ok()
+        .filter(|len| *len < MAP_THRESHOLD_BYTES)
+        .unwrap_or(0);
+    let decoded = ImageReader::open(input)
+        .ok()
+        .and_then(|reader| reader.with_guessed_format().ok())
+        .and_then(|reader| reader.into_decoder().ok())
+        .map(|decoder| {
+            let (width, height) = decoder.dimensions();
+            let bpp = decoder.color_type().bytes_per_pixel() as u64;
+            let original = u64::from(width) * u64::from(height);
+            // Stages work on RGBA8 or wider, so never charge less than that.
+            original.saturating_add(working_pixels(width, height)) * bpp.max(4)
+        })
+        .unwrap_or(0);
+    file_len + decoded
+}
+
+/// Parses sizes such as `512MiB`, `2G` or `1048576`.
+pub fn parse_byte_size(value: &str) -> Result<u64> {
+    let trimmed = value.trim();
+    let split = trimmed
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| anyhow!("Invalid memory size '{value}'"))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "kib" => 1 << 10,
+        "m" | "mb" => 1_000_000,
+        "mib" => 1 << 20,
+        "g" | "gb" => 1_000_000_000,
+        "gib" => 1 << 30,
+        "t" | "tb" => 1_000_000_000_000,
+        "tib" => 1 << 40,
+        other => bail!("Unknown memory size unit '{other}' in '{value}'"),
+    };
+    let bytes = number * multiplier as f64;
+    if !bytes.is_finite() || bytes < 1.0 {
+        bail!("Memory size must be at least one byte, got '{value}'");
+    }
+    Ok(bytes as u64)
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn parses_sizes_with_units() {
+        assert_eq!(parse_byte_size("1024").unwrap(), 1024);
+        assert_eq!(parse_byte_size("512MiB").unwrap(), 512 << 20);
+        assert_eq!(parse_byte_size("2G").unwrap(), 2_000_000_000);
+        assert_eq!(parse_byte_size("1.5 kib").unwrap(), 1536);
+        assert!(parse_byte_size("lots").is_err());
+        assert!(parse_byte_size("10 parsecs").is_err());
+    }
+
+    #[test]
+    fn rejects_reservations_over_the_ceiling() {
+        let budget = MemoryBudget::new(100);
+        assert!(budget.acquire(101).is_err());
+        let reservation = budget.acquire(60).unwrap();
+        assert_eq!(budget.in_use(), 60);
+        drop(reservation);
+        assert_eq!(budget.in_use(), 0);
+    }
+
+    #[test]
+    fn waits_for_release_before_admitting() {
+        let budget = MemoryBudget::new(100);
+        let first = budget.acquire(80).unwrap();
+        let waiter = {
+            let budget = Arc::clone(&budget);
+            thread::spawn(move || budget.acquire(50).map(|r| r.bytes()))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+        drop(first);
+        assert_eq!(waiter.join().unwrap().unwrap(), 50);
+    }
+}
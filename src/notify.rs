@@ -0,0 +1,158 @@
+//! Webhook notifications sent when a run finishes.
+//!
+//! `run --notify-webhook <url>` or a recipe's `notify.webhook` POSTs a JSON
+//! [`RunEvent`] carrying the run ID, status, summary metrics and failures, so
+//! orchestration systems and chat hooks learn about runs without polling.
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::journal::{RunRecord, RunStatus};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NotifySpec {
+    pub webhook: String,
+    #[serde(default)]
+    pub on: NotifyOn,
+}
+
+/// Which run outcomes trigger the webhook.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyOn {
+    #[default]
+    Always,
+    Success,
+    Failure,
+}
+
+impl NotifySpec {
+    pub fn wants(&self, status: RunStatus) -> bool {
+        match self.on {
+            NotifyOn::Always => true,
+            NotifyOn::Success => status == RunStatus::Succeeded,
+            NotifyOn::Failure => status == RunStatus::Failed,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunEvent<'a> {
+    pub event: &'static str,
+    pub run_id: &'a str,
+    pub status: RunStatus,
+    pub recipe: &'a Path,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub metrics: RunMetrics,
+    pub failures: Vec<String>,
+    pub manifest: Option<&'a Path>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunMetrics {
+    pub inputs: usize,
+    pub outputs: usize,
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+    pub duration_ms: f64,
+    pub quality_passed: usize,
+    pub quality_skipped: usize,
+}
+
+impl<'a> RunEvent<'a> {
+    pub fn from_record(record: &'a RunRecord) -> Self {
+        Self {
+            event: "run.finished",
+            run_id: &record.id,
+            status: record.status,
+            recipe: &record.recipe,
+            started_at: record.started_at,
+            finished_at: record.finished_at,
+            metrics: RunMetrics {
+                inputs: record.inputs,
+                outputs: record.outputs,
+                input_bytes: record.input_bytes,
+                output_bytes: record.output_bytes,
+                duration_ms: record.duration_ms,
+                quality_passed: record.quality_passed,
+                quality_skipped: record.quality_skipped,
+            },
+            failures: record.error.iter().cloned().collect(),
+            manifest: record.manifest.as_deref(),
+        }
+    }
+}
+
+/// POSTs `event` to `url`; non-2xx responses are errors.
+pub fn post(url: &str, event: &RunEvent) -> Result<()> {
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(TIMEOUT))
+        .build()
+        .into();
+    agent
+        .post(url)
+        .send_json(event)
+        .with_context(|| format!("Webhook notification to {url} failed"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn posts_run_event_as_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        });
+
+        let mut record = RunRecord::begin(Path::new("recipe.yaml"));
+        record.finish(&Err(anyhow::anyhow!("quality gate failed")));
+        post(&url, &RunEvent::from_record(&record)).unwrap();
+
+        let payload = server.join().unwrap();
+        assert_eq!(payload["event"], "run.finished");
+        assert_eq!(payload["run_id"], record.id.as_str());
+        assert_eq!(payload["status"], "failed");
+        assert_eq!(payload["failures"][0], "quality gate failed");
+        assert_eq!(payload["metrics"]["inputs"], 0);
+    }
+
+    #[test]
+    fn filters_by_outcome() {
+        let spec: NotifySpec =
+            serde_yaml::from_str("webhook: http://example.invalid\non: failure").unwrap();
+        assert!(spec.wants(RunStatus::Failed));
+        assert!(!spec.wants(RunStatus::Succeeded));
+    }
+}
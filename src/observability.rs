@@ -15,6 +15,17 @@ pub struct MetricsSnapshot {
     pub total_duration_ms: f64,
     pub quality_passes: u64,
     pub quality_failures: u64,
+    pub inputs_processed: u64,
+    /// Inputs served from an earlier run by `--resume`.
+    pub inputs_skipped: u64,
+    /// Inputs whose output `--cache` restored instead of converting.
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /// Existing outputs `--overwrite` kept instead of writing again.
+    pub outputs_skipped: u64,
+    /// Outputs the `optimize` stage processed, and the bytes it saved.
+    pub outputs_optimized: u64,
+    pub optimize_bytes_saved: u64,
 }
 
 #[derive(Debug, Default, Serialize, Clone)]
@@ -22,6 +33,10 @@ pub struct StageMetrics {
     pub calls: u64,
     pub total_duration_ms: f64,
     pub max_duration_ms: f64,
+    /// Failed attempts that were retried.
+    pub retries: u64,
+    /// Calls that ran on a hardware decoder rather than in software.
+    pub hardware_calls: u64,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -58,6 +73,26 @@ impl MetricsCollector {
         }
     }
 
+    pub fn record_stage_retry(&self, stage_name: &str) {
+        if let Ok(mut guard) = self.inner.lock() {
+            guard
+                .stages
+                .entry(stage_name.to_string())
+                .or_default()
+                .retries += 1;
+        }
+    }
+
+    pub fn record_stage_hardware(&self, stage_name: &str) {
+        if let Ok(mut guard) = self.inner.lock() {
+            guard
+                .stages
+                .entry(stage_name.to_string())
+                .or_default()
+                .hardware_calls += 1;
+        }
+    }
+
     pub fn record_quality_pass(&self) {
         if let Ok(mut guard) = self.inner.lock() {
             guard.quality_passes += 1;
@@ -70,6 +105,43 @@ impl MetricsCollector {
         }
     }
 
+    pub fn record_input_processed(&self) {
+        if let Ok(mut guard) = self.inner.lock() {
+            guard.inputs_processed += 1;
+        }
+    }
+
+    pub fn record_input_skipped(&self) {
+        if let Ok(mut guard) = self.inner.lock() {
+            guard.inputs_skipped += 1;
+        }
+    }
+
+    pub fn record_cache_hit(&self) {
+        if let Ok(mut guard) = self.inner.lock() {
+            guard.cache_hits += 1;
+        }
+    }
+
+    pub fn record_cache_miss(&self) {
+        if let Ok(mut guard) = self.inner.lock() {
+            guard.cache_misses += 1;
+        }
+    }
+
+    pub fn record_output_skipped(&self) {
+        if let Ok(mut guard) = self.inner.lock() {
+            guard.outputs_skipped += 1;
+        }
+    }
+
+    pub fn record_optimized(&self, bytes_saved: u64) {
+        if let Ok(mut guard) = self.inner.lock() {
+            guard.outputs_optimized += 1;
+            guard.optimize_bytes_saved += bytes_saved;
+        }
+    }
+
     pub fn snapshot(&self) -> MetricsSnapshot {
         self.inner.lock().map(|g| g.clone()).unwrap_or_default()
     }
@@ -124,6 +196,13 @@ pub fn log_snapshot(snapshot: &MetricsSnapshot) {
         stage_count = snapshot.stages.len(),
         quality_passes = snapshot.quality_passes,
         quality_failures = snapshot.quality_failures,
+        inputs_processed = snapshot.inputs_processed,
+        inputs_skipped = snapshot.inputs_skipped,
+        cache_hits = snapshot.cache_hits,
+        cache_misses = snapshot.cache_misses,
+        outputs_skipped = snapshot.outputs_skipped,
+        outputs_optimized = snapshot.outputs_optimized,
+        optimize_bytes_saved = snapshot.optimize_bytes_saved,
         "Pipeline metrics summary"
     );
     for (stage, metrics) in &snapshot.stages {
@@ -132,6 +211,8 @@ pub fn log_snapshot(snapshot: &MetricsSnapshot) {
             calls = metrics.calls,
             total_ms = metrics.total_duration_ms,
             max_ms = metrics.max_duration_ms,
+            retries = metrics.retries,
+            hardware_calls = metrics.hardware_calls,
             "Stage metrics"
         );
     }
@@ -154,6 +235,51 @@ impl MetricsSnapshot {
             "bunker_quality_failures_total {}\n",
             self.quality_failures
         ));
+        output.push_str("# HELP bunker_inputs_processed_total Inputs run through the pipeline\n");
+        output.push_str("# TYPE bunker_inputs_processed_total counter\n");
+        output.push_str(&format!(
+            "bunker_inputs_processed_total {}\n",
+            self.inputs_processed
+        ));
+        output.push_str("# HELP bunker_inputs_skipped_total Inputs skipped as already converted\n");
+        output.push_str("# TYPE bunker_inputs_skipped_total counter\n");
+        output.push_str(&format!(
+            "bunker_inputs_skipped_total {}\n",
+            self.inputs_skipped
+        ));
+        output.push_str("# HELP bunker_cache_hits_total Outputs restored from the output cache\n");
+        output.push_str("# TYPE bunker_cache_hits_total counter\n");
+        output.push_str(&format!("bunker_cache_hits_total {}\n", self.cache_hits));
+        output.push_str("# HELP bunker_cache_misses_total Output cache lookups that converted\n");
+        output.push_str("# TYPE bunker_cache_misses_total counter\n");
+        output.push_str(&format!(
+            "bunker_cache_misses_total {}\n",
+            self.cache_misses
+        ));
+        output.push_str(
+            "# HELP bunker_outputs_skipped_total Existing outputs kept by the overwrite policy\n",
+        );
+        output.push_str("# TYPE bunker_outputs_skipped_total counter\n");
+        output.push_str(&format!(
+            "bunker_outputs_skipped_total {}\n",
+            self.outputs_skipped
+        ));
+        output.push_str(
+            "# HELP bunker_outputs_optimized_total Outputs losslessly recompressed by optimize\n",
+        );
+        output.push_str("# TYPE bunker_outputs_optimized_total counter\n");
+        output.push_str(&format!(
+            "bunker_outputs_optimized_total {}\n",
+            self.outputs_optimized
+        ));
+        output.push_str(
+            "# HELP bunker_optimize_bytes_saved_total Bytes removed from outputs by optimize\n",
+        );
+        output.push_str("# TYPE bunker_optimize_bytes_saved_total counter\n");
+        output.push_str(&format!(
+            "bunker_optimize_bytes_saved_total {}\n",
+            self.optimize_bytes_saved
+        ));
         output.push_str("# HELP bunker_stage_calls_total Stage invocation count\n");
         output.push_str("# TYPE bunker_stage_calls_total counter\n");
         output.push_str(
@@ -164,6 +290,14 @@ impl MetricsSnapshot {
             "# HELP bunker_stage_duration_seconds_max Maximum stage duration in seconds\n",
         );
         output.push_str("# TYPE bunker_stage_duration_seconds_max gauge\n");
+        output.push_str(
+            "# HELP bunker_stage_retries_total Failed stage attempts that were retried\n",
+        );
+        output.push_str("# TYPE bunker_stage_retries_total counter\n");
+        output.push_str(
+            "# HELP bunker_stage_hardware_calls_total Stage calls that ran on hardware\n",
+        );
+        output.push_str("# TYPE bunker_stage_hardware_calls_total counter\n");
         for (stage, metrics) in &self.stages {
             output.push_str(&format!(
                 "bunker_stage_calls_total{{stage=\"{}\"}} {}\n",
@@ -179,6 +313,14 @@ impl MetricsSnapshot {
                 stage,
                 metrics.max_duration_ms / 1_000.0
             ));
+            output.push_str(&format!(
+                "bunker_stage_retries_total{{stage=\"{}\"}} {}\n",
+                stage, metrics.retries
+            ));
+            output.push_str(&format!(
+                "bunker_stage_hardware_calls_total{{stage=\"{}\"}} {}\n",
+                stage, metrics.hardware_calls
+            ));
         }
         output.push_str("# HELP bunker_pipeline_duration_seconds Total pipeline duration\n");
         output.push_str("# TYPE bunker_pipeline_duration_seconds gauge\n");
@@ -0,0 +1,110 @@
+//! Whether stages replace output files left by an earlier run.
+//!
+//! Every stage that writes an output asks the run's [`OverwritePolicy`]
+//! first. An output that is kept is not written again: the stage logs a
+//! notice, records why in `output.skipped` and reports the existing file as
+//! its output, and the run counts it in `outputs_skipped`.
+
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::archive::ArchiveMember;
+
+/// Metadata key recording why an existing output was kept.
+pub const SKIPPED_KEY: &str = "output.skipped";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverwritePolicy {
+    /// Replace existing outputs.
+    #[default]
+    Always,
+    /// Keep every existing output.
+    Never,
+    /// Replace an existing output only when its input was modified after it.
+    IfNewer,
+}
+
+impl OverwritePolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OverwritePolicy::Always => "always",
+            OverwritePolicy::Never => "never",
+            OverwritePolicy::IfNewer => "if-newer",
+        }
+    }
+
+    /// Why the existing file at `output` should be kept instead of being
+    /// replaced by a new conversion of `input`, or `None` to write it.
+    /// Archive members compare against the archive's modification time.
+    pub fn keep_reason(self, input: &Path, output: &Path) -> Option<&'static str> {
+        let existing = fs::metadata(output).ok()?;
+        match self {
+            OverwritePolicy::Always => None,
+            OverwritePolicy::Never => Some("exists"),
+            OverwritePolicy::IfNewer => {
+                let source = ArchiveMember::from_path(input)
+                    .map_or_else(|| input.to_path_buf(), |member| member.archive);
+                let input_modified = modified(&source)?;
+                (input_modified <= existing.modified().ok()?).then_some("up-to-date")
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn keeps_outputs_by_policy_and_modification_time() {
+        let temp = tempfile::tempdir().unwrap();
+        let input = temp.path().join("photo.png");
+        let output = temp.path().join("photo.webp");
+        fs::write(&input, b"input").unwrap();
+        for policy in [
+            OverwritePolicy::Always,
+            OverwritePolicy::Never,
+            OverwritePolicy::IfNewer,
+        ] {
+            assert_eq!(policy.keep_reason(&input, &output), None);
+        }
+
+        fs::write(&output, b"output").unwrap();
+        let set_modified = |path: &Path, time: SystemTime| {
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(time)
+                .unwrap();
+        };
+        let now = SystemTime::now();
+        set_modified(&input, now - Duration::from_secs(60));
+        set_modified(&output, now);
+        assert_eq!(OverwritePolicy::Always.keep_reason(&input, &output), None);
+        assert_eq!(
+            OverwritePolicy::Never.keep_reason(&input, &output),
+            Some("exists")
+        );
+        assert_eq!(
+            OverwritePolicy::IfNewer.keep_reason(&input, &output),
+            Some("up-to-date")
+        );
+
+        set_modified(&input, now + Duration::from_secs(60));
+        assert_eq!(OverwritePolicy::IfNewer.keep_reason(&input, &output), None);
+    }
+}
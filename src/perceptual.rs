@@ -0,0 +1,190 @@
+//! Perceptual image hashes: 64-bit fingerprints whose Hamming distance stays
+//! small when an image is resized, recompressed or lightly edited.
+
+use std::f64::consts::PI;
+use std::fmt;
+
+use anyhow::{Result, bail};
+use clap::ValueEnum;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GrayImage};
+
+/// Side of the grayscale thumbnail the pHash DCT runs on.
+const DCT_SIZE: usize = 32;
+/// Side of the block of hashed values; 8x8 gives 64 bits.
+const HASH_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HashAlgorithm {
+    /// Low DCT frequencies of a 32x32 thumbnail against their median.
+    Phash,
+    /// Brightness gradient between neighbours of a 9x8 thumbnail.
+    Dhash,
+    /// Pixels of an 8x8 thumbnail against their mean.
+    Ahash,
+}
+
+impl HashAlgorithm {
+    pub const ALL: [Self; 3] = [Self::Phash, Self::Dhash, Self::Ahash];
+
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "phash" => Ok(Self::Phash),
+            "dhash" => Ok(Self::Dhash),
+            "ahash" => Ok(Self::Ahash),
+            other => bail!("Unknown perceptual hash '{other}' (expected phash, dhash or ahash)"),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Phash => "phash",
+            Self::Dhash => "dhash",
+            Self::Ahash => "ahash",
+        }
+    }
+
+    /// Hashes `image`; bits are laid out row by row, most significant first.
+    pub fn hash(self, image: &DynamicImage) -> u64 {
+        match self {
+            Self::Phash => phash(image),
+            Self::Dhash => dhash(image),
+            Self::Ahash => ahash(image),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Number of differing bits between two hashes.
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// The hash as 16 lowercase hex digits, as recorded in metadata.
+pub fn to_hex(hash: u64) -> String {
+    format!("{hash:016x}")
+}
+
+fn thumbnail(image: &DynamicImage, width: u32, height: u32) -> GrayImage {
+    imageops::resize(&image.to_luma8(), width, height, FilterType::Triangle)
+}
+
+fn pack(bits: impl IntoIterator<Item = bool>) -> u64 {
+    bits.into_iter()
+        .fold(0, |hash, bit| (hash << 1) | u64::from(bit))
+}
+
+fn ahash(image: &DynamicImage) -> u64 {
+    let size = HASH_SIZE as u32;
+    let thumbnail = thumbnail(image, size, size);
+    let sum: u32 = thumbnail.pixels().map(|pixel| u32::from(pixel.0[0])).sum();
+    let count = thumbnail.pixels().len() as u32;
+    // pixel > sum / count, without rounding the mean.
+    pack(
+        thumbnail
+            .pixels()
+            .map(|pixel| u32::from(pixel.0[0]) * count > sum),
+    )
+}
+
+fn dhash(image: &DynamicImage) -> u64 {
+    let size = HASH_SIZE as u32;
+    let thumbnail = thumbnail(image, size + 1, size);
+    let brightness = |x: u32, y: u32| thumbnail.get_pixel(x, y).0[0];
+    pack((0..size).flat_map(|y| (0..size).map(move |x| brightness(x, y) > brightness(x + 1, y))))
+}
+
+fn phash(image: &DynamicImage) -> u64 {
+    let size = DCT_SIZE as u32;
+    let thumbnail = thumbnail(image, size, size);
+    let pixels: Vec<f64> = thumbnail
+        .pixels()
+        .map(|pixel| f64::from(pixel.0[0]))
+        .collect();
+    let basis = dct_basis();
+
+    // Separable DCT-II, keeping only the lowest HASH_SIZE frequencies.
+    let mut rows = [[0.0; HASH_SIZE]; DCT_SIZE];
+    for (y, row) in rows.iter_mut().enumerate() {
+        let line = &pixels[y * DCT_SIZE..(y + 1) * DCT_SIZE];
+        for (u, value) in row.iter_mut().enumerate() {
+            *value = line.iter().zip(&basis[u]).map(|(p, c)| p * c).sum();
+        }
+    }
+    let mut coefficients = [0.0; HASH_SIZE * HASH_SIZE];
+    for v in 0..HASH_SIZE {
+        for u in 0..HASH_SIZE {
+            coefficients[v * HASH_SIZE + u] =
+                rows.iter().zip(&basis[v]).map(|(row, c)| row[u] * c).sum();
+        }
+    }
+
+    // The DC term only tracks overall brightness; leave it out of the median.
+    let mut ac = coefficients[1..].to_vec();
+    ac.sort_by(f64::total_cmp);
+    let median = (ac[ac.len() / 2 - 1] + ac[ac.len() / 2]) / 2.0;
+    pack(coefficients.iter().map(|&value| value > median))
+}
+
+/// Orthonormal DCT-II basis for the first HASH_SIZE frequencies.
+fn dct_basis() -> [[f64; DCT_SIZE]; HASH_SIZE] {
+    let n = DCT_SIZE as f64;
+    let mut basis = [[0.0; DCT_SIZE]; HASH_SIZE];
+    for (u, row) in basis.iter_mut().enumerate() {
+        let scale = if u == 0 {
+            (1.0 / n).sqrt()
+        } else {
+            (2.0 / n).sqrt()
+        };
+        for (x, value) in row.iter_mut().enumerate() {
+            *value = scale * ((2 * x + 1) as f64 * u as f64 * PI / (2.0 * n)).cos();
+        }
+    }
+    basis
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let shade = ((x * 255 / width + y * 64 / height) % 256) as u8;
+            Rgb([shade, shade / 2, 255 - shade])
+        }))
+    }
+
+    #[test]
+    fn resized_copies_hash_close_and_different_images_far() {
+        let original = gradient(256, 192);
+        let resized = original.resize_exact(128, 96, FilterType::Lanczos3);
+        let mirrored = original.fliph();
+        for algorithm in HashAlgorithm::ALL {
+            let hash = algorithm.hash(&original);
+            assert_eq!(hash, algorithm.hash(&original));
+            assert!(
+                distance(hash, algorithm.hash(&resized)) <= 4,
+                "{algorithm} changed under resize"
+            );
+            assert!(
+                distance(hash, algorithm.hash(&mirrored)) >= 16,
+                "{algorithm} missed a mirrored image"
+            );
+        }
+    }
+
+    #[test]
+    fn hashes_format_as_sixteen_hex_digits() {
+        assert_eq!(to_hex(0xab), "00000000000000ab");
+        assert_eq!(distance(0b1011, 0b0110), 3);
+        assert_eq!(HashAlgorithm::parse("DHash").unwrap(), HashAlgorithm::Dhash);
+        assert!(HashAlgorithm::parse("md5").is_err());
+    }
+}
@@ -1,20 +1,40 @@
 use std::collections::HashMap;
-use std::fs;
+use std::fs::{self, File};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::time::Instant;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result, anyhow, bail};
-use image::DynamicImage;
+use chrono::{DateTime, Local};
+use image::{DynamicImage, RgbaImage};
 use serde::Deserialize;
 use serde_json::{Map, Value, json};
+use sha2::{Digest, Sha256};
 use tracing::{instrument, warn};
 
+use crate::archive::{self, ArchiveMember};
+use crate::buffers;
+use crate::cache::OutputCache;
+use crate::cancel::{self, CancellationToken, Cancelled};
+use crate::collision::{CollisionStrategy, OutputClaims};
+use crate::condition::Condition;
+use crate::embedded::EmbeddedMetadata;
+use crate::graph::StageGraph;
+use crate::memory::{MemoryBudget, MemoryReservation, estimate_artifact_bytes};
 use crate::observability::MetricsCollector;
-use crate::quality::{QualityMetrics, compute_metrics};
+use crate::overwrite::{self, OverwritePolicy};
+use crate::quality::{QualityMetrics, compute_metrics, compute_video_metrics};
 use crate::recipe::QualityGateSpec;
+use crate::resume::ResumeLedger;
+use crate::retry::RetryPolicy;
 use crate::scheduler::{DevicePolicy, StageDevice, TaskScheduler};
-use crate::video::MediaStreams;
+use crate::source::{ArtifactData, MAP_THRESHOLD_BYTES};
+use crate::stages;
+use crate::structure;
+use crate::video::{MediaStreams, VideoStream};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct OutputSpec {
@@ -27,27 +47,106 @@ fn default_output_structure() -> String {
     "{stem}.{ext}".to_string()
 }
 
-#[derive(Debug)]
+impl OutputSpec {
+    /// Renders `structure` for one output (see [`crate::structure`]).
+    /// Archive members land in a directory mirroring their place in the
+    /// archive.
+    pub fn resolve(&self, stem: &str, extension: &str, metadata: &Map<String, Value>) -> PathBuf {
+        let file_name = structure::render(&self.structure, stem, extension, metadata);
+
+        let mut path = self.directory.clone();
+        if let Some(dir) = metadata
+            .get(archive::OUTPUT_DIR_KEY)
+            .and_then(Value::as_str)
+        {
+            path.push(dir);
+        }
+        path.push(file_name);
+        path
+    }
+}
+
+/// One frame of an animated input, composited onto the full canvas.
+#[derive(Debug, Clone)]
+pub struct AnimationFrame {
+    pub image: RgbaImage,
+    pub delay_ms: u32,
+}
+
+/// A single input moving through the pipeline.
+///
+/// Bytes, images and media streams are reference counted so cloning an
+/// artifact (or keeping the decoded original next to the working image) shares
+/// the underlying buffers; stages that modify them get a private copy on write.
+#[derive(Debug, Clone)]
 pub struct Artifact {
     pub input_path: PathBuf,
     pub stem: String,
-    pub data: Vec<u8>,
+    /// Raw input bytes; large files are memory-mapped (see [`crate::source`]).
+    pub data: Arc<ArtifactData>,
     pub format: Option<String>,
-    pub original_image: Option<DynamicImage>,
-    pub image: Option<DynamicImage>,
-    pub media: MediaStreams,
+    pub original_image: Option<Arc<DynamicImage>>,
+    /// The streams as decoded, kept for video quality gates.
+    pub original_media: Option<Arc<MediaStreams>>,
+    pub image: Option<Arc<DynamicImage>>,
+    /// Set once any stage replaces or edits the image produced by decode.
+    pub image_edited: bool,
+    /// Every frame of an animated input; empty for still images, whose only
+    /// frame lives in `image`.
+    pub frames: Arc<Vec<AnimationFrame>>,
+    pub media: Arc<MediaStreams>,
+    /// EXIF and XMP packets read from the input by decode.
+    pub embedded: Arc<EmbeddedMetadata>,
     pub metadata: Map<String, Value>,
+    /// Further artifacts a splitting stage turned this one into (one per
+    /// PDF page, ...). The executor takes them after the stage and runs each
+    /// through the remaining stages as a result of its own.
+    pub split: Vec<Artifact>,
 }
 
 impl Artifact {
     pub fn load(input: &Path) -> Result<Self> {
-        let data = fs::read(input)
+        if let Some(member) = ArchiveMember::from_path(input) {
+            return Self::load_member(input, &member);
+        }
+        let file = File::open(input)
             .with_context(|| format!("Failed to read input file: {}", input.display()))?;
+        let data = ArtifactData::read(file, MAP_THRESHOLD_BYTES)
+            .with_context(|| format!("Failed to read input file: {}", input.display()))?;
+        if data.is_mapped() {
+            tracing::debug!(input = %input.display(), bytes = data.len(), "Mapped input file");
+        }
         let stem = input
             .file_stem()
             .map(|s| s.to_string_lossy().to_string())
             .unwrap_or_else(|| "artifact".to_string());
+        Ok(Self::from_bytes(input, stem, data))
+    }
 
+    fn load_member(input: &Path, member: &ArchiveMember) -> Result<Self> {
+        let data = member
+            .read()
+            .with_context(|| format!("Failed to read input file: {}", input.display()))?;
+        let size = data.len();
+        let mut artifact = Self::from_bytes(input, member.file_stem(), data);
+        let metadata = &mut artifact.metadata;
+        metadata.insert(
+            "archive.path".to_string(),
+            Value::String(member.archive.to_string_lossy().to_string()),
+        );
+        metadata.insert(
+            "archive.member".to_string(),
+            Value::String(member.name.clone()),
+        );
+        metadata.insert(
+            archive::OUTPUT_DIR_KEY.to_string(),
+            Value::String(member.output_dir()),
+        );
+        metadata.insert("input.size_bytes".to_string(), json!(size));
+        Ok(artifact)
+    }
+
+    fn from_bytes(input: &Path, stem: String, data: impl Into<ArtifactData>) -> Self {
         let mut metadata = Map::new();
         metadata.insert(
             "input_path".to_string(),
@@ -55,16 +154,21 @@ impl Artifact {
         );
         metadata.insert("stem".to_string(), Value::String(stem.clone()));
 
-        Ok(Self {
+        Self {
             input_path: input.to_path_buf(),
             stem,
-            data,
+            data: Arc::new(data.into()),
             format: None,
             original_image: None,
+            original_media: None,
             image: None,
-            media: MediaStreams::default(),
+            image_edited: false,
+            frames: Arc::default(),
+            media: Arc::default(),
+            embedded: Arc::default(),
             metadata,
-        })
+            split: Vec::new(),
+        }
     }
 
     pub fn set_format(&mut self, fmt: impl Into<String>) {
@@ -72,19 +176,58 @@ impl Artifact {
     }
 
     pub fn replace_data(&mut self, data: Vec<u8>) {
-        self.data = data;
+        let previous = std::mem::replace(&mut self.data, Arc::new(data.into()));
+        // Only recycle the old bytes when no clone still shares them.
+        if let Ok(ArtifactData::Owned(buffer)) = Arc::try_unwrap(previous) {
+            buffers::recycle(buffer);
+        }
     }
 
-    pub fn set_image(&mut self, image: DynamicImage) {
-        self.image = Some(image);
+    pub fn set_image(&mut self, image: impl Into<Arc<DynamicImage>>) {
+        self.image = Some(image.into());
+        self.image_edited = true;
     }
 
-    pub fn set_original_image(&mut self, image: DynamicImage) {
-        self.original_image = Some(image);
+    /// Stores a freshly decoded image that still matches `data` pixel for pixel.
+    pub fn set_decoded_image(&mut self, image: impl Into<Arc<DynamicImage>>) {
+        self.image = Some(image.into());
+        self.image_edited = false;
+    }
+
+    pub fn set_original_image(&mut self, image: impl Into<Arc<DynamicImage>>) {
+        self.original_image = Some(image.into());
+    }
+
+    pub fn image_mut(&mut self) -> Option<&mut DynamicImage> {
+        self.image_edited = true;
+        self.image.as_mut().map(Arc::make_mut)
+    }
+
+    pub fn set_frames(&mut self, frames: Vec<AnimationFrame>) {
+        self.frames = Arc::new(frames);
+    }
+
+    pub fn frames_mut(&mut self) -> &mut Vec<AnimationFrame> {
+        self.image_edited = true;
+        Arc::make_mut(&mut self.frames)
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.frames.len() > 1
+    }
+
+    pub fn set_media(&mut self, media: MediaStreams) {
+        self.media = Arc::new(media);
+    }
+
+    /// Keeps the current streams as the source video quality gates compare
+    /// against; they stay shared until a stage edits them.
+    pub fn keep_original_media(&mut self) {
+        self.original_media = Some(Arc::clone(&self.media));
     }
 
     pub fn media_mut(&mut self) -> &mut MediaStreams {
-        &mut self.media
+        Arc::make_mut(&mut self.media)
     }
 
     pub fn media(&self) -> &MediaStreams {
@@ -92,9 +235,23 @@ impl Artifact {
     }
 }
 
+/// Metadata key under which a stage records the hardware backend it ran
+/// on. Stages that fell back to software remove it; the executor counts the
+/// rest in the stage's `hardware_calls` metric.
+pub fn hardware_key(stage_name: &str) -> String {
+    format!("hardware.{stage_name}")
+}
+
 #[derive(Debug, Clone)]
 pub struct PipelineContext {
     pub output: OutputSpec,
+    pub quality_gates_enabled: bool,
+    /// Set when the run is cancelled; long-running stages should poll it.
+    pub cancellation: CancellationToken,
+    /// Output paths claimed so far; stages claim each output before writing.
+    pub outputs: OutputClaims,
+    /// Whether stages replace output files that already exist.
+    pub overwrite: OverwritePolicy,
 }
 
 pub type StageParameters = Map<String, Value>;
@@ -108,6 +265,54 @@ pub trait Stage: Send + Sync {
         ctx: &PipelineContext,
         device: StageDevice,
     ) -> Result<()>;
+
+    /// Runs once after every input has been processed, for stages that
+    /// assemble a single output from the whole run.
+    fn finalize(&self, _ctx: &PipelineContext) -> Result<()> {
+        Ok(())
+    }
+
+    /// Whether an input's output depends only on its own bytes and the stage
+    /// parameters, so the output cache may replay it.
+    fn cacheable(&self) -> bool {
+        true
+    }
+
+    /// Whether the stage can turn one artifact into several through
+    /// [`Artifact::split`], so one input yields several results.
+    fn splits(&self) -> bool {
+        false
+    }
+
+    /// Upper bound on the dimensions of the image the stage leaves for a
+    /// `width` x `height` input, for memory estimates.
+    fn output_dimensions(&self, width: u32, height: u32) -> (u32, u32) {
+        (width, height)
+    }
+
+    /// Applies what later stages and output paths depend on (stem, metadata,
+    /// image dimensions, output path) without touching pixels, for dry-run
+    /// plans. The default records [`Stage::output_dimensions`] as the new
+    /// `image.width`/`image.height`.
+    fn plan(&self, artifact: &mut Artifact, _ctx: &PipelineContext) -> Result<()> {
+        let dimension = |key: &str| {
+            artifact
+                .metadata
+                .get(key)
+                .and_then(Value::as_u64)
+                .and_then(|value| u32::try_from(value).ok())
+        };
+        if let (Some(width), Some(height)) = (dimension("image.width"), dimension("image.height")) {
+            let (width, height) = self.output_dimensions(width, height);
+            artifact
+                .metadata
+                .insert("image.width".to_string(), json!(width));
+            artifact
+                .metadata
+                .insert("image.height".to_string(), json!(height));
+        }
+        Ok(())
+    }
 }
 
 type StageConstructor = Arc<dyn Fn(StageParameters) -> Result<Box<dyn Stage>> + Send + Sync>;
@@ -157,9 +362,17 @@ impl StageRegistry {
 pub struct PipelineExecutor {
     stages: Vec<Box<dyn Stage>>,
     ctx: PipelineContext,
+    graph: StageGraph,
+    /// Per-stage context carrying the output layout of the stage's branch.
+    contexts: Vec<PipelineContext>,
+    conditions: Vec<Option<Condition>>,
+    retries: Vec<Option<RetryPolicy>>,
     metrics: MetricsCollector,
     quality_gates: Vec<QualityGateSpec>,
     scheduler: TaskScheduler,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    resume: Option<ResumeLedger>,
+    cache: Option<OutputCache>,
 }
 
 #[derive(Debug, Clone)]
@@ -179,15 +392,150 @@ impl PipelineExecutor {
         quality_gates: Vec<QualityGateSpec>,
         scheduler: TaskScheduler,
     ) -> Self {
+        let quality_gates_enabled = !quality_gates.is_empty();
+        let ctx = PipelineContext {
+            output,
+            quality_gates_enabled,
+            cancellation: CancellationToken::new(),
+            outputs: OutputClaims::default(),
+            overwrite: OverwritePolicy::default(),
+        };
+        let graph = StageGraph::linear(stages.len(), &ctx.output);
+        let contexts = vec![ctx.clone(); stages.len()];
+        let conditions = vec![None; stages.len()];
+        let retries = vec![None; stages.len()];
         Self {
             stages,
-            ctx: PipelineContext { output },
+            ctx,
+            graph,
+            contexts,
+            conditions,
+            retries,
             metrics: MetricsCollector::new(),
             quality_gates,
             scheduler,
+            memory_budget: None,
+            resume: None,
+            cache: None,
         }
     }
 
+    /// Runs the stages as `graph` connects them instead of one after another.
+    pub fn with_graph(mut self, graph: StageGraph) -> Self {
+        debug_assert_eq!(graph.len(), self.stages.len());
+        self.contexts = (0..graph.len())
+            .map(|index| PipelineContext {
+                output: graph.output(index).clone(),
+                quality_gates_enabled: self.ctx.quality_gates_enabled,
+                cancellation: self.ctx.cancellation.clone(),
+                outputs: self.ctx.outputs.clone(),
+                overwrite: self.ctx.overwrite,
+            })
+            .collect();
+        self.graph = graph;
+        self
+    }
+
+    /// Only runs each stage on inputs matching its condition, if it has one.
+    pub fn with_conditions(mut self, conditions: Vec<Option<Condition>>) -> Self {
+        debug_assert_eq!(conditions.len(), self.stages.len());
+        self.conditions = conditions;
+        self
+    }
+
+    /// Retries each stage that fails according to its policy, if it has one.
+    pub fn with_retries(mut self, retries: Vec<Option<RetryPolicy>>) -> Self {
+        debug_assert_eq!(retries.len(), self.stages.len());
+        self.retries = retries;
+        self
+    }
+
+    /// Stops picking up new inputs once `token` is cancelled and hands it to
+    /// every stage. Inputs already in flight finish.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        for ctx in std::iter::once(&mut self.ctx).chain(&mut self.contexts) {
+            ctx.cancellation = token.clone();
+        }
+        self
+    }
+
+    /// Resolves outputs that would overwrite another input's according to
+    /// `strategy`.
+    pub fn with_collisions(mut self, strategy: CollisionStrategy) -> Self {
+        let outputs = OutputClaims::new(strategy);
+        for ctx in std::iter::once(&mut self.ctx).chain(&mut self.contexts) {
+            ctx.outputs = outputs.clone();
+        }
+        self
+    }
+
+    /// Keeps or replaces output files that already exist according to
+    /// `policy`.
+    pub fn with_overwrite(mut self, policy: OverwritePolicy) -> Self {
+        for ctx in std::iter::once(&mut self.ctx).chain(&mut self.contexts) {
+            ctx.overwrite = policy;
+        }
+        self
+    }
+
+    fn cancelled(&self) -> bool {
+        self.ctx.cancellation.is_cancelled()
+    }
+
+    /// Whether some stage fans out or splits artifacts, so one input can
+    /// yield several results.
+    pub fn is_branched(&self) -> bool {
+        !self.graph.is_linear() || self.stages.iter().any(|stage| stage.splits())
+    }
+
+    pub fn with_encode_workers(mut self, workers: Option<usize>) -> Self {
+        self.scheduler = self.scheduler.with_encode_workers(workers);
+        self
+    }
+
+    pub fn with_jobs(mut self, jobs: Option<usize>) -> Self {
+        self.scheduler = self.scheduler.with_jobs(jobs);
+        self
+    }
+
+    /// Skips inputs `ledger` records as already converted and records every
+    /// input this executor completes.
+    pub fn with_resume(mut self, ledger: Option<ResumeLedger>) -> Self {
+        if ledger.is_some() && self.is_branched() {
+            warn!("Resume disabled: the ledger holds one result per input");
+            self.resume = None;
+        } else {
+            self.resume = ledger;
+        }
+        self
+    }
+
+    /// Serves inputs whose conversion `cache` already holds from it and stores
+    /// every other output. Ignored when a stage opts out of caching.
+    pub fn with_cache(mut self, cache: Option<OutputCache>) -> Self {
+        if cache.is_some() && self.is_branched() {
+            warn!("Output cache disabled: the cache holds one output per input");
+            self.cache = None;
+        } else if let Some(stage) = self.stages.iter().find(|stage| !stage.cacheable())
+            && cache.is_some()
+        {
+            warn!(
+                stage = stage.name(),
+                "Output cache disabled: stage output cannot be cached"
+            );
+            self.cache = None;
+        } else {
+            self.cache = cache;
+        }
+        self
+    }
+
+    /// Caps the estimated memory held by in-flight artifacts at `limit` bytes.
+    pub fn with_memory_limit(mut self, limit: Option<u64>) -> Self {
+        self.memory_budget = limit.map(MemoryBudget::new);
+        self
+    }
+
     #[instrument(skip(self, artifact, progress))]
     pub fn process(
         &self,
@@ -195,34 +543,59 @@ impl PipelineExecutor {
         input: &Path,
         input_index: usize,
         total_inputs: usize,
+        progress: Option<&mut dyn FnMut(StageProgress<'_>)>,
+    ) -> Result<()> {
+        self.run_stages(
+            artifact,
+            0..self.stages.len(),
+            input,
+            input_index,
+            total_inputs,
+            progress,
+        )
+    }
+
+    fn run_stages(
+        &self,
+        artifact: &mut Artifact,
+        range: Range<usize>,
+        input: &Path,
+        input_index: usize,
+        total_inputs: usize,
         mut progress: Option<&mut dyn FnMut(StageProgress<'_>)>,
     ) -> Result<()> {
         let total_stages = self.stages.len();
-        for (index, stage) in self.stages.iter().enumerate() {
+        for index in range {
+            let stage = &self.stages[index];
             let span = tracing::span!(tracing::Level::DEBUG, "stage", stage = stage.name());
             let _span_guard = span.enter();
+            if let Some(condition) = &self.conditions[index]
+                && !condition.matches(artifact)
+            {
+                tracing::debug!(%condition, "Skipping stage: when clause not met");
+                record_skipped_stage(artifact, stage.name());
+                if let Some(callback) = progress.as_deref_mut() {
+                    callback(StageProgress {
+                        input,
+                        input_index,
+                        total_inputs,
+                        stage_index: index + 1,
+                        total_stages,
+                        stage_name: stage.name(),
+                    });
+                }
+                continue;
+            }
             let _timer = self.metrics.start_stage(stage.name());
-            let requested = self.scheduler.select_device(stage.name());
-            let device = if stage.supports_device(requested) {
-                requested
-            } else if requested == StageDevice::Gpu && stage.supports_device(StageDevice::Cpu) {
-                tracing::debug!("Falling back to CPU device");
-                StageDevice::Cpu
-            } else if requested == StageDevice::Cpu
-                && self.scheduler.gpu_available()
-                && stage.supports_device(StageDevice::Gpu)
+            let device = self.stage_device(stage.as_ref())?;
+            self.run_stage(index, artifact, device)?;
+            if artifact
+                .metadata
+                .get(&hardware_key(stage.name()))
+                .is_some_and(Value::is_string)
             {
-                tracing::debug!("Promoting stage to GPU device");
-                StageDevice::Gpu
-            } else {
-                bail!(
-                    "Stage '{}' does not support requested device {:?}",
-                    stage.name(),
-                    requested
-                );
-            };
-            tracing::debug!(?requested, ?device, "Dispatching stage");
-            stage.run(artifact, &self.ctx, device)?;
+                self.metrics.record_stage_hardware(stage.name());
+            }
             if let Some(callback) = progress.as_deref_mut() {
                 callback(StageProgress {
                     input,
@@ -237,6 +610,134 @@ impl PipelineExecutor {
         Ok(())
     }
 
+    /// The device `stage` runs on: the scheduler's pick, or the other device
+    /// when the stage only supports that one.
+    fn stage_device(&self, stage: &dyn Stage) -> Result<StageDevice> {
+        let requested = self.scheduler.select_device(stage.name());
+        let device = if stage.supports_device(requested) {
+            requested
+        } else if requested == StageDevice::Gpu && stage.supports_device(StageDevice::Cpu) {
+            tracing::debug!("Falling back to CPU device");
+            StageDevice::Cpu
+        } else if requested == StageDevice::Cpu
+            && self.scheduler.gpu_available()
+            && stage.supports_device(StageDevice::Gpu)
+        {
+            tracing::debug!("Promoting stage to GPU device");
+            StageDevice::Gpu
+        } else {
+            bail!(
+                "Stage '{}' does not support requested device {:?}",
+                stage.name(),
+                requested
+            );
+        };
+        tracing::debug!(?requested, ?device, "Dispatching stage");
+        Ok(device)
+    }
+
+    /// The device each stage would run on.
+    pub fn stage_devices(&self) -> Result<Vec<StageDevice>> {
+        self.stages
+            .iter()
+            .map(|stage| self.stage_device(stage.as_ref()))
+            .collect()
+    }
+
+    /// Walks `input` through the stages with [`Stage::plan`] instead of
+    /// [`Stage::run`], honouring `when` clauses and branches, and returns
+    /// the result each leaf stage would produce.
+    pub fn plan_input(&self, input: &Path, input_index: usize) -> Result<Vec<PipelineResult>> {
+        let mut root = Artifact::load(input)?;
+        self.annotate_input(&mut root, input_index);
+        let mut planned: Vec<Vec<Artifact>> = Vec::with_capacity(self.stages.len());
+        for (index, stage) in self.stages.iter().enumerate() {
+            let artifacts = match self.graph.parent(index) {
+                Some(parent) => planned[parent].clone(),
+                None => vec![root.clone()],
+            };
+            let mut outputs = Vec::with_capacity(artifacts.len());
+            for mut artifact in artifacts {
+                match &self.conditions[index] {
+                    Some(condition) if !condition.matches(&artifact) => {
+                        record_skipped_stage(&mut artifact, stage.name());
+                    }
+                    _ => stage
+                        .plan(&mut artifact, &self.contexts[index])
+                        .with_context(|| format!("Stage '{}' failed", stage.name()))?,
+                }
+                let split = std::mem::take(&mut artifact.split);
+                outputs.push(artifact);
+                outputs.extend(split);
+            }
+            planned.push(outputs);
+        }
+        let leaves: Vec<(Artifact, &OutputSpec)> = if planned.is_empty() {
+            vec![(root, &self.ctx.output)]
+        } else {
+            self.graph
+                .leaves()
+                .flat_map(|leaf| {
+                    planned[leaf]
+                        .iter()
+                        .map(move |artifact| (artifact.clone(), &self.contexts[leaf].output))
+                })
+                .collect()
+        };
+        Ok(leaves
+            .into_iter()
+            .map(|(artifact, output)| PipelineResult {
+                input: input.to_path_buf(),
+                output: result_output(&artifact, output),
+                metadata: artifact.metadata,
+                duration: Duration::ZERO,
+            })
+            .collect())
+    }
+
+    /// Runs stage `index`, retrying it per its policy from a copy of the
+    /// artifact taken before the first attempt.
+    fn run_stage(&self, index: usize, artifact: &mut Artifact, device: StageDevice) -> Result<()> {
+        let stage = &self.stages[index];
+        let ctx = &self.contexts[index];
+        let Some(policy) = &self.retries[index] else {
+            return stage.run(artifact, ctx, device);
+        };
+        let original = artifact.clone();
+        let mut attempt = 1;
+        loop {
+            match stage.run(artifact, ctx, device) {
+                Ok(()) => break,
+                Err(err) if self.cancelled() => return Err(err.context(Cancelled)),
+                Err(err) if attempt < policy.max_attempts => {
+                    let delay = policy.delay(attempt);
+                    warn!(
+                        stage = stage.name(),
+                        attempt,
+                        max_attempts = policy.max_attempts,
+                        delay_ms = delay.as_millis() as u64,
+                        error = %format!("{err:#}"),
+                        "Stage failed; retrying"
+                    );
+                    self.metrics.record_stage_retry(stage.name());
+                    thread::sleep(delay);
+                    *artifact = original.clone();
+                    attempt += 1;
+                }
+                Err(err) => {
+                    return Err(err.context(format!(
+                        "Stage '{}' failed after {attempt} attempt(s)",
+                        stage.name()
+                    )));
+                }
+            }
+        }
+        artifact
+            .metadata
+            .insert(format!("retry.{}.attempts", stage.name()), json!(attempt));
+        Ok(())
+    }
+
     pub fn execute(&self, inputs: &[PathBuf]) -> Result<Vec<PipelineResult>> {
         self.execute_with_optional_progress(inputs, None)
     }
@@ -252,6 +753,20 @@ impl PipelineExecutor {
         self.execute_with_optional_progress(inputs, Some(&mut progress))
     }
 
+    /// Lazily processes `inputs`, yielding each result as soon as its input
+    /// completes instead of collecting them all up front.
+    pub fn execute_iter<'a>(&'a self, inputs: &'a [PathBuf]) -> PipelineResults<'a> {
+        self.metrics.reset();
+        PipelineResults {
+            executor: self,
+            inputs: inputs.iter().enumerate(),
+            total_inputs: inputs.len(),
+            started_at: Instant::now(),
+            finished: false,
+            pending: Vec::new().into_iter(),
+        }
+    }
+
     fn execute_with_optional_progress(
         &self,
         inputs: &[PathBuf],
@@ -259,54 +774,639 @@ impl PipelineExecutor {
     ) -> Result<Vec<PipelineResult>> {
         self.metrics.reset();
         let total_start = Instant::now();
-        let mut results = Vec::new();
-        let mut progress = progress;
-        for (input_index, input) in inputs.iter().enumerate() {
-            let mut artifact = Artifact::load(input)?;
-            let artifact_span =
-                tracing::span!(tracing::Level::DEBUG, "artifact", input = %input.display());
-            let _artifact_guard = artifact_span.enter();
-            match progress.as_mut() {
-                Some(callback) => {
-                    self.process(
-                        &mut artifact,
+        let split = self
+            .stages
+            .iter()
+            .position(|stage| self.scheduler.routes_to_encode_pool(stage.name()))
+            .filter(|_| !self.is_branched());
+        let results = match (
+            self.scheduler.jobs(),
+            split,
+            self.scheduler.encode_workers(),
+        ) {
+            (Some(jobs), _, _) => self.execute_parallel(inputs, jobs, progress)?,
+            (None, Some(split), Some(workers)) => {
+                self.execute_pipelined(inputs, split, workers, progress)?
+            }
+            _ => {
+                let mut results = Vec::with_capacity(inputs.len());
+                let mut progress = progress;
+                for (input_index, input) in inputs.iter().enumerate() {
+                    match self.run_input(
                         input,
                         input_index,
                         inputs.len(),
-                        Some(&mut **callback),
-                    )?;
+                        reborrow_progress(&mut progress),
+                    ) {
+                        Ok(input_results) => results.extend(input_results),
+                        Err(err) if cancel::is_cancelled(&err) => break,
+                        Err(err) => return Err(err),
+                    }
                 }
-                None => {
-                    self.process(&mut artifact, input, input_index, inputs.len(), None)?;
+                results
+            }
+        };
+        if self.cancelled() {
+            warn!("Run cancelled; skipping stage finalization");
+        } else {
+            self.finalize_stages()?;
+        }
+
+        self.metrics.record_total_duration(total_start.elapsed());
+
+        Ok(results)
+    }
+
+    /// Hands inputs to `jobs` worker threads that each run the whole pipeline,
+    /// collecting results in input order. The first failure stops workers from
+    /// picking up further inputs.
+    fn execute_parallel(
+        &self,
+        inputs: &[PathBuf],
+        jobs: usize,
+        mut progress: Option<&mut dyn FnMut(StageProgress<'_>)>,
+    ) -> Result<Vec<PipelineResult>> {
+        let total_inputs = inputs.len();
+        let mut slots: Vec<Option<Vec<PipelineResult>>> = (0..total_inputs).map(|_| None).collect();
+        let mut failure: Option<anyhow::Error> = None;
+        let next_input = AtomicUsize::new(0);
+        let stop = AtomicBool::new(false);
+        let (event_tx, event_rx) = mpsc::channel::<EncodeEvent>();
+
+        thread::scope(|scope| {
+            for _ in 0..jobs.min(total_inputs) {
+                let (next_input, stop) = (&next_input, &stop);
+                let event_tx = event_tx.clone();
+                scope.spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        let input_index = next_input.fetch_add(1, Ordering::Relaxed);
+                        let Some(input) = inputs.get(input_index) else {
+                            break;
+                        };
+                        let mut forward = |progress: StageProgress<'_>| {
+                            let _ = event_tx.send(EncodeEvent::Progress {
+                                input_index: progress.input_index,
+                                stage_index: progress.stage_index,
+                                stage_name: progress.stage_name,
+                            });
+                        };
+                        let result =
+                            self.run_input(input, input_index, total_inputs, Some(&mut forward));
+                        if result.is_err() {
+                            stop.store(true, Ordering::Relaxed);
+                        }
+                        if event_tx
+                            .send(EncodeEvent::Done {
+                                input_index,
+                                result,
+                            })
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(event_tx);
+
+            for event in event_rx {
+                self.handle_encode_event(
+                    event,
+                    inputs,
+                    &mut slots,
+                    &mut failure,
+                    reborrow_progress(&mut progress),
+                );
+            }
+        });
+
+        if let Some(err) = failure {
+            return Err(err);
+        }
+        collect_slots(slots, self.cancelled(), "Worker exited without a result")
+    }
+
+    /// Runs the stages before `split` on the calling thread and hands each
+    /// artifact to a bounded pool of encode workers for the remainder, so the
+    /// next input is decoded while earlier ones are still encoding.
+    fn execute_pipelined(
+        &self,
+        inputs: &[PathBuf],
+        split: usize,
+        workers: usize,
+        mut progress: Option<&mut dyn FnMut(StageProgress<'_>)>,
+    ) -> Result<Vec<PipelineResult>> {
+        let total_inputs = inputs.len();
+        let mut slots: Vec<Option<Vec<PipelineResult>>> = (0..total_inputs).map(|_| None).collect();
+        let mut failure: Option<anyhow::Error> = None;
+        let (job_tx, job_rx) = mpsc::sync_channel::<EncodeJob>(workers);
+        let job_rx = Mutex::new(job_rx);
+        let (event_tx, event_rx) = mpsc::channel::<EncodeEvent>();
+
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                let job_rx = &job_rx;
+                let event_tx = event_tx.clone();
+                scope.spawn(move || {
+                    loop {
+                        let job = match job_rx.lock() {
+                            Ok(receiver) => receiver.recv(),
+                            Err(_) => break,
+                        };
+                        let Ok(job) = job else {
+                            break;
+                        };
+                        let done = self.finish_encode_job(job, split, &event_tx);
+                        if event_tx.send(done).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(event_tx);
+
+            for (input_index, input) in inputs.iter().enumerate() {
+                while let Ok(event) = event_rx.try_recv() {
+                    self.handle_encode_event(
+                        event,
+                        inputs,
+                        &mut slots,
+                        &mut failure,
+                        reborrow_progress(&mut progress),
+                    );
+                }
+                if failure.is_some() || self.cancelled() {
+                    break;
+                }
+                match self.resumed(input) {
+                    Ok(Some(result)) => {
+                        slots[input_index] = Some(vec![result]);
+                        continue;
+                    }
+                    Ok(None) => {}
+                    Err(err) => {
+                        failure = Some(err);
+                        break;
+                    }
+                }
+                let started_at = Instant::now();
+                let (mut artifact, reservation) = match self.admit(input, input_index) {
+                    Ok(admitted) => admitted,
+                    Err(err) => {
+                        failure = Some(err);
+                        break;
+                    }
+                };
+                let cache_key = match self.cached(&artifact, started_at) {
+                    Ok(CacheLookup::Hit(result)) => {
+                        slots[input_index] = Some(vec![*result]);
+                        continue;
+                    }
+                    Ok(CacheLookup::Miss(key)) => key,
+                    Err(err) => {
+                        failure = Some(err);
+                        break;
+                    }
+                };
+                let artifact_span =
+                    tracing::span!(tracing::Level::DEBUG, "artifact", input = %input.display());
+                let artifact_guard = artifact_span.enter();
+                if let Err(err) = self.run_stages(
+                    &mut artifact,
+                    0..split,
+                    input,
+                    input_index,
+                    total_inputs,
+                    reborrow_progress(&mut progress),
+                ) {
+                    failure = Some(err);
+                    break;
+                }
+                drop(artifact_guard);
+                let job = EncodeJob {
+                    input_index,
+                    input: input.clone(),
+                    total_inputs,
+                    artifact,
+                    reservation,
+                    started_at,
+                    cache_key,
+                };
+                if job_tx.send(job).is_err() {
+                    break;
                 }
             }
-            if let Some(metrics) = self.evaluate_quality_gates(&mut artifact)? {
-                artifact
-                    .metadata
-                    .insert("quality.mse".to_string(), value_from_metric(metrics.mse));
-                artifact
-                    .metadata
-                    .insert("quality.psnr".to_string(), value_from_metric(metrics.psnr));
-                artifact
-                    .metadata
-                    .insert("quality.ssim".to_string(), value_from_metric(metrics.ssim));
+            drop(job_tx);
+
+            for event in event_rx {
+                self.handle_encode_event(
+                    event,
+                    inputs,
+                    &mut slots,
+                    &mut failure,
+                    reborrow_progress(&mut progress),
+                );
             }
-            let output_path = artifact
-                .metadata
-                .get("output_path")
-                .and_then(|v| v.as_str())
-                .map(PathBuf::from)
-                .unwrap_or_else(|| self.ctx.output.directory.join(&artifact.stem));
-            results.push(PipelineResult {
-                input: input.clone(),
-                output: output_path,
-                metadata: artifact.metadata.clone(),
+        });
+
+        if let Some(err) = failure {
+            return Err(err);
+        }
+        collect_slots(
+            slots,
+            self.cancelled(),
+            "Encode worker exited without a result",
+        )
+    }
+
+    fn handle_encode_event(
+        &self,
+        event: EncodeEvent,
+        inputs: &[PathBuf],
+        slots: &mut [Option<Vec<PipelineResult>>],
+        failure: &mut Option<anyhow::Error>,
+        progress: Option<&mut dyn FnMut(StageProgress<'_>)>,
+    ) {
+        match event {
+            EncodeEvent::Progress {
+                input_index,
+                stage_index,
+                stage_name,
+            } => {
+                if let Some(callback) = progress {
+                    callback(StageProgress {
+                        input: &inputs[input_index],
+                        input_index,
+                        total_inputs: inputs.len(),
+                        stage_index,
+                        total_stages: self.stages.len(),
+                        stage_name,
+                    });
+                }
+            }
+            EncodeEvent::Done {
+                input_index,
+                result,
+            } => match result {
+                Ok(result) => slots[input_index] = Some(result),
+                // The input stays pending.
+                Err(err) if cancel::is_cancelled(&err) => {}
+                Err(err) => {
+                    failure.get_or_insert(err);
+                }
+            },
+        }
+    }
+
+    fn finish_encode_job(
+        &self,
+        job: EncodeJob,
+        split: usize,
+        events: &mpsc::Sender<EncodeEvent>,
+    ) -> EncodeEvent {
+        let EncodeJob {
+            input_index,
+            input,
+            total_inputs,
+            mut artifact,
+            reservation,
+            started_at,
+            cache_key,
+        } = job;
+        let artifact_span =
+            tracing::span!(tracing::Level::DEBUG, "artifact", input = %input.display());
+        let _artifact_guard = artifact_span.enter();
+        let mut forward = |progress: StageProgress<'_>| {
+            let _ = events.send(EncodeEvent::Progress {
+                input_index: progress.input_index,
+                stage_index: progress.stage_index,
+                stage_name: progress.stage_name,
             });
+        };
+        let result = self
+            .run_stages(
+                &mut artifact,
+                split..self.stages.len(),
+                &input,
+                input_index,
+                total_inputs,
+                Some(&mut forward),
+            )
+            .and_then(|()| {
+                self.finish(
+                    artifact,
+                    &input,
+                    reservation.as_ref(),
+                    started_at,
+                    cache_key,
+                    self.leaf_output(),
+                )
+            })
+            .map(|result| vec![result]);
+        EncodeEvent::Done {
+            input_index,
+            result,
         }
+    }
 
-        self.metrics.record_total_duration(total_start.elapsed());
+    fn admit(
+        &self,
+        input: &Path,
+        input_index: usize,
+    ) -> Result<(Artifact, Option<MemoryReservation>)> {
+        let reservation = match &self.memory_budget {
+            Some(budget) => Some(
+                budget
+                    .acquire(estimate_artifact_bytes(input, |width, height| {
+                        self.working_pixels(width, height)
+                    }))
+                    .with_context(|| format!("Cannot admit input {}", input.display()))?,
+            ),
+            None => None,
+        };
+        let mut artifact = Artifact::load(input)?;
+        self.annotate_input(&mut artifact, input_index);
+        Ok((artifact, reservation))
+    }
 
-        Ok(results)
+    /// Records the input facts output structures ask for: its position in
+    /// the run, content hash and modification time.
+    fn annotate_input(&self, artifact: &mut Artifact, input_index: usize) {
+        let uses = |name: &str| {
+            std::iter::once(&self.ctx)
+                .chain(&self.contexts)
+                .any(|ctx| structure::uses(&ctx.output.structure, name))
+        };
+        if uses("seq") {
+            artifact
+                .metadata
+                .insert(structure::INPUT_INDEX_KEY.to_string(), json!(input_index));
+        }
+        if uses("hash") {
+            let digest = format!("{:x}", Sha256::digest(&artifact.data[..]));
+            artifact.metadata.insert(
+                structure::INPUT_SHA256_KEY.to_string(),
+                Value::String(digest),
+            );
+        }
+        if uses("date") {
+            // Archive members take the archive's modification time.
+            let source = artifact
+                .metadata
+                .get("archive.path")
+                .and_then(Value::as_str)
+                .map_or(artifact.input_path.clone(), PathBuf::from);
+            let modified = fs::metadata(&source)
+                .and_then(|metadata| metadata.modified())
+                .map_or_else(|_| Local::now(), DateTime::<Local>::from);
+            artifact.metadata.insert(
+                structure::INPUT_MODIFIED_KEY.to_string(),
+                Value::String(modified.to_rfc3339()),
+            );
+        }
+    }
+
+    /// Pixels held in working images for a `width` x `height` input: the
+    /// largest image any stage produces, once per branch of a branching
+    /// pipeline since their artifacts are alive together.
+    fn working_pixels(&self, width: u32, height: u32) -> u64 {
+        let mut dimensions = Vec::with_capacity(self.stages.len());
+        let mut peak = u64::from(width) * u64::from(height);
+        for (index, stage) in self.stages.iter().enumerate() {
+            let (in_width, in_height) = self
+                .graph
+                .parent(index)
+                .map_or((width, height), |parent| dimensions[parent]);
+            let (out_width, out_height) = stage.output_dimensions(in_width, in_height);
+            peak = peak.max(u64::from(out_width) * u64::from(out_height));
+            dimensions.push((out_width, out_height));
+        }
+        peak * self.graph.leaves().count().max(1) as u64
+    }
+
+    fn run_input(
+        &self,
+        input: &Path,
+        input_index: usize,
+        total_inputs: usize,
+        progress: Option<&mut dyn FnMut(StageProgress<'_>)>,
+    ) -> Result<Vec<PipelineResult>> {
+        self.ctx.cancellation.check()?;
+        if let Some(result) = self.resumed(input)? {
+            return Ok(vec![result]);
+        }
+        let started_at = Instant::now();
+        let (mut artifact, reservation) = self.admit(input, input_index)?;
+        let cache_key = match self.cached(&artifact, started_at)? {
+            CacheLookup::Hit(result) => return Ok(vec![*result]),
+            CacheLookup::Miss(key) => key,
+        };
+        let artifact_span =
+            tracing::span!(tracing::Level::DEBUG, "artifact", input = %input.display());
+        let _artifact_guard = artifact_span.enter();
+        if self.is_branched() {
+            let leaves =
+                self.process_branches(artifact, input, input_index, total_inputs, progress)?;
+            return leaves
+                .into_iter()
+                .map(|(leaf, artifact)| {
+                    self.finish(
+                        artifact,
+                        input,
+                        reservation.as_ref(),
+                        started_at,
+                        None,
+                        &self.contexts[leaf].output,
+                    )
+                })
+                .collect();
+        }
+        self.process(&mut artifact, input, input_index, total_inputs, progress)?;
+        let result = self.finish(
+            artifact,
+            input,
+            reservation.as_ref(),
+            started_at,
+            cache_key,
+            self.leaf_output(),
+        )?;
+        Ok(vec![result])
+    }
+
+    /// Runs every stage of a branching pipeline on `artifact`, handing each
+    /// stage a clone of its parent's outputs (the last one takes them over),
+    /// and returns the artifacts that come out of each leaf stage. Artifacts
+    /// a stage splits off continue next to the one they came from.
+    fn process_branches(
+        &self,
+        artifact: Artifact,
+        input: &Path,
+        input_index: usize,
+        total_inputs: usize,
+        mut progress: Option<&mut dyn FnMut(StageProgress<'_>)>,
+    ) -> Result<Vec<(usize, Artifact)>> {
+        let mut pending: Vec<usize> = (0..self.stages.len())
+            .map(|index| self.graph.children(index))
+            .collect();
+        let mut outputs: Vec<Option<Vec<Artifact>>> =
+            (0..self.stages.len()).map(|_| None).collect();
+        let mut root = Some(vec![artifact]);
+        let mut leaves = Vec::new();
+        for index in 0..self.stages.len() {
+            let artifacts = match self.graph.parent(index) {
+                Some(parent) => {
+                    pending[parent] -= 1;
+                    if pending[parent] == 0 {
+                        outputs[parent].take()
+                    } else {
+                        outputs[parent].clone()
+                    }
+                }
+                None => root.take(),
+            };
+            let artifacts =
+                artifacts.ok_or_else(|| anyhow!("Stage {} has no input artifact", index + 1))?;
+            let mut processed = Vec::with_capacity(artifacts.len());
+            for mut artifact in artifacts {
+                self.run_stages(
+                    &mut artifact,
+                    index..index + 1,
+                    input,
+                    input_index,
+                    total_inputs,
+                    reborrow_progress(&mut progress),
+                )?;
+                let split = std::mem::take(&mut artifact.split);
+                processed.push(artifact);
+                processed.extend(split);
+            }
+            if self.graph.children(index) == 0 {
+                leaves.extend(processed.into_iter().map(|artifact| (index, artifact)));
+            } else {
+                outputs[index] = Some(processed);
+            }
+        }
+        Ok(leaves)
+    }
+
+    /// Output layout of the last stage of a linear pipeline.
+    fn leaf_output(&self) -> &OutputSpec {
+        self.contexts
+            .last()
+            .map_or(&self.ctx.output, |ctx| &ctx.output)
+    }
+
+    fn finish(
+        &self,
+        mut artifact: Artifact,
+        input: &Path,
+        reservation: Option<&MemoryReservation>,
+        started_at: Instant,
+        cache_key: Option<String>,
+        output: &OutputSpec,
+    ) -> Result<PipelineResult> {
+        if let Some(metrics) = self.evaluate_quality_gates(&mut artifact)? {
+            artifact
+                .metadata
+                .insert("quality.mse".to_string(), value_from_metric(metrics.mse));
+            artifact
+                .metadata
+                .insert("quality.psnr".to_string(), value_from_metric(metrics.psnr));
+            artifact
+                .metadata
+                .insert("quality.ssim".to_string(), value_from_metric(metrics.ssim));
+        }
+        let output_path = result_output(&artifact, output);
+        artifact.replace_data(Vec::new());
+        if let Some(reservation) = reservation {
+            artifact.metadata.insert(
+                "memory.estimated_bytes".to_string(),
+                json!(reservation.bytes()),
+            );
+        }
+        let result = PipelineResult {
+            input: input.to_path_buf(),
+            output: output_path,
+            metadata: std::mem::take(&mut artifact.metadata),
+            duration: started_at.elapsed(),
+        };
+        let kept = result.metadata.contains_key(overwrite::SKIPPED_KEY);
+        if let (Some(cache), Some(key), false) = (&self.cache, cache_key, kept) {
+            // A failed store only costs a future cache hit.
+            if let Err(err) = cache.store(&key, &artifact.stem, &result) {
+                warn!(error = %err, input = %input.display(), "Failed to store output in cache");
+            }
+        }
+        self.record_completed(&result)?;
+        Ok(result)
+    }
+
+    fn record_completed(&self, result: &PipelineResult) -> Result<()> {
+        if let Some(ledger) = &self.resume {
+            ledger.record(result)?;
+        }
+        if result.metadata.contains_key(overwrite::SKIPPED_KEY) {
+            self.metrics.record_output_skipped();
+        }
+        if let Some(saved) = result
+            .metadata
+            .get(stages::OPTIMIZE_SAVED_KEY)
+            .and_then(Value::as_u64)
+        {
+            self.metrics.record_optimized(saved);
+        }
+        self.metrics.record_input_processed();
+        Ok(())
+    }
+
+    /// Looks `artifact` up in the output cache, restoring its output on a hit.
+    fn cached(&self, artifact: &Artifact, started_at: Instant) -> Result<CacheLookup> {
+        let Some(cache) = &self.cache else {
+            return Ok(CacheLookup::Miss(None));
+        };
+        let key = cache.key(artifact);
+        match cache.restore(
+            &key,
+            artifact,
+            self.leaf_output(),
+            &self.ctx.outputs,
+            self.ctx.overwrite,
+        )? {
+            Some(mut result) => {
+                tracing::debug!(input = %artifact.input_path.display(), "Output served from cache");
+                self.metrics.record_cache_hit();
+                result.duration = started_at.elapsed();
+                self.record_completed(&result)?;
+                Ok(CacheLookup::Hit(Box::new(result)))
+            }
+            None => {
+                self.metrics.record_cache_miss();
+                Ok(CacheLookup::Miss(Some(key)))
+            }
+        }
+    }
+
+    /// The earlier run's result for `input` when resuming and it is still
+    /// valid.
+    fn resumed(&self, input: &Path) -> Result<Option<PipelineResult>> {
+        let Some(ledger) = &self.resume else {
+            return Ok(None);
+        };
+        let result = ledger.completed(input)?;
+        if result.is_some() {
+            tracing::debug!(input = %input.display(), "Skipping already converted input");
+            self.metrics.record_input_skipped();
+        }
+        Ok(result)
+    }
+
+    fn finalize_stages(&self) -> Result<()> {
+        for (stage, ctx) in self.stages.iter().zip(&self.contexts) {
+            stage
+                .finalize(ctx)
+                .with_context(|| format!("Failed to finalize stage '{}'", stage.name()))?;
+        }
+        Ok(())
     }
 
     pub fn metrics(&self) -> MetricsCollector {
@@ -317,22 +1417,18 @@ impl PipelineExecutor {
         if self.quality_gates.is_empty() {
             return Ok(None);
         }
+        if let Some(reference) = artifact.original_media.clone()
+            && let Some(reference) = &reference.video
+        {
+            self.evaluate_video_gates(artifact, reference)?;
+            return Ok(None);
+        }
 
         let reference = artifact
             .original_image
             .as_ref()
             .ok_or_else(|| anyhow!("Quality gates require an original decoded image"))?;
-        if matches!(
-            artifact
-                .metadata
-                .get("output.decode_supported")
-                .and_then(Value::as_bool),
-            Some(false)
-        ) {
-            warn!("Skipping quality gates: encoded output decoder unavailable");
-            artifact
-                .metadata
-                .insert("quality.status".into(), Value::String("skipped".into()));
+        if skip_unverified_output(&mut artifact.metadata) {
             return Ok(None);
         }
         let Some(candidate) = artifact.image.as_ref() else {
@@ -387,10 +1483,133 @@ impl PipelineExecutor {
             bail!(reason);
         } else {
             self.metrics.record_quality_pass();
+            artifact
+                .metadata
+                .insert("quality.status".into(), Value::String("passed".into()));
         }
 
         Ok(Some(metrics))
     }
+
+    /// Compares the artifact's video with `reference` frame by frame and
+    /// checks the gates' video thresholds, recording the metrics as
+    /// `quality.video.*`.
+    fn evaluate_video_gates(&self, artifact: &mut Artifact, reference: &VideoStream) -> Result<()> {
+        if skip_unverified_output(&mut artifact.metadata) {
+            return Ok(());
+        }
+        let Some(candidate) = artifact.media.video.as_ref() else {
+            warn!("Skipping quality gates: artifact video unavailable");
+            artifact
+                .metadata
+                .insert("quality.status".into(), Value::String("skipped".into()));
+            return Ok(());
+        };
+
+        let metrics = compute_video_metrics(reference, candidate)?;
+        for (key, value) in [
+            ("min_psnr", metrics.min_psnr),
+            ("mean_psnr", metrics.mean_psnr),
+            ("min_ssim", metrics.min_ssim),
+            ("mean_ssim", metrics.mean_ssim),
+        ] {
+            artifact
+                .metadata
+                .insert(format!("quality.video.{key}"), value_from_metric(value));
+        }
+        artifact
+            .metadata
+            .insert("quality.video.frames".into(), json!(metrics.frames));
+
+        let mut failure: Option<String> = None;
+        for gate in &self.quality_gates {
+            let label = gate.label.as_deref();
+            if let Some(min_psnr) = gate.min_frame_psnr
+                && metrics.min_psnr < min_psnr
+            {
+                failure = Some(format!(
+                    "Quality gate '{}' failed: PSNR {:.2} at frame {} < {:.2}",
+                    label.unwrap_or("frame_psnr"),
+                    metrics.min_psnr,
+                    metrics.worst_psnr_frame,
+                    min_psnr
+                ));
+                break;
+            }
+            if let Some(min_psnr) = gate.min_mean_psnr
+                && metrics.mean_psnr < min_psnr
+            {
+                failure = Some(format!(
+                    "Quality gate '{}' failed: mean PSNR {:.2} < {:.2}",
+                    label.unwrap_or("mean_psnr"),
+                    metrics.mean_psnr,
+                    min_psnr
+                ));
+                break;
+            }
+            if let Some(min_ssim) = gate.min_frame_ssim
+                && metrics.min_ssim < min_ssim
+            {
+                failure = Some(format!(
+                    "Quality gate '{}' failed: SSIM {:.5} at frame {} < {:.5}",
+                    label.unwrap_or("frame_ssim"),
+                    metrics.min_ssim,
+                    metrics.worst_ssim_frame,
+                    min_ssim
+                ));
+                break;
+            }
+            if let Some(min_ssim) = gate.min_mean_ssim
+                && metrics.mean_ssim < min_ssim
+            {
+                failure = Some(format!(
+                    "Quality gate '{}' failed: mean SSIM {:.5} < {:.5}",
+                    label.unwrap_or("mean_ssim"),
+                    metrics.mean_ssim,
+                    min_ssim
+                ));
+                break;
+            }
+        }
+
+        if let Some(reason) = failure {
+            self.metrics.record_quality_failure();
+            bail!(reason);
+        }
+        self.metrics.record_quality_pass();
+        artifact
+            .metadata
+            .insert("quality.status".into(), Value::String("passed".into()));
+        Ok(())
+    }
+}
+
+struct EncodeJob {
+    input_index: usize,
+    input: PathBuf,
+    total_inputs: usize,
+    artifact: Artifact,
+    reservation: Option<MemoryReservation>,
+    started_at: Instant,
+    cache_key: Option<String>,
+}
+
+enum CacheLookup {
+    Hit(Box<PipelineResult>),
+    /// Carries the key to store the output under, if caching.
+    Miss(Option<String>),
+}
+
+enum EncodeEvent {
+    Progress {
+        input_index: usize,
+        stage_index: usize,
+        stage_name: &'static str,
+    },
+    Done {
+        input_index: usize,
+        result: Result<Vec<PipelineResult>>,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -398,6 +1617,79 @@ pub struct PipelineResult {
     pub input: PathBuf,
     pub output: PathBuf,
     pub metadata: Map<String, Value>,
+    pub duration: Duration,
+}
+
+/// Iterator returned by [`PipelineExecutor::execute_iter`].
+///
+/// Each call to `next` loads and processes one input. A failing input yields
+/// an `Err` without stopping the iterator, so callers decide whether to abort.
+pub struct PipelineResults<'a> {
+    executor: &'a PipelineExecutor,
+    inputs: std::iter::Enumerate<std::slice::Iter<'a, PathBuf>>,
+    total_inputs: usize,
+    started_at: Instant,
+    finished: bool,
+    /// Further results of the last input when a branching pipeline yields
+    /// several.
+    pending: std::vec::IntoIter<PipelineResult>,
+}
+
+impl Iterator for PipelineResults<'_> {
+    type Item = Result<PipelineResult>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(result) = self.pending.next() {
+            return Some(Ok(result));
+        }
+        if self.finished {
+            return None;
+        }
+        if self.executor.cancelled() {
+            // Leave the remaining inputs pending and skip finalization.
+            self.finished = true;
+            self.executor
+                .metrics
+                .record_total_duration(self.started_at.elapsed());
+            return None;
+        }
+        match self.inputs.next() {
+            Some((input_index, input)) => {
+                match self
+                    .executor
+                    .run_input(input, input_index, self.total_inputs, None)
+                {
+                    Ok(results) => {
+                        self.pending = results.into_iter();
+                        self.next()
+                    }
+                    Err(err) => Some(Err(err)),
+                }
+            }
+            None => {
+                self.finished = true;
+                let finalized = self.executor.finalize_stages();
+                self.executor
+                    .metrics
+                    .record_total_duration(self.started_at.elapsed());
+                finalized.err().map(Err)
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let pending = self.pending.len();
+        if self.finished {
+            (pending, Some(pending))
+        } else if self.executor.is_branched() {
+            (pending + self.inputs.len(), None)
+        } else {
+            (
+                pending + self.inputs.len(),
+                Some(pending + self.inputs.len()),
+            )
+        }
+    }
 }
 
 pub fn build_pipeline(
@@ -408,19 +1700,115 @@ pub fn build_pipeline(
     device_policy: DevicePolicy,
 ) -> Result<PipelineExecutor> {
     let mut stages = Vec::with_capacity(stage_specs.len());
-    for spec in stage_specs {
+    let mut conditions = Vec::with_capacity(stage_specs.len());
+    let mut retries = Vec::with_capacity(stage_specs.len());
+    for (index, spec) in stage_specs.iter().enumerate() {
         let params = spec.params.clone().unwrap_or_default();
         let stage = stage_registry.create(&spec.stage, params)?;
         stages.push(stage);
+        let condition = spec
+            .when
+            .as_deref()
+            .map(Condition::parse)
+            .transpose()
+            .with_context(|| {
+                format!(
+                    "Stage {} ('{}') has an invalid when clause",
+                    index + 1,
+                    spec.stage
+                )
+            })?;
+        conditions.push(condition);
+        if let Some(retry) = &spec.retry {
+            retry.validate().with_context(|| {
+                format!(
+                    "Stage {} ('{}') has an invalid retry policy",
+                    index + 1,
+                    spec.stage
+                )
+            })?;
+        }
+        retries.push(spec.retry.clone());
     }
 
+    let graph = StageGraph::resolve(stage_specs, &output_spec)?;
+    for leaf in graph.leaves() {
+        let structure = &graph.output(leaf).structure;
+        structure::validate(structure)
+            .with_context(|| format!("Invalid output structure '{structure}'"))?;
+    }
     let scheduler = TaskScheduler::new(device_policy);
-    Ok(PipelineExecutor::new(
-        stages,
-        output_spec,
-        quality_gates,
-        scheduler,
-    ))
+    Ok(
+        PipelineExecutor::new(stages, output_spec, quality_gates, scheduler)
+            .with_graph(graph)
+            .with_conditions(conditions)
+            .with_retries(retries),
+    )
+}
+
+/// Where `artifact`'s output went: the path its encode stage recorded, else a
+/// file named after its stem in the output directory.
+fn result_output(artifact: &Artifact, output: &OutputSpec) -> PathBuf {
+    artifact
+        .metadata
+        .get("output_path")
+        .and_then(|v| v.as_str())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| output.directory.join(&artifact.stem))
+}
+
+/// Lists `stage` under `pipeline.skipped_stages` in the artifact metadata.
+fn record_skipped_stage(artifact: &mut Artifact, stage: &str) {
+    let skipped = artifact
+        .metadata
+        .entry("pipeline.skipped_stages")
+        .or_insert_with(|| Value::Array(Vec::new()));
+    if let Value::Array(names) = skipped {
+        names.push(Value::String(stage.to_string()));
+    }
+}
+
+/// Flattens per-input results back into input order. Inputs a cancelled run
+/// never got to have no results.
+fn collect_slots(
+    slots: Vec<Option<Vec<PipelineResult>>>,
+    cancelled: bool,
+    missing: &str,
+) -> Result<Vec<PipelineResult>> {
+    let mut results = Vec::with_capacity(slots.len());
+    for slot in slots {
+        match slot {
+            Some(slot) => results.extend(slot),
+            None if cancelled => {}
+            None => bail!("{missing}"),
+        }
+    }
+    Ok(results)
+}
+
+fn reborrow_progress<'a>(
+    progress: &'a mut Option<&mut dyn FnMut(StageProgress<'_>)>,
+) -> Option<&'a mut dyn FnMut(StageProgress<'_>)> {
+    match progress {
+        Some(callback) => Some(&mut **callback),
+        None => None,
+    }
+}
+
+/// Marks the quality check skipped when the encode stage could not decode
+/// its output back or was told not to.
+fn skip_unverified_output(metadata: &mut Map<String, Value>) -> bool {
+    let flag = |key: &str| metadata.get(key).and_then(Value::as_bool);
+    let reason = if flag("output.decode_supported") == Some(false) {
+        "encoded output decoder unavailable"
+    } else if flag("output.verified") == Some(false) {
+        "encoded output was not verified"
+    } else {
+        return false;
+    };
+    warn!("Skipping quality gates: {reason}");
+    metadata.insert("quality.status".into(), Value::String("skipped".into()));
+    true
 }
 
 fn value_from_metric(value: f64) -> Value {
@@ -431,9 +1819,26 @@ fn value_from_metric(value: f64) -> Value {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct StageSpec {
     pub stage: String,
     #[serde(default)]
     pub params: Option<StageParameters>,
+    /// Names the stage so later stages can branch from it with `after`.
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Runs the stage on the output of the earlier stage with this `id`
+    /// instead of the one listed before it.
+    #[serde(default)]
+    pub after: Option<String>,
+    /// Output layout for this stage and every stage downstream of it.
+    #[serde(default)]
+    pub output: Option<OutputSpec>,
+    /// Runs the stage again when it fails (see [`crate::retry`]).
+    #[serde(default)]
+    pub retry: Option<RetryPolicy>,
+    /// Skips the stage for inputs whose metadata does not satisfy this
+    /// clause (see [`crate::condition`]).
+    #[serde(default)]
+    pub when: Option<String>,
 }
@@ -0,0 +1,208 @@
+//! Execution plans for `run --dry-run`.
+//!
+//! A plan instantiates every stage, so bad parameters fail as they would in
+//! a run, then walks each resolved input through [`Stage::plan`] to predict
+//! its metadata, dimensions and output paths from the input's header without
+//! decoding pixels or writing anything.
+//!
+//! [`Stage::plan`]: crate::pipeline::Stage::plan
+
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::overwrite;
+use crate::pipeline::{PipelineExecutor, StageParameters, StageSpec};
+use crate::retry::RetryPolicy;
+use crate::scheduler::StageDevice;
+use crate::stages::{FALLBACKS_KEY, PALETTE_SIDECAR_KEY, THUMBNAILS_KEY, VIDEO_THUMBNAILS_KEY};
+
+#[derive(Debug, Serialize)]
+pub struct RunPlan {
+    pub recipe: PathBuf,
+    pub stages: Vec<PlannedStage>,
+    pub inputs: Vec<PlannedInput>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlannedStage {
+    pub stage: String,
+    pub id: Option<String>,
+    pub after: Option<String>,
+    pub device: StageDevice,
+    /// Parameters as given, keys sorted.
+    pub params: StageParameters,
+    pub when: Option<String>,
+    pub retry: Option<RetryPolicy>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlannedInput {
+    pub input: PathBuf,
+    /// One path per result; branching pipelines produce several.
+    pub outputs: Vec<PathBuf>,
+    /// Outputs that already exist and that the overwrite policy would keep.
+    pub kept_outputs: Vec<PathBuf>,
+    /// Stages the input's metadata does not satisfy the `when` clause of.
+    pub skipped_stages: Vec<String>,
+    /// Why the input would fail, e.g. an unreadable header or an output
+    /// colliding with another input's.
+    pub error: Option<String>,
+}
+
+impl RunPlan {
+    /// Plans `inputs` through `executor`, built from `specs`.
+    pub fn build(
+        recipe: &Path,
+        specs: &[StageSpec],
+        executor: &PipelineExecutor,
+        inputs: &[PathBuf],
+    ) -> Result<Self> {
+        let stages = specs
+            .iter()
+            .zip(executor.stage_devices()?)
+            .map(|(spec, device)| PlannedStage {
+                stage: spec.stage.clone(),
+                id: spec.id.clone(),
+                after: spec.after.clone(),
+                device,
+                params: spec.params.clone().unwrap_or_default(),
+                when: spec.when.clone(),
+                retry: spec.retry.clone(),
+            })
+            .collect();
+        let inputs = inputs
+            .iter()
+            .enumerate()
+            .map(|(index, input)| plan_input(executor, input, index))
+            .collect();
+        Ok(Self {
+            recipe: recipe.to_path_buf(),
+            stages,
+            inputs,
+        })
+    }
+
+    /// Inputs the run would fail on.
+    pub fn errors(&self) -> usize {
+        self.inputs
+            .iter()
+            .filter(|input| input.error.is_some())
+            .count()
+    }
+
+    /// Human-readable plan, one stage or input per line.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "Plan for {}", self.recipe.display());
+        let _ = writeln!(out, "Stages ({}):", self.stages.len());
+        for (index, stage) in self.stages.iter().enumerate() {
+            let device = match stage.device {
+                StageDevice::Cpu => "cpu",
+                StageDevice::Gpu => "gpu",
+            };
+            let _ = write!(out, "  {}. {} ({device})", index + 1, stage.stage);
+            if let Some(id) = &stage.id {
+                let _ = write!(out, " id={id}");
+            }
+            if let Some(after) = &stage.after {
+                let _ = write!(out, " after={after}");
+            }
+            for (key, value) in &stage.params {
+                let _ = write!(out, " {key}={value}");
+            }
+            out.push('\n');
+            if let Some(when) = &stage.when {
+                let _ = writeln!(out, "     when: {when}");
+            }
+            if let Some(retry) = &stage.retry {
+                let _ = writeln!(
+                    out,
+                    "     retry: up to {} attempt(s), {} ms backoff",
+                    retry.max_attempts, retry.backoff_ms
+                );
+            }
+        }
+        let _ = writeln!(out, "Inputs ({}):", self.inputs.len());
+        for input in &self.inputs {
+            let _ = writeln!(out, "  {}", input.input.display());
+            if !input.skipped_stages.is_empty() {
+                let _ = writeln!(out, "    skips: {}", input.skipped_stages.join(", "));
+            }
+            match &input.error {
+                Some(error) => {
+                    let _ = writeln!(out, "    error: {error}");
+                }
+                None => {
+                    for output in &input.outputs {
+                        let kept = if input.kept_outputs.contains(output) {
+                            " (kept)"
+                        } else {
+                            ""
+                        };
+                        let _ = writeln!(out, "    -> {}{kept}", output.display());
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+fn plan_input(executor: &PipelineExecutor, input: &Path, index: usize) -> PlannedInput {
+    let mut planned = PlannedInput {
+        input: input.to_path_buf(),
+        outputs: Vec::new(),
+        kept_outputs: Vec::new(),
+        skipped_stages: Vec::new(),
+        error: None,
+    };
+    match executor.plan_input(input, index) {
+        Ok(results) => {
+            for result in results {
+                let skipped = result
+                    .metadata
+                    .get("pipeline.skipped_stages")
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(Value::as_str);
+                for stage in skipped {
+                    if !planned.skipped_stages.iter().any(|known| known == stage) {
+                        planned.skipped_stages.push(stage.to_string());
+                    }
+                }
+                if result.metadata.contains_key(overwrite::SKIPPED_KEY) {
+                    planned.kept_outputs.push(result.output.clone());
+                }
+                planned.outputs.push(result.output);
+                let extras = [THUMBNAILS_KEY, VIDEO_THUMBNAILS_KEY, FALLBACKS_KEY]
+                    .into_iter()
+                    .filter_map(|key| result.metadata.get(key))
+                    .filter_map(Value::as_array)
+                    .flatten();
+                for extra in extras {
+                    let Some(path) = extra.get("path").and_then(Value::as_str) else {
+                        continue;
+                    };
+                    if extra.get("skipped").is_some() {
+                        planned.kept_outputs.push(PathBuf::from(path));
+                    }
+                    planned.outputs.push(PathBuf::from(path));
+                }
+                if let Some(path) = result
+                    .metadata
+                    .get(PALETTE_SIDECAR_KEY)
+                    .and_then(Value::as_str)
+                {
+                    planned.outputs.push(PathBuf::from(path));
+                }
+            }
+        }
+        Err(err) => planned.error = Some(format!("{err:#}")),
+    }
+    planned
+}
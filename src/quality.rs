@@ -1,7 +1,11 @@
-use anyhow::{Result, anyhow};
-use image::{DynamicImage, GenericImageView};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow};
+use image::{DynamicImage, GenericImageView, ImageReader, Rgb, RgbImage};
 use serde::Serialize;
 
+use crate::video::VideoStream;
+
 type GrayFImage = image::ImageBuffer<image::Luma<f32>, Vec<f32>>;
 
 #[derive(Debug, Clone, Serialize)]
@@ -24,6 +28,148 @@ pub fn compute_metrics(
     Ok(QualityMetrics { mse, psnr, ssim })
 }
 
+/// A re-encoded video compared with its source frame by frame, each frame
+/// as [`compute_metrics`] compares still images.
+#[derive(Debug, Clone, Serialize)]
+pub struct VideoQualityMetrics {
+    pub frames: usize,
+    pub min_psnr: f64,
+    pub mean_psnr: f64,
+    pub min_ssim: f64,
+    pub mean_ssim: f64,
+    /// Indices of the frames with the lowest PSNR and the lowest SSIM.
+    pub worst_psnr_frame: usize,
+    pub worst_ssim_frame: usize,
+}
+
+/// Compares `candidate` with `reference` frame by frame, in display order.
+/// Both must have the same number of frames at the same size.
+pub fn compute_video_metrics(
+    reference: &VideoStream,
+    candidate: &VideoStream,
+) -> Result<VideoQualityMetrics> {
+    if reference.frames.len() != candidate.frames.len() {
+        return Err(anyhow!(
+            "Cannot compute metrics: frame count mismatch {} vs {}",
+            reference.frames.len(),
+            candidate.frames.len()
+        ));
+    }
+    if reference.frames.is_empty() {
+        return Err(anyhow!("Cannot compute metrics: video has no frames"));
+    }
+
+    let mut metrics = VideoQualityMetrics {
+        frames: reference.frames.len(),
+        min_psnr: f64::INFINITY,
+        mean_psnr: 0.0,
+        min_ssim: f64::INFINITY,
+        mean_ssim: 0.0,
+        worst_psnr_frame: 0,
+        worst_ssim_frame: 0,
+    };
+    for (index, (ref_frame, cand_frame)) in
+        reference.frames.iter().zip(&candidate.frames).enumerate()
+    {
+        let ref_image = DynamicImage::ImageRgb8(ref_frame.to_rgb(reference.color_space)?);
+        let cand_image = DynamicImage::ImageRgb8(cand_frame.to_rgb(candidate.color_space)?);
+        let frame = compute_metrics(&ref_image, &cand_image)
+            .with_context(|| format!("Failed to compare video frame {index}"))?;
+        if frame.psnr < metrics.min_psnr {
+            metrics.min_psnr = frame.psnr;
+            metrics.worst_psnr_frame = index;
+        }
+        if frame.ssim < metrics.min_ssim {
+            metrics.min_ssim = frame.ssim;
+            metrics.worst_ssim_frame = index;
+        }
+        metrics.mean_psnr += frame.psnr;
+        metrics.mean_ssim += frame.ssim;
+    }
+    metrics.mean_psnr /= metrics.frames as f64;
+    metrics.mean_ssim /= metrics.frames as f64;
+    Ok(metrics)
+}
+
+/// Dimensions and detected format of one side of a comparison.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageInfo {
+    pub path: PathBuf,
+    pub format: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    pub color: String,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ComparisonReport {
+    pub reference: ImageInfo,
+    pub candidate: ImageInfo,
+    pub metrics: QualityMetrics,
+}
+
+/// Decodes `path`, sniffing the format from its contents rather than its
+/// extension.
+pub fn load_for_comparison(path: &Path) -> Result<(DynamicImage, ImageInfo)> {
+    let reader = ImageReader::open(path)
+        .with_context(|| format!("Failed to open image: {}", path.display()))?
+        .with_guessed_format()
+        .with_context(|| format!("Failed to read image: {}", path.display()))?;
+    let format = reader
+        .format()
+        .and_then(|format| format.extensions_str().first().copied())
+        .map(str::to_string);
+    let image = reader
+        .decode()
+        .with_context(|| format!("Failed to decode image: {}", path.display()))?;
+    let bytes = std::fs::metadata(path).map(|meta| meta.len()).unwrap_or(0);
+    let info = ImageInfo {
+        path: path.to_path_buf(),
+        format,
+        width: image.width(),
+        height: image.height(),
+        color: format!("{:?}", image.color()),
+        bytes,
+    };
+    Ok((image, info))
+}
+
+/// Renders the largest per-channel difference of every pixel on a
+/// black → red → yellow → white ramp, scaled to the largest difference found.
+pub fn diff_heatmap(reference: &DynamicImage, candidate: &DynamicImage) -> Result<RgbImage> {
+    ensure_dimensions_match(reference, candidate)?;
+    let ref_rgb = reference.to_rgb8();
+    let cand_rgb = candidate.to_rgb8();
+
+    let diffs: Vec<u8> = ref_rgb
+        .pixels()
+        .zip(cand_rgb.pixels())
+        .map(|(r, c)| {
+            (0..3)
+                .map(|chan| r[chan].abs_diff(c[chan]))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let peak = diffs.iter().copied().max().unwrap_or(0).max(1) as f64;
+
+    let mut heatmap = RgbImage::new(ref_rgb.width(), ref_rgb.height());
+    for (pixel, diff) in heatmap.pixels_mut().zip(diffs) {
+        *pixel = heat_color(diff as f64 / peak);
+    }
+    Ok(heatmap)
+}
+
+fn heat_color(t: f64) -> Rgb<u8> {
+    let channel = |value: f64| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+    Rgb([
+        channel(t * 3.0),
+        channel(t * 3.0 - 1.0),
+        channel(t * 3.0 - 2.0),
+    ])
+}
+
 fn ensure_dimensions_match(reference: &DynamicImage, candidate: &DynamicImage) -> Result<()> {
     if reference.dimensions() != candidate.dimensions() {
         return Err(anyhow!(
@@ -109,3 +255,18 @@ fn covariance(
         .sum::<f64>()
         / (reference.width() * reference.height()) as f64
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heatmap_scales_to_largest_difference() {
+        let reference = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 1, Rgb([10, 10, 10])));
+        let mut candidate = RgbImage::from_pixel(2, 1, Rgb([10, 10, 10]));
+        candidate.put_pixel(1, 0, Rgb([60, 10, 10]));
+        let heatmap = diff_heatmap(&reference, &DynamicImage::ImageRgb8(candidate)).unwrap();
+        assert_eq!(heatmap.get_pixel(0, 0), &Rgb([0, 0, 0]));
+        assert_eq!(heatmap.get_pixel(1, 0), &Rgb([255, 255, 255]));
+    }
+}
@@ -0,0 +1,334 @@
+//! Palette quantization for indexed PNG output.
+//!
+//! Images that already use few enough colors (UI screenshots, diagrams, pixel
+//! art) get an exact palette and lose nothing. Anything busier is reduced
+//! with NeuQuant, optionally Floyd-Steinberg dithered so gradients keep
+//! their shape instead of banding. Grayscale can also be reduced to 1, 2 or
+//! 4 bits for e-ink panels and thermal printers.
+
+use std::collections::HashMap;
+
+use anyhow::{Result, bail};
+use color_quant::NeuQuant;
+use image::imageops;
+use image::{GrayImage, Luma, RgbaImage};
+
+/// NeuQuant sampling factor: 1 looks at every pixel, 30 at every 30th.
+const SAMPLE_FACTOR: i32 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dither {
+    None,
+    FloydSteinberg,
+    /// An 8x8 Bayer threshold matrix; grayscale reduction only.
+    Ordered,
+}
+
+impl Dither {
+    pub fn from_name(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().replace('-', "_").as_str() {
+            "none" | "off" => Some(Self::None),
+            "floyd_steinberg" | "floyd" | "fs" => Some(Self::FloydSteinberg),
+            "ordered" | "bayer" => Some(Self::Ordered),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::FloydSteinberg => "floyd_steinberg",
+            Self::Ordered => "ordered",
+        }
+    }
+}
+
+/// An image as palette indices, one byte per pixel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexedImage {
+    pub width: u32,
+    pub height: u32,
+    /// RGB triples, one per palette entry (PNG `PLTE`).
+    pub palette: Vec<u8>,
+    /// Alpha of the leading palette entries; entries past the end are
+    /// opaque (PNG `tRNS`). Empty when the whole palette is opaque.
+    pub alpha: Vec<u8>,
+    pub indices: Vec<u8>,
+    /// Whether the palette holds every color of the source exactly.
+    pub exact: bool,
+}
+
+impl IndexedImage {
+    pub fn colors(&self) -> usize {
+        self.palette.len() / 3
+    }
+
+    /// Looks every index up in the palette again, for encoders that build
+    /// their own palette from an image with few enough colors.
+    pub fn to_rgba(&self) -> RgbaImage {
+        let pixels = self
+            .indices
+            .iter()
+            .flat_map(|&index| {
+                let entry = usize::from(index);
+                let rgb = &self.palette[entry * 3..entry * 3 + 3];
+                [
+                    rgb[0],
+                    rgb[1],
+                    rgb[2],
+                    self.alpha.get(entry).copied().unwrap_or(u8::MAX),
+                ]
+            })
+            .collect();
+        RgbaImage::from_raw(self.width, self.height, pixels)
+            .expect("one index per pixel of the image")
+    }
+}
+
+/// Reduces `image` to at most `colors` (2-256) palette entries.
+pub fn quantize(image: &RgbaImage, colors: usize, dither: Dither) -> Result<IndexedImage> {
+    if !(2..=256).contains(&colors) {
+        bail!("palette size must be between 2 and 256 colors, got {colors}");
+    }
+    if dither == Dither::Ordered {
+        bail!("ordered dithering only applies to grayscale reduction");
+    }
+    let (width, height) = image.dimensions();
+    let (entries, indices, exact) = match exact_palette(image, colors) {
+        Some((entries, indices)) => (entries, indices, true),
+        None => {
+            let quantizer = NeuQuant::new(SAMPLE_FACTOR, colors, image.as_raw());
+            let entries = quantizer
+                .color_map_rgba()
+                .chunks_exact(4)
+                .map(|entry| [entry[0], entry[1], entry[2], entry[3]])
+                .collect();
+            let indices = match dither {
+                Dither::None => imageops::index_colors(image, &quantizer).into_raw(),
+                Dither::FloydSteinberg | Dither::Ordered => {
+                    let mut dithered = image.clone();
+                    imageops::dither(&mut dithered, &quantizer);
+                    imageops::index_colors(&dithered, &quantizer).into_raw()
+                }
+            };
+            (entries, indices, false)
+        }
+    };
+    Ok(pack(width, height, entries, indices, exact))
+}
+
+/// Reduces `image` to the `2^bits` evenly spaced gray levels of a 1, 2 or
+/// 4-bit sample (8 keeps every level), still stored one byte per pixel at
+/// full scale so any encoder can write it.
+pub fn reduce_gray(image: &GrayImage, bits: u8, dither: Dither) -> Result<GrayImage> {
+    if !matches!(bits, 1 | 2 | 4 | 8) {
+        bail!("gray bit depth must be 1, 2, 4 or 8, got {bits}");
+    }
+    let top = f32::from(u8::MAX >> (8 - bits));
+    let step = 255.0 / top;
+    let nearest = |value: f32| (value / step).round().clamp(0.0, top) * step;
+    let (width, height) = image.dimensions();
+    let mut reduced = GrayImage::new(width, height);
+    match dither {
+        Dither::None => {
+            for (out, pixel) in reduced.pixels_mut().zip(image.pixels()) {
+                *out = Luma([nearest(f32::from(pixel[0])) as u8]);
+            }
+        }
+        Dither::Ordered => {
+            for (x, y, out) in reduced.enumerate_pixels_mut() {
+                let threshold = (f32::from(BAYER[(y % 8) as usize][(x % 8) as usize]) + 0.5) / 64.0;
+                let value = f32::from(image.get_pixel(x, y)[0]) + (threshold - 0.5) * step;
+                *out = Luma([nearest(value) as u8]);
+            }
+        }
+        Dither::FloydSteinberg => {
+            let width = width as usize;
+            let mut values: Vec<f32> = image.as_raw().iter().copied().map(f32::from).collect();
+            for (index, out) in reduced.iter_mut().enumerate() {
+                let value = values[index];
+                let quantized = nearest(value);
+                *out = quantized as u8;
+                let error = value - quantized;
+                let x = index % width;
+                let mut spread = |offset: usize, weight: f32| {
+                    if let Some(value) = values.get_mut(offset) {
+                        *value += error * weight;
+                    }
+                };
+                if x + 1 < width {
+                    spread(index + 1, 7.0 / 16.0);
+                    spread(index + width + 1, 1.0 / 16.0);
+                }
+                if x > 0 {
+                    spread(index + width - 1, 3.0 / 16.0);
+                }
+                spread(index + width, 5.0 / 16.0);
+            }
+        }
+    }
+    Ok(reduced)
+}
+
+/// Rows of `image` (as produced by [`reduce_gray`]) packed `bits` per
+/// pixel, most significant bits first, each row padded to whole bytes as
+/// PNG stores them.
+pub fn pack_gray(image: &GrayImage, bits: u8) -> Vec<u8> {
+    if bits == 8 {
+        return image.as_raw().clone();
+    }
+    let top = u8::MAX >> (8 - bits);
+    let per_byte = usize::from(8 / bits);
+    let width = image.width() as usize;
+    let row_bytes = width.div_ceil(per_byte);
+    let mut packed = vec![0u8; row_bytes * image.height() as usize];
+    for (row, out) in image.rows().zip(packed.chunks_exact_mut(row_bytes)) {
+        for (x, pixel) in row.enumerate() {
+            let level = ((u16::from(pixel[0]) * u16::from(top) + 127) / 255) as u8;
+            let shift = 8 - bits * (x % per_byte + 1) as u8;
+            out[x / per_byte] |= level << shift;
+        }
+    }
+    packed
+}
+
+/// Bayer threshold matrix for ordered dithering, values 0-63.
+const BAYER: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// The distinct colors of `image` in first-seen order, or `None` when there
+/// are more than `limit`.
+fn exact_palette(image: &RgbaImage, limit: usize) -> Option<(Vec<[u8; 4]>, Vec<u8>)> {
+    let mut lookup: HashMap<[u8; 4], u8> = HashMap::new();
+    let mut entries = Vec::new();
+    let mut indices = Vec::with_capacity(image.as_raw().len() / 4);
+    for pixel in image.pixels() {
+        let index = match lookup.get(&pixel.0) {
+            Some(index) => *index,
+            None => {
+                if entries.len() == limit {
+                    return None;
+                }
+                let index = entries.len() as u8;
+                lookup.insert(pixel.0, index);
+                entries.push(pixel.0);
+                index
+            }
+        };
+        indices.push(index);
+    }
+    Some((entries, indices))
+}
+
+/// Moves translucent entries to the front so `tRNS` only has to list those.
+fn pack(
+    width: u32,
+    height: u32,
+    entries: Vec<[u8; 4]>,
+    mut indices: Vec<u8>,
+    exact: bool,
+) -> IndexedImage {
+    let mut order: Vec<usize> = (0..entries.len()).collect();
+    order.sort_by_key(|index| entries[*index][3] == u8::MAX);
+    let mut remap = vec![0u8; entries.len()];
+    for (new, old) in order.iter().enumerate() {
+        remap[*old] = new as u8;
+    }
+    for index in &mut indices {
+        *index = remap[*index as usize];
+    }
+    let palette = order
+        .iter()
+        .flat_map(|index| entries[*index][..3].to_vec())
+        .collect();
+    let alpha = order
+        .iter()
+        .map(|index| entries[*index][3])
+        .take_while(|alpha| *alpha < u8::MAX)
+        .collect();
+    IndexedImage {
+        width,
+        height,
+        palette,
+        alpha,
+        indices,
+        exact,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    #[test]
+    fn few_colors_stay_exact_and_gradients_are_reduced() {
+        let mut flat = RgbaImage::from_pixel(8, 8, Rgba([255, 255, 255, 255]));
+        flat.put_pixel(1, 1, Rgba([0, 0, 0, 0]));
+        flat.put_pixel(2, 2, Rgba([200, 10, 10, 255]));
+        let indexed = quantize(&flat, 16, Dither::FloydSteinberg).unwrap();
+        assert!(indexed.exact);
+        assert_eq!(indexed.colors(), 3);
+        assert_eq!(indexed.alpha, vec![0]);
+        assert_eq!(indexed.indices[9], 0);
+        assert_eq!(&indexed.palette[..3], &[0, 0, 0]);
+        assert_eq!(indexed.to_rgba(), flat);
+
+        let gradient = RgbaImage::from_fn(64, 64, |x, y| {
+            Rgba([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8, 255])
+        });
+        for dither in [Dither::None, Dither::FloydSteinberg] {
+            let indexed = quantize(&gradient, 32, dither).unwrap();
+            assert!(!indexed.exact);
+            assert_eq!(indexed.colors(), 32);
+            assert!(indexed.alpha.is_empty());
+            assert_eq!(indexed.indices.len(), 64 * 64);
+            assert!(indexed.indices.iter().all(|index| *index < 32));
+        }
+
+        assert!(quantize(&flat, 1, Dither::None).is_err());
+        assert!(quantize(&flat, 300, Dither::None).is_err());
+        assert_eq!(
+            Dither::from_name("Floyd-Steinberg"),
+            Some(Dither::FloydSteinberg)
+        );
+    }
+
+    #[test]
+    fn gray_reduction_keeps_levels_and_average_tone() {
+        let ramp = GrayImage::from_fn(64, 16, |x, _| Luma([(x * 4) as u8]));
+        let mean = |image: &GrayImage| {
+            image.pixels().map(|pixel| f64::from(pixel[0])).sum::<f64>() / image.len() as f64
+        };
+        for dither in [Dither::None, Dither::FloydSteinberg, Dither::Ordered] {
+            let one = reduce_gray(&ramp, 1, dither).unwrap();
+            assert!(
+                one.pixels().all(|pixel| matches!(pixel[0], 0 | 255)),
+                "{dither:?}"
+            );
+            let two = reduce_gray(&ramp, 2, dither).unwrap();
+            assert!(two.pixels().all(|pixel| pixel[0] % 85 == 0), "{dither:?}");
+            if dither != Dither::None {
+                assert!((mean(&one) - mean(&ramp)).abs() < 8.0, "{dither:?}");
+            }
+        }
+        assert!(reduce_gray(&ramp, 3, Dither::None).is_err());
+
+        let mut row = GrayImage::new(10, 1);
+        row.put_pixel(0, 0, Luma([255]));
+        row.put_pixel(9, 0, Luma([255]));
+        assert_eq!(pack_gray(&row, 1), vec![0b1000_0000, 0b0100_0000]);
+        row.put_pixel(1, 0, Luma([170]));
+        assert_eq!(pack_gray(&row, 2)[0], 0b1110_0000);
+        assert!(quantize(&RgbaImage::new(2, 2), 4, Dither::Ordered).is_err());
+    }
+}
@@ -1,10 +1,22 @@
 use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result};
-use glob::glob;
+use anyhow::{Context, Result, bail};
+use glob::Pattern;
 use serde::{Deserialize, Serialize};
 
-use crate::pipeline::{OutputSpec, StageSpec};
+use crate::archive::{self, ArchiveKind};
+use crate::discovery::discover;
+use crate::notify::NotifySpec;
+use crate::overwrite::OverwritePolicy;
+use crate::pipeline::{OutputSpec, StageParameters, StageSpec};
+
+/// Metadata key holding the name of the variant an output belongs to, usable
+/// as `{variant}` in output structures.
+pub const VARIANT_KEY: &str = "variant";
+
+/// Id given to the last `pipeline` stage, where variants branch off, when it
+/// has none.
+const VARIANTS_BRANCH_ID: &str = "variants";
 
 #[derive(Debug, Deserialize)]
 pub struct Recipe {
@@ -14,28 +26,131 @@ pub struct Recipe {
     pub output: OutputSpec,
     #[serde(default)]
     pub quality_gates: Vec<QualityGateSpec>,
+    /// Webhook POSTed when a run of this recipe finishes.
+    #[serde(default)]
+    pub notify: Option<NotifySpec>,
+    /// Whether stages replace output files that already exist; `run
+    /// --overwrite` takes precedence.
+    #[serde(default)]
+    pub overwrite: Option<OverwritePolicy>,
+}
+
+/// The recipe file as written, before `variants` are folded into the
+/// pipeline.
+#[derive(Deserialize)]
+struct RecipeFile {
+    #[serde(flatten)]
+    recipe: Recipe,
+    #[serde(default)]
+    variants: Vec<VariantSpec>,
+}
+
+/// One of several outputs produced from the same run of `pipeline`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct VariantSpec {
+    pub name: String,
+    /// Stages run on a copy of the artifact `pipeline` produced.
+    pub pipeline: Vec<StageSpec>,
+    /// Overrides the recipe's output directory for this variant.
+    #[serde(default)]
+    pub directory: Option<PathBuf>,
+    /// Overrides the recipe's output structure for this variant.
+    #[serde(default)]
+    pub structure: Option<String>,
 }
 
 impl Recipe {
     pub fn load(path: &Path) -> Result<Self> {
         let content = std::fs::read_to_string(path)
             .with_context(|| format!("Failed to read recipe file: {}", path.display()))?;
-        let recipe: Recipe = serde_yaml::from_str(&content)
+        let file: RecipeFile = serde_yaml::from_str(&content)
             .with_context(|| format!("Failed to parse recipe YAML: {}", path.display()))?;
-        Ok(recipe)
+        file.recipe
+            .with_variants(file.variants)
+            .with_context(|| format!("Invalid variants in recipe: {}", path.display()))
+    }
+
+    /// Appends each variant to the pipeline as a branch off its last stage
+    /// (see [`crate::graph`]). A branch starts with an `annotate` stage that
+    /// records the variant name under [`VARIANT_KEY`] and carries the
+    /// variant's output layout.
+    pub fn with_variants(mut self, variants: Vec<VariantSpec>) -> Result<Self> {
+        if variants.is_empty() {
+            return Ok(self);
+        }
+        let Some(last) = self.pipeline.last_mut() else {
+            bail!("Variants branch off the end of `pipeline`, which has no stages");
+        };
+        let branch_id = last
+            .id
+            .get_or_insert_with(|| VARIANTS_BRANCH_ID.to_string())
+            .clone();
+        if self.pipeline[..self.pipeline.len() - 1]
+            .iter()
+            .any(|stage| stage.id.as_deref() == Some(branch_id.as_str()))
+        {
+            bail!(
+                "Stage id '{branch_id}' is reserved for the last pipeline stage when using variants"
+            );
+        }
+        let mut names = Vec::with_capacity(variants.len());
+        for variant in variants {
+            if names.contains(&variant.name) {
+                bail!("Duplicate variant name '{}'", variant.name);
+            }
+            if variant.pipeline.is_empty() {
+                bail!("Variant '{}' has no stages", variant.name);
+            }
+            let mut params = StageParameters::new();
+            params.insert("key".into(), VARIANT_KEY.into());
+            params.insert("value".into(), variant.name.clone().into());
+            self.pipeline.push(StageSpec {
+                stage: "annotate".to_string(),
+                params: Some(params),
+                after: Some(branch_id.clone()),
+                output: Some(OutputSpec {
+                    directory: variant
+                        .directory
+                        .unwrap_or_else(|| self.output.directory.clone()),
+                    structure: variant
+                        .structure
+                        .unwrap_or_else(|| self.output.structure.clone()),
+                }),
+                ..StageSpec::default()
+            });
+            self.pipeline.extend(variant.pipeline);
+            names.push(variant.name);
+        }
+        Ok(self)
     }
 
+    /// Resolves every input glob to files (honouring `.bunkerignore`),
+    /// replacing ZIP/TAR archives with their (optionally `members`-filtered)
+    /// entries.
     pub fn expand_inputs(&self) -> Result<Vec<PathBuf>> {
         let mut resolved = Vec::new();
         for input in &self.inputs {
-            let matches = glob(&input.path)
-                .with_context(|| format!("Invalid glob pattern: {}", input.path))?;
+            let matches = discover(&input.path, input.include_hidden)?;
+            let members = input
+                .members
+                .as_deref()
+                .map(|pattern| {
+                    Pattern::new(pattern)
+                        .with_context(|| format!("Invalid members pattern: {pattern}"))
+                })
+                .transpose()?;
             let mut found = false;
-            for entry in matches {
-                let path = entry?;
-                if path.is_file() {
-                    resolved.push(path);
-                    found = true;
+            for path in matches {
+                match ArchiveKind::from_path(&path) {
+                    Some(kind) => {
+                        let entries = archive::expand(&path, kind, members.as_ref())?;
+                        found |= !entries.is_empty();
+                        resolved.extend(entries);
+                    }
+                    None => {
+                        resolved.push(path);
+                        found = true;
+                    }
                 }
             }
             if !found {
@@ -49,6 +164,12 @@ impl Recipe {
 #[derive(Debug, Deserialize)]
 pub struct InputSpec {
     pub path: String,
+    /// Glob applied to member names when `path` matches ZIP/TAR archives.
+    #[serde(default)]
+    pub members: Option<String>,
+    /// Also match hidden files and descend into hidden directories.
+    #[serde(default)]
+    pub include_hidden: bool,
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
@@ -61,4 +182,15 @@ pub struct QualityGateSpec {
     pub min_psnr: Option<f64>,
     #[serde(default)]
     pub max_mse: Option<f64>,
+    /// Video outputs: the lowest PSNR any frame may have against its source
+    /// frame. The still-image thresholds above do not apply to video.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_frame_psnr: Option<f64>,
+    /// Video outputs: the lowest PSNR averaged over the frames.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_mean_psnr: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_frame_ssim: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_mean_ssim: Option<f64>,
 }
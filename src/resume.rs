@@ -0,0 +1,231 @@
+//! Skipping inputs an earlier run already converted.
+//!
+//! With `run --resume`, every completed input is appended to a ledger
+//! (`.bunker-resume.jsonl` in the output directory) holding its output path,
+//! result metadata and a fingerprint of the pipeline that produced it. A later
+//! run with the same pipeline skips inputs whose recorded output still exists;
+//! `--resume hash` also requires the input and output SHA256 to match, so
+//! edited inputs and tampered outputs are converted again. Because entries
+//! are written as inputs finish, an interrupted run picks up where it stopped.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::archive::ArchiveMember;
+use crate::pipeline::{OutputSpec, PipelineResult, StageSpec};
+use crate::recipe::QualityGateSpec;
+use crate::security::compute_sha256;
+
+pub const LEDGER_FILE: &str = ".bunker-resume.jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ResumeMode {
+    /// Skip inputs whose recorded output still exists.
+    Exists,
+    /// Also require the input and output digests to match the ledger.
+    Hash,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LedgerEntry {
+    input: PathBuf,
+    output: PathBuf,
+    fingerprint: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    input_sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    output_sha256: Option<String>,
+    metadata: Map<String, Value>,
+}
+
+pub struct ResumeLedger {
+    path: PathBuf,
+    mode: ResumeMode,
+    fingerprint: String,
+    entries: HashMap<PathBuf, LedgerEntry>,
+    writer: Mutex<File>,
+}
+
+impl ResumeLedger {
+    /// Loads the ledger at `path` (later entries for an input win) and opens
+    /// it for appending.
+    pub fn open(path: &Path, mode: ResumeMode, fingerprint: String) -> Result<Self> {
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            std::fs::create_dir_all(parent).with_context(|| {
+                format!(
+                    "Failed to create resume ledger directory: {}",
+                    parent.display()
+                )
+            })?;
+        }
+        let mut entries = HashMap::new();
+        if path.exists() {
+            let file = File::open(path)
+                .with_context(|| format!("Failed to read resume ledger: {}", path.display()))?;
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                // A run killed mid-write can leave a torn last line; the
+                // input it described is simply converted again.
+                if let Ok(entry) = serde_json::from_str::<LedgerEntry>(&line) {
+                    entries.insert(entry.input.clone(), entry);
+                }
+            }
+        }
+        let writer = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open resume ledger: {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            mode,
+            fingerprint,
+            entries,
+            writer: Mutex::new(writer),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The earlier result for `input`, if it can stand in for converting the
+    /// input again.
+    pub fn completed(&self, input: &Path) -> Result<Option<PipelineResult>> {
+        let Some(entry) = self.entries.get(input) else {
+            return Ok(None);
+        };
+        if entry.fingerprint != self.fingerprint || !entry.output.is_file() {
+            return Ok(None);
+        }
+        if self.mode == ResumeMode::Hash {
+            let (Some(input_sha), Some(output_sha)) = (&entry.input_sha256, &entry.output_sha256)
+            else {
+                return Ok(None);
+            };
+            if *input_sha != input_digest(input)? || *output_sha != compute_sha256(&entry.output)? {
+                return Ok(None);
+            }
+        }
+        let mut metadata = entry.metadata.clone();
+        metadata.insert("resume.skipped".to_string(), Value::Bool(true));
+        Ok(Some(PipelineResult {
+            input: entry.input.clone(),
+            output: entry.output.clone(),
+            metadata,
+            duration: Duration::ZERO,
+        }))
+    }
+
+    pub fn record(&self, result: &PipelineResult) -> Result<()> {
+        let (input_sha256, output_sha256) = match self.mode {
+            ResumeMode::Hash => (
+                Some(input_digest(&result.input)?),
+                Some(compute_sha256(&result.output)?),
+            ),
+            ResumeMode::Exists => (None, None),
+        };
+        let entry = LedgerEntry {
+            input: result.input.clone(),
+            output: result.output.clone(),
+            fingerprint: self.fingerprint.clone(),
+            input_sha256,
+            output_sha256,
+            metadata: result.metadata.clone(),
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        self.writer
+            .lock()
+            .map_err(|_| anyhow!("resume ledger writer poisoned"))?
+            .write_all(&line)
+            .with_context(|| format!("Failed to append to resume ledger: {}", self.path.display()))
+    }
+}
+
+/// Digest of everything that shapes an output: the stages and their
+/// parameters, the output layout and the quality gates.
+pub fn fingerprint(
+    stages: &[StageSpec],
+    output: &OutputSpec,
+    quality_gates: &[QualityGateSpec],
+) -> Result<String> {
+    let mut hasher = Sha256::new();
+    for stage in stages {
+        hasher.update(stage.stage.as_bytes());
+        hasher.update(serde_json::to_vec(&stage.params)?);
+        if let Some(when) = &stage.when {
+            hasher.update(when.as_bytes());
+        }
+    }
+    hasher.update(output.directory.to_string_lossy().as_bytes());
+    hasher.update(output.structure.as_bytes());
+    hasher.update(serde_json::to_vec(quality_gates)?);
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn input_digest(input: &Path) -> Result<String> {
+    match ArchiveMember::from_path(input) {
+        Some(member) => Ok(format!("{:x}", Sha256::digest(member.read()?))),
+        None => compute_sha256(input),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(input: &Path, output: &Path) -> PipelineResult {
+        PipelineResult {
+            input: input.to_path_buf(),
+            output: output.to_path_buf(),
+            metadata: Map::new(),
+            duration: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn skips_only_matching_completed_inputs() {
+        let temp = tempfile::tempdir().unwrap();
+        let input = temp.path().join("in.png");
+        let output = temp.path().join("out.webp");
+        std::fs::write(&input, b"input").unwrap();
+        std::fs::write(&output, b"output").unwrap();
+        let ledger_path = temp.path().join(LEDGER_FILE);
+
+        let ledger = ResumeLedger::open(&ledger_path, ResumeMode::Hash, "v1".into()).unwrap();
+        assert!(ledger.completed(&input).unwrap().is_none());
+        ledger.record(&result(&input, &output)).unwrap();
+        drop(ledger);
+
+        let ledger = ResumeLedger::open(&ledger_path, ResumeMode::Hash, "v1".into()).unwrap();
+        let skipped = ledger.completed(&input).unwrap().unwrap();
+        assert_eq!(skipped.output, output);
+        assert_eq!(skipped.metadata["resume.skipped"], Value::Bool(true));
+
+        // A changed pipeline invalidates every entry.
+        let changed = ResumeLedger::open(&ledger_path, ResumeMode::Hash, "v2".into()).unwrap();
+        assert!(changed.completed(&input).unwrap().is_none());
+
+        // Editing the input only matters when hashes are checked.
+        std::fs::write(&input, b"edited").unwrap();
+        assert!(ledger.completed(&input).unwrap().is_none());
+        let exists = ResumeLedger::open(&ledger_path, ResumeMode::Exists, "v1".into()).unwrap();
+        assert!(exists.completed(&input).unwrap().is_some());
+
+        std::fs::remove_file(&output).unwrap();
+        assert!(exists.completed(&input).unwrap().is_none());
+    }
+}
@@ -0,0 +1,103 @@
+//! Per-stage retry policies.
+//!
+//! A stage with a `retry` block is run again when it fails, after an
+//! exponentially growing pause, for stages that touch network-backed storage
+//! or flaky hardware encoders. Every attempt starts from the artifact as it
+//! was before the first one, so a half-applied failure never leaks into the
+//! next try.
+
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Pause before the first retry.
+    #[serde(default = "default_backoff_ms")]
+    pub backoff_ms: u64,
+    /// Factor the pause grows by after each retry.
+    #[serde(default = "default_multiplier")]
+    pub multiplier: f64,
+    #[serde(default)]
+    pub max_backoff_ms: Option<u64>,
+}
+
+fn default_max_attempts() -> u32 {
+    3
+}
+
+fn default_backoff_ms() -> u64 {
+    200
+}
+
+fn default_multiplier() -> f64 {
+    2.0
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            backoff_ms: default_backoff_ms(),
+            multiplier: default_multiplier(),
+            max_backoff_ms: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_attempts == 0 {
+            bail!("retry max_attempts must be at least 1");
+        }
+        if !self.multiplier.is_finite() || self.multiplier < 1.0 {
+            bail!(
+                "retry multiplier must be at least 1, got {}",
+                self.multiplier
+            );
+        }
+        Ok(())
+    }
+
+    /// Pause before retry number `retry` (1 for the first retry).
+    pub fn delay(&self, retry: u32) -> Duration {
+        let exponent = retry.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let millis = self.backoff_ms as f64 * self.multiplier.powi(exponent);
+        let capped = match self.max_backoff_ms {
+            Some(max) => millis.min(max as f64),
+            None => millis,
+        };
+        Duration::from_millis(capped.min(u64::MAX as f64) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_until_capped() {
+        let policy = RetryPolicy {
+            backoff_ms: 100,
+            max_backoff_ms: Some(300),
+            ..RetryPolicy::default()
+        };
+        let delays: Vec<_> = (1..=4)
+            .map(|retry| policy.delay(retry).as_millis())
+            .collect();
+        assert_eq!(delays, vec![100, 200, 300, 300]);
+        assert!(policy.validate().is_ok());
+        assert!(
+            RetryPolicy {
+                max_attempts: 0,
+                ..policy
+            }
+            .validate()
+            .is_err()
+        );
+    }
+}
@@ -1,7 +1,7 @@
 use clap::ValueEnum;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Deserialize, ValueEnum, Default)]
+#[derive(Debug, Clone, Deserialize, Serialize, ValueEnum, Default)]
 #[serde(rename_all = "kebab-case")]
 pub enum DevicePolicy {
     #[default]
@@ -10,7 +10,8 @@ pub enum DevicePolicy {
     GpuPreferred,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum StageDevice {
     Cpu,
     Gpu,
@@ -20,6 +21,8 @@ pub enum StageDevice {
 pub struct TaskScheduler {
     policy: DevicePolicy,
     gpu_available: bool,
+    encode_workers: Option<usize>,
+    jobs: Option<usize>,
 }
 
 impl TaskScheduler {
@@ -28,9 +31,43 @@ impl TaskScheduler {
         Self {
             policy,
             gpu_available,
+            encode_workers: None,
+            jobs: None,
         }
     }
 
+    /// Routes encode stages to a dedicated pool of `workers` threads so slow
+    /// encodes overlap with decoding of the following inputs.
+    pub fn with_encode_workers(mut self, workers: Option<usize>) -> Self {
+        self.encode_workers = workers.filter(|&count| count > 0);
+        self
+    }
+
+    pub fn encode_workers(&self) -> Option<usize> {
+        self.encode_workers
+    }
+
+    /// Runs up to `jobs` inputs at once, each through the whole pipeline on
+    /// its own thread; `0` means one per available core. A single job keeps
+    /// the sequential path, and parallel jobs supersede the encode pool.
+    pub fn with_jobs(mut self, jobs: Option<usize>) -> Self {
+        self.jobs = jobs
+            .map(|count| match count {
+                0 => std::thread::available_parallelism().map_or(1, |cores| cores.get()),
+                count => count,
+            })
+            .filter(|&count| count > 1);
+        self
+    }
+
+    pub fn jobs(&self) -> Option<usize> {
+        self.jobs
+    }
+
+    pub fn routes_to_encode_pool(&self, stage_name: &str) -> bool {
+        self.jobs.is_none() && self.encode_workers.is_some() && is_encode_stage(stage_name)
+    }
+
     pub fn select_device(&self, _stage_name: &str) -> StageDevice {
         match self.policy {
             DevicePolicy::CpuOnly => StageDevice::Cpu,
@@ -56,6 +93,10 @@ impl TaskScheduler {
     }
 }
 
+fn is_encode_stage(stage_name: &str) -> bool {
+    matches!(stage_name, "encode" | "video_encode")
+}
+
 fn detect_gpu() -> bool {
     // Placeholder heuristic; in real implementation this would query CUDA/Metal/Vulkan.
     std::env::var("BUNKER_FORCE_GPU")
@@ -0,0 +1,136 @@
+//! Destinations for encoded output.
+//!
+//! Encoders write through an [`OutputSink`] rather than returning a finished
+//! buffer, which lets large outputs go straight to disk while the sink keeps a
+//! running byte count and SHA256 digest of everything written.
+//!
+//! [`FileSink`] writes to a hidden `.part` file next to the destination and
+//! only renames it into place in [`OutputSink::finish`]. A sink dropped
+//! before then (a failed or cancelled stage) removes its partial file, so an
+//! interrupted run never leaves a truncated output behind.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Clone)]
+pub struct SinkSummary {
+    pub path: PathBuf,
+    pub size_bytes: u64,
+    pub sha256: String,
+}
+
+pub trait OutputSink: Write {
+    /// Flushes any buffered bytes and reports what was written.
+    fn finish(self) -> Result<SinkSummary>
+    where
+        Self: Sized;
+}
+
+pub struct FileSink {
+    path: PathBuf,
+    partial: PathBuf,
+    persisted: bool,
+    writer: BufWriter<File>,
+    hasher: Sha256,
+    written: u64,
+}
+
+impl FileSink {
+    pub fn create(path: &Path) -> Result<Self> {
+        let partial = partial_path(path);
+        let file = File::create(&partial)
+            .with_context(|| format!("Failed to write output file: {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            partial,
+            persisted: false,
+            writer: BufWriter::new(file),
+            hasher: Sha256::new(),
+            written: 0,
+        })
+    }
+}
+
+impl Write for FileSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl OutputSink for FileSink {
+    fn finish(mut self) -> Result<SinkSummary> {
+        self.writer
+            .flush()
+            .with_context(|| format!("Failed to write output file: {}", self.path.display()))?;
+        std::fs::rename(&self.partial, &self.path)
+            .with_context(|| format!("Failed to write output file: {}", self.path.display()))?;
+        self.persisted = true;
+        Ok(SinkSummary {
+            path: std::mem::take(&mut self.path),
+            size_bytes: self.written,
+            sha256: format!("{:x}", std::mem::take(&mut self.hasher).finalize()),
+        })
+    }
+}
+
+impl Drop for FileSink {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = std::fs::remove_file(&self.partial);
+        }
+    }
+}
+
+/// Hidden sibling of `path` that output is staged in until complete.
+fn partial_path(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".{}.part", std::process::id()));
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn file_sink_tracks_size_and_digest() {
+        let temp = tempdir().unwrap();
+        let path = temp.path().join("out.bin");
+        let mut sink = FileSink::create(&path).unwrap();
+        sink.write_all(b"bun").unwrap();
+        sink.write_all(b"ker").unwrap();
+        let summary = sink.finish().unwrap();
+
+        assert_eq!(summary.size_bytes, 6);
+        assert_eq!(
+            summary.sha256,
+            "9078e43e365a0d2849587c33e1623ccdbd92ad1ea81c5762414e9fbee6f20c03"
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), b"bunker");
+    }
+
+    #[test]
+    fn unfinished_file_sink_leaves_no_output() {
+        let temp = tempdir().unwrap();
+        let path = temp.path().join("out.bin");
+        let mut sink = FileSink::create(&path).unwrap();
+        sink.write_all(b"bun").unwrap();
+        drop(sink);
+
+        assert!(!path.exists());
+        assert_eq!(std::fs::read_dir(temp.path()).unwrap().count(), 0);
+    }
+}
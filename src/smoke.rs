@@ -0,0 +1,328 @@
+//! Recipe smoke tests for `bunker-convert recipe test`.
+//!
+//! The recipe's pipeline runs over a handful of small generated fixtures (or
+//! every file in a user-supplied directory) with outputs redirected to a
+//! scratch directory. Each fixture reports which stages ran, whether its
+//! quality gates held and whether the output was actually written, so recipe
+//! authors get a CI check without maintaining their own harness.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use image::{DynamicImage, ImageBuffer, Luma, Rgb, Rgba};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::discovery::discover;
+use crate::pipeline::{OutputSpec, StageRegistry, build_pipeline};
+use crate::recipe::Recipe;
+use crate::scheduler::DevicePolicy;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Passed,
+    Failed,
+    /// A stage after the one that failed.
+    NotRun,
+    /// Quality gates were configured but could not be measured.
+    Skipped,
+    /// The recipe has no quality gates.
+    NotConfigured,
+}
+
+impl CheckStatus {
+    pub fn label(self) -> &'static str {
+        match self {
+            CheckStatus::Passed => "pass",
+            CheckStatus::Failed => "FAIL",
+            CheckStatus::NotRun => "not run",
+            CheckStatus::Skipped => "skipped",
+            CheckStatus::NotConfigured => "n/a",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StageCheck {
+    pub stage: String,
+    pub status: CheckStatus,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FixtureReport {
+    pub fixture: PathBuf,
+    pub stages: Vec<StageCheck>,
+    pub quality: CheckStatus,
+    pub ssim: Option<f64>,
+    pub output: Option<PathBuf>,
+    pub output_exists: bool,
+    pub error: Option<String>,
+}
+
+impl FixtureReport {
+    pub fn passed(&self) -> bool {
+        self.error.is_none() && self.output_exists && self.quality != CheckStatus::Failed
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SmokeReport {
+    pub recipe: PathBuf,
+    pub fixtures: Vec<FixtureReport>,
+}
+
+impl SmokeReport {
+    pub fn failures(&self) -> usize {
+        self.fixtures
+            .iter()
+            .filter(|report| !report.passed())
+            .count()
+    }
+}
+
+/// Writes the built-in fixtures (an RGB gradient PNG, a translucent RGBA
+/// PNG, a grayscale PNG and a JPEG photo stand-in) into `dir`.
+pub fn write_builtin_fixtures(dir: &Path) -> Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create fixture directory: {}", dir.display()))?;
+    let gradient = ImageBuffer::from_fn(64, 48, |x, y| {
+        Rgb([(x * 4) as u8, (y * 5) as u8, ((x + y) * 2) as u8])
+    });
+    let alpha = ImageBuffer::from_fn(48, 48, |x, y| {
+        Rgba([200, (x * 5) as u8, (y * 5) as u8, ((x + y) * 2 + 40) as u8])
+    });
+    let gray = ImageBuffer::from_fn(40, 40, |x, y| Luma([((x * y) % 256) as u8]));
+    let photo = ImageBuffer::from_fn(80, 60, |x, y| {
+        let wave = ((x as f32 / 6.0).sin() * (y as f32 / 5.0).cos() * 60.0) as i32;
+        Rgb([
+            (120 + wave).clamp(0, 255) as u8,
+            (x * 3) as u8,
+            (200 - y as i32 * 2).clamp(0, 255) as u8,
+        ])
+    });
+    let fixtures = [
+        ("gradient.png", DynamicImage::ImageRgb8(gradient)),
+        ("alpha.png", DynamicImage::ImageRgba8(alpha)),
+        ("gray.png", DynamicImage::ImageLuma8(gray)),
+        ("photo.jpg", DynamicImage::ImageRgb8(photo)),
+    ];
+    let mut paths = Vec::with_capacity(fixtures.len());
+    for (name, image) in fixtures {
+        let path = dir.join(name);
+        image
+            .save(&path)
+            .with_context(|| format!("Failed to write fixture: {}", path.display()))?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// Every non-hidden file under `dir`, honouring `.bunkerignore`.
+pub fn collect_fixtures(dir: &Path) -> Result<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        anyhow::bail!("Fixtures directory not found: {}", dir.display());
+    }
+    let fixtures = discover(&format!("{}/**/*", dir.display()), false)?;
+    if fixtures.is_empty() {
+        anyhow::bail!("No fixtures found in {}", dir.display());
+    }
+    Ok(fixtures)
+}
+
+/// Runs `recipe` over each fixture separately, writing outputs below
+/// `output_dir` instead of the recipe's own output directory.
+pub fn run_smoke_test(
+    registry: &StageRegistry,
+    recipe: &Recipe,
+    recipe_path: &Path,
+    fixtures: &[PathBuf],
+    output_dir: &Path,
+    device_policy: DevicePolicy,
+) -> Result<SmokeReport> {
+    let output = OutputSpec {
+        directory: output_dir.to_path_buf(),
+        structure: recipe.output.structure.clone(),
+    };
+    let executor = build_pipeline(
+        registry,
+        &recipe.pipeline,
+        output,
+        recipe.quality_gates.clone(),
+        device_policy,
+    )?;
+    let reports = fixtures
+        .iter()
+        .map(|fixture| {
+            let mut completed = 0;
+            let result = executor
+                .execute_with_progress(std::slice::from_ref(fixture), |progress| {
+                    completed = progress.stage_index
+                })
+                .map(|mut results| results.remove(0));
+            let stages = recipe
+                .pipeline
+                .iter()
+                .enumerate()
+                .map(|(index, spec)| StageCheck {
+                    stage: spec.stage.clone(),
+                    status: if index < completed {
+                        CheckStatus::Passed
+                    } else if index == completed && result.is_err() {
+                        CheckStatus::Failed
+                    } else {
+                        CheckStatus::NotRun
+                    },
+                })
+                .collect();
+            match result {
+                Ok(result) => {
+                    let quality = match result
+                        .metadata
+                        .get("quality.status")
+                        .and_then(Value::as_str)
+                    {
+                        Some("passed") => CheckStatus::Passed,
+                        Some(_) => CheckStatus::Skipped,
+                        None => CheckStatus::NotConfigured,
+                    };
+                    FixtureReport {
+                        fixture: fixture.clone(),
+                        stages,
+                        quality,
+                        ssim: result.metadata.get("quality.ssim").and_then(Value::as_f64),
+                        output_exists: result.output.is_file(),
+                        output: Some(result.output),
+                        error: None,
+                    }
+                }
+                Err(err) => {
+                    // Every stage ran, so the quality gates (or finalizing)
+                    // rejected the output.
+                    let quality =
+                        if completed == recipe.pipeline.len() && !recipe.quality_gates.is_empty() {
+                            CheckStatus::Failed
+                        } else {
+                            CheckStatus::NotRun
+                        };
+                    FixtureReport {
+                        fixture: fixture.clone(),
+                        stages,
+                        quality,
+                        ssim: None,
+                        output: None,
+                        output_exists: false,
+                        error: Some(format!("{err:#}")),
+                    }
+                }
+            }
+        })
+        .collect();
+    Ok(SmokeReport {
+        recipe: recipe_path.to_path_buf(),
+        fixtures: reports,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::StageSpec;
+    use crate::recipe::{InputSpec, QualityGateSpec};
+    use crate::stages;
+
+    fn recipe(pipeline: &[(&str, Value)], quality_gates: Vec<QualityGateSpec>) -> Recipe {
+        Recipe {
+            version: 1,
+            inputs: vec![InputSpec {
+                path: "*.png".into(),
+                members: None,
+                include_hidden: false,
+            }],
+            pipeline: pipeline
+                .iter()
+                .map(|(stage, params)| StageSpec {
+                    stage: stage.to_string(),
+                    params: params.as_object().cloned(),
+                    ..StageSpec::default()
+                })
+                .collect(),
+            output: OutputSpec {
+                directory: PathBuf::from("unused"),
+                structure: "{stem}.{ext}".into(),
+            },
+            quality_gates,
+            notify: None,
+            overwrite: None,
+        }
+    }
+
+    #[test]
+    fn reports_stages_quality_and_outputs() {
+        let temp = tempfile::tempdir().unwrap();
+        let fixtures = write_builtin_fixtures(&temp.path().join("fixtures")).unwrap();
+        let mut registry = StageRegistry::new();
+        stages::register_defaults(&mut registry);
+        let gates = vec![QualityGateSpec {
+            min_ssim: Some(0.5),
+            ..QualityGateSpec::default()
+        }];
+        let recipe = recipe(
+            &[
+                ("decode", Value::Null),
+                ("encode", serde_json::json!({"format": "png"})),
+            ],
+            gates,
+        );
+        let report = run_smoke_test(
+            &registry,
+            &recipe,
+            Path::new("recipe.yaml"),
+            &fixtures,
+            &temp.path().join("out"),
+            DevicePolicy::Auto,
+        )
+        .unwrap();
+        assert_eq!(report.fixtures.len(), 4);
+        assert_eq!(report.failures(), 0);
+        let first = &report.fixtures[0];
+        assert!(first.output_exists);
+        assert_eq!(first.quality, CheckStatus::Passed);
+        assert!(
+            first
+                .stages
+                .iter()
+                .all(|check| check.status == CheckStatus::Passed)
+        );
+    }
+
+    #[test]
+    fn pinpoints_the_failing_stage() {
+        let temp = tempfile::tempdir().unwrap();
+        let fixtures = write_builtin_fixtures(&temp.path().join("fixtures")).unwrap();
+        let mut registry = StageRegistry::new();
+        stages::register_defaults(&mut registry);
+        // Encoding before decoding leaves nothing to encode.
+        let recipe = recipe(
+            &[
+                ("encode", serde_json::json!({"format": "png"})),
+                ("decode", Value::Null),
+            ],
+            Vec::new(),
+        );
+        let report = run_smoke_test(
+            &registry,
+            &recipe,
+            Path::new("recipe.yaml"),
+            &fixtures[..1],
+            &temp.path().join("out"),
+            DevicePolicy::Auto,
+        )
+        .unwrap();
+        assert_eq!(report.failures(), 1);
+        let stages = &report.fixtures[0].stages;
+        assert_eq!(stages[0].status, CheckStatus::Failed);
+        assert_eq!(stages[1].status, CheckStatus::NotRun);
+        assert!(report.fixtures[0].error.is_some());
+    }
+}
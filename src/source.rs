@@ -0,0 +1,119 @@
+//! Backing storage for an artifact's raw bytes.
+//!
+//! Small inputs are read into a pooled buffer. Inputs of at least
+//! [`MAP_THRESHOLD_BYTES`] are memory-mapped instead, so a multi-GB video is
+//! paged in only where the demuxer or decoder actually looks (an MP4 `moov`
+//! atom, say) and the kernel can drop pages again under pressure, rather than
+//! the whole file being copied onto the heap up front.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+use std::ops::Deref;
+
+use memmap2::Mmap;
+
+use crate::buffers;
+
+pub const MAP_THRESHOLD_BYTES: u64 = 64 * 1024 * 1024;
+
+pub enum ArtifactData {
+    Owned(Vec<u8>),
+    Mapped(Mmap),
+}
+
+impl ArtifactData {
+    /// Reads `file` into memory, or maps it when it holds at least
+    /// `map_threshold` bytes.
+    pub fn read(mut file: File, map_threshold: u64) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        if len > 0 && len >= map_threshold {
+            // SAFETY: the mapping is read-only. Inputs are not expected to be
+            // modified while a run converts them; truncating one mid-run can
+            // fault the process just as it would corrupt a buffered read.
+            let map = unsafe { Mmap::map(&file)? };
+            return Ok(Self::Mapped(map));
+        }
+        let mut data = buffers::take(len as usize);
+        file.read_to_end(&mut data)?;
+        Ok(Self::Owned(data))
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        matches!(self, Self::Mapped(_))
+    }
+
+    /// A seekable reader over the bytes, for parsers that consume them
+    /// incrementally.
+    pub fn reader(&self) -> Cursor<&[u8]> {
+        Cursor::new(self)
+    }
+
+    /// The bytes as an owned buffer, copying them out of a mapping.
+    pub fn into_vec(self) -> Vec<u8> {
+        match self {
+            Self::Owned(data) => data,
+            Self::Mapped(map) => map.to_vec(),
+        }
+    }
+}
+
+impl Deref for ArtifactData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Owned(data) => data,
+            Self::Mapped(map) => map,
+        }
+    }
+}
+
+impl AsRef<[u8]> for ArtifactData {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Default for ArtifactData {
+    fn default() -> Self {
+        Self::Owned(Vec::new())
+    }
+}
+
+impl From<Vec<u8>> for ArtifactData {
+    fn from(data: Vec<u8>) -> Self {
+        Self::Owned(data)
+    }
+}
+
+impl fmt::Debug for ArtifactData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = if self.is_mapped() { "Mapped" } else { "Owned" };
+        f.debug_struct(kind).field("len", &self.len()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_inputs_at_the_threshold() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("clip.mp4");
+        std::fs::write(&path, b"0123456789").unwrap();
+
+        let owned = ArtifactData::read(File::open(&path).unwrap(), 11).unwrap();
+        assert!(!owned.is_mapped());
+        let mapped = ArtifactData::read(File::open(&path).unwrap(), 10).unwrap();
+        assert!(mapped.is_mapped());
+        assert_eq!(&mapped[..], &owned[..]);
+
+        let mut reader = mapped.reader();
+        let mut head = [0u8; 4];
+        reader.read_exact(&mut head).unwrap();
+        assert_eq!(&head, b"0123");
+        assert_eq!(mapped.into_vec(), b"0123456789");
+    }
+}
@@ -0,0 +1,467 @@
+use std::fs;
+use std::io::Write;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde_json::{Value, json};
+
+use crate::audio::pcm::{self, SampleFormat};
+use crate::audio::{container, flac, mp3, opus, wav};
+use crate::overwrite;
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+use crate::sink::{FileSink, OutputSink};
+use crate::video::AudioStream;
+use crate::video::container as video_container;
+use crate::video::matroska;
+
+use super::{keep_existing_output, take_string, take_u32};
+
+/// Decodes a WAV, FLAC, MP3 or Ogg Opus file, or headerless PCM described by
+/// the stage's parameters, into a float audio stream on the artifact's media.
+pub struct AudioDecodeStage {
+    format: InputFormat,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum InputFormat {
+    /// WAV, FLAC, MP3 or Opus, whichever the file's first bytes say.
+    Auto,
+    Wav,
+    Flac,
+    Mp3,
+    Opus,
+    Raw {
+        sample: SampleFormat,
+        sample_rate: u32,
+        channels: u16,
+    },
+}
+
+impl AudioDecodeStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let format = take_string(&mut params, "format").unwrap_or_else(|| "auto".to_string());
+        let format = match format.trim().to_ascii_lowercase().as_str() {
+            "auto" => InputFormat::Auto,
+            "wav" | "wave" => InputFormat::Wav,
+            "flac" => InputFormat::Flac,
+            "mp3" => InputFormat::Mp3,
+            "opus" => InputFormat::Opus,
+            "raw" | "pcm" => {
+                let sample = match take_string(&mut params, "sample_format") {
+                    Some(name) => SampleFormat::from_name(&name).ok_or_else(|| {
+                        anyhow!(
+                            "Unknown audio_decode sample_format '{name}' \
+                             (expected u8, or s16/s24/s32/f32/f64 with le or be)"
+                        )
+                    })?,
+                    None => SampleFormat::S16 { big_endian: false },
+                };
+                let sample_rate = take_u32(&mut params, "sample_rate")
+                    .filter(|rate| *rate > 0)
+                    .ok_or_else(|| anyhow!("audio_decode format raw needs a sample_rate"))?;
+                let channels = match take_u32(&mut params, "channels") {
+                    None => 2,
+                    Some(channels @ 1..=255) => channels as u16,
+                    Some(channels) => {
+                        bail!("audio_decode channels must be between 1 and 255, got {channels}")
+                    }
+                };
+                InputFormat::Raw {
+                    sample,
+                    sample_rate,
+                    channels,
+                }
+            }
+            _ => bail!(
+                "Unknown audio_decode format '{format}' \
+                 (expected auto, wav, flac, mp3, opus or raw)"
+            ),
+        };
+        Ok(Self { format })
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<AudioStream> {
+        match self.format {
+            InputFormat::Auto if wav::is_wav(data) => {
+                wav::decode(data).context("failed to decode WAV audio")
+            }
+            InputFormat::Auto if flac::is_flac(data) => {
+                flac::decode(data).context("failed to decode FLAC audio")
+            }
+            InputFormat::Auto if mp3::is_mp3(data) => {
+                mp3::decode(data).context("failed to decode MP3 audio")
+            }
+            InputFormat::Auto if opus::is_opus(data) => {
+                opus::decode(data).context("failed to decode Opus audio")
+            }
+            InputFormat::Auto => bail!(
+                "input is not a WAV, FLAC, MP3 or Ogg Opus file; \
+                 set format: raw to read headerless PCM"
+            ),
+            InputFormat::Wav => {
+                if !wav::is_wav(data) {
+                    bail!("input is not a WAV file; set format: raw to read headerless PCM");
+                }
+                wav::decode(data).context("failed to decode WAV audio")
+            }
+            InputFormat::Flac => flac::decode(data).context("failed to decode FLAC audio"),
+            InputFormat::Mp3 => mp3::decode(data).context("failed to decode MP3 audio"),
+            InputFormat::Opus => opus::decode(data).context("failed to decode Opus audio"),
+            InputFormat::Raw {
+                sample,
+                sample_rate,
+                channels,
+            } => pcm::decode(data, sample, sample_rate, channels),
+        }
+    }
+}
+
+impl Stage for AudioDecodeStage {
+    fn name(&self) -> &'static str {
+        "audio_decode"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        _ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let stream = self.decode(&artifact.data)?;
+        set_stream(artifact, stream);
+        Ok(())
+    }
+}
+
+/// Puts `stream` on the artifact's media, recording what it holds.
+fn set_stream(artifact: &mut Artifact, stream: AudioStream) {
+    let buffer = &stream.buffers[0];
+    let channels = buffer.channel_layout.channel_count();
+    let frames = buffer.samples.len() / usize::from(channels);
+    let duration = Duration::from_secs_f64(frames as f64 / f64::from(buffer.sample_rate));
+
+    artifact
+        .metadata
+        .insert("audio.codec".into(), json!(stream.codec.name()));
+    artifact
+        .metadata
+        .insert("audio.sample_rate".into(), json!(buffer.sample_rate));
+    artifact
+        .metadata
+        .insert("audio.channels".into(), json!(channels));
+    artifact
+        .metadata
+        .insert("audio.frame_count".into(), json!(frames));
+    artifact
+        .metadata
+        .insert("audio.duration".into(), json!(duration.as_secs_f64()));
+
+    let media = artifact.media_mut();
+    media.audio = Some(stream);
+    media.duration = Some(
+        media
+            .duration
+            .map_or(duration, |longest| longest.max(duration)),
+    );
+}
+
+/// Writes the decoded audio stream as the artifact's output: a WAV file of
+/// `sample_format` samples, a FLAC file at `compression_level`, or an MP3 or
+/// Ogg Opus file at `bitrate` kilobits per second.
+pub struct AudioEncodeStage {
+    format: OutputFormat,
+    extension: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum OutputFormat {
+    Wav(SampleFormat),
+    Flac {
+        level: u32,
+        /// Bits per sample; `None` picks the depth that fits the stream.
+        bit_depth: Option<u32>,
+    },
+    Mp3 {
+        bit_rate: u32,
+    },
+    Opus {
+        bit_rate: u32,
+    },
+}
+
+impl OutputFormat {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Wav(_) => "wav",
+            Self::Flac { .. } => "flac",
+            Self::Mp3 { .. } => "mp3",
+            Self::Opus { .. } => "opus",
+        }
+    }
+
+    /// The format with whatever it leaves to the stream settled.
+    fn for_stream(self, stream: &AudioStream) -> Self {
+        match self {
+            Self::Flac {
+                level,
+                bit_depth: None,
+            } => Self::Flac {
+                level,
+                bit_depth: Some(flac::fitting_bit_depth(stream)),
+            },
+            format => format,
+        }
+    }
+}
+
+impl AudioEncodeStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let format = take_string(&mut params, "format").unwrap_or_else(|| "wav".to_string());
+        let format = match format.trim().to_ascii_lowercase().as_str() {
+            "wav" | "wave" => {
+                let sample = match take_string(&mut params, "sample_format") {
+                    Some(name) => SampleFormat::from_name(&name)
+                        .filter(|sample| !sample.big_endian())
+                        .ok_or_else(|| {
+                            anyhow!(
+                                "Unknown audio_encode sample_format '{name}' for WAV \
+                                 (expected u8, s16le, s24le, s32le, f32le or f64le)"
+                            )
+                        })?,
+                    None => SampleFormat::S16 { big_endian: false },
+                };
+                OutputFormat::Wav(sample)
+            }
+            "flac" => {
+                let level = match take_u32(&mut params, "compression_level") {
+                    None => flac::DEFAULT_LEVEL,
+                    Some(level @ 0..=8) => level,
+                    Some(level) => {
+                        bail!("audio_encode compression_level must be between 0 and 8, got {level}")
+                    }
+                };
+                let bit_depth = take_u32(&mut params, "bit_depth");
+                if let Some(depth) = bit_depth.filter(|depth| !flac::BIT_DEPTHS.contains(depth)) {
+                    bail!(
+                        "audio_encode bit_depth must be 8, 12, 16, 20 or 24 for FLAC, got {depth}"
+                    );
+                }
+                OutputFormat::Flac { level, bit_depth }
+            }
+            "mp3" => OutputFormat::Mp3 {
+                bit_rate: take_bit_rate(&mut params, 128, 8..=320)?,
+            },
+            "opus" => OutputFormat::Opus {
+                bit_rate: take_bit_rate(&mut params, 96, 6..=510)?,
+            },
+            _ => bail!("Unknown audio_encode format '{format}' (expected wav, flac, mp3 or opus)"),
+        };
+        let extension = take_string(&mut params, "extension");
+        Ok(Self { format, extension })
+    }
+
+    fn extension(&self) -> &str {
+        self.extension
+            .as_deref()
+            .unwrap_or_else(|| self.format.name())
+    }
+
+    fn encode(format: OutputFormat, stream: &AudioStream) -> Result<Vec<u8>> {
+        match format {
+            OutputFormat::Wav(sample) => wav::encode(stream, sample),
+            OutputFormat::Flac { level, bit_depth } => {
+                let bit_depth = bit_depth.unwrap_or_else(|| flac::fitting_bit_depth(stream));
+                flac::encode(stream, bit_depth, level).context("failed to encode FLAC audio")
+            }
+            OutputFormat::Mp3 { bit_rate } => {
+                mp3::encode(stream, bit_rate * 1000).context("failed to encode MP3 audio")
+            }
+            OutputFormat::Opus { bit_rate } => opus::encode(stream, bit_rate * 1000)
+                .and_then(|encoded| opus::write_ogg(&encoded))
+                .context("failed to encode Opus audio"),
+        }
+    }
+}
+
+/// The `bitrate` parameter in kilobits per second, `default` when unset.
+fn take_bit_rate(
+    params: &mut StageParameters,
+    default: u32,
+    range: std::ops::RangeInclusive<u32>,
+) -> Result<u32> {
+    match take_u32(params, "bitrate") {
+        None => Ok(default),
+        Some(kbps) if range.contains(&kbps) => Ok(kbps),
+        Some(kbps) => bail!(
+            "audio_encode bitrate must be between {} and {} kbps, got {kbps}",
+            range.start(),
+            range.end()
+        ),
+    }
+}
+
+impl Stage for AudioEncodeStage {
+    fn name(&self) -> &'static str {
+        "audio_encode"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        if artifact.media().audio.is_none() {
+            bail!("audio_encode requires a decoded audio stream");
+        }
+        let output_path = ctx.outputs.claim(
+            ctx.output
+                .resolve(&artifact.stem, self.extension(), &artifact.metadata),
+            &artifact.input_path,
+            &mut artifact.metadata,
+        )?;
+        if keep_existing_output(artifact, ctx, &output_path) {
+            return Ok(());
+        }
+        let stream = artifact.media().audio.as_ref().expect("checked above");
+        let format = self.format.for_stream(stream);
+        let encoded = Self::encode(format, stream)?;
+
+        if let Some(parent) = output_path.parent() {
+            fs::create_dir_all(parent).with_context(|| {
+                format!("Failed to create output directory: {}", parent.display())
+            })?;
+        }
+        let mut sink = FileSink::create(&output_path)?;
+        sink.write_all(&encoded)
+            .with_context(|| format!("Failed to write output file: {}", output_path.display()))?;
+        let summary = sink.finish()?;
+
+        artifact.metadata.insert(
+            "output_path".to_string(),
+            Value::String(output_path.to_string_lossy().to_string()),
+        );
+        artifact.metadata.insert(
+            "output.format".to_string(),
+            Value::String(self.extension().to_string()),
+        );
+        artifact
+            .metadata
+            .insert("output.size_bytes".to_string(), json!(summary.size_bytes));
+        artifact
+            .metadata
+            .insert("output.sha256".to_string(), Value::String(summary.sha256));
+        artifact
+            .metadata
+            .insert("audio.output.format".into(), json!(self.format.name()));
+        match format {
+            OutputFormat::Wav(sample) => {
+                artifact
+                    .metadata
+                    .insert("audio.output.codec".into(), json!(sample.codec().name()));
+            }
+            OutputFormat::Flac { level, bit_depth } => {
+                artifact
+                    .metadata
+                    .insert("audio.output.codec".into(), json!("flac"));
+                artifact
+                    .metadata
+                    .insert("audio.output.bit_depth".into(), json!(bit_depth));
+                artifact
+                    .metadata
+                    .insert("audio.output.compression_level".into(), json!(level));
+            }
+            OutputFormat::Mp3 { bit_rate } | OutputFormat::Opus { bit_rate } => {
+                artifact
+                    .metadata
+                    .insert("audio.output.codec".into(), json!(self.format.name()));
+                artifact
+                    .metadata
+                    .insert("audio.output.bitrate".into(), json!(bit_rate));
+            }
+        }
+        Ok(())
+    }
+
+    fn plan(&self, artifact: &mut Artifact, ctx: &PipelineContext) -> Result<()> {
+        let output_path = ctx.outputs.claim(
+            ctx.output
+                .resolve(&artifact.stem, self.extension(), &artifact.metadata),
+            &artifact.input_path,
+            &mut artifact.metadata,
+        )?;
+        if let Some(reason) = ctx
+            .overwrite
+            .keep_reason(&artifact.input_path, &output_path)
+        {
+            artifact.metadata.insert(
+                overwrite::SKIPPED_KEY.to_string(),
+                Value::String(reason.to_string()),
+            );
+        }
+        artifact.metadata.insert(
+            "output_path".to_string(),
+            Value::String(output_path.to_string_lossy().to_string()),
+        );
+        Ok(())
+    }
+}
+
+/// Pulls the audio track out of an MP4 or Matroska/WebM input, decodes it
+/// onto the artifact's media as `audio_decode` would, and writes it as the
+/// artifact's output as `audio_encode` does, taking the same parameters.
+pub struct AudioExtractStage {
+    encode: AudioEncodeStage,
+}
+
+impl AudioExtractStage {
+    pub fn from_params(params: StageParameters) -> Result<Self> {
+        Ok(Self {
+            encode: AudioEncodeStage::from_params(params)?,
+        })
+    }
+}
+
+impl Stage for AudioExtractStage {
+    fn name(&self) -> &'static str {
+        "audio_extract"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        ctx: &PipelineContext,
+        device: StageDevice,
+    ) -> Result<()> {
+        let data = &artifact.data;
+        let track = if matroska::is_matroska(data) {
+            matroska::audio_samples(data).context("failed to read Matroska/WebM container")?
+        } else if video_container::is_mp4(data) {
+            video_container::audio_samples(data).context("failed to read MP4 container")?
+        } else {
+            bail!("audio_extract needs an MP4, MOV or Matroska/WebM input");
+        };
+        let track = track.ok_or_else(|| anyhow!("input has no audio track"))?;
+        let stream = container::decode(&track)
+            .with_context(|| format!("failed to decode the {} audio track", track.codec.name()))?;
+        set_stream(artifact, stream);
+        self.encode.run(artifact, ctx, device)
+    }
+
+    fn plan(&self, artifact: &mut Artifact, ctx: &PipelineContext) -> Result<()> {
+        self.encode.plan(artifact, ctx)
+    }
+}
@@ -0,0 +1,105 @@
+use anyhow::{Result, anyhow, bail};
+use serde_json::{Map, Value, json};
+
+use crate::audio::channels;
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+use crate::video::ChannelLayout;
+
+use super::{take_bool, take_string};
+
+/// Downmixes or upmixes the decoded audio stream to another channel
+/// layout, recording the mixing matrix it used.
+pub struct AudioChannelsStage {
+    layout: ChannelLayout,
+    normalize: bool,
+}
+
+impl AudioChannelsStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let name = take_string(&mut params, "layout")
+            .ok_or_else(|| anyhow!("audio_channels stage requires a 'layout' parameter"))?;
+        let layout = channels::layout_from_name(&name).ok_or_else(|| {
+            anyhow!("unknown audio_channels layout '{name}' (expected mono, stereo, 5.1 or 7.1)")
+        })?;
+        let normalize = take_bool(&mut params, "normalize")?.unwrap_or(true);
+        Ok(Self { layout, normalize })
+    }
+}
+
+impl Stage for AudioChannelsStage {
+    fn name(&self) -> &'static str {
+        "audio_channels"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        _ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let audio = artifact
+            .media_mut()
+            .audio
+            .as_mut()
+            .filter(|audio| !audio.buffers.is_empty())
+            .ok_or_else(|| anyhow!("audio_channels stage requires a decoded audio stream"))?;
+        let source = audio.buffers[0].channel_layout;
+        if audio
+            .buffers
+            .iter()
+            .any(|buffer| buffer.channel_layout != source)
+        {
+            bail!("audio_channels needs every audio buffer in one channel layout");
+        }
+        let matrix = channels::matrix(source, self.layout, self.normalize)?;
+        for buffer in &mut audio.buffers {
+            *buffer = channels::mix(buffer, self.layout, &matrix);
+        }
+
+        artifact
+            .metadata
+            .insert("audio.channels".into(), json!(self.layout.channel_count()));
+        artifact.metadata.insert(
+            "audio_channels.source_layout".into(),
+            json!(channels::layout_name(source)),
+        );
+        artifact.metadata.insert(
+            "audio_channels.layout".into(),
+            json!(channels::layout_name(self.layout)),
+        );
+        artifact.metadata.insert(
+            "audio_channels.matrix".into(),
+            describe(source, self.layout, &matrix),
+        );
+        Ok(())
+    }
+}
+
+/// The matrix as a list of output channels, each with the gains of the
+/// input channels that feed it, by speaker label.
+fn describe(source: ChannelLayout, target: ChannelLayout, matrix: &[Vec<f32>]) -> Value {
+    let label = |layout: ChannelLayout, index: usize| {
+        channels::positions(layout)
+            .map(|positions| positions[index].label().to_string())
+            .unwrap_or_else(|| format!("{}", index + 1))
+    };
+    let rows = matrix.iter().enumerate().map(|(row, gains)| {
+        let mix: Map<String, Value> = gains
+            .iter()
+            .enumerate()
+            .filter(|&(_, &gain)| gain != 0.0)
+            .map(|(column, &gain)| {
+                // Four decimals are well below audible precision.
+                let gain = (f64::from(gain) * 10_000.0).round() / 10_000.0;
+                (label(source, column), json!(gain))
+            })
+            .collect();
+        json!({ "channel": label(target, row), "mix": mix })
+    });
+    Value::Array(rows.collect())
+}
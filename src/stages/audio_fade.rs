@@ -0,0 +1,85 @@
+use std::time::Duration;
+
+use anyhow::{Result, anyhow, bail};
+use serde_json::json;
+
+use crate::audio::edit::{self, Curve};
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+
+use super::take_string;
+use super::video_thumbnail::{Position, parse_position};
+
+/// Fades the decoded audio stream in from silence over its first `fade_in`
+/// and out to silence over its last `fade_out`, each in seconds,
+/// `[hh:]mm:ss` or a percentage of the stream.
+pub struct AudioFadeStage {
+    fade_in: Option<Position>,
+    fade_out: Option<Position>,
+    curve: Curve,
+}
+
+impl AudioFadeStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let mut position = |key: &str| params.remove(key).map(|value| parse_position(&value));
+        let fade_in = position("fade_in").transpose()?;
+        let fade_out = position("fade_out").transpose()?;
+        if fade_in.is_none() && fade_out.is_none() {
+            bail!("audio_fade stage requires 'fade_in' or 'fade_out'");
+        }
+        let curve = match take_string(&mut params, "curve") {
+            Some(name) => Curve::from_name(&name).ok_or_else(|| {
+                anyhow!("unknown audio_fade curve '{name}' (expected linear or sine)")
+            })?,
+            None => Curve::Linear,
+        };
+        Ok(Self {
+            fade_in,
+            fade_out,
+            curve,
+        })
+    }
+}
+
+impl Stage for AudioFadeStage {
+    fn name(&self) -> &'static str {
+        "audio_fade"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        _ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let audio = artifact
+            .media_mut()
+            .audio
+            .as_mut()
+            .filter(|audio| !audio.buffers.is_empty())
+            .ok_or_else(|| anyhow!("audio_fade stage requires a decoded audio stream"))?;
+        let total = edit::duration(&audio.buffers);
+        let resolve = |position: Option<Position>| {
+            position.map_or(Duration::ZERO, |position| {
+                position.resolve(total).min(total)
+            })
+        };
+        let (fade_in, fade_out) = (resolve(self.fade_in), resolve(self.fade_out));
+        edit::fade(&mut audio.buffers, fade_in, fade_out, self.curve);
+
+        artifact
+            .metadata
+            .insert("audio_fade.in".into(), json!(fade_in.as_secs_f64()));
+        artifact
+            .metadata
+            .insert("audio_fade.out".into(), json!(fade_out.as_secs_f64()));
+        artifact
+            .metadata
+            .insert("audio_fade.curve".into(), json!(self.curve.name()));
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+use anyhow::{Result, anyhow, bail};
+use serde_json::json;
+
+use crate::audio::resample::{self, Quality};
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+
+use super::{take_string, take_u32};
+
+/// Converts the decoded audio stream to another sample rate, such as the
+/// 48 kHz web codecs expect. Buffers already at the rate pass through.
+pub struct AudioResampleStage {
+    sample_rate: u32,
+    quality: Quality,
+}
+
+impl AudioResampleStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let sample_rate = match take_u32(&mut params, "sample_rate") {
+            Some(rate @ 1000..=768_000) => rate,
+            Some(rate) => {
+                bail!("audio_resample sample_rate must be between 1000 and 768000 Hz, got {rate}")
+            }
+            None => bail!("audio_resample stage requires a 'sample_rate' parameter"),
+        };
+        let quality = match take_string(&mut params, "quality") {
+            Some(name) => Quality::from_name(&name).ok_or_else(|| {
+                anyhow!("unknown audio_resample quality '{name}' (expected low, medium or high)")
+            })?,
+            None => Quality::High,
+        };
+        Ok(Self {
+            sample_rate,
+            quality,
+        })
+    }
+}
+
+impl Stage for AudioResampleStage {
+    fn name(&self) -> &'static str {
+        "audio_resample"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        _ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let audio = artifact
+            .media_mut()
+            .audio
+            .as_mut()
+            .filter(|audio| !audio.buffers.is_empty())
+            .ok_or_else(|| anyhow!("audio_resample stage requires a decoded audio stream"))?;
+        let source_rate = audio.buffers[0].sample_rate;
+        for buffer in &mut audio.buffers {
+            *buffer = resample::resample(buffer, self.sample_rate, self.quality);
+        }
+        let buffer = &audio.buffers[0];
+        let frames = buffer.samples.len() / usize::from(buffer.channel_layout.channel_count());
+
+        artifact
+            .metadata
+            .insert("audio.sample_rate".into(), json!(self.sample_rate));
+        artifact
+            .metadata
+            .insert("audio.frame_count".into(), json!(frames));
+        artifact
+            .metadata
+            .insert("audio_resample.source_rate".into(), json!(source_rate));
+        artifact
+            .metadata
+            .insert("audio_resample.quality".into(), json!(self.quality.name()));
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use anyhow::{Result, anyhow, bail};
+use serde_json::json;
+
+use crate::audio::edit;
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+
+use super::video_thumbnail::{Position, parse_position};
+
+/// Cuts the decoded audio stream down to the part between `start` and
+/// `end` (or `start` plus `duration`), each in seconds, `[hh:]mm:ss` or a
+/// percentage of the stream.
+pub struct AudioTrimStage {
+    start: Position,
+    end: Option<Position>,
+    duration: Option<Position>,
+}
+
+impl AudioTrimStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let mut position = |key: &str| params.remove(key).map(|value| parse_position(&value));
+        let start = position("start").transpose()?;
+        let end = position("end").transpose()?;
+        let duration = position("duration").transpose()?;
+        if end.is_some() && duration.is_some() {
+            bail!("audio_trim takes 'end' or 'duration', not both");
+        }
+        if start.is_none() && end.is_none() && duration.is_none() {
+            bail!("audio_trim stage requires 'start', 'end' or 'duration'");
+        }
+        Ok(Self {
+            start: start.unwrap_or(Position::Time(Duration::ZERO)),
+            end,
+            duration,
+        })
+    }
+
+    /// The span to keep of a stream lasting `total`.
+    fn span(&self, total: Duration) -> Result<(Duration, Duration)> {
+        let start = self.start.resolve(total);
+        let end = match (self.end, self.duration) {
+            (Some(end), _) => end.resolve(total),
+            (None, Some(duration)) => start + duration.resolve(total),
+            (None, None) => total,
+        }
+        .min(total);
+        if end <= start {
+            bail!(
+                "audio_trim from {:.3}s to {:.3}s holds none of the {:.3}s stream",
+                start.as_secs_f64(),
+                end.as_secs_f64(),
+                total.as_secs_f64()
+            );
+        }
+        Ok((start, end))
+    }
+}
+
+impl Stage for AudioTrimStage {
+    fn name(&self) -> &'static str {
+        "audio_trim"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        _ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let media = artifact.media_mut();
+        let audio = media
+            .audio
+            .as_mut()
+            .filter(|audio| !audio.buffers.is_empty())
+            .ok_or_else(|| anyhow!("audio_trim stage requires a decoded audio stream"))?;
+        let (start, end) = self.span(edit::duration(&audio.buffers))?;
+        edit::trim(&mut audio.buffers, start, end);
+        if audio.buffers.is_empty() {
+            bail!("audio_trim left no whole sample frames");
+        }
+        let duration = edit::duration(&audio.buffers);
+        let frames: usize = audio
+            .buffers
+            .iter()
+            .map(|buffer| buffer.samples.len() / usize::from(buffer.channel_layout.channel_count()))
+            .sum();
+        if media.video.is_none() {
+            media.duration = Some(duration);
+        }
+
+        artifact
+            .metadata
+            .insert("audio.frame_count".into(), json!(frames));
+        artifact
+            .metadata
+            .insert("audio.duration".into(), json!(duration.as_secs_f64()));
+        artifact
+            .metadata
+            .insert("audio_trim.start".into(), json!(start.as_secs_f64()));
+        artifact
+            .metadata
+            .insert("audio_trim.end".into(), json!(end.as_secs_f64()));
+        Ok(())
+    }
+}
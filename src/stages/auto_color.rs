@@ -0,0 +1,235 @@
+use anyhow::{Result, anyhow, bail};
+use image::DynamicImage;
+use serde_json::{Value, json};
+
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+
+use super::{take_bool, take_string, value_as_f64};
+
+const DEFAULT_CLIP_PERCENT: f64 = 0.5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum WhiteBalance {
+    None,
+    GrayWorld,
+    Percentile,
+}
+
+impl WhiteBalance {
+    fn from_str(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "none" | "off" => Some(Self::None),
+            "gray_world" | "grey_world" | "grayworld" => Some(Self::GrayWorld),
+            "percentile" | "white_patch" => Some(Self::Percentile),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::GrayWorld => "gray_world",
+            Self::Percentile => "percentile",
+        }
+    }
+}
+
+/// Corrects color casts and stretches each channel to the full range with a
+/// per-channel lookup table built from the image's own histograms.
+pub struct AutoColorStage {
+    white_balance: WhiteBalance,
+    levels: bool,
+    clip: f64,
+}
+
+impl AutoColorStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let white_balance = match take_string(&mut params, "white_balance") {
+            Some(value) => WhiteBalance::from_str(&value)
+                .ok_or_else(|| anyhow!("Unknown white_balance mode '{value}'"))?,
+            None => WhiteBalance::GrayWorld,
+        };
+        let levels = take_bool(&mut params, "levels")?.unwrap_or(true);
+        let clip = match params.remove("clip_percent") {
+            Some(value) => value_as_f64(&value)
+                .ok_or_else(|| anyhow!("clip_percent must be a number, got {value}"))?,
+            None => DEFAULT_CLIP_PERCENT,
+        };
+        if !(0.0..50.0).contains(&clip) {
+            bail!("clip_percent must be in 0..50, got {clip}");
+        }
+        Ok(Self {
+            white_balance,
+            levels,
+            clip: clip / 100.0,
+        })
+    }
+
+    fn lookup_tables(&self, histograms: &[[u64; 256]; 3], total: u64) -> [[u8; 256]; 3] {
+        let gains = match self.white_balance {
+            WhiteBalance::None => [1.0; 3],
+            WhiteBalance::GrayWorld => {
+                let means = histograms.map(|histogram| histogram_mean(&histogram, total));
+                let gray = means.iter().sum::<f64>() / 3.0;
+                means.map(|mean| if mean > 0.0 { gray / mean } else { 1.0 })
+            }
+            WhiteBalance::Percentile => {
+                let highs = histograms
+                    .map(|histogram| percentile(&histogram, total, 1.0 - self.clip) as f64);
+                let target = highs.iter().copied().fold(0.0, f64::max);
+                highs.map(|high| if high > 0.0 { target / high } else { 1.0 })
+            }
+        };
+
+        let mut tables = [[0u8; 256]; 3];
+        for channel in 0..3 {
+            let mut balanced = [0u8; 256];
+            let mut histogram = [0u64; 256];
+            for value in 0..256 {
+                let mapped = (value as f64 * gains[channel]).round().clamp(0.0, 255.0) as u8;
+                balanced[value] = mapped;
+                histogram[mapped as usize] += histograms[channel][value];
+            }
+            let (low, high) = if self.levels {
+                (
+                    percentile(&histogram, total, self.clip),
+                    percentile(&histogram, total, 1.0 - self.clip),
+                )
+            } else {
+                (0, 255)
+            };
+            for value in 0..256 {
+                tables[channel][value] = stretch(balanced[value], low, high);
+            }
+        }
+        tables
+    }
+}
+
+impl Stage for AutoColorStage {
+    fn name(&self) -> &'static str {
+        "auto_color"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        _ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let image = artifact
+            .image
+            .as_ref()
+            .ok_or_else(|| anyhow!("auto_color stage requires a decoded image"))?;
+        let mut rgba = image.to_rgba8();
+
+        let mut histograms = [[0u64; 256]; 3];
+        let mut total = 0u64;
+        for pixel in rgba.pixels() {
+            // Fully transparent pixels carry no visible color.
+            if pixel[3] == 0 {
+                continue;
+            }
+            for channel in 0..3 {
+                histograms[channel][pixel[channel] as usize] += 1;
+            }
+            total += 1;
+        }
+        if total > 0 {
+            let tables = self.lookup_tables(&histograms, total);
+            for pixel in rgba.pixels_mut() {
+                for channel in 0..3 {
+                    pixel[channel] = tables[channel][pixel[channel] as usize];
+                }
+            }
+        }
+
+        artifact.set_image(DynamicImage::ImageRgba8(rgba));
+        artifact.metadata.insert(
+            "auto_color.white_balance".to_string(),
+            Value::String(self.white_balance.as_str().to_string()),
+        );
+        artifact
+            .metadata
+            .insert("auto_color.levels".to_string(), json!(self.levels));
+        Ok(())
+    }
+}
+
+fn histogram_mean(histogram: &[u64; 256], total: u64) -> f64 {
+    let sum: f64 = histogram
+        .iter()
+        .enumerate()
+        .map(|(value, &count)| value as f64 * count as f64)
+        .sum();
+    sum / total as f64
+}
+
+/// Smallest value with at least `fraction` of the samples at or below it.
+fn percentile(histogram: &[u64; 256], total: u64, fraction: f64) -> u8 {
+    let threshold = (total as f64 * fraction).ceil().max(1.0) as u64;
+    let mut seen = 0u64;
+    for (value, &count) in histogram.iter().enumerate() {
+        seen += count;
+        if seen >= threshold {
+            return value as u8;
+        }
+    }
+    255
+}
+
+fn stretch(value: u8, low: u8, high: u8) -> u8 {
+    if high <= low {
+        return value;
+    }
+    let scaled = (value.saturating_sub(low)) as f64 * 255.0 / (high - low) as f64;
+    scaled.round().clamp(0.0, 255.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stages::from_json;
+
+    fn histograms(pixels: &[[u8; 3]]) -> [[u64; 256]; 3] {
+        let mut histograms = [[0u64; 256]; 3];
+        for pixel in pixels {
+            for channel in 0..3 {
+                histograms[channel][pixel[channel] as usize] += 1;
+            }
+        }
+        histograms
+    }
+
+    #[test]
+    fn gray_world_removes_uniform_cast() {
+        let pixels = [[120, 100, 80], [60, 50, 40]];
+        let tables = from_json(AutoColorStage::from_params, json!({ "levels": false }))
+            .unwrap()
+            .lookup_tables(&histograms(&pixels), pixels.len() as u64);
+        for pixel in pixels {
+            let corrected = [0, 1, 2].map(|c| tables[c][pixel[c] as usize]);
+            assert!(corrected[0].abs_diff(corrected[2]) <= 1, "{corrected:?}");
+        }
+    }
+
+    #[test]
+    fn levels_stretch_each_channel_to_full_range() {
+        let pixels = [[50, 60, 70], [100, 110, 120], [150, 160, 170]];
+        let tables = from_json(
+            AutoColorStage::from_params,
+            json!({ "white_balance": "none", "clip_percent": 0 }),
+        )
+        .unwrap()
+        .lookup_tables(&histograms(&pixels), pixels.len() as u64);
+        assert_eq!(tables[0][50], 0);
+        assert_eq!(tables[0][150], 255);
+        assert_eq!(tables[2][70], 0);
+        assert_eq!(tables[2][170], 255);
+    }
+}
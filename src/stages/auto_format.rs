@@ -0,0 +1,194 @@
+//! `format: auto` for encode: every candidate format is encoded in memory
+//! and the smallest one whose decoded pixels stay within `min_ssim` of the
+//! image is written.
+
+use anyhow::{Result, bail};
+use image::{DynamicImage, ImageFormat};
+use serde_json::{Value, json};
+use tracing::{debug, warn};
+
+use crate::pipeline::{AnimationFrame, StageParameters};
+use crate::quality::compute_metrics;
+
+use super::{encode_artifact, format_extension, format_with_options, take_f64};
+
+/// The `format` value that turns the mode on.
+pub(super) const LABEL: &str = "auto";
+const DEFAULT_CANDIDATES: [&str; 3] = ["avif", "webp", "jpeg"];
+const DEFAULT_MIN_SSIM: f64 = 0.95;
+
+struct Candidate {
+    format: ImageFormat,
+    /// Stage-wide options with the candidate's own on top.
+    options: StageParameters,
+}
+
+/// The candidate that won, already encoded.
+pub(super) struct Choice {
+    pub format: ImageFormat,
+    pub options: StageParameters,
+    pub encoded: Vec<u8>,
+    /// Size and SSIM of every candidate tried, for `output.auto.candidates`.
+    pub report: Value,
+    pub ssim: f64,
+}
+
+pub(super) struct AutoFormat {
+    candidates: Vec<Candidate>,
+    min_ssim: f64,
+}
+
+impl AutoFormat {
+    /// Takes `candidates` and `min_ssim` out of the encode parameters;
+    /// `options` are what is left, shared by every candidate.
+    pub fn from_params(params: &mut StageParameters) -> Result<Self> {
+        let min_ssim = take_f64(params, "min_ssim")?.unwrap_or(DEFAULT_MIN_SSIM);
+        if !(0.0..=1.0).contains(&min_ssim) {
+            bail!("min_ssim must be between 0 and 1, got {min_ssim}");
+        }
+        let listed = match params.remove("candidates") {
+            None => DEFAULT_CANDIDATES.map(Value::from).to_vec(),
+            Some(Value::Array(listed)) if !listed.is_empty() => listed,
+            Some(other) => bail!("auto format candidates must be a non-empty list, got {other}"),
+        };
+        let candidates = listed
+            .into_iter()
+            .map(|entry| format_with_options(entry, "auto format candidates"))
+            .collect::<Result<Vec<_>>>()?;
+        let shared = std::mem::take(params);
+        Ok(Self {
+            candidates: candidates
+                .into_iter()
+                .map(|(format, own)| {
+                    let mut options = shared.clone();
+                    options.extend(own);
+                    Candidate { format, options }
+                })
+                .collect(),
+            min_ssim,
+        })
+    }
+
+    /// The extension of the first candidate, which is all a dry run can
+    /// predict.
+    pub fn planned_extension(&self) -> &'static str {
+        format_extension(self.candidates[0].format)
+    }
+
+    pub fn select(&self, image: &DynamicImage, frames: &[AnimationFrame]) -> Result<Choice> {
+        let mut report = Vec::with_capacity(self.candidates.len());
+        // The chosen candidate and whether it reached `min_ssim`.
+        let mut best: Option<(Choice, bool)> = None;
+        for candidate in &self.candidates {
+            let label = format_extension(candidate.format);
+            let mut encoded = Vec::new();
+            if let Err(err) = encode_artifact(
+                image,
+                frames,
+                candidate.format,
+                &candidate.options,
+                &mut encoded,
+            ) {
+                debug!(format = label, error = %err, "Auto format candidate failed to encode");
+                report.push(json!({ "format": label, "error": err.to_string() }));
+                continue;
+            }
+            // Animations are compared by their first frame. A format this
+            // build can only write (AVIF) cannot be checked, so it is
+            // skipped like a failed encode.
+            let decoded = match image::load_from_memory_with_format(&encoded, candidate.format) {
+                Ok(decoded) => decoded,
+                Err(err) => {
+                    debug!(format = label, error = %err, "Auto format candidate failed to decode");
+                    report.push(json!({ "format": label, "error": err.to_string() }));
+                    continue;
+                }
+            };
+            let ssim = compute_metrics(image, &decoded)?.ssim;
+            let passes = ssim >= self.min_ssim;
+            report.push(json!({
+                "format": label,
+                "size_bytes": encoded.len(),
+                "ssim": ssim,
+                "passed": passes,
+            }));
+            // Smallest passing candidate; failing that, the most faithful.
+            let better = match &best {
+                None => true,
+                Some((_, false)) if passes => true,
+                Some((chosen, true)) => passes && encoded.len() < chosen.encoded.len(),
+                Some((chosen, false)) => ssim > chosen.ssim,
+            };
+            if better {
+                let choice = Choice {
+                    format: candidate.format,
+                    options: candidate.options.clone(),
+                    encoded,
+                    report: Value::Null,
+                    ssim,
+                };
+                best = Some((choice, passes));
+            }
+        }
+        let Some((mut choice, passed)) = best else {
+            bail!("No auto format candidate could be encoded and checked");
+        };
+        if !passed {
+            warn!(
+                min_ssim = self.min_ssim,
+                ssim = choice.ssim,
+                format = format_extension(choice.format),
+                "No auto format candidate reached min_ssim; writing the closest"
+            );
+        }
+        choice.report = Value::Array(report);
+        Ok(choice)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stages::json_params;
+
+    #[test]
+    fn candidates_inherit_shared_options_and_override_their_own() {
+        let mut options = json_params(json!({
+            "candidates": ["webp", { "format": "jpeg", "quality": 90 }],
+            "min_ssim": 0.9,
+            "quality": 70,
+        }));
+        let auto = AutoFormat::from_params(&mut options).unwrap();
+        assert!(options.is_empty());
+        assert_eq!(auto.min_ssim, 0.9);
+        assert_eq!(auto.planned_extension(), "webp");
+        let quality: Vec<_> = auto
+            .candidates
+            .iter()
+            .map(|candidate| candidate.options["quality"].clone())
+            .collect();
+        assert_eq!(quality, [json!(70), json!(90)]);
+
+        let defaults = AutoFormat::from_params(&mut StageParameters::new()).unwrap();
+        assert_eq!(defaults.candidates.len(), DEFAULT_CANDIDATES.len());
+        assert!(
+            AutoFormat::from_params(&mut json_params(json!({ "candidates": ["heic"] }))).is_err()
+        );
+        assert!(AutoFormat::from_params(&mut json_params(json!({ "min_ssim": 2 }))).is_err());
+    }
+
+    #[test]
+    fn defaults_skip_candidates_that_cannot_be_decoded() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(32, 32, |x, y| {
+            image::Rgb([(x * 8) as u8, (y * 8) as u8, 128])
+        }));
+        let auto = AutoFormat::from_params(&mut StageParameters::new()).unwrap();
+        let choice = auto.select(&image, &[]).unwrap();
+        assert_ne!(choice.format, ImageFormat::Avif);
+        let report = choice.report.as_array().unwrap();
+        assert_eq!(report.len(), DEFAULT_CANDIDATES.len());
+        assert_eq!(report[0]["format"], "avif");
+        assert!(report[0]["error"].is_string());
+        assert!(report[1..].iter().all(|entry| entry["ssim"].is_number()));
+    }
+}
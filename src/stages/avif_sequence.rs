@@ -0,0 +1,135 @@
+//! Animated AVIF: frames encoded as AV1 with rav1e (the `rav1e` feature)
+//! and written as an `avis` image sequence.
+
+use std::io::Write;
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use image::codecs::gif::Repeat as GifRepeat;
+use tracing::warn;
+
+use crate::pipeline::{AnimationFrame, StageParameters};
+use crate::video::av1::{self, EncoderConfig};
+use crate::video::{
+    ColorSpace, FrameRate, HdrMetadata, TransferFunction, VideoCodec, VideoFrame, VideoStream,
+    muxer,
+};
+
+use super::{frame_delay, param_u8, parse_gif_repeat};
+
+/// The smallest frame edge rav1e encodes.
+const MIN_SIZE: u32 = 16;
+
+/// Encodes `frames` with the still AVIF options (`quality`, `speed`), each
+/// shown for its own delay, looping as `repeat` says. There is no alpha
+/// track, so transparency is dropped.
+pub(super) fn encode(
+    frames: &[AnimationFrame],
+    options: &StageParameters,
+    out: &mut dyn Write,
+) -> Result<()> {
+    if options.contains_key("frame_quality") {
+        warn!("frame_quality applies to animated WebP; animated AVIF uses quality for every frame");
+    }
+    if frames
+        .iter()
+        .any(|frame| frame.image.pixels().any(|pixel| pixel[3] < u8::MAX))
+    {
+        warn!("Animated AVIF output has no alpha track; transparent pixels become opaque");
+    }
+    let quality = param_u8(options, "quality").unwrap_or(80).clamp(1, 100);
+    let speed = param_u8(options, "speed").unwrap_or(4).clamp(1, 10);
+
+    let (width, height) = frames[0].image.dimensions();
+    if width < MIN_SIZE || height < MIN_SIZE {
+        bail!("Animated AVIF frames must be at least {MIN_SIZE}x{MIN_SIZE}, got {width}x{height}");
+    }
+    let mut timestamp = Duration::ZERO;
+    let mut video_frames = Vec::with_capacity(frames.len());
+    for (index, frame) in frames.iter().enumerate() {
+        if frame.image.dimensions() != (width, height) {
+            bail!("Animation frame {index} does not match the {width}x{height} canvas");
+        }
+        let duration = Duration::from_millis(u64::from(frame_delay(frame, options)));
+        video_frames.push(VideoFrame::from_rgba(
+            &frame.image,
+            ColorSpace::Bt709,
+            timestamp,
+            duration,
+        ));
+        timestamp += duration;
+    }
+    let stream = VideoStream {
+        codec: VideoCodec::Av1,
+        frame_rate: FrameRate::Variable,
+        frames: video_frames,
+        color_space: ColorSpace::Bt709,
+        transfer: TransferFunction::Sdr,
+        rotation: 0,
+        hdr: HdrMetadata::default(),
+    };
+    let config = EncoderConfig {
+        speed,
+        quantizer: quantizer(quality),
+        ..EncoderConfig::default()
+    };
+    let encoded = av1::encode(&stream, &config).context("Animated AVIF encode failed")?;
+    // Like WebP, a repeat count of 0 loops forever.
+    let plays = match parse_gif_repeat(options)? {
+        Some(GifRepeat::Finite(count)) if count > 0 => Some(u32::from(count)),
+        _ => None,
+    };
+    out.write_all(&muxer::write_avif_sequence(&encoded, plays)?)
+        .context("AVIF write failed")?;
+    Ok(())
+}
+
+/// rav1e's quantizer for an AVIF `quality`, on the curve the still image
+/// encoder (ravif) uses so both kinds of output look alike.
+fn quantizer(quality: u8) -> u8 {
+    let quality = f32::from(quality) / 100.0;
+    let scaled = if quality >= 0.85 {
+        (1.0 - quality) * 3.0
+    } else if quality > 0.25 {
+        1.0 - 0.125 - quality * 0.5
+    } else {
+        1.0 - quality
+    };
+    (scaled * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quality_maps_onto_the_still_encoder_curve() {
+        assert_eq!(quantizer(100), 0);
+        assert_eq!(quantizer(90), 77);
+        assert_eq!(quantizer(50), 159);
+        assert_eq!(quantizer(1), 252);
+    }
+
+    #[cfg(feature = "rav1e")]
+    #[test]
+    fn frames_become_an_avif_image_sequence() {
+        let frames: Vec<AnimationFrame> = [[255, 0, 0], [0, 0, 255]]
+            .into_iter()
+            .map(|[red, green, blue]| AnimationFrame {
+                image: image::RgbaImage::from_pixel(16, 16, image::Rgba([red, green, blue, 255])),
+                delay_ms: 100,
+            })
+            .collect();
+        let mut options = StageParameters::new();
+        options.insert("speed".into(), 10.into());
+        options.insert("repeat".into(), 3.into());
+        let mut out = Vec::new();
+        encode(&frames, &options, &mut out).unwrap();
+        assert_eq!(&out[8..12], b"avis");
+        let track = crate::video::container::video_samples(&out)
+            .unwrap()
+            .unwrap();
+        assert_eq!(track.samples.len(), 2);
+        assert_eq!(track.samples[1].timestamp, Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,372 @@
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+use anyhow::{Context, Result, anyhow, bail};
+use image::{DynamicImage, ImageBuffer, ImageDecoder, ImageFormat, RgbaImage};
+use moxcms::{ColorProfile, DataColorSpace, Layout, RenderingIntent, TransformOptions};
+use serde_json::{Value, json};
+use tiff::ColorType as TiffColorType;
+use tiff::decoder::{Decoder as TiffDecoder, DecodingResult};
+use tracing::warn;
+
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+
+use super::{format_from_label, take_string};
+
+/// Built-in profiles selectable by name; anything else is read as a path to
+/// an `.icc` file.
+const NAMED_PROFILES: [&str; 6] = [
+    "srgb",
+    "adobe_rgb",
+    "display_p3",
+    "dci_p3",
+    "prophoto",
+    "bt2020",
+];
+
+/// A profile and how it was chosen, for metadata records.
+struct Profile {
+    profile: ColorProfile,
+    label: String,
+}
+
+impl Profile {
+    fn load(spec: &str) -> Result<Self> {
+        let profile = match spec.trim().to_lowercase().replace('-', "_").as_str() {
+            "srgb" => ColorProfile::new_srgb(),
+            "adobe_rgb" | "adobergb" => ColorProfile::new_adobe_rgb(),
+            "display_p3" => ColorProfile::new_display_p3(),
+            "dci_p3" => ColorProfile::new_dci_p3(),
+            "prophoto" | "prophoto_rgb" => ColorProfile::new_pro_photo_rgb(),
+            "bt2020" | "rec2020" => ColorProfile::new_bt2020(),
+            _ => {
+                let data = fs::read(Path::new(spec)).with_context(|| {
+                    format!(
+                        "Failed to read ICC profile '{spec}' (built-in profiles: {})",
+                        NAMED_PROFILES.join(", ")
+                    )
+                })?;
+                parse_profile(&data).with_context(|| format!("Invalid ICC profile '{spec}'"))?
+            }
+        };
+        Ok(Self {
+            profile,
+            label: spec.to_string(),
+        })
+    }
+}
+
+/// Transforms pixels from the input's color profile to another one through
+/// an ICC color engine, so colors look the same after the profile changes
+/// instead of only being re-tagged.
+pub struct ColorConvertStage {
+    /// Overrides the profile embedded in the input.
+    from: Option<Profile>,
+    to: Profile,
+    intent: RenderingIntent,
+}
+
+impl ColorConvertStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let from = take_string(&mut params, "from")
+            .map(|spec| Profile::load(&spec))
+            .transpose()?;
+        let to = Profile::load(&take_string(&mut params, "to").unwrap_or_else(|| "srgb".into()))?;
+        if !matches!(
+            to.profile.color_space,
+            DataColorSpace::Rgb | DataColorSpace::Gray
+        ) {
+            bail!(
+                "color_convert target profile '{}' must be RGB or grayscale",
+                to.label
+            );
+        }
+        let intent = match take_string(&mut params, "intent") {
+            Some(value) => parse_intent(&value).ok_or_else(|| {
+                anyhow!(
+                    "Unknown rendering intent '{value}' \
+                     (expected perceptual, relative, saturation or absolute)"
+                )
+            })?,
+            None => RenderingIntent::Perceptual,
+        };
+        Ok(Self { from, to, intent })
+    }
+
+    fn options(&self) -> TransformOptions {
+        TransformOptions {
+            rendering_intent: self.intent,
+            ..TransformOptions::default()
+        }
+    }
+
+    /// Converts `image`, whose pixels are in `source`. Everything goes
+    /// through 16 bits per channel; 8-bit images come back as 8-bit.
+    fn convert(&self, image: &DynamicImage, source: &ColorProfile) -> Result<DynamicImage> {
+        let gray_in = source.color_space == DataColorSpace::Gray;
+        let gray_out = self.to.profile.color_space == DataColorSpace::Gray;
+        if !gray_in && source.color_space != DataColorSpace::Rgb {
+            bail!("color_convert cannot read {:?} pixels", source.color_space);
+        }
+        let alpha = image.color().has_alpha();
+        let layout = |gray: bool| match (gray, alpha) {
+            (true, false) => Layout::Gray,
+            (true, true) => Layout::GrayAlpha,
+            (false, false) => Layout::Rgb,
+            (false, true) => Layout::Rgba,
+        };
+        let transform = source
+            .create_transform_16bit(
+                layout(gray_in),
+                &self.to.profile,
+                layout(gray_out),
+                self.options(),
+            )
+            .map_err(|err| anyhow!("Failed to build color transform: {err}"))?;
+        let pixels = match (gray_in, alpha) {
+            (true, false) => image.to_luma16().into_raw(),
+            (true, true) => image.to_luma_alpha16().into_raw(),
+            (false, false) => image.to_rgb16().into_raw(),
+            (false, true) => image.to_rgba16().into_raw(),
+        };
+        let channels = usize::from(!gray_out) * 2 + 1 + usize::from(alpha);
+        let (width, height) = (image.width(), image.height());
+        let mut converted = vec![0u16; width as usize * height as usize * channels];
+        transform
+            .transform(&pixels, &mut converted)
+            .map_err(|err| anyhow!("Color transform failed: {err}"))?;
+        let converted = rebuild(width, height, gray_out, alpha, converted)?;
+        let deep = image.color().bytes_per_pixel() > image.color().channel_count();
+        Ok(if deep {
+            converted
+        } else if gray_out {
+            if alpha {
+                DynamicImage::ImageLumaA8(converted.to_luma_alpha8())
+            } else {
+                DynamicImage::ImageLuma8(converted.to_luma8())
+            }
+        } else if alpha {
+            DynamicImage::ImageRgba8(converted.to_rgba8())
+        } else {
+            DynamicImage::ImageRgb8(converted.to_rgb8())
+        })
+    }
+
+    /// Converts the raw ink values of a CMYK TIFF, which decoding has
+    /// already approximated as RGB without a profile.
+    fn convert_cmyk(&self, data: &[u8], source: &ColorProfile) -> Result<DynamicImage> {
+        let mut decoder =
+            TiffDecoder::new(Cursor::new(data)).context("Failed to read CMYK TIFF")?;
+        let (width, height) = decoder.dimensions()?;
+        let (inks, deep): (Vec<u16>, bool) = match (decoder.colortype()?, decoder.read_image()?) {
+            (TiffColorType::CMYK(8), DecodingResult::U8(inks)) => (
+                inks.into_iter().map(|ink| u16::from(ink) * 257).collect(),
+                false,
+            ),
+            (TiffColorType::CMYK(16), DecodingResult::U16(inks)) => (inks, true),
+            (color, _) => bail!("color_convert cannot read {color:?} TIFF pixels as CMYK"),
+        };
+        let gray_out = self.to.profile.color_space == DataColorSpace::Gray;
+        let dst_layout = if gray_out { Layout::Gray } else { Layout::Rgb };
+        // Four-channel device data uses the RGBA layout.
+        let transform = source
+            .create_transform_16bit(Layout::Rgba, &self.to.profile, dst_layout, self.options())
+            .map_err(|err| anyhow!("Failed to build color transform: {err}"))?;
+        let channels = if gray_out { 1 } else { 3 };
+        let mut converted = vec![0u16; width as usize * height as usize * channels];
+        transform
+            .transform(&inks, &mut converted)
+            .map_err(|err| anyhow!("Color transform failed: {err}"))?;
+        let converted = rebuild(width, height, gray_out, false, converted)?;
+        Ok(match (deep, gray_out) {
+            (true, _) => converted,
+            (false, true) => DynamicImage::ImageLuma8(converted.to_luma8()),
+            (false, false) => DynamicImage::ImageRgb8(converted.to_rgb8()),
+        })
+    }
+}
+
+impl Stage for ColorConvertStage {
+    fn name(&self) -> &'static str {
+        "color_convert"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        _ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let image = artifact
+            .image
+            .clone()
+            .ok_or_else(|| anyhow!("color_convert stage requires a decoded image"))?;
+        let format = artifact.format.as_deref().and_then(format_from_label);
+        let (source, label) = match &self.from {
+            Some(from) => (from.profile.clone(), from.label.clone()),
+            None => match format.and_then(|format| embedded_profile(&artifact.data, format)) {
+                Some(profile) => (profile, "embedded".to_string()),
+                None => {
+                    warn!(
+                        input = %artifact.input_path.display(),
+                        "Input has no ICC profile; assuming sRGB"
+                    );
+                    (ColorProfile::new_srgb(), "srgb".to_string())
+                }
+            },
+        };
+
+        let converted = if source.color_space == DataColorSpace::Cmyk {
+            if format != Some(ImageFormat::Tiff) {
+                bail!("color_convert only converts CMYK from TIFF inputs");
+            }
+            if artifact.image_edited {
+                bail!("color_convert must run before any stage that edits a CMYK image");
+            }
+            self.convert_cmyk(&artifact.data, &source)?
+        } else {
+            if artifact.is_animated() {
+                let transform_frame = |frame: &RgbaImage| {
+                    self.convert(&DynamicImage::ImageRgba8(frame.clone()), &source)
+                        .map(|converted| converted.to_rgba8())
+                };
+                for frame in artifact.frames_mut() {
+                    frame.image = transform_frame(&frame.image)?;
+                }
+            }
+            self.convert(&image, &source)?
+        };
+        artifact.set_image(converted);
+        artifact
+            .metadata
+            .insert("color_convert.from".to_string(), Value::String(label));
+        artifact.metadata.insert(
+            "color_convert.to".to_string(),
+            Value::String(self.to.label.clone()),
+        );
+        artifact.metadata.insert(
+            "color_convert.intent".to_string(),
+            json!(intent_name(self.intent)),
+        );
+        Ok(())
+    }
+}
+
+/// The ICC profile embedded in an encoded image, if it has a usable one.
+fn embedded_profile(data: &[u8], format: ImageFormat) -> Option<ColorProfile> {
+    let mut decoder = image::ImageReader::with_format(Cursor::new(data), format)
+        .into_decoder()
+        .ok()?;
+    let icc = decoder.icc_profile().ok()??;
+    match parse_profile(&icc) {
+        Ok(profile) => Some(profile),
+        Err(err) => {
+            warn!(error = %err, "Ignoring unreadable embedded ICC profile");
+            None
+        }
+    }
+}
+
+fn parse_profile(data: &[u8]) -> Result<ColorProfile> {
+    ColorProfile::new_from_slice(data).map_err(|err| anyhow!("{err}"))
+}
+
+fn rebuild(
+    width: u32,
+    height: u32,
+    gray: bool,
+    alpha: bool,
+    pixels: Vec<u16>,
+) -> Result<DynamicImage> {
+    let image = match (gray, alpha) {
+        (true, false) => {
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLuma16)
+        }
+        (true, true) => {
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLumaA16)
+        }
+        (false, false) => {
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgb16)
+        }
+        (false, true) => {
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgba16)
+        }
+    };
+    image.ok_or_else(|| anyhow!("Color transform produced a truncated image"))
+}
+
+fn parse_intent(value: &str) -> Option<RenderingIntent> {
+    match value.trim().to_lowercase().replace('-', "_").as_str() {
+        "perceptual" => Some(RenderingIntent::Perceptual),
+        "relative" | "relative_colorimetric" => Some(RenderingIntent::RelativeColorimetric),
+        "saturation" => Some(RenderingIntent::Saturation),
+        "absolute" | "absolute_colorimetric" => Some(RenderingIntent::AbsoluteColorimetric),
+        _ => None,
+    }
+}
+
+fn intent_name(intent: RenderingIntent) -> &'static str {
+    match intent {
+        RenderingIntent::Perceptual => "perceptual",
+        RenderingIntent::RelativeColorimetric => "relative",
+        RenderingIntent::Saturation => "saturation",
+        RenderingIntent::AbsoluteColorimetric => "absolute",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+    use crate::stages::from_json;
+
+    #[test]
+    fn converts_wide_gamut_pixels_into_srgb() {
+        // Pure Adobe RGB green lies outside sRGB: red is clipped to zero and
+        // green stays saturated, while neutral gray is unchanged.
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(2, 1, |x, _| {
+            if x == 0 {
+                Rgb([0, 255, 0])
+            } else {
+                Rgb([128, 128, 128])
+            }
+        }));
+        let convert = from_json(
+            ColorConvertStage::from_params,
+            json!({ "from": "adobe_rgb", "intent": "relative" }),
+        )
+        .unwrap();
+        let converted = convert
+            .convert(&image, &convert.from.as_ref().unwrap().profile)
+            .unwrap();
+        let DynamicImage::ImageRgb8(converted) = converted else {
+            panic!("8-bit input should stay 8-bit");
+        };
+        let green = converted.get_pixel(0, 0).0;
+        assert!(
+            green[0] < 10 && green[1] > 240 && green[2] < 70,
+            "{green:?}"
+        );
+        let gray = converted.get_pixel(1, 0).0;
+        assert!(
+            gray.iter().all(|&channel| channel.abs_diff(128) <= 2),
+            "{gray:?}"
+        );
+
+        assert!(
+            from_json(
+                ColorConvertStage::from_params,
+                json!({ "to": "missing.icc" })
+            )
+            .is_err()
+        );
+        assert!(from_json(ColorConvertStage::from_params, json!({ "intent": "vivid" })).is_err());
+    }
+}
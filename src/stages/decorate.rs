@@ -0,0 +1,237 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result, anyhow, bail};
+use image::{DynamicImage, Rgba, RgbaImage, imageops};
+use serde_json::json;
+
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+
+use super::{parse_color, record_dimensions, take_f64, take_string, take_u32};
+
+/// Where the vignette starts darkening, as a fraction of the distance from
+/// the center to a corner.
+const DEFAULT_VIGNETTE_START: f64 = 0.5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Vignette {
+    /// How much the corners darken: 0 leaves them, 1 makes them black.
+    strength: f64,
+    start: f64,
+}
+
+/// Framing effects for marketing assets, applied in this order: a vignette
+/// over the image, a solid border around it, then rounded corners cut out
+/// of the whole canvas with transparent, anti-aliased edges.
+pub struct DecorateStage {
+    vignette: Option<Vignette>,
+    /// Border width in pixels on every side.
+    border: u32,
+    border_color: Rgba<u8>,
+    /// Corner radius in pixels.
+    radius: u32,
+}
+
+impl DecorateStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let border = take_u32(&mut params, "border").unwrap_or(0);
+        let border_color = match take_string(&mut params, "border_color") {
+            Some(color) => parse_color(&color).context("Invalid decorate border_color")?,
+            None => Rgba([255, 255, 255, 255]),
+        };
+        let radius = take_u32(&mut params, "radius").unwrap_or(0);
+        let strength = take_f64(&mut params, "vignette")?;
+        let start = take_f64(&mut params, "vignette_start")?;
+        let vignette = match (strength, start) {
+            (None, Some(_)) => bail!("decorate vignette_start needs a 'vignette' strength"),
+            (None, None) => None,
+            (Some(strength), start) => {
+                if !(0.0..=1.0).contains(&strength) {
+                    bail!("decorate vignette must be between 0 and 1, got {strength}");
+                }
+                let start = start.unwrap_or(DEFAULT_VIGNETTE_START);
+                if !(0.0..1.0).contains(&start) {
+                    bail!("decorate vignette_start must be at least 0 and below 1, got {start}");
+                }
+                Some(Vignette { strength, start })
+            }
+        };
+        if border == 0 && radius == 0 && vignette.is_none() {
+            bail!("decorate stage requires a 'border', 'radius' or 'vignette'");
+        }
+        Ok(Self {
+            vignette,
+            border,
+            border_color,
+            radius,
+        })
+    }
+
+    fn decorate(&self, image: &RgbaImage) -> RgbaImage {
+        let mut image = image.clone();
+        if let Some(vignette) = self.vignette {
+            apply_vignette(&mut image, vignette);
+        }
+        if self.border > 0 {
+            let (width, height) = self.output_dimensions(image.width(), image.height());
+            let mut framed = RgbaImage::from_pixel(width, height, self.border_color);
+            let offset = i64::from(self.border);
+            imageops::replace(&mut framed, &image, offset, offset);
+            image = framed;
+        }
+        if self.radius > 0 {
+            round_corners(&mut image, self.radius);
+        }
+        image
+    }
+}
+
+impl Stage for DecorateStage {
+    fn name(&self) -> &'static str {
+        "decorate"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn output_dimensions(&self, width: u32, height: u32) -> (u32, u32) {
+        let grow = |edge: u32| edge.saturating_add(self.border.saturating_mul(2));
+        (grow(width), grow(height))
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        _ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let image = artifact
+            .image
+            .as_ref()
+            .map(Arc::clone)
+            .ok_or_else(|| anyhow!("decorate stage requires a decoded image"))?;
+        if artifact.is_animated() {
+            for frame in artifact.frames_mut() {
+                frame.image = self.decorate(&frame.image);
+            }
+        }
+        let decorated = DynamicImage::ImageRgba8(self.decorate(&image.to_rgba8()));
+        record_dimensions(artifact, "image", &decorated);
+        artifact.set_image(decorated);
+        artifact
+            .metadata
+            .insert("decorate.border".to_string(), json!(self.border));
+        artifact
+            .metadata
+            .insert("decorate.radius".to_string(), json!(self.radius));
+        if let Some(vignette) = self.vignette {
+            artifact
+                .metadata
+                .insert("decorate.vignette".to_string(), json!(vignette.strength));
+        }
+        Ok(())
+    }
+}
+
+/// Darkens the image towards its corners, from `start` of the way out.
+fn apply_vignette(image: &mut RgbaImage, vignette: Vignette) {
+    let center = (
+        f64::from(image.width()) / 2.0,
+        f64::from(image.height()) / 2.0,
+    );
+    let reach = center.0.hypot(center.1).max(f64::EPSILON);
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let distance = (f64::from(x) + 0.5 - center.0).hypot(f64::from(y) + 0.5 - center.1) / reach;
+        let t = ((distance - vignette.start) / (1.0 - vignette.start)).clamp(0.0, 1.0);
+        // Smoothstep, so the falloff has no visible edge.
+        let factor = 1.0 - vignette.strength * t * t * (3.0 - 2.0 * t);
+        for channel in 0..3 {
+            pixel[channel] = (f64::from(pixel[channel]) * factor).round() as u8;
+        }
+    }
+}
+
+/// Makes everything outside quarter circles of `radius` in each corner
+/// transparent, with partial alpha along the arc.
+fn round_corners(image: &mut RgbaImage, radius: u32) {
+    let (width, height) = image.dimensions();
+    let radius = radius.min(width / 2).min(height / 2);
+    if radius == 0 {
+        return;
+    }
+    let r = f64::from(radius);
+    let span = |edge: u32, at: u32| {
+        if at < radius {
+            Some(r - (f64::from(at) + 0.5))
+        } else if at >= edge - radius {
+            Some(f64::from(at) + 0.5 - f64::from(edge - radius))
+        } else {
+            None
+        }
+    };
+    for y in (0..radius).chain(height - radius..height) {
+        for x in (0..radius).chain(width - radius..width) {
+            let (Some(dx), Some(dy)) = (span(width, x), span(height, y)) else {
+                continue;
+            };
+            let coverage = (r - dx.hypot(dy) + 0.5).clamp(0.0, 1.0);
+            let pixel = image.get_pixel_mut(x, y);
+            pixel[3] = (f64::from(pixel[3]) * coverage).round() as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stages::from_json;
+
+    #[test]
+    fn border_grows_the_canvas_and_corners_turn_transparent() {
+        let framed = from_json(
+            DecorateStage::from_params,
+            json!({ "border": 2, "border_color": "#f00", "radius": 4 }),
+        )
+        .unwrap();
+        assert_eq!(framed.output_dimensions(10, 6), (14, 10));
+        let image = RgbaImage::from_pixel(10, 6, Rgba([0, 0, 255, 255]));
+        let decorated = framed.decorate(&image);
+        assert_eq!(decorated.dimensions(), (14, 10));
+        assert_eq!(decorated.get_pixel(0, 0)[3], 0);
+        assert_eq!(decorated.get_pixel(13, 9)[3], 0);
+        assert_eq!(decorated.get_pixel(7, 0).0, [255, 0, 0, 255]);
+        assert_eq!(decorated.get_pixel(7, 5).0, [0, 0, 255, 255]);
+        // The arc is anti-aliased rather than stepped.
+        let edge = decorated.get_pixel(1, 1)[3];
+        assert!(edge > 0 && edge < 255, "alpha {edge}");
+
+        assert!(from_json(DecorateStage::from_params, json!({})).is_err());
+        assert!(from_json(DecorateStage::from_params, json!({ "vignette": 1.5 })).is_err());
+        assert!(
+            from_json(
+                DecorateStage::from_params,
+                json!({ "radius": 4, "vignette_start": 0.2 })
+            )
+            .is_err()
+        );
+        assert!(
+            from_json(
+                DecorateStage::from_params,
+                json!({ "border": 2, "border_color": "red" })
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn vignette_darkens_corners_and_keeps_the_center() {
+        let vignette = from_json(DecorateStage::from_params, json!({ "vignette": 0.8 })).unwrap();
+        let image = RgbaImage::from_pixel(40, 40, Rgba([200, 200, 200, 255]));
+        let decorated = vignette.decorate(&image);
+        assert_eq!(decorated.get_pixel(20, 20).0, [200, 200, 200, 255]);
+        let corner = decorated.get_pixel(0, 0);
+        assert!(corner[0] < 80, "corner {corner:?}");
+        assert_eq!(corner[3], 255);
+    }
+}
@@ -0,0 +1,212 @@
+//! `fallbacks` for encode: extra copies of the output in other formats
+//! (say `.webp` and `.jpg` next to an `.avif`), encoded from the same
+//! decoded image and named by the same output structure.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use image::{DynamicImage, ImageFormat};
+use serde_json::{Value, json};
+use tracing::info;
+
+use crate::embedded::EmbeddedMetadata;
+use crate::pipeline::{Artifact, PipelineContext, StageParameters};
+use crate::sink::{FileSink, OutputSink};
+
+use super::{encode_artifact, encode_cursor, format_extension, format_with_options};
+
+/// Metadata key listing the fallback outputs written for an input.
+pub const FALLBACKS_KEY: &str = "output.fallbacks";
+
+struct Fallback {
+    format: ImageFormat,
+    /// Stage-wide options with the fallback's own on top.
+    options: StageParameters,
+}
+
+pub(super) struct Fallbacks {
+    fallbacks: Vec<Fallback>,
+}
+
+impl Fallbacks {
+    /// Takes `fallbacks` out of the encode parameters; every fallback starts
+    /// from the options left in `params`, which stay for the main output.
+    pub fn from_params(params: &mut StageParameters) -> Result<Option<Self>> {
+        let listed = match params.remove("fallbacks") {
+            None => return Ok(None),
+            Some(Value::Array(listed)) if !listed.is_empty() => listed,
+            Some(other) => bail!("encode fallbacks must be a non-empty list, got {other}"),
+        };
+        let mut fallbacks: Vec<Fallback> = Vec::with_capacity(listed.len());
+        for entry in listed {
+            let (format, own) = format_with_options(entry, "encode fallbacks")?;
+            if fallbacks.iter().any(|fallback| fallback.format == format) {
+                bail!(
+                    "encode fallback {} is listed twice",
+                    format_extension(format)
+                );
+            }
+            let mut options = params.clone();
+            options.extend(own);
+            fallbacks.push(Fallback { format, options });
+        }
+        Ok(Some(Self { fallbacks }))
+    }
+
+    /// Path of `fallback`, which may not land on the main output (`main`)
+    /// or an earlier fallback.
+    fn output_path(
+        &self,
+        artifact: &Artifact,
+        ctx: &PipelineContext,
+        fallback: &Fallback,
+        main: &Path,
+        taken: &[PathBuf],
+    ) -> Result<PathBuf> {
+        let extension = format_extension(fallback.format);
+        let path = ctx.outputs.claim_side_output(
+            super::resolve_output_path(&ctx.output, artifact, extension),
+            &artifact.input_path,
+        )?;
+        if path == main || taken.contains(&path) {
+            bail!(
+                "encode fallback {extension} would overwrite {}; use a structure with {{ext}} \
+                 and formats that differ from the main output",
+                path.display()
+            );
+        }
+        Ok(path)
+    }
+
+    /// Encodes and writes every fallback of `image`, recording them under
+    /// [`FALLBACKS_KEY`]. EXIF/XMP in `embedded` goes into the formats that
+    /// can carry it.
+    pub fn write(
+        &self,
+        artifact: &mut Artifact,
+        ctx: &PipelineContext,
+        image: &DynamicImage,
+        main: &Path,
+        embedded: &EmbeddedMetadata,
+    ) -> Result<()> {
+        let mut written = Vec::with_capacity(self.fallbacks.len());
+        let mut taken = Vec::with_capacity(self.fallbacks.len());
+        for fallback in &self.fallbacks {
+            ctx.cancellation.check()?;
+            let label = format_extension(fallback.format);
+            let path = self.output_path(artifact, ctx, fallback, main, &taken)?;
+            taken.push(path.clone());
+            let mut entry = json!({
+                "format": label,
+                "path": path.to_string_lossy(),
+            });
+            if let Some(reason) = ctx.overwrite.keep_reason(&artifact.input_path, &path) {
+                info!(output = %path.display(), reason, "Keeping existing fallback output");
+                entry["skipped"] = Value::String(reason.to_string());
+                written.push(entry);
+                continue;
+            }
+
+            let mut cursor = encode_cursor(image);
+            encode_artifact(
+                image,
+                &artifact.frames,
+                fallback.format,
+                &fallback.options,
+                &mut cursor,
+            )
+            .with_context(|| format!("Failed to encode {label} fallback"))?;
+            let mut buffer = cursor.into_inner();
+            if EmbeddedMetadata::supported(fallback.format) {
+                buffer = embedded
+                    .embed(fallback.format, buffer)
+                    .context("Failed to preserve image metadata")?;
+            }
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).with_context(|| {
+                    format!("Failed to create output directory: {}", parent.display())
+                })?;
+            }
+            let mut sink = FileSink::create(&path)?;
+            sink.write_all(&buffer)
+                .with_context(|| format!("Failed to write output file: {}", path.display()))?;
+            let summary = sink.finish()?;
+            entry["size_bytes"] = json!(summary.size_bytes);
+            entry["sha256"] = Value::String(summary.sha256);
+            written.push(entry);
+        }
+        artifact
+            .metadata
+            .insert(FALLBACKS_KEY.to_string(), Value::Array(written));
+        Ok(())
+    }
+
+    pub fn plan(&self, artifact: &mut Artifact, ctx: &PipelineContext, main: &Path) -> Result<()> {
+        let mut planned = Vec::with_capacity(self.fallbacks.len());
+        let mut taken = Vec::with_capacity(self.fallbacks.len());
+        for fallback in &self.fallbacks {
+            let path = self.output_path(artifact, ctx, fallback, main, &taken)?;
+            taken.push(path.clone());
+            let mut entry = json!({
+                "format": format_extension(fallback.format),
+                "path": path.to_string_lossy(),
+            });
+            if let Some(reason) = ctx.overwrite.keep_reason(&artifact.input_path, &path) {
+                entry["skipped"] = Value::String(reason.to_string());
+            }
+            planned.push(entry);
+        }
+        artifact
+            .metadata
+            .insert(FALLBACKS_KEY.to_string(), Value::Array(planned));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stages::json_params;
+
+    #[test]
+    fn fallbacks_inherit_main_options_and_override_their_own() {
+        let mut options = json_params(json!({
+            "fallbacks": ["webp", { "format": "jpeg", "quality": 85 }],
+            "quality": 60,
+        }));
+        let fallbacks = Fallbacks::from_params(&mut options).unwrap().unwrap();
+        assert_eq!(options, json_params(json!({ "quality": 60 })));
+        let formats: Vec<_> = fallbacks
+            .fallbacks
+            .iter()
+            .map(|fallback| (fallback.format, fallback.options["quality"].clone()))
+            .collect();
+        assert_eq!(
+            formats,
+            [
+                (ImageFormat::WebP, json!(60)),
+                (ImageFormat::Jpeg, json!(85))
+            ]
+        );
+
+        assert!(
+            Fallbacks::from_params(&mut StageParameters::new())
+                .unwrap()
+                .is_none()
+        );
+        for bad in [
+            json!({ "fallbacks": [] }),
+            json!({ "fallbacks": "webp" }),
+            json!({ "fallbacks": ["heic"] }),
+            json!({ "fallbacks": ["jpg", "jpeg"] }),
+            json!({ "fallbacks": [{ "quality": 80 }] }),
+        ] {
+            assert!(
+                Fallbacks::from_params(&mut json_params(bad.clone())).is_err(),
+                "{bad}"
+            );
+        }
+    }
+}
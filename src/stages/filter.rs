@@ -0,0 +1,311 @@
+use anyhow::{Result, anyhow, bail};
+use image::{DynamicImage, Rgba, RgbaImage, imageops};
+use serde_json::{Value, json};
+
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+
+use super::{take_f64, take_string};
+
+const DEFAULT_SHARPEN_AMOUNT: f64 = 1.0;
+const DEFAULT_SHARPEN_RADIUS: f64 = 1.0;
+/// The largest blur and sharpen radius, in pixels. Past it every method is
+/// a flat average of the whole image, reached at the cost of huge windows
+/// and kernels.
+const MAX_RADIUS: f64 = 1000.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BlurMethod {
+    Gaussian,
+    Box,
+}
+
+impl BlurMethod {
+    fn from_str(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "gaussian" => Some(Self::Gaussian),
+            "box" => Some(Self::Box),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Gaussian => "gaussian",
+            Self::Box => "box",
+        }
+    }
+}
+
+/// Softens the image with a gaussian (`radius` is the standard deviation)
+/// or a box filter (`radius` is the half-width of the averaged window).
+pub struct BlurStage {
+    method: BlurMethod,
+    radius: f64,
+}
+
+impl BlurStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let method = match take_string(&mut params, "method") {
+            Some(value) => BlurMethod::from_str(&value).ok_or_else(|| {
+                anyhow!("Unknown blur method '{value}' (expected gaussian or box)")
+            })?,
+            None => BlurMethod::Gaussian,
+        };
+        let radius = take_f64(&mut params, "radius")?
+            .ok_or_else(|| anyhow!("blur stage requires 'radius' parameter"))?;
+        if !(radius > 0.0 && radius <= MAX_RADIUS) {
+            bail!("blur radius must be above 0 and at most {MAX_RADIUS}, got {radius}");
+        }
+        if method == BlurMethod::Box && radius.fract() != 0.0 {
+            bail!("box blur radius must be a whole number of pixels, got {radius}");
+        }
+        Ok(Self { method, radius })
+    }
+
+    fn blur(&self, image: &RgbaImage) -> RgbaImage {
+        match self.method {
+            BlurMethod::Gaussian => imageops::blur(image, self.radius as f32),
+            BlurMethod::Box => box_blur(image, self.radius as u32),
+        }
+    }
+}
+
+impl Stage for BlurStage {
+    fn name(&self) -> &'static str {
+        "blur"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        _ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        filter_image(artifact, "blur", |image| self.blur(image))?;
+        artifact.metadata.insert(
+            "blur.method".to_string(),
+            Value::String(self.method.as_str().to_string()),
+        );
+        artifact
+            .metadata
+            .insert("blur.radius".to_string(), json!(self.radius));
+        Ok(())
+    }
+}
+
+/// Unsharp mask: adds `amount` times the difference between the image and a
+/// gaussian blur of it, skipping differences of `threshold` levels or less
+/// so flat areas and noise are left alone.
+pub struct SharpenStage {
+    amount: f64,
+    radius: f64,
+    threshold: u8,
+}
+
+impl SharpenStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let amount = take_f64(&mut params, "amount")?.unwrap_or(DEFAULT_SHARPEN_AMOUNT);
+        if !(amount > 0.0 && amount.is_finite()) {
+            bail!("sharpen amount must be positive, got {amount}");
+        }
+        let radius = take_f64(&mut params, "radius")?.unwrap_or(DEFAULT_SHARPEN_RADIUS);
+        if !(radius > 0.0 && radius <= MAX_RADIUS) {
+            bail!("sharpen radius must be above 0 and at most {MAX_RADIUS}, got {radius}");
+        }
+        let threshold = match take_f64(&mut params, "threshold")? {
+            Some(threshold) if (0.0..=255.0).contains(&threshold) => threshold.round() as u8,
+            Some(threshold) => bail!("sharpen threshold must be in 0..=255, got {threshold}"),
+            None => 0,
+        };
+        Ok(Self {
+            amount,
+            radius,
+            threshold,
+        })
+    }
+
+    fn sharpen(&self, image: &RgbaImage) -> RgbaImage {
+        let blurred = imageops::blur(image, self.radius as f32);
+        let mut sharpened = image.clone();
+        for (pixel, soft) in sharpened.pixels_mut().zip(blurred.pixels()) {
+            // Alpha is left as it is.
+            for channel in 0..3 {
+                let detail = f64::from(pixel[channel]) - f64::from(soft[channel]);
+                if detail.abs() > f64::from(self.threshold) {
+                    let value = f64::from(pixel[channel]) + self.amount * detail;
+                    pixel[channel] = value.round().clamp(0.0, 255.0) as u8;
+                }
+            }
+        }
+        sharpened
+    }
+}
+
+impl Stage for SharpenStage {
+    fn name(&self) -> &'static str {
+        "sharpen"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        _ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        filter_image(artifact, "sharpen", |image| self.sharpen(image))?;
+        artifact
+            .metadata
+            .insert("sharpen.amount".to_string(), json!(self.amount));
+        artifact
+            .metadata
+            .insert("sharpen.radius".to_string(), json!(self.radius));
+        artifact
+            .metadata
+            .insert("sharpen.threshold".to_string(), json!(self.threshold));
+        Ok(())
+    }
+}
+
+/// Replaces the working image, and every frame of an animation, with
+/// `filter` applied to it.
+fn filter_image(
+    artifact: &mut Artifact,
+    stage: &str,
+    filter: impl Fn(&RgbaImage) -> RgbaImage,
+) -> Result<()> {
+    let image = artifact
+        .image
+        .as_ref()
+        .ok_or_else(|| anyhow!("{stage} stage requires a decoded image"))?;
+    let filtered = filter(&image.to_rgba8());
+    if artifact.is_animated() {
+        for frame in artifact.frames_mut() {
+            frame.image = filter(&frame.image);
+        }
+    }
+    artifact.set_image(DynamicImage::ImageRgba8(filtered));
+    Ok(())
+}
+
+/// Averages each pixel with its neighbours up to `radius` pixels away in
+/// both directions, repeating edge pixels past the border.
+fn box_blur(image: &RgbaImage, radius: u32) -> RgbaImage {
+    let horizontal = box_pass(image, radius, true);
+    box_pass(&horizontal, radius, false)
+}
+
+fn box_pass(image: &RgbaImage, radius: u32, horizontal: bool) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    let mut blurred = RgbaImage::new(width, height);
+    let (lines, len) = if horizontal {
+        (height, width)
+    } else {
+        (width, height)
+    };
+    if len == 0 {
+        return blurred;
+    }
+    let radius = i64::from(radius);
+    let window = (2 * radius + 1) as u64;
+    let position = |line: u32, index: i64| {
+        let index = index.clamp(0, i64::from(len) - 1) as u32;
+        if horizontal {
+            (index, line)
+        } else {
+            (line, index)
+        }
+    };
+    for line in 0..lines {
+        let sample = |index: i64| {
+            let (x, y) = position(line, index);
+            image.get_pixel(x, y).0
+        };
+        let mut sums = [0u64; 4];
+        for index in -radius..=radius {
+            for (sum, value) in sums.iter_mut().zip(sample(index)) {
+                *sum += u64::from(value);
+            }
+        }
+        for index in 0..i64::from(len) {
+            let (x, y) = position(line, index);
+            blurred.put_pixel(
+                x,
+                y,
+                Rgba(sums.map(|sum| ((sum + window / 2) / window) as u8)),
+            );
+            let (entering, leaving) = (sample(index + radius + 1), sample(index - radius));
+            for channel in 0..4 {
+                sums[channel] += u64::from(entering[channel]);
+                sums[channel] -= u64::from(leaving[channel]);
+            }
+        }
+    }
+    blurred
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stages::json_params;
+
+    #[test]
+    fn box_blur_spreads_a_point_evenly() {
+        let mut image = RgbaImage::from_pixel(5, 5, Rgba([0, 0, 0, 255]));
+        image.put_pixel(2, 2, Rgba([225, 225, 225, 255]));
+        let stage =
+            BlurStage::from_params(json_params(json!({ "method": "box", "radius": 1 }))).unwrap();
+        let blurred = stage.blur(&image);
+        for (x, y, pixel) in blurred.enumerate_pixels() {
+            let expected = if x.abs_diff(2) <= 1 && y.abs_diff(2) <= 1 {
+                25
+            } else {
+                0
+            };
+            assert_eq!(pixel.0, [expected, expected, expected, 255], "at {x},{y}");
+        }
+
+        assert!(BlurStage::from_params(json_params(json!({}))).is_err());
+        assert!(
+            BlurStage::from_params(json_params(json!({ "method": "box", "radius": 1.5 }))).is_err()
+        );
+        assert!(
+            BlurStage::from_params(json_params(json!({ "method": "box", "radius": 100_000 })))
+                .is_err()
+        );
+        assert!(
+            BlurStage::from_params(json_params(json!({ "method": "motion", "radius": 2 })))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn sharpen_boosts_edges_above_the_threshold() {
+        let mut image = RgbaImage::from_pixel(8, 1, Rgba([100, 100, 100, 255]));
+        for x in 4..8 {
+            image.put_pixel(x, 0, Rgba([140, 140, 140, 255]));
+        }
+        let sharpen = |value: Value| {
+            SharpenStage::from_params(json_params(value))
+                .unwrap()
+                .sharpen(&image)
+        };
+
+        let sharpened = sharpen(json!({ "amount": 1.5, "radius": 1 }));
+        assert!(sharpened.get_pixel(3, 0)[0] < 100);
+        assert!(sharpened.get_pixel(4, 0)[0] > 140);
+        assert_eq!(sharpened.get_pixel(0, 0)[0], 100);
+        assert_eq!(sharpened.get_pixel(4, 0)[3], 255);
+
+        assert_eq!(sharpen(json!({ "threshold": 40 })), image);
+        assert!(SharpenStage::from_params(json_params(json!({ "threshold": 300 }))).is_err());
+    }
+}
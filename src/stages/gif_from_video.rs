@@ -0,0 +1,455 @@
+use std::fs;
+use std::io::Write;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail};
+use image::imageops::FilterType as ResizeFilter;
+use image::{DynamicImage, ImageFormat};
+use serde_json::{Value, json};
+
+use crate::overwrite;
+use crate::pipeline::{AnimationFrame, Artifact, PipelineContext, Stage, StageParameters};
+use crate::quantize::{self, Dither};
+use crate::scheduler::StageDevice;
+use crate::sink::{FileSink, OutputSink};
+use crate::video::VideoStream;
+
+use super::video_thumbnail::{Position, frame_at, parse_position, stream_duration};
+use super::{
+    encode_animated_gif, encode_animated_webp, format_extension, keep_existing_output, map_filter,
+    parse_dither, take_string, take_u32, value_as_u64,
+};
+
+const DEFAULT_FPS: f64 = 10.0;
+/// GIF delays count hundredths of a second, and browsers slow anything
+/// under two of them down, so faster clips would play back wrong.
+const MAX_FPS: f64 = 50.0;
+
+/// Turns the decoded video, or the part of it between `start` and `end` (or
+/// `start` plus `duration`), into an animated GIF or WebP written as the
+/// artifact's output. Frames are resampled to `fps` and fitted inside
+/// `width` x `height`; GIF frames are reduced to `colors` palette entries
+/// first, dithered unless `dither` is off. Remaining parameters go to the
+/// animation encoder as they would for `encode` (`repeat`, `quality`, ...).
+pub struct GifFromVideoStage {
+    format: ImageFormat,
+    start: Position,
+    end: Option<Position>,
+    duration: Option<Position>,
+    fps: f64,
+    size: (Option<u32>, Option<u32>),
+    filter: ResizeFilter,
+    /// Palette size and dithering, for GIF output.
+    palette: Option<(usize, Dither)>,
+    options: StageParameters,
+}
+
+impl GifFromVideoStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let format = match take_string(&mut params, "format")
+            .as_deref()
+            .map(str::to_ascii_lowercase)
+            .as_deref()
+        {
+            Some("gif") | None => ImageFormat::Gif,
+            Some("webp") => ImageFormat::WebP,
+            Some(other) => {
+                bail!("unsupported gif_from_video format '{other}' (expected gif or webp)")
+            }
+        };
+        let mut position = |key: &str| params.remove(key).map(|value| parse_position(&value));
+        let start = position("start")
+            .transpose()?
+            .unwrap_or(Position::Time(Duration::ZERO));
+        let end = position("end").transpose()?;
+        let duration = position("duration").transpose()?;
+        if end.is_some() && duration.is_some() {
+            bail!("gif_from_video takes 'end' or 'duration', not both");
+        }
+        let fps = match params.remove("fps") {
+            Some(value) => value
+                .as_f64()
+                .filter(|fps| *fps > 0.0 && *fps <= MAX_FPS)
+                .ok_or_else(|| {
+                    anyhow!("gif_from_video fps must be above 0 and at most {MAX_FPS}, got {value}")
+                })?,
+            None => DEFAULT_FPS,
+        };
+        let size = (
+            take_u32(&mut params, "width").filter(|width| *width > 0),
+            take_u32(&mut params, "height").filter(|height| *height > 0),
+        );
+        let filter = match take_string(&mut params, "method") {
+            Some(method) => map_filter(method.clone())
+                .ok_or_else(|| anyhow!("unknown gif_from_video method '{method}'"))?,
+            None => ResizeFilter::CatmullRom,
+        };
+        let palette = if format == ImageFormat::Gif {
+            let colors = match params.remove("colors") {
+                Some(value) => value_as_u64(&value)
+                    .filter(|colors| (2..=256).contains(colors))
+                    .ok_or_else(|| {
+                        anyhow!("gif_from_video colors must be between 2 and 256, got {value}")
+                    })? as usize,
+                None => 256,
+            };
+            let dither = parse_dither(&params)?;
+            params.remove("dither");
+            if dither == Dither::Ordered {
+                bail!("ordered dithering only applies to gray_bits output");
+            }
+            Some((colors, dither))
+        } else {
+            if params.contains_key("colors") || params.contains_key("dither") {
+                bail!("gif_from_video colors and dither only apply to gif output");
+            }
+            None
+        };
+        Ok(Self {
+            format,
+            start,
+            end,
+            duration,
+            fps,
+            size,
+            filter,
+            palette,
+            options: params,
+        })
+    }
+
+    fn extension(&self) -> &'static str {
+        format_extension(self.format)
+    }
+
+    /// The clip's start and end within a video lasting `total`.
+    fn clip(&self, total: Duration) -> Result<(Duration, Duration)> {
+        let start = self.start.resolve(total);
+        let end = match (self.end, self.duration) {
+            (Some(end), _) => end.resolve(total),
+            (None, Some(duration)) => start + duration.resolve(total),
+            (None, None) => total,
+        }
+        .min(total);
+        if end <= start {
+            bail!(
+                "gif_from_video clip from {:.3}s to {:.3}s holds no frames of the {:.3}s video",
+                start.as_secs_f64(),
+                end.as_secs_f64(),
+                total.as_secs_f64()
+            );
+        }
+        Ok((start, end))
+    }
+
+    /// Source frame and display time in milliseconds for each animation
+    /// frame. Output ticks landing on the same source frame are merged.
+    fn schedule(&self, video: &VideoStream, start: Duration, end: Duration) -> Vec<(usize, u32)> {
+        let length_ms = (end - start).as_secs_f64() * 1000.0;
+        let tick_ms = 1000.0 / self.fps;
+        let mut schedule: Vec<(usize, u32)> = Vec::new();
+        let mut tick = 0u32;
+        loop {
+            let at_ms = f64::from(tick) * tick_ms;
+            if at_ms >= length_ms {
+                break;
+            }
+            let next_ms = (at_ms + tick_ms).min(length_ms);
+            // Rounding the boundaries keeps the total exact.
+            let delay = (next_ms.round() - at_ms.round()) as u32;
+            let index = frame_at(
+                video,
+                start + Duration::from_nanos((at_ms * 1e6).round() as u64),
+            );
+            match schedule.last_mut() {
+                Some((last, total)) if *last == index => *total += delay,
+                _ => schedule.push((index, delay)),
+            }
+            tick += 1;
+        }
+        schedule
+    }
+
+    /// Output dimensions for `width` x `height` frames: fitted inside the
+    /// box, with a missing edge following the aspect ratio.
+    fn dimensions(&self, width: u32, height: u32) -> (u32, u32) {
+        let ratio = match self.size {
+            (None, None) => return (width, height),
+            (Some(box_width), None) => f64::from(box_width) / f64::from(width),
+            (None, Some(box_height)) => f64::from(box_height) / f64::from(height),
+            (Some(box_width), Some(box_height)) => (f64::from(box_width) / f64::from(width))
+                .min(f64::from(box_height) / f64::from(height)),
+        };
+        (
+            ((f64::from(width) * ratio).round() as u32).max(1),
+            ((f64::from(height) * ratio).round() as u32).max(1),
+        )
+    }
+
+    fn render(
+        &self,
+        video: &VideoStream,
+        schedule: &[(usize, u32)],
+    ) -> Result<Vec<AnimationFrame>> {
+        let mut frames = Vec::with_capacity(schedule.len());
+        for &(index, delay_ms) in schedule {
+            let frame = &video.frames[index];
+            let rgb = frame
+                .to_rgb(video.color_space)
+                .with_context(|| format!("Failed to convert frame {index} to RGB"))?;
+            let (width, height) = self.dimensions(frame.width, frame.height);
+            let mut image = DynamicImage::ImageRgb8(rgb);
+            if (width, height) != (frame.width, frame.height) {
+                image = image.resize_exact(width, height, self.filter);
+            }
+            let mut image = image.into_rgba8();
+            if let Some((colors, dither)) = self.palette {
+                image = quantize::quantize(&image, colors, dither)?.to_rgba();
+            }
+            frames.push(AnimationFrame { image, delay_ms });
+        }
+        Ok(frames)
+    }
+}
+
+impl Stage for GifFromVideoStage {
+    fn name(&self) -> &'static str {
+        "gif_from_video"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let video = artifact
+            .media()
+            .video
+            .as_ref()
+            .filter(|video| !video.frames.is_empty())
+            .ok_or_else(|| anyhow!("gif_from_video stage requires decoded video frames"))?;
+        let (start, end) = self.clip(stream_duration(video))?;
+        let schedule = self.schedule(video, start, end);
+        let first = &video.frames[schedule[0].0];
+        let (width, height) = self.dimensions(first.width, first.height);
+
+        artifact
+            .metadata
+            .insert("image.width".to_string(), json!(width));
+        artifact
+            .metadata
+            .insert("image.height".to_string(), json!(height));
+        let output_path = ctx.outputs.claim(
+            ctx.output
+                .resolve(&artifact.stem, self.extension(), &artifact.metadata),
+            &artifact.input_path,
+            &mut artifact.metadata,
+        )?;
+        if keep_existing_output(artifact, ctx, &output_path) {
+            return Ok(());
+        }
+
+        let frames = self.render(
+            artifact.media().video.as_ref().expect("checked above"),
+            &schedule,
+        )?;
+        let mut encoded = Vec::new();
+        match self.format {
+            ImageFormat::Gif => encode_animated_gif(&frames, &self.options, &mut encoded)?,
+            _ => encode_animated_webp(&frames, &self.options, &mut encoded)?,
+        }
+        if let Some(parent) = output_path.parent() {
+            fs::create_dir_all(parent).with_context(|| {
+                format!("Failed to create output directory: {}", parent.display())
+            })?;
+        }
+        let mut sink = FileSink::create(&output_path)?;
+        sink.write_all(&encoded)
+            .with_context(|| format!("Failed to write output file: {}", output_path.display()))?;
+        let summary = sink.finish()?;
+
+        artifact.metadata.insert(
+            "output_path".to_string(),
+            Value::String(output_path.to_string_lossy().to_string()),
+        );
+        artifact.metadata.insert(
+            "output.format".to_string(),
+            Value::String(self.extension().to_string()),
+        );
+        artifact
+            .metadata
+            .insert("output.frame_count".into(), json!(frames.len()));
+        artifact
+            .metadata
+            .insert("output.size_bytes".to_string(), json!(summary.size_bytes));
+        artifact
+            .metadata
+            .insert("output.sha256".to_string(), Value::String(summary.sha256));
+        artifact
+            .metadata
+            .insert("gif_from_video.start".into(), json!(start.as_secs_f64()));
+        artifact
+            .metadata
+            .insert("gif_from_video.end".into(), json!(end.as_secs_f64()));
+        artifact
+            .metadata
+            .insert("gif_from_video.fps".into(), json!(self.fps));
+        Ok(())
+    }
+
+    fn plan(&self, artifact: &mut Artifact, ctx: &PipelineContext) -> Result<()> {
+        let output_path = ctx.outputs.claim(
+            ctx.output
+                .resolve(&artifact.stem, self.extension(), &artifact.metadata),
+            &artifact.input_path,
+            &mut artifact.metadata,
+        )?;
+        if let Some(reason) = ctx
+            .overwrite
+            .keep_reason(&artifact.input_path, &output_path)
+        {
+            artifact.metadata.insert(
+                overwrite::SKIPPED_KEY.to_string(),
+                Value::String(reason.to_string()),
+            );
+        }
+        artifact.metadata.insert(
+            "output_path".to_string(),
+            Value::String(output_path.to_string_lossy().to_string()),
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stages::from_json;
+    use crate::video::{
+        ColorSpace, FramePlanes, FrameRate, HdrMetadata, PixelFormat, TransferFunction, VideoCodec,
+        VideoFrame,
+    };
+
+    /// Two seconds at 25 fps.
+    fn video() -> VideoStream {
+        let frames = (0..50)
+            .map(|index| VideoFrame {
+                width: 64,
+                height: 36,
+                pixel_format: PixelFormat::Yuv420,
+                data: FramePlanes::ExternalHandle,
+                timestamp: Duration::from_millis(index * 40),
+                duration: Duration::from_millis(40),
+                keyframe: index == 0,
+            })
+            .collect();
+        VideoStream {
+            codec: VideoCodec::Raw,
+            frame_rate: FrameRate::Constant {
+                numerator: 25,
+                denominator: 1,
+            },
+            frames,
+            color_space: ColorSpace::Bt709,
+            transfer: TransferFunction::Sdr,
+            rotation: 0,
+            hdr: HdrMetadata::default(),
+        }
+    }
+
+    #[test]
+    fn resamples_the_trimmed_clip() {
+        let video = video();
+        let total = stream_duration(&video);
+        let gif = from_json(
+            GifFromVideoStage::from_params,
+            json!({ "start": 0.5, "duration": "50%", "fps": 3 }),
+        )
+        .unwrap();
+        let (start, end) = gif.clip(total).unwrap();
+        assert_eq!(
+            (start, end),
+            (Duration::from_millis(500), Duration::from_millis(1500))
+        );
+        let schedule = gif.schedule(&video, start, end);
+        // Ticks at 0.5s, 0.833s and 1.167s; the delays add up to the clip.
+        assert_eq!(schedule, vec![(12, 333), (20, 334), (29, 333)]);
+
+        // Faster than the source: repeated frames merge into longer delays.
+        let fast = from_json(
+            GifFromVideoStage::from_params,
+            json!({ "fps": 50, "end": 0.08 }),
+        )
+        .unwrap();
+        let (start, end) = fast.clip(total).unwrap();
+        assert_eq!(fast.schedule(&video, start, end), vec![(0, 40), (1, 40)]);
+
+        assert!(
+            from_json(
+                GifFromVideoStage::from_params,
+                json!({ "start": "90%", "end": "10%" })
+            )
+            .unwrap()
+            .clip(total)
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn validates_settings_and_fits_the_box() {
+        let gif = from_json(GifFromVideoStage::from_params, json!({ "width": 32 })).unwrap();
+        assert_eq!(gif.dimensions(64, 36), (32, 18));
+        let gif = from_json(
+            GifFromVideoStage::from_params,
+            json!({ "width": 32, "height": 9 }),
+        )
+        .unwrap();
+        assert_eq!(gif.dimensions(64, 36), (16, 9));
+        assert_eq!(
+            from_json(GifFromVideoStage::from_params, json!({}))
+                .unwrap()
+                .dimensions(64, 36),
+            (64, 36)
+        );
+        assert_eq!(
+            from_json(
+                GifFromVideoStage::from_params,
+                json!({ "colors": 16, "dither": "none" })
+            )
+            .unwrap()
+            .palette,
+            Some((16, Dither::None))
+        );
+
+        assert!(from_json(GifFromVideoStage::from_params, json!({ "format": "apng" })).is_err());
+        assert!(from_json(GifFromVideoStage::from_params, json!({ "fps": 0 })).is_err());
+        assert!(from_json(GifFromVideoStage::from_params, json!({ "fps": 60 })).is_err());
+        assert!(
+            from_json(
+                GifFromVideoStage::from_params,
+                json!({ "end": 2, "duration": 1 })
+            )
+            .is_err()
+        );
+        assert!(from_json(GifFromVideoStage::from_params, json!({ "colors": 1 })).is_err());
+        assert!(
+            from_json(
+                GifFromVideoStage::from_params,
+                json!({ "dither": "ordered" })
+            )
+            .is_err()
+        );
+        assert!(
+            from_json(
+                GifFromVideoStage::from_params,
+                json!({ "format": "webp", "colors": 64 })
+            )
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,722 @@
+//! Lossless JPEG rewrites: dropping segments the caller does not want and
+//! re-coding sequential Huffman scans with tables built for the image (what
+//! `jpegtran -optimize` does). Coefficients are never decoded to pixels, so
+//! the result decodes to exactly the same image.
+
+use anyhow::{Result, bail};
+use tracing::debug;
+
+const SOI: u8 = 0xD8;
+const EOI: u8 = 0xD9;
+const SOS: u8 = 0xDA;
+const DHT: u8 = 0xC4;
+const DRI: u8 = 0xDD;
+const RST0: u8 = 0xD0;
+/// Baseline and extended sequential Huffman frames; other frame types keep
+/// their entropy-coded data as it is.
+const SEQUENTIAL_FRAMES: [u8; 2] = [0xC0, 0xC1];
+
+/// Rewrites `data`, keeping the marker segments for which `keep(marker,
+/// body)` holds (it is only asked about `APPn` and `COM` segments) and, with
+/// `huffman`, re-coding the scan with optimal tables when the file is a
+/// single-scan sequential JPEG.
+pub(super) fn optimize(
+    data: &[u8],
+    keep: impl Fn(u8, &[u8]) -> bool,
+    huffman: bool,
+) -> Result<Vec<u8>> {
+    let parts = parse(data)?;
+    let recoded = if huffman {
+        match recode(&parts) {
+            Ok(recoded) => recoded,
+            Err(err) => {
+                debug!(error = %format!("{err:#}"), "Keeping the JPEG's Huffman tables");
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&[0xFF, SOI]);
+    for part in &parts {
+        match *part {
+            Part::Segment { marker, body } => {
+                let droppable = matches!(marker, 0xE0..=0xEF | 0xFE);
+                if (droppable && !keep(marker, body)) || (marker == DHT && recoded.is_some()) {
+                    continue;
+                }
+                write_segment(&mut out, marker, body)?;
+            }
+            Part::Scan { header, data } => match &recoded {
+                Some((tables, scan)) => {
+                    write_segment(&mut out, DHT, tables)?;
+                    write_segment(&mut out, SOS, header)?;
+                    out.extend_from_slice(scan);
+                }
+                None => {
+                    write_segment(&mut out, SOS, header)?;
+                    out.extend_from_slice(data);
+                }
+            },
+        }
+    }
+    out.extend_from_slice(&[0xFF, EOI]);
+    Ok(out)
+}
+
+enum Part<'a> {
+    /// A marker segment; `body` excludes the length field.
+    Segment { marker: u8, body: &'a [u8] },
+    /// A start-of-scan header and the entropy-coded data after it.
+    Scan { header: &'a [u8], data: &'a [u8] },
+}
+
+/// Splits the file between `SOI` and `EOI`; anything after `EOI` is dropped.
+fn parse(data: &[u8]) -> Result<Vec<Part<'_>>> {
+    if !data.starts_with(&[0xFF, SOI]) {
+        bail!("Not a JPEG file");
+    }
+    let mut parts = Vec::new();
+    let mut pos = 2;
+    loop {
+        // Markers may be preceded by any number of 0xFF fill bytes.
+        while data.get(pos) == Some(&0xFF) && data.get(pos + 1) == Some(&0xFF) {
+            pos += 1;
+        }
+        let (Some(&0xFF), Some(&marker)) = (data.get(pos), data.get(pos + 1)) else {
+            bail!("JPEG is truncated or malformed at byte {pos}");
+        };
+        pos += 2;
+        if marker == EOI {
+            return Ok(parts);
+        }
+        let length = match data.get(pos..pos + 2) {
+            Some(length) => u16::from_be_bytes([length[0], length[1]]) as usize,
+            None => bail!("JPEG is truncated inside a segment"),
+        };
+        if length < 2 || pos + length > data.len() {
+            bail!("JPEG segment {marker:#04x} has an invalid length");
+        }
+        let body = &data[pos + 2..pos + length];
+        pos += length;
+        if marker != SOS {
+            parts.push(Part::Segment { marker, body });
+            continue;
+        }
+        let start = pos;
+        while pos < data.len() {
+            match (data[pos], data.get(pos + 1)) {
+                (0xFF, Some(0x00 | RST0..=0xD7)) => pos += 2,
+                (0xFF, Some(0xFF)) => pos += 1,
+                (0xFF, _) => break,
+                _ => pos += 1,
+            }
+        }
+        // A run of fill bytes can end right before the next marker.
+        let mut end = pos.min(data.len());
+        while end > start && data[end - 1] == 0xFF && data.get(end) == Some(&0xFF) {
+            end -= 1;
+        }
+        parts.push(Part::Scan {
+            header: body,
+            data: &data[start..end],
+        });
+        pos = end;
+    }
+}
+
+fn write_segment(out: &mut Vec<u8>, marker: u8, body: &[u8]) -> Result<()> {
+    let Ok(length) = u16::try_from(body.len() + 2) else {
+        bail!("JPEG segment {marker:#04x} is too long");
+    };
+    out.extend_from_slice(&[0xFF, marker]);
+    out.extend_from_slice(&length.to_be_bytes());
+    out.extend_from_slice(body);
+    Ok(())
+}
+
+struct Component {
+    id: u8,
+    h: usize,
+    v: usize,
+}
+
+struct ScanComponent {
+    /// Blocks per MCU across and down (1x1 in a single-component scan).
+    h: usize,
+    v: usize,
+    dc: usize,
+    ac: usize,
+}
+
+/// One Huffman-coded symbol and the raw bits after it; `table` 0-3 are DC
+/// tables, 4-7 AC tables, and [`RESTART`] marks a restart marker.
+#[derive(Clone, Copy)]
+struct Token {
+    table: u8,
+    symbol: u8,
+    extra_len: u8,
+    extra: u16,
+}
+
+const RESTART: u8 = u8::MAX;
+
+/// The `DHT` body and entropy-coded data of the re-coded scan, or `None`
+/// when the file is not a single-scan sequential Huffman JPEG.
+fn recode(parts: &[Part<'_>]) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let mut tables: [Option<HuffmanDecoder>; 8] = Default::default();
+    let mut frame = None;
+    let mut restart_interval = 0;
+    let mut scan = None;
+    for part in parts {
+        match *part {
+            Part::Segment { marker, body } => match marker {
+                DHT => read_tables(body, &mut tables)?,
+                DRI if scan.is_none() => {
+                    let [high, low, ..] = body else {
+                        bail!("Malformed DRI segment");
+                    };
+                    restart_interval = u16::from_be_bytes([*high, *low]) as usize;
+                }
+                0xC0..=0xCF if !matches!(marker, DHT | 0xC8 | 0xCC) => {
+                    if !SEQUENTIAL_FRAMES.contains(&marker) {
+                        return Ok(None);
+                    }
+                    frame = Some(read_frame(body)?);
+                }
+                _ => {}
+            },
+            Part::Scan { header, data } => {
+                if scan.is_some() {
+                    return Ok(None);
+                }
+                scan = Some((header, data));
+            }
+        }
+    }
+    let (Some((width, height, components)), Some((header, data))) = (frame, scan) else {
+        return Ok(None);
+    };
+
+    let &[count, ref rest @ ..] = header else {
+        bail!("Malformed SOS segment");
+    };
+    let count = count as usize;
+    if count == 0 || rest.len() < count * 2 + 3 {
+        bail!("Malformed SOS segment");
+    }
+    if rest[count * 2..count * 2 + 3] != [0, 63, 0] {
+        bail!("Scan is not a sequential scan");
+    }
+    let h_max = components.iter().map(|c| c.h).max().unwrap_or(1);
+    let v_max = components.iter().map(|c| c.v).max().unwrap_or(1);
+    let mut scan_components = Vec::with_capacity(count);
+    for pair in rest[..count * 2].chunks(2) {
+        let Some(component) = components.iter().find(|c| c.id == pair[0]) else {
+            bail!("Scan names unknown component {}", pair[0]);
+        };
+        let (dc, ac) = ((pair[1] >> 4) as usize, 4 + (pair[1] & 0x0F) as usize);
+        if dc > 3 || ac > 7 || tables[dc].is_none() || tables[ac].is_none() {
+            bail!("Scan uses an undefined Huffman table");
+        }
+        scan_components.push((component, dc, ac));
+    }
+    let (units, scan_components): (usize, Vec<ScanComponent>) = if count == 1 {
+        let (component, dc, ac) = scan_components[0];
+        let across = (width * component.h).div_ceil(h_max).div_ceil(8);
+        let down = (height * component.v).div_ceil(v_max).div_ceil(8);
+        let single = ScanComponent { h: 1, v: 1, dc, ac };
+        (across * down, vec![single])
+    } else {
+        let across = width.div_ceil(8 * h_max);
+        let down = height.div_ceil(8 * v_max);
+        let interleaved = scan_components
+            .into_iter()
+            .map(|(component, dc, ac)| ScanComponent {
+                h: component.h,
+                v: component.v,
+                dc,
+                ac,
+            })
+            .collect();
+        (across * down, interleaved)
+    };
+
+    let mut reader = BitReader::new(data);
+    let mut tokens = Vec::new();
+    for unit in 0..units {
+        if restart_interval > 0 && unit > 0 && unit % restart_interval == 0 {
+            reader.restart()?;
+            tokens.push(Token {
+                table: RESTART,
+                symbol: 0,
+                extra_len: 0,
+                extra: 0,
+            });
+        }
+        for component in &scan_components {
+            for _ in 0..component.h * component.v {
+                read_block(&mut reader, &tables, component, &mut tokens)?;
+            }
+        }
+    }
+
+    let mut frequencies = [[0u32; 256]; 8];
+    for token in tokens.iter().filter(|token| token.table != RESTART) {
+        frequencies[token.table as usize][token.symbol as usize] += 1;
+    }
+    let mut segment = Vec::new();
+    let mut encoders: [Option<HuffmanEncoder>; 8] = Default::default();
+    for (index, frequencies) in frequencies.iter().enumerate() {
+        if frequencies.iter().all(|&count| count == 0) {
+            continue;
+        }
+        let (counts, values) = optimal_table(frequencies);
+        let class = if index < 4 { 0 } else { 1 };
+        segment.push((class << 4) | (index % 4) as u8);
+        segment.extend_from_slice(&counts);
+        segment.extend_from_slice(&values);
+        encoders[index] = Some(HuffmanEncoder::new(&counts, &values));
+    }
+
+    let mut writer = BitWriter::default();
+    let mut restarts = 0u8;
+    for token in &tokens {
+        if token.table == RESTART {
+            writer.flush();
+            writer.out.extend_from_slice(&[0xFF, RST0 + restarts % 8]);
+            restarts = restarts.wrapping_add(1);
+            continue;
+        }
+        let Some(encoder) = &encoders[token.table as usize] else {
+            bail!("Huffman table {} was not built", token.table);
+        };
+        let (code, length) = encoder.codes[token.symbol as usize];
+        writer.put(code as u32, length);
+        writer.put(token.extra as u32, token.extra_len);
+    }
+    writer.flush();
+    Ok(Some((segment, writer.out)))
+}
+
+fn read_frame(body: &[u8]) -> Result<(usize, usize, Vec<Component>)> {
+    let &[
+        _precision,
+        y_high,
+        y_low,
+        x_high,
+        x_low,
+        count,
+        ref rest @ ..,
+    ] = body
+    else {
+        bail!("Malformed SOF segment");
+    };
+    let height = u16::from_be_bytes([y_high, y_low]) as usize;
+    let width = u16::from_be_bytes([x_high, x_low]) as usize;
+    if width == 0 || height == 0 {
+        bail!("Frame dimensions are missing");
+    }
+    if rest.len() < count as usize * 3 {
+        bail!("Malformed SOF segment");
+    }
+    let components = rest
+        .chunks(3)
+        .take(count as usize)
+        .map(|component| Component {
+            id: component[0],
+            h: ((component[1] >> 4) as usize).max(1),
+            v: ((component[1] & 0x0F) as usize).max(1),
+        })
+        .collect();
+    Ok((width, height, components))
+}
+
+fn read_tables(mut body: &[u8], tables: &mut [Option<HuffmanDecoder>; 8]) -> Result<()> {
+    while let [class_id, rest @ ..] = body {
+        let (class, id) = ((class_id >> 4) as usize, (class_id & 0x0F) as usize);
+        if class > 1 || id > 3 || rest.len() < 16 {
+            bail!("Malformed DHT segment");
+        }
+        let mut counts = [0u8; 16];
+        counts.copy_from_slice(&rest[..16]);
+        let total: usize = counts.iter().map(|&count| count as usize).sum();
+        if rest.len() < 16 + total {
+            bail!("Malformed DHT segment");
+        }
+        tables[class * 4 + id] = Some(HuffmanDecoder::new(&counts, &rest[16..16 + total]));
+        body = &rest[16 + total..];
+    }
+    Ok(())
+}
+
+fn read_block(
+    reader: &mut BitReader<'_>,
+    tables: &[Option<HuffmanDecoder>; 8],
+    component: &ScanComponent,
+    tokens: &mut Vec<Token>,
+) -> Result<()> {
+    let (Some(dc), Some(ac)) = (&tables[component.dc], &tables[component.ac]) else {
+        bail!("Scan uses an undefined Huffman table");
+    };
+    let size = dc.decode(reader)?;
+    if size > 16 {
+        bail!("DC difference of {size} bits is out of range");
+    }
+    tokens.push(Token {
+        table: component.dc as u8,
+        symbol: size,
+        extra_len: size,
+        extra: reader.bits(size)?,
+    });
+    let mut index = 1;
+    while index < 64 {
+        let symbol = ac.decode(reader)?;
+        let (run, size) = (symbol >> 4, symbol & 0x0F);
+        tokens.push(Token {
+            table: component.ac as u8,
+            symbol,
+            extra_len: size,
+            extra: reader.bits(size)?,
+        });
+        match (run, size) {
+            (15, 0) => index += 16,
+            (_, 0) => break,
+            _ => index += run as usize + 1,
+        }
+        if index > 64 {
+            bail!("Coefficient index ran past the end of a block");
+        }
+    }
+    Ok(())
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    byte: u8,
+    left: u8,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0,
+            byte: 0,
+            left: 0,
+        }
+    }
+
+    fn bit(&mut self) -> Result<u16> {
+        if self.left == 0 {
+            let Some(&byte) = self.data.get(self.pos) else {
+                bail!("Scan data ended early");
+            };
+            if byte == 0xFF {
+                if self.data.get(self.pos + 1) != Some(&0x00) {
+                    bail!("Unexpected marker inside scan data");
+                }
+                self.pos += 1;
+            }
+            self.pos += 1;
+            self.byte = byte;
+            self.left = 8;
+        }
+        self.left -= 1;
+        Ok(((self.byte >> self.left) & 1) as u16)
+    }
+
+    fn bits(&mut self, count: u8) -> Result<u16> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = (value << 1) | self.bit()?;
+        }
+        Ok(value)
+    }
+
+    /// Drops the padding bits of the current byte and steps over the next
+    /// restart marker.
+    fn restart(&mut self) -> Result<()> {
+        self.left = 0;
+        while self.data.get(self.pos) == Some(&0xFF) && self.data.get(self.pos + 1) == Some(&0xFF) {
+            self.pos += 1;
+        }
+        match (self.data.get(self.pos), self.data.get(self.pos + 1)) {
+            (Some(0xFF), Some(RST0..=0xD7)) => {
+                self.pos += 2;
+                Ok(())
+            }
+            _ => bail!("Missing restart marker"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    buffer: u32,
+    filled: u8,
+}
+
+impl BitWriter {
+    fn put(&mut self, value: u32, length: u8) {
+        for shift in (0..length).rev() {
+            self.buffer = (self.buffer << 1) | ((value >> shift) & 1);
+            self.filled += 1;
+            if self.filled == 8 {
+                self.emit();
+            }
+        }
+    }
+
+    fn emit(&mut self) {
+        let byte = self.buffer as u8;
+        self.out.push(byte);
+        if byte == 0xFF {
+            self.out.push(0x00);
+        }
+        self.buffer = 0;
+        self.filled = 0;
+    }
+
+    /// Pads the last byte with one bits, as the standard asks.
+    fn flush(&mut self) {
+        if self.filled > 0 {
+            let padding = 8 - self.filled;
+            self.put((1 << padding) - 1, padding);
+        }
+    }
+}
+
+struct HuffmanDecoder {
+    /// Largest code of each length (index 1-16), -1 when there is none.
+    max_code: [i32; 17],
+    min_code: [i32; 17],
+    first_value: [usize; 17],
+    values: Vec<u8>,
+}
+
+impl HuffmanDecoder {
+    fn new(counts: &[u8; 16], values: &[u8]) -> Self {
+        let mut decoder = Self {
+            max_code: [-1; 17],
+            min_code: [0; 17],
+            first_value: [0; 17],
+            values: values.to_vec(),
+        };
+        let (mut code, mut index) = (0i32, 0usize);
+        for length in 1..=16 {
+            let count = counts[length - 1] as usize;
+            decoder.first_value[length] = index;
+            decoder.min_code[length] = code;
+            if count > 0 {
+                decoder.max_code[length] = code + count as i32 - 1;
+            }
+            code = (code + count as i32) << 1;
+            index += count;
+        }
+        decoder
+    }
+
+    fn decode(&self, reader: &mut BitReader<'_>) -> Result<u8> {
+        let mut code = 0i32;
+        for length in 1..=16 {
+            code = (code << 1) | reader.bit()? as i32;
+            if code <= self.max_code[length] {
+                let index = self.first_value[length] + (code - self.min_code[length]) as usize;
+                return match self.values.get(index) {
+                    Some(&value) => Ok(value),
+                    None => bail!("Huffman code points past the table"),
+                };
+            }
+        }
+        bail!("Invalid Huffman code in scan data")
+    }
+}
+
+struct HuffmanEncoder {
+    /// Code and length of every symbol.
+    codes: [(u16, u8); 256],
+}
+
+impl HuffmanEncoder {
+    fn new(counts: &[u8; 16], values: &[u8]) -> Self {
+        let mut codes = [(0, 0); 256];
+        let (mut code, mut index) = (0u16, 0usize);
+        for (length, &count) in (1..=16u8).zip(counts) {
+            for &value in &values[index..index + count as usize] {
+                codes[value as usize] = (code, length);
+                code += 1;
+            }
+            index += count as usize;
+            code <<= 1;
+        }
+        Self { codes }
+    }
+}
+
+/// Code length counts and symbols of an optimal table for `frequencies`,
+/// limited to 16 bits and never using the all-ones code (ITU T.81 K.2).
+fn optimal_table(frequencies: &[u32; 256]) -> ([u8; 16], Vec<u8>) {
+    // Symbol 256 reserves the all-ones code point.
+    let mut freq = [0u64; 257];
+    for (slot, &count) in freq.iter_mut().zip(frequencies) {
+        *slot = count as u64;
+    }
+    freq[256] = 1;
+    let mut code_size = [0usize; 257];
+    let mut others = [None::<usize>; 257];
+    loop {
+        // The least frequent symbol (the highest index among ties), then
+        // the next least frequent.
+        let mut first: Option<usize> = None;
+        for symbol in 0..257 {
+            if freq[symbol] > 0 && first.is_none_or(|first| freq[symbol] <= freq[first]) {
+                first = Some(symbol);
+            }
+        }
+        let Some(first) = first else { break };
+        let mut second: Option<usize> = None;
+        for symbol in 0..257 {
+            if freq[symbol] > 0
+                && symbol != first
+                && second.is_none_or(|second| freq[symbol] <= freq[second])
+            {
+                second = Some(symbol);
+            }
+        }
+        let Some(second) = second else { break };
+
+        freq[first] += freq[second];
+        freq[second] = 0;
+        let mut symbol = first;
+        code_size[symbol] += 1;
+        while let Some(next) = others[symbol] {
+            symbol = next;
+            code_size[symbol] += 1;
+        }
+        others[symbol] = Some(second);
+        let mut symbol = second;
+        code_size[symbol] += 1;
+        while let Some(next) = others[symbol] {
+            symbol = next;
+            code_size[symbol] += 1;
+        }
+    }
+
+    let mut bits = [0u8; 33];
+    for &size in code_size.iter().filter(|&&size| size > 0) {
+        bits[size] += 1;
+    }
+    for length in (17..=32).rev() {
+        while bits[length] > 0 {
+            let mut shorter = length - 2;
+            while bits[shorter] == 0 {
+                shorter -= 1;
+            }
+            bits[length] -= 2;
+            bits[length - 1] += 1;
+            bits[shorter + 1] += 2;
+            bits[shorter] -= 1;
+        }
+    }
+    // Give the reserved code point back.
+    let mut longest = 16;
+    while bits[longest] == 0 {
+        longest -= 1;
+    }
+    bits[longest] -= 1;
+
+    let mut counts = [0u8; 16];
+    counts.copy_from_slice(&bits[1..=16]);
+    // Shortest codes first, ties in symbol order.
+    let mut symbols: Vec<usize> = (0..256).filter(|&symbol| code_size[symbol] > 0).collect();
+    symbols.sort_by_key(|&symbol| code_size[symbol]);
+    let values = symbols.into_iter().map(|symbol| symbol as u8).collect();
+    (counts, values)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, RgbImage};
+    use jpeg_encoder::{ColorType, Encoder, SamplingFactor};
+
+    use super::*;
+
+    fn noisy(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            let noise = (x.wrapping_mul(7919) ^ y.wrapping_mul(104_729)) % 61;
+            image::Rgb([(x * 3 + noise) as u8, (y * 5) as u8, (noise * 4) as u8])
+        })
+    }
+
+    fn encode(image: &RgbImage, gray: bool, restart: u16) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut encoder = Encoder::new(&mut out, 85);
+        encoder.set_sampling_factor(SamplingFactor::F_2_2);
+        encoder.set_restart_interval(restart);
+        encoder
+            .add_app_segment(15, b"junk junk junk".to_vec())
+            .unwrap();
+        if gray {
+            let luma = DynamicImage::ImageRgb8(image.clone()).into_luma8();
+            let (width, height) = (luma.width() as u16, luma.height() as u16);
+            encoder
+                .encode(luma.as_raw(), width, height, ColorType::Luma)
+                .unwrap();
+        } else {
+            let (width, height) = (image.width() as u16, image.height() as u16);
+            encoder
+                .encode(image.as_raw(), width, height, ColorType::Rgb)
+                .unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn recoded_scans_decode_to_the_same_pixels() {
+        let image = noisy(53, 37);
+        for (gray, restart) in [(false, 0), (false, 3), (true, 0), (true, 5)] {
+            let original = encode(&image, gray, restart);
+            let optimized = optimize(&original, |marker, _| marker != 0xEF, true).unwrap();
+            assert!(
+                optimized.len() < original.len(),
+                "gray={gray} restart={restart}: {} >= {}",
+                optimized.len(),
+                original.len()
+            );
+            let before = image::load_from_memory(&original).unwrap();
+            let after = image::load_from_memory(&optimized).unwrap();
+            assert_eq!(before.to_rgb8(), after.to_rgb8());
+            assert!(!optimized.windows(4).any(|window| window == b"junk"));
+            // Already optimal tables stay put.
+            let again = optimize(&optimized, |_, _| true, true).unwrap();
+            assert_eq!(again.len(), optimized.len());
+        }
+    }
+
+    #[test]
+    fn optimal_tables_never_exceed_sixteen_bits() {
+        let mut frequencies = [0u32; 256];
+        // Fibonacci weights force a maximally skewed tree.
+        let (mut a, mut b) = (1u32, 1u32);
+        for slot in frequencies.iter_mut().take(30) {
+            *slot = a;
+            (a, b) = (b, a.saturating_add(b));
+        }
+        let (counts, values) = optimal_table(&frequencies);
+        assert_eq!(values.len(), 30);
+        let total: u32 = counts.iter().map(|&count| count as u32).sum();
+        assert_eq!(total, 30);
+        // Kraft inequality with the all-ones code left unused.
+        let kraft: f64 = counts
+            .iter()
+            .enumerate()
+            .map(|(index, &count)| count as f64 / 2f64.powi(index as i32 + 1))
+            .sum();
+        assert!(kraft < 1.0);
+    }
+}
@@ -0,0 +1,334 @@
+use std::fs;
+use std::io::{Cursor, Write};
+
+use anyhow::{Context, Result, anyhow, bail};
+use image::{DynamicImage, ImageDecoder};
+use jxl_oxide::integration::JxlDecoder;
+use serde_json::{Value, json};
+use zune_core::bit_depth::BitDepth;
+use zune_core::colorspace::ColorSpace;
+use zune_core::options::EncoderOptions;
+use zune_jpegxl::JxlSimpleEncoder;
+
+use crate::pipeline::{Artifact, PipelineContext, StageParameters};
+use crate::sink::{FileSink, OutputSink};
+
+use super::{
+    keep_existing_output, param_bool, param_f64, record_dimensions, resolve_output_path,
+    value_as_u64,
+};
+
+pub(super) const LABEL: &str = "jxl";
+/// A bare JPEG XL codestream.
+const CODESTREAM_SIGNATURE: &[u8] = &[0xFF, 0x0A];
+/// The ISOBMFF container wrapping a codestream and its metadata boxes.
+const CONTAINER_SIGNATURE: &[u8] = b"\0\0\0\x0CJXL \r\n\x87\n";
+/// libjxl's default effort.
+const DEFAULT_EFFORT: u8 = 7;
+const MAX_EFFORT: u8 = 10;
+
+pub(super) fn is_jxl_label(label: &str) -> bool {
+    label
+        .trim()
+        .trim_start_matches('.')
+        .eq_ignore_ascii_case(LABEL)
+}
+
+pub(super) fn is_jxl(data: &[u8]) -> bool {
+    data.starts_with(CODESTREAM_SIGNATURE) || data.starts_with(CONTAINER_SIGNATURE)
+}
+
+/// Whether decode should read the artifact as JPEG XL. image has no JPEG XL
+/// support, so the checks mirror `infer_format`: hint, then the label set by
+/// an earlier stage, then the extension and finally the file signature.
+pub(super) fn detect(hint: Option<&str>, artifact: &Artifact) -> bool {
+    match hint {
+        Some(hint) => is_jxl_label(hint),
+        None => {
+            artifact.format.as_deref().is_some_and(is_jxl_label)
+                || artifact
+                    .input_path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .is_some_and(is_jxl_label)
+                || is_jxl(&artifact.data)
+        }
+    }
+}
+
+/// Whether encode should write JPEG XL: asked for, or kept from a JPEG XL
+/// input when no `format` is given.
+pub(super) fn targets(hint: Option<&str>, artifact: &Artifact) -> bool {
+    match hint {
+        Some(hint) => is_jxl_label(hint),
+        None => artifact.format.as_deref().is_some_and(is_jxl_label),
+    }
+}
+
+fn decoder(data: &[u8]) -> Result<JxlDecoder<Cursor<&[u8]>>> {
+    JxlDecoder::new(Cursor::new(data)).context("Failed to read JPEG XL header")
+}
+
+pub(super) fn decode(data: &[u8]) -> Result<DynamicImage> {
+    DynamicImage::from_decoder(decoder(data)?).context("Failed to decode image as JPEG XL")
+}
+
+pub(super) fn dimensions(data: &[u8]) -> Result<(u32, u32)> {
+    Ok(decoder(data)?.dimensions())
+}
+
+/// Encode options for `format: jxl`. Lossless output is the default; a
+/// `quality` or `lossless: false` switches to lossy VarDCT, which needs the
+/// `jxl-lossy` cargo feature.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) struct JxlOptions {
+    lossless: bool,
+    /// 0-100, mapped onto a butteraugli distance for lossy output.
+    quality: f32,
+    /// 1 (fastest) to 10 (smallest), as in `cjxl`.
+    effort: u8,
+}
+
+impl JxlOptions {
+    pub(super) fn from_params(options: &StageParameters) -> Result<Self> {
+        let quality = param_f64(options, "quality");
+        if let Some(quality) = quality
+            && !(0.0..=100.0).contains(&quality)
+        {
+            bail!("jxl quality must be between 0 and 100, got {quality}");
+        }
+        let lossless = param_bool(options, "lossless").unwrap_or(quality.is_none());
+        let effort = match options.get("effort") {
+            Some(value) => value_as_u64(value)
+                .and_then(|effort| u8::try_from(effort).ok())
+                .filter(|effort| (1..=MAX_EFFORT).contains(effort))
+                .ok_or_else(|| anyhow!("jxl effort must be between 1 and 10, got {value}"))?,
+            None => DEFAULT_EFFORT,
+        };
+        if !lossless && !cfg!(feature = "jxl-lossy") {
+            bail!("lossy JPEG XL output requires building with the jxl-lossy feature");
+        }
+        Ok(Self {
+            lossless,
+            quality: quality.unwrap_or(90.0) as f32,
+            effort,
+        })
+    }
+
+    pub(super) fn encode(&self, image: &DynamicImage, out: &mut dyn Write) -> Result<()> {
+        let image = normalize(image);
+        let (width, height) = (image.width(), image.height());
+        let layout = Layout::of(&image);
+        if self.lossless {
+            // zune-jpegxl is libjxl's fast lossless encoder, whose effort
+            // only decides how much of the image the histograms sample.
+            let options = EncoderOptions::new(
+                width as usize,
+                height as usize,
+                layout.colorspace,
+                layout.depth,
+            )
+            .set_effort(((u32::from(self.effort) - 1) * 127 / 9) as u8);
+            let mut encoded = Vec::new();
+            JxlSimpleEncoder::new(image.as_bytes(), options)
+                .encode(&mut encoded)
+                .map_err(|err| anyhow!("JPEG XL encode failed: {err:?}"))?;
+            out.write_all(&encoded).context("JPEG XL encode failed")?;
+            return Ok(());
+        }
+        self.encode_lossy(&image, layout, out)
+    }
+
+    #[cfg(feature = "jxl-lossy")]
+    fn encode_lossy(
+        &self,
+        image: &DynamicImage,
+        layout: Layout,
+        out: &mut dyn Write,
+    ) -> Result<()> {
+        use jxl_encoder::{LossyConfig, PixelLayout, quality_to_distance};
+
+        let pixels = match (layout.colorspace, layout.depth) {
+            (ColorSpace::Luma, BitDepth::Eight) => PixelLayout::Gray8,
+            (ColorSpace::LumaA, BitDepth::Eight) => PixelLayout::GrayAlpha8,
+            (ColorSpace::RGB, BitDepth::Eight) => PixelLayout::Rgb8,
+            (ColorSpace::RGBA, BitDepth::Eight) => PixelLayout::Rgba8,
+            (ColorSpace::Luma, _) => PixelLayout::Gray16,
+            (ColorSpace::LumaA, _) => PixelLayout::GrayAlpha16,
+            (ColorSpace::RGB, _) => PixelLayout::Rgb16,
+            _ => PixelLayout::Rgba16,
+        };
+        let encoded = LossyConfig::new(quality_to_distance(self.quality))
+            .with_effort(self.effort)
+            .encode(image.as_bytes(), image.width(), image.height(), pixels)
+            .map_err(|err| anyhow!("JPEG XL encode failed: {err}"))?;
+        out.write_all(&encoded).context("JPEG XL encode failed")?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "jxl-lossy"))]
+    fn encode_lossy(
+        &self,
+        _image: &DynamicImage,
+        _layout: Layout,
+        _out: &mut dyn Write,
+    ) -> Result<()> {
+        bail!("lossy JPEG XL output requires building with the jxl-lossy feature")
+    }
+
+    /// Writes the artifact's image to its output path, following the same
+    /// overwrite, verification and metadata rules as other formats.
+    pub(super) fn write(
+        &self,
+        artifact: &mut Artifact,
+        ctx: &PipelineContext,
+        extension: &str,
+        verify: bool,
+    ) -> Result<()> {
+        let image = artifact
+            .image
+            .clone()
+            .ok_or_else(|| anyhow!("encode stage requires a decoded image"))?;
+        artifact.set_format(LABEL);
+        let resolved = ctx.outputs.claim(
+            resolve_output_path(&ctx.output, artifact, extension),
+            &artifact.input_path,
+            &mut artifact.metadata,
+        )?;
+        artifact.metadata.insert(
+            "output.extension".to_string(),
+            Value::String(extension.to_string()),
+        );
+        artifact
+            .metadata
+            .insert("output.format".to_string(), Value::String(LABEL.into()));
+        if keep_existing_output(artifact, ctx, &resolved) {
+            return Ok(());
+        }
+        if let Some(parent) = resolved.parent() {
+            fs::create_dir_all(parent).with_context(|| {
+                format!("Failed to create output directory: {}", parent.display())
+            })?;
+        }
+        let mut buffer = Vec::new();
+        self.encode(&image, &mut buffer)?;
+        let mut sink = FileSink::create(&resolved)?;
+        sink.write_all(&buffer)
+            .with_context(|| format!("Failed to write output file: {}", resolved.display()))?;
+        let summary = sink.finish()?;
+
+        artifact
+            .metadata
+            .insert("output.verified".into(), Value::Bool(verify));
+        if verify {
+            let decoded = decode(&buffer)?;
+            artifact
+                .metadata
+                .insert("output.decode_supported".into(), Value::Bool(true));
+            record_dimensions(artifact, "image", &decoded);
+            artifact.set_image(decoded);
+        }
+        artifact.replace_data(buffer);
+        for key in ["output.streamed", "output.passthrough"] {
+            artifact.metadata.insert(key.into(), Value::Bool(false));
+        }
+        artifact.metadata.insert(
+            "output_path".to_string(),
+            Value::String(resolved.to_string_lossy().to_string()),
+        );
+        artifact
+            .metadata
+            .insert("output.size_bytes".to_string(), json!(summary.size_bytes));
+        artifact
+            .metadata
+            .insert("output.sha256".to_string(), Value::String(summary.sha256));
+        artifact
+            .metadata
+            .insert("output.encoder.lossless".into(), Value::Bool(self.lossless));
+        artifact
+            .metadata
+            .insert("output.encoder.effort".into(), json!(self.effort));
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Layout {
+    colorspace: ColorSpace,
+    depth: BitDepth,
+}
+
+impl Layout {
+    fn of(image: &DynamicImage) -> Self {
+        let (colorspace, depth) = match image {
+            DynamicImage::ImageLuma8(_) => (ColorSpace::Luma, BitDepth::Eight),
+            DynamicImage::ImageLumaA8(_) => (ColorSpace::LumaA, BitDepth::Eight),
+            DynamicImage::ImageRgb8(_) => (ColorSpace::RGB, BitDepth::Eight),
+            DynamicImage::ImageLuma16(_) => (ColorSpace::Luma, BitDepth::Sixteen),
+            DynamicImage::ImageLumaA16(_) => (ColorSpace::LumaA, BitDepth::Sixteen),
+            DynamicImage::ImageRgb16(_) => (ColorSpace::RGB, BitDepth::Sixteen),
+            DynamicImage::ImageRgba16(_) => (ColorSpace::RGBA, BitDepth::Sixteen),
+            _ => (ColorSpace::RGBA, BitDepth::Eight),
+        };
+        Self { colorspace, depth }
+    }
+}
+
+/// Float images are stored as 16-bit; everything else keeps its layout.
+fn normalize(image: &DynamicImage) -> std::borrow::Cow<'_, DynamicImage> {
+    match image {
+        DynamicImage::ImageRgb32F(_) => {
+            std::borrow::Cow::Owned(DynamicImage::ImageRgb16(image.to_rgb16()))
+        }
+        DynamicImage::ImageRgba32F(_) => {
+            std::borrow::Cow::Owned(DynamicImage::ImageRgba16(image.to_rgba16()))
+        }
+        DynamicImage::ImageLuma8(_)
+        | DynamicImage::ImageLumaA8(_)
+        | DynamicImage::ImageRgb8(_)
+        | DynamicImage::ImageRgba8(_)
+        | DynamicImage::ImageLuma16(_)
+        | DynamicImage::ImageLumaA16(_)
+        | DynamicImage::ImageRgb16(_)
+        | DynamicImage::ImageRgba16(_) => std::borrow::Cow::Borrowed(image),
+        _ => std::borrow::Cow::Owned(DynamicImage::ImageRgba8(image.to_rgba8())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+    use crate::stages::json_params;
+
+    #[test]
+    fn lossless_round_trip() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(33, 17, |x, y| {
+            Rgb([(x * 7) as u8, (y * 13) as u8, ((x ^ y) * 5) as u8])
+        }));
+        let lossless = JxlOptions::from_params(&json_params(json!({ "effort": 3 }))).unwrap();
+        let mut encoded = Vec::new();
+        lossless.encode(&image, &mut encoded).unwrap();
+        assert!(is_jxl(&encoded));
+        assert_eq!(dimensions(&encoded).unwrap(), (33, 17));
+        assert_eq!(decode(&encoded).unwrap().to_rgb8(), image.to_rgb8());
+
+        assert!(JxlOptions::from_params(&json_params(json!({ "effort": 0 }))).is_err());
+        assert!(JxlOptions::from_params(&json_params(json!({ "effort": 11 }))).is_err());
+        assert!(JxlOptions::from_params(&json_params(json!({ "quality": 120 }))).is_err());
+        assert_eq!(
+            JxlOptions::from_params(&json_params(json!({ "quality": 80 }))).is_ok(),
+            cfg!(feature = "jxl-lossy")
+        );
+        if cfg!(feature = "jxl-lossy") {
+            let mut lossy = Vec::new();
+            JxlOptions::from_params(&json_params(json!({ "quality": 80, "effort": 3 })))
+                .unwrap()
+                .encode(&image, &mut lossy)
+                .unwrap();
+            assert_eq!(dimensions(&lossy).unwrap(), (33, 17));
+            assert_eq!(decode(&lossy).unwrap().width(), 33);
+        }
+    }
+}
@@ -1,26 +1,84 @@
+mod audio;
+mod audio_channels;
+mod audio_fade;
+mod audio_resample;
+mod audio_trim;
+mod auto_color;
+mod auto_format;
+mod avif_sequence;
+mod color;
+mod decorate;
+mod fallbacks;
+mod filter;
+mod gif_from_video;
+mod jpeg_optimize;
+mod jxl;
+mod montage;
+mod optimize;
+mod pad;
+mod pages;
+mod palette;
+mod pdf;
+mod pdf_rasterize;
+mod phash;
+mod probe;
+mod redact;
+mod rename;
+mod rotate;
+mod smart_crop;
+mod storyboard;
+mod thumbnails;
+mod tiff_pages;
+mod tile;
+mod tonemap;
+mod upscale;
 mod video;
+mod video_color;
+mod video_resize;
+mod video_thumbnail;
+mod video_transform;
 
+use std::borrow::Cow;
 use std::fs;
-use std::io::Cursor;
+use std::io::{Cursor, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use anyhow::{Context, Result, anyhow, bail};
 use image::codecs::avif::{AvifEncoder, ColorSpace as AvifColorSpace};
-use image::codecs::gif::{GifEncoder, Repeat as GifRepeat};
-use image::codecs::jpeg::JpegEncoder;
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat as GifRepeat};
 use image::codecs::png::{
     CompressionType as PngCompressionType, FilterType as PngFilterType, PngEncoder,
 };
+use image::codecs::webp::WebPDecoder;
 use image::imageops::FilterType as ResizeFilter;
-use image::{DynamicImage, ExtendedColorType, ImageEncoder, ImageFormat};
+use image::metadata::Orientation;
+use image::{
+    AnimationDecoder, ColorType, Delay, DynamicImage, ExtendedColorType, Frame, GrayImage,
+    ImageDecoder, ImageEncoder, ImageFormat, Luma, Rgba,
+};
 use serde_json::{Value, json};
-use tracing::warn;
-use webp::Encoder as WebpEncoder;
+use tracing::{info, warn};
+use webp::{AnimEncoder, AnimFrame, Encoder as WebpEncoder, PixelLayout, WebPConfig};
 
+use crate::buffers;
+use crate::embedded::EmbeddedMetadata;
+use crate::overwrite;
 use crate::pipeline::{
-    Artifact, OutputSpec, PipelineContext, Stage, StageParameters, StageRegistry,
+    AnimationFrame, Artifact, OutputSpec, PipelineContext, Stage, StageParameters, StageRegistry,
 };
+use crate::quantize::{self, Dither, IndexedImage};
 use crate::scheduler::StageDevice;
+use crate::sink::{FileSink, OutputSink};
+use crate::source::ArtifactData;
+
+use pages::PageSelection;
+
+pub use fallbacks::FALLBACKS_KEY;
+pub use optimize::OPTIMIZE_SAVED_KEY;
+pub use palette::PALETTE_SIDECAR_KEY;
+pub use thumbnails::THUMBNAILS_KEY;
+pub use video_thumbnail::VIDEO_THUMBNAILS_KEY;
 
 pub fn register_defaults(registry: &mut StageRegistry) {
     registry.register("decode", |params| {
@@ -32,12 +90,125 @@ pub fn register_defaults(registry: &mut StageRegistry) {
     registry.register("resize", |params| {
         Ok(Box::new(ResizeStage::from_params(params)?))
     });
+    registry.register("blur", |params| {
+        Ok(Box::new(filter::BlurStage::from_params(params)?))
+    });
+    registry.register("sharpen", |params| {
+        Ok(Box::new(filter::SharpenStage::from_params(params)?))
+    });
+    registry.register("auto_color", |params| {
+        Ok(Box::new(auto_color::AutoColorStage::from_params(params)?))
+    });
+    registry.register("color_convert", |params| {
+        Ok(Box::new(color::ColorConvertStage::from_params(params)?))
+    });
+    registry.register("decorate", |params| {
+        Ok(Box::new(decorate::DecorateStage::from_params(params)?))
+    });
+    registry.register("montage", |params| {
+        Ok(Box::new(montage::MontageStage::from_params(params)?))
+    });
+    registry.register("optimize", |params| {
+        Ok(Box::new(optimize::OptimizeStage::from_params(params)?))
+    });
+    registry.register("pad", |params| {
+        Ok(Box::new(pad::PadStage::from_params(params)?))
+    });
+    registry.register("palette", |params| {
+        Ok(Box::new(palette::PaletteStage::from_params(params)?))
+    });
+    registry.register("pdf_rasterize", |params| {
+        Ok(Box::new(pdf_rasterize::PdfRasterizeStage::from_params(
+            params,
+        )?))
+    });
+    registry.register("phash", |params| {
+        Ok(Box::new(phash::PhashStage::from_params(params)?))
+    });
+    registry.register("redact", |params| {
+        Ok(Box::new(redact::RedactStage::from_params(params)?))
+    });
+    registry.register("rotate", |params| {
+        Ok(Box::new(rotate::RotateStage::from_params(params)?))
+    });
+    registry.register("smart_crop", |params| {
+        Ok(Box::new(smart_crop::SmartCropStage::from_params(params)?))
+    });
+    registry.register("rename", |params| {
+        Ok(Box::new(rename::RenameStage::from_params(params)?))
+    });
+    registry.register("thumbnails", |params| {
+        Ok(Box::new(thumbnails::ThumbnailsStage::from_params(params)?))
+    });
+    registry.register("tile", |params| {
+        Ok(Box::new(tile::TileStage::from_params(params)?))
+    });
+    registry.register("tonemap", |params| {
+        Ok(Box::new(tonemap::TonemapStage::from_params(params)?))
+    });
+    registry.register("upscale", |params| {
+        Ok(Box::new(upscale::UpscaleStage::from_params(params)?))
+    });
     registry.register("encode", |params| {
         Ok(Box::new(EncodeStage::from_params(params)?))
     });
+    registry.register("probe", |params| {
+        Ok(Box::new(probe::ProbeStage::from_params(params)?))
+    });
+    registry.register("audio_decode", |params| {
+        Ok(Box::new(audio::AudioDecodeStage::from_params(params)?))
+    });
+    registry.register("audio_encode", |params| {
+        Ok(Box::new(audio::AudioEncodeStage::from_params(params)?))
+    });
+    registry.register("audio_extract", |params| {
+        Ok(Box::new(audio::AudioExtractStage::from_params(params)?))
+    });
+    registry.register("audio_channels", |params| {
+        Ok(Box::new(audio_channels::AudioChannelsStage::from_params(
+            params,
+        )?))
+    });
+    registry.register("audio_resample", |params| {
+        Ok(Box::new(audio_resample::AudioResampleStage::from_params(
+            params,
+        )?))
+    });
+    registry.register("audio_trim", |params| {
+        Ok(Box::new(audio_trim::AudioTrimStage::from_params(params)?))
+    });
+    registry.register("audio_fade", |params| {
+        Ok(Box::new(audio_fade::AudioFadeStage::from_params(params)?))
+    });
     registry.register("video_decode", |params| {
         Ok(Box::new(video::VideoDecodeStage::from_params(params)?))
     });
+    registry.register("video_resize", |params| {
+        Ok(Box::new(video_resize::VideoResizeStage::from_params(
+            params,
+        )?))
+    });
+    registry.register("video_transform", |params| {
+        Ok(Box::new(video_transform::VideoTransformStage::from_params(
+            params,
+        )?))
+    });
+    registry.register("video_color", |params| {
+        Ok(Box::new(video_color::VideoColorStage::from_params(params)?))
+    });
+    registry.register("video_thumbnail", |params| {
+        Ok(Box::new(video_thumbnail::VideoThumbnailStage::from_params(
+            params,
+        )?))
+    });
+    registry.register("storyboard", |params| {
+        Ok(Box::new(storyboard::StoryboardStage::from_params(params)?))
+    });
+    registry.register("gif_from_video", |params| {
+        Ok(Box::new(gif_from_video::GifFromVideoStage::from_params(
+            params,
+        )?))
+    });
     registry.register("video_encode", |params| {
         Ok(Box::new(video::VideoEncodeStage::from_params(params)?))
     });
@@ -45,12 +216,70 @@ pub fn register_defaults(registry: &mut StageRegistry) {
 
 struct DecodeStage {
     format_hint: Option<String>,
+    all_frames: bool,
+    /// Applies the EXIF orientation so the image is stored upright.
+    auto_orient: bool,
+    /// Pages of multi-page TIFFs to split into artifacts of their own;
+    /// `None` reads the first page only.
+    pages: Option<PageSelection>,
 }
 
 impl DecodeStage {
     fn from_params(mut params: StageParameters) -> Result<Self> {
         let format_hint = take_string(&mut params, "format");
-        Ok(Self { format_hint })
+        let all_frames = match take_string(&mut params, "frames").as_deref() {
+            None | Some("first") => false,
+            Some("all") => true,
+            Some(other) => bail!("Unknown decode frames mode '{other}' (expected first or all)"),
+        };
+        let auto_orient = take_bool(&mut params, "auto_orient")?.unwrap_or(false);
+        let pages = match params.remove("pages") {
+            Some(Value::String(value)) if value.trim() == "first" => None,
+            Some(value) => Some(PageSelection::from_value(&value)?),
+            None => None,
+        };
+        if all_frames && pages.is_some() {
+            bail!(
+                "decode takes either frames: all (pages kept together) or pages \
+                 (one artifact per page), not both"
+            );
+        }
+        Ok(Self {
+            format_hint,
+            all_frames,
+            auto_orient,
+            pages,
+        })
+    }
+
+    /// Splits a TIFF artifact decode left holding its first page into one
+    /// artifact per selected page.
+    fn split_tiff_pages(
+        &self,
+        artifact: &mut Artifact,
+        ctx: &PipelineContext,
+        selection: &PageSelection,
+        orientation: Option<Orientation>,
+    ) -> Result<()> {
+        let count = tiff_pages::page_count(&artifact.data)?;
+        let selected = selection.pages(count)?;
+        let first = artifact.image.clone();
+        // A page on its own no longer matches the whole file's bytes.
+        let edited = orientation.is_some() || count > 1;
+        pages::split(artifact, ctx, "image", count, &selected, |target, page| {
+            let decoded = match (&first, page) {
+                (Some(first), 1) => Arc::unwrap_or_clone(Arc::clone(first)),
+                _ => {
+                    let mut decoded = tiff_pages::decode_page(&target.data, page - 1)?;
+                    if let Some(orientation) = orientation {
+                        decoded.apply_orientation(orientation);
+                    }
+                    decoded
+                }
+            };
+            store_decoded(target, ctx, decoded, edited);
+            Ok(())
+        })
     }
 }
 
@@ -63,21 +292,129 @@ impl Stage for DecodeStage {
         matches!(device, StageDevice::Cpu)
     }
 
+    fn splits(&self) -> bool {
+        self.pages.is_some()
+    }
+
     fn run(
         &self,
         artifact: &mut Artifact,
-        _ctx: &PipelineContext,
+        ctx: &PipelineContext,
         _device: StageDevice,
     ) -> Result<()> {
+        if jxl::detect(self.format_hint.as_deref(), artifact) {
+            // JPEG XL stills only; their orientation is applied while
+            // rendering and their metadata boxes are not read.
+            let decoded = jxl::decode(&artifact.data)?;
+            artifact.embedded = Arc::default();
+            store_decoded(artifact, ctx, decoded, false);
+            artifact.set_format(jxl::LABEL);
+            return Ok(());
+        }
         let (image_format, label) = infer_format(self.format_hint.as_deref(), artifact)?;
-        let decoded = image::load_from_memory_with_format(&artifact.data, image_format)
-            .with_context(|| format!("Failed to decode image as {:?}", image_format))?;
+        let mut frames = if self.all_frames {
+            decode_frames(&artifact.data, image_format)?
+        } else {
+            Vec::new()
+        };
+        let orientation = self
+            .auto_orient
+            .then(|| orientation(&artifact.data, image_format))
+            .flatten();
+        if let Some(orientation) = orientation {
+            for frame in &mut frames {
+                let mut canvas = DynamicImage::ImageRgba8(std::mem::take(&mut frame.image));
+                canvas.apply_orientation(orientation);
+                frame.image = canvas.into_rgba8();
+            }
+        }
+        let mut decoded = match frames.first() {
+            Some(first) if frames.len() > 1 => DynamicImage::ImageRgba8(first.image.clone()),
+            _ => image::load_from_memory_with_format(&artifact.data, image_format)
+                .with_context(|| format!("Failed to decode image as {:?}", image_format))?,
+        };
+        if frames.len() > 1 {
+            artifact
+                .metadata
+                .insert("image.frame_count".to_string(), json!(frames.len()));
+            artifact.set_frames(frames);
+        } else if let Some(orientation) = orientation {
+            decoded.apply_orientation(orientation);
+        }
+        if let Some(orientation) = orientation {
+            artifact.metadata.insert(
+                "image.orientation".to_string(),
+                json!(orientation.to_exif()),
+            );
+        }
+        let mut embedded = EmbeddedMetadata::read(image_format, &artifact.data);
+        if orientation.is_some() {
+            embedded.reset_orientation();
+        }
+        if !embedded.is_empty() {
+            artifact
+                .metadata
+                .insert("image.embedded".to_string(), json!(embedded.kinds()));
+        }
+        artifact.embedded = Arc::new(embedded);
+        // Upright pixels no longer match the input bytes.
+        store_decoded(artifact, ctx, decoded, orientation.is_some());
+        artifact.set_format(label);
+        if let Some(selection) = &self.pages
+            && image_format == ImageFormat::Tiff
+        {
+            self.split_tiff_pages(artifact, ctx, selection, orientation)?;
+        }
+        Ok(())
+    }
 
-        let width = decoded.width();
-        let height = decoded.height();
-        artifact.set_original_image(decoded.clone());
-        artifact.set_image(decoded);
-        artifact.set_format(label.clone());
+    fn plan(&self, artifact: &mut Artifact, ctx: &PipelineContext) -> Result<()> {
+        let (width, height) = if jxl::detect(self.format_hint.as_deref(), artifact) {
+            artifact.set_format(jxl::LABEL);
+            jxl::dimensions(&artifact.data)?
+        } else {
+            let (image_format, label) = infer_format(self.format_hint.as_deref(), artifact)?;
+            let (width, height) =
+                image::ImageReader::with_format(Cursor::new(&artifact.data[..]), image_format)
+                    .into_dimensions()
+                    .with_context(|| format!("Failed to read {:?} image header", image_format))?;
+            artifact.set_format(label);
+            let swaps_axes = self.auto_orient
+                && orientation(&artifact.data, image_format).is_some_and(rotate::swaps_axes);
+            if let Some(selection) = &self.pages
+                && image_format == ImageFormat::Tiff
+            {
+                let dimensions = tiff_pages::dimensions(&artifact.data)?;
+                let selected = selection.pages(dimensions.len())?;
+                return pages::split(
+                    artifact,
+                    ctx,
+                    "image",
+                    dimensions.len(),
+                    &selected,
+                    |target, page| {
+                        let (width, height) = dimensions[page - 1];
+                        let (width, height) = if swaps_axes {
+                            (height, width)
+                        } else {
+                            (width, height)
+                        };
+                        target
+                            .metadata
+                            .insert("image.width".to_string(), json!(width));
+                        target
+                            .metadata
+                            .insert("image.height".to_string(), json!(height));
+                        Ok(())
+                    },
+                );
+            }
+            if swaps_axes {
+                (height, width)
+            } else {
+                (width, height)
+            }
+        };
         artifact
             .metadata
             .insert("image.width".to_string(), json!(width));
@@ -88,6 +425,32 @@ impl Stage for DecodeStage {
     }
 }
 
+/// Stores what decode produced as the working image; `edited` when the
+/// pixels no longer match the input bytes.
+fn store_decoded(
+    artifact: &mut Artifact,
+    ctx: &PipelineContext,
+    decoded: DynamicImage,
+    edited: bool,
+) {
+    record_dimensions(artifact, "image", &decoded);
+    artifact.metadata.insert(
+        "image.bit_depth".to_string(),
+        json!(BitDepth::of(&decoded).bits()),
+    );
+    // The untouched original is only read back by quality gates; it shares
+    // the decoded pixels with the working image until a stage edits them.
+    let decoded = Arc::new(decoded);
+    if ctx.quality_gates_enabled {
+        artifact.set_original_image(Arc::clone(&decoded));
+    }
+    if edited {
+        artifact.set_image(decoded);
+    } else {
+        artifact.set_decoded_image(decoded);
+    }
+}
+
 struct AnnotateStage {
     key: String,
     value: Value,
@@ -124,6 +487,10 @@ impl Stage for AnnotateStage {
             .insert(self.key.clone(), self.value.clone());
         Ok(())
     }
+
+    fn plan(&self, artifact: &mut Artifact, ctx: &PipelineContext) -> Result<()> {
+        self.run(artifact, ctx, StageDevice::Cpu)
+    }
 }
 
 struct ResizeStage {
@@ -155,6 +522,22 @@ impl ResizeStage {
     }
 }
 
+impl ResizeStage {
+    /// Every fit mode leaves an image that already has the target dimensions
+    /// untouched, so resampling it would only cost a full-size copy.
+    fn is_noop(&self, image: &DynamicImage) -> bool {
+        image.width() == self.width && image.height() == self.height
+    }
+
+    fn resize(&self, image: &DynamicImage) -> DynamicImage {
+        match self.fit {
+            ResizeMode::Cover => image.resize_to_fill(self.width, self.height, self.filter),
+            ResizeMode::Exact => image.resize_exact(self.width, self.height, self.filter),
+            ResizeMode::Inside => image.resize(self.width, self.height, self.filter),
+        }
+    }
+}
+
 impl Stage for ResizeStage {
     fn name(&self) -> &'static str {
         "resize"
@@ -164,6 +547,38 @@ impl Stage for ResizeStage {
         matches!(device, StageDevice::Cpu)
     }
 
+    fn output_dimensions(&self, _width: u32, _height: u32) -> (u32, u32) {
+        // `inside` may come out smaller, never larger.
+        (self.width, self.height)
+    }
+
+    fn plan(&self, artifact: &mut Artifact, _ctx: &PipelineContext) -> Result<()> {
+        let dimension = |key: &str| artifact.metadata.get(key).and_then(Value::as_u64);
+        let (width, height) = match (
+            self.fit,
+            dimension("image.width"),
+            dimension("image.height"),
+        ) {
+            (ResizeMode::Inside, Some(width), Some(height)) if width > 0 && height > 0 => {
+                // Mirrors `DynamicImage::resize`: scale to fit, keep the ratio.
+                let ratio = (f64::from(self.width) / width as f64)
+                    .min(f64::from(self.height) / height as f64);
+                (
+                    ((width as f64 * ratio).round() as u64).max(1),
+                    ((height as f64 * ratio).round() as u64).max(1),
+                )
+            }
+            _ => (u64::from(self.width), u64::from(self.height)),
+        };
+        artifact
+            .metadata
+            .insert("image.width".to_string(), json!(width));
+        artifact
+            .metadata
+            .insert("image.height".to_string(), json!(height));
+        Ok(())
+    }
+
     fn run(
         &self,
         artifact: &mut Artifact,
@@ -172,16 +587,24 @@ impl Stage for ResizeStage {
     ) -> Result<()> {
         let image = artifact
             .image
-            .as_ref()
+            .take()
             .ok_or_else(|| anyhow!("resize stage requires a decoded image"))?;
 
-        let resized = match self.fit {
-            ResizeMode::Cover => image.resize_to_fill(self.width, self.height, self.filter),
-            ResizeMode::Exact => image.resize_exact(self.width, self.height, self.filter),
-            ResizeMode::Inside => image.resize(self.width, self.height, self.filter),
-        };
-
-        artifact.set_image(resized.clone());
+        if self.is_noop(&image) {
+            artifact.image = Some(image);
+        } else if artifact.is_animated() {
+            for frame in artifact.frames_mut() {
+                let canvas = DynamicImage::ImageRgba8(std::mem::take(&mut frame.image));
+                frame.image = self.resize(&canvas).into_rgba8();
+            }
+            let first = DynamicImage::ImageRgba8(artifact.frames[0].image.clone());
+            record_dimensions(artifact, "image", &first);
+            artifact.set_image(first);
+        } else {
+            let resized = self.resize(&image);
+            record_dimensions(artifact, "image", &resized);
+            artifact.set_image(resized);
+        }
         artifact
             .metadata
             .insert("resize.width".to_string(), json!(self.width));
@@ -196,7 +619,6 @@ impl Stage for ResizeStage {
             "resize.mode".to_string(),
             Value::String(self.fit.as_str().to_string()),
         );
-        record_dimensions(artifact, "image", &resized);
         Ok(())
     }
 }
@@ -204,6 +626,15 @@ impl Stage for ResizeStage {
 struct EncodeStage {
     format: Option<String>,
     extension: Option<String>,
+    verify: VerifyOutput,
+    stream: bool,
+    passthrough: Passthrough,
+    /// Which of the input's EXIF and XMP packets to write into the output.
+    preserve: (bool, bool),
+    pdf: Option<pdf::PdfEncoder>,
+    /// Set for `format: auto`, which then holds the encoder options.
+    auto: Option<auto_format::AutoFormat>,
+    fallbacks: Option<fallbacks::Fallbacks>,
     options: StageParameters,
 }
 
@@ -211,14 +642,121 @@ impl EncodeStage {
     fn from_params(mut params: StageParameters) -> Result<Self> {
         let format = take_string(&mut params, "format");
         let extension = take_string(&mut params, "extension");
+        let verify = match take_string(&mut params, "verify_output") {
+            Some(value) => VerifyOutput::from_str(&value)
+                .ok_or_else(|| anyhow!("Unknown verify_output mode '{value}'"))?,
+            None => VerifyOutput::Auto,
+        };
+        let stream = take_bool(&mut params, "stream")?.unwrap_or(false);
+        let optimize = take_bool(&mut params, "passthrough_optimize")?.unwrap_or(false);
+        let passthrough = match take_bool(&mut params, "passthrough_if_same_format")? {
+            Some(true) if optimize => Passthrough::Optimize,
+            Some(true) => Passthrough::Copy,
+            _ => Passthrough::Off,
+        };
+        let preserve = match params.remove("preserve_metadata") {
+            Some(value) => parse_preserve_metadata(&value)?,
+            None => (false, false),
+        };
+        parse_bit_depth(&params)?;
+        parse_gray_bits(&params)?;
+        parse_png_palette(&params)?;
+        let fallbacks = fallbacks::Fallbacks::from_params(&mut params)?;
+        let pdf = if format.as_deref().is_some_and(is_pdf_label) {
+            Some(pdf::PdfEncoder::from_params(&mut params)?)
+        } else {
+            None
+        };
+        let auto = if format.as_deref() == Some(auto_format::LABEL) {
+            if extension.is_some() {
+                bail!("extension cannot be set with format: auto; it follows the chosen format");
+            }
+            Some(auto_format::AutoFormat::from_params(&mut params)?)
+        } else {
+            None
+        };
+        if fallbacks.is_some() && (pdf.is_some() || auto.is_some()) {
+            bail!("encode fallbacks cannot be combined with format: pdf or format: auto");
+        }
         Ok(Self {
             format,
             extension,
+            verify,
+            stream,
+            passthrough,
+            preserve,
+            pdf,
+            auto,
+            fallbacks,
             options: params,
         })
     }
 }
 
+impl EncodeStage {
+    fn pdf_extension(&self) -> &str {
+        self.extension.as_deref().unwrap_or("pdf")
+    }
+
+    fn jxl_extension(&self) -> &str {
+        self.extension.as_deref().unwrap_or(jxl::LABEL)
+    }
+
+    /// JPEG XL is not an image format, so it is written outside the shared
+    /// encode path: no passthrough, streaming or metadata preservation.
+    fn encode_jxl(&self, artifact: &mut Artifact, ctx: &PipelineContext) -> Result<()> {
+        let options = jxl::JxlOptions::from_params(&self.options)?;
+        let (exif, xmp) = self.preserve;
+        let embedded = artifact.embedded.select(exif, xmp);
+        if !embedded.is_empty() {
+            warn!(
+                format = jxl::LABEL,
+                kinds = ?embedded.kinds(),
+                "Output format cannot carry EXIF/XMP metadata; dropping it"
+            );
+        }
+        let verify = match self.verify {
+            VerifyOutput::Always => true,
+            VerifyOutput::Never => false,
+            VerifyOutput::Auto => ctx.quality_gates_enabled,
+        };
+        let image = artifact.image.clone();
+        options.write(artifact, ctx, self.jxl_extension(), verify)?;
+        if let Some(image) = image {
+            self.write_fallbacks(artifact, ctx, &image)?;
+        }
+        record_encoder_metadata(artifact, &self.options);
+        Ok(())
+    }
+
+    /// Writes the `fallbacks` next to the main output already recorded in
+    /// `output_path`.
+    fn write_fallbacks(
+        &self,
+        artifact: &mut Artifact,
+        ctx: &PipelineContext,
+        image: &DynamicImage,
+    ) -> Result<()> {
+        let Some(fallbacks) = &self.fallbacks else {
+            return Ok(());
+        };
+        let main = main_output(artifact)?;
+        let (exif, xmp) = self.preserve;
+        let embedded = artifact.embedded.select(exif, xmp);
+        fallbacks.write(artifact, ctx, image, &main, &embedded)
+    }
+}
+
+/// The artifact's main output, as recorded by encode.
+fn main_output(artifact: &Artifact) -> Result<PathBuf> {
+    artifact
+        .metadata
+        .get("output_path")
+        .and_then(Value::as_str)
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow!("encode recorded no output path"))
+}
+
 impl Stage for EncodeStage {
     fn name(&self) -> &'static str {
         "encode"
@@ -234,55 +772,208 @@ impl Stage for EncodeStage {
         ctx: &PipelineContext,
         _device: StageDevice,
     ) -> Result<()> {
-        let (image_format, label) = infer_format(self.format.as_deref(), artifact)?;
+        if let Some(pdf) = &self.pdf {
+            return pdf.encode(artifact, ctx, self.pdf_extension());
+        }
+        if jxl::targets(self.format.as_deref(), artifact) {
+            return self.encode_jxl(artifact, ctx);
+        }
+        // A shared handle, so the artifact stays free to record the output.
+        let image = artifact
+            .image
+            .clone()
+            .ok_or_else(|| anyhow!("encode stage requires a decoded image"))?;
+        let image = image.as_ref();
+
+        let (auto, mut auto_encoded) = match &self.auto {
+            Some(auto) => {
+                let mut choice = auto.select(image, &artifact.frames)?;
+                let encoded = std::mem::take(&mut choice.encoded);
+                (Some(choice), Some(encoded))
+            }
+            None => (None, None),
+        };
+        let options = auto
+            .as_ref()
+            .map_or(&self.options, |choice| &choice.options);
+        let source_format = artifact.format.as_deref().and_then(format_from_label);
+        let (image_format, label) = match &auto {
+            Some(choice) => (choice.format, format_extension(choice.format).to_string()),
+            None => infer_format(self.format.as_deref(), artifact)?,
+        };
+        let passthrough = self.passthrough != Passthrough::Off
+            && auto.is_none()
+            && !artifact.image_edited
+            && !transforms_pixels(options, image_format)?
+            && source_format == Some(image_format);
         artifact.set_format(label.clone());
         let extension = self
             .extension
             .clone()
             .unwrap_or_else(|| format_extension(image_format).to_string());
 
-        let image = artifact
-            .image
-            .as_ref()
-            .ok_or_else(|| anyhow!("encode stage requires a decoded image"))?;
-
-        let buffer = encode_with_options(image, image_format, &self.options)
-            .with_context(|| format!("Failed to encode image as {:?}", image_format))?;
-
-        let resolved = resolve_output_path(&ctx.output, artifact, &extension);
+        let resolved = ctx.outputs.claim(
+            resolve_output_path(&ctx.output, artifact, &extension),
+            &artifact.input_path,
+            &mut artifact.metadata,
+        )?;
+        if keep_existing_output(artifact, ctx, &resolved) {
+            artifact
+                .metadata
+                .insert("output.extension".to_string(), Value::String(extension));
+            artifact
+                .metadata
+                .insert("output.format".to_string(), Value::String(label));
+            return self.write_fallbacks(artifact, ctx, image);
+        }
         if let Some(parent) = resolved.parent() {
             fs::create_dir_all(parent).with_context(|| {
                 format!("Failed to create output directory: {}", parent.display())
             })?;
         }
-        fs::write(&resolved, &buffer)
-            .with_context(|| format!("Failed to write output file: {}", resolved.display()))?;
+        let (exif, xmp) = self.preserve;
+        let embedded = Some(artifact.embedded.select(exif, xmp))
+            .filter(|embedded| !embedded.is_empty())
+            .filter(|embedded| {
+                let supported = EmbeddedMetadata::supported(image_format);
+                if !supported {
+                    warn!(
+                        format = ?image_format,
+                        kinds = ?embedded.kinds(),
+                        "Output format cannot carry EXIF/XMP metadata; dropping it"
+                    );
+                }
+                supported
+            });
+        let embed = |buffer: Vec<u8>| match &embedded {
+            Some(embedded) => embedded
+                .embed(image_format, buffer)
+                .context("Failed to preserve image metadata"),
+            None => Ok(buffer),
+        };
+        // Packets are spliced into the finished file, so it has to be
+        // buffered.
+        let stream = self.stream && embedded.is_none() && auto.is_none();
+        let mut sink = FileSink::create(&resolved)?;
+        let buffer = if passthrough {
+            let source = Arc::try_unwrap(std::mem::take(&mut artifact.data))
+                .map_or_else(|shared| shared.to_vec(), ArtifactData::into_vec);
+            let buffer = match self.passthrough {
+                // Multi-page TIFFs would lose every page but the first.
+                Passthrough::Optimize if is_lossless(image_format) && !artifact.is_animated() => {
+                    let mut cursor = encode_cursor(image);
+                    encode_with_options(image, image_format, options, &mut cursor)
+                        .with_context(|| format!("Failed to encode image as {:?}", image_format))?;
+                    smaller_of(source, embed(cursor.into_inner())?)
+                }
+                // Re-encoding a JPEG loses detail; rebuilding its Huffman
+                // tables does not.
+                Passthrough::Optimize if image_format == ImageFormat::Jpeg => {
+                    match jpeg_optimize::optimize(&source, |_, _| true, true) {
+                        Ok(optimized) => smaller_of(source, optimized),
+                        Err(err) => {
+                            warn!(error = %format!("{err:#}"), "Copying the JPEG unoptimized");
+                            source
+                        }
+                    }
+                }
+                _ => source,
+            };
+            sink.write_all(&buffer)
+                .with_context(|| format!("Failed to write output file: {}", resolved.display()))?;
+            Some(buffer)
+        } else if stream {
+            encode_artifact(image, &artifact.frames, image_format, options, &mut sink)
+                .with_context(|| format!("Failed to encode image as {:?}", image_format))?;
+            None
+        } else {
+            let encoded = match auto_encoded.take() {
+                Some(encoded) => encoded,
+                None => {
+                    let mut cursor = encode_cursor(image);
+                    encode_artifact(image, &artifact.frames, image_format, options, &mut cursor)
+                        .with_context(|| format!("Failed to encode image as {:?}", image_format))?;
+                    cursor.into_inner()
+                }
+            };
+            let buffer = embed(encoded)?;
+            sink.write_all(&buffer)
+                .with_context(|| format!("Failed to write output file: {}", resolved.display()))?;
+            Some(buffer)
+        };
+        let summary = sink.finish()?;
 
-        match image::load_from_memory_with_format(&buffer, image_format) {
-            Ok(decoded) => {
-                artifact
-                    .metadata
-                    .insert("output.decode_supported".into(), Value::Bool(true));
-                artifact.set_image(decoded.clone());
-                record_dimensions(artifact, "image", &decoded);
-            }
-            Err(err) => {
-                artifact
-                    .metadata
-                    .insert("output.decode_supported".into(), Value::Bool(false));
-                artifact.metadata.insert(
-                    "output.decode_warning".into(),
-                    Value::String(err.to_string()),
-                );
-                warn!(
-                    format = ?image_format,
-                    error = %err,
-                    "Post-encode decode skipped; decoder unavailable"
-                );
-                artifact.image = None;
+        let verify = match self.verify {
+            VerifyOutput::Always => true,
+            VerifyOutput::Never => false,
+            VerifyOutput::Auto => ctx.quality_gates_enabled,
+        };
+        artifact
+            .metadata
+            .insert("output.verified".into(), Value::Bool(verify));
+        if verify {
+            let encoded = match &buffer {
+                Some(buffer) => Cow::Borrowed(buffer.as_slice()),
+                None => Cow::Owned(fs::read(&resolved).with_context(|| {
+                    format!("Failed to read back output file: {}", resolved.display())
+                })?),
+            };
+            match image::load_from_memory_with_format(&encoded, image_format) {
+                Ok(decoded) => {
+                    artifact
+                        .metadata
+                        .insert("output.decode_supported".into(), Value::Bool(true));
+                    record_dimensions(artifact, "image", &decoded);
+                    artifact.set_image(decoded);
+                }
+                Err(err) => {
+                    artifact
+                        .metadata
+                        .insert("output.decode_supported".into(), Value::Bool(false));
+                    artifact.metadata.insert(
+                        "output.decode_warning".into(),
+                        Value::String(err.to_string()),
+                    );
+                    warn!(
+                        format = ?image_format,
+                        error = %err,
+                        "Post-encode decode skipped; decoder unavailable"
+                    );
+                    artifact.image = None;
+                }
             }
         }
-        artifact.replace_data(buffer.clone());
+        // Streamed outputs never exist in memory; drop the source bytes too so
+        // nothing stale is mistaken for the encoded data.
+        artifact.replace_data(buffer.unwrap_or_default());
+        artifact.metadata.insert(
+            "output.streamed".into(),
+            Value::Bool(stream && !passthrough),
+        );
+        if let Some(embedded) = &embedded
+            && !passthrough
+        {
+            artifact
+                .metadata
+                .insert("output.embedded".into(), json!(embedded.kinds()));
+        }
+        artifact
+            .metadata
+            .insert("output.passthrough".into(), Value::Bool(passthrough));
+        let bit_depth = if artifact.is_animated() && supports_animation(image_format) {
+            artifact
+                .metadata
+                .insert("output.frame_count".into(), json!(artifact.frames.len()));
+            // Frames are always 8-bit RGBA.
+            BitDepth::Eight
+        } else if passthrough {
+            BitDepth::of(image)
+        } else {
+            output_bit_depth(image, image_format, options)?
+        };
+        artifact
+            .metadata
+            .insert("output.bit_depth".into(), json!(bit_depth.bits()));
         artifact.metadata.insert(
             "output_path".to_string(),
             Value::String(resolved.to_string_lossy().to_string()),
@@ -296,86 +987,554 @@ impl Stage for EncodeStage {
             .insert("output.format".to_string(), Value::String(label));
         artifact
             .metadata
-            .insert("output.size_bytes".to_string(), json!(buffer.len()));
-        record_encoder_metadata(artifact, &self.options);
+            .insert("output.size_bytes".to_string(), json!(summary.size_bytes));
+        artifact
+            .metadata
+            .insert("output.sha256".to_string(), Value::String(summary.sha256));
+        if let Some(choice) = &auto {
+            artifact.metadata.insert(
+                "output.auto.format".into(),
+                Value::String(format_extension(choice.format).to_string()),
+            );
+            artifact
+                .metadata
+                .insert("output.auto.ssim".into(), json!(choice.ssim));
+            artifact
+                .metadata
+                .insert("output.auto.candidates".into(), choice.report.clone());
+        }
+        self.write_fallbacks(artifact, ctx, image)?;
+        // A copied source was never encoded with these options.
+        if !passthrough {
+            record_encoder_metadata(artifact, options);
+        }
+        Ok(())
+    }
+
+    fn plan(&self, artifact: &mut Artifact, ctx: &PipelineContext) -> Result<()> {
+        let (resolved, extension) = match (&self.pdf, &self.auto) {
+            (Some(pdf), _) => {
+                artifact.set_format("pdf");
+                let extension = self.pdf_extension().to_string();
+                (pdf.output_path(artifact, ctx, &extension)?, extension)
+            }
+            (None, Some(auto)) => {
+                let extension = auto.planned_extension().to_string();
+                artifact.set_format(extension.clone());
+                let resolved = ctx.outputs.claim(
+                    resolve_output_path(&ctx.output, artifact, &extension),
+                    &artifact.input_path,
+                    &mut artifact.metadata,
+                )?;
+                (resolved, extension)
+            }
+            (None, None) if jxl::targets(self.format.as_deref(), artifact) => {
+                artifact.set_format(jxl::LABEL);
+                let extension = self.jxl_extension().to_string();
+                let resolved = ctx.outputs.claim(
+                    resolve_output_path(&ctx.output, artifact, &extension),
+                    &artifact.input_path,
+                    &mut artifact.metadata,
+                )?;
+                (resolved, extension)
+            }
+            (None, None) => {
+                let (image_format, label) = infer_format(self.format.as_deref(), artifact)?;
+                artifact.set_format(label);
+                let extension = self
+                    .extension
+                    .clone()
+                    .unwrap_or_else(|| format_extension(image_format).to_string());
+                let resolved = ctx.outputs.claim(
+                    resolve_output_path(&ctx.output, artifact, &extension),
+                    &artifact.input_path,
+                    &mut artifact.metadata,
+                )?;
+                (resolved, extension)
+            }
+        };
+        if let Some(reason) = ctx.overwrite.keep_reason(&artifact.input_path, &resolved) {
+            artifact.metadata.insert(
+                overwrite::SKIPPED_KEY.to_string(),
+                Value::String(reason.to_string()),
+            );
+        }
+        artifact.metadata.insert(
+            "output_path".to_string(),
+            Value::String(resolved.to_string_lossy().to_string()),
+        );
+        artifact
+            .metadata
+            .insert("output.extension".to_string(), Value::String(extension));
+        if let Some(fallbacks) = &self.fallbacks {
+            fallbacks.plan(artifact, ctx, &resolved)?;
+        }
         Ok(())
     }
+
+    fn finalize(&self, ctx: &PipelineContext) -> Result<()> {
+        match &self.pdf {
+            Some(pdf) => pdf.finalize(ctx, self.pdf_extension()),
+            None => Ok(()),
+        }
+    }
+
+    /// A cache replay restores the main output only, not the fallbacks.
+    fn cacheable(&self) -> bool {
+        self.fallbacks.is_none() && self.pdf.as_ref().is_none_or(|pdf| !pdf.combines())
+    }
+}
+
+/// The EXIF orientation of an encoded image, unless it is already upright.
+fn orientation(data: &[u8], format: ImageFormat) -> Option<Orientation> {
+    let mut decoder = image::ImageReader::with_format(Cursor::new(data), format)
+        .into_decoder()
+        .ok()?;
+    decoder
+        .orientation()
+        .ok()
+        .filter(|&orientation| orientation != Orientation::NoTransforms)
+}
+
+/// Decodes every frame of an animated GIF or WebP; other formats have none.
+fn decode_frames(data: &[u8], format: ImageFormat) -> Result<Vec<AnimationFrame>> {
+    let frames = match format {
+        ImageFormat::Gif => GifDecoder::new(Cursor::new(data))
+            .context("Failed to read GIF animation")?
+            .into_frames(),
+        ImageFormat::WebP => {
+            let decoder =
+                WebPDecoder::new(Cursor::new(data)).context("Failed to read WebP animation")?;
+            if !decoder.has_animation() {
+                return Ok(Vec::new());
+            }
+            decoder.into_frames()
+        }
+        ImageFormat::Tiff => return tiff_pages::decode_frames(data),
+        _ => return Ok(Vec::new()),
+    };
+    frames
+        .map(|frame| {
+            let frame = frame.context("Failed to decode animation frame")?;
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            Ok(AnimationFrame {
+                delay_ms: numer / denom.max(1),
+                image: frame.into_buffer(),
+            })
+        })
+        .collect()
 }
 
 fn resolve_output_path(spec: &OutputSpec, artifact: &Artifact, extension: &str) -> PathBuf {
-    let mut file_name = spec.structure.clone();
-    file_name = file_name.replace("{stem}", &artifact.stem);
-    file_name = file_name.replace("{ext}", extension);
+    spec.resolve(&artifact.stem, extension, &artifact.metadata)
+}
+
+/// Whether the run's overwrite policy keeps the existing file at `path`. A
+/// kept file becomes the artifact's output and must not be written.
+fn keep_existing_output(artifact: &mut Artifact, ctx: &PipelineContext, path: &Path) -> bool {
+    let Some(reason) = ctx.overwrite.keep_reason(&artifact.input_path, path) else {
+        return false;
+    };
+    info!(output = %path.display(), reason, "Keeping existing output");
+    artifact.metadata.insert(
+        overwrite::SKIPPED_KEY.to_string(),
+        Value::String(reason.to_string()),
+    );
+    artifact.metadata.insert(
+        "output_path".to_string(),
+        Value::String(path.to_string_lossy().to_string()),
+    );
+    true
+}
+
+fn encode_artifact(
+    image: &DynamicImage,
+    frames: &[AnimationFrame],
+    format: ImageFormat,
+    options: &StageParameters,
+    out: &mut dyn Write,
+) -> Result<()> {
+    if frames.len() < 2 {
+        return encode_with_options(image, format, options, out);
+    }
+    if supports_animation(format) && options.contains_key("gray_bits") {
+        bail!("gray_bits output is for still images; decode with frames: first");
+    }
+    match format {
+        ImageFormat::WebP => encode_animated_webp(frames, options, out),
+        ImageFormat::Gif => encode_animated_gif(frames, options, out),
+        ImageFormat::Tiff => tiff_pages::encode(frames, out),
+        ImageFormat::Avif => avif_sequence::encode(frames, options, out),
+        _ => {
+            warn!(
+                format = ?format,
+                frames = frames.len(),
+                "Format cannot hold an animation; writing the first frame only"
+            );
+            encode_with_options(image, format, options, out)
+        }
+    }
+}
+
+/// Formats that keep every frame; TIFF stores them as pages.
+fn supports_animation(format: ImageFormat) -> bool {
+    matches!(
+        format,
+        ImageFormat::WebP | ImageFormat::Gif | ImageFormat::Tiff | ImageFormat::Avif
+    )
+}
 
-    for (key, value) in artifact.metadata.iter() {
-        if let Some(as_str) = value.as_str() {
-            let placeholder = format!("{{{}}}", key);
-            file_name = file_name.replace(&placeholder, as_str);
+fn encode_animated_webp(
+    frames: &[AnimationFrame],
+    options: &StageParameters,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let mut config = WebPConfig::new().map_err(|_| anyhow!("Failed to prepare WebP config"))?;
+    config.lossless = i32::from(param_bool(options, "lossless").unwrap_or(false));
+    config.quality = param_f64(options, "quality")
+        .unwrap_or(75.0)
+        .clamp(0.0, 100.0) as f32;
+    let frame_configs = parse_frame_quality(options, frames.len())?
+        .into_iter()
+        .map(|quality| WebPConfig { quality, ..config })
+        .collect::<Vec<_>>();
+
+    let (width, height) = frames[0].image.dimensions();
+    let mut encoder = AnimEncoder::new(width, height, &config);
+    encoder.set_loop_count(match parse_gif_repeat(options)? {
+        Some(GifRepeat::Finite(count)) => i32::from(count),
+        Some(GifRepeat::Infinite) | None => 0,
+    });
+    let mut timestamp = 0i32;
+    for (index, frame) in frames.iter().enumerate() {
+        if frame.image.dimensions() != (width, height) {
+            bail!("Animation frame {index} does not match the {width}x{height} canvas");
         }
+        encoder.add_frame(AnimFrame::new(
+            frame.image.as_raw(),
+            PixelLayout::Rgba,
+            width,
+            height,
+            timestamp,
+            frame_configs.get(index),
+        ));
+        timestamp = timestamp.saturating_add(frame_delay(frame, options) as i32);
     }
+    let encoded = encoder
+        .try_encode()
+        .map_err(|err| anyhow!("Animated WebP encode failed: {err:?}"))?;
+    out.write_all(&encoded).context("WebP write failed")?;
+    Ok(())
+}
+
+fn encode_animated_gif(
+    frames: &[AnimationFrame],
+    options: &StageParameters,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let speed = param_u8(options, "speed").unwrap_or(10).clamp(1, 30) as i32;
+    let mut encoder = GifEncoder::new_with_speed(out, speed);
+    encoder
+        .set_repeat(parse_gif_repeat(options)?.unwrap_or(GifRepeat::Infinite))
+        .context("Failed to configure GIF repeat")?;
+    encoder
+        .encode_frames(frames.iter().map(|frame| {
+            Frame::from_parts(
+                frame.image.clone(),
+                0,
+                0,
+                Delay::from_numer_denom_ms(frame_delay(frame, options), 1),
+            )
+        }))
+        .context("Animated GIF encode failed")?;
+    Ok(())
+}
+
+/// `frame_delay_ms` overrides the source timing of every frame.
+fn frame_delay(frame: &AnimationFrame, options: &StageParameters) -> u32 {
+    options
+        .get("frame_delay_ms")
+        .and_then(value_as_u64)
+        .and_then(|delay| u32::try_from(delay).ok())
+        .unwrap_or(frame.delay_ms)
+}
+
+/// Reads `frame_quality`, a list of per-frame WebP qualities; frames past the
+/// end of the list use the stage-wide `quality`.
+fn parse_frame_quality(options: &StageParameters, frames: usize) -> Result<Vec<f32>> {
+    let Some(value) = options.get("frame_quality") else {
+        return Ok(Vec::new());
+    };
+    let Value::Array(entries) = value else {
+        bail!("frame_quality must be a list of numbers, got {value}");
+    };
+    if entries.len() > frames {
+        warn!(
+            listed = entries.len(),
+            frames, "frame_quality lists more entries than the animation has frames"
+        );
+    }
+    entries
+        .iter()
+        .take(frames)
+        .map(|entry| {
+            value_as_f64(entry)
+                .map(|quality| quality.clamp(0.0, 100.0) as f32)
+                .ok_or_else(|| anyhow!("frame_quality entries must be numbers, got {entry}"))
+        })
+        .collect()
+}
+
+/// Lossless re-encodes are the only "optimization" that cannot lose detail.
+fn is_lossless(format: ImageFormat) -> bool {
+    matches!(
+        format,
+        ImageFormat::Png | ImageFormat::Bmp | ImageFormat::Tiff | ImageFormat::Pnm
+    )
+}
 
-    let mut path = spec.directory.clone();
-    path.push(file_name);
-    path
+/// Whether `options` ask the `format` encoder to change the pixels it
+/// writes, which a copied source would not reflect. An explicit `bit_depth`
+/// counts even when it matches the source.
+fn transforms_pixels(options: &StageParameters, format: ImageFormat) -> Result<bool> {
+    Ok(parse_gray_bits(options)?.is_some()
+        || parse_bit_depth(options)?.is_some()
+        || (format == ImageFormat::Png && parse_png_palette(options)?.is_some()))
+}
+
+fn smaller_of(source: Vec<u8>, candidate: Vec<u8>) -> Vec<u8> {
+    if candidate.len() < source.len() {
+        buffers::recycle(source);
+        candidate
+    } else {
+        buffers::recycle(candidate);
+        source
+    }
 }
 
 fn encode_with_options(
     image: &DynamicImage,
     format: ImageFormat,
     options: &StageParameters,
-) -> Result<Vec<u8>> {
+    out: &mut dyn Write,
+) -> Result<()> {
+    if let Some(depth @ (BitDepth::Sixteen | BitDepth::Float)) = parse_bit_depth(options)?
+        && !matches!(format, ImageFormat::Png | ImageFormat::Tiff)
+    {
+        bail!(
+            "{format:?} output cannot store {}-bit samples; use png or tiff",
+            depth.bits()
+        );
+    }
+    let reduced;
+    let image = match parse_gray_bits(options)? {
+        Some((bits, dither)) => {
+            let levels = gray_levels(image, bits, dither)?;
+            if format == ImageFormat::Png {
+                return encode_gray_png(&levels, bits, options, out);
+            }
+            reduced = DynamicImage::ImageLuma8(levels);
+            &reduced
+        }
+        None => image,
+    };
     match format {
-        ImageFormat::Jpeg => encode_jpeg(image, options),
-        ImageFormat::Png => encode_png(image, options),
-        ImageFormat::WebP => encode_webp(image, options),
-        ImageFormat::Avif => encode_avif(image, options),
-        ImageFormat::Gif => encode_gif(image, options),
-        _ => encode_generic(image, format),
+        ImageFormat::Jpeg => encode_jpeg(image, options, out),
+        ImageFormat::Png => encode_png(image, options, out),
+        ImageFormat::WebP => encode_webp(image, options, out),
+        ImageFormat::Avif => encode_avif(image, options, out),
+        ImageFormat::Gif => encode_gif(image, options, out),
+        ImageFormat::Tiff => encode_tiff(image, options, out),
+        _ => encode_generic(image, format, out),
     }
 }
 
-fn encode_jpeg(image: &DynamicImage, options: &StageParameters) -> Result<Vec<u8>> {
+fn encode_jpeg(image: &DynamicImage, options: &StageParameters, out: &mut dyn Write) -> Result<()> {
     let (data, width, height) = to_rgb8(image);
-    let mut cursor = Cursor::new(Vec::new());
+    let (width, height) = match (u16::try_from(width), u16::try_from(height)) {
+        (Ok(width), Ok(height)) => (width, height),
+        _ => bail!("JPEG output is limited to 65535x65535 pixels, got {width}x{height}"),
+    };
     let quality = param_u8(options, "quality").unwrap_or(90).clamp(1, 100);
-    {
-        let mut encoder = JpegEncoder::new_with_quality(&mut cursor, quality);
-        if let Some((icc, path)) = load_icc_profile(options)? {
-            encoder.set_icc_profile(icc).map_err(|err| {
-                anyhow!("Failed to apply ICC profile '{path}' for JPEG encoder: {err}")
-            })?;
-        }
-        encoder
-            .write_image(&data, width, height, ExtendedColorType::Rgb8)
-            .context("JPEG encode failed")?;
+    let mut encoder = jpeg_encoder::Encoder::new(out, quality);
+    encoder.set_sampling_factor(parse_jpeg_subsampling(options)?);
+    encoder.set_progressive(param_bool(options, "progressive").unwrap_or(false));
+    encoder.set_optimized_huffman_tables(param_bool(options, "optimize_huffman").unwrap_or(false));
+    if let Some((icc, path)) = load_icc_profile(options)? {
+        encoder.add_icc_profile(&icc).map_err(|err| {
+            anyhow!("Failed to apply ICC profile '{path}' for JPEG encoder: {err}")
+        })?;
     }
-    Ok(cursor.into_inner())
+    encoder
+        .encode(&data, width, height, jpeg_encoder::ColorType::Rgb)
+        .context("JPEG encode failed")?;
+    Ok(())
 }
 
-fn encode_png(image: &DynamicImage, options: &StageParameters) -> Result<Vec<u8>> {
-    let (data, width, height) = to_rgba8(image);
+fn encode_png(image: &DynamicImage, options: &StageParameters, out: &mut dyn Write) -> Result<()> {
     let compression = parse_png_compression(options)?;
     let filter = parse_png_filter(options)?;
-    let mut cursor = Cursor::new(Vec::new());
-    {
-        let mut encoder = PngEncoder::new_with_quality(&mut cursor, compression, filter);
-        if let Some((icc, path)) = load_icc_profile(options)? {
-            encoder.set_icc_profile(icc).map_err(|err| {
-                anyhow!("Failed to apply ICC profile '{path}' for PNG encoder: {err}")
-            })?;
-        }
+    if let Some((colors, dither)) = parse_png_palette(options)? {
+        let indexed = quantize::quantize(&image.to_rgba8(), colors, dither)?;
+        return encode_indexed_png(&indexed, compression, filter, options, out);
+    }
+    let mut encoder = PngEncoder::new_with_quality(out, compression, filter);
+    if let Some((icc, path)) = load_icc_profile(options)? {
+        encoder.set_icc_profile(icc).map_err(|err| {
+            anyhow!("Failed to apply ICC profile '{path}' for PNG encoder: {err}")
+        })?;
+    }
+    let (width, height) = (image.width(), image.height());
+    if png_bit_depth(image, options)? == BitDepth::Sixteen {
+        let wide = to_depth(image, BitDepth::Sixteen, true);
+        encoder
+            .write_image(wide.as_bytes(), width, height, wide.color().into())
+            .context("PNG encode failed")?;
+    } else {
+        let (data, _, _) = to_rgba8(image);
         encoder
             .write_image(&data, width, height, ExtendedColorType::Rgba8)
             .context("PNG encode failed")?;
     }
-    Ok(cursor.into_inner())
+    Ok(())
+}
+
+/// 16-bit and float images stay 16-bit unless `bit_depth: 8` is asked for;
+/// PNG has no float samples.
+fn png_bit_depth(image: &DynamicImage, options: &StageParameters) -> Result<BitDepth> {
+    match parse_bit_depth(options)? {
+        Some(BitDepth::Float) => bail!("PNG output stores at most 16 bits per channel"),
+        Some(depth) => Ok(depth),
+        None if BitDepth::of(image) == BitDepth::Eight => Ok(BitDepth::Eight),
+        None => Ok(BitDepth::Sixteen),
+    }
+}
+
+/// The sample depth `encode_with_options` writes for `image`.
+fn output_bit_depth(
+    image: &DynamicImage,
+    format: ImageFormat,
+    options: &StageParameters,
+) -> Result<BitDepth> {
+    if parse_gray_bits(options)?.is_some() {
+        return Ok(BitDepth::Eight);
+    }
+    Ok(match format {
+        ImageFormat::Png if parse_png_palette(options)?.is_none() => png_bit_depth(image, options)?,
+        ImageFormat::Tiff => parse_bit_depth(options)?.unwrap_or_else(|| BitDepth::of(image)),
+        _ => BitDepth::Eight,
+    })
+}
+
+/// TIFF keeps 8-bit, 16-bit and float samples as decoded unless `bit_depth`
+/// asks for another depth. Gray+alpha is written as RGBA, which the TIFF
+/// encoder supports.
+fn encode_tiff(image: &DynamicImage, options: &StageParameters, out: &mut dyn Write) -> Result<()> {
+    let depth = parse_bit_depth(options)?.unwrap_or_else(|| BitDepth::of(image));
+    let converted = to_depth(image, depth, false);
+    encode_generic(&converted, ImageFormat::Tiff, out)
+}
+
+/// Writes an 8-bit palette PNG; image's encoder only writes direct color.
+fn encode_indexed_png(
+    indexed: &IndexedImage,
+    compression: PngCompressionType,
+    filter: PngFilterType,
+    options: &StageParameters,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let mut info = png::Info::with_size(indexed.width, indexed.height);
+    let icc = load_icc_profile(options)?;
+    if let Some((icc, _)) = &icc {
+        info.icc_profile = Some(Cow::Borrowed(icc));
+    }
+    let mut encoder = png::Encoder::with_info(out, info).context("PNG encode failed")?;
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(indexed.palette.as_slice());
+    if !indexed.alpha.is_empty() {
+        encoder.set_trns(indexed.alpha.as_slice());
+    }
+    configure_png(&mut encoder, compression, filter);
+    let mut writer = encoder.write_header().context("PNG encode failed")?;
+    writer
+        .write_image_data(&indexed.indices)
+        .context("PNG encode failed")?;
+    writer.finish().context("PNG encode failed")?;
+    Ok(())
+}
+
+/// Grayscale PNG with `bits` per sample, from `gray_levels` output.
+fn encode_gray_png(
+    gray: &GrayImage,
+    bits: u8,
+    options: &StageParameters,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let mut info = png::Info::with_size(gray.width(), gray.height());
+    let icc = load_icc_profile(options)?;
+    if let Some((icc, _)) = &icc {
+        info.icc_profile = Some(Cow::Borrowed(icc));
+    }
+    let mut encoder = png::Encoder::with_info(out, info).context("PNG encode failed")?;
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(match bits {
+        1 => png::BitDepth::One,
+        2 => png::BitDepth::Two,
+        4 => png::BitDepth::Four,
+        _ => png::BitDepth::Eight,
+    });
+    configure_png(
+        &mut encoder,
+        parse_png_compression(options)?,
+        parse_png_filter(options)?,
+    );
+    let mut writer = encoder.write_header().context("PNG encode failed")?;
+    writer
+        .write_image_data(&quantize::pack_gray(gray, bits))
+        .context("PNG encode failed")?;
+    writer.finish().context("PNG encode failed")?;
+    Ok(())
+}
+
+fn configure_png<W: Write>(
+    encoder: &mut png::Encoder<'_, W>,
+    compression: PngCompressionType,
+    filter: PngFilterType,
+) {
+    encoder.set_compression(match compression {
+        PngCompressionType::Default => png::Compression::Balanced,
+        PngCompressionType::Best => png::Compression::High,
+        _ => png::Compression::Fast,
+    });
+    encoder.set_filter(match filter {
+        PngFilterType::NoFilter => png::Filter::NoFilter,
+        PngFilterType::Sub => png::Filter::Sub,
+        PngFilterType::Up => png::Filter::Up,
+        PngFilterType::Avg => png::Filter::Avg,
+        PngFilterType::Paeth => png::Filter::Paeth,
+        _ => png::Filter::Adaptive,
+    });
 }
 
-fn encode_webp(image: &DynamicImage, options: &StageParameters) -> Result<Vec<u8>> {
+fn encode_webp(image: &DynamicImage, options: &StageParameters, out: &mut dyn Write) -> Result<()> {
     let lossless = param_bool(options, "lossless").unwrap_or(false);
     let quality = param_f64(options, "quality")
         .unwrap_or(75.0)
         .clamp(0.0, 100.0) as f32;
+    // libwebp only accepts 8-bit RGB(A); widen grayscale and 16-bit images.
+    let widened;
+    let image = match image {
+        DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_) => image,
+        other if other.color().has_alpha() => {
+            widened = DynamicImage::ImageRgba8(other.to_rgba8());
+            &widened
+        }
+        other => {
+            widened = DynamicImage::ImageRgb8(other.to_rgb8());
+            &widened
+        }
+    };
     let encoder = WebpEncoder::from_image(image)
         .map_err(|err| anyhow!("Failed to prepare WebP encoder: {err}"))?;
     let encoded = if lossless {
@@ -383,15 +1542,15 @@ fn encode_webp(image: &DynamicImage, options: &StageParameters) -> Result<Vec<u8
     } else {
         encoder.encode(quality)
     };
-    Ok(encoded.to_vec())
+    out.write_all(&encoded).context("WebP write failed")?;
+    Ok(())
 }
 
-fn encode_avif(image: &DynamicImage, options: &StageParameters) -> Result<Vec<u8>> {
+fn encode_avif(image: &DynamicImage, options: &StageParameters, out: &mut dyn Write) -> Result<()> {
     let (data, width, height) = to_rgba8(image);
     let quality = param_u8(options, "quality").unwrap_or(80).clamp(1, 100);
     let speed = param_u8(options, "speed").unwrap_or(4).clamp(1, 10);
-    let mut cursor = Cursor::new(Vec::new());
-    let encoder = AvifEncoder::new_with_speed_quality(&mut cursor, speed, quality);
+    let encoder = AvifEncoder::new_with_speed_quality(out, speed, quality);
     let encoder = match parse_avif_colorspace(options)? {
         Some(space) => encoder.with_colorspace(space),
         None => encoder,
@@ -399,15 +1558,14 @@ fn encode_avif(image: &DynamicImage, options: &StageParameters) -> Result<Vec<u8
     encoder
         .write_image(&data, width, height, ExtendedColorType::Rgba8)
         .context("AVIF encode failed")?;
-    Ok(cursor.into_inner())
+    Ok(())
 }
 
-fn encode_gif(image: &DynamicImage, options: &StageParameters) -> Result<Vec<u8>> {
+fn encode_gif(image: &DynamicImage, options: &StageParameters, out: &mut dyn Write) -> Result<()> {
     let (data, width, height) = to_rgba8(image);
     let speed = param_u8(options, "speed").unwrap_or(10).clamp(1, 30) as i32;
-    let mut cursor = Cursor::new(Vec::new());
     {
-        let mut encoder = GifEncoder::new_with_speed(&mut cursor, speed);
+        let mut encoder = GifEncoder::new_with_speed(out, speed);
         if let Some(repeat) = parse_gif_repeat(options)? {
             encoder
                 .set_repeat(repeat)
@@ -417,27 +1575,119 @@ fn encode_gif(image: &DynamicImage, options: &StageParameters) -> Result<Vec<u8>
             .encode(&data, width, height, ExtendedColorType::Rgba8)
             .context("GIF encode failed")?;
     }
-    Ok(cursor.into_inner())
+    Ok(())
 }
 
-fn encode_generic(image: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>> {
-    let mut cursor = Cursor::new(Vec::new());
+fn encode_generic(image: &DynamicImage, format: ImageFormat, out: &mut dyn Write) -> Result<()> {
+    // The generic encoders need to seek, so they go through a pooled buffer.
+    let mut cursor = encode_cursor(image);
     image
         .write_to(&mut cursor, format)
         .with_context(|| format!("Failed to encode image as {:?}", format))?;
-    Ok(cursor.into_inner())
+    let buffer = cursor.into_inner();
+    out.write_all(&buffer)
+        .with_context(|| format!("Failed to write {:?} output", format))?;
+    buffers::recycle(buffer);
+    Ok(())
+}
+
+fn encode_cursor(image: &DynamicImage) -> Cursor<Vec<u8>> {
+    // Compressed output is rarely larger than one byte per pixel; the pooled
+    // buffer grows as needed when it is.
+    let estimate = image.width() as usize * image.height() as usize;
+    Cursor::new(buffers::take(estimate))
+}
+
+fn to_rgb8(image: &DynamicImage) -> (Cow<'_, [u8]>, u32, u32) {
+    let (width, height) = (image.width(), image.height());
+    match image.as_rgb8() {
+        Some(rgb) => (Cow::Borrowed(rgb.as_raw().as_slice()), width, height),
+        None => (Cow::Owned(image.to_rgb8().into_raw()), width, height),
+    }
+}
+
+fn to_rgba8(image: &DynamicImage) -> (Cow<'_, [u8]>, u32, u32) {
+    let (width, height) = (image.width(), image.height());
+    match image.as_rgba8() {
+        Some(rgba) => (Cow::Borrowed(rgba.as_raw().as_slice()), width, height),
+        None => (Cow::Owned(image.to_rgba8().into_raw()), width, height),
+    }
 }
 
-fn to_rgb8(image: &DynamicImage) -> (Vec<u8>, u32, u32) {
-    let rgb = image.to_rgb8();
-    let (width, height) = rgb.dimensions();
-    (rgb.into_raw(), width, height)
+/// Sample depth of decoded pixels, or the depth an encode writes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BitDepth {
+    Eight,
+    Sixteen,
+    Float,
 }
 
-fn to_rgba8(image: &DynamicImage) -> (Vec<u8>, u32, u32) {
-    let rgba = image.to_rgba8();
-    let (width, height) = rgba.dimensions();
-    (rgba.into_raw(), width, height)
+impl BitDepth {
+    fn of(image: &DynamicImage) -> Self {
+        match image.color().bytes_per_pixel() / image.color().channel_count() {
+            1 => Self::Eight,
+            2 => Self::Sixteen,
+            _ => Self::Float,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        match self {
+            Self::Eight => 8,
+            Self::Sixteen => 16,
+            Self::Float => 32,
+        }
+    }
+}
+
+/// `bit_depth`: 8, 16 or 32 (float); unset keeps the decoded depth where the
+/// output format can store it.
+fn parse_bit_depth(options: &StageParameters) -> Result<Option<BitDepth>> {
+    match options.get("bit_depth") {
+        None => Ok(None),
+        Some(Value::String(value)) if value.eq_ignore_ascii_case("auto") => Ok(None),
+        Some(value) => match value_as_u64(value) {
+            Some(8) => Ok(Some(BitDepth::Eight)),
+            Some(16) => Ok(Some(BitDepth::Sixteen)),
+            Some(32) => Ok(Some(BitDepth::Float)),
+            _ => bail!("bit_depth must be 8, 16, 32 or auto, got {value}"),
+        },
+    }
+}
+
+/// `image` with `depth` samples and its channels kept, borrowed when it
+/// already has them. Gray+alpha becomes RGBA unless `gray_alpha`.
+fn to_depth(image: &DynamicImage, depth: BitDepth, gray_alpha: bool) -> Cow<'_, DynamicImage> {
+    let color = image.color();
+    let alpha = color.has_alpha();
+    let gray = !color.has_color() && (gray_alpha || !alpha) && depth != BitDepth::Float;
+    let target = match (depth, gray, alpha) {
+        (BitDepth::Eight, true, false) => ColorType::L8,
+        (BitDepth::Eight, true, true) => ColorType::La8,
+        (BitDepth::Eight, false, false) => ColorType::Rgb8,
+        (BitDepth::Eight, false, true) => ColorType::Rgba8,
+        (BitDepth::Sixteen, true, false) => ColorType::L16,
+        (BitDepth::Sixteen, true, true) => ColorType::La16,
+        (BitDepth::Sixteen, false, false) => ColorType::Rgb16,
+        (BitDepth::Sixteen, false, true) => ColorType::Rgba16,
+        (BitDepth::Float, _, false) => ColorType::Rgb32F,
+        (BitDepth::Float, _, true) => ColorType::Rgba32F,
+    };
+    if target == color {
+        return Cow::Borrowed(image);
+    }
+    Cow::Owned(match target {
+        ColorType::L8 => DynamicImage::ImageLuma8(image.to_luma8()),
+        ColorType::La8 => DynamicImage::ImageLumaA8(image.to_luma_alpha8()),
+        ColorType::Rgb8 => DynamicImage::ImageRgb8(image.to_rgb8()),
+        ColorType::L16 => DynamicImage::ImageLuma16(image.to_luma16()),
+        ColorType::La16 => DynamicImage::ImageLumaA16(image.to_luma_alpha16()),
+        ColorType::Rgb16 => DynamicImage::ImageRgb16(image.to_rgb16()),
+        ColorType::Rgba16 => DynamicImage::ImageRgba16(image.to_rgba16()),
+        ColorType::Rgb32F => DynamicImage::ImageRgb32F(image.to_rgb32f()),
+        ColorType::Rgba32F => DynamicImage::ImageRgba32F(image.to_rgba32f()),
+        _ => DynamicImage::ImageRgba8(image.to_rgba8()),
+    })
 }
 
 fn load_icc_profile(options: &StageParameters) -> Result<Option<(Vec<u8>, String)>> {
@@ -452,6 +1702,23 @@ fn load_icc_profile(options: &StageParameters) -> Result<Option<(Vec<u8>, String
     }
 }
 
+fn parse_jpeg_subsampling(options: &StageParameters) -> Result<jpeg_encoder::SamplingFactor> {
+    let Some(value) = options.get("subsampling") else {
+        return Ok(jpeg_encoder::SamplingFactor::R_4_2_0);
+    };
+    let label = match value {
+        Value::String(s) => s.trim().to_string(),
+        Value::Number(num) => num.to_string(),
+        _ => bail!("Unsupported JPEG subsampling value: {value}"),
+    };
+    match label.replace(['-', '_'], ":").as_str() {
+        "4:4:4" | "444" => Ok(jpeg_encoder::SamplingFactor::R_4_4_4),
+        "4:2:2" | "422" => Ok(jpeg_encoder::SamplingFactor::R_4_2_2),
+        "4:2:0" | "420" => Ok(jpeg_encoder::SamplingFactor::R_4_2_0),
+        _ => bail!("Unknown JPEG subsampling '{label}' (expected 4:4:4, 4:2:2 or 4:2:0)"),
+    }
+}
+
 fn parse_png_compression(options: &StageParameters) -> Result<PngCompressionType> {
     let Some(value) = options.get("compression") else {
         return Ok(PngCompressionType::Default);
@@ -475,6 +1742,71 @@ fn parse_png_compression(options: &StageParameters) -> Result<PngCompressionType
     bail!("Unsupported PNG compression value: {value}")
 }
 
+/// `colors` turns on palette output; `dither` defaults to Floyd-Steinberg.
+fn parse_png_palette(options: &StageParameters) -> Result<Option<(usize, Dither)>> {
+    let Some(value) = options.get("colors") else {
+        if options.contains_key("dither") && !options.contains_key("gray_bits") {
+            bail!("PNG 'dither' only applies to palette output; set 'colors' as well");
+        }
+        return Ok(None);
+    };
+    let colors = value_as_u64(value)
+        .filter(|colors| (2..=256).contains(colors))
+        .ok_or_else(|| anyhow!("PNG 'colors' must be between 2 and 256, got {value}"))?;
+    let dither = parse_dither(options)?;
+    if dither == Dither::Ordered {
+        bail!("ordered dithering only applies to gray_bits output");
+    }
+    Ok(Some((colors as usize, dither)))
+}
+
+/// `gray_bits` turns on 1, 2, 4 or 8-bit grayscale output; `dither`
+/// defaults to Floyd-Steinberg.
+fn parse_gray_bits(options: &StageParameters) -> Result<Option<(u8, Dither)>> {
+    let Some(value) = options.get("gray_bits") else {
+        return Ok(None);
+    };
+    let bits = value_as_u64(value)
+        .filter(|bits| matches!(bits, 1 | 2 | 4 | 8))
+        .ok_or_else(|| anyhow!("gray_bits must be 1, 2, 4 or 8, got {value}"))?;
+    if options.contains_key("colors") {
+        bail!("gray_bits and colors cannot be combined");
+    }
+    if matches!(
+        parse_bit_depth(options)?,
+        Some(BitDepth::Sixteen | BitDepth::Float)
+    ) {
+        bail!("gray_bits output is at most 8 bits per sample; drop bit_depth");
+    }
+    Ok(Some((bits as u8, parse_dither(options)?)))
+}
+
+fn parse_dither(options: &StageParameters) -> Result<Dither> {
+    Ok(match options.get("dither") {
+        None => Dither::FloydSteinberg,
+        Some(Value::Bool(true)) => Dither::FloydSteinberg,
+        Some(Value::Bool(false)) => Dither::None,
+        Some(Value::String(method)) => Dither::from_name(method).ok_or_else(|| {
+            anyhow!("Unknown dither method '{method}' (expected floyd_steinberg, ordered or none)")
+        })?,
+        Some(other) => bail!("Unsupported dither value: {other}"),
+    })
+}
+
+/// The luminance of `image` over white paper, reduced to `bits` per sample.
+fn gray_levels(image: &DynamicImage, bits: u8, dither: Dither) -> Result<GrayImage> {
+    let gray = if image.color().has_alpha() {
+        let luma_alpha = image.to_luma_alpha8();
+        GrayImage::from_fn(image.width(), image.height(), |x, y| {
+            let [luma, alpha] = luma_alpha.get_pixel(x, y).0.map(u16::from);
+            Luma([((luma * alpha + 255 * (255 - alpha) + 127) / 255) as u8])
+        })
+    } else {
+        image.to_luma8()
+    };
+    quantize::reduce_gray(&gray, bits, dither)
+}
+
 fn parse_png_filter(options: &StageParameters) -> Result<PngFilterType> {
     let Some(value) = options.get("filter") else {
         return Ok(PngFilterType::Adaptive);
@@ -565,7 +1897,21 @@ fn record_encoder_metadata(artifact: &mut Artifact, options: &StageParameters) {
             .metadata
             .insert("output.encoder.colorspace".into(), Value::String(color));
     }
-    for key in ["compression", "filter", "repeat"] {
+    for key in [
+        "effort",
+        "progressive",
+        "subsampling",
+        "optimize_huffman",
+        "colors",
+        "gray_bits",
+        "dither",
+        "compression",
+        "filter",
+        "repeat",
+        "frame_delay_ms",
+        "frame_quality",
+        "bit_depth",
+    ] {
         if let Some(value) = options.get(key) {
             artifact
                 .metadata
@@ -628,6 +1974,31 @@ fn value_as_bool(value: &Value) -> Option<bool> {
     }
 }
 
+/// Parses `#RGB`, `#RRGGBB`, `#RRGGBBAA` (leading `#` optional) or
+/// `transparent`.
+fn parse_color(value: &str) -> Result<Rgba<u8>> {
+    if value.trim().eq_ignore_ascii_case("transparent") {
+        return Ok(Rgba([0, 0, 0, 0]));
+    }
+    let hex = value.trim().trim_start_matches('#');
+    let expanded: String = if hex.len() == 3 {
+        hex.chars().flat_map(|c| [c, c]).collect()
+    } else {
+        hex.to_string()
+    };
+    let channel = |index: usize| {
+        expanded
+            .get(index * 2..index * 2 + 2)
+            .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+            .ok_or_else(|| anyhow!("Invalid color '{value}'"))
+    };
+    match expanded.len() {
+        6 => Ok(Rgba([channel(0)?, channel(1)?, channel(2)?, 255])),
+        8 => Ok(Rgba([channel(0)?, channel(1)?, channel(2)?, channel(3)?])),
+        _ => bail!("Invalid color '{value}'"),
+    }
+}
+
 fn take_string(params: &mut StageParameters, key: &str) -> Option<String> {
     params.remove(key).map(|value| match value {
         Value::String(s) => s,
@@ -635,6 +2006,24 @@ fn take_string(params: &mut StageParameters, key: &str) -> Option<String> {
     })
 }
 
+fn take_bool(params: &mut StageParameters, key: &str) -> Result<Option<bool>> {
+    params
+        .remove(key)
+        .map(|value| {
+            value_as_bool(&value).ok_or_else(|| anyhow!("{key} must be a boolean, got {value}"))
+        })
+        .transpose()
+}
+
+fn take_f64(params: &mut StageParameters, key: &str) -> Result<Option<f64>> {
+    params
+        .remove(key)
+        .map(|value| {
+            value_as_f64(&value).ok_or_else(|| anyhow!("{key} must be a number, got {value}"))
+        })
+        .transpose()
+}
+
 fn take_u32(params: &mut StageParameters, key: &str) -> Option<u32> {
     params.remove(key).and_then(|value| match value {
         Value::Number(num) => num.as_u64().and_then(|n| n.try_into().ok()),
@@ -643,6 +2032,59 @@ fn take_u32(params: &mut StageParameters, key: &str) -> Option<u32> {
     })
 }
 
+/// `true`/`false`, `all`/`none`, or `exif`, `xmp` or a list of them.
+fn parse_preserve_metadata(value: &Value) -> Result<(bool, bool)> {
+    let kinds = match value {
+        Value::Array(kinds) => kinds.iter().collect(),
+        other => vec![other],
+    };
+    let (mut exif, mut xmp) = (false, false);
+    for kind in kinds {
+        if let Some(preserve) = value_as_bool(kind) {
+            exif |= preserve;
+            xmp |= preserve;
+            continue;
+        }
+        match kind
+            .as_str()
+            .map(|kind| kind.trim().to_lowercase())
+            .as_deref()
+        {
+            Some("all") => (exif, xmp) = (true, true),
+            Some("none") => {}
+            Some("exif") => exif = true,
+            Some("xmp") => xmp = true,
+            _ => bail!("Unknown preserve_metadata value {kind} (expected true, exif or xmp)"),
+        }
+    }
+    Ok((exif, xmp))
+}
+
+#[derive(Clone, Copy)]
+enum VerifyOutput {
+    Auto,
+    Always,
+    Never,
+}
+
+impl VerifyOutput {
+    fn from_str(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "auto" => Some(Self::Auto),
+            "always" | "true" => Some(Self::Always),
+            "never" | "false" => Some(Self::Never),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Passthrough {
+    Off,
+    Copy,
+    Optimize,
+}
+
 #[derive(Clone, Copy)]
 enum ResizeMode {
     Inside,
@@ -712,6 +2154,29 @@ fn format_from_label(label: &str) -> Option<ImageFormat> {
     ImageFormat::from_extension(&normalized)
 }
 
+/// A format label, or `{ format, ...options }` giving that format encoder
+/// options of its own; `what` names the list in errors.
+fn format_with_options(entry: Value, what: &str) -> Result<(ImageFormat, StageParameters)> {
+    let (label, options) = match entry {
+        Value::String(label) => (label, StageParameters::new()),
+        Value::Object(mut options) => match options.remove("format") {
+            Some(Value::String(label)) => (label, options),
+            _ => bail!("{what} need a 'format'"),
+        },
+        other => bail!("{what} must be formats, got {other}"),
+    };
+    let format =
+        format_from_label(&label).ok_or_else(|| anyhow!("Unknown format '{label}' in {what}"))?;
+    Ok((format, options))
+}
+
+fn is_pdf_label(label: &str) -> bool {
+    label
+        .trim()
+        .trim_start_matches('.')
+        .eq_ignore_ascii_case("pdf")
+}
+
 fn format_extension(format: ImageFormat) -> &'static str {
     format.extensions_str().first().copied().unwrap_or("bin")
 }
@@ -736,10 +2201,30 @@ fn format_filter(filter: ResizeFilter) -> String {
     .to_string()
 }
 
+/// The parameters in a `json!` object, for tests.
+#[cfg(test)]
+pub(crate) fn json_params(params: Value) -> StageParameters {
+    match params {
+        Value::Object(params) => params,
+        other => panic!("stage parameters must be a JSON object, got {other}"),
+    }
+}
+
+/// Builds a stage with `from_params` from a `json!` object, for tests.
+#[cfg(test)]
+pub(crate) fn from_json<S>(
+    from_params: impl FnOnce(StageParameters) -> Result<S>,
+    params: Value,
+) -> Result<S> {
+    from_params(json_params(params))
+}
+
 #[cfg(test)]
 mod tests {
-    use super::map_filter;
+    use super::{encode_jpeg, encode_with_options, json_params, map_filter};
     use image::imageops::FilterType;
+    use image::{ColorType, DynamicImage, ImageBuffer, ImageFormat, Rgb, RgbImage};
+    use serde_json::{Value, json};
 
     #[test]
     fn filter_mapping() {
@@ -747,4 +2232,71 @@ mod tests {
         assert_eq!(map_filter("nearest".into()), Some(FilterType::Nearest));
         assert_eq!(map_filter("unknown".into()), None);
     }
+
+    /// The SOF marker and the luma sampling byte of an encoded JPEG.
+    fn frame_header(jpeg: &[u8]) -> (u8, u8) {
+        let at = jpeg
+            .windows(2)
+            .position(|pair| pair[0] == 0xFF && (0xC0..=0xC2).contains(&pair[1]))
+            .expect("JPEG has a frame header");
+        // FFCx, length (2), precision, height (2), width (2), count, id, sampling
+        (jpeg[at + 1], jpeg[at + 11])
+    }
+
+    #[test]
+    fn jpeg_progressive_and_subsampling() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(40, 24, |x, y| {
+            image::Rgb([(x * 6) as u8, (y * 10) as u8, 128])
+        }));
+        let encode = |options: Value| {
+            let options = json_params(options);
+            let mut out = Vec::new();
+            encode_jpeg(&image, &options, &mut out).map(|_| out)
+        };
+
+        let baseline = encode(json!({})).unwrap();
+        assert_eq!(frame_header(&baseline), (0xC0, 0x22));
+        let progressive = encode(json!({
+            "progressive": true,
+            "subsampling": "4:4:4",
+            "optimize_huffman": true,
+        }))
+        .unwrap();
+        assert_eq!(frame_header(&progressive), (0xC2, 0x11));
+        let decoded = image::load_from_memory(&progressive).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (40, 24));
+        let optimized =
+            encode(json!({ "subsampling": "4:2:2", "optimize_huffman": true })).unwrap();
+        assert_eq!(frame_header(&optimized), (0xC0, 0x21));
+
+        assert!(encode(json!({ "subsampling": "4:1:1" })).is_err());
+    }
+
+    #[test]
+    fn deep_images_keep_their_depth_in_png_and_tiff() {
+        let deep = DynamicImage::ImageRgb16(ImageBuffer::from_fn(9, 7, |x, y| {
+            Rgb([x as u16 * 7001, y as u16 * 9001, 257])
+        }));
+        let encode = |image: &DynamicImage, format: ImageFormat, options: Value| {
+            let options = json_params(options);
+            let mut out = Vec::new();
+            encode_with_options(image, format, &options, &mut out).map(|_| out)
+        };
+
+        let png = encode(&deep, ImageFormat::Png, json!({})).unwrap();
+        assert_eq!(image::load_from_memory(&png).unwrap(), deep);
+        let narrowed = encode(&deep, ImageFormat::Png, json!({ "bit_depth": 8 })).unwrap();
+        assert_eq!(
+            image::load_from_memory(&narrowed).unwrap().color(),
+            ColorType::Rgba8
+        );
+        assert!(encode(&deep, ImageFormat::Png, json!({ "bit_depth": 32 })).is_err());
+        assert!(encode(&deep, ImageFormat::Jpeg, json!({ "bit_depth": 16 })).is_err());
+
+        let float = DynamicImage::ImageRgb32F(deep.to_rgb32f());
+        let tiff = encode(&float, ImageFormat::Tiff, json!({})).unwrap();
+        assert_eq!(image::load_from_memory(&tiff).unwrap(), float);
+        let tiff16 = encode(&float, ImageFormat::Tiff, json!({ "bit_depth": 16 })).unwrap();
+        assert_eq!(image::load_from_memory(&tiff16).unwrap(), deep);
+    }
 }
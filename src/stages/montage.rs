@@ -0,0 +1,434 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result, anyhow, bail};
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, ImageFormat, Rgba, RgbaImage};
+use serde_json::{Map, Value, json};
+use tracing::info;
+
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+use crate::sink::{FileSink, OutputSink};
+
+use super::{
+    encode_cursor, encode_with_options, format_extension, format_from_label, parse_color,
+    take_bool, take_string, take_u32,
+};
+
+const DEFAULT_SHEET: &str = "sprites";
+
+/// Where a tile landed on the composite, in pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Placement {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+/// One artifact's image waiting for the combined sheet.
+struct Sprite {
+    input: PathBuf,
+    name: String,
+    image: Arc<DynamicImage>,
+}
+
+/// How the run's images become one sprite sheet, for `combine: true`.
+struct Sheet {
+    name: String,
+    format: ImageFormat,
+    /// Also writes `{name}.json` with every sprite's position.
+    map: bool,
+    pending: Mutex<Vec<Sprite>>,
+}
+
+/// Lays the artifact image and a set of extra images out on a grid and
+/// replaces the artifact image with the composite. With `combine` it instead
+/// collects the image of every artifact in the run and writes them as one
+/// sprite sheet once all inputs are done, leaving the artifacts as they are.
+pub struct MontageStage {
+    tiles: Vec<PathBuf>,
+    include_self: bool,
+    columns: Option<u32>,
+    rows: Option<u32>,
+    gutter: u32,
+    background: Rgba<u8>,
+    cell: Option<(u32, u32)>,
+    sheet: Option<Sheet>,
+}
+
+impl MontageStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let tiles = match params.remove("tiles") {
+            Some(Value::String(pattern)) => expand_tiles(&pattern)?,
+            Some(Value::Array(entries)) => entries
+                .into_iter()
+                .map(|entry| match entry {
+                    Value::String(path) => Ok(PathBuf::from(path)),
+                    other => Err(anyhow!("montage tiles must be paths, got {other}")),
+                })
+                .collect::<Result<_>>()?,
+            Some(other) => bail!("montage tiles must be a glob or a list of paths, got {other}"),
+            None => Vec::new(),
+        };
+        let include_self = take_bool(&mut params, "include_self")?.unwrap_or(true);
+        let sheet = Sheet::from_params(&mut params)?;
+        if sheet.is_some() && (!tiles.is_empty() || !include_self) {
+            bail!("montage combine lays out the run's own images; drop tiles and include_self");
+        }
+        if tiles.is_empty() && !include_self {
+            bail!("montage stage needs 'tiles' when include_self is false");
+        }
+        let columns = take_u32(&mut params, "columns").filter(|&n| n > 0);
+        let rows = take_u32(&mut params, "rows").filter(|&n| n > 0);
+        let gutter = match take_u32(&mut params, "padding") {
+            Some(padding) => padding,
+            None => take_u32(&mut params, "gutter").unwrap_or(0),
+        };
+        let background = match take_string(&mut params, "background") {
+            Some(color) => parse_color(&color).context("Invalid montage background")?,
+            None => Rgba([0, 0, 0, 0]),
+        };
+        let cell = match (
+            take_u32(&mut params, "cell_width"),
+            take_u32(&mut params, "cell_height"),
+        ) {
+            (Some(width), Some(height)) if width > 0 && height > 0 => Some((width, height)),
+            (None, None) => None,
+            _ => bail!("montage cell_width and cell_height must be set together and be positive"),
+        };
+        Ok(Self {
+            tiles,
+            include_self,
+            columns,
+            rows,
+            gutter,
+            background,
+            cell,
+            sheet,
+        })
+    }
+
+    fn grid(&self, count: u32) -> (u32, u32) {
+        match (self.columns, self.rows) {
+            (Some(columns), _) => (columns, count.div_ceil(columns)),
+            (None, Some(rows)) => (count.div_ceil(rows), rows),
+            (None, None) => {
+                let columns = (count as f64).sqrt().ceil() as u32;
+                (columns, count.div_ceil(columns))
+            }
+        }
+    }
+
+    fn compose(&self, images: &[Arc<DynamicImage>]) -> Result<(RgbaImage, Vec<Placement>)> {
+        let count = u32::try_from(images.len()).context("too many montage tiles")?;
+        let (columns, rows) = self.grid(count);
+        if columns * rows < count {
+            bail!("montage grid {columns}x{rows} cannot hold {count} tiles");
+        }
+        let (cell_width, cell_height) = self.cell.unwrap_or_else(|| {
+            images.iter().fold((1, 1), |(w, h), image| {
+                (w.max(image.width()), h.max(image.height()))
+            })
+        });
+        let width = columns * cell_width + (columns + 1) * self.gutter;
+        let height = rows * cell_height + (rows + 1) * self.gutter;
+        let mut canvas = RgbaImage::from_pixel(width, height, self.background);
+        let mut placements = Vec::with_capacity(images.len());
+
+        for (index, image) in (0u32..).zip(images) {
+            let tile = if image.width() > cell_width || image.height() > cell_height {
+                image
+                    .resize(cell_width, cell_height, FilterType::CatmullRom)
+                    .to_rgba8()
+            } else {
+                image.to_rgba8()
+            };
+            let column = index % columns;
+            let row = index / columns;
+            let x = self.gutter + column * (cell_width + self.gutter);
+            let y = self.gutter + row * (cell_height + self.gutter);
+            let offset_x = (cell_width - tile.width()) / 2;
+            let offset_y = (cell_height - tile.height()) / 2;
+            imageops::overlay(
+                &mut canvas,
+                &tile,
+                i64::from(x + offset_x),
+                i64::from(y + offset_y),
+            );
+            placements.push(Placement {
+                x: x + offset_x,
+                y: y + offset_y,
+                width: tile.width(),
+                height: tile.height(),
+            });
+        }
+        Ok((canvas, placements))
+    }
+}
+
+impl Sheet {
+    fn from_params(params: &mut StageParameters) -> Result<Option<Self>> {
+        let combine = take_bool(params, "combine")?.unwrap_or(false);
+        let name = take_string(params, "sheet");
+        let format = take_string(params, "format");
+        let map = take_bool(params, "map")?;
+        if !combine {
+            if name.is_some() || format.is_some() || map.is_some() {
+                bail!("montage sheet, format and map require combine: true");
+            }
+            return Ok(None);
+        }
+        let format = match format {
+            Some(label) => format_from_label(&label)
+                .ok_or_else(|| anyhow!("Unknown montage sheet format '{label}'"))?,
+            None => ImageFormat::Png,
+        };
+        Ok(Some(Self {
+            name: name.unwrap_or_else(|| DEFAULT_SHEET.to_string()),
+            format,
+            map: map.unwrap_or(false),
+            pending: Mutex::new(Vec::new()),
+        }))
+    }
+
+    fn path(&self, ctx: &PipelineContext, extension: &str) -> PathBuf {
+        ctx.output.resolve(&self.name, extension, &Map::new())
+    }
+
+    /// Lists every sprite's name, source and place on the sheet.
+    fn map_json(
+        &self,
+        sheet: &Path,
+        canvas: &DynamicImage,
+        sprites: &[Sprite],
+        placements: &[Placement],
+    ) -> Value {
+        let entries: Vec<Value> = sprites
+            .iter()
+            .zip(placements)
+            .map(|(sprite, placement)| {
+                json!({
+                    "name": sprite.name,
+                    "source": sprite.input.to_string_lossy(),
+                    "x": placement.x,
+                    "y": placement.y,
+                    "width": placement.width,
+                    "height": placement.height,
+                })
+            })
+            .collect();
+        json!({
+            "image": sheet.file_name().map(|name| name.to_string_lossy()),
+            "width": canvas.width(),
+            "height": canvas.height(),
+            "sprites": entries,
+        })
+    }
+}
+
+fn write_file(path: &Path, bytes: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create output directory: {}", parent.display()))?;
+    }
+    let mut sink = FileSink::create(path)?;
+    sink.write_all(bytes)
+        .with_context(|| format!("Failed to write output file: {}", path.display()))?;
+    sink.finish()?;
+    Ok(())
+}
+
+impl Stage for MontageStage {
+    fn name(&self) -> &'static str {
+        "montage"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    /// Tiles are read from disk, so their contents are not part of the
+    /// cache key.
+    fn cacheable(&self) -> bool {
+        false
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        if let Some(sheet) = &self.sheet {
+            let image = artifact
+                .image
+                .clone()
+                .ok_or_else(|| anyhow!("montage stage requires a decoded image"))?;
+            sheet
+                .pending
+                .lock()
+                .map_err(|_| anyhow!("montage sprite buffer poisoned"))?
+                .push(Sprite {
+                    input: artifact.input_path.clone(),
+                    name: artifact.stem.clone(),
+                    image,
+                });
+            let path = sheet.path(ctx, format_extension(sheet.format));
+            artifact.metadata.insert(
+                "montage.sheet".to_string(),
+                Value::String(path.to_string_lossy().to_string()),
+            );
+            // Stands as the output unless a later encode writes one per input.
+            artifact.metadata.insert(
+                "output_path".to_string(),
+                Value::String(path.to_string_lossy().to_string()),
+            );
+            return Ok(());
+        }
+        let mut images = Vec::with_capacity(self.tiles.len() + 1);
+        if self.include_self {
+            let image = artifact
+                .image
+                .clone()
+                .ok_or_else(|| anyhow!("montage stage requires a decoded image"))?;
+            images.push(image);
+        }
+        for path in &self.tiles {
+            let tile = image::open(path)
+                .with_context(|| format!("Failed to decode montage tile: {}", path.display()))?;
+            images.push(Arc::new(tile));
+        }
+
+        let (composite, _) = self.compose(&images)?;
+        artifact
+            .metadata
+            .insert("montage.tiles".to_string(), json!(images.len()));
+        artifact
+            .metadata
+            .insert("image.width".to_string(), json!(composite.width()));
+        artifact
+            .metadata
+            .insert("image.height".to_string(), json!(composite.height()));
+        artifact.set_image(DynamicImage::ImageRgba8(composite));
+        Ok(())
+    }
+
+    /// Writes the combined sprite sheet (and its map), sprites ordered by
+    /// input path so the layout does not depend on which worker finished
+    /// first.
+    fn finalize(&self, ctx: &PipelineContext) -> Result<()> {
+        let Some(sheet) = &self.sheet else {
+            return Ok(());
+        };
+        let mut sprites = std::mem::take(
+            &mut *sheet
+                .pending
+                .lock()
+                .map_err(|_| anyhow!("montage sprite buffer poisoned"))?,
+        );
+        if sprites.is_empty() {
+            return Ok(());
+        }
+        let path = sheet.path(ctx, format_extension(sheet.format));
+        // The sheet is kept only if the policy keeps it for every sprite.
+        let kept: Option<Vec<_>> = sprites
+            .iter()
+            .map(|sprite| ctx.overwrite.keep_reason(&sprite.input, &path))
+            .collect();
+        if let Some(reason) = kept.and_then(|reasons| reasons.first().copied()) {
+            info!(output = %path.display(), reason, "Keeping existing output");
+            return Ok(());
+        }
+        sprites.sort_by(|a, b| a.input.cmp(&b.input));
+        let images: Vec<Arc<DynamicImage>> = sprites
+            .iter()
+            .map(|sprite| Arc::clone(&sprite.image))
+            .collect();
+        let (canvas, placements) = self.compose(&images)?;
+        let composite = DynamicImage::ImageRgba8(canvas);
+        let mut cursor = encode_cursor(&composite);
+        encode_with_options(
+            &composite,
+            sheet.format,
+            &StageParameters::new(),
+            &mut cursor,
+        )
+        .with_context(|| format!("Failed to encode sprite sheet as {:?}", sheet.format))?;
+        write_file(&path, &cursor.into_inner())?;
+        if sheet.map {
+            let map = sheet.map_json(&path, &composite, &sprites, &placements);
+            let json = serde_json::to_vec_pretty(&map)?;
+            write_file(&sheet.path(ctx, "json"), &json)?;
+        }
+        Ok(())
+    }
+}
+
+fn expand_tiles(pattern: &str) -> Result<Vec<PathBuf>> {
+    let mut tiles = Vec::new();
+    for entry in
+        glob::glob(pattern).with_context(|| format!("Invalid montage tiles pattern: {pattern}"))?
+    {
+        let path = entry?;
+        if path.is_file() {
+            tiles.push(path);
+        }
+    }
+    if tiles.is_empty() {
+        bail!("No montage tiles matched pattern: {pattern}");
+    }
+    Ok(tiles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stages::from_json;
+
+    #[test]
+    fn colors_accept_short_and_alpha_forms() {
+        assert_eq!(parse_color("#fff").unwrap(), Rgba([255, 255, 255, 255]));
+        assert_eq!(parse_color("10203040").unwrap(), Rgba([16, 32, 48, 64]));
+        assert!(parse_color("#12345").is_err());
+    }
+
+    #[test]
+    fn grid_places_tiles_with_gutters() {
+        let montage = from_json(
+            MontageStage::from_params,
+            json!({
+                "tiles": ["unused.png"],
+                "columns": 2,
+                "gutter": 1,
+                "background": "#00ff00",
+            }),
+        )
+        .unwrap();
+        let tile = |color| Arc::new(DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, color)));
+        let red = Rgba([255, 0, 0, 255]);
+        let blue = Rgba([0, 0, 255, 255]);
+        let (canvas, placements) = montage
+            .compose(&[tile(red), tile(blue), tile(red)])
+            .unwrap();
+
+        assert_eq!(canvas.dimensions(), (7, 7));
+        assert_eq!(canvas.get_pixel(0, 0), &Rgba([0, 255, 0, 255]));
+        assert_eq!(canvas.get_pixel(1, 1), &red);
+        assert_eq!(canvas.get_pixel(4, 1), &blue);
+        assert_eq!(canvas.get_pixel(1, 4), &red);
+        assert_eq!(canvas.get_pixel(4, 4), &Rgba([0, 255, 0, 255]));
+        assert_eq!(
+            placements[1],
+            Placement {
+                x: 4,
+                y: 1,
+                width: 2,
+                height: 2
+            }
+        );
+    }
+}
@@ -0,0 +1,314 @@
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result, anyhow, bail};
+use image::ImageFormat;
+use oxipng::{Deflater, IndexSet, StripChunks, ZopfliOptions};
+use serde_json::{Value, json};
+use tracing::debug;
+
+use crate::overwrite;
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+use crate::sink::{FileSink, OutputSink};
+
+use super::{
+    format_extension, jpeg_optimize, keep_existing_output, resolve_output_path, take_bool,
+    take_string, take_u32,
+};
+
+/// Metadata key holding how many bytes `optimize` took off an output; the
+/// executor adds it up in the run metrics.
+pub const OPTIMIZE_SAVED_KEY: &str = "optimize.bytes_saved";
+
+const DEFAULT_LEVEL: u32 = 2;
+const XMP_SIGNATURE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+/// PNG chunks `strip: safe` keeps: everything that changes how the image
+/// looks, EXIF (orientation) included.
+const PNG_DISPLAY_CHUNKS: [[u8; 4]; 10] = [
+    *b"cICP", *b"iCCP", *b"sRGB", *b"gAMA", *b"cHRM", *b"pHYs", *b"eXIf", *b"acTL", *b"fcTL",
+    *b"fdAT",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Strip {
+    None,
+    /// Drops what does not affect display, keeping XMP the encode stage was
+    /// asked to preserve.
+    Safe,
+    All,
+}
+
+/// Losslessly shrinks JPEG and PNG files: the output an earlier `encode`
+/// wrote, or the input itself when the pipeline has no encode.
+pub struct OptimizeStage {
+    level: u8,
+    zopfli: bool,
+    huffman: bool,
+    strip: Strip,
+}
+
+impl OptimizeStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let level = take_u32(&mut params, "level").unwrap_or(DEFAULT_LEVEL);
+        if level > 6 {
+            bail!("optimize level must be between 0 and 6, got {level}");
+        }
+        let zopfli = take_bool(&mut params, "zopfli")?.unwrap_or(false);
+        let huffman = take_bool(&mut params, "huffman")?.unwrap_or(true);
+        let strip = match take_string(&mut params, "strip").as_deref() {
+            None | Some("safe") => Strip::Safe,
+            Some("none") => Strip::None,
+            Some("all") => Strip::All,
+            Some(other) => {
+                bail!("Unknown optimize strip mode '{other}' (expected none, safe or all)")
+            }
+        };
+        Ok(Self {
+            level: level as u8,
+            zopfli,
+            huffman,
+            strip,
+        })
+    }
+
+    fn optimize(&self, data: &[u8], format: ImageFormat, keep_xmp: bool) -> Result<Vec<u8>> {
+        match format {
+            ImageFormat::Png => oxipng::optimize_from_memory(data, &self.png_options(keep_xmp))
+                .map_err(|err| anyhow!("Failed to optimize PNG: {err}")),
+            _ => jpeg_optimize::optimize(
+                data,
+                |marker, body| self.keep_jpeg_segment(marker, body, keep_xmp),
+                self.huffman,
+            )
+            .context("Failed to optimize JPEG"),
+        }
+    }
+
+    fn png_options(&self, keep_xmp: bool) -> oxipng::Options {
+        let mut options = oxipng::Options::from_preset(self.level);
+        options.strip = match self.strip {
+            Strip::None => StripChunks::None,
+            Strip::All => StripChunks::All,
+            Strip::Safe => {
+                let mut keep: IndexSet<[u8; 4]> = PNG_DISPLAY_CHUNKS.into_iter().collect();
+                if keep_xmp {
+                    keep.insert(*b"iTXt");
+                }
+                StripChunks::Keep(keep)
+            }
+        };
+        if self.zopfli {
+            options.deflater = Deflater::Zopfli(ZopfliOptions::default());
+        }
+        options
+    }
+
+    /// Whether to keep an `APPn` or `COM` segment. JFIF and Adobe segments
+    /// describe how the pixels are coded, so they always stay.
+    fn keep_jpeg_segment(&self, marker: u8, body: &[u8], keep_xmp: bool) -> bool {
+        let named = |signature: &[u8]| body.starts_with(signature);
+        let structural =
+            (marker == 0xE0 && named(b"JFIF\0")) || (marker == 0xEE && named(b"Adobe"));
+        match self.strip {
+            Strip::None => true,
+            Strip::All => structural,
+            Strip::Safe => {
+                structural
+                    || (marker == 0xE1 && named(b"Exif\0\0"))
+                    || (marker == 0xE1 && keep_xmp && named(XMP_SIGNATURE))
+                    || (marker == 0xE2 && named(b"ICC_PROFILE\0"))
+            }
+        }
+    }
+
+    /// The output path for an input optimized without an encode stage,
+    /// claimed like encode claims its own.
+    fn input_output_path(
+        &self,
+        artifact: &mut Artifact,
+        ctx: &PipelineContext,
+        format: ImageFormat,
+    ) -> Result<PathBuf> {
+        let extension = artifact
+            .input_path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_string())
+            .unwrap_or_else(|| format_extension(format).to_string());
+        let label = format_label(format);
+        artifact.set_format(label);
+        artifact.metadata.insert(
+            "output.format".to_string(),
+            Value::String(label.to_string()),
+        );
+        artifact.metadata.insert(
+            "output.extension".to_string(),
+            Value::String(extension.clone()),
+        );
+        ctx.outputs.claim(
+            resolve_output_path(&ctx.output, artifact, &extension),
+            &artifact.input_path,
+            &mut artifact.metadata,
+        )
+    }
+}
+
+impl Stage for OptimizeStage {
+    fn name(&self) -> &'static str {
+        "optimize"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        if artifact.metadata.contains_key(overwrite::SKIPPED_KEY) {
+            return Ok(());
+        }
+        let encoded = artifact
+            .metadata
+            .get("output_path")
+            .and_then(Value::as_str)
+            .map(PathBuf::from);
+        let source = match &encoded {
+            // Streamed outputs only exist on disk.
+            Some(path) if artifact.data.is_empty() => fs::read(path)
+                .with_context(|| format!("Failed to read back output file: {}", path.display()))?,
+            _ => artifact.data.to_vec(),
+        };
+        let Some(format) = detect(&source) else {
+            if encoded.is_some() {
+                debug!("Output is neither JPEG nor PNG; leaving it as is");
+                return Ok(());
+            }
+            bail!(
+                "optimize stage needs a JPEG or PNG input or a preceding encode; {} is neither",
+                artifact.input_path.display()
+            );
+        };
+        let (path, must_write) = match encoded {
+            Some(path) => (path, false),
+            None => {
+                let path = self.input_output_path(artifact, ctx, format)?;
+                if keep_existing_output(artifact, ctx, &path) {
+                    return Ok(());
+                }
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent).with_context(|| {
+                        format!("Failed to create output directory: {}", parent.display())
+                    })?;
+                }
+                (path, true)
+            }
+        };
+
+        let keep_xmp = artifact
+            .metadata
+            .get("output.embedded")
+            .and_then(Value::as_array)
+            .is_some_and(|kinds| kinds.iter().any(|kind| kind == "xmp"));
+        let optimized = self.optimize(&source, format, keep_xmp)?;
+        let before = source.len();
+        let output = if optimized.len() < before {
+            optimized
+        } else {
+            source
+        };
+        let saved = before - output.len();
+        if must_write || saved > 0 {
+            let mut sink = FileSink::create(&path)?;
+            sink.write_all(&output)
+                .with_context(|| format!("Failed to write output file: {}", path.display()))?;
+            let summary = sink.finish()?;
+            artifact.metadata.insert(
+                "output_path".to_string(),
+                Value::String(path.to_string_lossy().to_string()),
+            );
+            artifact
+                .metadata
+                .insert("output.size_bytes".to_string(), json!(summary.size_bytes));
+            artifact
+                .metadata
+                .insert("output.sha256".to_string(), Value::String(summary.sha256));
+        }
+        artifact
+            .metadata
+            .insert("optimize.bytes_before".to_string(), json!(before));
+        artifact
+            .metadata
+            .insert("optimize.bytes_after".to_string(), json!(output.len()));
+        artifact
+            .metadata
+            .insert(OPTIMIZE_SAVED_KEY.to_string(), json!(saved));
+        artifact.replace_data(output);
+        Ok(())
+    }
+
+    fn plan(&self, artifact: &mut Artifact, ctx: &PipelineContext) -> Result<()> {
+        if artifact.metadata.contains_key("output_path") {
+            return Ok(());
+        }
+        let Some(format) = detect(&artifact.data) else {
+            bail!(
+                "optimize stage needs a JPEG or PNG input or a preceding encode; {} is neither",
+                artifact.input_path.display()
+            );
+        };
+        let path = self.input_output_path(artifact, ctx, format)?;
+        if let Some(reason) = ctx.overwrite.keep_reason(&artifact.input_path, &path) {
+            artifact.metadata.insert(
+                overwrite::SKIPPED_KEY.to_string(),
+                Value::String(reason.to_string()),
+            );
+        }
+        artifact.metadata.insert(
+            "output_path".to_string(),
+            Value::String(path.to_string_lossy().to_string()),
+        );
+        Ok(())
+    }
+}
+
+fn detect(data: &[u8]) -> Option<ImageFormat> {
+    match image::guess_format(data).ok()? {
+        format @ (ImageFormat::Jpeg | ImageFormat::Png) => Some(format),
+        _ => None,
+    }
+}
+
+fn format_label(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::Png => "png",
+        _ => "jpeg",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_strip_keeps_what_changes_the_picture() {
+        let stage = OptimizeStage::from_params(StageParameters::default()).unwrap();
+        assert!(stage.keep_jpeg_segment(0xE0, b"JFIF\0\x01\x02", false));
+        assert!(stage.keep_jpeg_segment(0xE1, b"Exif\0\0II*\0", false));
+        assert!(stage.keep_jpeg_segment(0xE2, b"ICC_PROFILE\0\x01\x01", false));
+        assert!(!stage.keep_jpeg_segment(0xE1, XMP_SIGNATURE, false));
+        assert!(stage.keep_jpeg_segment(0xE1, XMP_SIGNATURE, true));
+        assert!(!stage.keep_jpeg_segment(0xED, b"Photoshop 3.0\0", false));
+        assert!(!stage.keep_jpeg_segment(0xFE, b"made with love", false));
+
+        let mut params = StageParameters::default();
+        params.insert("strip".to_string(), json!("all"));
+        let all = OptimizeStage::from_params(params).unwrap();
+        assert!(all.keep_jpeg_segment(0xEE, b"Adobe\0", false));
+        assert!(!all.keep_jpeg_segment(0xE2, b"ICC_PROFILE\0\x01\x01", false));
+    }
+}
@@ -0,0 +1,237 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result, anyhow, bail};
+use image::{DynamicImage, Rgba, RgbaImage, imageops};
+use serde_json::{Value, json};
+
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+
+use super::{parse_color, record_dimensions, take_string, take_u32, value_as_f64};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PadTarget {
+    /// Exact canvas dimensions.
+    Size(u32, u32),
+    /// Width divided by height; the canvas only grows along one axis.
+    Aspect(f64),
+}
+
+/// Extends the canvas around the image, which is placed by `gravity`, so
+/// thumbnails resized with `fit: inside` line up in a grid.
+pub struct PadStage {
+    target: PadTarget,
+    background: Rgba<u8>,
+    /// Where the image sits along each axis: 0 at the start, 1 at the end.
+    gravity: (f64, f64),
+}
+
+impl PadStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let size = (
+            take_u32(&mut params, "width"),
+            take_u32(&mut params, "height"),
+        );
+        let aspect = params
+            .remove("aspect")
+            .map(|value| parse_aspect(&value))
+            .transpose()?;
+        let target = match (size, aspect) {
+            ((Some(width), Some(height)), None) if width > 0 && height > 0 => {
+                PadTarget::Size(width, height)
+            }
+            ((None, None), Some(aspect)) => PadTarget::Aspect(aspect),
+            ((None, None), None) => {
+                bail!("pad stage requires 'width' and 'height' or an 'aspect' ratio")
+            }
+            (_, None) => bail!("pad width and height must be set together and be positive"),
+            (_, Some(_)) => bail!("pad takes either 'width' and 'height' or 'aspect', not both"),
+        };
+        let background = match take_string(&mut params, "background") {
+            Some(color) => parse_color(&color).context("Invalid pad background")?,
+            None => Rgba([0, 0, 0, 0]),
+        };
+        let gravity = match take_string(&mut params, "gravity") {
+            Some(value) => parse_gravity(&value).ok_or_else(|| {
+                anyhow!("Unknown pad gravity '{value}' (expected center, top, bottom_left, ...)")
+            })?,
+            None => (0.5, 0.5),
+        };
+        Ok(Self {
+            target,
+            background,
+            gravity,
+        })
+    }
+
+    /// Where a `width` x `height` image goes: canvas size and the image's
+    /// top-left corner on it.
+    fn layout(&self, width: u32, height: u32) -> Result<((u32, u32), (u32, u32))> {
+        let (canvas_width, canvas_height) = match self.target {
+            PadTarget::Size(target_width, target_height) => {
+                if width > target_width || height > target_height {
+                    bail!(
+                        "pad target {target_width}x{target_height} is smaller than the \
+                         {width}x{height} image; resize with fit: inside first"
+                    );
+                }
+                (target_width, target_height)
+            }
+            PadTarget::Aspect(aspect) => aspect_canvas(width, height, aspect),
+        };
+        let offset = |free: u32, gravity: f64| (f64::from(free) * gravity).round() as u32;
+        Ok((
+            (canvas_width, canvas_height),
+            (
+                offset(canvas_width - width, self.gravity.0),
+                offset(canvas_height - height, self.gravity.1),
+            ),
+        ))
+    }
+
+    fn pad(&self, image: &RgbaImage, canvas: (u32, u32), offset: (u32, u32)) -> RgbaImage {
+        let mut padded = RgbaImage::from_pixel(canvas.0, canvas.1, self.background);
+        imageops::replace(&mut padded, image, i64::from(offset.0), i64::from(offset.1));
+        padded
+    }
+}
+
+impl Stage for PadStage {
+    fn name(&self) -> &'static str {
+        "pad"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn output_dimensions(&self, width: u32, height: u32) -> (u32, u32) {
+        match self.target {
+            PadTarget::Size(target_width, target_height) => {
+                (target_width.max(width), target_height.max(height))
+            }
+            PadTarget::Aspect(aspect) => aspect_canvas(width, height, aspect),
+        }
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        _ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let image = artifact
+            .image
+            .as_ref()
+            .map(Arc::clone)
+            .ok_or_else(|| anyhow!("pad stage requires a decoded image"))?;
+        let (canvas, offset) = self.layout(image.width(), image.height())?;
+        if artifact.is_animated() {
+            for frame in artifact.frames_mut() {
+                frame.image = self.pad(&frame.image, canvas, offset);
+            }
+        }
+        let padded = DynamicImage::ImageRgba8(self.pad(&image.to_rgba8(), canvas, offset));
+        record_dimensions(artifact, "image", &padded);
+        artifact.set_image(padded);
+        artifact
+            .metadata
+            .insert("pad.left".to_string(), json!(offset.0));
+        artifact
+            .metadata
+            .insert("pad.top".to_string(), json!(offset.1));
+        Ok(())
+    }
+}
+
+/// Accepts `16:9`, `16/9` or a plain ratio such as `1.5`.
+fn parse_aspect(value: &Value) -> Result<f64> {
+    let ratio = match value {
+        Value::String(text) => match text.split_once([':', '/']) {
+            Some((width, height)) => {
+                let parse = |part: &str| part.trim().parse::<f64>().ok();
+                parse(width).zip(parse(height)).map(|(w, h)| w / h)
+            }
+            None => value_as_f64(value),
+        },
+        _ => value_as_f64(value),
+    };
+    ratio
+        .filter(|ratio| ratio.is_finite() && *ratio > 0.0)
+        .ok_or_else(|| anyhow!("pad aspect must be a positive ratio such as 16:9, got {value}"))
+}
+
+fn parse_gravity(value: &str) -> Option<(f64, f64)> {
+    let gravity = match value.trim().to_lowercase().replace('-', "_").as_str() {
+        "center" | "centre" => (0.5, 0.5),
+        "top" => (0.5, 0.0),
+        "bottom" => (0.5, 1.0),
+        "left" => (0.0, 0.5),
+        "right" => (1.0, 0.5),
+        "top_left" => (0.0, 0.0),
+        "top_right" => (1.0, 0.0),
+        "bottom_left" => (0.0, 1.0),
+        "bottom_right" => (1.0, 1.0),
+        _ => return None,
+    };
+    Some(gravity)
+}
+
+/// The smallest canvas with ratio `aspect` that holds a `width` x `height`
+/// image.
+fn aspect_canvas(width: u32, height: u32, aspect: f64) -> (u32, u32) {
+    let (w, h) = (f64::from(width), f64::from(height));
+    if w / h < aspect {
+        (((h * aspect).round() as u32).max(width), height)
+    } else {
+        (width, ((w / aspect).round() as u32).max(height))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stages::from_json;
+
+    #[test]
+    fn pads_to_aspect_or_size_around_the_image() {
+        let letterbox = from_json(
+            PadStage::from_params,
+            json!({ "aspect": "16:9", "background": "#000" }),
+        )
+        .unwrap();
+        assert_eq!(letterbox.output_dimensions(100, 100), (178, 100));
+        assert_eq!(letterbox.output_dimensions(320, 90), (320, 180));
+        assert_eq!(letterbox.layout(100, 100).unwrap(), ((178, 100), (39, 0)));
+
+        let square = from_json(
+            PadStage::from_params,
+            json!({ "width": 4, "height": 4, "gravity": "bottom-right" }),
+        )
+        .unwrap();
+        let image = RgbaImage::from_pixel(2, 1, Rgba([255, 0, 0, 255]));
+        let (canvas, offset) = square.layout(2, 1).unwrap();
+        assert_eq!((canvas, offset), ((4, 4), (2, 3)));
+        let padded = square.pad(&image, canvas, offset);
+        assert_eq!(padded.get_pixel(3, 3).0, [255, 0, 0, 255]);
+        assert_eq!(padded.get_pixel(0, 0).0, [0, 0, 0, 0]);
+        assert!(square.layout(5, 1).is_err());
+
+        assert!(from_json(PadStage::from_params, json!({ "width": 4 })).is_err());
+        assert!(from_json(PadStage::from_params, json!({ "aspect": "wide" })).is_err());
+        assert!(
+            from_json(
+                PadStage::from_params,
+                json!({ "width": 4, "height": 4, "aspect": 1 })
+            )
+            .is_err()
+        );
+        assert!(
+            from_json(
+                PadStage::from_params,
+                json!({ "aspect": 1, "background": "#12" })
+            )
+            .is_err()
+        );
+    }
+}
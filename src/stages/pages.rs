@@ -0,0 +1,165 @@
+//! Turning one document artifact into one artifact per page, for stages that
+//! split PDFs and multi-page TIFFs.
+
+use std::ops::RangeInclusive;
+
+use anyhow::{Result, bail};
+use serde_json::{Value, json};
+
+use crate::pipeline::{Artifact, PipelineContext};
+
+/// Metadata key holding the one-based page number, for `{page}`.
+pub(super) const PAGE_KEY: &str = "page";
+
+/// Which pages of a document to use: `all`, or one-based pages and ranges such as
+/// `1-3,5` (`7-` runs to the last page).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(super) enum PageSelection {
+    All,
+    Ranges(Vec<RangeInclusive<usize>>),
+}
+
+impl PageSelection {
+    pub(super) fn parse(value: &str) -> Result<Self> {
+        let value = value.trim();
+        if value.is_empty() || value.eq_ignore_ascii_case("all") {
+            return Ok(Self::All);
+        }
+        let page = |part: &str| -> Result<usize> {
+            match part.trim().parse::<usize>() {
+                Ok(page) if page > 0 => Ok(page),
+                _ => bail!("Invalid page '{}' in pages '{value}'", part.trim()),
+            }
+        };
+        let mut ranges = Vec::new();
+        for part in value.split(',') {
+            let range = match part.split_once('-') {
+                Some((start, "")) => page(start)?..=usize::MAX,
+                Some((start, end)) => page(start)?..=page(end)?,
+                None => {
+                    let page = page(part)?;
+                    page..=page
+                }
+            };
+            if range.start() > range.end() {
+                bail!("Page range '{}' runs backwards", part.trim());
+            }
+            ranges.push(range);
+        }
+        Ok(Self::Ranges(ranges))
+    }
+
+    /// The selected pages of a `count`-page document, in order and without
+    /// repeats.
+    pub(super) fn pages(&self, count: usize) -> Result<Vec<usize>> {
+        let ranges = match self {
+            Self::All => return Ok((1..=count).collect()),
+            Self::Ranges(ranges) => ranges,
+        };
+        let mut pages = Vec::new();
+        for range in ranges {
+            if *range.start() > count {
+                bail!(
+                    "pages selects page {} but the document has {count}",
+                    range.start()
+                );
+            }
+            if *range.end() != usize::MAX && *range.end() > count {
+                bail!(
+                    "pages selects page {} but the document has {count}",
+                    range.end()
+                );
+            }
+            pages.extend(*range.start()..=(*range.end()).min(count));
+        }
+        let mut seen = vec![false; count + 1];
+        pages.retain(|&page| !std::mem::replace(&mut seen[page], true));
+        Ok(pages)
+    }
+}
+
+impl PageSelection {
+    /// Reads a `pages` parameter: a selection string, a single page number,
+    /// or a list of either.
+    pub(super) fn from_value(value: &Value) -> Result<Self> {
+        match value {
+            Value::Array(items) => {
+                let items: Vec<String> = items.iter().map(value_text).collect();
+                Self::parse(&items.join(","))
+            }
+            other => Self::parse(&value_text(other)),
+        }
+    }
+}
+
+fn value_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Turns `artifact` into one artifact per entry of `pages` of a
+/// `page_count`-page document, calling `fill` on each; the first stays in
+/// `artifact`, the rest go to [`Artifact::split`]. Every page records its
+/// number as `page` for `{page}` in output structures, plus
+/// `{prefix}.page`/`{prefix}.page_count`, and lands at `{stem}-{page}`
+/// unless the output structure places `{page}` itself.
+pub(super) fn split(
+    artifact: &mut Artifact,
+    ctx: &PipelineContext,
+    prefix: &str,
+    page_count: usize,
+    pages: &[usize],
+    mut fill: impl FnMut(&mut Artifact, usize) -> Result<()>,
+) -> Result<()> {
+    let template = artifact.clone();
+    let suffix_stem = !ctx.output.structure.contains("{page");
+    let label = |target: &mut Artifact, page: usize| {
+        if suffix_stem {
+            target.stem = format!("{}-{page}", template.stem);
+        }
+        let metadata = &mut target.metadata;
+        metadata.insert(PAGE_KEY.to_string(), Value::String(page.to_string()));
+        metadata.insert(format!("{prefix}.page"), json!(page));
+        metadata.insert(format!("{prefix}.page_count"), json!(page_count));
+    };
+    let mut split = Vec::with_capacity(pages.len().saturating_sub(1));
+    for (index, &page) in pages.iter().enumerate() {
+        if index == 0 {
+            label(artifact, page);
+            fill(artifact, page)?;
+        } else {
+            let mut target = template.clone();
+            label(&mut target, page);
+            fill(&mut target, page)?;
+            split.push(target);
+        }
+    }
+    artifact.split = split;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_selection_parses_pages_and_ranges() {
+        let selection = PageSelection::parse("1-3, 5,2").unwrap();
+        assert_eq!(selection.pages(6).unwrap(), vec![1, 2, 3, 5]);
+        assert_eq!(
+            PageSelection::parse("4-").unwrap().pages(6).unwrap(),
+            vec![4, 5, 6]
+        );
+        assert_eq!(
+            PageSelection::parse("all").unwrap().pages(2).unwrap(),
+            vec![1, 2]
+        );
+        assert!(PageSelection::parse("0").is_err());
+        assert!(PageSelection::parse("3-1").is_err());
+        assert!(PageSelection::parse("x").is_err());
+        assert!(PageSelection::parse("2-9").unwrap().pages(3).is_err());
+        assert!(PageSelection::parse("5-").unwrap().pages(3).is_err());
+    }
+}
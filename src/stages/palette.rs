@@ -0,0 +1,289 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result, anyhow, bail};
+use image::imageops::FilterType;
+use serde_json::{Value, json};
+use tracing::info;
+
+use crate::pipeline::{Artifact, OutputSpec, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+use crate::structure;
+
+use super::take_u32;
+
+const DEFAULT_COLORS: u32 = 5;
+const DEFAULT_SAMPLE_SIZE: u32 = 64;
+/// Extension of the default sidecar, named by the run's output structure.
+const SIDECAR_EXTENSION: &str = "palette.json";
+
+/// Metadata key holding the path of the palette sidecar.
+pub const PALETTE_SIDECAR_KEY: &str = "palette.sidecar";
+
+/// Records the dominant colors (median cut) and average luminance of the
+/// image as metadata without touching its pixels, and optionally as a JSON
+/// sidecar.
+pub struct PaletteStage {
+    colors: usize,
+    sample_size: u32,
+    /// Structure of the sidecar path; `None` renders the run's output
+    /// structure with a `palette.json` extension, next to the output.
+    sidecar: Option<Option<String>>,
+}
+
+impl PaletteStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let colors = take_u32(&mut params, "colors").unwrap_or(DEFAULT_COLORS);
+        if !(1..=32).contains(&colors) {
+            bail!("palette colors must be between 1 and 32, got {colors}");
+        }
+        let sample_size = take_u32(&mut params, "sample_size").unwrap_or(DEFAULT_SAMPLE_SIZE);
+        if sample_size == 0 {
+            bail!("palette sample_size must be positive");
+        }
+        let sidecar = match params.remove("sidecar") {
+            None | Some(Value::Bool(false)) => None,
+            Some(Value::Bool(true)) => Some(None),
+            Some(Value::String(template)) => {
+                structure::validate(&template)
+                    .with_context(|| format!("Invalid palette sidecar structure '{template}'"))?;
+                Some(Some(template))
+            }
+            Some(other) => {
+                bail!("palette sidecar must be true or a path structure, got {other}")
+            }
+        };
+        Ok(Self {
+            colors: colors as usize,
+            sample_size,
+            sidecar,
+        })
+    }
+
+    fn sidecar_path(&self, artifact: &Artifact, ctx: &PipelineContext) -> Result<Option<PathBuf>> {
+        let Some(template) = &self.sidecar else {
+            return Ok(None);
+        };
+        let path = match template {
+            None => ctx
+                .output
+                .resolve(&artifact.stem, SIDECAR_EXTENSION, &artifact.metadata),
+            Some(template) => OutputSpec {
+                directory: ctx.output.directory.clone(),
+                structure: template.clone(),
+            }
+            .resolve(&artifact.stem, "json", &artifact.metadata),
+        };
+        ctx.outputs
+            .claim_side_output(path, &artifact.input_path)
+            .map(Some)
+    }
+
+    /// Writes the `palette.*` metadata to the sidecar, unless the overwrite
+    /// policy keeps an existing one.
+    fn write_sidecar(&self, artifact: &mut Artifact, ctx: &PipelineContext) -> Result<()> {
+        let Some(path) = self.sidecar_path(artifact, ctx)? else {
+            return Ok(());
+        };
+        artifact.metadata.insert(
+            PALETTE_SIDECAR_KEY.to_string(),
+            Value::String(path.to_string_lossy().to_string()),
+        );
+        if let Some(reason) = ctx.overwrite.keep_reason(&artifact.input_path, &path) {
+            info!(output = %path.display(), reason, "Keeping existing palette sidecar");
+            return Ok(());
+        }
+        let field = |key: &str| artifact.metadata.get(key).cloned().unwrap_or(Value::Null);
+        let document = json!({
+            "input": artifact.input_path.to_string_lossy(),
+            "colors": field("palette.colors"),
+            "dominant": field("palette.dominant"),
+            "average_luminance": field("palette.average_luminance"),
+        });
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).with_context(|| {
+                format!("Failed to create output directory: {}", parent.display())
+            })?;
+        }
+        let mut bytes = serde_json::to_vec_pretty(&document)?;
+        bytes.push(b'\n');
+        fs::write(&path, bytes)
+            .with_context(|| format!("Failed to write palette sidecar: {}", path.display()))
+    }
+}
+
+impl Stage for PaletteStage {
+    fn name(&self) -> &'static str {
+        "palette"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let image = artifact
+            .image
+            .as_ref()
+            .ok_or_else(|| anyhow!("palette stage requires a decoded image"))?;
+        let sample = if image.width() > self.sample_size || image.height() > self.sample_size {
+            image
+                .resize(self.sample_size, self.sample_size, FilterType::Triangle)
+                .to_rgba8()
+        } else {
+            image.to_rgba8()
+        };
+        let pixels: Vec<[u8; 3]> = sample
+            .pixels()
+            .filter(|pixel| pixel[3] > 0)
+            .map(|pixel| [pixel[0], pixel[1], pixel[2]])
+            .collect();
+
+        let swatches = median_cut(&pixels, self.colors);
+        let colors: Vec<Value> = swatches
+            .iter()
+            .map(|swatch| {
+                json!({
+                    "hex": hex(swatch.color),
+                    "proportion": swatch.proportion,
+                })
+            })
+            .collect();
+        artifact
+            .metadata
+            .insert("palette.colors".to_string(), Value::Array(colors));
+        if let Some(dominant) = swatches.first() {
+            artifact.metadata.insert(
+                "palette.dominant".to_string(),
+                Value::String(hex(dominant.color)),
+            );
+        }
+        artifact.metadata.insert(
+            "palette.average_luminance".to_string(),
+            json!(average_luminance(&pixels)),
+        );
+        self.write_sidecar(artifact, ctx)
+    }
+
+    fn plan(&self, artifact: &mut Artifact, ctx: &PipelineContext) -> Result<()> {
+        if let Some(path) = self.sidecar_path(artifact, ctx)? {
+            artifact.metadata.insert(
+                PALETTE_SIDECAR_KEY.to_string(),
+                Value::String(path.to_string_lossy().to_string()),
+            );
+        }
+        Ok(())
+    }
+
+    /// A cache replay restores the main output only, not the sidecar.
+    fn cacheable(&self) -> bool {
+        self.sidecar.is_none()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Swatch {
+    color: [u8; 3],
+    proportion: f64,
+}
+
+/// Splits the pixel set along its widest channel until `count` boxes exist,
+/// then reports each box's mean color, most common first.
+fn median_cut(pixels: &[[u8; 3]], count: usize) -> Vec<Swatch> {
+    if pixels.is_empty() {
+        return Vec::new();
+    }
+    let mut boxes: Vec<Vec<[u8; 3]>> = vec![pixels.to_vec()];
+    while boxes.len() < count {
+        let Some((index, channel)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, pixels)| pixels.len() > 1)
+            .map(|(index, pixels)| {
+                let (channel, range) = widest_channel(pixels);
+                (index, channel, range)
+            })
+            .filter(|&(_, _, range)| range > 0)
+            .max_by_key(|&(_, _, range)| range)
+            .map(|(index, channel, _)| (index, channel))
+        else {
+            break;
+        };
+        let mut pixels = boxes.swap_remove(index);
+        pixels.sort_unstable_by_key(|pixel| pixel[channel]);
+        let upper = pixels.split_off(pixels.len() / 2);
+        boxes.push(pixels);
+        boxes.push(upper);
+    }
+
+    let total = pixels.len() as f64;
+    let mut swatches: Vec<Swatch> = Vec::with_capacity(boxes.len());
+    for pixels in &boxes {
+        let mut sums = [0u64; 3];
+        for pixel in pixels {
+            for channel in 0..3 {
+                sums[channel] += pixel[channel] as u64;
+            }
+        }
+        let len = pixels.len() as u64;
+        let color = sums.map(|sum| ((sum + len / 2) / len) as u8);
+        let proportion = pixels.len() as f64 / total;
+        // Median splits can cut a flat region in two; report it once.
+        match swatches.iter_mut().find(|swatch| swatch.color == color) {
+            Some(swatch) => swatch.proportion += proportion,
+            None => swatches.push(Swatch { color, proportion }),
+        }
+    }
+    swatches.sort_by(|a, b| b.proportion.total_cmp(&a.proportion));
+    swatches
+}
+
+fn widest_channel(pixels: &[[u8; 3]]) -> (usize, u8) {
+    (0..3)
+        .map(|channel| {
+            let (min, max) = pixels.iter().fold((u8::MAX, u8::MIN), |(min, max), pixel| {
+                (min.min(pixel[channel]), max.max(pixel[channel]))
+            });
+            (channel, max - min)
+        })
+        .max_by_key(|&(_, range)| range)
+        .unwrap_or((0, 0))
+}
+
+/// Mean Rec. 709 relative luminance in `0..=1`.
+fn average_luminance(pixels: &[[u8; 3]]) -> f64 {
+    if pixels.is_empty() {
+        return 0.0;
+    }
+    let sum: f64 = pixels
+        .iter()
+        .map(|[r, g, b]| 0.2126 * *r as f64 + 0.7152 * *g as f64 + 0.0722 * *b as f64)
+        .sum();
+    sum / (pixels.len() as f64 * 255.0)
+}
+
+fn hex([r, g, b]: [u8; 3]) -> String {
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn median_cut_orders_colors_by_share() {
+        let mut pixels = vec![[255, 0, 0]; 6];
+        pixels.extend([[0, 0, 255]; 2]);
+        let swatches = median_cut(&pixels, 4);
+        assert_eq!(swatches.len(), 2);
+        assert_eq!(hex(swatches[0].color), "#ff0000");
+        assert_eq!(swatches[0].proportion, 0.75);
+        assert_eq!(hex(swatches[1].color), "#0000ff");
+        assert!((average_luminance(&[[255, 255, 255]]) - 1.0).abs() < 1e-9);
+    }
+}
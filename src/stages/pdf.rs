@@ -0,0 +1,487 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result, anyhow, bail};
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ExtendedColorType, ImageEncoder, Rgba, RgbaImage};
+use serde_json::{Map, Value, json};
+use tracing::info;
+
+use crate::pipeline::{Artifact, PipelineContext, StageParameters};
+use crate::sink::{FileSink, OutputSink};
+
+use super::{keep_existing_output, take_bool, take_f64, take_string};
+
+const DEFAULT_DPI: f64 = 300.0;
+const DEFAULT_QUALITY: u8 = 90;
+const DEFAULT_DOCUMENT: &str = "document";
+const POINTS_PER_INCH: f64 = 72.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PageSize {
+    /// Each page is exactly as large as its image at the configured DPI.
+    Fit,
+    /// Portrait width and height in points; pages turn landscape for
+    /// landscape images.
+    Fixed(f64, f64),
+}
+
+impl PageSize {
+    fn from_str(value: &str) -> Option<Self> {
+        let size = match value.trim().to_lowercase().as_str() {
+            "fit" | "auto" => return Some(Self::Fit),
+            "a3" => (841.89, 1190.55),
+            "a4" => (595.28, 841.89),
+            "a5" => (419.53, 595.28),
+            "letter" => (612.0, 792.0),
+            "legal" => (612.0, 1008.0),
+            _ => return None,
+        };
+        Some(Self::Fixed(size.0, size.1))
+    }
+}
+
+/// One JPEG-compressed page image.
+struct PdfPage {
+    jpeg: Vec<u8>,
+    width: u32,
+    height: u32,
+    gray: bool,
+}
+
+/// Writes `encode` stages with `format: pdf`: each artifact becomes its own
+/// PDF (animation frames as pages), or with `combine` every artifact of the
+/// run is appended to one document written once all inputs are done.
+pub(super) struct PdfEncoder {
+    page_size: PageSize,
+    dpi: f64,
+    margin: f64,
+    quality: u8,
+    document: Option<String>,
+    pending: Mutex<Vec<(PathBuf, Vec<PdfPage>)>>,
+}
+
+impl PdfEncoder {
+    pub(super) fn from_params(params: &mut StageParameters) -> Result<Self> {
+        let page_size = match take_string(params, "page_size") {
+            Some(value) => PageSize::from_str(&value)
+                .ok_or_else(|| anyhow!("Unknown pdf page_size '{value}'"))?,
+            None => PageSize::Fit,
+        };
+        let dpi = take_f64(params, "dpi")?.unwrap_or(DEFAULT_DPI);
+        if dpi <= 0.0 {
+            bail!("pdf dpi must be positive, got {dpi}");
+        }
+        let margin = take_f64(params, "margin")?.unwrap_or(0.0);
+        if margin < 0.0 {
+            bail!("pdf margin cannot be negative, got {margin}");
+        }
+        let quality = match take_f64(params, "quality")? {
+            Some(quality) if (1.0..=100.0).contains(&quality) => quality.round() as u8,
+            Some(quality) => bail!("pdf quality must be between 1 and 100, got {quality}"),
+            None => DEFAULT_QUALITY,
+        };
+        let combine = take_bool(params, "combine")?.unwrap_or(false);
+        let document = take_string(params, "document");
+        if document.is_some() && !combine {
+            bail!("pdf document name requires combine: true");
+        }
+        let document = combine.then(|| document.unwrap_or_else(|| DEFAULT_DOCUMENT.to_string()));
+        Ok(Self {
+            page_size,
+            dpi,
+            margin,
+            quality,
+            document,
+            pending: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub(super) fn encode(
+        &self,
+        artifact: &mut Artifact,
+        ctx: &PipelineContext,
+        extension: &str,
+    ) -> Result<()> {
+        let pages = self.render_pages(artifact)?;
+        let page_count = pages.len();
+        artifact.set_format("pdf");
+        artifact
+            .metadata
+            .insert("output.verified".into(), Value::Bool(false));
+        artifact
+            .metadata
+            .insert("output.pages".into(), json!(page_count));
+
+        let resolved = self.output_path(artifact, ctx, extension)?;
+        match &self.document {
+            Some(document) => {
+                self.pending
+                    .lock()
+                    .map_err(|_| anyhow!("pdf page buffer poisoned"))?
+                    .push((artifact.input_path.clone(), pages));
+                artifact.metadata.insert(
+                    "output.document".into(),
+                    Value::String(document.to_string()),
+                );
+            }
+            None if keep_existing_output(artifact, ctx, &resolved) => {}
+            None => {
+                let summary = write_document(&resolved, &self.layout(&pages), &pages)?;
+                artifact
+                    .metadata
+                    .insert("output.size_bytes".into(), json!(summary.size_bytes));
+                artifact
+                    .metadata
+                    .insert("output.sha256".into(), Value::String(summary.sha256));
+            }
+        }
+
+        artifact.replace_data(Vec::new());
+        artifact.metadata.insert(
+            "output_path".to_string(),
+            Value::String(resolved.to_string_lossy().to_string()),
+        );
+        artifact.metadata.insert(
+            "output.extension".to_string(),
+            Value::String(extension.to_string()),
+        );
+        artifact
+            .metadata
+            .insert("output.format".to_string(), Value::String("pdf".into()));
+        artifact
+            .metadata
+            .insert("pdf.dpi".to_string(), json!(self.dpi));
+        Ok(())
+    }
+
+    /// Where `artifact`'s pages go: the combined document, or a PDF of its
+    /// own claimed for the input.
+    pub(super) fn output_path(
+        &self,
+        artifact: &mut Artifact,
+        ctx: &PipelineContext,
+        extension: &str,
+    ) -> Result<PathBuf> {
+        match &self.document {
+            Some(document) => Ok(ctx.output.resolve(document, extension, &Map::new())),
+            None => ctx.outputs.claim(
+                ctx.output
+                    .resolve(&artifact.stem, extension, &artifact.metadata),
+                &artifact.input_path,
+                &mut artifact.metadata,
+            ),
+        }
+    }
+
+    /// Writes the combined document, pages ordered by input path so the
+    /// result does not depend on which worker finished first.
+    /// Whether pages are collected into one document instead of a PDF per
+    /// input.
+    pub(super) fn combines(&self) -> bool {
+        self.document.is_some()
+    }
+
+    pub(super) fn finalize(&self, ctx: &PipelineContext, extension: &str) -> Result<()> {
+        let Some(document) = &self.document else {
+            return Ok(());
+        };
+        let mut pending = std::mem::take(
+            &mut *self
+                .pending
+                .lock()
+                .map_err(|_| anyhow!("pdf page buffer poisoned"))?,
+        );
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let resolved = ctx.output.resolve(document, extension, &Map::new());
+        // The document is kept only if the policy keeps it for every page.
+        let kept: Option<Vec<_>> = pending
+            .iter()
+            .map(|(input, _)| ctx.overwrite.keep_reason(input, &resolved))
+            .collect();
+        if let Some(reason) = kept.and_then(|reasons| reasons.first().copied()) {
+            info!(output = %resolved.display(), reason, "Keeping existing output");
+            return Ok(());
+        }
+        pending.sort_by(|a, b| a.0.cmp(&b.0));
+        let pages: Vec<PdfPage> = pending.into_iter().flat_map(|(_, pages)| pages).collect();
+        write_document(&resolved, &self.layout(&pages), &pages)?;
+        Ok(())
+    }
+
+    fn render_pages(&self, artifact: &Artifact) -> Result<Vec<PdfPage>> {
+        if artifact.is_animated() {
+            return artifact
+                .frames
+                .iter()
+                .map(|frame| self.render_page(&DynamicImage::ImageRgba8(frame.image.clone())))
+                .collect();
+        }
+        let image = artifact
+            .image
+            .as_ref()
+            .ok_or_else(|| anyhow!("encode stage requires a decoded image"))?;
+        Ok(vec![self.render_page(image)?])
+    }
+
+    fn render_page(&self, image: &DynamicImage) -> Result<PdfPage> {
+        let gray = !image.color().has_color();
+        let flattened;
+        let source = if image.color().has_alpha() {
+            flattened = DynamicImage::ImageRgba8(flatten_on_white(&image.to_rgba8()));
+            &flattened
+        } else {
+            image
+        };
+        let (pixels, color) = if gray {
+            (source.to_luma8().into_raw(), ExtendedColorType::L8)
+        } else {
+            (source.to_rgb8().into_raw(), ExtendedColorType::Rgb8)
+        };
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, self.quality)
+            .write_image(&pixels, image.width(), image.height(), color)
+            .context("Failed to encode pdf page")?;
+        Ok(PdfPage {
+            jpeg,
+            width: image.width(),
+            height: image.height(),
+            gray,
+        })
+    }
+
+    fn layout(&self, pages: &[PdfPage]) -> Vec<PageLayout> {
+        pages
+            .iter()
+            .map(|page| self.place(page.width, page.height))
+            .collect()
+    }
+
+    /// Sizes the page and centres the image on it, shrinking (never
+    /// enlarging) the image to fit inside the margins.
+    fn place(&self, width: u32, height: u32) -> PageLayout {
+        let natural_width = width as f64 * POINTS_PER_INCH / self.dpi;
+        let natural_height = height as f64 * POINTS_PER_INCH / self.dpi;
+        match self.page_size {
+            PageSize::Fit => PageLayout {
+                page: (
+                    natural_width + 2.0 * self.margin,
+                    natural_height + 2.0 * self.margin,
+                ),
+                origin: (self.margin, self.margin),
+                size: (natural_width, natural_height),
+            },
+            PageSize::Fixed(short, long) => {
+                let page = if width > height {
+                    (long, short)
+                } else {
+                    (short, long)
+                };
+                let available = (
+                    (page.0 - 2.0 * self.margin).max(1.0),
+                    (page.1 - 2.0 * self.margin).max(1.0),
+                );
+                let scale = (available.0 / natural_width)
+                    .min(available.1 / natural_height)
+                    .min(1.0);
+                let size = (natural_width * scale, natural_height * scale);
+                PageLayout {
+                    page,
+                    origin: ((page.0 - size.0) / 2.0, (page.1 - size.1) / 2.0),
+                    size,
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct PageLayout {
+    page: (f64, f64),
+    origin: (f64, f64),
+    size: (f64, f64),
+}
+
+fn flatten_on_white(image: &RgbaImage) -> RgbaImage {
+    let mut flattened = image.clone();
+    for pixel in flattened.pixels_mut() {
+        let alpha = pixel[3] as u32;
+        let blend =
+            |channel: u8| ((channel as u32 * alpha + 255 * (255 - alpha) + 127) / 255) as u8;
+        *pixel = Rgba([blend(pixel[0]), blend(pixel[1]), blend(pixel[2]), 255]);
+    }
+    flattened
+}
+
+fn write_document(
+    path: &Path,
+    layouts: &[PageLayout],
+    pages: &[PdfPage],
+) -> Result<crate::sink::SinkSummary> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create output directory: {}", parent.display()))?;
+    }
+    let mut sink = FileSink::create(path)?;
+    sink.write_all(&build_document(layouts, pages))
+        .with_context(|| format!("Failed to write output file: {}", path.display()))?;
+    sink.finish()
+}
+
+/// Serialises a minimal PDF 1.4 file: a catalog, a page tree and, per page,
+/// a page object, a DCT-encoded image XObject and a content stream drawing it.
+fn build_document(layouts: &[PageLayout], pages: &[PdfPage]) -> Vec<u8> {
+    let mut out = b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n".to_vec();
+    let object_count = 2 + 3 * pages.len();
+    let mut offsets = Vec::with_capacity(object_count);
+
+    offsets.push(out.len());
+    out.extend_from_slice(b"1 0 obj\n<< /Type /Catalog /Pages 2 0 R >>\nendobj\n");
+
+    let kids: Vec<String> = (0..pages.len())
+        .map(|index| format!("{} 0 R", 3 + 3 * index))
+        .collect();
+    offsets.push(out.len());
+    out.extend_from_slice(
+        format!(
+            "2 0 obj\n<< /Type /Pages /Kids [{}] /Count {} >>\nendobj\n",
+            kids.join(" "),
+            pages.len()
+        )
+        .as_bytes(),
+    );
+
+    for (index, (page, layout)) in pages.iter().zip(layouts).enumerate() {
+        let page_id = 3 + 3 * index;
+        let image_id = page_id + 1;
+        let content_id = page_id + 2;
+
+        offsets.push(out.len());
+        out.extend_from_slice(
+            format!(
+                "{page_id} 0 obj\n<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] \
+                 /Resources << /XObject << /Im0 {image_id} 0 R >> >> /Contents {content_id} 0 R >>\nendobj\n",
+                number(layout.page.0),
+                number(layout.page.1),
+            )
+            .as_bytes(),
+        );
+
+        offsets.push(out.len());
+        out.extend_from_slice(
+            format!(
+                "{image_id} 0 obj\n<< /Type /XObject /Subtype /Image /Width {} /Height {} \
+                 /ColorSpace /{} /BitsPerComponent 8 /Filter /DCTDecode /Length {} >>\nstream\n",
+                page.width,
+                page.height,
+                if page.gray { "DeviceGray" } else { "DeviceRGB" },
+                page.jpeg.len(),
+            )
+            .as_bytes(),
+        );
+        out.extend_from_slice(&page.jpeg);
+        out.extend_from_slice(b"\nendstream\nendobj\n");
+
+        let content = format!(
+            "q {} 0 0 {} {} {} cm /Im0 Do Q",
+            number(layout.size.0),
+            number(layout.size.1),
+            number(layout.origin.0),
+            number(layout.origin.1),
+        );
+        offsets.push(out.len());
+        out.extend_from_slice(
+            format!(
+                "{content_id} 0 obj\n<< /Length {} >>\nstream\n{content}\nendstream\nendobj\n",
+                content.len()
+            )
+            .as_bytes(),
+        );
+    }
+
+    let xref = out.len();
+    out.extend_from_slice(
+        format!("xref\n0 {}\n0000000000 65535 f \n", object_count + 1).as_bytes(),
+    );
+    for offset in offsets {
+        out.extend_from_slice(format!("{offset:010} 00000 n \n").as_bytes());
+    }
+    out.extend_from_slice(
+        format!(
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{xref}\n%%EOF\n",
+            object_count + 1
+        )
+        .as_bytes(),
+    );
+    out
+}
+
+/// Formats a coordinate with at most two decimals and no trailing zeros.
+fn number(value: f64) -> String {
+    let formatted = format!("{value:.2}");
+    formatted
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stages::json_params;
+
+    #[test]
+    fn fixed_pages_rotate_and_shrink_to_fit() {
+        let pdf = PdfEncoder::from_params(&mut json_params(
+            json!({ "page_size": "a4", "dpi": 72, "margin": 10 }),
+        ))
+        .unwrap();
+        let layout = pdf.place(1000, 500);
+        assert_eq!(layout.page, (841.89, 595.28));
+        assert!((layout.size.0 - 821.89).abs() < 1e-9);
+        assert!((layout.origin.0 - 10.0).abs() < 1e-9);
+
+        let fit = PdfEncoder::from_params(&mut json_params(json!({ "dpi": 144 }))).unwrap();
+        let layout = fit.place(288, 144);
+        assert_eq!(layout.page, (144.0, 72.0));
+        assert_eq!(layout.origin, (0.0, 0.0));
+    }
+
+    #[test]
+    fn document_xref_points_at_objects() {
+        let page = PdfPage {
+            jpeg: vec![0xff, 0xd8, 0xff, 0xd9],
+            width: 2,
+            height: 2,
+            gray: true,
+        };
+        let layout = PdfEncoder::from_params(&mut json_params(json!({})))
+            .unwrap()
+            .place(2, 2);
+        let document = build_document(&[layout], &[page]);
+        let find = |needle: &[u8]| {
+            document
+                .windows(needle.len())
+                .rposition(|window| window == needle)
+                .unwrap()
+        };
+        assert!(document.starts_with(b"%PDF-1.4"));
+        find(b"/Count 1");
+        find(b"/ColorSpace /DeviceGray");
+
+        let start = find(b"startxref\n") + b"startxref\n".len();
+        let tail = String::from_utf8_lossy(&document[start..]);
+        let xref: usize = tail.lines().next().unwrap().parse().unwrap();
+        let table = String::from_utf8_lossy(&document[xref..]);
+        assert!(table.starts_with("xref\n0 6\n"));
+        let entry = table.lines().nth(6).unwrap();
+        let offset: usize = entry[..10].parse().unwrap();
+        assert!(document[offset..].starts_with(b"4 0 obj"));
+        assert_eq!(number(595.28), "595.28");
+        assert_eq!(number(72.0), "72");
+    }
+}
@@ -0,0 +1,182 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result, anyhow, bail};
+use hayro::hayro_interpret::InterpreterSettings;
+use hayro::hayro_syntax::Pdf;
+use hayro::vello_cpu::color::palette::css::WHITE;
+use hayro::vello_cpu::peniko::ImageAlphaType;
+use hayro::{PixmapSettings, RenderCache, RenderSettings};
+use image::{DynamicImage, RgbaImage};
+use serde_json::json;
+
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+
+use super::pages::{self, PageSelection};
+use super::{store_decoded, take_f64};
+
+const DEFAULT_DPI: f64 = 150.0;
+const POINTS_PER_INCH: f64 = 72.0;
+/// Rendered pages are lossless rasters; encode writes them as PNG unless
+/// told otherwise.
+const PAGE_FORMAT: &str = "png";
+
+/// Renders the pages of a PDF input to images, one artifact per page (see
+/// [`pages::split`]).
+pub struct PdfRasterizeStage {
+    pages: PageSelection,
+    dpi: f64,
+}
+
+impl PdfRasterizeStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let pages = match params.remove("pages") {
+            Some(value) => PageSelection::from_value(&value)?,
+            None => PageSelection::All,
+        };
+        let dpi = take_f64(&mut params, "dpi")?.unwrap_or(DEFAULT_DPI);
+        if !(dpi > 0.0 && dpi.is_finite()) {
+            bail!("pdf_rasterize dpi must be positive, got {dpi}");
+        }
+        Ok(Self { pages, dpi })
+    }
+
+    fn scale(&self) -> f32 {
+        (self.dpi / POINTS_PER_INCH) as f32
+    }
+
+    /// Parses the input and returns it with the pages to render.
+    fn load(&self, artifact: &Artifact) -> Result<(Pdf, Vec<usize>)> {
+        let pdf = Pdf::new(artifact.data.to_vec()).map_err(|err| {
+            anyhow!(
+                "Failed to read PDF {}: {err:?}",
+                artifact.input_path.display()
+            )
+        })?;
+        let count = pdf.pages().len();
+        if count == 0 {
+            bail!("PDF {} has no pages", artifact.input_path.display());
+        }
+        let pages = self
+            .pages
+            .pages(count)
+            .with_context(|| format!("PDF {}", artifact.input_path.display()))?;
+        Ok((pdf, pages))
+    }
+
+    /// What every page shares: rendered pixels replace the document, so
+    /// nothing of it carries over to encode.
+    fn prepare(&self, artifact: &mut Artifact) {
+        artifact.embedded = Arc::default();
+        artifact.set_format(PAGE_FORMAT);
+    }
+
+    fn record_dpi(&self, artifact: &mut Artifact) {
+        artifact
+            .metadata
+            .insert("pdf.dpi".to_string(), json!(self.dpi));
+    }
+
+    /// Pixel size of a page rendered at the stage's DPI.
+    fn page_dimensions(&self, render_dimensions: (f32, f32), page: usize) -> Result<(u32, u32)> {
+        let scale = self.scale();
+        let (width, height) = (
+            (render_dimensions.0 * scale) as u32,
+            (render_dimensions.1 * scale) as u32,
+        );
+        if width == 0 || height == 0 || width > u16::MAX as u32 || height > u16::MAX as u32 {
+            bail!(
+                "Page {page} renders to {width}x{height} at {} dpi; \
+                 pages must be 1 to {} pixels on each side",
+                self.dpi,
+                u16::MAX
+            );
+        }
+        Ok((width, height))
+    }
+}
+
+impl Stage for PdfRasterizeStage {
+    fn name(&self) -> &'static str {
+        "pdf_rasterize"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn splits(&self) -> bool {
+        true
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let (pdf, pages) = self.load(artifact)?;
+        let document = pdf.pages();
+        let cache = RenderCache::new();
+        let interpreter = InterpreterSettings::default();
+        let settings = PixmapSettings {
+            x_scale: self.scale(),
+            y_scale: self.scale(),
+            bg_color: WHITE,
+        };
+        self.prepare(artifact);
+        pages::split(
+            artifact,
+            ctx,
+            "pdf",
+            document.len(),
+            &pages,
+            |target, page| {
+                self.record_dpi(target);
+                let source = &document[page - 1];
+                // Checked up front: the renderer's canvas sizes wrap past 16 bits.
+                self.page_dimensions(source.render_dimensions(), page)?;
+                let pixmap = hayro::render(
+                    source,
+                    &cache,
+                    &interpreter,
+                    &RenderSettings::default(),
+                    &settings,
+                );
+                let (width, height) = (pixmap.width() as u32, pixmap.height() as u32);
+                let pixels = pixmap.take_rgba8(ImageAlphaType::Alpha);
+                let rgba = RgbaImage::from_raw(width, height, pixels)
+                    .ok_or_else(|| anyhow!("Page {page} rendered to a malformed pixel buffer"))?;
+                // Pages are drawn onto white, so there is no alpha to keep.
+                let rendered = DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(rgba).into_rgb8());
+                store_decoded(target, ctx, rendered, true);
+                Ok(())
+            },
+        )
+    }
+
+    fn plan(&self, artifact: &mut Artifact, ctx: &PipelineContext) -> Result<()> {
+        let (pdf, pages) = self.load(artifact)?;
+        let document = pdf.pages();
+        self.prepare(artifact);
+        pages::split(
+            artifact,
+            ctx,
+            "pdf",
+            document.len(),
+            &pages,
+            |target, page| {
+                self.record_dpi(target);
+                let (width, height) =
+                    self.page_dimensions(document[page - 1].render_dimensions(), page)?;
+                target
+                    .metadata
+                    .insert("image.width".to_string(), json!(width));
+                target
+                    .metadata
+                    .insert("image.height".to_string(), json!(height));
+                Ok(())
+            },
+        )
+    }
+}
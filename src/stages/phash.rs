@@ -0,0 +1,70 @@
+use anyhow::{Result, anyhow, bail};
+use serde_json::Value;
+
+use crate::perceptual::{self, HashAlgorithm};
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+
+/// Records perceptual hashes of the image as `hash.<algorithm>` metadata
+/// (16 hex digits) without touching its pixels.
+pub struct PhashStage {
+    algorithms: Vec<HashAlgorithm>,
+}
+
+impl PhashStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let algorithms = match params.remove("algorithms") {
+            None => HashAlgorithm::ALL.to_vec(),
+            Some(Value::String(name)) if name.eq_ignore_ascii_case("all") => {
+                HashAlgorithm::ALL.to_vec()
+            }
+            Some(Value::String(name)) => vec![HashAlgorithm::parse(&name)?],
+            Some(Value::Array(names)) if !names.is_empty() => {
+                let mut algorithms = Vec::with_capacity(names.len());
+                for name in names {
+                    let algorithm = match name {
+                        Value::String(name) => HashAlgorithm::parse(&name)?,
+                        other => bail!("phash algorithms must be names, got {other}"),
+                    };
+                    if !algorithms.contains(&algorithm) {
+                        algorithms.push(algorithm);
+                    }
+                }
+                algorithms
+            }
+            Some(other) => {
+                bail!("phash algorithms must be 'all', a name or a list of names, got {other}")
+            }
+        };
+        Ok(Self { algorithms })
+    }
+}
+
+impl Stage for PhashStage {
+    fn name(&self) -> &'static str {
+        "phash"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        _ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let image = artifact
+            .image
+            .as_ref()
+            .ok_or_else(|| anyhow!("phash stage requires a decoded image"))?;
+        for algorithm in &self.algorithms {
+            artifact.metadata.insert(
+                format!("hash.{algorithm}"),
+                Value::String(perceptual::to_hex(algorithm.hash(image))),
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+use crate::video::probe;
+
+/// Records an ffprobe-like description of the input (container, size,
+/// duration, bit rate and every video and audio track) as `probe.*`
+/// metadata, read from the container headers and sample tables without
+/// decoding a frame.
+pub struct ProbeStage;
+
+impl ProbeStage {
+    pub fn from_params(_params: StageParameters) -> Result<Self> {
+        Ok(Self)
+    }
+
+    fn record(artifact: &mut Artifact) -> Result<()> {
+        let info = probe::probe(&artifact.data).context("failed to probe the input")?;
+        let Value::Object(fields) = serde_json::to_value(info)? else {
+            unreachable!("media info serializes to an object");
+        };
+        for (key, value) in fields {
+            artifact.metadata.insert(format!("probe.{key}"), value);
+        }
+        Ok(())
+    }
+}
+
+impl Stage for ProbeStage {
+    fn name(&self) -> &'static str {
+        "probe"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    /// Probing reads headers only, so dry runs get the real description.
+    fn plan(&self, artifact: &mut Artifact, _ctx: &PipelineContext) -> Result<()> {
+        Self::record(artifact)
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        _ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        Self::record(artifact)
+    }
+}
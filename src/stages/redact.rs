@@ -0,0 +1,536 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow, bail};
+use image::{DynamicImage, Rgba, RgbaImage, imageops};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+use crate::structure;
+
+use super::{take_f64, take_string, value_as_u64};
+
+/// Sidecar looked up next to the input when `sidecar: true`.
+const DEFAULT_SIDECAR: &str = "{stem}.regions.json";
+#[cfg(feature = "onnx")]
+const DEFAULT_FACE_CONFIDENCE: f64 = 0.7;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RedactMethod {
+    Blur,
+    Pixelate,
+}
+
+impl RedactMethod {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Blur => "blur",
+            Self::Pixelate => "pixelate",
+        }
+    }
+}
+
+/// A rectangle in image pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Region {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Region {
+    /// The part of the region inside a `width` x `height` image, if any.
+    fn clip(self, width: u32, height: u32) -> Option<Self> {
+        let right = self.x.saturating_add(self.width).min(width);
+        let bottom = self.y.saturating_add(self.height).min(height);
+        (self.x < right && self.y < bottom).then(|| Self {
+            x: self.x,
+            y: self.y,
+            width: right - self.x,
+            height: bottom - self.y,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SidecarFile {
+    List(Vec<Region>),
+    Object { regions: Vec<Region> },
+}
+
+/// Hides rectangular regions of the image, listed in `regions`, read from a
+/// per-input sidecar JSON file, or (with the `onnx` feature) found by a face
+/// detection model. Regions are blurred or pixelated in place on every
+/// frame; the rest of the image is left untouched.
+pub struct RedactStage {
+    method: RedactMethod,
+    /// Gaussian sigma for `blur`, or the block side for `pixelate`; scaled
+    /// to each region's size when not set.
+    strength: Option<f64>,
+    regions: Vec<Region>,
+    sidecar: Option<String>,
+    #[cfg(feature = "onnx")]
+    faces: Option<onnx::FaceDetector>,
+}
+
+impl RedactStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let method = match take_string(&mut params, "method").as_deref() {
+            None | Some("blur") => RedactMethod::Blur,
+            Some("pixelate") => RedactMethod::Pixelate,
+            Some(other) => bail!("Unknown redact method '{other}' (expected blur or pixelate)"),
+        };
+        let strength = match method {
+            RedactMethod::Blur => {
+                let radius = take_f64(&mut params, "radius")?;
+                if let Some(radius) = radius
+                    && !(radius > 0.0 && radius.is_finite())
+                {
+                    bail!("redact radius must be positive, got {radius}");
+                }
+                radius
+            }
+            RedactMethod::Pixelate => match params.remove("block_size") {
+                None => None,
+                Some(value) => match value_as_u64(&value) {
+                    Some(size @ 2..=4096) => Some(size as f64),
+                    _ => bail!("redact block_size must be between 2 and 4096 pixels, got {value}"),
+                },
+            },
+        };
+        let regions = match params.remove("regions") {
+            None => Vec::new(),
+            Some(value) => serde_json::from_value(value)
+                .context("redact regions must be a list of {x, y, width, height} in pixels")?,
+        };
+        let sidecar = match params.remove("sidecar") {
+            None | Some(Value::Bool(false)) => None,
+            Some(Value::Bool(true)) => Some(DEFAULT_SIDECAR.to_string()),
+            Some(Value::String(template)) => {
+                structure::validate(&template)?;
+                Some(template)
+            }
+            Some(other) => bail!("redact sidecar must be true or a path template, got {other}"),
+        };
+        let faces = params.remove("faces");
+
+        #[cfg(feature = "onnx")]
+        let faces = faces.map(onnx::FaceDetector::from_params).transpose()?;
+        #[cfg(not(feature = "onnx"))]
+        if faces.is_some() {
+            // Quietly publishing unredacted faces is worse than failing.
+            bail!("redact faces needs face detection; rebuild with --features onnx");
+        }
+
+        #[cfg(feature = "onnx")]
+        let detects = faces.is_some();
+        #[cfg(not(feature = "onnx"))]
+        let detects = false;
+        if regions.is_empty() && sidecar.is_none() && !detects {
+            bail!("redact stage requires 'regions', 'sidecar' or 'faces'");
+        }
+        Ok(Self {
+            method,
+            strength,
+            regions,
+            sidecar,
+            #[cfg(feature = "onnx")]
+            faces,
+        })
+    }
+
+    fn sidecar_path(&self, template: &str, artifact: &Artifact) -> PathBuf {
+        let extension = artifact
+            .input_path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let rendered = structure::render(template, &artifact.stem, &extension, &artifact.metadata);
+        let path = Path::new(&rendered);
+        match artifact.input_path.parent() {
+            Some(parent) if path.is_relative() => parent.join(path),
+            _ => path.to_path_buf(),
+        }
+    }
+
+    /// Every region to hide, with where it came from, before clipping.
+    fn collect(&self, artifact: &Artifact, image: &DynamicImage) -> Result<Vec<(Region, &str)>> {
+        let mut regions: Vec<_> = self
+            .regions
+            .iter()
+            .map(|region| (*region, "params"))
+            .collect();
+        if let Some(template) = &self.sidecar {
+            let path = self.sidecar_path(template, artifact);
+            // Inputs without anything to hide simply have no sidecar.
+            if path.is_file() {
+                let text = fs::read_to_string(&path).with_context(|| {
+                    format!("Failed to read redact sidecar: {}", path.display())
+                })?;
+                let file: SidecarFile = serde_json::from_str(&text)
+                    .with_context(|| format!("Invalid redact sidecar: {}", path.display()))?;
+                let (SidecarFile::List(found) | SidecarFile::Object { regions: found }) = file;
+                regions.extend(found.into_iter().map(|region| (region, "sidecar")));
+            }
+        }
+        #[cfg(feature = "onnx")]
+        if let Some(faces) = &self.faces {
+            regions.extend(
+                faces
+                    .detect(image)?
+                    .into_iter()
+                    .map(|region| (region, "face")),
+            );
+        }
+        #[cfg(not(feature = "onnx"))]
+        let _ = image;
+        Ok(regions)
+    }
+
+    fn redact(&self, image: &mut RgbaImage, region: Region) {
+        let patch =
+            imageops::crop_imm(image, region.x, region.y, region.width, region.height).to_image();
+        let short_side = f64::from(region.width.min(region.height));
+        let hidden = match self.method {
+            RedactMethod::Blur => {
+                let sigma = self.strength.unwrap_or((short_side / 6.0).max(2.0));
+                imageops::blur(&patch, sigma as f32)
+            }
+            RedactMethod::Pixelate => {
+                let size = self.strength.unwrap_or((short_side / 8.0).max(4.0));
+                pixelate(&patch, size as u32)
+            }
+        };
+        imageops::replace(image, &hidden, i64::from(region.x), i64::from(region.y));
+    }
+}
+
+impl Stage for RedactStage {
+    fn name(&self) -> &'static str {
+        "redact"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        _ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let image = artifact
+            .image
+            .as_ref()
+            .ok_or_else(|| anyhow!("redact stage requires a decoded image"))?;
+        let (width, height) = (image.width(), image.height());
+        let found = self.collect(artifact, image)?;
+        let regions: Vec<_> = found
+            .into_iter()
+            .filter_map(|(region, source)| Some((region.clip(width, height)?, source)))
+            .collect();
+
+        if !regions.is_empty() {
+            let mut redacted = image.to_rgba8();
+            for (region, _) in &regions {
+                self.redact(&mut redacted, *region);
+            }
+            if artifact.is_animated() {
+                for frame in artifact.frames_mut() {
+                    let (width, height) = frame.image.dimensions();
+                    for (region, _) in &regions {
+                        if let Some(region) = region.clip(width, height) {
+                            self.redact(&mut frame.image, region);
+                        }
+                    }
+                }
+            }
+            artifact.set_image(DynamicImage::ImageRgba8(redacted));
+        }
+
+        artifact.metadata.insert(
+            "redact.method".to_string(),
+            Value::String(self.method.as_str().to_string()),
+        );
+        artifact.metadata.insert(
+            "redact.regions".to_string(),
+            Value::Array(
+                regions
+                    .iter()
+                    .map(|(region, source)| {
+                        json!({
+                            "x": region.x,
+                            "y": region.y,
+                            "width": region.width,
+                            "height": region.height,
+                            "source": source,
+                        })
+                    })
+                    .collect(),
+            ),
+        );
+        Ok(())
+    }
+}
+
+/// Replaces each `size` x `size` block with its average colour.
+fn pixelate(image: &RgbaImage, size: u32) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    let mut pixelated = RgbaImage::new(width, height);
+    for top in (0..height).step_by(size as usize) {
+        for left in (0..width).step_by(size as usize) {
+            let (right, bottom) = ((left + size).min(width), (top + size).min(height));
+            let count = u64::from((right - left) * (bottom - top));
+            let mut sums = [0u64; 4];
+            for y in top..bottom {
+                for x in left..right {
+                    for (sum, value) in sums.iter_mut().zip(image.get_pixel(x, y).0) {
+                        *sum += u64::from(value);
+                    }
+                }
+            }
+            let average = Rgba(sums.map(|sum| ((sum + count / 2) / count) as u8));
+            for y in top..bottom {
+                for x in left..right {
+                    pixelated.put_pixel(x, y, average);
+                }
+            }
+        }
+    }
+    pixelated
+}
+
+#[cfg(feature = "onnx")]
+mod onnx {
+    use std::path::PathBuf;
+
+    use anyhow::{Context, Result, anyhow, bail};
+    use image::DynamicImage;
+    use image::imageops::{self, FilterType};
+    use serde_json::Value;
+    use tract_onnx::prelude::*;
+
+    use super::{DEFAULT_FACE_CONFIDENCE, Region};
+
+    type Plan = TypedRunnableModel<TypedModel>;
+
+    const INPUT_WIDTH: u32 = 320;
+    const INPUT_HEIGHT: u32 = 240;
+    /// Overlap above which a lower-scoring detection is the same face.
+    const NMS_IOU: f32 = 0.3;
+    /// Detections are grown by this fraction on each side so hair and chin
+    /// are hidden too.
+    const MARGIN: f32 = 0.1;
+
+    /// An UltraFace-style detector: `[1, 3, 240, 320]` RGB scaled to
+    /// `-1..=1` in, `[1, N, 2]` background/face scores and `[1, N, 4]`
+    /// normalised corner boxes out.
+    pub struct FaceDetector {
+        plan: Plan,
+        confidence: f32,
+    }
+
+    impl FaceDetector {
+        /// `faces` is the model path, or `{model, confidence}`.
+        pub fn from_params(faces: Value) -> Result<Self> {
+            let (model, confidence) = match faces {
+                Value::String(model) => (model, DEFAULT_FACE_CONFIDENCE),
+                Value::Object(mut options) => {
+                    let model = match options.remove("model") {
+                        Some(Value::String(model)) => model,
+                        _ => bail!("redact faces requires a 'model' path"),
+                    };
+                    let confidence = match options.remove("confidence") {
+                        None => DEFAULT_FACE_CONFIDENCE,
+                        Some(value) => value
+                            .as_f64()
+                            .filter(|confidence| (0.0..=1.0).contains(confidence))
+                            .ok_or_else(|| {
+                                anyhow!("redact faces confidence must be in 0..=1, got {value}")
+                            })?,
+                    };
+                    (model, confidence)
+                }
+                other => {
+                    bail!("redact faces must be a model path or {{model, confidence}}, got {other}")
+                }
+            };
+            let path = PathBuf::from(model);
+            let plan = tract_onnx::onnx()
+                .model_for_path(&path)
+                .and_then(|model| {
+                    model.with_input_fact(
+                        0,
+                        f32::fact([1, 3, INPUT_HEIGHT as usize, INPUT_WIDTH as usize]).into(),
+                    )
+                })
+                .and_then(|model| model.into_optimized())
+                .and_then(|model| model.into_runnable())
+                .map_err(|err| anyhow!("{err:?}"))
+                .with_context(|| format!("Failed to load face model: {}", path.display()))?;
+            Ok(Self {
+                plan,
+                confidence: confidence as f32,
+            })
+        }
+
+        pub fn detect(&self, image: &DynamicImage) -> Result<Vec<Region>> {
+            let resized = imageops::resize(
+                &image.to_rgb8(),
+                INPUT_WIDTH,
+                INPUT_HEIGHT,
+                FilterType::Triangle,
+            );
+            let input = tract_ndarray::Array4::from_shape_fn(
+                (1, 3, INPUT_HEIGHT as usize, INPUT_WIDTH as usize),
+                |(_, channel, y, x)| {
+                    (f32::from(resized.get_pixel(x as u32, y as u32)[channel]) - 127.0) / 128.0
+                },
+            );
+            let result = self
+                .plan
+                .run(tvec!(Tensor::from(input).into()))
+                .map_err(|err| anyhow!("face model failed: {err:?}"))?;
+            if result.len() < 2 {
+                bail!("face model must output scores and boxes");
+            }
+            let scores = result[0]
+                .to_array_view::<f32>()
+                .map_err(|err| anyhow!("unexpected face model scores: {err:?}"))?;
+            let boxes = result[1]
+                .to_array_view::<f32>()
+                .map_err(|err| anyhow!("unexpected face model boxes: {err:?}"))?;
+            let count = scores.shape().get(1).copied().unwrap_or(0);
+            if scores.shape() != [1, count, 2] || boxes.shape() != [1, count, 4] {
+                bail!(
+                    "face model produced shapes {:?} and {:?}, expected [1, N, 2] and [1, N, 4]",
+                    scores.shape(),
+                    boxes.shape()
+                );
+            }
+
+            let mut candidates: Vec<(f32, [f32; 4])> = (0..count)
+                .filter(|&i| scores[[0, i, 1]] >= self.confidence)
+                .map(|i| {
+                    let corner = |c: usize| boxes[[0, i, c]].clamp(0.0, 1.0);
+                    (
+                        scores[[0, i, 1]],
+                        [corner(0), corner(1), corner(2), corner(3)],
+                    )
+                })
+                .collect();
+            candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+            let mut kept: Vec<[f32; 4]> = Vec::new();
+            for (_, candidate) in candidates {
+                if kept.iter().all(|face| iou(face, &candidate) <= NMS_IOU) {
+                    kept.push(candidate);
+                }
+            }
+
+            let (width, height) = (image.width() as f32, image.height() as f32);
+            Ok(kept
+                .into_iter()
+                .map(|[x1, y1, x2, y2]| {
+                    let (grow_x, grow_y) = ((x2 - x1) * MARGIN, (y2 - y1) * MARGIN);
+                    let left = ((x1 - grow_x).max(0.0) * width).floor();
+                    let top = ((y1 - grow_y).max(0.0) * height).floor();
+                    let right = ((x2 + grow_x).min(1.0) * width).ceil();
+                    let bottom = ((y2 + grow_y).min(1.0) * height).ceil();
+                    Region {
+                        x: left as u32,
+                        y: top as u32,
+                        width: (right - left).max(1.0) as u32,
+                        height: (bottom - top).max(1.0) as u32,
+                    }
+                })
+                .collect())
+        }
+    }
+
+    fn iou(a: &[f32; 4], b: &[f32; 4]) -> f32 {
+        let area = |r: &[f32; 4]| (r[2] - r[0]).max(0.0) * (r[3] - r[1]).max(0.0);
+        let overlap = area(&[
+            a[0].max(b[0]),
+            a[1].max(b[1]),
+            a[2].min(b[2]),
+            a[3].min(b[3]),
+        ]);
+        let union = area(a) + area(b) - overlap;
+        if union > 0.0 { overlap / union } else { 0.0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stages::json_params;
+
+    fn checkerboard(width: u32, height: u32) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, y| {
+            if (x + y) % 2 == 0 {
+                Rgba([255, 255, 255, 255])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        })
+    }
+
+    #[test]
+    fn pixelate_only_touches_the_region() {
+        let stage = RedactStage::from_params(json_params(json!({
+            "method": "pixelate",
+            "block_size": 4,
+            "regions": [{ "x": 4, "y": 4, "width": 8, "height": 8 }],
+        })))
+        .unwrap();
+        let original = checkerboard(16, 16);
+        let mut image = original.clone();
+        stage.redact(&mut image, stage.regions[0]);
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let inside = (4..12).contains(&x) && (4..12).contains(&y);
+            if inside {
+                assert!((127..=128).contains(&pixel[0]), "({x}, {y}) kept detail");
+            } else {
+                assert_eq!(pixel, original.get_pixel(x, y));
+            }
+        }
+    }
+
+    #[test]
+    fn regions_are_clipped_to_the_image() {
+        let region = Region {
+            x: 10,
+            y: 5,
+            width: 20,
+            height: 20,
+        };
+        assert_eq!(
+            region.clip(16, 16),
+            Some(Region {
+                x: 10,
+                y: 5,
+                width: 6,
+                height: 11,
+            })
+        );
+        assert_eq!(region.clip(8, 8), None);
+    }
+
+    #[test]
+    fn requires_something_to_redact() {
+        assert!(RedactStage::from_params(json_params(json!({ "method": "blur" }))).is_err());
+        assert!(RedactStage::from_params(json_params(json!({ "sidecar": true }))).is_ok());
+        assert!(
+            RedactStage::from_params(json_params(json!({
+                "regions": [{ "x": 0, "y": 0, "w": 4, "h": 4 }],
+            })))
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,268 @@
+use anyhow::{Result, anyhow, bail};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+
+use super::{take_string, value_as_bool, value_as_u64};
+
+const DEFAULT_MAX_LENGTH: usize = 80;
+const DEFAULT_HASH_LENGTH: usize = 8;
+
+/// Rewrites the artifact stem into a URL-safe slug so later stages (and the
+/// output structure) see a clean name.
+pub struct RenameStage {
+    separator: String,
+    lowercase: bool,
+    max_length: usize,
+    hash_length: usize,
+}
+
+impl RenameStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let separator = take_string(&mut params, "separator").unwrap_or_else(|| "-".to_string());
+        if separator.chars().any(|c| c == '/' || c == '\\') {
+            bail!("rename separator cannot contain path separators");
+        }
+        let lowercase = match params.remove("lowercase") {
+            Some(value) => value_as_bool(&value)
+                .ok_or_else(|| anyhow!("lowercase must be a boolean, got {value}"))?,
+            None => true,
+        };
+        let max_length = match params.remove("max_length") {
+            Some(value) => value_as_u64(&value)
+                .filter(|&length| length > 0)
+                .ok_or_else(|| anyhow!("max_length must be a positive integer, got {value}"))?
+                as usize,
+            None => DEFAULT_MAX_LENGTH,
+        };
+        let hash_length = match params.remove("hash") {
+            Some(Value::Bool(true)) => DEFAULT_HASH_LENGTH,
+            Some(Value::Bool(false)) => 0,
+            Some(value) => {
+                let length = value_as_u64(&value)
+                    .ok_or_else(|| anyhow!("hash must be a boolean or digit count, got {value}"))?;
+                if length > 64 {
+                    bail!("hash length {length} exceeds the 64 hex digits of SHA256");
+                }
+                length as usize
+            }
+            None => 0,
+        };
+        if hash_length > 0 && hash_length + separator.chars().count() >= max_length {
+            bail!("max_length {max_length} leaves no room for the stem next to the hash");
+        }
+        Ok(Self {
+            separator,
+            lowercase,
+            max_length,
+            hash_length,
+        })
+    }
+
+    fn rename(&self, stem: &str, data: &[u8]) -> String {
+        let suffix = if self.hash_length > 0 {
+            let digest = format!("{:x}", Sha256::digest(data));
+            format!("{}{}", self.separator, &digest[..self.hash_length])
+        } else {
+            String::new()
+        };
+        // Lengths count characters, as the separator need not be ASCII.
+        let budget = self.max_length - suffix.chars().count();
+        let mut slug = slugify(stem, &self.separator, self.lowercase);
+        if let Some((mut cut, _)) = slug.char_indices().nth(budget) {
+            // Never keep part of a separator, like `photo_-` from `_-_`.
+            if let Some((start, _)) = slug
+                .match_indices(self.separator.as_str())
+                .find(|(start, separator)| *start < cut && cut < start + separator.len())
+            {
+                cut = start;
+            }
+            slug.truncate(cut);
+            while !self.separator.is_empty() && slug.ends_with(self.separator.as_str()) {
+                slug.truncate(slug.len() - self.separator.len());
+            }
+        }
+        if slug.is_empty() {
+            slug.push_str("file");
+        }
+        slug.push_str(&suffix);
+        slug
+    }
+}
+
+impl Stage for RenameStage {
+    fn name(&self) -> &'static str {
+        "rename"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        _ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let renamed = self.rename(&artifact.stem, &artifact.data);
+        artifact.metadata.insert(
+            "rename.original_stem".to_string(),
+            Value::String(artifact.stem.clone()),
+        );
+        artifact
+            .metadata
+            .insert("stem".to_string(), json!(renamed.as_str()));
+        artifact.stem = renamed;
+        Ok(())
+    }
+
+    fn plan(&self, artifact: &mut Artifact, ctx: &PipelineContext) -> Result<()> {
+        self.run(artifact, ctx, StageDevice::Cpu)
+    }
+}
+
+/// ASCII-folds `input` and joins its alphanumeric runs with `separator`.
+fn slugify(input: &str, separator: &str, lowercase: bool) -> String {
+    let mut slug = String::with_capacity(input.len());
+    let mut pending_separator = false;
+    for c in input.chars() {
+        let folded = fold_char(c);
+        for c in folded.chars() {
+            if c.is_ascii_alphanumeric() {
+                if pending_separator && !slug.is_empty() {
+                    slug.push_str(separator);
+                }
+                pending_separator = false;
+                slug.push(if lowercase { c.to_ascii_lowercase() } else { c });
+            } else {
+                pending_separator = true;
+            }
+        }
+    }
+    slug
+}
+
+fn fold_char(c: char) -> String {
+    if c.is_ascii() {
+        return c.to_string();
+    }
+    let folded = match c {
+        'À' | 'Á' | 'Â' | 'Ã' | 'Ä' | 'Å' | 'Ā' | 'Ă' | 'Ą' => "A",
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' => "a",
+        'Æ' => "AE",
+        'æ' => "ae",
+        'Ç' | 'Ć' | 'Ĉ' | 'Ċ' | 'Č' => "C",
+        'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => "c",
+        'Ð' | 'Ď' | 'Đ' => "D",
+        'ð' | 'ď' | 'đ' => "d",
+        'È' | 'É' | 'Ê' | 'Ë' | 'Ē' | 'Ĕ' | 'Ė' | 'Ę' | 'Ě' => "E",
+        'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => "e",
+        'Ĝ' | 'Ğ' | 'Ġ' | 'Ģ' => "G",
+        'ĝ' | 'ğ' | 'ġ' | 'ģ' => "g",
+        'Ĥ' | 'Ħ' => "H",
+        'ĥ' | 'ħ' => "h",
+        'Ì' | 'Í' | 'Î' | 'Ï' | 'Ĩ' | 'Ī' | 'Ĭ' | 'Į' | 'İ' => "I",
+        'ì' | 'í' | 'î' | 'ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' | 'ı' => "i",
+        'Ĵ' => "J",
+        'ĵ' => "j",
+        'Ķ' => "K",
+        'ķ' => "k",
+        'Ĺ' | 'Ļ' | 'Ľ' | 'Ŀ' | 'Ł' => "L",
+        'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => "l",
+        'Ñ' | 'Ń' | 'Ņ' | 'Ň' => "N",
+        'ñ' | 'ń' | 'ņ' | 'ň' => "n",
+        'Ò' | 'Ó' | 'Ô' | 'Õ' | 'Ö' | 'Ø' | 'Ō' | 'Ŏ' | 'Ő' => "O",
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ŏ' | 'ő' => "o",
+        'Œ' => "OE",
+        'œ' => "oe",
+        'Ŕ' | 'Ŗ' | 'Ř' => "R",
+        'ŕ' | 'ŗ' | 'ř' => "r",
+        'Ś' | 'Ŝ' | 'Ş' | 'Š' | 'Ș' => "S",
+        'ś' | 'ŝ' | 'ş' | 'š' | 'ș' => "s",
+        'ß' => "ss",
+        'Ţ' | 'Ť' | 'Ŧ' | 'Ț' => "T",
+        'ţ' | 'ť' | 'ŧ' | 'ț' => "t",
+        'Þ' => "TH",
+        'þ' => "th",
+        'Ù' | 'Ú' | 'Û' | 'Ü' | 'Ũ' | 'Ū' | 'Ŭ' | 'Ů' | 'Ű' | 'Ų' => "U",
+        'ù' | 'ú' | 'û' | 'ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => "u",
+        'Ŵ' => "W",
+        'ŵ' => "w",
+        'Ý' | 'Ÿ' | 'Ŷ' => "Y",
+        'ý' | 'ÿ' | 'ŷ' => "y",
+        'Ź' | 'Ż' | 'Ž' => "Z",
+        'ź' | 'ż' | 'ž' => "z",
+        _ => "",
+    };
+    folded.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stages::from_json;
+
+    #[test]
+    fn slugify_folds_and_collapses_separators() {
+        assert_eq!(
+            slugify("Crème Brûlée  (Final) v2", "-", true),
+            "creme-brulee-final-v2"
+        );
+        assert_eq!(slugify("__Straße__", "_", false), "Strasse");
+        assert_eq!(slugify("日本", "-", true), "");
+    }
+
+    #[test]
+    fn rename_truncates_and_appends_hash() {
+        let rename = from_json(
+            RenameStage::from_params,
+            json!({ "max_length": 12, "hash": 4 }),
+        )
+        .unwrap();
+        let renamed = rename.rename("Quarterly Report Cover", b"bytes");
+        let digest = format!("{:x}", Sha256::digest(b"bytes"));
+        assert_eq!(renamed, format!("quarter-{}", &digest[..4]));
+
+        let rename = from_json(RenameStage::from_params, json!({ "max_length": 10 })).unwrap();
+        assert_eq!(rename.rename("Quarterly Report", b""), "quarterly");
+        assert_eq!(
+            from_json(RenameStage::from_params, json!({}))
+                .unwrap()
+                .rename("日本", b""),
+            "file"
+        );
+
+        let rename = from_json(
+            RenameStage::from_params,
+            json!({ "separator": "·", "max_length": 8, "hash": 2 }),
+        )
+        .unwrap();
+        let digest = format!("{:x}", Sha256::digest(b""));
+        assert_eq!(
+            rename.rename("ab cd ef", b""),
+            format!("ab·cd·{}", &digest[..2])
+        );
+        let rename = from_json(
+            RenameStage::from_params,
+            json!({ "separator": "–", "max_length": 3 }),
+        )
+        .unwrap();
+        assert_eq!(rename.rename("ab cd", b""), "ab");
+        let rename = from_json(
+            RenameStage::from_params,
+            json!({ "separator": "··", "max_length": 11, "hash": 8 }),
+        )
+        .unwrap();
+        assert_eq!(rename.rename("ab", b"").chars().count(), 11);
+        let rename = from_json(
+            RenameStage::from_params,
+            json!({ "separator": "_-_", "max_length": 7 }),
+        )
+        .unwrap();
+        assert_eq!(rename.rename("photo holiday", b""), "photo");
+        assert_eq!(rename.rename("ph oto holiday", b""), "ph_-_ot");
+    }
+}
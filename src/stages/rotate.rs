@@ -0,0 +1,186 @@
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow, bail};
+use image::DynamicImage;
+use image::metadata::Orientation;
+use serde_json::{Value, json};
+
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+
+use super::{record_dimensions, take_string, value_as_f64};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Flip {
+    None,
+    Horizontal,
+    Vertical,
+}
+
+impl Flip {
+    fn from_str(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "none" | "off" => Some(Self::None),
+            "horizontal" | "h" => Some(Self::Horizontal),
+            "vertical" | "v" => Some(Self::Vertical),
+            _ => None,
+        }
+    }
+
+    pub(super) fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Horizontal => "horizontal",
+            Self::Vertical => "vertical",
+        }
+    }
+}
+
+/// Rotates the image clockwise by a multiple of 90 degrees, then mirrors it.
+pub struct RotateStage {
+    angle: u32,
+    flip: Flip,
+}
+
+impl RotateStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let angle = take_angle(&mut params)?;
+        let flip = take_flip(&mut params)?;
+        if angle == 0 && flip == Flip::None {
+            bail!("rotate stage requires a non-zero 'angle' or a 'flip'");
+        }
+        Ok(Self { angle, flip })
+    }
+
+    fn transform(&self, image: &mut DynamicImage) {
+        image.apply_orientation(match self.angle {
+            90 => Orientation::Rotate90,
+            180 => Orientation::Rotate180,
+            270 => Orientation::Rotate270,
+            _ => Orientation::NoTransforms,
+        });
+        image.apply_orientation(match self.flip {
+            Flip::None => Orientation::NoTransforms,
+            Flip::Horizontal => Orientation::FlipHorizontal,
+            Flip::Vertical => Orientation::FlipVertical,
+        });
+    }
+}
+
+impl Stage for RotateStage {
+    fn name(&self) -> &'static str {
+        "rotate"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn output_dimensions(&self, width: u32, height: u32) -> (u32, u32) {
+        if self.angle.is_multiple_of(180) {
+            (width, height)
+        } else {
+            (height, width)
+        }
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        _ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let image = artifact
+            .image
+            .take()
+            .ok_or_else(|| anyhow!("rotate stage requires a decoded image"))?;
+        if artifact.is_animated() {
+            for frame in artifact.frames_mut() {
+                let mut canvas = DynamicImage::ImageRgba8(std::mem::take(&mut frame.image));
+                self.transform(&mut canvas);
+                frame.image = canvas.into_rgba8();
+            }
+        }
+        let mut rotated = Arc::unwrap_or_clone(image);
+        self.transform(&mut rotated);
+        record_dimensions(artifact, "image", &rotated);
+        artifact.set_image(rotated);
+        artifact
+            .metadata
+            .insert("rotate.angle".to_string(), json!(self.angle));
+        artifact.metadata.insert(
+            "rotate.flip".to_string(),
+            Value::String(self.flip.as_str().to_string()),
+        );
+        Ok(())
+    }
+}
+
+/// The clockwise `angle` parameter as 0, 90, 180 or 270 degrees; negative
+/// angles turn counter-clockwise.
+pub(super) fn take_angle(params: &mut StageParameters) -> Result<u32> {
+    match params.remove("angle") {
+        Some(value) => {
+            let degrees = value_as_f64(&value)
+                .filter(|degrees| degrees.fract() == 0.0 && degrees % 90.0 == 0.0)
+                .ok_or_else(|| anyhow!("angle must be a multiple of 90, got {value}"))?;
+            Ok(degrees.rem_euclid(360.0) as u32)
+        }
+        None => Ok(0),
+    }
+}
+
+pub(super) fn take_flip(params: &mut StageParameters) -> Result<Flip> {
+    match take_string(params, "flip") {
+        Some(value) => Flip::from_str(&value)
+            .ok_or_else(|| anyhow!("Unknown flip '{value}' (expected horizontal or vertical)")),
+        None => Ok(Flip::None),
+    }
+}
+
+/// Whether applying `orientation` swaps the image's width and height.
+pub(super) fn swaps_axes(orientation: Orientation) -> bool {
+    matches!(
+        orientation,
+        Orientation::Rotate90
+            | Orientation::Rotate270
+            | Orientation::Rotate90FlipH
+            | Orientation::Rotate270FlipH
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+    use crate::stages::from_json;
+
+    #[test]
+    fn rotates_clockwise_then_flips() {
+        // A 2x1 image: red on the left, blue on the right.
+        let mut image = RgbaImage::new(2, 1);
+        image.put_pixel(0, 0, Rgba([255, 0, 0, 255]));
+        image.put_pixel(1, 0, Rgba([0, 0, 255, 255]));
+        let pixel = |stage: &RotateStage, x: u32, y: u32| {
+            let mut image = DynamicImage::ImageRgba8(image.clone());
+            stage.transform(&mut image);
+            image.to_rgba8().get_pixel(x, y).0
+        };
+
+        let quarter = from_json(RotateStage::from_params, json!({ "angle": 90 })).unwrap();
+        assert_eq!(quarter.output_dimensions(2, 1), (1, 2));
+        assert_eq!(pixel(&quarter, 0, 0), [255, 0, 0, 255]);
+        let back = from_json(RotateStage::from_params, json!({ "angle": -90 })).unwrap();
+        assert_eq!(back.angle, 270);
+        assert_eq!(pixel(&back, 0, 0), [0, 0, 255, 255]);
+        let mirrored =
+            from_json(RotateStage::from_params, json!({ "flip": "horizontal" })).unwrap();
+        assert_eq!(mirrored.output_dimensions(2, 1), (2, 1));
+        assert_eq!(pixel(&mirrored, 0, 0), [0, 0, 255, 255]);
+
+        assert!(from_json(RotateStage::from_params, json!({ "angle": 45 })).is_err());
+        assert!(from_json(RotateStage::from_params, json!({ "flip": "diagonal" })).is_err());
+        assert!(from_json(RotateStage::from_params, json!({})).is_err());
+    }
+}
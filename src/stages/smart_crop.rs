@@ -0,0 +1,351 @@
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow, bail};
+use image::imageops::FilterType as ResizeFilter;
+use image::{DynamicImage, GrayImage};
+use serde_json::{Value, json};
+
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+
+use super::{map_filter, record_dimensions, take_string, take_u32};
+
+/// Longest edge of the downscaled copy the crop window is scored on.
+const ANALYSIS_EDGE: u32 = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Strategy {
+    /// Most detail: the sum of luma gradients inside the window.
+    Edges,
+    /// Most varied tones: the Shannon entropy of the window's luma histogram.
+    Entropy,
+}
+
+impl Strategy {
+    fn from_str(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "edges" | "edge" => Some(Self::Edges),
+            "entropy" => Some(Self::Entropy),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Edges => "edges",
+            Self::Entropy => "entropy",
+        }
+    }
+}
+
+/// Crops to the aspect ratio of `width` x `height` around the busiest part
+/// of the image, then resizes to exactly that size. The window is as large
+/// as the aspect ratio allows, so it only ever slides along one axis.
+pub struct SmartCropStage {
+    width: u32,
+    height: u32,
+    strategy: Strategy,
+    filter: ResizeFilter,
+}
+
+impl SmartCropStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let (width, height) = match (
+            take_u32(&mut params, "width"),
+            take_u32(&mut params, "height"),
+        ) {
+            (Some(width), Some(height)) if width > 0 && height > 0 => (width, height),
+            _ => bail!("smart_crop stage requires positive 'width' and 'height' parameters"),
+        };
+        let strategy = match take_string(&mut params, "strategy") {
+            Some(value) => Strategy::from_str(&value).ok_or_else(|| {
+                anyhow!("Unknown smart_crop strategy '{value}' (expected edges or entropy)")
+            })?,
+            None => Strategy::Edges,
+        };
+        let filter = take_string(&mut params, "method")
+            .and_then(map_filter)
+            .unwrap_or(ResizeFilter::CatmullRom);
+        Ok(Self {
+            width,
+            height,
+            strategy,
+            filter,
+        })
+    }
+
+    /// The crop window `(x, y, width, height)` for `image`.
+    fn window(&self, image: &DynamicImage) -> (u32, u32, u32, u32) {
+        let (width, height) = (image.width(), image.height());
+        let (crop_width, crop_height) = window_size(width, height, self.width, self.height);
+        if (crop_width, crop_height) == (width, height) {
+            return (0, 0, width, height);
+        }
+        // Score a small copy; the window keeps its proportions on it.
+        let scale = f64::from(ANALYSIS_EDGE) / f64::from(width.max(height));
+        let luma = if scale < 1.0 {
+            let scaled = |edge: u32| ((f64::from(edge) * scale).round() as u32).max(1);
+            image
+                .resize_exact(scaled(width), scaled(height), ResizeFilter::Triangle)
+                .into_luma8()
+        } else {
+            image.to_luma8()
+        };
+        let horizontal = crop_width < width;
+        let (len, window) = if horizontal {
+            let window = f64::from(crop_width) / f64::from(width) * f64::from(luma.width());
+            (luma.width(), (window.round() as u32).clamp(1, luma.width()))
+        } else {
+            let window = f64::from(crop_height) / f64::from(height) * f64::from(luma.height());
+            (
+                luma.height(),
+                (window.round() as u32).clamp(1, luma.height()),
+            )
+        };
+        let best = match self.strategy {
+            Strategy::Edges => best_by_edges(&luma, horizontal, window),
+            Strategy::Entropy => best_by_entropy(&luma, horizontal, window),
+        };
+        // Back to full resolution, keeping the window inside the image.
+        let free = if horizontal {
+            width - crop_width
+        } else {
+            height - crop_height
+        };
+        let slack = len - window;
+        let offset = if slack == 0 {
+            free / 2
+        } else {
+            ((u64::from(best) * u64::from(free) + u64::from(slack) / 2) / u64::from(slack)) as u32
+        };
+        if horizontal {
+            (offset, 0, crop_width, crop_height)
+        } else {
+            (0, offset, crop_width, crop_height)
+        }
+    }
+
+    fn crop(&self, image: &DynamicImage, window: (u32, u32, u32, u32)) -> DynamicImage {
+        let (x, y, width, height) = window;
+        image
+            .crop_imm(x, y, width, height)
+            .resize_exact(self.width, self.height, self.filter)
+    }
+}
+
+impl Stage for SmartCropStage {
+    fn name(&self) -> &'static str {
+        "smart_crop"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn output_dimensions(&self, _width: u32, _height: u32) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        _ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let image = artifact
+            .image
+            .as_ref()
+            .map(Arc::clone)
+            .ok_or_else(|| anyhow!("smart_crop stage requires a decoded image"))?;
+        // Animations keep the window chosen on their first frame so the
+        // subject does not jump between frames.
+        let window = self.window(&image);
+        if artifact.is_animated() {
+            for frame in artifact.frames_mut() {
+                let canvas = DynamicImage::ImageRgba8(std::mem::take(&mut frame.image));
+                frame.image = self.crop(&canvas, window).into_rgba8();
+            }
+        }
+        let cropped = self.crop(&image, window);
+        record_dimensions(artifact, "image", &cropped);
+        artifact.set_image(cropped);
+        let (x, y, width, height) = window;
+        for (key, value) in [
+            ("smart_crop.x", x),
+            ("smart_crop.y", y),
+            ("smart_crop.width", width),
+            ("smart_crop.height", height),
+        ] {
+            artifact.metadata.insert(key.to_string(), json!(value));
+        }
+        artifact.metadata.insert(
+            "smart_crop.strategy".to_string(),
+            Value::String(self.strategy.as_str().to_string()),
+        );
+        Ok(())
+    }
+}
+
+/// The largest `target_width`:`target_height` window that fits in the image.
+fn window_size(width: u32, height: u32, target_width: u32, target_height: u32) -> (u32, u32) {
+    let (width, height) = (u64::from(width), u64::from(height));
+    let (target_width, target_height) = (u64::from(target_width), u64::from(target_height));
+    if width * target_height > height * target_width {
+        let crop = (height * target_width + target_height / 2) / target_height;
+        (crop.clamp(1, width) as u32, height as u32)
+    } else {
+        let crop = (width * target_height + target_width / 2) / target_width;
+        (width as u32, crop.clamp(1, height) as u32)
+    }
+}
+
+/// Per-line totals along the sliding axis, so a window's score is the sum
+/// over the lines it covers.
+fn edge_profile(luma: &GrayImage, horizontal: bool) -> Vec<u64> {
+    let (width, height) = luma.dimensions();
+    let mut profile = vec![0u64; if horizontal { width } else { height } as usize];
+    let at = |x: u32, y: u32| i32::from(luma.get_pixel(x, y)[0]);
+    for y in 0..height {
+        for x in 0..width {
+            let dx = if x + 1 < width {
+                at(x + 1, y) - at(x, y)
+            } else {
+                0
+            };
+            let dy = if y + 1 < height {
+                at(x, y + 1) - at(x, y)
+            } else {
+                0
+            };
+            let line = if horizontal { x } else { y };
+            profile[line as usize] += u64::from(dx.unsigned_abs() + dy.unsigned_abs());
+        }
+    }
+    profile
+}
+
+fn best_by_edges(luma: &GrayImage, horizontal: bool, window: u32) -> u32 {
+    let profile = edge_profile(luma, horizontal);
+    let window = window as usize;
+    let mut score: u64 = profile[..window].iter().sum();
+    let mut scores = vec![score as f64];
+    for start in 1..=profile.len() - window {
+        score = score + profile[start + window - 1] - profile[start - 1];
+        scores.push(score as f64);
+    }
+    best_start(&scores)
+}
+
+fn best_by_entropy(luma: &GrayImage, horizontal: bool, window: u32) -> u32 {
+    let (width, height) = luma.dimensions();
+    let (len, across) = if horizontal {
+        (width, height)
+    } else {
+        (height, width)
+    };
+    let line = |index: u32| {
+        (0..across).map(move |other| {
+            let (x, y) = if horizontal {
+                (index, other)
+            } else {
+                (other, index)
+            };
+            luma.get_pixel(x, y)[0] as usize
+        })
+    };
+    let mut histogram = [0u64; 256];
+    for index in 0..window {
+        for value in line(index) {
+            histogram[value] += 1;
+        }
+    }
+    let total = f64::from(window) * f64::from(across);
+    let mut scores = vec![entropy(&histogram, total)];
+    for start in 1..=len - window {
+        for value in line(start + window - 1) {
+            histogram[value] += 1;
+        }
+        for value in line(start - 1) {
+            histogram[value] -= 1;
+        }
+        scores.push(entropy(&histogram, total));
+    }
+    best_start(&scores)
+}
+
+fn entropy(histogram: &[u64; 256], total: f64) -> f64 {
+    histogram
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// The highest-scoring window start; ties go to the one nearest the center
+/// so featureless images crop like a centered cover resize.
+fn best_start(scores: &[f64]) -> u32 {
+    let center = (scores.len() - 1) as f64 / 2.0;
+    let mut best = 0;
+    for (start, score) in scores.iter().enumerate() {
+        let closer = (start as f64 - center).abs() < (best as f64 - center).abs();
+        if *score > scores[best] + 1e-9 || ((*score - scores[best]).abs() <= 1e-9 && closer) {
+            best = start;
+        }
+    }
+    best as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+    use crate::stages::from_json;
+
+    #[test]
+    fn window_follows_detail_and_centers_on_flat_images() {
+        // A flat 400x100 image with a checkered patch near the right edge.
+        let mut image = RgbaImage::from_pixel(400, 100, Rgba([90, 90, 90, 255]));
+        for y in 20..80 {
+            for x in 300..360 {
+                let shade = if (x / 4 + y / 4) % 2 == 0 { 20 } else { 230 };
+                image.put_pixel(x, y, Rgba([shade, shade, shade, 255]));
+            }
+        }
+        let image = DynamicImage::ImageRgba8(image);
+        for strategy in ["edges", "entropy"] {
+            let square = from_json(
+                SmartCropStage::from_params,
+                json!({ "width": 50, "height": 50, "strategy": strategy }),
+            )
+            .unwrap();
+            let (x, y, width, height) = square.window(&image);
+            assert_eq!((y, width, height), (0, 100, 100), "{strategy}");
+            assert!((260..=300).contains(&x), "{strategy} picked x = {x}");
+            assert_eq!(square.crop(&image, (x, y, width, height)).width(), 50);
+        }
+
+        let flat = DynamicImage::ImageRgba8(RgbaImage::from_pixel(100, 300, Rgba([0; 4])));
+        let square = from_json(
+            SmartCropStage::from_params,
+            json!({ "width": 1, "height": 1 }),
+        )
+        .unwrap();
+        let (x, y, width, height) = square.window(&flat);
+        assert_eq!((x, width, height), (0, 100, 100));
+        assert!(y.abs_diff(100) <= 1, "flat image cropped at y = {y}");
+        assert_eq!(window_size(640, 480, 16, 9), (640, 360));
+
+        assert!(from_json(SmartCropStage::from_params, json!({ "width": 10 })).is_err());
+        assert!(
+            from_json(
+                SmartCropStage::from_params,
+                json!({ "width": 10, "height": 10, "strategy": "faces" })
+            )
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,259 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail};
+use image::imageops::{self, FilterType as ResizeFilter};
+use image::{DynamicImage, Rgba, RgbaImage};
+use serde_json::{Value, json};
+
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+
+use super::video_thumbnail::{frame_at, stream_duration};
+use super::{map_filter, parse_color, record_dimensions, take_bool, take_string, take_u32};
+
+const DEFAULT_COUNT: u32 = 12;
+const DEFAULT_COLUMNS: u32 = 4;
+const DEFAULT_TILE_WIDTH: u32 = 320;
+
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+
+/// Rows of the 5x7 bitmap for a timestamp character, leftmost pixel in the
+/// highest of the five bits.
+fn glyph(character: char) -> Option<[u8; GLYPH_HEIGHT as usize]> {
+    Some(match character {
+        '0' => [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e],
+        '1' => [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e],
+        '2' => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f],
+        '3' => [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e],
+        '4' => [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02],
+        '5' => [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e],
+        '6' => [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e],
+        '7' => [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e],
+        '9' => [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c],
+        ':' => [0x00, 0x0c, 0x0c, 0x00, 0x0c, 0x0c, 0x00],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c],
+        _ => return None,
+    })
+}
+
+/// Samples `count` frames evenly across the decoded video, at the middle of
+/// each equal share of its duration, and lays them out as a grid that
+/// replaces the working image, so `encode` writes the contact sheet. Each
+/// tile carries its timestamp in the corner unless `timestamps` is false.
+/// The decoded video is left as it is for later stages.
+pub struct StoryboardStage {
+    count: u32,
+    columns: u32,
+    tile_width: u32,
+    gutter: u32,
+    background: Rgba<u8>,
+    timestamps: bool,
+    filter: ResizeFilter,
+}
+
+impl StoryboardStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let positive = |value: Option<u32>, name: &str, default: u32| match value {
+            Some(0) => bail!("storyboard {name} must be positive"),
+            Some(value) => Ok(value),
+            None => Ok(default),
+        };
+        let count = positive(take_u32(&mut params, "count"), "count", DEFAULT_COUNT)?;
+        let columns = positive(take_u32(&mut params, "columns"), "columns", DEFAULT_COLUMNS)?;
+        let tile_width = positive(take_u32(&mut params, "width"), "width", DEFAULT_TILE_WIDTH)?;
+        let gutter = take_u32(&mut params, "gutter").unwrap_or(4);
+        let background = match take_string(&mut params, "background") {
+            Some(color) => parse_color(&color).context("Invalid storyboard background")?,
+            None => Rgba([0, 0, 0, 255]),
+        };
+        let timestamps = take_bool(&mut params, "timestamps")?.unwrap_or(true);
+        let filter = match take_string(&mut params, "method") {
+            Some(method) => map_filter(method.clone())
+                .ok_or_else(|| anyhow!("unknown storyboard method '{method}'"))?,
+            None => ResizeFilter::Triangle,
+        };
+        Ok(Self {
+            count,
+            columns,
+            tile_width,
+            gutter,
+            background,
+            timestamps,
+            filter,
+        })
+    }
+
+    /// Sample times for a video lasting `duration`.
+    fn times(&self, count: u32, duration: Duration) -> Vec<Duration> {
+        (0..count)
+            .map(|index| duration.mul_f64((f64::from(index) + 0.5) / f64::from(count)))
+            .collect()
+    }
+}
+
+impl Stage for StoryboardStage {
+    fn name(&self) -> &'static str {
+        "storyboard"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let video = artifact
+            .media()
+            .video
+            .as_ref()
+            .filter(|video| !video.frames.is_empty())
+            .ok_or_else(|| anyhow!("storyboard stage requires decoded video frames"))?;
+        // A short clip gets one tile per frame rather than repeats.
+        let count = self
+            .count
+            .min(u32::try_from(video.frames.len()).unwrap_or(u32::MAX));
+        let columns = self.columns.min(count);
+        let rows = count.div_ceil(columns);
+        let first = &video.frames[0];
+        let tile_height = ((u64::from(self.tile_width) * u64::from(first.height)
+            + u64::from(first.width) / 2)
+            / u64::from(first.width).max(1))
+        .max(1) as u32;
+        let width = columns * self.tile_width + (columns + 1) * self.gutter;
+        let height = rows * tile_height + (rows + 1) * self.gutter;
+        let mut sheet = RgbaImage::from_pixel(width, height, self.background);
+
+        let mut sampled = Vec::with_capacity(count as usize);
+        for (index, time) in (0u32..).zip(self.times(count, stream_duration(video))) {
+            ctx.cancellation.check()?;
+            let frame_index = frame_at(video, time);
+            let frame = &video.frames[frame_index];
+            let rgb = frame
+                .to_rgb(video.color_space)
+                .with_context(|| format!("Failed to convert frame {frame_index} to RGB"))?;
+            let mut tile = DynamicImage::ImageRgb8(rgb)
+                .resize_exact(self.tile_width, tile_height, self.filter)
+                .into_rgba8();
+            if self.timestamps {
+                draw_label(&mut tile, &format_timestamp(frame.timestamp));
+            }
+            let x = self.gutter + (index % columns) * (self.tile_width + self.gutter);
+            let y = self.gutter + (index / columns) * (tile_height + self.gutter);
+            imageops::overlay(&mut sheet, &tile, i64::from(x), i64::from(y));
+            sampled.push(json!({
+                "frame": frame_index,
+                "time": frame.timestamp.as_secs_f64(),
+            }));
+        }
+
+        artifact
+            .metadata
+            .insert("storyboard.columns".to_string(), json!(columns));
+        artifact
+            .metadata
+            .insert("storyboard.rows".to_string(), json!(rows));
+        artifact
+            .metadata
+            .insert("storyboard.frames".to_string(), Value::Array(sampled));
+        let sheet = DynamicImage::ImageRgba8(sheet);
+        record_dimensions(artifact, "image", &sheet);
+        artifact.set_image(sheet);
+        Ok(())
+    }
+}
+
+/// `m:ss.mmm`, with hours in front once the video runs that long.
+fn format_timestamp(time: Duration) -> String {
+    let millis = time.as_millis();
+    let (hours, minutes, seconds) = (millis / 3_600_000, millis / 60_000 % 60, millis / 1000 % 60);
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{seconds:02}.{:03}", millis % 1000)
+    } else {
+        format!("{minutes}:{seconds:02}.{:03}", millis % 1000)
+    }
+}
+
+/// Burns `text` into the bottom-left corner of `tile`, white on a
+/// translucent black box, scaling the glyphs with the tile height.
+fn draw_label(tile: &mut RgbaImage, text: &str) {
+    let scale = (tile.height() / 60).max(1);
+    let margin = 2 * scale;
+    let characters = text.chars().count() as u32;
+    let box_width = characters * (GLYPH_WIDTH + 1) * scale + margin;
+    let box_height = GLYPH_HEIGHT * scale + 2 * margin;
+    let left = margin.min(tile.width().saturating_sub(box_width));
+    let top = tile.height().saturating_sub(box_height + margin);
+    for y in top..(top + box_height).min(tile.height()) {
+        for x in left..(left + box_width).min(tile.width()) {
+            let pixel = tile.get_pixel_mut(x, y);
+            for channel in &mut pixel.0[..3] {
+                *channel = (u16::from(*channel) * 2 / 5) as u8;
+            }
+        }
+    }
+    for (position, character) in (0u32..).zip(text.chars()) {
+        let Some(rows) = glyph(character) else {
+            continue;
+        };
+        let origin_x = left + margin + position * (GLYPH_WIDTH + 1) * scale;
+        let origin_y = top + margin;
+        for (row, bits) in (0u32..).zip(rows) {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (0x10 >> column) == 0 {
+                    continue;
+                }
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        let (x, y) = (origin_x + column * scale + dx, origin_y + row * scale + dy);
+                        if x < tile.width() && y < tile.height() {
+                            tile.put_pixel(x, y, Rgba([255, 255, 255, 255]));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stages::from_json;
+
+    #[test]
+    fn samples_the_middle_of_equal_shares() {
+        let storyboard = from_json(StoryboardStage::from_params, json!({ "count": 4 })).unwrap();
+        assert_eq!(
+            storyboard.times(4, Duration::from_secs(8)),
+            [1, 3, 5, 7].map(Duration::from_secs)
+        );
+        assert!(from_json(StoryboardStage::from_params, json!({ "count": 0 })).is_err());
+        assert!(from_json(StoryboardStage::from_params, json!({ "columns": 0 })).is_err());
+        assert!(from_json(StoryboardStage::from_params, json!({ "method": "bicubic" })).is_err());
+        assert!(from_json(StoryboardStage::from_params, json!({ "background": "#zz" })).is_err());
+    }
+
+    #[test]
+    fn formats_and_draws_timestamps() {
+        assert_eq!(format_timestamp(Duration::from_millis(83_250)), "1:23.250");
+        assert_eq!(
+            format_timestamp(Duration::from_millis(3_723_004)),
+            "1:02:03.004"
+        );
+        assert!("0123456789:.".chars().all(|c| glyph(c).is_some()));
+
+        let mut tile = RgbaImage::from_pixel(64, 36, Rgba([100, 100, 100, 255]));
+        draw_label(&mut tile, "1:00");
+        // The box darkens the corner, the glyphs are white, the rest is
+        // untouched.
+        assert_eq!(tile.get_pixel(2, 33).0, [40, 40, 40, 255]);
+        assert!(tile.pixels().any(|pixel| pixel.0 == [255, 255, 255, 255]));
+        assert_eq!(tile.get_pixel(63, 0).0, [100, 100, 100, 255]);
+    }
+}
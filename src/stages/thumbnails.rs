@@ -0,0 +1,352 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow, bail};
+use image::imageops::FilterType as ResizeFilter;
+use image::{DynamicImage, ImageFormat};
+use serde_json::{Map, Value, json};
+use tracing::info;
+
+use crate::pipeline::{Artifact, OutputSpec, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+use crate::sink::{FileSink, OutputSink};
+use crate::structure;
+
+use super::{
+    encode_cursor, encode_with_options, format_extension, infer_format, map_filter, take_string,
+    value_as_u64,
+};
+
+const DEFAULT_STRUCTURE: &str = "{stem}-{size}.{ext}";
+
+/// Metadata key listing the thumbnails written for an input.
+pub const THUMBNAILS_KEY: &str = "thumbnails";
+
+#[derive(Clone, Debug, PartialEq)]
+struct Thumbnail {
+    /// Longest edge in pixels.
+    size: u32,
+    structure: String,
+}
+
+/// Writes a downscaled copy of the image per entry in `sizes`, each fitted
+/// inside a `size` x `size` box and named by its own output structure, so
+/// one decode feeds every size. Images are never enlarged, and animations
+/// get a still thumbnail of their first frame. The working image is left as
+/// it is for later stages.
+pub struct ThumbnailsStage {
+    thumbnails: Vec<Thumbnail>,
+    format: Option<String>,
+    extension: Option<String>,
+    filter: ResizeFilter,
+    /// Encoder options (`quality`, `lossless`, ...) shared by every size.
+    options: StageParameters,
+}
+
+impl ThumbnailsStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let default_structure =
+            take_string(&mut params, "structure").unwrap_or_else(|| DEFAULT_STRUCTURE.to_string());
+        let sizes = match params.remove("sizes") {
+            Some(Value::Array(sizes)) if !sizes.is_empty() => sizes,
+            _ => bail!("thumbnails stage requires a non-empty 'sizes' list"),
+        };
+        let mut thumbnails = Vec::with_capacity(sizes.len());
+        for entry in &sizes {
+            let thumbnail = parse_thumbnail(entry, &default_structure)?;
+            structure::validate(&thumbnail.structure).with_context(|| {
+                format!("Invalid thumbnail structure '{}'", thumbnail.structure)
+            })?;
+            if thumbnails.contains(&thumbnail) {
+                bail!(
+                    "thumbnail size {} is listed twice with structure '{}'",
+                    thumbnail.size,
+                    thumbnail.structure
+                );
+            }
+            thumbnails.push(thumbnail);
+        }
+        let format = take_string(&mut params, "format");
+        let extension = take_string(&mut params, "extension");
+        let filter = take_string(&mut params, "method")
+            .and_then(map_filter)
+            .unwrap_or(ResizeFilter::CatmullRom);
+        Ok(Self {
+            thumbnails,
+            format,
+            extension,
+            filter,
+            options: params,
+        })
+    }
+
+    /// Renders the path of `thumbnail`; `{size}`, `{width}` and `{height}`
+    /// describe the thumbnail rather than the working image.
+    fn output_path(
+        &self,
+        artifact: &Artifact,
+        ctx: &PipelineContext,
+        thumbnail: &Thumbnail,
+        dimensions: Option<(u32, u32)>,
+        extension: &str,
+    ) -> Result<PathBuf> {
+        let mut fields = Map::new();
+        fields.insert(
+            "size".to_string(),
+            Value::String(thumbnail.size.to_string()),
+        );
+        side_image_path(
+            artifact,
+            ctx,
+            &thumbnail.structure,
+            extension,
+            fields,
+            dimensions,
+        )
+    }
+
+    fn extension(&self, artifact: &Artifact) -> Result<(image::ImageFormat, String)> {
+        let (format, _) = infer_format(self.format.as_deref(), artifact)?;
+        let extension = self
+            .extension
+            .clone()
+            .unwrap_or_else(|| format_extension(format).to_string());
+        Ok((format, extension))
+    }
+}
+
+impl Stage for ThumbnailsStage {
+    fn name(&self) -> &'static str {
+        "thumbnails"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    /// A cache replay restores the main output only, not the thumbnails.
+    fn cacheable(&self) -> bool {
+        false
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let image = artifact
+            .image
+            .clone()
+            .ok_or_else(|| anyhow!("thumbnails stage requires a decoded image"))?;
+        let (format, extension) = self.extension(artifact)?;
+        let mut written = Vec::with_capacity(self.thumbnails.len());
+        for thumbnail in &self.thumbnails {
+            ctx.cancellation.check()?;
+            let (width, height) = fit_inside(image.width(), image.height(), thumbnail.size);
+            let path =
+                self.output_path(artifact, ctx, thumbnail, Some((width, height)), &extension)?;
+            let mut entry = json!({
+                "size": thumbnail.size,
+                "width": width,
+                "height": height,
+                "path": path.to_string_lossy(),
+            });
+            if let Some(reason) = ctx.overwrite.keep_reason(&artifact.input_path, &path) {
+                info!(output = %path.display(), reason, "Keeping existing thumbnail");
+                entry["skipped"] = Value::String(reason.to_string());
+                written.push(entry);
+                continue;
+            }
+
+            let resized;
+            let scaled: &DynamicImage = if (width, height) == (image.width(), image.height()) {
+                &image
+            } else {
+                resized = image.resize_exact(width, height, self.filter);
+                &resized
+            };
+            write_side_image(&path, scaled, format, &self.options, &mut entry)
+                .context("Failed to write thumbnail")?;
+            written.push(entry);
+        }
+        artifact
+            .metadata
+            .insert(THUMBNAILS_KEY.to_string(), Value::Array(written));
+        Ok(())
+    }
+
+    fn plan(&self, artifact: &mut Artifact, ctx: &PipelineContext) -> Result<()> {
+        let dimension = |key: &str| {
+            artifact
+                .metadata
+                .get(key)
+                .and_then(value_as_u64)
+                .and_then(|value| u32::try_from(value).ok())
+        };
+        let source = dimension("image.width").zip(dimension("image.height"));
+        let (_, extension) = self.extension(artifact)?;
+        let mut planned = Vec::with_capacity(self.thumbnails.len());
+        for thumbnail in &self.thumbnails {
+            let dimensions =
+                source.map(|(width, height)| fit_inside(width, height, thumbnail.size));
+            let path = self.output_path(artifact, ctx, thumbnail, dimensions, &extension)?;
+            let mut entry = json!({
+                "size": thumbnail.size,
+                "path": path.to_string_lossy(),
+            });
+            if let Some((width, height)) = dimensions {
+                entry["width"] = json!(width);
+                entry["height"] = json!(height);
+            }
+            if let Some(reason) = ctx.overwrite.keep_reason(&artifact.input_path, &path) {
+                entry["skipped"] = Value::String(reason.to_string());
+            }
+            planned.push(entry);
+        }
+        artifact
+            .metadata
+            .insert(THUMBNAILS_KEY.to_string(), Value::Array(planned));
+        Ok(())
+    }
+}
+
+/// Resolves `structure` for an image written next to the main output, and
+/// claims it. `fields` are the image's own placeholders (`{size}`,
+/// `{index}`, ...) and `dimensions` its `{width}` and `{height}`, both over
+/// the artifact's metadata.
+pub(super) fn side_image_path(
+    artifact: &Artifact,
+    ctx: &PipelineContext,
+    structure: &str,
+    extension: &str,
+    fields: Map<String, Value>,
+    dimensions: Option<(u32, u32)>,
+) -> Result<PathBuf> {
+    let mut metadata = artifact.metadata.clone();
+    metadata.extend(fields);
+    if let Some((width, height)) = dimensions {
+        metadata.insert("image.width".to_string(), json!(width));
+        metadata.insert("image.height".to_string(), json!(height));
+    }
+    let spec = OutputSpec {
+        directory: ctx.output.directory.clone(),
+        structure: structure.to_string(),
+    };
+    ctx.outputs.claim_side_output(
+        spec.resolve(&artifact.stem, extension, &metadata),
+        &artifact.input_path,
+    )
+}
+
+/// Encodes `image` as `format` and writes it to `path`, adding the written
+/// `size_bytes` and `sha256` to its metadata `entry`.
+pub(super) fn write_side_image(
+    path: &Path,
+    image: &DynamicImage,
+    format: ImageFormat,
+    options: &StageParameters,
+    entry: &mut Value,
+) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create output directory: {}", parent.display()))?;
+    }
+    let mut cursor = encode_cursor(image);
+    encode_with_options(image, format, options, &mut cursor)
+        .with_context(|| format!("Failed to encode {format:?}"))?;
+    let mut sink = FileSink::create(path)?;
+    sink.write_all(cursor.get_ref())
+        .with_context(|| format!("Failed to write output file: {}", path.display()))?;
+    let summary = sink.finish()?;
+    entry["size_bytes"] = json!(summary.size_bytes);
+    entry["sha256"] = Value::String(summary.sha256);
+    Ok(())
+}
+
+/// A bare number, or `{ size, structure }` to name that size differently.
+fn parse_thumbnail(entry: &Value, default_structure: &str) -> Result<Thumbnail> {
+    let (size, structure) = match entry {
+        Value::Object(fields) => (
+            fields.get("size"),
+            match fields.get("structure") {
+                Some(Value::String(structure)) => structure.clone(),
+                Some(other) => bail!("thumbnail structure must be a string, got {other}"),
+                None => default_structure.to_string(),
+            },
+        ),
+        other => (Some(other), default_structure.to_string()),
+    };
+    let size = size
+        .and_then(value_as_u64)
+        .and_then(|size| u32::try_from(size).ok())
+        .filter(|size| *size > 0)
+        .ok_or_else(|| {
+            anyhow!("thumbnail size must be a positive number of pixels, got {entry}")
+        })?;
+    Ok(Thumbnail { size, structure })
+}
+
+/// Dimensions of a `width` x `height` image scaled down so its longest edge
+/// is at most `size`, keeping the aspect ratio.
+fn fit_inside(width: u32, height: u32, size: u32) -> (u32, u32) {
+    let longest = width.max(height);
+    if longest <= size {
+        return (width, height);
+    }
+    let scale = |edge: u32| {
+        ((u64::from(edge) * u64::from(size) + u64::from(longest) / 2) / u64::from(longest)).max(1)
+            as u32
+    };
+    (scale(width), scale(height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stages::from_json;
+
+    #[test]
+    fn parses_sizes_and_fits_longest_edge() {
+        let thumbnails = from_json(
+            ThumbnailsStage::from_params,
+            json!({
+                "sizes": [512, { "size": 128, "structure": "icons/{stem}.{ext}" }],
+                "quality": 80,
+            }),
+        )
+        .unwrap();
+        assert_eq!(
+            thumbnails.thumbnails,
+            vec![
+                Thumbnail {
+                    size: 512,
+                    structure: DEFAULT_STRUCTURE.to_string()
+                },
+                Thumbnail {
+                    size: 128,
+                    structure: "icons/{stem}.{ext}".to_string()
+                },
+            ]
+        );
+        assert!(thumbnails.options.contains_key("quality"));
+
+        assert_eq!(fit_inside(1920, 1080, 512), (512, 288));
+        assert_eq!(fit_inside(1080, 1920, 128), (72, 128));
+        assert_eq!(fit_inside(300, 200, 512), (300, 200));
+        assert_eq!(fit_inside(4000, 1, 100), (100, 1));
+
+        assert!(from_json(ThumbnailsStage::from_params, json!({})).is_err());
+        assert!(from_json(ThumbnailsStage::from_params, json!({ "sizes": [] })).is_err());
+        assert!(from_json(ThumbnailsStage::from_params, json!({ "sizes": [0] })).is_err());
+        assert!(from_json(ThumbnailsStage::from_params, json!({ "sizes": [128, 128] })).is_err());
+        assert!(
+            from_json(
+                ThumbnailsStage::from_params,
+                json!({ "sizes": [128], "structure": "{hash:99}" })
+            )
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! Pages of multi-page TIFFs, which the `image` crate reads only the first
+//! of.
+
+use std::io::{Cursor, Write};
+
+use anyhow::{Context, Result, anyhow, bail};
+use image::{DynamicImage, ImageBuffer, RgbaImage};
+use tiff::ColorType as TiffColorType;
+use tiff::decoder::{Decoder as TiffDecoder, DecodingResult};
+use tiff::encoder::{TiffEncoder, colortype};
+
+use crate::pipeline::AnimationFrame;
+
+fn decoder(data: &[u8]) -> Result<TiffDecoder<Cursor<&[u8]>>> {
+    TiffDecoder::new(Cursor::new(data)).context("Failed to read TIFF")
+}
+
+/// Number of images (IFDs) in the file.
+pub(super) fn page_count(data: &[u8]) -> Result<usize> {
+    let mut decoder = decoder(data)?;
+    let mut count = 1;
+    while decoder.more_images() {
+        decoder.next_image().context("Failed to read TIFF page")?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Width and height of every page, in order.
+pub(super) fn dimensions(data: &[u8]) -> Result<Vec<(u32, u32)>> {
+    let mut decoder = decoder(data)?;
+    let mut dimensions = vec![decoder.dimensions()?];
+    while decoder.more_images() {
+        decoder.next_image().context("Failed to read TIFF page")?;
+        dimensions.push(decoder.dimensions()?);
+    }
+    Ok(dimensions)
+}
+
+/// Decodes the zero-based page `index`. The first page goes through the
+/// `image` crate like any still TIFF; later pages support gray, gray+alpha,
+/// RGB and RGBA samples of 8 or 16 bits and 32-bit floats.
+pub(super) fn decode_page(data: &[u8], index: usize) -> Result<DynamicImage> {
+    if index == 0 {
+        return image::load_from_memory_with_format(data, image::ImageFormat::Tiff)
+            .context("Failed to decode image as Tiff");
+    }
+    let mut decoder = decoder(data)?;
+    decoder
+        .seek_to_image(index)
+        .with_context(|| format!("Failed to find TIFF page {}", index + 1))?;
+    let (width, height) = decoder.dimensions()?;
+    let color = decoder.colortype()?;
+    let pixels = decoder
+        .read_image()
+        .with_context(|| format!("Failed to decode TIFF page {}", index + 1))?;
+    let malformed = || anyhow!("TIFF page {} has a malformed pixel buffer", index + 1);
+    let image = match (color, pixels) {
+        (TiffColorType::Gray(8), DecodingResult::U8(data)) => DynamicImage::ImageLuma8(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(malformed)?,
+        ),
+        (TiffColorType::Gray(16), DecodingResult::U16(data)) => DynamicImage::ImageLuma16(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(malformed)?,
+        ),
+        (TiffColorType::GrayA(8), DecodingResult::U8(data)) => DynamicImage::ImageLumaA8(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(malformed)?,
+        ),
+        (TiffColorType::GrayA(16), DecodingResult::U16(data)) => DynamicImage::ImageLumaA16(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(malformed)?,
+        ),
+        (TiffColorType::RGB(8), DecodingResult::U8(data)) => DynamicImage::ImageRgb8(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(malformed)?,
+        ),
+        (TiffColorType::RGB(16), DecodingResult::U16(data)) => DynamicImage::ImageRgb16(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(malformed)?,
+        ),
+        (TiffColorType::RGB(32), DecodingResult::F32(data)) => DynamicImage::ImageRgb32F(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(malformed)?,
+        ),
+        (TiffColorType::RGBA(8), DecodingResult::U8(data)) => DynamicImage::ImageRgba8(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(malformed)?,
+        ),
+        (TiffColorType::RGBA(16), DecodingResult::U16(data)) => DynamicImage::ImageRgba16(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(malformed)?,
+        ),
+        (TiffColorType::RGBA(32), DecodingResult::F32(data)) => DynamicImage::ImageRgba32F(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(malformed)?,
+        ),
+        (color, _) => bail!("Cannot decode {color:?} pixels of TIFF page {}", index + 1),
+    };
+    Ok(image)
+}
+
+/// Every page as a frame, for `frames: all`; empty for single-page files so
+/// they decode as stills.
+pub(super) fn decode_frames(data: &[u8]) -> Result<Vec<AnimationFrame>> {
+    let count = page_count(data)?;
+    if count < 2 {
+        return Ok(Vec::new());
+    }
+    (0..count)
+        .map(|index| {
+            Ok(AnimationFrame {
+                image: decode_page(data, index)?.into_rgba8(),
+                delay_ms: 0,
+            })
+        })
+        .collect()
+}
+
+/// Writes every frame as a page of one TIFF; opaque pages are stored as
+/// RGB.
+pub(super) fn encode(frames: &[AnimationFrame], out: &mut dyn Write) -> Result<()> {
+    let mut buffer = Cursor::new(Vec::new());
+    let mut encoder = TiffEncoder::new(&mut buffer).context("Failed to start TIFF")?;
+    for (index, frame) in frames.iter().enumerate() {
+        let (width, height) = frame.image.dimensions();
+        let written = if is_opaque(&frame.image) {
+            let rgb = DynamicImage::ImageRgba8(frame.image.clone()).into_rgb8();
+            encoder.write_image::<colortype::RGB8>(width, height, rgb.as_raw())
+        } else {
+            encoder.write_image::<colortype::RGBA8>(width, height, frame.image.as_raw())
+        };
+        written.with_context(|| format!("Failed to write TIFF page {}", index + 1))?;
+    }
+    out.write_all(buffer.get_ref())
+        .context("Failed to write TIFF")?;
+    Ok(())
+}
+
+fn is_opaque(image: &RgbaImage) -> bool {
+    image.pixels().all(|pixel| pixel.0[3] == u8::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    #[test]
+    fn pages_round_trip_through_a_multi_page_tiff() {
+        let frames: Vec<AnimationFrame> = [(8, 4, [200, 10, 10, 255]), (3, 6, [0, 0, 250, 128])]
+            .into_iter()
+            .map(|(width, height, color)| AnimationFrame {
+                image: RgbaImage::from_pixel(width, height, Rgba(color)),
+                delay_ms: 0,
+            })
+            .collect();
+        let mut tiff = Vec::new();
+        encode(&frames, &mut tiff).unwrap();
+
+        assert_eq!(page_count(&tiff).unwrap(), 2);
+        assert_eq!(dimensions(&tiff).unwrap(), vec![(8, 4), (3, 6)]);
+        let first = decode_page(&tiff, 0).unwrap();
+        assert!(matches!(first, DynamicImage::ImageRgb8(_)));
+        let second = decode_page(&tiff, 1).unwrap().into_rgba8();
+        assert_eq!(second, frames[1].image);
+        let decoded = decode_frames(&tiff).unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].image, frames[0].image);
+        assert!(decode_page(&tiff, 2).is_err());
+    }
+}
@@ -0,0 +1,484 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow, bail};
+use image::imageops::FilterType as ResizeFilter;
+use image::{DynamicImage, ImageFormat};
+use serde_json::{Value, json};
+use tracing::info;
+
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+use crate::sink::{FileSink, OutputSink};
+
+use super::{
+    encode_cursor, encode_with_options, format_extension, format_from_label, keep_existing_output,
+    map_filter, take_string, take_u32, value_as_u64,
+};
+
+const DZI_TILE_SIZE: u32 = 254;
+const DZI_OVERLAP: u32 = 1;
+const IIIF_TILE_SIZE: u32 = 512;
+const IIIF_CONTEXT: &str = "http://iiif.io/api/image/3/context.json";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Layout {
+    /// Deep Zoom: `{stem}.dzi` next to `{stem}_files/{level}/{column}_{row}.{ext}`.
+    Dzi,
+    /// IIIF Image API 3.0 level 0: `{stem}/info.json` next to
+    /// `{stem}/{region}/{width},{height}/0/default.{ext}`.
+    Iiif,
+}
+
+impl Layout {
+    fn from_str(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "dzi" | "deepzoom" | "deep_zoom" => Some(Self::Dzi),
+            "iiif" => Some(Self::Iiif),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Dzi => "dzi",
+            Self::Iiif => "iiif",
+        }
+    }
+}
+
+/// One level of the pyramid: the image scaled down by `scale` (a power of
+/// two), cut into tiles.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Level {
+    /// DZI level number; the full-size image is the highest.
+    index: u32,
+    scale: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Level {
+    fn grid(&self, tile_size: u32) -> (u32, u32) {
+        (
+            self.width.div_ceil(tile_size),
+            self.height.div_ceil(tile_size),
+        )
+    }
+}
+
+/// Where the pyramid of one artifact goes.
+struct Target {
+    /// `.dzi` file or IIIF `info.json`; the artifact's output.
+    descriptor: PathBuf,
+    /// Directory holding the tiles.
+    tiles: PathBuf,
+}
+
+/// Slices the image into a tile pyramid for deep zoom viewers: every level
+/// halves the one above down to a single pixel (DZI) or a single tile
+/// (IIIF), and each level is cut into `tile_size` squares. The descriptor is
+/// the artifact's output; the tiles are written next to it.
+pub struct TileStage {
+    layout: Layout,
+    tile_size: u32,
+    overlap: u32,
+    format: ImageFormat,
+    filter: ResizeFilter,
+    /// Prefix of the IIIF `id`, e.g. `https://example.com/iiif`.
+    base_url: Option<String>,
+    /// Encoder options (`quality`, ...) shared by every tile.
+    options: StageParameters,
+}
+
+impl TileStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let layout = match take_string(&mut params, "layout") {
+            Some(value) => Layout::from_str(&value)
+                .ok_or_else(|| anyhow!("Unknown tile layout '{value}' (expected dzi or iiif)"))?,
+            None => Layout::Dzi,
+        };
+        let tile_size = match params.remove("tile_size") {
+            Some(value) => value_as_u64(&value)
+                .and_then(|size| u32::try_from(size).ok())
+                .filter(|size| *size > 0)
+                .ok_or_else(|| anyhow!("tile_size must be a positive number, got {value}"))?,
+            None if layout == Layout::Dzi => DZI_TILE_SIZE,
+            None => IIIF_TILE_SIZE,
+        };
+        let overlap = match (layout, take_u32(&mut params, "overlap")) {
+            (Layout::Dzi, Some(overlap)) if overlap >= tile_size => {
+                bail!("tile overlap {overlap} must be smaller than tile_size {tile_size}")
+            }
+            (Layout::Dzi, overlap) => overlap.unwrap_or(DZI_OVERLAP),
+            (Layout::Iiif, None | Some(0)) => 0,
+            (Layout::Iiif, Some(_)) => bail!("IIIF tiles do not overlap; drop 'overlap'"),
+        };
+        let format = match take_string(&mut params, "format") {
+            Some(label) => {
+                format_from_label(&label).ok_or_else(|| anyhow!("Unknown tile format '{label}'"))?
+            }
+            None => ImageFormat::Jpeg,
+        };
+        let filter = take_string(&mut params, "method")
+            .and_then(map_filter)
+            .unwrap_or(ResizeFilter::Triangle);
+        let base_url =
+            take_string(&mut params, "base_url").map(|url| url.trim_end_matches('/').to_string());
+        if base_url.is_some() && layout != Layout::Iiif {
+            bail!("tile base_url only applies to layout: iiif");
+        }
+        Ok(Self {
+            layout,
+            tile_size,
+            overlap,
+            format,
+            filter,
+            base_url,
+            options: params,
+        })
+    }
+
+    fn extension(&self) -> &'static str {
+        format_extension(self.format)
+    }
+
+    /// Every level, smallest first.
+    fn levels(&self, width: u32, height: u32) -> Vec<Level> {
+        let mut levels = vec![Level {
+            index: 0,
+            scale: 1,
+            width,
+            height,
+        }];
+        loop {
+            let last = levels[levels.len() - 1];
+            let done = match self.layout {
+                Layout::Dzi => last.width == 1 && last.height == 1,
+                Layout::Iiif => last.width <= self.tile_size && last.height <= self.tile_size,
+            };
+            if done {
+                break;
+            }
+            levels.push(Level {
+                index: 0,
+                scale: last.scale * 2,
+                width: last.width.div_ceil(2),
+                height: last.height.div_ceil(2),
+            });
+        }
+        levels.reverse();
+        for (index, level) in (0u32..).zip(&mut levels) {
+            level.index = index;
+        }
+        levels
+    }
+
+    fn target(&self, artifact: &mut Artifact, ctx: &PipelineContext) -> Result<Target> {
+        let extension = match self.layout {
+            Layout::Dzi => "dzi",
+            Layout::Iiif => "json",
+        };
+        let resolved = ctx.outputs.claim(
+            ctx.output
+                .resolve(&artifact.stem, extension, &artifact.metadata),
+            &artifact.input_path,
+            &mut artifact.metadata,
+        )?;
+        let stem = resolved
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_else(|| artifact.stem.clone());
+        let parent = resolved.parent().map(Path::to_path_buf).unwrap_or_default();
+        Ok(match self.layout {
+            Layout::Dzi => Target {
+                tiles: parent.join(format!("{stem}_files")),
+                descriptor: resolved,
+            },
+            Layout::Iiif => {
+                let tiles = parent.join(&stem);
+                Target {
+                    descriptor: tiles.join("info.json"),
+                    tiles,
+                }
+            }
+        })
+    }
+
+    /// Pixel rectangle of tile (`column`, `row`) within `level`, widened by
+    /// the overlap on every side that has a neighbour.
+    fn tile_rect(&self, level: &Level, column: u32, row: u32) -> (u32, u32, u32, u32) {
+        let x = column * self.tile_size;
+        let y = row * self.tile_size;
+        let left = x.saturating_sub(self.overlap);
+        let top = y.saturating_sub(self.overlap);
+        let right = (x + self.tile_size + self.overlap).min(level.width);
+        let bottom = (y + self.tile_size + self.overlap).min(level.height);
+        (left, top, right - left, bottom - top)
+    }
+
+    /// Path of tile (`column`, `row`) of `level`, relative to the tile
+    /// directory.
+    fn tile_path(&self, level: &Level, column: u32, row: u32, full: (u32, u32)) -> PathBuf {
+        let extension = self.extension();
+        match self.layout {
+            Layout::Dzi => {
+                PathBuf::from(level.index.to_string()).join(format!("{column}_{row}.{extension}"))
+            }
+            Layout::Iiif => {
+                let (x, y, width, height) = self.tile_rect(level, column, row);
+                // Regions are given in full-size pixels.
+                let region_x = x * level.scale;
+                let region_y = y * level.scale;
+                let region_width = (width * level.scale).min(full.0 - region_x);
+                let region_height = (height * level.scale).min(full.1 - region_y);
+                let region = if (region_x, region_y, region_width, region_height)
+                    == (0, 0, full.0, full.1)
+                {
+                    "full".to_string()
+                } else {
+                    format!("{region_x},{region_y},{region_width},{region_height}")
+                };
+                PathBuf::from(region)
+                    .join(format!("{width},{height}"))
+                    .join("0")
+                    .join(format!("default.{extension}"))
+            }
+        }
+    }
+
+    fn descriptor(&self, target: &Target, width: u32, height: u32, levels: &[Level]) -> Vec<u8> {
+        match self.layout {
+            Layout::Dzi => format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                 <Image xmlns=\"http://schemas.microsoft.com/deepzoom/2008\" \
+                 Format=\"{}\" Overlap=\"{}\" TileSize=\"{}\">\n  \
+                 <Size Width=\"{width}\" Height=\"{height}\"/>\n</Image>\n",
+                self.extension(),
+                self.overlap,
+                self.tile_size
+            )
+            .into_bytes(),
+            Layout::Iiif => {
+                let name = target
+                    .tiles
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                let id = match &self.base_url {
+                    Some(base) => format!("{base}/{name}"),
+                    None => name,
+                };
+                let scale_factors: Vec<u32> =
+                    levels.iter().rev().map(|level| level.scale).collect();
+                let sizes: Vec<Value> = levels
+                    .iter()
+                    .map(|level| json!({ "width": level.width, "height": level.height }))
+                    .collect();
+                let info = json!({
+                    "@context": IIIF_CONTEXT,
+                    "id": id,
+                    "type": "ImageService3",
+                    "protocol": "http://iiif.io/api/image",
+                    "profile": "level0",
+                    "width": width,
+                    "height": height,
+                    "sizes": sizes,
+                    "tiles": [{
+                        "width": self.tile_size,
+                        "scaleFactors": scale_factors,
+                    }],
+                    "extraFormats": [self.extension()],
+                });
+                let mut bytes = serde_json::to_vec_pretty(&info).unwrap_or_default();
+                bytes.push(b'\n');
+                bytes
+            }
+        }
+    }
+
+    fn record(&self, artifact: &mut Artifact, target: &Target, levels: &[Level], count: u64) {
+        let metadata = &mut artifact.metadata;
+        metadata.insert(
+            "output_path".to_string(),
+            Value::String(target.descriptor.to_string_lossy().to_string()),
+        );
+        metadata.insert(
+            "output.format".to_string(),
+            Value::String(self.layout.as_str().to_string()),
+        );
+        metadata.insert(
+            "tiles.directory".to_string(),
+            Value::String(target.tiles.to_string_lossy().to_string()),
+        );
+        metadata.insert("tiles.levels".to_string(), json!(levels.len()));
+        metadata.insert("tiles.count".to_string(), json!(count));
+        metadata.insert("tiles.tile_size".to_string(), json!(self.tile_size));
+        metadata.insert(
+            "tiles.format".to_string(),
+            Value::String(self.extension().to_string()),
+        );
+    }
+}
+
+fn tile_count(levels: &[Level], tile_size: u32) -> u64 {
+    levels
+        .iter()
+        .map(|level| {
+            let (columns, rows) = level.grid(tile_size);
+            u64::from(columns) * u64::from(rows)
+        })
+        .sum()
+}
+
+fn write_file(path: &Path, bytes: &[u8]) -> Result<u64> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create output directory: {}", parent.display()))?;
+    }
+    let mut sink = FileSink::create(path)?;
+    sink.write_all(bytes)
+        .with_context(|| format!("Failed to write output file: {}", path.display()))?;
+    Ok(sink.finish()?.size_bytes)
+}
+
+impl Stage for TileStage {
+    fn name(&self) -> &'static str {
+        "tile"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    /// A cache replay restores the descriptor only, not the tiles.
+    fn cacheable(&self) -> bool {
+        false
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let image = artifact
+            .image
+            .clone()
+            .ok_or_else(|| anyhow!("tile stage requires a decoded image"))?;
+        let (width, height) = (image.width(), image.height());
+        let levels = self.levels(width, height);
+        let target = self.target(artifact, ctx)?;
+        let count = tile_count(&levels, self.tile_size);
+        if keep_existing_output(artifact, ctx, &target.descriptor) {
+            self.record(artifact, &target, &levels, count);
+            return Ok(());
+        }
+
+        let mut written_bytes = 0;
+        // Largest level first, each one halving the one before.
+        let mut current: Option<DynamicImage> = None;
+        for level in levels.iter().rev() {
+            ctx.cancellation.check()?;
+            let scaled = match current.take() {
+                None => image.as_ref().clone(),
+                Some(previous) => previous.resize_exact(level.width, level.height, self.filter),
+            };
+            let (columns, rows) = level.grid(self.tile_size);
+            for row in 0..rows {
+                for column in 0..columns {
+                    let (x, y, tile_width, tile_height) = self.tile_rect(level, column, row);
+                    let tile = scaled.crop_imm(x, y, tile_width, tile_height);
+                    let mut cursor = encode_cursor(&tile);
+                    encode_with_options(&tile, self.format, &self.options, &mut cursor)
+                        .with_context(|| format!("Failed to encode tile as {:?}", self.format))?;
+                    let path =
+                        target
+                            .tiles
+                            .join(self.tile_path(level, column, row, (width, height)));
+                    written_bytes += write_file(&path, cursor.get_ref())?;
+                }
+            }
+            current = Some(scaled);
+        }
+        let descriptor = self.descriptor(&target, width, height, &levels);
+        written_bytes += write_file(&target.descriptor, &descriptor)?;
+        info!(
+            output = %target.descriptor.display(),
+            tiles = count,
+            levels = levels.len(),
+            "Wrote tile pyramid"
+        );
+        self.record(artifact, &target, &levels, count);
+        artifact
+            .metadata
+            .insert("output.size_bytes".to_string(), json!(written_bytes));
+        Ok(())
+    }
+
+    fn plan(&self, artifact: &mut Artifact, ctx: &PipelineContext) -> Result<()> {
+        let dimension = |key: &str| {
+            artifact
+                .metadata
+                .get(key)
+                .and_then(value_as_u64)
+                .and_then(|value| u32::try_from(value).ok())
+        };
+        let source = dimension("image.width").zip(dimension("image.height"));
+        let target = self.target(artifact, ctx)?;
+        let levels = source
+            .map(|(width, height)| self.levels(width, height))
+            .unwrap_or_default();
+        let count = tile_count(&levels, self.tile_size);
+        self.record(artifact, &target, &levels, count);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stages::from_json;
+
+    #[test]
+    fn dzi_levels_halve_down_to_one_pixel() {
+        let tiles = from_json(TileStage::from_params, json!({ "tile_size": 4 })).unwrap();
+        let levels = tiles.levels(10, 5);
+        let sizes: Vec<(u32, u32)> = levels.iter().map(|l| (l.width, l.height)).collect();
+        assert_eq!(sizes, vec![(1, 1), (2, 1), (3, 2), (5, 3), (10, 5)]);
+        assert_eq!(levels[4].index, 4);
+        assert_eq!(levels[4].grid(4), (3, 2));
+        // Interior tiles reach one pixel into each neighbour.
+        assert_eq!(tiles.tile_rect(&levels[4], 1, 0), (3, 0, 6, 5));
+        assert_eq!(tiles.tile_rect(&levels[4], 2, 1), (7, 3, 3, 2));
+    }
+
+    #[test]
+    fn iiif_tiles_name_full_size_regions() {
+        let tiles = from_json(
+            TileStage::from_params,
+            json!({ "layout": "iiif", "tile_size": 4 }),
+        )
+        .unwrap();
+        let levels = tiles.levels(10, 5);
+        assert_eq!(levels.len(), 3);
+        let smallest = &levels[0];
+        assert_eq!((smallest.width, smallest.height, smallest.scale), (3, 2, 4));
+        assert_eq!(
+            tiles.tile_path(smallest, 0, 0, (10, 5)),
+            PathBuf::from("full/3,2/0/default.jpg")
+        );
+        assert_eq!(
+            tiles.tile_path(&levels[1], 1, 0, (10, 5)),
+            PathBuf::from("8,0,2,5/1,3/0/default.jpg")
+        );
+        assert!(
+            from_json(
+                TileStage::from_params,
+                json!({ "layout": "iiif", "overlap": 2 })
+            )
+            .is_err()
+        );
+    }
+}
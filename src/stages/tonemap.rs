@@ -0,0 +1,313 @@
+use anyhow::{Result, anyhow, bail};
+use image::{DynamicImage, Rgba, Rgba32FImage, RgbaImage};
+use serde_json::{Value, json};
+
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+
+use super::{take_string, value_as_f64};
+
+/// Scene luminance `exposure: auto` maps the log-average to.
+const MIDDLE_GRAY: f64 = 0.18;
+/// Rec. 709 luminance weights of linear RGB.
+const LUMA: [f64; 3] = [0.2126, 0.7152, 0.0722];
+/// Keeps the log-average finite over black pixels.
+const LOG_EPSILON: f64 = 1e-6;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Operator {
+    Reinhard,
+    Aces,
+}
+
+impl Operator {
+    pub(super) fn from_str(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "reinhard" => Some(Self::Reinhard),
+            "aces" | "filmic" => Some(Self::Aces),
+            _ => None,
+        }
+    }
+
+    pub(super) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Reinhard => "reinhard",
+            Self::Aces => "aces",
+        }
+    }
+
+    /// Maps linear Rec. 709 `rgb` into 0..=1. `white` is the luminance
+    /// `reinhard` takes to white.
+    pub(super) fn map(self, rgb: [f64; 3], white: Option<f64>) -> [f64; 3] {
+        match self {
+            Self::Reinhard => {
+                // On luminance, so saturated highlights keep their hue.
+                let lum = LUMA[0] * rgb[0] + LUMA[1] * rgb[1] + LUMA[2] * rgb[2];
+                let mapped = match white {
+                    Some(white) => lum * (1.0 + lum / (white * white)) / (1.0 + lum),
+                    None => lum / (1.0 + lum),
+                };
+                let ratio = if lum > 0.0 { mapped / lum } else { 0.0 };
+                rgb.map(|value| value * ratio)
+            }
+            Self::Aces => rgb.map(aces),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Exposure {
+    /// Stops to scale the scene by before mapping.
+    Stops(f64),
+    /// Stops that bring the log-average luminance to middle gray.
+    Auto,
+}
+
+/// Maps high dynamic range pixels (float images from EXR, Radiance HDR or
+/// TIFF, taken as linear light) to 8-bit sRGB. Integer images are taken as
+/// sRGB and linearized first.
+pub struct TonemapStage {
+    operator: Operator,
+    exposure: Exposure,
+    /// Scene luminance that maps to white with `reinhard`; unset lets only
+    /// infinity reach white.
+    white: Option<f64>,
+}
+
+impl TonemapStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let operator = match take_string(&mut params, "operator") {
+            Some(value) => Operator::from_str(&value).ok_or_else(|| {
+                anyhow!("Unknown tonemap operator '{value}' (expected reinhard or aces)")
+            })?,
+            None => Operator::Reinhard,
+        };
+        let exposure = match params.remove("exposure") {
+            None => Exposure::Stops(0.0),
+            Some(Value::String(value)) if value.eq_ignore_ascii_case("auto") => Exposure::Auto,
+            Some(value) => match value_as_f64(&value) {
+                Some(stops) if stops.is_finite() && stops.abs() <= 32.0 => Exposure::Stops(stops),
+                _ => bail!("tonemap exposure must be 'auto' or stops in -32..=32, got {value}"),
+            },
+        };
+        let white = match params.remove("white") {
+            None => None,
+            Some(value) => match value_as_f64(&value) {
+                Some(white) if white > 0.0 && white.is_finite() => Some(white),
+                _ => bail!("tonemap white must be a positive luminance, got {value}"),
+            },
+        };
+        if white.is_some() && operator != Operator::Reinhard {
+            bail!("tonemap white only applies to the reinhard operator");
+        }
+        Ok(Self {
+            operator,
+            exposure,
+            white,
+        })
+    }
+
+    /// Linear scene pixels of `image`.
+    fn linearize(image: &DynamicImage) -> Rgba32FImage {
+        let mut linear = image.to_rgba32f();
+        if !matches!(
+            image,
+            DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)
+        ) {
+            for pixel in linear.pixels_mut() {
+                for channel in 0..3 {
+                    pixel[channel] = srgb_to_linear(pixel[channel]);
+                }
+            }
+        }
+        linear
+    }
+
+    /// Factor applied to the scene before mapping.
+    fn scale(&self, linear: &Rgba32FImage) -> f64 {
+        match self.exposure {
+            Exposure::Stops(stops) => 2f64.powf(stops),
+            Exposure::Auto => {
+                let count = linear.pixels().len().max(1) as f64;
+                let log_sum: f64 = linear
+                    .pixels()
+                    .map(|pixel| (luminance(pixel) + LOG_EPSILON).ln())
+                    .sum();
+                MIDDLE_GRAY / (log_sum / count).exp()
+            }
+        }
+    }
+
+    fn map(&self, linear: &Rgba32FImage, scale: f64) -> RgbaImage {
+        let mut mapped = RgbaImage::new(linear.width(), linear.height());
+        for (out, pixel) in mapped.pixels_mut().zip(linear.pixels()) {
+            let rgb = [0, 1, 2].map(|channel| f64::from(pixel[channel]).max(0.0) * scale);
+            let rgb = self.operator.map(rgb, self.white);
+            let alpha = f64::from(pixel[3]).clamp(0.0, 1.0);
+            *out = Rgba([
+                encode_srgb(rgb[0]),
+                encode_srgb(rgb[1]),
+                encode_srgb(rgb[2]),
+                (alpha * 255.0).round() as u8,
+            ]);
+        }
+        mapped
+    }
+}
+
+impl Stage for TonemapStage {
+    fn name(&self) -> &'static str {
+        "tonemap"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        _ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let image = artifact
+            .image
+            .as_ref()
+            .ok_or_else(|| anyhow!("tonemap stage requires a decoded image"))?;
+        let has_alpha = image.color().has_alpha();
+        let linear = Self::linearize(image);
+        let scale = self.scale(&linear);
+        let mapped = self.map(&linear, scale);
+        if artifact.is_animated() {
+            for frame in artifact.frames_mut() {
+                let frame_linear =
+                    Self::linearize(&DynamicImage::ImageRgba8(std::mem::take(&mut frame.image)));
+                frame.image = self.map(&frame_linear, scale);
+            }
+        }
+        let mapped = DynamicImage::ImageRgba8(mapped);
+        artifact.set_image(if has_alpha {
+            mapped
+        } else {
+            DynamicImage::ImageRgb8(mapped.into_rgb8())
+        });
+        artifact
+            .metadata
+            .insert("image.bit_depth".to_string(), json!(8));
+        artifact.metadata.insert(
+            "tonemap.operator".to_string(),
+            Value::String(self.operator.as_str().to_string()),
+        );
+        artifact
+            .metadata
+            .insert("tonemap.exposure".to_string(), json!(scale.log2()));
+        if let Some(white) = self.white {
+            artifact
+                .metadata
+                .insert("tonemap.white".to_string(), json!(white));
+        }
+        Ok(())
+    }
+}
+
+fn luminance(pixel: &Rgba<f32>) -> f64 {
+    let [r, g, b, _] = pixel.0.map(|value| f64::from(value).max(0.0));
+    LUMA[0] * r + LUMA[1] * g + LUMA[2] * b
+}
+
+/// Narkowicz's fit of the ACES filmic reference curve.
+fn aces(x: f64) -> f64 {
+    (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn encode_srgb(linear: f64) -> u8 {
+    let linear = linear.clamp(0.0, 1.0);
+    let encoded = if linear <= 0.0031308 {
+        linear * 12.92
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    };
+    (encoded * 255.0).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb32FImage;
+
+    use super::*;
+    use crate::stages::from_json;
+
+    fn hdr() -> DynamicImage {
+        // A ramp from black to 16x brighter than display white.
+        DynamicImage::ImageRgb32F(Rgb32FImage::from_fn(17, 1, |x, _| {
+            image::Rgb([x as f32, x as f32, x as f32])
+        }))
+    }
+
+    #[test]
+    fn operators_compress_highlights_without_clipping_the_ramp() {
+        let linear = TonemapStage::linearize(&hdr());
+        for operator in ["reinhard", "aces"] {
+            let stage =
+                from_json(TonemapStage::from_params, json!({ "operator": operator })).unwrap();
+            let mapped = stage.map(&linear, stage.scale(&linear));
+            let values: Vec<u8> = mapped.pixels().map(|pixel| pixel[0]).collect();
+            assert_eq!(values[0], 0, "{operator}");
+            assert!(
+                values.windows(2).all(|pair| pair[0] <= pair[1]),
+                "{operator}"
+            );
+            assert!(values[1] < 255, "{operator} clipped 1.0");
+        }
+        let reinhard = from_json(
+            TonemapStage::from_params,
+            json!({ "operator": "reinhard", "white": 16 }),
+        )
+        .unwrap();
+        let mapped = reinhard.map(&linear, 1.0);
+        assert_eq!(mapped.get_pixel(16, 0)[0], 255);
+        assert!(mapped.get_pixel(8, 0)[0] < 255);
+    }
+
+    #[test]
+    fn exposure_in_stops_and_auto() {
+        let linear = TonemapStage::linearize(&hdr());
+        assert_eq!(
+            from_json(TonemapStage::from_params, json!({ "exposure": -2 }))
+                .unwrap()
+                .scale(&linear),
+            0.25
+        );
+        let auto = from_json(TonemapStage::from_params, json!({ "exposure": "auto" })).unwrap();
+        let scale = auto.scale(&linear);
+        assert!(
+            scale > 0.0 && scale < 1.0,
+            "bright scene darkened, got {scale}"
+        );
+        assert!(from_json(TonemapStage::from_params, json!({ "exposure": "bright" })).is_err());
+        assert!(
+            from_json(
+                TonemapStage::from_params,
+                json!({ "operator": "aces", "white": 4 })
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn integer_images_round_trip_through_linear_light() {
+        let gray = DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, Rgba([128, 64, 200, 77])));
+        let linear = TonemapStage::linearize(&gray);
+        let pixel = linear.get_pixel(0, 0);
+        let back = [0, 1, 2].map(|channel| encode_srgb(f64::from(pixel[channel])));
+        assert_eq!(back, [128, 64, 200]);
+    }
+}
@@ -0,0 +1,246 @@
+use std::path::PathBuf;
+
+use anyhow::{Result, anyhow, bail};
+use image::DynamicImage;
+use image::imageops::FilterType;
+use serde_json::{Value, json};
+#[cfg(not(feature = "onnx"))]
+use tracing::warn;
+
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+
+use super::{take_string, take_u32};
+
+const DEFAULT_SCALE: u32 = 2;
+const DEFAULT_TILE_SIZE: u32 = 128;
+
+/// Enlarges the image by an integer factor, through an ESRGAN-style ONNX
+/// model when the `onnx` feature is enabled and a model is configured, and
+/// with Lanczos resampling otherwise.
+pub struct UpscaleStage {
+    scale: u32,
+    #[cfg(feature = "onnx")]
+    model: Option<onnx::SuperResolution>,
+}
+
+impl UpscaleStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let scale = take_u32(&mut params, "scale").unwrap_or(DEFAULT_SCALE);
+        if !(1..=8).contains(&scale) {
+            bail!("upscale scale must be between 1 and 8, got {scale}");
+        }
+        let tile_size = take_u32(&mut params, "tile_size").unwrap_or(DEFAULT_TILE_SIZE);
+        if tile_size < 16 {
+            bail!("upscale tile_size must be at least 16, got {tile_size}");
+        }
+        let model = take_string(&mut params, "model").map(PathBuf::from);
+
+        #[cfg(feature = "onnx")]
+        {
+            let model = model
+                .map(|path| onnx::SuperResolution::load(&path, scale, tile_size))
+                .transpose()?;
+            Ok(Self { scale, model })
+        }
+
+        #[cfg(not(feature = "onnx"))]
+        {
+            if let Some(path) = model {
+                warn!(
+                    model = %path.display(),
+                    "upscale model ignored; rebuild with --features onnx. Using Lanczos"
+                );
+            }
+            Ok(Self { scale })
+        }
+    }
+
+    fn method(&self) -> &'static str {
+        #[cfg(feature = "onnx")]
+        if self.model.is_some() {
+            return "onnx";
+        }
+        "lanczos3"
+    }
+
+    fn upscale(&self, image: &DynamicImage) -> Result<DynamicImage> {
+        #[cfg(feature = "onnx")]
+        if let Some(model) = &self.model {
+            return model.upscale(image);
+        }
+        let width = image
+            .width()
+            .checked_mul(self.scale)
+            .ok_or_else(|| anyhow!("upscaled width overflows"))?;
+        let height = image
+            .height()
+            .checked_mul(self.scale)
+            .ok_or_else(|| anyhow!("upscaled height overflows"))?;
+        Ok(image.resize_exact(width, height, FilterType::Lanczos3))
+    }
+}
+
+impl Stage for UpscaleStage {
+    fn name(&self) -> &'static str {
+        "upscale"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn output_dimensions(&self, width: u32, height: u32) -> (u32, u32) {
+        (
+            width.saturating_mul(self.scale),
+            height.saturating_mul(self.scale),
+        )
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        _ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let image = artifact
+            .image
+            .as_ref()
+            .ok_or_else(|| anyhow!("upscale stage requires a decoded image"))?;
+        if self.scale == 1 {
+            return Ok(());
+        }
+        let upscaled = self.upscale(image)?;
+        artifact
+            .metadata
+            .insert("image.width".to_string(), json!(upscaled.width()));
+        artifact
+            .metadata
+            .insert("image.height".to_string(), json!(upscaled.height()));
+        artifact
+            .metadata
+            .insert("upscale.scale".to_string(), json!(self.scale));
+        artifact.metadata.insert(
+            "upscale.method".to_string(),
+            Value::String(self.method().to_string()),
+        );
+        artifact.set_image(upscaled);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "onnx")]
+mod onnx {
+    use std::path::Path;
+
+    use anyhow::{Context, Result, anyhow, bail};
+    use image::imageops::FilterType;
+    use image::{DynamicImage, Rgba, RgbaImage};
+    use tract_onnx::prelude::*;
+
+    type Plan = TypedRunnableModel<TypedModel>;
+
+    /// An ONNX model mapping `[1, 3, tile, tile]` RGB in `0..=1` to
+    /// `[1, 3, tile * scale, tile * scale]`, run tile by tile.
+    pub struct SuperResolution {
+        plan: Plan,
+        scale: u32,
+        tile: u32,
+    }
+
+    impl SuperResolution {
+        pub fn load(path: &Path, scale: u32, tile: u32) -> Result<Self> {
+            let plan = tract_onnx::onnx()
+                .model_for_path(path)
+                .and_then(|model| {
+                    model.with_input_fact(0, f32::fact([1, 3, tile as usize, tile as usize]).into())
+                })
+                .and_then(|model| model.into_optimized())
+                .and_then(|model| model.into_runnable())
+                .map_err(|err| anyhow!("{err:?}"))
+                .with_context(|| format!("Failed to load upscale model: {}", path.display()))?;
+            Ok(Self { plan, scale, tile })
+        }
+
+        pub fn upscale(&self, image: &DynamicImage) -> Result<DynamicImage> {
+            let source = image.to_rgba8();
+            let (width, height) = source.dimensions();
+            let mut output = RgbaImage::new(width * self.scale, height * self.scale);
+
+            for top in (0..height).step_by(self.tile as usize) {
+                for left in (0..width).step_by(self.tile as usize) {
+                    let tile_width = self.tile.min(width - left);
+                    let tile_height = self.tile.min(height - top);
+                    let input = tract_ndarray::Array4::from_shape_fn(
+                        (1, 3, self.tile as usize, self.tile as usize),
+                        |(_, channel, y, x)| {
+                            // Edge tiles repeat their last row/column to fill
+                            // the fixed model input.
+                            let sx = left + (x as u32).min(tile_width - 1);
+                            let sy = top + (y as u32).min(tile_height - 1);
+                            source.get_pixel(sx, sy)[channel] as f32 / 255.0
+                        },
+                    );
+                    let result = self
+                        .plan
+                        .run(tvec!(Tensor::from(input).into()))
+                        .map_err(|err| anyhow!("upscale model failed: {err:?}"))?;
+                    let view = result[0]
+                        .to_array_view::<f32>()
+                        .map_err(|err| anyhow!("unexpected upscale model output: {err:?}"))?;
+                    let shape = view.shape();
+                    let expected = (self.tile * self.scale) as usize;
+                    if shape != [1, 3, expected, expected] {
+                        bail!(
+                            "upscale model produced shape {shape:?}, expected [1, 3, {expected}, {expected}]"
+                        );
+                    }
+                    for y in 0..tile_height * self.scale {
+                        for x in 0..tile_width * self.scale {
+                            let channel = |c: usize| {
+                                (view[[0, c, y as usize, x as usize]] * 255.0)
+                                    .round()
+                                    .clamp(0.0, 255.0) as u8
+                            };
+                            output.put_pixel(
+                                left * self.scale + x,
+                                top * self.scale + y,
+                                Rgba([channel(0), channel(1), channel(2), 255]),
+                            );
+                        }
+                    }
+                }
+            }
+
+            if image.color().has_alpha() {
+                // Models only see RGB; alpha is resampled conventionally.
+                let alpha = image::imageops::resize(
+                    &source,
+                    width * self.scale,
+                    height * self.scale,
+                    FilterType::Lanczos3,
+                );
+                for (pixel, resized) in output.pixels_mut().zip(alpha.pixels()) {
+                    pixel[3] = resized[3];
+                }
+            }
+            Ok(DynamicImage::ImageRgba8(output))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stages::from_json;
+    use image::{Rgba, RgbaImage};
+
+    #[test]
+    fn lanczos_fallback_multiplies_dimensions() {
+        let stage = from_json(UpscaleStage::from_params, json!({ "scale": 3 })).unwrap();
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(5, 4, Rgba([1, 2, 3, 255])));
+        let upscaled = stage.upscale(&image).unwrap();
+        assert_eq!((upscaled.width(), upscaled.height()), (15, 12));
+        assert_eq!(stage.method(), "lanczos3");
+    }
+}
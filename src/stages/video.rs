@@ -1,18 +1,77 @@
 use std::fs;
 use std::path::PathBuf;
+use std::time::Duration;
 
-use anyhow::{Context, Result, anyhow};
+use anyhow::{Context, Result, anyhow, bail};
 use serde_json::{Value, json};
+use tracing::{debug, warn};
 
-use crate::pipeline::{Artifact, OutputSpec, PipelineContext, Stage, StageParameters};
+use crate::audio::{opus, pcm};
+use crate::overwrite;
+use crate::pipeline::{self, Artifact, OutputSpec, PipelineContext, Stage, StageParameters};
 use crate::scheduler::StageDevice;
-use crate::video::{self, MediaStreams};
+use crate::video;
+use crate::video::av1;
+use crate::video::container::VideoSamples;
+use crate::video::h264;
+use crate::video::hevc;
+use crate::video::hwaccel::{self, HwAccel};
+use crate::video::matroska::{self, DocType};
+use crate::video::muxer;
+use crate::video::vp9;
+use crate::video::{AudioStream, EncodedVideo, VideoCodec};
 
-pub struct VideoDecodeStage;
+use super::{keep_existing_output, take_bool, take_f64, take_u32, value_as_u64};
+
+/// Decodes the input's video track. When the scheduler places it on the
+/// GPU and the build includes hardware backends, it tries them in turn and
+/// falls back to the software decoders when none can handle the stream.
+pub struct VideoDecodeStage {
+    hardware: Vec<HwAccel>,
+}
 
 impl VideoDecodeStage {
-    pub fn from_params(_params: StageParameters) -> Result<Self> {
-        Ok(Self)
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let hardware = match take_string(&mut params, "hwaccel")
+            .map(|hwaccel| hwaccel.to_ascii_lowercase())
+            .as_deref()
+        {
+            None | Some("auto") => HwAccel::available(),
+            Some("none") => Vec::new(),
+            Some(name) => {
+                let backend = HwAccel::parse(name).context("invalid video_decode hwaccel")?;
+                if !backend.compiled() {
+                    bail!("video_decode hwaccel {name} requires building with the {name} feature");
+                }
+                vec![backend]
+            }
+        };
+        Ok(Self { hardware })
+    }
+
+    /// Decodes `track` on the first backend that takes it, returning that
+    /// backend, or `None` after leaving `media` for the software decoders.
+    fn decode_on_hardware(
+        &self,
+        track: &VideoSamples<'_>,
+        media: &mut video::MediaStreams,
+    ) -> Option<HwAccel> {
+        for &backend in &self.hardware {
+            match hwaccel::decode_samples(backend, track, media) {
+                Ok(true) => return Some(backend),
+                Ok(false) => debug!(
+                    backend = backend.name(),
+                    "Hardware decoder could not take the stream; trying the next one"
+                ),
+                Err(err) => debug!(
+                    backend = backend.name(),
+                    error = %format!("{err:#}"),
+                    "Hardware decoding failed; trying the next one"
+                ),
+            }
+            media.video = None;
+        }
+        None
     }
 }
 
@@ -22,28 +81,66 @@ impl Stage for VideoDecodeStage {
     }
 
     fn supports_device(&self, device: StageDevice) -> bool {
-        matches!(device, StageDevice::Cpu)
+        match device {
+            StageDevice::Cpu => true,
+            StageDevice::Gpu => !self.hardware.is_empty(),
+        }
     }
 
     fn run(
         &self,
         artifact: &mut Artifact,
-        _ctx: &PipelineContext,
-        _device: StageDevice,
+        ctx: &PipelineContext,
+        device: StageDevice,
     ) -> Result<()> {
-        let mut media = match video::container::demux_media(&artifact.data) {
-            Ok(streams) => streams,
-            Err(_) => MediaStreams::default(),
+        let (mut media, track) = if matroska::is_matroska(&artifact.data) {
+            let track = matroska::video_samples(&artifact.data)
+                .context("failed to read Matroska/WebM container")?;
+            (video::MediaStreams::default(), track)
+        } else {
+            let media = video::container::demux_media(&artifact.data).unwrap_or_default();
+            let track = match media.video {
+                Some(_) => video::container::video_samples(&artifact.data)?,
+                None => None,
+            };
+            (media, track)
+        };
+        let hardware = match (&track, device) {
+            (Some(track), StageDevice::Gpu) => self.decode_on_hardware(track, &mut media),
+            _ => None,
         };
-        if media.video.as_ref().map_or(true, |v| v.frames.is_empty()) {
-            video::h264::decode_annex_b(&artifact.data, &mut media)
+        match hardware {
+            Some(backend) => {
+                artifact
+                    .metadata
+                    .insert(pipeline::hardware_key(self.name()), json!(backend.name()));
+            }
+            None => {
+                artifact
+                    .metadata
+                    .remove(&pipeline::hardware_key(self.name()));
+            }
+        }
+        // The decoders build the stream afresh, without the track header.
+        let rotation = track.as_ref().map_or(0, |track| track.rotation);
+        let container_hdr = track.as_ref().map(|track| track.hdr).unwrap_or_default();
+        if let (Some(track), None) = (track, hardware) {
+            decode_track(&track, &mut media)?;
+        }
+        if media.video.as_ref().is_none_or(|v| v.frames.is_empty()) {
+            h264::decode_annex_b(&artifact.data, &mut media)
                 .context("failed to decode H.264 Annex B stream")?;
         }
 
         let video_stream = media
             .video
-            .as_ref()
+            .as_mut()
             .ok_or_else(|| anyhow!("no decodable video stream found"))?;
+        video_stream.rotation = rotation;
+        // HDR metadata in the bitstream's SEI wins over the container's.
+        let hdr = &mut video_stream.hdr;
+        hdr.mastering_display = hdr.mastering_display.or(container_hdr.mastering_display);
+        hdr.content_light = hdr.content_light.or(container_hdr.content_light);
 
         artifact.metadata.insert(
             "video.frame_count".to_string(),
@@ -61,27 +158,321 @@ impl Stage for VideoDecodeStage {
             "video.codec".into(),
             json!(format!("{:?}", video_stream.codec)),
         );
-        artifact.media = media;
+        artifact
+            .metadata
+            .insert("video.rotation".into(), json!(rotation));
+        if video_stream.hdr.is_empty() {
+            artifact.metadata.remove("video.hdr");
+        } else {
+            artifact
+                .metadata
+                .insert("video.hdr".into(), json!(video_stream.hdr));
+        }
+        artifact.set_media(media);
+        if ctx.quality_gates_enabled {
+            artifact.keep_original_media();
+        }
         Ok(())
     }
 }
 
+/// Decodes `track` in software.
+fn decode_track(track: &VideoSamples<'_>, media: &mut video::MediaStreams) -> Result<()> {
+    match track.codec {
+        VideoCodec::H264 => {
+            h264::decode_samples(track, media).context("failed to decode H.264 video track")
+        }
+        VideoCodec::Vp9 => {
+            vp9::decode_samples(track, media).context("failed to decode VP9 video track")
+        }
+        VideoCodec::Av1 => {
+            av1::decode_samples(track, media).context("failed to decode AV1 video track")
+        }
+        VideoCodec::H265 => {
+            hevc::decode_samples(track, media).context("failed to decode HEVC video track")
+        }
+        codec => bail!("no decoder for {codec:?} video tracks"),
+    }
+}
+
 pub struct VideoEncodeStage {
-    format: Option<String>,
+    format: OutputFormat,
     extension: Option<String>,
-    _options: StageParameters,
+    codec: OutputCodec,
+    /// Fragment length for fragmented MP4 output.
+    fragment_duration: Option<Duration>,
+    /// Kilobits per second of the Opus audio in WebM output.
+    audio_bit_rate: u32,
+}
+
+const DEFAULT_FRAGMENT_SECONDS: f64 = 2.0;
+const DEFAULT_AUDIO_KBPS: u32 = 96;
+
+/// Frames rav1e codes out of display order in each group when reordering,
+/// the AV1 counterpart of B-frames.
+const AV1_REORDERED_FRAMES: u64 = 3;
+
+/// Container written by `video_encode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Mp4,
+    Matroska,
+    WebM,
+    /// A raw Annex B H.264 elementary stream.
+    AnnexB,
+}
+
+impl OutputFormat {
+    fn parse(value: &str) -> Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "mp4" => Ok(Self::Mp4),
+            "mkv" | "matroska" => Ok(Self::Matroska),
+            "webm" => Ok(Self::WebM),
+            "h264" | "annexb" => Ok(Self::AnnexB),
+            other => {
+                bail!("unsupported video_encode format '{other}' (expected mp4, mkv, webm or h264)")
+            }
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Mp4 => "mp4",
+            Self::Matroska => "mkv",
+            Self::WebM => "webm",
+            Self::AnnexB => "h264",
+        }
+    }
+}
+
+/// Codec written by `video_encode`, with its settings.
+#[derive(Debug, Clone, Copy)]
+enum OutputCodec {
+    H264(h264::EncoderConfig),
+    Av1(av1::EncoderConfig),
+}
+
+impl OutputCodec {
+    fn name(self) -> &'static str {
+        match self {
+            Self::H264(_) => "h264",
+            Self::Av1(_) => "av1",
+        }
+    }
+
+    /// The fixed keyframe interval, unless the encoder places keyframes.
+    fn keyframe_interval(self) -> Option<u32> {
+        match self {
+            Self::H264(config) => Some(config.keyframe_interval),
+            Self::Av1(config) => Some(config.keyframe_interval).filter(|&interval| interval > 0),
+        }
+    }
+
+    fn bframes(self) -> u64 {
+        match self {
+            Self::H264(_) => 0,
+            Self::Av1(config) if config.low_latency => 0,
+            Self::Av1(_) => AV1_REORDERED_FRAMES,
+        }
+    }
 }
 
 impl VideoEncodeStage {
     pub fn from_params(mut params: StageParameters) -> Result<Self> {
-        let format = take_string(&mut params, "format");
+        let format = take_string(&mut params, "format")
+            .map(|format| OutputFormat::parse(&format))
+            .transpose()?
+            .unwrap_or(OutputFormat::Mp4);
         let extension = take_string(&mut params, "extension");
+        // WebM has no H.264, so it defaults to AV1.
+        let av1 = match take_string(&mut params, "codec")
+            .map(|codec| codec.to_ascii_lowercase())
+            .as_deref()
+        {
+            None => format == OutputFormat::WebM,
+            Some("h264" | "avc") => false,
+            Some("av1") => true,
+            Some(other) => {
+                bail!("unsupported video_encode codec '{other}' (expected h264 or av1)")
+            }
+        };
+        let qp = params.remove("qp");
+        let keyframe_interval = match params.remove("keyframe_interval") {
+            Some(value) => {
+                let interval = value_as_u64(&value)
+                    .and_then(|interval| u32::try_from(interval).ok())
+                    .filter(|&interval| interval > 0);
+                Some(interval.ok_or_else(|| {
+                    anyhow!("video_encode keyframe_interval must be a positive count, got {value}")
+                })?)
+            }
+            None => None,
+        };
+        let bframes = match params.remove("bframes") {
+            Some(value) => Some(value_as_u64(&value).ok_or_else(|| {
+                anyhow!("video_encode bframes must be a frame count, got {value}")
+            })?),
+            None => None,
+        };
+        // Neither encoder references frames across a keyframe.
+        if take_bool(&mut params, "closed_gop")? == Some(false) {
+            bail!("video_encode only writes closed GOPs; drop closed_gop: false");
+        }
+        let av1_params =
+            ["speed", "quantizer", "tile_cols", "tile_rows"].map(|key| params.remove(key));
+        let codec = if av1 {
+            if format == OutputFormat::AnnexB {
+                bail!("video_encode format h264 needs codec h264");
+            }
+            if qp.is_some() {
+                bail!("video_encode qp applies to h264; use quantizer with av1");
+            }
+            let [speed, quantizer, tile_cols, tile_rows] = av1_params;
+            let mut config = av1::EncoderConfig {
+                keyframe_interval: keyframe_interval.unwrap_or(0),
+                ..av1::EncoderConfig::default()
+            };
+            config.low_latency = match bframes {
+                None | Some(AV1_REORDERED_FRAMES) => false,
+                Some(0) => true,
+                Some(other) => bail!(
+                    "video_encode bframes with av1 must be 0 or {AV1_REORDERED_FRAMES} \
+                     (rav1e reorders whole groups of four frames), got {other}"
+                ),
+            };
+            if let Some(speed) = speed {
+                config.speed = value_as_u64(&speed)
+                    .filter(|&speed| speed <= 10)
+                    .ok_or_else(|| {
+                        anyhow!("video_encode speed must be between 0 and 10, got {speed}")
+                    })? as u8;
+            }
+            if let Some(quantizer) = quantizer {
+                config.quantizer = value_as_u64(&quantizer)
+                    .filter(|&quantizer| quantizer <= 255)
+                    .ok_or_else(|| {
+                        anyhow!("video_encode quantizer must be between 0 and 255, got {quantizer}")
+                    })? as u8;
+            }
+            for (value, key, field) in [
+                (tile_cols, "tile_cols", &mut config.tile_cols),
+                (tile_rows, "tile_rows", &mut config.tile_rows),
+            ] {
+                if let Some(value) = value {
+                    *field = value_as_u64(&value)
+                        .filter(|&tiles| tiles.is_power_of_two() && tiles <= 64)
+                        .ok_or_else(|| {
+                            anyhow!(
+                                "video_encode {key} must be a power of two up to 64, got {value}"
+                            )
+                        })? as u32;
+                }
+            }
+            OutputCodec::Av1(config)
+        } else {
+            if format == OutputFormat::WebM {
+                bail!("video_encode format webm needs codec av1");
+            }
+            if av1_params.iter().any(Option::is_some) {
+                bail!("video_encode speed, quantizer, tile_cols and tile_rows apply to av1");
+            }
+            if bframes.is_some_and(|bframes| bframes > 0) {
+                bail!("video_encode bframes must be 0 with h264; baseline profile has no B slices");
+            }
+            let mut config = h264::EncoderConfig {
+                keyframe_interval: keyframe_interval.unwrap_or(1),
+                ..h264::EncoderConfig::default()
+            };
+            if let Some(qp) = qp {
+                config.qp = value_as_u64(&qp)
+                    .filter(|&qp| qp <= 51)
+                    .ok_or_else(|| anyhow!("video_encode qp must be between 0 and 51, got {qp}"))?
+                    as u8;
+            }
+            OutputCodec::H264(config)
+        };
+        let fragment_seconds = take_f64(&mut params, "fragment_duration")?;
+        let fragment_duration = if take_bool(&mut params, "fragmented")?.unwrap_or(false) {
+            if format != OutputFormat::Mp4 {
+                bail!("video_encode fragmented output needs format mp4");
+            }
+            let seconds = fragment_seconds.unwrap_or(DEFAULT_FRAGMENT_SECONDS);
+            if !(seconds.is_finite() && seconds > 0.0) {
+                bail!("video_encode fragment_duration must be positive seconds, got {seconds}");
+            }
+            Some(Duration::from_secs_f64(seconds))
+        } else {
+            if fragment_seconds.is_some() {
+                bail!("video_encode fragment_duration needs fragmented: true");
+            }
+            None
+        };
+        let audio_bit_rate = match take_u32(&mut params, "audio_bitrate") {
+            None => DEFAULT_AUDIO_KBPS,
+            Some(_) if format != OutputFormat::WebM => {
+                bail!("video_encode audio_bitrate applies to webm, whose audio is Opus")
+            }
+            Some(kbps @ 6..=510) => kbps,
+            Some(kbps) => {
+                bail!("video_encode audio_bitrate must be between 6 and 510 kbps, got {kbps}")
+            }
+        };
         Ok(Self {
             format,
             extension,
-            _options: params,
+            codec,
+            fragment_duration,
+            audio_bit_rate,
         })
     }
+
+    /// Writes `video` in the output container, with `audio` where the
+    /// container carries it.
+    fn mux(&self, video: &EncodedVideo, audio: Option<&AudioStream>) -> Result<Vec<u8>> {
+        let audio = audio.filter(|audio| audio.buffers.iter().any(|b| !b.samples.is_empty()));
+        match self.format {
+            OutputFormat::Mp4 => match self.fragment_duration {
+                Some(fragment_duration) => muxer::write_fragmented_mp4(video, fragment_duration),
+                None => muxer::write_mp4(video),
+            },
+            OutputFormat::Matroska => {
+                let audio = audio.map(pcm::float_packets).transpose()?;
+                matroska::write_matroska(video, audio.as_ref(), DocType::Matroska)
+            }
+            OutputFormat::WebM => {
+                let audio = audio
+                    .map(|audio| opus::encode(audio, self.audio_bit_rate * 1000))
+                    .transpose()
+                    .context("failed to encode the audio track as Opus")?;
+                matroska::write_matroska(video, audio.as_ref(), DocType::WebM)
+            }
+            OutputFormat::AnnexB => bail!("video_encode format h264 needs codec h264"),
+        }
+    }
+
+    /// Decodes the stage's own output back, for quality gates to compare
+    /// with the source.
+    fn decode_output(&self, data: &[u8]) -> Result<video::VideoStream> {
+        let track = match self.format {
+            OutputFormat::Mp4 => video::container::video_samples(data)?,
+            OutputFormat::Matroska | OutputFormat::WebM => matroska::video_samples(data)?,
+            OutputFormat::AnnexB => None,
+        };
+        let mut media = video::MediaStreams::default();
+        match track {
+            Some(track) => decode_track(&track, &mut media)?,
+            None => h264::decode_annex_b(data, &mut media)?,
+        }
+        media
+            .video
+            .ok_or_else(|| anyhow!("encoded output has no video stream"))
+    }
+
+    fn extension(&self) -> String {
+        self.extension
+            .clone()
+            .unwrap_or_else(|| self.format.name().to_string())
+    }
 }
 
 impl Stage for VideoEncodeStage {
@@ -99,72 +490,164 @@ impl Stage for VideoEncodeStage {
         ctx: &PipelineContext,
         _device: StageDevice,
     ) -> Result<()> {
-        let video_stream = artifact
+        let frame_count = artifact
             .media()
             .video
             .as_ref()
-            .ok_or_else(|| anyhow!("video_encode requires a decoded video stream"))?;
-
-        let frame_count = video_stream.frames.len();
+            .ok_or_else(|| anyhow!("video_encode requires a decoded video stream"))?
+            .frames
+            .len();
 
-        let format = self.format.as_deref().unwrap_or("mp4").to_ascii_lowercase();
-        let extension = self
-            .extension
-            .clone()
-            .unwrap_or_else(|| default_extension(&format));
+        let output_path = ctx.outputs.claim(
+            resolve_output_path(&ctx.output, artifact, &self.extension()),
+            &artifact.input_path,
+            &mut artifact.metadata,
+        )?;
+        if keep_existing_output(artifact, ctx, &output_path) {
+            artifact.metadata.insert(
+                "video.output_path".into(),
+                Value::String(output_path.to_string_lossy().to_string()),
+            );
+            return Ok(());
+        }
+        let media = artifact.media();
+        let video_stream = media
+            .video
+            .as_ref()
+            .expect("video stream was checked above");
+        let (bytes, keyframe_count) = match &self.codec {
+            OutputCodec::H264(config) => {
+                let encoded =
+                    h264::encode(video_stream, config).context("failed to encode H.264 video")?;
+                let keyframes = encoded.frames.iter().filter(|frame| frame.keyframe).count();
+                let bytes = match self.format {
+                    OutputFormat::AnnexB => encoded.to_annex_b(),
+                    _ => self.mux(&encoded.to_video(), media.audio.as_ref())?,
+                };
+                (bytes, keyframes)
+            }
+            OutputCodec::Av1(config) => {
+                let encoded =
+                    av1::encode(video_stream, config).context("failed to encode AV1 video")?;
+                let keyframes = encoded
+                    .samples
+                    .iter()
+                    .filter(|sample| sample.keyframe)
+                    .count();
+                (self.mux(&encoded, media.audio.as_ref())?, keyframes)
+            }
+        };
 
-        let output_path = resolve_output_path(&ctx.output, artifact, &extension);
         if let Some(parent) = output_path.parent() {
             fs::create_dir_all(parent).with_context(|| {
                 format!("failed to create output directory: {}", parent.display())
             })?;
         }
 
-        let buffer = artifact.data.clone();
-        fs::write(&output_path, &buffer)
+        fs::write(&output_path, &bytes)
             .with_context(|| format!("failed to write encoded video: {}", output_path.display()))?;
 
-        artifact.replace_data(buffer);
         artifact.metadata.insert(
             "video.output_path".into(),
             Value::String(output_path.to_string_lossy().to_string()),
         );
         artifact
             .metadata
-            .insert("video.output.format".into(), Value::String(format.clone()));
+            .insert("video.output.format".into(), json!(self.format.name()));
+        artifact
+            .metadata
+            .insert("video.output.codec".into(), json!(self.codec.name()));
+        if let Some(interval) = self.codec.keyframe_interval() {
+            artifact
+                .metadata
+                .insert("video.output.keyframe_interval".into(), json!(interval));
+        }
         artifact
             .metadata
-            .insert("video.output.size_bytes".into(), json!(artifact.data.len()));
+            .insert("video.output.bframes".into(), json!(self.codec.bframes()));
+        artifact
+            .metadata
+            .insert("video.output.closed_gop".into(), json!(true));
+        artifact
+            .metadata
+            .insert("video.output.keyframe_count".into(), json!(keyframe_count));
+        if let Some(fragment_duration) = self.fragment_duration {
+            artifact.metadata.insert(
+                "video.output.fragment_duration".into(),
+                json!(fragment_duration.as_secs_f64()),
+            );
+        }
+        artifact
+            .metadata
+            .insert("video.output.size_bytes".into(), json!(bytes.len()));
         artifact
             .metadata
             .insert("video.output.frame_count".into(), json!(frame_count));
+
+        // Quality gates compare the output as a player would decode it.
+        let verify = ctx.quality_gates_enabled;
+        artifact
+            .metadata
+            .insert("output.verified".into(), Value::Bool(verify));
+        if verify {
+            match self.decode_output(&bytes) {
+                Ok(decoded) => {
+                    artifact
+                        .metadata
+                        .insert("output.decode_supported".into(), Value::Bool(true));
+                    let media = artifact.media();
+                    let media = video::MediaStreams {
+                        video: Some(decoded),
+                        audio: media.audio.clone(),
+                        subtitles: media.subtitles.clone(),
+                        duration: media.duration,
+                    };
+                    artifact.set_media(media);
+                }
+                Err(err) => {
+                    artifact
+                        .metadata
+                        .insert("output.decode_supported".into(), Value::Bool(false));
+                    artifact.metadata.insert(
+                        "output.decode_warning".into(),
+                        Value::String(format!("{err:#}")),
+                    );
+                    warn!(
+                        codec = self.codec.name(),
+                        error = %err,
+                        "Post-encode decode skipped; decoder unavailable"
+                    );
+                }
+            }
+        }
         Ok(())
     }
-}
 
-fn resolve_output_path(spec: &OutputSpec, artifact: &Artifact, extension: &str) -> PathBuf {
-    let mut file_name = spec.structure.clone();
-    file_name = file_name.replace("{stem}", &artifact.stem);
-    file_name = file_name.replace("{ext}", extension);
-
-    for (key, value) in artifact.metadata.iter() {
-        if let Some(as_str) = value.as_str() {
-            let placeholder = format!("{{{}}}", key);
-            file_name = file_name.replace(&placeholder, as_str);
+    fn plan(&self, artifact: &mut Artifact, ctx: &PipelineContext) -> Result<()> {
+        let output_path = ctx.outputs.claim(
+            resolve_output_path(&ctx.output, artifact, &self.extension()),
+            &artifact.input_path,
+            &mut artifact.metadata,
+        )?;
+        if let Some(reason) = ctx
+            .overwrite
+            .keep_reason(&artifact.input_path, &output_path)
+        {
+            artifact.metadata.insert(
+                overwrite::SKIPPED_KEY.to_string(),
+                Value::String(reason.to_string()),
+            );
         }
+        artifact.metadata.insert(
+            "video.output_path".into(),
+            Value::String(output_path.to_string_lossy().to_string()),
+        );
+        Ok(())
     }
-
-    let mut path = spec.directory.clone();
-    path.push(file_name);
-    path
 }
 
-fn default_extension(format: &str) -> String {
-    match format {
-        "mp4" => "mp4".to_string(),
-        "annexb" | "h264" => "h264".to_string(),
-        other => other.to_string(),
-    }
+fn resolve_output_path(spec: &OutputSpec, artifact: &Artifact, extension: &str) -> PathBuf {
+    spec.resolve(&artifact.stem, extension, &artifact.metadata)
 }
 
 fn take_string(params: &mut StageParameters, key: &str) -> Option<String> {
@@ -0,0 +1,607 @@
+use anyhow::{Result, anyhow, bail};
+use serde_json::{Value, json};
+
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+use crate::video::{ColorSpace, FramePlanes, HdrMetadata, TransferFunction, VideoFrame};
+
+use super::tonemap::Operator;
+use super::{take_string, value_as_f64};
+
+/// Luminance in nits of SDR reference white (BT.2408), which HDR light is
+/// measured against.
+const REFERENCE_WHITE: f64 = 203.0;
+/// Mastering peak PQ content without HDR metadata is taken to reach, and
+/// the display peak HLG is rendered for.
+const DEFAULT_PEAK: f64 = 1000.0;
+/// Gamma of the BT.1886 display SDR video is graded on.
+const SDR_GAMMA: f64 = 2.4;
+
+/// CIE xy chromaticities of the red, green and blue primaries, then the
+/// white point.
+type Primaries = [[f64; 2]; 4];
+type Matrix = [[f64; 3]; 3];
+
+const D65: [f64; 2] = [0.3127, 0.3290];
+/// SMPTE 170M, the primaries BT.601 video is produced with.
+const BT601_PRIMARIES: Primaries = [[0.630, 0.340], [0.310, 0.595], [0.155, 0.070], D65];
+const BT709_PRIMARIES: Primaries = [[0.640, 0.330], [0.300, 0.600], [0.150, 0.060], D65];
+const BT2020_PRIMARIES: Primaries = [[0.708, 0.292], [0.170, 0.797], [0.131, 0.046], D65];
+
+/// Converts decoded video between the BT.601, BT.709 and BT.2020 matrices
+/// and primaries, tone mapping PQ and HLG sources to SDR on the way. The
+/// source's colour space and transfer come from the decoder unless `from`
+/// or `transfer` override them; the output is always SDR, so the stream's
+/// HDR metadata is dropped. Without a `peak`, PQ content is tone mapped
+/// from its MaxCLL or mastering display peak.
+pub struct VideoColorStage {
+    from: Option<ColorSpace>,
+    to: ColorSpace,
+    transfer: Option<TransferFunction>,
+    /// `None` clips HDR highlights instead of rolling them off.
+    operator: Option<Operator>,
+    peak: Option<f64>,
+}
+
+impl VideoColorStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let from = take_string(&mut params, "from")
+            .map(|value| parse_space(&value, "from"))
+            .transpose()?;
+        let to = take_string(&mut params, "to")
+            .map(|value| parse_space(&value, "to"))
+            .transpose()?
+            .unwrap_or(ColorSpace::Bt709);
+        let transfer = match take_string(&mut params, "transfer") {
+            Some(value) => Some(match value.trim().to_ascii_lowercase().as_str() {
+                "sdr" | "bt709" | "bt1886" => TransferFunction::Sdr,
+                "pq" | "smpte2084" | "hdr10" => TransferFunction::Pq,
+                "hlg" | "arib-std-b67" => TransferFunction::Hlg,
+                _ => bail!("Unknown video_color transfer '{value}' (expected sdr, pq or hlg)"),
+            }),
+            None => None,
+        };
+        let operator = match take_string(&mut params, "tonemap") {
+            Some(value) if value.eq_ignore_ascii_case("none") => None,
+            Some(value) => Some(Operator::from_str(&value).ok_or_else(|| {
+                anyhow!("Unknown video_color tonemap '{value}' (expected reinhard, aces or none)")
+            })?),
+            None => Some(Operator::Reinhard),
+        };
+        let peak = match params.remove("peak") {
+            None => None,
+            Some(value) => match value_as_f64(&value) {
+                Some(peak) if peak > 0.0 && peak <= 10_000.0 => Some(peak),
+                _ => bail!(
+                    "video_color peak must be in nits, above 0 and at most 10000, got {value}"
+                ),
+            },
+        };
+        Ok(Self {
+            from,
+            to,
+            transfer,
+            operator,
+            peak,
+        })
+    }
+
+    /// The peak in nits to tone map from: the configured one, else for PQ
+    /// the content's MaxCLL or its mastering display's peak.
+    fn peak(&self, transfer: TransferFunction, hdr: &HdrMetadata) -> f64 {
+        let content_peak = || {
+            let max_cll = hdr.content_light.map(|light| f64::from(light.max_cll));
+            let mastering = hdr.mastering_display.map(|display| display.max_nits());
+            max_cll
+                .filter(|nits| *nits > 0.0)
+                .or(mastering)
+                .filter(|nits| (1.0..=10_000.0).contains(nits))
+        };
+        self.peak
+            .or_else(|| {
+                (transfer == TransferFunction::Pq)
+                    .then(content_peak)
+                    .flatten()
+            })
+            .unwrap_or(DEFAULT_PEAK)
+    }
+
+    fn record(&self, artifact: &mut Artifact, source: Option<(ColorSpace, TransferFunction, f64)>) {
+        artifact.metadata.insert(
+            "video.color_space".to_string(),
+            Value::String(space_name(self.to).to_string()),
+        );
+        artifact.metadata.insert(
+            "video.transfer".to_string(),
+            Value::String(transfer_name(TransferFunction::Sdr).to_string()),
+        );
+        artifact.metadata.remove("video.hdr");
+        let Some((space, transfer, peak)) = source else {
+            return;
+        };
+        artifact.metadata.insert(
+            "video_color.from".to_string(),
+            Value::String(space_name(space).to_string()),
+        );
+        artifact.metadata.insert(
+            "video_color.transfer".to_string(),
+            Value::String(transfer_name(transfer).to_string()),
+        );
+        if transfer != TransferFunction::Sdr {
+            let operator = self.operator.map_or("none", |operator| operator.as_str());
+            artifact.metadata.insert(
+                "video_color.tonemap".to_string(),
+                Value::String(operator.to_string()),
+            );
+            artifact
+                .metadata
+                .insert("video_color.peak".to_string(), json!(peak));
+        }
+    }
+}
+
+impl Stage for VideoColorStage {
+    fn name(&self) -> &'static str {
+        "video_color"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn plan(&self, artifact: &mut Artifact, _ctx: &PipelineContext) -> Result<()> {
+        self.record(artifact, None);
+        Ok(())
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let media = artifact.media_mut();
+        let video = media
+            .video
+            .as_mut()
+            .filter(|video| !video.frames.is_empty())
+            .ok_or_else(|| anyhow!("video_color stage requires decoded video frames"))?;
+        let source = self.from.unwrap_or(video.color_space);
+        let transfer = self.transfer.unwrap_or(video.transfer);
+        let peak = self.peak(transfer, &video.hdr);
+        let conversion = Conversion::new(self, source, transfer, peak);
+        if !conversion.is_identity() {
+            for frame in &mut video.frames {
+                ctx.cancellation.check()?;
+                conversion.convert_frame(frame)?;
+            }
+        }
+        video.color_space = self.to;
+        video.transfer = TransferFunction::Sdr;
+        video.hdr = HdrMetadata::default();
+        self.record(artifact, Some((source, transfer, peak)));
+        Ok(())
+    }
+}
+
+/// The per-sample work of one source-to-target conversion.
+struct Conversion {
+    source: (f64, f64),
+    target: (f64, f64),
+    transfer: TransferFunction,
+    /// Linear source RGB to linear target RGB.
+    gamut: Matrix,
+    operator: Option<Operator>,
+    peak: f64,
+}
+
+impl Conversion {
+    fn new(
+        stage: &VideoColorStage,
+        source: ColorSpace,
+        transfer: TransferFunction,
+        peak: f64,
+    ) -> Self {
+        let weights = |space: ColorSpace| {
+            let (kr, kb) = space.luma_weights();
+            (f64::from(kr), f64::from(kb))
+        };
+        let gamut = multiply(
+            &invert(&rgb_to_xyz(&primaries(stage.to))),
+            &rgb_to_xyz(&primaries(source)),
+        );
+        Self {
+            source: weights(source),
+            target: weights(stage.to),
+            transfer,
+            gamut,
+            operator: stage.operator,
+            peak,
+        }
+    }
+
+    /// Whether frames come out as they went in: SDR with the same matrix
+    /// and primaries on both sides.
+    fn is_identity(&self) -> bool {
+        self.transfer == TransferFunction::Sdr
+            && self.source == self.target
+            && self.gamut.iter().enumerate().all(|(row, values)| {
+                values.iter().enumerate().all(|(column, value)| {
+                    (value - if row == column { 1.0 } else { 0.0 }).abs() < 1e-9
+                })
+            })
+    }
+
+    /// Converts non-linear source R'G'B' in 0..=1 to the target's.
+    fn rgb(&self, rgb: [f64; 3]) -> [f64; 3] {
+        let linear = self.linearize(rgb);
+        let linear = apply(&self.gamut, linear).map(|value| value.max(0.0));
+        let display = match (self.transfer, self.operator) {
+            (TransferFunction::Sdr, _) | (_, None) => linear,
+            (_, Some(operator)) => operator.map(linear, Some(self.peak / REFERENCE_WHITE)),
+        };
+        display.map(|value| value.clamp(0.0, 1.0).powf(1.0 / SDR_GAMMA))
+    }
+
+    /// Linear light relative to SDR reference white.
+    fn linearize(&self, rgb: [f64; 3]) -> [f64; 3] {
+        match self.transfer {
+            TransferFunction::Sdr => rgb.map(|value| value.clamp(0.0, 1.0).powf(SDR_GAMMA)),
+            TransferFunction::Pq => rgb.map(|value| pq_to_nits(value) / REFERENCE_WHITE),
+            TransferFunction::Hlg => {
+                // The BT.2100 OOTF renders scene light for a display of
+                // `peak` nits, with a system gamma growing with the peak.
+                let scene = rgb.map(hlg_to_scene);
+                let (kr, kb) = self.source;
+                let luminance = kr * scene[0] + (1.0 - kr - kb) * scene[1] + kb * scene[2];
+                let gamma = 1.2 + 0.42 * (self.peak / 1000.0).log10();
+                let gain = self.peak * luminance.powf(gamma - 1.0) / REFERENCE_WHITE;
+                scene.map(|value| value * gain)
+            }
+        }
+    }
+
+    /// Converts one limited range Y'CbCr sample, returning unrounded code
+    /// values.
+    fn yuv(&self, [y, u, v]: [u8; 3]) -> [f64; 3] {
+        let (kr, kb) = self.source;
+        let luma = (f64::from(y) - 16.0) / 219.0;
+        let cb = (f64::from(u) - 128.0) / 224.0;
+        let cr = (f64::from(v) - 128.0) / 224.0;
+        let red = luma + 2.0 * (1.0 - kr) * cr;
+        let blue = luma + 2.0 * (1.0 - kb) * cb;
+        let green = (luma - kr * red - kb * blue) / (1.0 - kr - kb);
+        let [red, green, blue] = self.rgb([red, green, blue]);
+
+        let (kr, kb) = self.target;
+        let luma = kr * red + (1.0 - kr - kb) * green + kb * blue;
+        [
+            16.0 + 219.0 * luma,
+            128.0 + 224.0 * (blue - luma) / (2.0 * (1.0 - kb)),
+            128.0 + 224.0 * (red - luma) / (2.0 * (1.0 - kr)),
+        ]
+    }
+
+    fn convert_frame(&self, frame: &mut VideoFrame) -> Result<()> {
+        let (width, height) = (frame.width as usize, frame.height as usize);
+        match &mut frame.data {
+            FramePlanes::Yuv444 { y, u, v } => {
+                if y.len() < width * height || u.len() < width * height || v.len() < width * height
+                {
+                    bail!("frame planes are smaller than {width}x{height}");
+                }
+                for index in 0..width * height {
+                    let [luma, cb, cr] = self.yuv([y[index], u[index], v[index]]);
+                    (y[index], u[index], v[index]) = (code(luma), code(cb), code(cr));
+                }
+            }
+            FramePlanes::Yuv420 { y, u, v } => {
+                let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+                let chroma_len = chroma_width * chroma_height;
+                if y.len() < width * height || u.len() < chroma_len || v.len() < chroma_len {
+                    bail!("frame planes are smaller than {width}x{height}");
+                }
+                // Each chroma sample becomes the average of the samples
+                // converted with the luma it covers.
+                for chroma_row in 0..chroma_height {
+                    for chroma_column in 0..chroma_width {
+                        let chroma = chroma_row * chroma_width + chroma_column;
+                        let (mut cb_sum, mut cr_sum, mut count) = (0.0, 0.0, 0.0);
+                        for row in 2 * chroma_row..(2 * chroma_row + 2).min(height) {
+                            for column in 2 * chroma_column..(2 * chroma_column + 2).min(width) {
+                                let index = row * width + column;
+                                let [luma, cb, cr] = self.yuv([y[index], u[chroma], v[chroma]]);
+                                y[index] = code(luma);
+                                cb_sum += cb;
+                                cr_sum += cr;
+                                count += 1.0;
+                            }
+                        }
+                        u[chroma] = code(cb_sum / count);
+                        v[chroma] = code(cr_sum / count);
+                    }
+                }
+            }
+            FramePlanes::Rgb(data) => {
+                for pixel in data.chunks_exact_mut(3) {
+                    self.convert_pixel(pixel);
+                }
+            }
+            FramePlanes::Rgba(data) => {
+                for pixel in data.chunks_exact_mut(4) {
+                    self.convert_pixel(&mut pixel[..3]);
+                }
+            }
+            FramePlanes::ExternalHandle => {
+                bail!("video_color needs frames in memory, not device handles")
+            }
+        }
+        Ok(())
+    }
+
+    fn convert_pixel(&self, pixel: &mut [u8]) {
+        let rgb = self.rgb([0, 1, 2].map(|channel| f64::from(pixel[channel]) / 255.0));
+        for (channel, value) in pixel.iter_mut().zip(rgb) {
+            *channel = code(value * 255.0);
+        }
+    }
+}
+
+fn code(value: f64) -> u8 {
+    value.round().clamp(0.0, 255.0) as u8
+}
+
+fn parse_space(value: &str, key: &str) -> Result<ColorSpace> {
+    Ok(match value.trim().to_ascii_lowercase().as_str() {
+        "bt601" | "rec601" | "smpte170m" => ColorSpace::Bt601,
+        "bt709" | "rec709" => ColorSpace::Bt709,
+        "bt2020" | "rec2020" => ColorSpace::Bt2020,
+        _ => bail!("Unknown video_color {key} '{value}' (expected bt601, bt709 or bt2020)"),
+    })
+}
+
+fn space_name(space: ColorSpace) -> &'static str {
+    match space {
+        ColorSpace::Bt601 => "bt601",
+        ColorSpace::Bt709 => "bt709",
+        ColorSpace::Bt2020 => "bt2020",
+        ColorSpace::Srgb => "srgb",
+        ColorSpace::Unknown => "unknown",
+    }
+}
+
+fn transfer_name(transfer: TransferFunction) -> &'static str {
+    match transfer {
+        TransferFunction::Sdr => "sdr",
+        TransferFunction::Pq => "pq",
+        TransferFunction::Hlg => "hlg",
+    }
+}
+
+/// Primaries of `space`; BT.709's when it is unknown, as for the matrix.
+fn primaries(space: ColorSpace) -> Primaries {
+    match space {
+        ColorSpace::Bt601 => BT601_PRIMARIES,
+        ColorSpace::Bt2020 => BT2020_PRIMARIES,
+        ColorSpace::Bt709 | ColorSpace::Srgb | ColorSpace::Unknown => BT709_PRIMARIES,
+    }
+}
+
+/// The matrix taking linear RGB on `primaries` to CIE XYZ, scaled so RGB
+/// white lands on the white point with Y = 1.
+fn rgb_to_xyz(primaries: &Primaries) -> Matrix {
+    let xyz = |[x, y]: [f64; 2]| [x / y, 1.0, (1.0 - x - y) / y];
+    let [red, green, blue, white] = primaries.map(xyz);
+    let columns = [
+        [red[0], green[0], blue[0]],
+        [red[1], green[1], blue[1]],
+        [red[2], green[2], blue[2]],
+    ];
+    let scale = apply(&invert(&columns), white);
+    columns.map(|row| [0, 1, 2].map(|column| row[column] * scale[column]))
+}
+
+fn apply(matrix: &Matrix, vector: [f64; 3]) -> [f64; 3] {
+    matrix.map(|row| row[0] * vector[0] + row[1] * vector[1] + row[2] * vector[2])
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    [0, 1, 2].map(|row| {
+        [0, 1, 2].map(|column| (0..3).map(|index| a[row][index] * b[index][column]).sum())
+    })
+}
+
+fn invert(m: &Matrix) -> Matrix {
+    let cofactor = |row: usize, column: usize| {
+        let (r0, r1) = ((row + 1) % 3, (row + 2) % 3);
+        let (c0, c1) = ((column + 1) % 3, (column + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+    let determinant = (0..3)
+        .map(|column| m[0][column] * cofactor(0, column))
+        .sum::<f64>();
+    // The inverse is the transposed cofactor matrix over the determinant.
+    [0, 1, 2].map(|row| [0, 1, 2].map(|column| cofactor(column, row) / determinant))
+}
+
+/// The SMPTE ST 2084 EOTF: luminance in nits of a PQ signal in 0..=1.
+fn pq_to_nits(signal: f64) -> f64 {
+    const M1: f64 = 2610.0 / 16384.0;
+    const M2: f64 = 2523.0 / 4096.0 * 128.0;
+    const C1: f64 = 3424.0 / 4096.0;
+    const C2: f64 = 2413.0 / 4096.0 * 32.0;
+    const C3: f64 = 2392.0 / 4096.0 * 32.0;
+    let power = signal.clamp(0.0, 1.0).powf(1.0 / M2);
+    10_000.0 * ((power - C1).max(0.0) / (C2 - C3 * power)).powf(1.0 / M1)
+}
+
+/// The inverse of the HLG OETF: normalized scene light of a signal in
+/// 0..=1.
+fn hlg_to_scene(signal: f64) -> f64 {
+    const A: f64 = 0.178_832_77;
+    const B: f64 = 0.284_668_92;
+    const C: f64 = 0.559_910_73;
+    let signal = signal.clamp(0.0, 1.0);
+    if signal <= 0.5 {
+        signal * signal / 3.0
+    } else {
+        (((signal - C) / A).exp() + B) / 12.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::stages::from_json;
+    use crate::video::{ContentLightLevel, MasteringDisplay, PixelFormat};
+
+    fn gray(luma: u8) -> VideoFrame {
+        VideoFrame {
+            width: 2,
+            height: 2,
+            pixel_format: PixelFormat::Yuv420,
+            data: FramePlanes::Yuv420 {
+                y: vec![luma; 4],
+                u: vec![128],
+                v: vec![128],
+            },
+            timestamp: Duration::ZERO,
+            duration: Duration::from_millis(40),
+            keyframe: true,
+        }
+    }
+
+    fn luma(frame: &VideoFrame) -> u8 {
+        let FramePlanes::Yuv420 { y, u, v } = &frame.data else {
+            unreachable!()
+        };
+        assert!(
+            u[0].abs_diff(128) <= 1 && v[0].abs_diff(128) <= 1,
+            "gray picked up a tint"
+        );
+        y[0]
+    }
+
+    #[test]
+    fn transfer_curves_and_gamut_matrices_match_the_standards() {
+        // PQ puts 100 nits at 0.508 and tops out at 10000.
+        assert!((pq_to_nits(0.508_078) - 100.0).abs() < 0.1);
+        assert!((pq_to_nits(1.0) - 10_000.0).abs() < 1e-6);
+        assert!((hlg_to_scene(0.5) - 1.0 / 12.0).abs() < 1e-12);
+        assert!((hlg_to_scene(1.0) - 1.0).abs() < 1e-6);
+
+        // BT.2100's BT.2020 to BT.709 conversion.
+        let gamut = multiply(
+            &invert(&rgb_to_xyz(&BT709_PRIMARIES)),
+            &rgb_to_xyz(&BT2020_PRIMARIES),
+        );
+        for (row, expected) in gamut.iter().zip([
+            [1.6605, -0.5876, -0.0728],
+            [-0.1246, 1.1329, -0.0083],
+            [-0.0182, -0.1006, 1.1187],
+        ]) {
+            for (value, expected) in row.iter().zip(expected) {
+                assert!((value - expected).abs() < 1e-3, "{gamut:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn tone_maps_hdr_and_keeps_gray_neutral() {
+        let to_sdr = |params: Value, transfer, luma_in| {
+            let stage = from_json(VideoColorStage::from_params, params).unwrap();
+            let conversion = Conversion::new(&stage, ColorSpace::Bt2020, transfer, DEFAULT_PEAK);
+            let mut frame = gray(luma_in);
+            conversion.convert_frame(&mut frame).unwrap();
+            luma(&frame)
+        };
+        // PQ at the 1000 nit mastering peak (0.7518) reaches SDR white;
+        // reference white (0.5807) lands in the upper midtones.
+        assert_eq!(to_sdr(json!({}), TransferFunction::Pq, 181), 235);
+        let midtone = to_sdr(json!({}), TransferFunction::Pq, 143);
+        assert!((170..=195).contains(&midtone), "{midtone}");
+        // Clipping keeps reference white at white.
+        assert!(to_sdr(json!({ "tonemap": "none" }), TransferFunction::Pq, 143) >= 233);
+        // HLG's nominal peak is white too.
+        assert_eq!(to_sdr(json!({}), TransferFunction::Hlg, 235), 235);
+
+        // Gray is gray in every space, and an SDR conversion to the same
+        // space leaves frames alone.
+        let stage = from_json(VideoColorStage::from_params, json!({ "to": "bt709" })).unwrap();
+        let conversion = Conversion::new(
+            &stage,
+            ColorSpace::Bt601,
+            TransferFunction::Sdr,
+            DEFAULT_PEAK,
+        );
+        let mut frame = gray(126);
+        conversion.convert_frame(&mut frame).unwrap();
+        assert!(luma(&frame).abs_diff(126) <= 1);
+        assert!(!conversion.is_identity());
+        let unknown = Conversion::new(
+            &stage,
+            ColorSpace::Unknown,
+            TransferFunction::Sdr,
+            DEFAULT_PEAK,
+        );
+        assert!(unknown.is_identity());
+    }
+
+    #[test]
+    fn pq_peaks_default_to_the_content_light_levels() {
+        let mut hdr = HdrMetadata {
+            mastering_display: Some(MasteringDisplay {
+                primaries: [[35400, 14600], [8500, 39850], [6550, 2300]],
+                white_point: [15635, 16450],
+                max_luminance: 40_000_000,
+                min_luminance: 50,
+            }),
+            content_light: Some(ContentLightLevel {
+                max_cll: 1500,
+                max_fall: 300,
+            }),
+        };
+        let color = from_json(VideoColorStage::from_params, json!({})).unwrap();
+        assert_eq!(color.peak(TransferFunction::Pq, &hdr), 1500.0);
+        assert_eq!(color.peak(TransferFunction::Hlg, &hdr), DEFAULT_PEAK);
+        hdr.content_light = None;
+        assert_eq!(color.peak(TransferFunction::Pq, &hdr), 4000.0);
+        let color = from_json(VideoColorStage::from_params, json!({ "peak": 600 })).unwrap();
+        assert_eq!(color.peak(TransferFunction::Pq, &hdr), 600.0);
+        let none = HdrMetadata::default();
+        assert_eq!(
+            from_json(VideoColorStage::from_params, json!({}))
+                .unwrap()
+                .peak(TransferFunction::Pq, &none),
+            DEFAULT_PEAK
+        );
+    }
+
+    #[test]
+    fn validates_settings() {
+        let color = from_json(
+            VideoColorStage::from_params,
+            json!({ "from": "Rec2020", "transfer": "HDR10", "peak": 4000 }),
+        )
+        .unwrap();
+        assert_eq!(color.from, Some(ColorSpace::Bt2020));
+        assert_eq!(color.to, ColorSpace::Bt709);
+        assert_eq!(color.transfer, Some(TransferFunction::Pq));
+        assert_eq!(color.operator, Some(Operator::Reinhard));
+        assert_eq!(color.peak, Some(4000.0));
+        assert!(
+            from_json(VideoColorStage::from_params, json!({ "tonemap": "none" }))
+                .unwrap()
+                .operator
+                .is_none()
+        );
+
+        assert!(from_json(VideoColorStage::from_params, json!({ "to": "srgb" })).is_err());
+        assert!(from_json(VideoColorStage::from_params, json!({ "transfer": "log" })).is_err());
+        assert!(from_json(VideoColorStage::from_params, json!({ "tonemap": "hable" })).is_err());
+        assert!(from_json(VideoColorStage::from_params, json!({ "peak": 0 })).is_err());
+        assert!(from_json(VideoColorStage::from_params, json!({ "peak": 20000 })).is_err());
+    }
+}
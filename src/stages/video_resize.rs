@@ -0,0 +1,370 @@
+use anyhow::{Result, anyhow, bail};
+use image::imageops::{self, FilterType as ResizeFilter};
+use image::{GrayImage, ImageBuffer, Pixel, RgbImage, RgbaImage};
+use serde_json::{Value, json};
+
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+use crate::video::{FramePlanes, VideoFrame};
+
+use super::{ResizeMode, format_filter, map_filter, take_string, take_u32};
+
+/// Scales every decoded video frame, fitting the target box the way
+/// `resize` does for images. Each plane is resampled at its own size, so
+/// 4:2:0 chroma stays at half the luma resolution; 4:2:0 output dimensions
+/// are rounded down to even numbers, as encoders need them.
+pub struct VideoResizeStage {
+    width: u32,
+    height: u32,
+    fit: ResizeMode,
+    filter: ResizeFilter,
+}
+
+/// Where to cut the source frame and what size to scale the cut to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Geometry {
+    crop: (u32, u32, u32, u32),
+    output: (u32, u32),
+}
+
+impl VideoResizeStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let (width, height) = match (
+            take_u32(&mut params, "width"),
+            take_u32(&mut params, "height"),
+        ) {
+            (Some(width), Some(height)) if width > 0 && height > 0 => (width, height),
+            _ => bail!("video_resize stage requires positive 'width' and 'height' parameters"),
+        };
+        let fit = match take_string(&mut params, "fit") {
+            Some(fit) => ResizeMode::from_str(&fit).ok_or_else(|| {
+                anyhow!("unknown video_resize fit '{fit}' (expected inside, cover or exact)")
+            })?,
+            None => ResizeMode::Inside,
+        };
+        let filter = match take_string(&mut params, "method") {
+            Some(method) => map_filter(method.clone())
+                .ok_or_else(|| anyhow!("unknown video_resize method '{method}'"))?,
+            None => ResizeFilter::CatmullRom,
+        };
+        Ok(Self {
+            width,
+            height,
+            fit,
+            filter,
+        })
+    }
+
+    /// The crop and output size for a `width` x `height` frame. `even`
+    /// keeps 4:2:0 chroma aligned with the luma it covers.
+    fn geometry(&self, width: u32, height: u32, even: bool) -> Geometry {
+        let align = |value: u32| {
+            if even {
+                (value & !1).max(2)
+            } else {
+                value.max(1)
+            }
+        };
+        let full = (0, 0, width, height);
+        match self.fit {
+            ResizeMode::Exact => Geometry {
+                crop: full,
+                output: (align(self.width), align(self.height)),
+            },
+            ResizeMode::Inside => {
+                let ratio = (f64::from(self.width) / f64::from(width))
+                    .min(f64::from(self.height) / f64::from(height));
+                Geometry {
+                    crop: full,
+                    output: (
+                        align((f64::from(width) * ratio).round() as u32),
+                        align((f64::from(height) * ratio).round() as u32),
+                    ),
+                }
+            }
+            ResizeMode::Cover => {
+                // The largest centred window with the target's aspect ratio.
+                let target = f64::from(self.width) / f64::from(self.height);
+                let (crop_width, crop_height) = if f64::from(width) / f64::from(height) > target {
+                    (
+                        align((f64::from(height) * target).round() as u32).min(width),
+                        height,
+                    )
+                } else {
+                    (
+                        width,
+                        align((f64::from(width) / target).round() as u32).min(height),
+                    )
+                };
+                let offset = |spare: u32| if even { (spare / 2) & !1 } else { spare / 2 };
+                Geometry {
+                    crop: (
+                        offset(width - crop_width),
+                        offset(height - crop_height),
+                        crop_width,
+                        crop_height,
+                    ),
+                    output: (align(self.width), align(self.height)),
+                }
+            }
+        }
+    }
+
+    fn resize_frame(&self, frame: &mut VideoFrame) -> Result<()> {
+        let (width, height) = (frame.width, frame.height);
+        let even = matches!(frame.data, FramePlanes::Yuv420 { .. });
+        let Geometry { crop, output } = self.geometry(width, height, even);
+        if crop == (0, 0, width, height) && output == (width, height) {
+            return Ok(());
+        }
+        frame.data = match std::mem::replace(&mut frame.data, FramePlanes::ExternalHandle) {
+            FramePlanes::Yuv420 {
+                mut y,
+                mut u,
+                mut v,
+            } => {
+                self.resize_plane(&mut y, (width, height), crop, output)?;
+                let (x, top, crop_width, crop_height) = crop;
+                for plane in [&mut u, &mut v] {
+                    self.resize_plane(
+                        plane,
+                        (width.div_ceil(2), height.div_ceil(2)),
+                        (x / 2, top / 2, crop_width / 2, crop_height / 2),
+                        (output.0 / 2, output.1 / 2),
+                    )?;
+                }
+                FramePlanes::Yuv420 { y, u, v }
+            }
+            FramePlanes::Yuv444 {
+                mut y,
+                mut u,
+                mut v,
+            } => {
+                for plane in [&mut y, &mut u, &mut v] {
+                    self.resize_plane(plane, (width, height), crop, output)?;
+                }
+                FramePlanes::Yuv444 { y, u, v }
+            }
+            FramePlanes::Rgb(data) => {
+                let image = RgbImage::from_raw(width, height, data)
+                    .ok_or_else(|| anyhow!("RGB frame data does not match the frame size"))?;
+                FramePlanes::Rgb(self.crop_and_scale(&image, crop, output).into_raw())
+            }
+            FramePlanes::Rgba(data) => {
+                let image = RgbaImage::from_raw(width, height, data)
+                    .ok_or_else(|| anyhow!("RGBA frame data does not match the frame size"))?;
+                FramePlanes::Rgba(self.crop_and_scale(&image, crop, output).into_raw())
+            }
+            FramePlanes::ExternalHandle => {
+                bail!("video_resize needs frames in memory, not device handles")
+            }
+        };
+        frame.width = output.0;
+        frame.height = output.1;
+        Ok(())
+    }
+
+    /// Crops a `size` plane to `crop` and scales the cut to `output`.
+    fn resize_plane(
+        &self,
+        plane: &mut Vec<u8>,
+        size: (u32, u32),
+        crop: (u32, u32, u32, u32),
+        output: (u32, u32),
+    ) -> Result<()> {
+        let image = GrayImage::from_raw(size.0, size.1, std::mem::take(plane))
+            .ok_or_else(|| anyhow!("frame plane does not match the frame size"))?;
+        *plane = self.crop_and_scale(&image, crop, output).into_raw();
+        Ok(())
+    }
+
+    fn crop_and_scale<P>(
+        &self,
+        image: &ImageBuffer<P, Vec<u8>>,
+        (x, y, width, height): (u32, u32, u32, u32),
+        (out_width, out_height): (u32, u32),
+    ) -> ImageBuffer<P, Vec<u8>>
+    where
+        P: Pixel<Subpixel = u8> + 'static,
+    {
+        if (x, y, width, height) == (0, 0, image.width(), image.height()) {
+            imageops::resize(image, out_width, out_height, self.filter)
+        } else {
+            let cropped = imageops::crop_imm(image, x, y, width, height).to_image();
+            imageops::resize(&cropped, out_width, out_height, self.filter)
+        }
+    }
+}
+
+impl Stage for VideoResizeStage {
+    fn name(&self) -> &'static str {
+        "video_resize"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn plan(&self, artifact: &mut Artifact, _ctx: &PipelineContext) -> Result<()> {
+        let dimension = |key: &str| {
+            artifact
+                .metadata
+                .get(key)
+                .and_then(Value::as_u64)
+                .and_then(|value| u32::try_from(value).ok())
+        };
+        if let (Some(width), Some(height)) = (dimension("video.width"), dimension("video.height"))
+            && width > 0
+            && height > 0
+        {
+            // Decoded frames are 4:2:0 unless the source says otherwise.
+            let (width, height) = self.geometry(width, height, true).output;
+            self.record(artifact, width, height);
+        }
+        Ok(())
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        _ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let media = artifact.media_mut();
+        let video = media
+            .video
+            .as_mut()
+            .filter(|video| !video.frames.is_empty())
+            .ok_or_else(|| anyhow!("video_resize stage requires decoded video frames"))?;
+        for frame in &mut video.frames {
+            self.resize_frame(frame)?;
+        }
+        let (width, height) = (video.frames[0].width, video.frames[0].height);
+        self.record(artifact, width, height);
+        Ok(())
+    }
+}
+
+impl VideoResizeStage {
+    fn record(&self, artifact: &mut Artifact, width: u32, height: u32) {
+        artifact
+            .metadata
+            .insert("video.width".to_string(), json!(width));
+        artifact
+            .metadata
+            .insert("video.height".to_string(), json!(height));
+        artifact
+            .metadata
+            .insert("video_resize.width".to_string(), json!(self.width));
+        artifact
+            .metadata
+            .insert("video_resize.height".to_string(), json!(self.height));
+        artifact.metadata.insert(
+            "video_resize.filter".to_string(),
+            Value::String(format_filter(self.filter)),
+        );
+        artifact.metadata.insert(
+            "video_resize.mode".to_string(),
+            Value::String(self.fit.as_str().to_string()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::stages::from_json;
+    use crate::video::PixelFormat;
+
+    fn yuv420(width: u32, height: u32) -> VideoFrame {
+        let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+        VideoFrame {
+            width,
+            height,
+            pixel_format: PixelFormat::Yuv420,
+            data: FramePlanes::Yuv420 {
+                y: vec![200; (width * height) as usize],
+                u: vec![90; (chroma_width * chroma_height) as usize],
+                v: vec![160; (chroma_width * chroma_height) as usize],
+            },
+            timestamp: Duration::ZERO,
+            duration: Duration::from_millis(40),
+            keyframe: true,
+        }
+    }
+
+    #[test]
+    fn inside_keeps_the_aspect_ratio_on_even_sizes() {
+        let stage = from_json(
+            VideoResizeStage::from_params,
+            json!({ "width": 1920, "height": 1080 }),
+        )
+        .unwrap();
+        assert_eq!(
+            stage.geometry(3840, 2160, true),
+            Geometry {
+                crop: (0, 0, 3840, 2160),
+                output: (1920, 1080),
+            }
+        );
+        assert_eq!(stage.geometry(1600, 1200, true).output, (1440, 1080));
+        assert_eq!(stage.geometry(1001, 1001, true).output, (1080, 1080));
+        assert_eq!(stage.geometry(101, 33, false).output, (1920, 627));
+        assert_eq!(stage.geometry(101, 33, true).output, (1920, 626));
+    }
+
+    #[test]
+    fn cover_crops_a_centred_even_window() {
+        let stage = from_json(
+            VideoResizeStage::from_params,
+            json!({ "width": 1280, "height": 720, "fit": "cover" }),
+        )
+        .unwrap();
+        assert_eq!(
+            stage.geometry(1440, 1080, true),
+            Geometry {
+                crop: (0, 134, 1440, 810),
+                output: (1280, 720),
+            }
+        );
+        assert_eq!(
+            stage.geometry(3000, 720, true),
+            Geometry {
+                crop: (860, 0, 1280, 720),
+                output: (1280, 720),
+            }
+        );
+    }
+
+    #[test]
+    fn scales_chroma_planes_at_half_resolution() {
+        let stage = from_json(
+            VideoResizeStage::from_params,
+            json!({ "width": 8, "height": 8, "fit": "exact" }),
+        )
+        .unwrap();
+        let mut frame = yuv420(16, 12);
+        stage.resize_frame(&mut frame).unwrap();
+        assert_eq!((frame.width, frame.height), (8, 8));
+        let FramePlanes::Yuv420 { y, u, v } = &frame.data else {
+            panic!("expected 4:2:0 planes");
+        };
+        assert_eq!(y.len(), 64);
+        assert_eq!(u.len(), 16);
+        assert_eq!(v.len(), 16);
+        assert!(y.iter().all(|&sample| sample == 200));
+        assert!(u.iter().all(|&sample| sample == 90));
+        assert!(v.iter().all(|&sample| sample == 160));
+    }
+
+    #[test]
+    fn rejects_missing_or_unknown_settings() {
+        let from = |params| from_json(VideoResizeStage::from_params, params);
+        assert!(from(json!({ "width": 1280 })).is_err());
+        assert!(from(json!({ "width": 0, "height": 720 })).is_err());
+        assert!(from(json!({ "width": 1280, "height": 720, "fit": "fill" })).is_err());
+        assert!(from(json!({ "width": 1280, "height": 720, "method": "cubic" })).is_err());
+    }
+}
@@ -0,0 +1,347 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail};
+use image::{DynamicImage, ImageFormat};
+use serde_json::{Map, Value, json};
+use tracing::info;
+
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+use crate::structure;
+use crate::video::VideoStream;
+
+use super::thumbnails::{side_image_path, write_side_image};
+use super::{format_extension, format_from_label, take_string};
+
+const DEFAULT_STRUCTURE: &str = "{stem}-poster-{index}.{ext}";
+
+/// Metadata key listing the poster frames written for an input.
+pub const VIDEO_THUMBNAILS_KEY: &str = "video_thumbnails";
+
+/// Where in the video to take a frame from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum Position {
+    Time(Duration),
+    /// Share of the stream duration, 0 to 100.
+    Percent(f64),
+}
+
+impl Position {
+    pub(super) fn resolve(self, duration: Duration) -> Duration {
+        match self {
+            Self::Time(time) => time,
+            Self::Percent(percent) => duration.mul_f64(percent / 100.0),
+        }
+    }
+}
+
+/// Writes the decoded frame shown at each position in `at` as an image,
+/// through the same encoders as `encode`, so a recipe can emit posters next
+/// to its transcode. Each image is named by `structure`, where `{index}` is
+/// the position's place in the list (from 1) and `{time}` the frame's
+/// timestamp in milliseconds. The decoded video is left as it is.
+pub struct VideoThumbnailStage {
+    positions: Vec<Position>,
+    structure: String,
+    format: ImageFormat,
+    extension: String,
+    /// Encoder options (`quality`, `lossless`, ...) shared by every image.
+    options: StageParameters,
+}
+
+impl VideoThumbnailStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let positions = match params.remove("at") {
+            Some(Value::Array(entries)) if !entries.is_empty() => entries
+                .iter()
+                .map(parse_position)
+                .collect::<Result<Vec<_>>>()?,
+            Some(Value::Array(_)) | None => {
+                bail!("video_thumbnail stage requires an 'at' timestamp, percentage or list")
+            }
+            Some(entry) => vec![parse_position(&entry)?],
+        };
+        let structure =
+            take_string(&mut params, "structure").unwrap_or_else(|| DEFAULT_STRUCTURE.to_string());
+        structure::validate(&structure)
+            .with_context(|| format!("Invalid video_thumbnail structure '{structure}'"))?;
+        let label = take_string(&mut params, "format").unwrap_or_else(|| "jpeg".to_string());
+        let format = format_from_label(&label)
+            .ok_or_else(|| anyhow!("unsupported video_thumbnail format '{label}'"))?;
+        let extension = take_string(&mut params, "extension")
+            .unwrap_or_else(|| format_extension(format).to_string());
+        Ok(Self {
+            positions,
+            structure,
+            format,
+            extension,
+            options: params,
+        })
+    }
+
+    /// Renders the path of the `index`th image; `time` is unknown in plans
+    /// of percentage positions, whose stream duration is not known yet.
+    fn output_path(
+        &self,
+        artifact: &Artifact,
+        ctx: &PipelineContext,
+        index: usize,
+        time: Option<Duration>,
+        dimensions: Option<(u32, u32)>,
+    ) -> Result<PathBuf> {
+        let mut fields = Map::new();
+        fields.insert("index".to_string(), Value::String((index + 1).to_string()));
+        if let Some(time) = time {
+            fields.insert(
+                "time".to_string(),
+                Value::String(time.as_millis().to_string()),
+            );
+        }
+        side_image_path(
+            artifact,
+            ctx,
+            &self.structure,
+            &self.extension,
+            fields,
+            dimensions,
+        )
+    }
+}
+
+impl Stage for VideoThumbnailStage {
+    fn name(&self) -> &'static str {
+        "video_thumbnail"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    /// A cache replay restores the main output only, not the posters.
+    fn cacheable(&self) -> bool {
+        false
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let video = artifact
+            .media()
+            .video
+            .as_ref()
+            .filter(|video| !video.frames.is_empty())
+            .ok_or_else(|| anyhow!("video_thumbnail stage requires decoded video frames"))?;
+        let duration = stream_duration(video);
+        let mut written = Vec::with_capacity(self.positions.len());
+        for (index, position) in self.positions.iter().enumerate() {
+            ctx.cancellation.check()?;
+            let frame_index = frame_at(video, position.resolve(duration));
+            let frame = &video.frames[frame_index];
+            let path = self.output_path(
+                artifact,
+                ctx,
+                index,
+                Some(frame.timestamp),
+                Some((frame.width, frame.height)),
+            )?;
+            let mut entry = json!({
+                "index": index + 1,
+                "frame": frame_index,
+                "time": frame.timestamp.as_secs_f64(),
+                "width": frame.width,
+                "height": frame.height,
+                "path": path.to_string_lossy(),
+            });
+            if let Some(reason) = ctx.overwrite.keep_reason(&artifact.input_path, &path) {
+                info!(output = %path.display(), reason, "Keeping existing poster frame");
+                entry["skipped"] = Value::String(reason.to_string());
+                written.push(entry);
+                continue;
+            }
+
+            let image = DynamicImage::ImageRgb8(
+                frame
+                    .to_rgb(video.color_space)
+                    .with_context(|| format!("Failed to convert frame {frame_index} to RGB"))?,
+            );
+            write_side_image(&path, &image, self.format, &self.options, &mut entry)
+                .context("Failed to write poster frame")?;
+            written.push(entry);
+        }
+        artifact
+            .metadata
+            .insert(VIDEO_THUMBNAILS_KEY.to_string(), Value::Array(written));
+        Ok(())
+    }
+
+    fn plan(&self, artifact: &mut Artifact, ctx: &PipelineContext) -> Result<()> {
+        let mut planned = Vec::with_capacity(self.positions.len());
+        for (index, position) in self.positions.iter().enumerate() {
+            let time = match position {
+                Position::Time(time) => Some(*time),
+                Position::Percent(_) => None,
+            };
+            let path = self.output_path(artifact, ctx, index, time, None)?;
+            let mut entry = json!({
+                "index": index + 1,
+                "path": path.to_string_lossy(),
+            });
+            if let Some(reason) = ctx.overwrite.keep_reason(&artifact.input_path, &path) {
+                entry["skipped"] = Value::String(reason.to_string());
+            }
+            planned.push(entry);
+        }
+        artifact
+            .metadata
+            .insert(VIDEO_THUMBNAILS_KEY.to_string(), Value::Array(planned));
+        Ok(())
+    }
+}
+
+/// A number of seconds, or a string: `"50%"`, `"12.5"` or `"[hh:]mm:ss[.fff]"`.
+pub(super) fn parse_position(entry: &Value) -> Result<Position> {
+    let invalid = || anyhow!("position must be seconds, a percentage or [hh:]mm:ss, got {entry}");
+    let seconds = match entry {
+        Value::Number(number) => number.as_f64().ok_or_else(invalid)?,
+        Value::String(text) => {
+            let text = text.trim();
+            if let Some(percent) = text.strip_suffix('%') {
+                let percent: f64 = percent.trim().parse().map_err(|_| invalid())?;
+                if !(0.0..=100.0).contains(&percent) {
+                    bail!("percentage must be between 0 and 100, got {entry}");
+                }
+                return Ok(Position::Percent(percent));
+            }
+            let mut seconds = 0.0;
+            let fields: Vec<&str> = text.split(':').collect();
+            if fields.len() > 3 {
+                return Err(invalid());
+            }
+            for (position, field) in fields.iter().enumerate() {
+                let value: f64 = field.parse().map_err(|_| invalid())?;
+                // Only the seconds field may carry a fraction.
+                if position + 1 < fields.len() && (value.fract() != 0.0 || field.contains('.')) {
+                    return Err(invalid());
+                }
+                seconds = seconds * 60.0 + value;
+            }
+            seconds
+        }
+        _ => return Err(invalid()),
+    };
+    if !seconds.is_finite() || seconds < 0.0 {
+        return Err(invalid());
+    }
+    Ok(Position::Time(Duration::from_secs_f64(seconds)))
+}
+
+/// End of the last frame to be shown.
+pub(super) fn stream_duration(video: &VideoStream) -> Duration {
+    video
+        .frames
+        .iter()
+        .map(|frame| frame.timestamp + frame.duration)
+        .max()
+        .unwrap_or_default()
+}
+
+/// Index of the frame on screen at `time`: the latest one starting at or
+/// before it, or the earliest frame when `time` precedes them all.
+pub(super) fn frame_at(video: &VideoStream, time: Duration) -> usize {
+    let frames = video.frames.iter().enumerate();
+    frames
+        .clone()
+        .filter(|(_, frame)| frame.timestamp <= time)
+        .max_by_key(|(_, frame)| frame.timestamp)
+        .or_else(|| frames.min_by_key(|(_, frame)| frame.timestamp))
+        .map_or(0, |(index, _)| index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stages::from_json;
+    use crate::video::{
+        ColorSpace, FramePlanes, FrameRate, HdrMetadata, PixelFormat, TransferFunction, VideoCodec,
+        VideoFrame,
+    };
+
+    #[test]
+    fn parses_times_and_percentages() {
+        let parsed = from_json(
+            VideoThumbnailStage::from_params,
+            json!({ "at": [2, "1.5", "01:02.5", "1:00:00", "25%"] }),
+        )
+        .unwrap();
+        assert_eq!(
+            parsed.positions,
+            vec![
+                Position::Time(Duration::from_secs(2)),
+                Position::Time(Duration::from_millis(1500)),
+                Position::Time(Duration::from_millis(62_500)),
+                Position::Time(Duration::from_secs(3600)),
+                Position::Percent(25.0),
+            ]
+        );
+        assert_eq!(parsed.format, ImageFormat::Jpeg);
+        assert_eq!(parsed.extension, "jpg");
+        assert_eq!(
+            from_json(VideoThumbnailStage::from_params, json!({ "at": "50%" }))
+                .unwrap()
+                .positions
+                .len(),
+            1
+        );
+
+        assert!(from_json(VideoThumbnailStage::from_params, json!({})).is_err());
+        assert!(from_json(VideoThumbnailStage::from_params, json!({ "at": [] })).is_err());
+        assert!(from_json(VideoThumbnailStage::from_params, json!({ "at": -1 })).is_err());
+        assert!(from_json(VideoThumbnailStage::from_params, json!({ "at": "150%" })).is_err());
+        assert!(from_json(VideoThumbnailStage::from_params, json!({ "at": "1.5:00" })).is_err());
+        assert!(from_json(VideoThumbnailStage::from_params, json!({ "at": "soon" })).is_err());
+        assert!(
+            from_json(
+                VideoThumbnailStage::from_params,
+                json!({ "at": 1, "format": "mp4" })
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn picks_the_frame_on_screen() {
+        let frame = |millis: u64| VideoFrame {
+            width: 2,
+            height: 2,
+            pixel_format: PixelFormat::Yuv420,
+            data: FramePlanes::ExternalHandle,
+            timestamp: Duration::from_millis(millis),
+            duration: Duration::from_millis(40),
+            keyframe: millis == 0,
+        };
+        let video = VideoStream {
+            codec: VideoCodec::Raw,
+            frame_rate: FrameRate::Constant {
+                numerator: 25,
+                denominator: 1,
+            },
+            frames: vec![frame(0), frame(40), frame(80), frame(120)],
+            color_space: ColorSpace::Bt709,
+            transfer: TransferFunction::Sdr,
+            rotation: 0,
+            hdr: HdrMetadata::default(),
+        };
+        let duration = stream_duration(&video);
+        assert_eq!(duration, Duration::from_millis(160));
+        let at = |position: Position| frame_at(&video, position.resolve(duration));
+        assert_eq!(at(Position::Percent(0.0)), 0);
+        assert_eq!(at(Position::Percent(50.0)), 2);
+        assert_eq!(at(Position::Percent(100.0)), 3);
+        assert_eq!(at(Position::Time(Duration::from_millis(79))), 1);
+        assert_eq!(at(Position::Time(Duration::from_secs(60))), 3);
+    }
+}
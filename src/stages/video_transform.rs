@@ -0,0 +1,431 @@
+use anyhow::{Context, Result, anyhow, bail};
+use image::imageops;
+use image::{GrayImage, ImageBuffer, Pixel, RgbImage, RgbaImage};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::pipeline::{Artifact, PipelineContext, Stage, StageParameters};
+use crate::scheduler::StageDevice;
+use crate::video::{FramePlanes, VideoFrame};
+
+use super::rotate::{Flip, take_angle, take_flip};
+use super::take_bool;
+
+/// A crop window in the coordinates of the upright frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Crop {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Crop {
+    /// Checks the window against a `width` x `height` frame. `even` rounds
+    /// it inward to even coordinates, keeping 4:2:0 chroma aligned with the
+    /// luma it covers.
+    fn fit(self, width: u32, height: u32, even: bool) -> Result<Self> {
+        if self.x.saturating_add(self.width) > width || self.y.saturating_add(self.height) > height
+        {
+            bail!(
+                "video_transform crop {}x{} at {},{} does not fit {width}x{height} frames",
+                self.width,
+                self.height,
+                self.x,
+                self.y
+            );
+        }
+        if !even {
+            return Ok(self);
+        }
+        let (x, y) = (self.x.next_multiple_of(2), self.y.next_multiple_of(2));
+        let fitted = Self {
+            x,
+            y,
+            width: (self.x + self.width).saturating_sub(x) & !1,
+            height: (self.y + self.height).saturating_sub(y) & !1,
+        };
+        if fitted.width == 0 || fitted.height == 0 {
+            bail!("video_transform crop is too small for 4:2:0 frames");
+        }
+        Ok(fitted)
+    }
+}
+
+/// Crops, turns by quarter turns and mirrors every decoded video frame,
+/// plane by plane. Frames are first turned upright by the container's
+/// display rotation unless `autorotate` is false, in which case the
+/// rotation is kept for players and the transform applies to the frames as
+/// stored.
+pub struct VideoTransformStage {
+    crop: Option<Crop>,
+    angle: u32,
+    flip: Flip,
+    autorotate: bool,
+}
+
+impl VideoTransformStage {
+    pub fn from_params(mut params: StageParameters) -> Result<Self> {
+        let crop = match params.remove("crop") {
+            Some(value) => {
+                let crop: Crop = serde_json::from_value(value)
+                    .context("video_transform crop must be { x, y, width, height }")?;
+                if crop.width == 0 || crop.height == 0 {
+                    bail!("video_transform crop needs a positive width and height");
+                }
+                Some(crop)
+            }
+            None => None,
+        };
+        Ok(Self {
+            crop,
+            angle: take_angle(&mut params)?,
+            flip: take_flip(&mut params)?,
+            autorotate: take_bool(&mut params, "autorotate")?.unwrap_or(true),
+        })
+    }
+
+    /// The size of a `width` x `height` frame once transformed, with the
+    /// crop window fitted to its upright size.
+    fn geometry(
+        &self,
+        width: u32,
+        height: u32,
+        upright: u32,
+        even: bool,
+    ) -> Result<(Option<Crop>, (u32, u32))> {
+        let (width, height) = turned(upright, width, height);
+        let crop = self
+            .crop
+            .map(|crop| crop.fit(width, height, even))
+            .transpose()?;
+        let size = crop.map_or((width, height), |crop| (crop.width, crop.height));
+        Ok((crop, turned(self.angle, size.0, size.1)))
+    }
+
+    fn transform_frame(&self, frame: &mut VideoFrame, upright: u32) -> Result<()> {
+        let (width, height) = (frame.width, frame.height);
+        let even = matches!(frame.data, FramePlanes::Yuv420 { .. });
+        let (crop, output) = self.geometry(width, height, upright, even)?;
+        frame.data = match std::mem::replace(&mut frame.data, FramePlanes::ExternalHandle) {
+            FramePlanes::Yuv420 { y, u, v } => {
+                let chroma = (width.div_ceil(2), height.div_ceil(2));
+                let chroma_crop = crop.map(|crop| Crop {
+                    x: crop.x / 2,
+                    y: crop.y / 2,
+                    width: crop.width / 2,
+                    height: crop.height / 2,
+                });
+                FramePlanes::Yuv420 {
+                    y: self.transform_plane(y, (width, height), upright, crop)?,
+                    u: self.transform_plane(u, chroma, upright, chroma_crop)?,
+                    v: self.transform_plane(v, chroma, upright, chroma_crop)?,
+                }
+            }
+            FramePlanes::Yuv444 { y, u, v } => FramePlanes::Yuv444 {
+                y: self.transform_plane(y, (width, height), upright, crop)?,
+                u: self.transform_plane(u, (width, height), upright, crop)?,
+                v: self.transform_plane(v, (width, height), upright, crop)?,
+            },
+            FramePlanes::Rgb(data) => {
+                let image = RgbImage::from_raw(width, height, data)
+                    .ok_or_else(|| anyhow!("RGB frame data does not match the frame size"))?;
+                FramePlanes::Rgb(self.transform(image, upright, crop).into_raw())
+            }
+            FramePlanes::Rgba(data) => {
+                let image = RgbaImage::from_raw(width, height, data)
+                    .ok_or_else(|| anyhow!("RGBA frame data does not match the frame size"))?;
+                FramePlanes::Rgba(self.transform(image, upright, crop).into_raw())
+            }
+            FramePlanes::ExternalHandle => {
+                bail!("video_transform needs frames in memory, not device handles")
+            }
+        };
+        (frame.width, frame.height) = output;
+        Ok(())
+    }
+
+    fn transform_plane(
+        &self,
+        plane: Vec<u8>,
+        (width, height): (u32, u32),
+        upright: u32,
+        crop: Option<Crop>,
+    ) -> Result<Vec<u8>> {
+        let image = GrayImage::from_raw(width, height, plane)
+            .ok_or_else(|| anyhow!("frame plane does not match the frame size"))?;
+        Ok(self.transform(image, upright, crop).into_raw())
+    }
+
+    /// Turns `image` upright, crops it, then applies the stage's own turn
+    /// and mirror.
+    fn transform<P>(
+        &self,
+        image: ImageBuffer<P, Vec<u8>>,
+        upright: u32,
+        crop: Option<Crop>,
+    ) -> ImageBuffer<P, Vec<u8>>
+    where
+        P: Pixel<Subpixel = u8> + 'static,
+    {
+        let mut image = turn(image, upright);
+        if let Some(Crop {
+            x,
+            y,
+            width,
+            height,
+        }) = crop
+        {
+            image = imageops::crop_imm(&image, x, y, width, height).to_image();
+        }
+        let image = turn(image, self.angle);
+        match self.flip {
+            Flip::None => image,
+            Flip::Horizontal => imageops::flip_horizontal(&image),
+            Flip::Vertical => imageops::flip_vertical(&image),
+        }
+    }
+
+    fn record(&self, artifact: &mut Artifact, (width, height): (u32, u32), rotation: u16) {
+        artifact
+            .metadata
+            .insert("video.width".to_string(), json!(width));
+        artifact
+            .metadata
+            .insert("video.height".to_string(), json!(height));
+        artifact
+            .metadata
+            .insert("video.rotation".to_string(), json!(rotation));
+        artifact
+            .metadata
+            .insert("video_transform.angle".to_string(), json!(self.angle));
+        artifact.metadata.insert(
+            "video_transform.flip".to_string(),
+            Value::String(self.flip.as_str().to_string()),
+        );
+        if let Some(crop) = self.crop {
+            artifact.metadata.insert(
+                "video_transform.crop".to_string(),
+                json!({
+                    "x": crop.x,
+                    "y": crop.y,
+                    "width": crop.width,
+                    "height": crop.height,
+                }),
+            );
+        }
+    }
+}
+
+impl Stage for VideoTransformStage {
+    fn name(&self) -> &'static str {
+        "video_transform"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        matches!(device, StageDevice::Cpu)
+    }
+
+    fn plan(&self, artifact: &mut Artifact, _ctx: &PipelineContext) -> Result<()> {
+        let value = |key: &str| {
+            artifact
+                .metadata
+                .get(key)
+                .and_then(Value::as_u64)
+                .and_then(|value| u32::try_from(value).ok())
+        };
+        let rotation = value("video.rotation").unwrap_or(0);
+        if let (Some(width), Some(height)) = (value("video.width"), value("video.height"))
+            && width > 0
+            && height > 0
+        {
+            let upright = if self.autorotate { rotation } else { 0 };
+            // Decoded frames are 4:2:0 unless the source says otherwise.
+            let (_, output) = self.geometry(width, height, upright, true)?;
+            let rotation = if self.autorotate { 0 } else { rotation as u16 };
+            self.record(artifact, output, rotation);
+        }
+        Ok(())
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let media = artifact.media_mut();
+        let video = media
+            .video
+            .as_mut()
+            .filter(|video| !video.frames.is_empty())
+            .ok_or_else(|| anyhow!("video_transform stage requires decoded video frames"))?;
+        let upright = if self.autorotate {
+            u32::from(std::mem::take(&mut video.rotation))
+        } else {
+            0
+        };
+        for frame in &mut video.frames {
+            ctx.cancellation.check()?;
+            self.transform_frame(frame, upright)?;
+        }
+        let output = (video.frames[0].width, video.frames[0].height);
+        let rotation = video.rotation;
+        self.record(artifact, output, rotation);
+        Ok(())
+    }
+}
+
+/// The size of a `width` x `height` image turned clockwise by `degrees`.
+fn turned(degrees: u32, width: u32, height: u32) -> (u32, u32) {
+    if degrees % 180 == 90 {
+        (height, width)
+    } else {
+        (width, height)
+    }
+}
+
+fn turn<P>(image: ImageBuffer<P, Vec<u8>>, degrees: u32) -> ImageBuffer<P, Vec<u8>>
+where
+    P: Pixel<Subpixel = u8> + 'static,
+{
+    match degrees {
+        90 => imageops::rotate90(&image),
+        180 => imageops::rotate180(&image),
+        270 => imageops::rotate270(&image),
+        _ => image,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::stages::from_json;
+    use crate::video::PixelFormat;
+
+    /// A 4:2:0 frame whose luma samples count up in raster order and whose
+    /// chroma is 10 on the left half and 20 on the right.
+    fn frame(width: u32, height: u32) -> VideoFrame {
+        let chroma = |row: u32| -> Vec<u8> {
+            (0..width.div_ceil(2))
+                .map(move |x| if x < width / 4 { 10 } else { 20 })
+                .cycle()
+                .take((width.div_ceil(2) * row) as usize)
+                .collect()
+        };
+        VideoFrame {
+            width,
+            height,
+            pixel_format: PixelFormat::Yuv420,
+            data: FramePlanes::Yuv420 {
+                y: (0..width * height).map(|sample| sample as u8).collect(),
+                u: chroma(height.div_ceil(2)),
+                v: chroma(height.div_ceil(2)),
+            },
+            timestamp: Duration::ZERO,
+            duration: Duration::from_millis(40),
+            keyframe: true,
+        }
+    }
+
+    fn luma(frame: &VideoFrame) -> &[u8] {
+        let FramePlanes::Yuv420 { y, .. } = &frame.data else {
+            panic!("expected 4:2:0 planes");
+        };
+        y
+    }
+
+    #[test]
+    fn turns_upright_then_crops_rotates_and_mirrors() {
+        // 8x4 stored, shown turned a quarter clockwise: 4x8 upright.
+        let mut cropped = frame(8, 4);
+        let transform = from_json(
+            VideoTransformStage::from_params,
+            json!({ "crop": { "x": 0, "y": 2, "width": 4, "height": 2 } }),
+        )
+        .unwrap();
+        transform.transform_frame(&mut cropped, 90).unwrap();
+        assert_eq!((cropped.width, cropped.height), (4, 2));
+        // Upright row 2 is stored column 2 read bottom to top.
+        assert_eq!(&luma(&cropped)[..4], &[26, 18, 10, 2]);
+        let FramePlanes::Yuv420 { u, .. } = &cropped.data else {
+            unreachable!()
+        };
+        // The left half of the stored frame ends up on the top.
+        assert_eq!(u, &[10, 10]);
+
+        let mut mirrored = frame(8, 4);
+        let transform = from_json(
+            VideoTransformStage::from_params,
+            json!({ "angle": -90, "flip": "vertical" }),
+        )
+        .unwrap();
+        transform.transform_frame(&mut mirrored, 0).unwrap();
+        assert_eq!((mirrored.width, mirrored.height), (4, 8));
+        // A counter-clockwise turn brings the last column up top, read top
+        // to bottom; the vertical flip then moves it to the bottom.
+        assert_eq!(&luma(&mirrored)[28..], &[7, 15, 23, 31]);
+    }
+
+    #[test]
+    fn crops_are_checked_and_kept_even_for_subsampled_frames() {
+        let crop = Crop {
+            x: 3,
+            y: 1,
+            width: 10,
+            height: 6,
+        };
+        assert_eq!(
+            crop.fit(16, 8, true).unwrap(),
+            Crop {
+                x: 4,
+                y: 2,
+                width: 8,
+                height: 4,
+            }
+        );
+        assert_eq!(crop.fit(16, 8, false).unwrap(), crop);
+        assert!(crop.fit(12, 8, false).is_err());
+        let sliver = Crop {
+            x: 1,
+            y: 0,
+            width: 2,
+            height: 2,
+        };
+        assert!(sliver.fit(16, 8, true).is_err());
+
+        let transform = from_json(
+            VideoTransformStage::from_params,
+            json!({ "crop": { "x": 0, "y": 0, "width": 4, "height": 4 } }),
+        );
+        assert_eq!(
+            transform.unwrap().geometry(8, 6, 90, true).unwrap().1,
+            (4, 4)
+        );
+        assert!(
+            from_json(
+                VideoTransformStage::from_params,
+                json!({ "crop": { "x": 0, "y": 0, "w": 4, "h": 4 } })
+            )
+            .is_err()
+        );
+        assert!(
+            from_json(
+                VideoTransformStage::from_params,
+                json!({ "crop": { "x": 0, "y": 0, "width": 0, "height": 4 } })
+            )
+            .is_err()
+        );
+        assert!(from_json(VideoTransformStage::from_params, json!({ "angle": 45 })).is_err());
+        assert!(
+            from_json(
+                VideoTransformStage::from_params,
+                json!({ "flip": "diagonal" })
+            )
+            .is_err()
+        );
+    }
+}
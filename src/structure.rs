@@ -0,0 +1,210 @@
+//! Output structure templates.
+//!
+//! An output `structure` such as `{date:%Y/%m}/{parent}/{stem}-{width}x{height}.{ext}`
+//! is rendered once per output. Besides the built-in placeholders below, any
+//! string-valued metadata key can be used as `{key}`; placeholders without a
+//! value are left as written. Built-ins that need something from the input
+//! (`hash`, `date`, `seq`) read metadata the executor only records when a
+//! structure asks for it.
+
+use std::fmt::Write;
+use std::path::Path;
+
+use anyhow::{Result, bail};
+use chrono::DateTime;
+use chrono::format::{Item, StrftimeItems};
+use serde_json::{Map, Value};
+
+/// Built-in placeholders, in the order they are documented.
+pub const PLACEHOLDERS: [&str; 8] = [
+    "stem", "ext", "width", "height", "hash", "date", "seq", "parent",
+];
+
+/// SHA256 of the input bytes, recorded for `{hash}`.
+pub const INPUT_SHA256_KEY: &str = "input.sha256";
+/// Modification time of the input file (RFC 3339), recorded for `{date}`.
+pub const INPUT_MODIFIED_KEY: &str = "input.modified";
+/// Zero-based position of the input in the run, recorded for `{seq}`.
+pub const INPUT_INDEX_KEY: &str = "input.index";
+
+const DEFAULT_HASH_LENGTH: usize = 8;
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+const MAX_SEQ_WIDTH: usize = 20;
+
+/// Checks the arguments of built-in placeholders, e.g. that `{hash:N}` asks
+/// for at most 64 digits and `{date:FORMAT}` is a valid strftime format.
+pub fn validate(structure: &str) -> Result<()> {
+    for placeholder in placeholders(structure)? {
+        let (name, arg) = split(placeholder);
+        match (name, arg) {
+            ("hash", Some(arg)) if !matches!(arg.parse::<usize>(), Ok(1..=64)) => {
+                bail!("{{hash:{arg}}} must ask for 1 to 64 hex digits");
+            }
+            ("seq", Some(arg)) if !matches!(arg.parse::<usize>(), Ok(1..=MAX_SEQ_WIDTH)) => {
+                bail!("{{seq:{arg}}} must pad to 1 to {MAX_SEQ_WIDTH} digits");
+            }
+            ("date", Some(arg))
+                if StrftimeItems::new(arg).any(|item| matches!(item, Item::Error)) =>
+            {
+                bail!("{{date:{arg}}} is not a valid strftime format");
+            }
+            ("hash" | "seq" | "date", _) | (_, None) => {}
+            (name, Some(_)) if PLACEHOLDERS.contains(&name) => {
+                bail!("{{{name}}} does not take an argument");
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Whether `structure` uses the built-in placeholder `name`, with or without
+/// an argument.
+pub fn uses(structure: &str, name: &str) -> bool {
+    placeholders(structure)
+        .unwrap_or_default()
+        .into_iter()
+        .any(|placeholder| split(placeholder).0 == name)
+}
+
+/// Renders `structure` for an output named `stem` with `extension`.
+pub fn render(
+    structure: &str,
+    stem: &str,
+    extension: &str,
+    metadata: &Map<String, Value>,
+) -> String {
+    let mut rendered = String::with_capacity(structure.len());
+    let mut rest = structure;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        rendered.push_str(&rest[..start]);
+        let placeholder = &rest[start + 1..start + len];
+        match value(placeholder, stem, extension, metadata) {
+            Some(value) => rendered.push_str(&value),
+            None => rendered.push_str(&rest[start..=start + len]),
+        }
+        rest = &rest[start + len + 1..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+fn value(
+    placeholder: &str,
+    stem: &str,
+    extension: &str,
+    metadata: &Map<String, Value>,
+) -> Option<String> {
+    let (name, arg) = split(placeholder);
+    match name {
+        "stem" => Some(stem.to_string()),
+        "ext" => Some(extension.to_string()),
+        "width" | "height" => match metadata.get(&format!("image.{name}"))? {
+            Value::Number(number) => Some(number.to_string()),
+            Value::String(text) => Some(text.clone()),
+            _ => None,
+        },
+        "hash" => {
+            let digest = metadata.get(INPUT_SHA256_KEY)?.as_str()?;
+            let length = arg.map_or(Some(DEFAULT_HASH_LENGTH), |arg| arg.parse().ok())?;
+            digest.get(..length).map(str::to_string)
+        }
+        "date" => {
+            let modified = metadata.get(INPUT_MODIFIED_KEY)?.as_str()?;
+            let modified = DateTime::parse_from_rfc3339(modified).ok()?;
+            let mut formatted = String::new();
+            write!(
+                formatted,
+                "{}",
+                modified.format(arg.unwrap_or(DEFAULT_DATE_FORMAT))
+            )
+            .ok()?;
+            Some(formatted)
+        }
+        "seq" => {
+            let index = metadata.get(INPUT_INDEX_KEY)?.as_u64()?;
+            let width = arg.map_or(Some(0), |arg| arg.parse().ok())?;
+            Some(format!("{:0width$}", index + 1))
+        }
+        "parent" => {
+            let input = metadata.get("input_path")?.as_str()?;
+            let parent = Path::new(input).parent()?.file_name()?;
+            Some(parent.to_string_lossy().to_string())
+        }
+        _ => metadata
+            .get(placeholder)
+            .and_then(Value::as_str)
+            .map(str::to_string),
+    }
+}
+
+fn placeholders(structure: &str) -> Result<Vec<&str>> {
+    let mut found = Vec::new();
+    let mut rest = structure;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            bail!("Unclosed '{{' in output structure: {structure}");
+        };
+        found.push(&rest[start + 1..start + len]);
+        rest = &rest[start + len + 1..];
+    }
+    Ok(found)
+}
+
+fn split(placeholder: &str) -> (&str, Option<&str>) {
+    match placeholder.split_once(':') {
+        Some((name, arg)) => (name, Some(arg)),
+        None => (placeholder, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn renders_built_in_and_metadata_placeholders() {
+        let metadata = json!({
+            "input_path": "/shoots/beach/IMG_0042.jpg",
+            "image.width": 1920,
+            "image.height": 1080,
+            "input.sha256": "3f2a9c1e77b0d5a4",
+            "input.modified": "2024-07-09T18:30:00+02:00",
+            "input.index": 6,
+            "variant": "web",
+        });
+        let metadata = metadata.as_object().unwrap();
+        let render = |structure: &str| render(structure, "IMG_0042", "webp", metadata);
+
+        assert_eq!(render("{stem}.{ext}"), "IMG_0042.webp");
+        assert_eq!(
+            render("{date:%Y/%m}/{parent}/{stem}-{width}x{height}.{ext}"),
+            "2024/07/beach/IMG_0042-1920x1080.webp"
+        );
+        assert_eq!(render("{date}_{seq:4}_{hash}"), "2024-07-09_0007_3f2a9c1e");
+        assert_eq!(render("{variant}/{seq}-{hash:4}"), "web/7-3f2a");
+        assert_eq!(render("{missing}/{stem"), "{missing}/{stem");
+        assert!(uses("{stem}-{hash:12}", "hash"));
+        assert!(!uses("{stem}", "seq"));
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        assert!(validate("{date:%Y/%m}/{seq:4}/{hash:16}/{variant}").is_ok());
+        for structure in [
+            "{hash:0}",
+            "{hash:65}",
+            "{seq:x}",
+            "{date:%Q}",
+            "{stem:2}",
+            "{stem",
+        ] {
+            assert!(validate(structure).is_err(), "{structure} validated");
+        }
+    }
+}
@@ -0,0 +1,203 @@
+//! One-line run summaries rendered from a user template.
+//!
+//! `run --summary-template "{count} files, {bytes_saved} saved, avg SSIM {avg_ssim}"`
+//! prints the rendered line once the run finishes, so CI jobs and chat hooks
+//! get a human summary without parsing the manifest.
+
+use anyhow::{Result, bail};
+
+use crate::manifest::{ManifestQuality, RunManifest};
+use crate::memory::format_bytes;
+
+/// Placeholders a template may use, in the order they are documented.
+pub const PLACEHOLDERS: [&str; 13] = [
+    "count",
+    "outputs",
+    "duplicates",
+    "input_bytes",
+    "output_bytes",
+    "bytes_saved",
+    "saved_percent",
+    "duration",
+    "avg_ssim",
+    "avg_psnr",
+    "quality_passed",
+    "quality_skipped",
+    "recipe",
+];
+
+#[derive(Debug, Clone)]
+pub struct SummaryTemplate {
+    template: String,
+}
+
+impl SummaryTemplate {
+    /// Checks every `{placeholder}` up front so a typo fails before the run
+    /// rather than after it.
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let Some(len) = rest[start..].find('}') else {
+                bail!("Unclosed '{{' in summary template: {template}");
+            };
+            let name = &rest[start + 1..start + len];
+            if !PLACEHOLDERS.contains(&name) {
+                bail!(
+                    "Unknown summary placeholder '{{{name}}}'; expected one of: {}",
+                    PLACEHOLDERS.join(", ")
+                );
+            }
+            rest = &rest[start + len + 1..];
+        }
+        Ok(Self {
+            template: template.to_string(),
+        })
+    }
+
+    /// Fills the placeholders in one pass over the template, so a value
+    /// that looks like a placeholder (a recipe path with `{count}` in it)
+    /// is written as it is.
+    pub fn render(&self, manifest: &RunManifest) -> String {
+        let mut rendered = String::with_capacity(self.template.len());
+        let mut rest = self.template.as_str();
+        // `parse` checked that every `{` opens a known placeholder.
+        while let Some(start) = rest.find('{') {
+            let len = rest[start..].find('}').unwrap_or(rest.len() - start);
+            rendered.push_str(&rest[..start]);
+            rendered.push_str(&value(manifest, &rest[start + 1..start + len]));
+            rest = rest.get(start + len + 1..).unwrap_or_default();
+        }
+        rendered.push_str(rest);
+        rendered
+    }
+}
+
+fn value(manifest: &RunManifest, name: &str) -> String {
+    let totals = &manifest.totals;
+    match name {
+        "count" => totals.inputs.to_string(),
+        "outputs" => totals.outputs.to_string(),
+        "duplicates" => totals.duplicates.to_string(),
+        "input_bytes" => format_bytes(totals.input_bytes),
+        "output_bytes" => format_bytes(totals.output_bytes),
+        "bytes_saved" if totals.output_bytes > totals.input_bytes => {
+            format!(
+                "-{}",
+                format_bytes(totals.output_bytes - totals.input_bytes)
+            )
+        }
+        "bytes_saved" => format_bytes(totals.input_bytes - totals.output_bytes),
+        "saved_percent" if totals.input_bytes == 0 => "n/a".to_string(),
+        "saved_percent" => format!(
+            "{:.1}%",
+            (1.0 - totals.output_bytes as f64 / totals.input_bytes as f64) * 100.0
+        ),
+        "duration" => format!("{:.1}s", totals.duration_ms / 1_000.0),
+        "avg_ssim" => average(manifest, |quality| quality.ssim)
+            .map(|ssim| format!("{ssim:.4}"))
+            .unwrap_or_else(|| "n/a".to_string()),
+        "avg_psnr" => average(manifest, |quality| quality.psnr)
+            .map(|psnr| format!("{psnr:.2} dB"))
+            .unwrap_or_else(|| "n/a".to_string()),
+        "quality_passed" => totals.quality_passed.to_string(),
+        "quality_skipped" => totals.quality_skipped.to_string(),
+        "recipe" => manifest
+            .recipe
+            .as_ref()
+            .map(|recipe| recipe.display().to_string())
+            .unwrap_or_default(),
+        _ => String::new(),
+    }
+}
+
+/// Mean of a quality metric over the entries that recorded it; infinite
+/// PSNR (identical images) is left out so it does not swamp the average.
+fn average(
+    manifest: &RunManifest,
+    metric: impl Fn(&ManifestQuality) -> Option<f64>,
+) -> Option<f64> {
+    let values: Vec<f64> = manifest
+        .entries
+        .iter()
+        .filter_map(|entry| metric(&entry.quality))
+        .filter(|value| value.is_finite())
+        .collect();
+    (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::{ManifestEntry, ManifestTotals};
+    use std::path::PathBuf;
+
+    fn entry(ssim: Option<f64>) -> ManifestEntry {
+        ManifestEntry {
+            input: PathBuf::from("in.png"),
+            output: PathBuf::from("out.webp"),
+            input_bytes: Some(4096),
+            output_bytes: Some(1024),
+            output_sha256: None,
+            duration_ms: 500.0,
+            quality: ManifestQuality {
+                status: "passed".into(),
+                ssim,
+                psnr: None,
+                mse: None,
+            },
+            duplicate_of: None,
+            aliased: false,
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn renders_totals_and_averages() {
+        let manifest = RunManifest {
+            generated_at: String::new(),
+            recipe: None,
+            entries: vec![entry(Some(0.98)), entry(Some(0.96)), entry(None)],
+            totals: ManifestTotals {
+                inputs: 3,
+                outputs: 3,
+                input_bytes: 3 * 4096,
+                output_bytes: 3 * 1024,
+                duration_ms: 1500.0,
+                ..ManifestTotals::default()
+            },
+        };
+        let template = SummaryTemplate::parse(
+            "{count} files, {bytes_saved} saved ({saved_percent}), avg SSIM {avg_ssim}, PSNR {avg_psnr}",
+        )
+        .unwrap();
+        assert_eq!(
+            template.render(&manifest),
+            "3 files, 9.0 KiB saved (75.0%), avg SSIM 0.9700, PSNR n/a"
+        );
+    }
+
+    #[test]
+    fn values_are_not_expanded_again() {
+        let manifest = RunManifest {
+            generated_at: String::new(),
+            recipe: Some(PathBuf::from("recipes/{count}-{outputs}.yaml")),
+            entries: Vec::new(),
+            totals: ManifestTotals {
+                inputs: 2,
+                ..ManifestTotals::default()
+            },
+        };
+        let template = SummaryTemplate::parse("{recipe}: {count} files").unwrap();
+        assert_eq!(
+            template.render(&manifest),
+            "recipes/{count}-{outputs}.yaml: 2 files"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders() {
+        let err = SummaryTemplate::parse("{count} {bytes}").unwrap_err();
+        assert!(err.to_string().contains("'{bytes}'"));
+        assert!(SummaryTemplate::parse("{count").is_err());
+    }
+}
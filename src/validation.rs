@@ -1,8 +1,11 @@
 use anyhow::{Context, Result};
 use serde::Serialize;
 
+use crate::condition::Condition;
+use crate::graph::StageGraph;
 use crate::pipeline::{StageRegistry, StageSpec};
 use crate::recipe::Recipe;
+use crate::structure;
 
 #[derive(Debug, Default, Serialize)]
 pub struct ValidationReport {
@@ -67,8 +70,49 @@ pub fn validate_recipe(recipe: &Recipe, registry: &StageRegistry) -> ValidationR
             .push("Output directory cannot be empty".into());
     }
 
+    if let Some(notify) = &recipe.notify
+        && !notify.webhook.starts_with("http://")
+        && !notify.webhook.starts_with("https://")
+    {
+        report.errors.push(format!(
+            "Notify webhook must be an http(s) URL: {}",
+            notify.webhook
+        ));
+    }
+
+    let graph = match StageGraph::resolve(&recipe.pipeline, &recipe.output) {
+        Ok(graph) => Some(graph),
+        Err(err) => {
+            report.errors.push(err.to_string());
+            None
+        }
+    };
+    // Variants and `output` overrides give each branch its own structure.
+    let mut structures: Vec<&str> = match &graph {
+        Some(graph) => graph
+            .leaves()
+            .map(|leaf| graph.output(leaf).structure.as_str())
+            .collect(),
+        None => vec![recipe.output.structure.as_str()],
+    };
+    structures.dedup();
+    for structure in structures {
+        if let Err(err) = structure::validate(structure) {
+            report
+                .errors
+                .push(format!("Invalid output structure '{structure}': {err}"));
+        }
+    }
     for (idx, stage) in recipe.pipeline.iter().enumerate() {
-        report.merge(validate_stage_order(idx, stage, &recipe.pipeline));
+        // Ordering rules only look at the stages this one runs after.
+        let earlier: Vec<&StageSpec> = match &graph {
+            Some(graph) => graph
+                .ancestors(idx)
+                .map(|ancestor| &recipe.pipeline[ancestor])
+                .collect(),
+            None => recipe.pipeline[..idx].iter().collect(),
+        };
+        report.merge(validate_stage_order(idx, stage, &earlier));
         report.merge(
             validate_stage(stage, registry)
                 .with_context(|| format!("Stage {} ('{}')", idx + 1, stage.stage))
@@ -91,25 +135,40 @@ fn validate_stage(stage: &StageSpec, registry: &StageRegistry) -> Result<Validat
             .errors
             .push(err.context("Failed to instantiate stage").to_string());
     }
+    if let Some(retry) = &stage.retry
+        && let Err(err) = retry.validate()
+    {
+        report.errors.push(err.to_string());
+    }
+    if let Some(when) = &stage.when
+        && let Err(err) = Condition::parse(when)
+    {
+        report.errors.push(format!("Invalid when clause: {err}"));
+    }
 
     Ok(report)
 }
 
-fn validate_stage_order(idx: usize, stage: &StageSpec, pipeline: &[StageSpec]) -> ValidationReport {
+fn validate_stage_order(idx: usize, stage: &StageSpec, earlier: &[&StageSpec]) -> ValidationReport {
     let mut report = ValidationReport::default();
     if stage.stage == "encode" {
         if idx == 0 {
             report.errors.push("Encode stage cannot be first".into());
         }
-        let previous_has_decode = pipeline[..idx].iter().any(|prev| prev.stage == "decode");
+        let previous_has_decode = earlier.iter().any(|prev| prev.stage == "decode");
         if !previous_has_decode {
             report
                 .errors
                 .push("Encode stage requires a decode stage earlier in the pipeline".into());
         }
     }
+    if stage.stage == "rename" && earlier.iter().any(|prev| prev.stage == "encode") {
+        report
+            .warnings
+            .push("Rename stage after encode does not change the encoded output name".into());
+    }
     if stage.stage == "quality" {
-        let has_encode = pipeline[..idx].iter().any(|prev| prev.stage == "encode");
+        let has_encode = earlier.iter().any(|prev| prev.stage == "encode");
         if !has_encode {
             report.errors.push(
                 "Quality evaluation stage must follow an encode stage to compare outputs".into(),
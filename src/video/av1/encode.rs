@@ -0,0 +1,421 @@
+//! AV1 encoding with rav1e, behind the `rav1e` feature. Samples are stored
+//! as MP4 and Matroska expect them: the temporal unit's OBUs without their
+//! temporal delimiter, with the sequence header also carried in `av1C`.
+
+use anyhow::Result;
+#[cfg(not(feature = "rav1e"))]
+use anyhow::bail;
+
+use crate::video::{EncodedVideo, VideoStream};
+
+/// AV1 encoder settings.
+#[derive(Debug, Clone, Copy)]
+pub struct EncoderConfig {
+    /// rav1e speed preset from 0 (slowest, smallest output) to 10.
+    pub speed: u8,
+    /// Base quantizer from 0 (best quality) to 255 (smallest output).
+    pub quantizer: u8,
+    /// Tile columns and rows, each a power of two; 0 lets the encoder
+    /// choose.
+    pub tile_cols: u32,
+    pub tile_rows: u32,
+    /// Frames from one keyframe to the next, with scene-cut keyframes off;
+    /// 0 lets the encoder place them.
+    pub keyframe_interval: u32,
+    /// Codes frames in display order instead of reordering them in groups.
+    pub low_latency: bool,
+}
+
+impl Default for EncoderConfig {
+    fn default() -> Self {
+        Self {
+            speed: 6,
+            quantizer: 100,
+            tile_cols: 0,
+            tile_rows: 0,
+            keyframe_interval: 0,
+            low_latency: false,
+        }
+    }
+}
+
+/// Encodes `stream`'s YUV 4:2:0 frames as AV1.
+#[cfg(feature = "rav1e")]
+pub fn encode(stream: &VideoStream, config: &EncoderConfig) -> Result<EncodedVideo> {
+    use anyhow::{anyhow, bail};
+    use rav1e::prelude::{
+        ChromaSampling, ChromaticityPoint, ColorDescription, ColorPrimaries, Config, ContentLight,
+        Context, EncoderStatus, FrameType, MatrixCoefficients, Rational, SceneDetectionSpeed,
+        TransferCharacteristics,
+    };
+
+    use crate::video::{
+        ColorSpace, EncodedSample, FramePlanes, FrameRate, MasteringDisplay, TransferFunction,
+        VideoCodec,
+    };
+
+    if config.speed > 10 {
+        bail!("AV1 speed must be between 0 and 10, got {}", config.speed);
+    }
+    let Some(first) = stream.frames.first() else {
+        bail!("no video frames to encode");
+    };
+    let (width, height) = (first.width, first.height);
+    if width == 0 || height == 0 {
+        bail!("cannot encode {width}x{height} frames");
+    }
+    let (w, h) = (width as usize, height as usize);
+    let (chroma_w, chroma_h) = (w.div_ceil(2), h.div_ceil(2));
+
+    let mut encoder = rav1e::EncoderConfig::with_speed_preset(config.speed);
+    encoder.width = w;
+    encoder.height = h;
+    encoder.bit_depth = 8;
+    encoder.chroma_sampling = ChromaSampling::Cs420;
+    encoder.quantizer = usize::from(config.quantizer);
+    encoder.tile_cols = config.tile_cols as usize;
+    encoder.tile_rows = config.tile_rows as usize;
+    if config.keyframe_interval > 0 {
+        let interval = u64::from(config.keyframe_interval);
+        encoder.set_key_frame_interval(interval, interval);
+        encoder.speed_settings.scene_detection_mode = SceneDetectionSpeed::None;
+    }
+    encoder.low_latency = config.low_latency;
+    encoder.time_base = match stream.frame_rate {
+        FrameRate::Constant {
+            numerator,
+            denominator,
+        } if numerator > 0 && denominator > 0 => {
+            Rational::new(u64::from(denominator), u64::from(numerator))
+        }
+        _ => Rational::new(1, 1000),
+    };
+    encoder.color_description = match stream.color_space {
+        ColorSpace::Bt601 => Some(ColorDescription {
+            color_primaries: ColorPrimaries::BT601,
+            transfer_characteristics: TransferCharacteristics::BT601,
+            matrix_coefficients: MatrixCoefficients::BT601,
+        }),
+        ColorSpace::Bt709 => Some(ColorDescription {
+            color_primaries: ColorPrimaries::BT709,
+            transfer_characteristics: TransferCharacteristics::BT709,
+            matrix_coefficients: MatrixCoefficients::BT709,
+        }),
+        ColorSpace::Bt2020 => Some(ColorDescription {
+            color_primaries: ColorPrimaries::BT2020,
+            transfer_characteristics: TransferCharacteristics::BT2020_10Bit,
+            matrix_coefficients: MatrixCoefficients::BT2020NCL,
+        }),
+        ColorSpace::Srgb | ColorSpace::Unknown => None,
+    }
+    .map(|description| ColorDescription {
+        transfer_characteristics: match stream.transfer {
+            TransferFunction::Sdr => description.transfer_characteristics,
+            TransferFunction::Pq => TransferCharacteristics::SMPTE2084,
+            TransferFunction::Hlg => TransferCharacteristics::HLG,
+        },
+        ..description
+    });
+    // AV1's metadata OBUs take chromaticities as 0.16 and luminances as
+    // 24.8 (peak) and 18.14 (minimum) fixed-point numbers.
+    encoder.mastering_display = stream.hdr.mastering_display.map(|display| {
+        let point = |[x, y]: [u16; 2]| {
+            let fixed = |value: u16| {
+                (f64::from(value) * MasteringDisplay::CHROMATICITY_UNIT * 65536.0)
+                    .round()
+                    .min(f64::from(u16::MAX)) as u16
+            };
+            ChromaticityPoint {
+                x: fixed(x),
+                y: fixed(y),
+            }
+        };
+        let nits = |value: u32, scale: f64| {
+            (f64::from(value) * MasteringDisplay::LUMINANCE_UNIT * scale).round() as u32
+        };
+        rav1e::prelude::MasteringDisplay {
+            primaries: display.primaries.map(point),
+            white_point: point(display.white_point),
+            max_luminance: nits(display.max_luminance, 256.0),
+            min_luminance: nits(display.min_luminance, 16384.0),
+        }
+    });
+    encoder.content_light = stream.hdr.content_light.map(|light| ContentLight {
+        max_content_light_level: light.max_cll,
+        max_frame_average_light_level: light.max_fall,
+    });
+    let mut context: Context<u8> = Config::new()
+        .with_encoder_config(encoder)
+        .new_context()
+        .map_err(|err| anyhow!("invalid AV1 encoder settings: {err}"))?;
+
+    let mut pending = stream.frames.iter().enumerate();
+    let mut packets = Vec::with_capacity(stream.frames.len());
+    loop {
+        match context.receive_packet() {
+            Ok(packet) => packets.push(packet),
+            Err(EncoderStatus::Encoded) => {}
+            Err(EncoderStatus::LimitReached) => break,
+            Err(EncoderStatus::NeedMoreData) => match pending.next() {
+                Some((index, frame)) => {
+                    if (frame.width, frame.height) != (width, height) {
+                        bail!(
+                            "frame {index} is {}x{}, but the stream started at {width}x{height}",
+                            frame.width,
+                            frame.height
+                        );
+                    }
+                    let FramePlanes::Yuv420 { y, u, v } = &frame.data else {
+                        bail!("AV1 encoding needs YUV 4:2:0 frames");
+                    };
+                    if y.len() != w * h
+                        || u.len() != chroma_w * chroma_h
+                        || v.len() != chroma_w * chroma_h
+                    {
+                        bail!("frame {index} planes do not match its {width}x{height} size");
+                    }
+                    let mut input = context.new_frame();
+                    input.planes[0].copy_from_raw_u8(y, w, 1);
+                    input.planes[1].copy_from_raw_u8(u, chroma_w, 1);
+                    input.planes[2].copy_from_raw_u8(v, chroma_w, 1);
+                    context
+                        .send_frame(input)
+                        .map_err(|err| anyhow!("AV1 encoder rejected frame {index}: {err}"))?;
+                }
+                None => context.flush(),
+            },
+            Err(err) => bail!("AV1 encoding failed: {err}"),
+        }
+    }
+
+    let mut samples = Vec::with_capacity(packets.len());
+    let mut sequence_header = None;
+    for packet in packets {
+        let source = usize::try_from(packet.input_frameno)
+            .ok()
+            .and_then(|index| stream.frames.get(index))
+            .ok_or_else(|| anyhow!("AV1 encoder returned an unknown frame"))?;
+        let data = packet
+            .data
+            .strip_prefix(&TEMPORAL_DELIMITER[..])
+            .unwrap_or(&packet.data)
+            .to_vec();
+        if sequence_header.is_none() {
+            sequence_header = obus(&data)?
+                .into_iter()
+                .find(|&(kind, _)| kind == OBU_SEQUENCE_HEADER)
+                .map(|(_, obu)| obu.to_vec());
+        }
+        samples.push(EncodedSample {
+            data,
+            timestamp: source.timestamp,
+            duration: source.duration,
+            keyframe: packet.frame_type == FrameType::KEY,
+        });
+    }
+    let mut av1c = context.container_sequence_header();
+    av1c.extend(sequence_header.unwrap_or_default());
+
+    Ok(EncodedVideo {
+        codec: VideoCodec::Av1,
+        width,
+        height,
+        frame_rate: stream.frame_rate,
+        config: av1c,
+        samples,
+        rotation: stream.rotation,
+        hdr: stream.hdr,
+    })
+}
+
+#[cfg(not(feature = "rav1e"))]
+pub fn encode(_stream: &VideoStream, _config: &EncoderConfig) -> Result<EncodedVideo> {
+    bail!("AV1 encoding requires building with the rav1e feature")
+}
+
+#[cfg(feature = "rav1e")]
+const OBU_SEQUENCE_HEADER: u8 = 1;
+/// A temporal delimiter OBU, which rav1e starts every packet with.
+#[cfg(feature = "rav1e")]
+const TEMPORAL_DELIMITER: [u8; 2] = [0x12, 0x00];
+
+/// Splits a temporal unit into its OBUs, each with its type. Every OBU must
+/// carry its size, as rav1e writes them.
+#[cfg(feature = "rav1e")]
+fn obus(mut data: &[u8]) -> Result<Vec<(u8, &[u8])>> {
+    use anyhow::{anyhow, bail};
+
+    let mut out = Vec::new();
+    while let Some(&header) = data.first() {
+        if header & 0x02 == 0 {
+            bail!("AV1 OBU without a size field");
+        }
+        let mut at = if header & 0x04 != 0 { 2 } else { 1 };
+        let mut size = 0usize;
+        for shift in (0..56).step_by(7) {
+            let byte = *data
+                .get(at)
+                .ok_or_else(|| anyhow!("truncated AV1 OBU size"))?;
+            at += 1;
+            size |= usize::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let end = at
+            .checked_add(size)
+            .filter(|&end| end <= data.len())
+            .ok_or_else(|| anyhow!("AV1 OBU runs past the end of its packet"))?;
+        out.push(((header >> 3) & 0x0F, &data[..end]));
+        data = &data[end..];
+    }
+    Ok(out)
+}
+
+#[cfg(all(test, feature = "rav1e"))]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::video::{
+        ColorSpace, ContentLightLevel, FramePlanes, FrameRate, HdrMetadata, MasteringDisplay,
+        PixelFormat, TransferFunction, VideoCodec, VideoFrame,
+    };
+
+    const OBU_METADATA: u8 = 5;
+
+    fn gradient(index: u8) -> VideoFrame {
+        let (width, height) = (64usize, 48usize);
+        let y = (0..width * height)
+            .map(|at| ((at % width) * 4) as u8 ^ (index * 8))
+            .collect();
+        VideoFrame {
+            width: width as u32,
+            height: height as u32,
+            pixel_format: PixelFormat::Yuv420,
+            data: FramePlanes::Yuv420 {
+                y,
+                u: vec![128; width * height / 4],
+                v: vec![128; width * height / 4],
+            },
+            timestamp: Duration::from_millis(40 * u64::from(index)),
+            duration: Duration::from_millis(40),
+            keyframe: index == 0,
+        }
+    }
+
+    #[test]
+    fn encodes_one_sample_per_frame_with_av1c() {
+        let stream = VideoStream {
+            codec: VideoCodec::Raw,
+            frame_rate: FrameRate::Constant {
+                numerator: 25,
+                denominator: 1,
+            },
+            frames: (0..4).map(gradient).collect(),
+            color_space: ColorSpace::Bt709,
+            transfer: TransferFunction::Sdr,
+            rotation: 0,
+            hdr: HdrMetadata::default(),
+        };
+        let config = EncoderConfig {
+            speed: 10,
+            ..EncoderConfig::default()
+        };
+        let video = encode(&stream, &config).unwrap();
+
+        assert!(matches!(video.codec, VideoCodec::Av1));
+        assert_eq!(video.samples.len(), 4);
+        assert!(video.samples[0].keyframe);
+        for (index, sample) in video.samples.iter().enumerate() {
+            assert_eq!(sample.timestamp, Duration::from_millis(40 * index as u64));
+            assert!(!sample.data.starts_with(&TEMPORAL_DELIMITER));
+        }
+        // av1C version 1, then the sequence header OBU.
+        assert_eq!(video.config[0], 0x81);
+        let config_obus = obus(&video.config[4..]).unwrap();
+        assert_eq!(config_obus.len(), 1);
+        assert_eq!(config_obus[0].0, OBU_SEQUENCE_HEADER);
+
+        assert!(
+            encode(
+                &stream,
+                &EncoderConfig {
+                    speed: 11,
+                    ..config
+                }
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn hdr_metadata_is_written_to_keyframes() {
+        let stream = VideoStream {
+            codec: VideoCodec::Raw,
+            frame_rate: FrameRate::Constant {
+                numerator: 25,
+                denominator: 1,
+            },
+            frames: (0..2).map(gradient).collect(),
+            color_space: ColorSpace::Bt2020,
+            transfer: TransferFunction::Pq,
+            rotation: 0,
+            hdr: HdrMetadata {
+                mastering_display: Some(MasteringDisplay {
+                    primaries: [[35400, 14600], [8500, 39850], [6550, 2300]],
+                    white_point: [15635, 16450],
+                    max_luminance: 10_000_000,
+                    min_luminance: 50,
+                }),
+                content_light: Some(ContentLightLevel {
+                    max_cll: 1000,
+                    max_fall: 400,
+                }),
+            },
+        };
+        let config = EncoderConfig {
+            speed: 10,
+            ..EncoderConfig::default()
+        };
+        let video = encode(&stream, &config).unwrap();
+        assert_eq!(video.hdr, stream.hdr);
+        let keyframe = &video.samples[0].data;
+        let metadata = obus(keyframe)
+            .unwrap()
+            .iter()
+            .filter(|(kind, _)| *kind == OBU_METADATA)
+            .count();
+        assert_eq!(metadata, 2);
+        // metadata_type 1 (HDR_CLL) with MaxCLL and MaxFALL, and the 1000
+        // nit mastering peak as 24.8 fixed point.
+        let contains = |bytes: &[u8]| keyframe.windows(bytes.len()).any(|window| window == bytes);
+        assert!(contains(&[1, 0x03, 0xE8, 0x01, 0x90]));
+        assert!(contains(&256_000u32.to_be_bytes()));
+    }
+
+    #[test]
+    fn fixed_keyframe_intervals_place_every_keyframe() {
+        let stream = VideoStream {
+            codec: VideoCodec::Raw,
+            frame_rate: FrameRate::Constant {
+                numerator: 25,
+                denominator: 1,
+            },
+            frames: (0..7).map(gradient).collect(),
+            color_space: ColorSpace::Bt709,
+            transfer: TransferFunction::Sdr,
+            rotation: 0,
+            hdr: HdrMetadata::default(),
+        };
+        let config = EncoderConfig {
+            speed: 10,
+            keyframe_interval: 3,
+            low_latency: true,
+            ..EncoderConfig::default()
+        };
+        let video = encode(&stream, &config).unwrap();
+        let keyframes: Vec<bool> = video.samples.iter().map(|sample| sample.keyframe).collect();
+        assert_eq!(keyframes, [true, false, false, true, false, false, true]);
+    }
+}
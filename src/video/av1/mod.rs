@@ -0,0 +1,39 @@
+//! AV1 decoding, through FFmpeg's libavcodec (preferring its dav1d
+//! wrapper) when built with the `av1` feature, and encoding with rav1e when
+//! built with the `rav1e` feature.
+
+mod encode;
+
+pub use encode::{EncoderConfig, encode};
+
+use anyhow::Result;
+#[cfg(not(feature = "av1"))]
+use anyhow::bail;
+
+use crate::video::MediaStreams;
+use crate::video::container::VideoSamples;
+
+/// Decodes the samples of an AV1 track (`av01` in MP4, `V_AV1` in WebM or
+/// Matroska) into `streams.video`.
+#[cfg(feature = "av1")]
+pub fn decode_samples(track: &VideoSamples<'_>, streams: &mut MediaStreams) -> Result<()> {
+    use crate::video::ffmpeg::{DecoderSpec, decode_samples};
+
+    // The av1C record (MP4) or CodecPrivate (Matroska) carries the sequence
+    // header OBU when the samples do not repeat it.
+    decode_samples(
+        DecoderSpec {
+            id: ffmpeg_next::codec::Id::AV1,
+            name: Some("libdav1d"),
+            codec: crate::video::VideoCodec::Av1,
+            extradata: track.codec_config.as_deref(),
+        },
+        track,
+        streams,
+    )
+}
+
+#[cfg(not(feature = "av1"))]
+pub fn decode_samples(_track: &VideoSamples<'_>, _streams: &mut MediaStreams) -> Result<()> {
+    bail!("AV1 decoding requires building with the av1 feature")
+}
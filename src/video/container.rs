@@ -4,77 +4,334 @@
 //! PCM audio tracks for the first milestone. The implementation favours
 //! clarity and correctness over absolute performance; optimization will follow
 //! later.
+//!
+//! [`Mp4Demuxer`] reads from any `Read + Seek` source. Only `moov` is read
+//! whole; `mdat` is seeked past, and each sample is read on its own when
+//! asked for, so multi-gigabyte files are demuxed incrementally.
 
+use std::borrow::Cow;
 use std::convert::TryInto;
-use std::io::{Cursor, Read};
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::time::Duration;
 
 use anyhow::{Context, Result, anyhow, bail};
 
+use crate::audio::pcm::SampleFormat;
+use crate::video::probe::{MediaInfo, TrackDetails, TrackInfo};
 use crate::video::{
-    AudioCodec, AudioStream, ColorSpace, FrameRate, MediaStreams, VideoCodec, VideoStream,
+    AudioCodec, AudioStream, ColorSpace, ContentLightLevel, FrameRate, HdrMetadata,
+    MasteringDisplay, MediaStreams, TransferFunction, VideoCodec, VideoStream,
 };
 
+/// Size of the fields shared by every visual sample entry, after the box
+/// header; codec configuration boxes follow them.
+const VISUAL_SAMPLE_ENTRY_LEN: usize = 78;
+
 #[derive(Debug)]
-pub struct Mp4Demuxer<'a> {
-    cursor: Cursor<&'a [u8]>,
+pub struct Mp4Demuxer<R> {
+    reader: R,
+    /// Length of the source, which sample locations are checked against.
+    len: u64,
+}
+
+/// A coded sample stored in `mdat`; for video, one access unit.
+#[derive(Debug, Clone)]
+pub struct Sample<'a> {
+    pub data: &'a [u8],
+    /// Presentation time: the decoding time shifted by the sample's `ctts`
+    /// composition offset, less the start of the track's edit list.
+    pub timestamp: Duration,
+    /// Decoding time from `stts`. Matroska records presentation times only,
+    /// so its samples repeat [`Self::timestamp`] here.
+    pub decode_time: Duration,
+    pub duration: Duration,
+    pub keyframe: bool,
+}
+
+/// The samples of a video track, in decoding order, with what is needed to
+/// decode them.
+#[derive(Debug, Clone)]
+pub struct VideoSamples<'a> {
+    pub codec: VideoCodec,
+    pub width: u32,
+    pub height: u32,
+    pub frame_rate: FrameRate,
+    /// Payload of the sample entry's decoder configuration box (`avcC` for
+    /// H.264).
+    pub codec_config: Option<Cow<'a, [u8]>>,
+    pub samples: Vec<Sample<'a>>,
+    /// Clockwise display rotation in degrees, from the track header.
+    pub rotation: u16,
+    /// From the sample entry's `mdcv` and `clli` boxes (Matroska's Colour
+    /// element).
+    pub hdr: HdrMetadata,
+}
+
+/// The packets of an audio track, in decoding order, with what is needed
+/// to decode them.
+#[derive(Debug, Clone)]
+pub struct AudioSamples<'a> {
+    pub codec: AudioCodec,
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// How the samples of a PCM track are stored.
+    pub pcm: Option<SampleFormat>,
+    /// The decoder setup as Matroska's CodecPrivate holds it: the
+    /// AudioSpecificConfig for AAC, an `OpusHead` packet for Opus, and the
+    /// `fLaC` marker and metadata blocks for FLAC. MP4's `esds`, `dOps` and
+    /// `dfLa` boxes are rewritten to match.
+    pub codec_config: Option<Cow<'a, [u8]>>,
+    pub samples: Vec<Sample<'a>>,
+}
+
+/// Where a sample's bytes lie in the source, timed as [`Sample`] is.
+#[derive(Debug, Clone)]
+pub struct SampleLocation {
+    pub offset: u64,
+    pub size: u32,
+    pub timestamp: Duration,
+    pub decode_time: Duration,
+    pub duration: Duration,
+    pub keyframe: bool,
+}
+
+/// An MP4 video track as `moov` describes it: its header fields and where
+/// its samples lie, with none of their data read.
+#[derive(Debug, Clone)]
+pub struct VideoTrack {
+    pub codec: VideoCodec,
+    pub width: u32,
+    pub height: u32,
+    pub frame_rate: FrameRate,
+    /// As on [`VideoSamples`].
+    pub codec_config: Option<Vec<u8>>,
+    /// In decoding order.
+    pub samples: Vec<SampleLocation>,
+    pub rotation: u16,
+    pub hdr: HdrMetadata,
+    fourcc: [u8; 4],
+    timescale: u32,
+    duration: u64,
+}
+
+impl VideoTrack {
+    /// The track's samples as slices of `file`, the source it was read
+    /// from, which holds every one of them.
+    pub fn samples_in(self, file: &[u8]) -> VideoSamples<'_> {
+        VideoSamples {
+            codec: self.codec,
+            width: self.width,
+            height: self.height,
+            frame_rate: self.frame_rate,
+            codec_config: self.codec_config.map(Cow::Owned),
+            samples: self
+                .samples
+                .iter()
+                .map(|sample| Sample {
+                    data: &file[sample.offset as usize..][..sample.size as usize],
+                    timestamp: sample.timestamp,
+                    decode_time: sample.decode_time,
+                    duration: sample.duration,
+                    keyframe: sample.keyframe,
+                })
+                .collect(),
+            rotation: self.rotation,
+            hdr: self.hdr,
+        }
+    }
 }
 
 #[derive(Debug, Default)]
 struct TrackCollector {
-    video: Option<VideoTrack>,
-    audio: Option<AudioTrack>,
+    /// From `mvhd`.
+    duration: Option<Duration>,
+    /// Every video and audio track, in file order.
+    tracks: Vec<ParsedTrack>,
 }
 
-#[derive(Debug)]
-#[allow(dead_code)]
-struct VideoTrack {
-    codec: VideoCodec,
-    width: u32,
-    height: u32,
-    timescale: u32,
-    duration: u32,
-    frame_count: u32,
+impl TrackCollector {
+    /// The last video track, which is the one decoded.
+    fn video(&self) -> Option<&VideoTrack> {
+        self.tracks.iter().rev().find_map(|track| match track {
+            ParsedTrack::Video(track) => Some(track),
+            _ => None,
+        })
+    }
+
+    fn audio(&self) -> Option<&AudioTrack> {
+        self.tracks.iter().rev().find_map(|track| match track {
+            ParsedTrack::Audio(track) => Some(track),
+            _ => None,
+        })
+    }
+}
+
+/// The sample table (`stbl`) boxes a track's samples are located by.
+#[derive(Debug, Default)]
+struct SampleTables<'a> {
+    stsd: Option<&'a [u8]>,
+    stts: Option<&'a [u8]>,
+    ctts: Option<&'a [u8]>,
+    stss: Option<&'a [u8]>,
+    stsz: Option<&'a [u8]>,
+    stsc: Option<&'a [u8]>,
+    stco: Option<&'a [u8]>,
+    co64: Option<&'a [u8]>,
 }
 
 #[derive(Debug)]
-#[allow(dead_code)]
 struct AudioTrack {
     codec: AudioCodec,
+    fourcc: [u8; 4],
     sample_rate: u32,
     channels: u16,
+    pcm: Option<SampleFormat>,
+    codec_config: Option<Vec<u8>>,
+    samples: Vec<SampleLocation>,
     timescale: u32,
-    duration: u32,
+    duration: u64,
+    /// How many samples `stsz` lists and their total size.
+    sample_totals: (u64, u64),
 }
 
-impl<'a> Mp4Demuxer<'a> {
+impl AudioTrack {
+    /// The track's samples as slices of `file`, as [`VideoTrack::samples_in`]
+    /// gives a video track's.
+    fn samples_in(self, file: &[u8]) -> AudioSamples<'_> {
+        AudioSamples {
+            codec: self.codec,
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+            pcm: self.pcm,
+            codec_config: self.codec_config.map(Cow::Owned),
+            samples: self
+                .samples
+                .iter()
+                .map(|sample| Sample {
+                    data: &file[sample.offset as usize..][..sample.size as usize],
+                    timestamp: sample.timestamp,
+                    decode_time: sample.decode_time,
+                    duration: sample.duration,
+                    keyframe: sample.keyframe,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl<'a> Mp4Demuxer<Cursor<&'a [u8]>> {
     pub fn new(data: &'a [u8]) -> Self {
         Self {
-            cursor: Cursor::new(data),
+            reader: Cursor::new(data),
+            len: data.len() as u64,
         }
     }
 
-    pub fn demux(mut self) -> Result<MediaStreams> {
+    /// The video track's samples, located through its sample tables.
+    pub fn video_samples(mut self) -> Result<Option<VideoSamples<'a>>> {
+        let file = *self.reader.get_ref();
+        Ok(self.video_track()?.map(|track| track.samples_in(file)))
+    }
+
+    /// The last audio track's samples, located through its sample tables.
+    pub fn audio_samples(mut self) -> Result<Option<AudioSamples<'a>>> {
+        let file = *self.reader.get_ref();
+        let collector = self.collect()?;
+        Ok(collector
+            .tracks
+            .into_iter()
+            .rev()
+            .find_map(|track| match track {
+                ParsedTrack::Audio(track) => Some(track.samples_in(file)),
+                _ => None,
+            }))
+    }
+
+    /// Describes the file and its tracks from the movie header and sample
+    /// tables.
+    pub fn probe(mut self) -> Result<MediaInfo> {
+        let file = *self.reader.get_ref();
+        let collector = self.collect()?;
+        let tracks = collector
+            .tracks
+            .into_iter()
+            .enumerate()
+            .map(|(index, track)| match track {
+                ParsedTrack::Video(track) => {
+                    let tag = String::from_utf8_lossy(&track.fourcc).into_owned();
+                    let duration = (track.duration > 0)
+                        .then(|| ticks_to_duration(track.duration, track.timescale));
+                    TrackInfo::video(index, &track.samples_in(file), &tag, duration)
+                }
+                ParsedTrack::Audio(track) => TrackInfo::new(
+                    index,
+                    track.codec.name(),
+                    &String::from_utf8_lossy(&track.fourcc),
+                    track.sample_totals,
+                    (track.duration > 0)
+                        .then(|| ticks_to_duration(track.duration, track.timescale)),
+                    TrackDetails::Audio {
+                        sample_rate: track.sample_rate,
+                        channels: track.channels,
+                    },
+                ),
+            })
+            .collect();
+        Ok(MediaInfo::new(
+            "mp4",
+            file.len(),
+            collector.duration,
+            tracks,
+        ))
+    }
+}
+
+impl<R: Read + Seek> Mp4Demuxer<R> {
+    /// A demuxer reading from `reader`, which it seeks around in freely.
+    pub fn from_reader(mut reader: R) -> Result<Self> {
+        let len = reader
+            .seek(SeekFrom::End(0))
+            .context("failed to find the end of the MP4 source")?;
+        Ok(Self { reader, len })
+    }
+
+    /// Walks the top-level boxes, reading `moov` and seeking past the rest.
+    fn collect(&mut self) -> Result<TrackCollector> {
         let mut collector = TrackCollector::default();
-        while let Some(atom) = read_atom(&mut self.cursor)? {
-            match atom.kind.as_str() {
-                "moov" => collect_moov(&atom.data, &mut collector)?,
-                _ => {}
+        let mut at = 0;
+        while at < self.len {
+            self.reader.seek(SeekFrom::Start(at))?;
+            let (kind, header_len, size) = read_box_header(&mut self.reader, self.len - at)?;
+            if &kind == b"moov" {
+                let mut moov = vec![0; (size - header_len) as usize];
+                self.reader
+                    .read_exact(&mut moov)
+                    .context("failed to read the moov atom")?;
+                collect_moov(&moov, self.len, &mut collector)?;
             }
+            at += size;
         }
+        Ok(collector)
+    }
+
+    /// Describes the tracks. Video frames are left empty; decode the
+    /// [`Self::video_track`] to fill them.
+    pub fn demux(mut self) -> Result<MediaStreams> {
+        let collector = self.collect()?;
 
         let mut streams = MediaStreams::default();
-        if let Some(video) = collector.video {
+        if let Some(video) = collector.video() {
             streams.video = Some(VideoStream {
                 codec: video.codec,
-                frame_rate: FrameRate::Constant {
-                    numerator: video.frame_count,
-                    denominator: video.duration.max(1),
-                },
+                frame_rate: video.frame_rate,
                 frames: Vec::new(),
                 color_space: ColorSpace::Bt709,
+                transfer: TransferFunction::Sdr,
+                rotation: video.rotation,
+                hdr: video.hdr,
             });
         }
-        if let Some(audio) = collector.audio {
+        if let Some(audio) = collector.audio() {
             streams.audio = Some(AudioStream {
                 codec: audio.codec,
                 buffers: Vec::new(),
@@ -82,6 +339,55 @@ impl<'a> Mp4Demuxer<'a> {
         }
         Ok(streams)
     }
+
+    /// The video track, located through its sample tables; its samples are
+    /// read with [`Self::read_sample`].
+    pub fn video_track(&mut self) -> Result<Option<VideoTrack>> {
+        let collector = self.collect()?;
+        Ok(collector
+            .tracks
+            .into_iter()
+            .rev()
+            .find_map(|track| match track {
+                ParsedTrack::Video(track) => Some(track),
+                _ => None,
+            }))
+    }
+
+    /// Reads one sample's bytes from the source.
+    pub fn read_sample(&mut self, sample: &SampleLocation) -> Result<Vec<u8>> {
+        let mut data = vec![0; sample.size as usize];
+        self.reader.seek(SeekFrom::Start(sample.offset))?;
+        self.reader
+            .read_exact(&mut data)
+            .with_context(|| format!("failed to read the sample at byte {}", sample.offset))?;
+        Ok(data)
+    }
+}
+
+/// Reads a box header from `reader`: the box kind, the header's length and
+/// the whole box's size, which `remaining` bytes must hold. Handles the
+/// 64-bit `largesize` form and size 0, a box running to the end.
+fn read_box_header(reader: &mut impl Read, remaining: u64) -> Result<([u8; 4], u64, u64)> {
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header).context("atom header")?;
+    let kind: [u8; 4] = header[4..].try_into()?;
+    let (header_len, size) = match read_u32(&header) {
+        0 => (8, remaining),
+        1 => {
+            let mut large = [0u8; 8];
+            reader.read_exact(&mut large).context("atom largesize")?;
+            (16, u64::from_be_bytes(large))
+        }
+        size => (8, u64::from(size)),
+    };
+    if size < header_len {
+        bail!("invalid atom size {size}");
+    }
+    if size > remaining {
+        bail!("atom payload exceeds buffer bounds");
+    }
+    Ok((kind, header_len, size))
 }
 
 #[derive(Debug)]
@@ -120,20 +426,26 @@ fn read_atom<'a>(cursor: &mut Cursor<&'a [u8]>) -> Result<Option<Atom<'a>>> {
     Ok(Some(Atom { kind, data }))
 }
 
-fn collect_moov(data: &[u8], collector: &mut TrackCollector) -> Result<()> {
+/// Reads the tracks of a `moov` box whose samples lie in a source of
+/// `file_len` bytes.
+fn collect_moov(data: &[u8], file_len: u64, collector: &mut TrackCollector) -> Result<()> {
     let mut cursor = Cursor::new(data);
     while let Some(atom) = read_atom(&mut cursor)? {
-        if atom.kind == "trak" {
-            collect_trak(atom.data, collector)?;
+        match atom.kind.as_str() {
+            "mvhd" => collector.duration = header_duration(atom.data, "mvhd")?,
+            "trak" => collect_trak(atom.data, file_len, collector)?,
+            _ => {}
         }
     }
     Ok(())
 }
 
-fn collect_trak(data: &[u8], collector: &mut TrackCollector) -> Result<()> {
+fn collect_trak(data: &[u8], file_len: u64, collector: &mut TrackCollector) -> Result<()> {
     let mut cursor = Cursor::new(data);
     let mut tkhd_timescale = None;
     let mut tkhd_duration = None;
+    let mut rotation = 0;
+    let mut media_start = 0;
     let mut mdia_data = None;
 
     while let Some(atom) = read_atom(&mut cursor)? {
@@ -151,6 +463,20 @@ fn collect_trak(data: &[u8], collector: &mut TrackCollector) -> Result<()> {
                 };
                 tkhd_timescale = Some(read_u32(&atom.data[timescale_offset..timescale_offset + 4]));
                 tkhd_duration = Some(read_u32(&atom.data[duration_offset..duration_offset + 4]));
+                let matrix_offset = if version == 1 { 52 } else { 40 };
+                if let Some(matrix) = atom.data.get(matrix_offset..matrix_offset + 20) {
+                    rotation = matrix_rotation([0, 4, 12, 16].map(|at| {
+                        i32::from_be_bytes(matrix[at..at + 4].try_into().expect("four bytes"))
+                    }));
+                }
+            }
+            "edts" => {
+                let mut edts_cursor = Cursor::new(atom.data);
+                while let Some(child) = read_atom(&mut edts_cursor)? {
+                    if child.kind == "elst" {
+                        media_start = edit_list_start(child.data)?;
+                    }
+                }
             }
             "mdia" => mdia_data = Some(atom.data),
             _ => {}
@@ -158,62 +484,62 @@ fn collect_trak(data: &[u8], collector: &mut TrackCollector) -> Result<()> {
     }
 
     let mdia = mdia_data.ok_or_else(|| anyhow!("trak missing mdia"))?;
-    let track = parse_media(mdia, tkhd_timescale, tkhd_duration)?;
-    match track {
-        ParsedTrack::Video(track) => collector.video = Some(track),
-        ParsedTrack::Audio(track) => collector.audio = Some(track),
-        ParsedTrack::Unknown => {}
+    match parse_media(mdia, file_len, (tkhd_timescale, tkhd_duration), media_start)? {
+        Some(ParsedTrack::Video(track)) => {
+            collector
+                .tracks
+                .push(ParsedTrack::Video(VideoTrack { rotation, ..track }));
+        }
+        Some(track) => collector.tracks.push(track),
+        None => {}
     }
     Ok(())
 }
 
+#[derive(Debug)]
 enum ParsedTrack {
     Video(VideoTrack),
     Audio(AudioTrack),
-    Unknown,
 }
 
+/// Parses a track's `mdia` box, or `None` for a track other than video and
+/// audio. `media_start` is where, in media time, the edit list starts
+/// presenting it.
 fn parse_media(
     data: &[u8],
-    tk_timescale: Option<u32>,
-    tk_duration: Option<u32>,
-) -> Result<ParsedTrack> {
+    file_len: u64,
+    (tk_timescale, tk_duration): (Option<u32>, Option<u32>),
+    media_start: u64,
+) -> Result<Option<ParsedTrack>> {
     let mut cursor = Cursor::new(data);
     let mut hdlr_type = None;
-    let mut mdhd_timescale = None;
-    let mut mdhd_duration = None;
-    let mut stsd_data = None;
+    let mut mdhd = None;
+    let mut tables = SampleTables::default();
 
     while let Some(atom) = read_atom(&mut cursor)? {
         match atom.kind.as_str() {
-            "hdlr" => {
-                if atom.data.len() >= 12 {
-                    hdlr_type = Some(atom.data[8..12].try_into().unwrap());
-                }
-            }
-            "mdhd" => {
-                let version = atom
-                    .data
-                    .first()
-                    .copied()
-                    .ok_or_else(|| anyhow!("mdhd missing version"))?;
-                let (timescale_offset, duration_offset) = if version == 1 {
-                    (20usize, 24usize)
-                } else {
-                    (12, 16)
-                };
-                mdhd_timescale = Some(read_u32(&atom.data[timescale_offset..timescale_offset + 4]));
-                mdhd_duration = Some(read_u32(&atom.data[duration_offset..duration_offset + 4]));
+            "hdlr" if atom.data.len() >= 12 => {
+                hdlr_type = Some(atom.data[8..12].try_into().unwrap());
             }
+            "mdhd" => mdhd = Some(header_fields(atom.data, "mdhd")?),
             "minf" => {
                 let mut minf_cursor = Cursor::new(atom.data);
                 while let Some(child) = read_atom(&mut minf_cursor)? {
                     if child.kind == "stbl" {
                         let mut stbl_cursor = Cursor::new(child.data);
                         while let Some(grandchild) = read_atom(&mut stbl_cursor)? {
-                            if grandchild.kind == "stsd" {
-                                stsd_data = Some(grandchild.data);
-                            }
+                            let table = match grandchild.kind.as_str() {
+                                "stsd" => &mut tables.stsd,
+                                "stts" => &mut tables.stts,
+                                "ctts" => &mut tables.ctts,
+                                "stss" => &mut tables.stss,
+                                "stsz" => &mut tables.stsz,
+                                "stsc" => &mut tables.stsc,
+                                "stco" => &mut tables.stco,
+                                "co64" => &mut tables.co64,
+                                _ => continue,
+                            };
+                            *table = Some(grandchild.data);
                         }
                     }
                 }
@@ -224,67 +550,585 @@ fn parse_media(
 
     let handler: [u8; 4] = match hdlr_type {
         Some(v) => v,
-        None => return Ok(ParsedTrack::Unknown),
+        None => return Ok(None),
+    };
+    let (timescale, duration) = match mdhd {
+        Some(fields) => fields,
+        None => (tk_timescale.unwrap_or(1), tk_duration.map_or(0, u64::from)),
     };
-    let timescale = mdhd_timescale.or(tk_timescale).unwrap_or(1);
-    let duration = mdhd_duration.or(tk_duration).unwrap_or(0);
 
-    let stsd = stsd_data.ok_or_else(|| anyhow!("stsd not found"))?;
+    let stsd = tables.stsd.ok_or_else(|| anyhow!("stsd not found"))?;
     if stsd.len() < 16 {
         bail!("invalid stsd atom");
     }
     let entry_count = read_u32(&stsd[4..8]);
     if entry_count == 0 {
-        return Ok(ParsedTrack::Unknown);
+        return Ok(None);
     }
 
+    // The first sample entry, from its size field on.
     let entry_size = read_u32(&stsd[8..12]) as usize;
-    if entry_size + 8 > stsd.len() {
+    // Audio sample entries, the shorter kind, hold 28 bytes of fields.
+    if entry_size < 36 || entry_size + 8 > stsd.len() {
         bail!("stsd entry exceeds buffer");
     }
-    let entry_data = &stsd[12..12 + entry_size];
-    let codec_fourcc = &entry_data[4..8];
+    let entry_data = &stsd[8..8 + entry_size];
+    let fourcc: [u8; 4] = entry_data[4..8].try_into()?;
+    let codec_fourcc = &fourcc;
 
     match &handler {
-        b"vide" => {
+        // AVIF image sequences are `pict` tracks laid out like video.
+        b"vide" | b"pict" => {
+            if entry_data.len() < 8 + VISUAL_SAMPLE_ENTRY_LEN {
+                bail!("visual sample entry is truncated");
+            }
             let width = u16::from_be_bytes(entry_data[32..34].try_into()?);
             let height = u16::from_be_bytes(entry_data[34..36].try_into()?);
+            let config_kind: &[u8; 4] = match codec_fourcc {
+                b"avc1" | b"avc3" => b"avcC",
+                b"hvc1" | b"hev1" => b"hvcC",
+                b"vp09" => b"vpcC",
+                b"av01" => b"av1C",
+                _ => b"    ",
+            };
+            let mut config_cursor = Cursor::new(&entry_data[8 + VISUAL_SAMPLE_ENTRY_LEN..]);
+            let mut codec_config = None;
+            let mut hdr = HdrMetadata::default();
+            while let Some(child) = read_atom(&mut config_cursor)? {
+                match child.kind.as_str() {
+                    "mdcv" => hdr.mastering_display = MasteringDisplay::from_bytes(child.data),
+                    "clli" => hdr.content_light = ContentLightLevel::from_bytes(child.data),
+                    kind if kind.as_bytes() == config_kind => {
+                        codec_config = Some(child.data.to_vec());
+                    }
+                    _ => {}
+                }
+            }
+            let samples = resolve_samples(&tables, file_len, timescale, media_start)?;
             let codec = match codec_fourcc {
-                b"avc1" => VideoCodec::H264,
-                b"hvc1" => VideoCodec::H265,
+                b"avc1" | b"avc3" => VideoCodec::H264,
+                b"hvc1" | b"hev1" => VideoCodec::H265,
                 b"vp09" => VideoCodec::Vp9,
                 b"av01" => VideoCodec::Av1,
                 _ => VideoCodec::Unknown,
             };
-            Ok(ParsedTrack::Video(VideoTrack {
+            Ok(Some(ParsedTrack::Video(VideoTrack {
                 codec,
+                fourcc,
                 width: width as u32,
                 height: height as u32,
                 timescale,
                 duration,
-                frame_count: 0,
-            }))
+                frame_rate: sample_frame_rate(&tables, timescale)?,
+                codec_config,
+                samples,
+                rotation: 0,
+                hdr,
+            })))
         }
         b"soun" => {
-            let channels = u16::from_be_bytes(entry_data[16..18].try_into()?);
-            let sample_rate_fixed = read_u32(&entry_data[24..28]);
-            let sample_rate = (sample_rate_fixed >> 16) as u32;
-            let codec = match codec_fourcc {
-                b"lpcm" => AudioCodec::PcmS16,
-                b"f32 " => AudioCodec::PcmF32,
-                b"aac " => AudioCodec::Aac,
-                b"Opus" => AudioCodec::Opus,
-                _ => AudioCodec::Unknown,
-            };
-            Ok(ParsedTrack::Audio(AudioTrack {
-                codec,
-                sample_rate,
-                channels,
+            let sound = sound_entry(entry_data)?;
+            Ok(Some(ParsedTrack::Audio(AudioTrack {
+                codec: sound.codec,
+                fourcc,
+                sample_rate: sound.sample_rate,
+                channels: sound.channels,
+                pcm: sound.pcm,
+                codec_config: sound.codec_config,
+                samples: resolve_samples(&tables, file_len, timescale, media_start)?,
                 timescale,
                 duration,
-            }))
+                sample_totals: match tables.stsz {
+                    Some(stsz) => sample_totals(stsz)?,
+                    None => (0, 0),
+                },
+            })))
         }
-        _ => Ok(ParsedTrack::Unknown),
+        _ => Ok(None),
+    }
+}
+
+/// What an audio sample entry says about its track's coding.
+struct SoundEntry {
+    codec: AudioCodec,
+    sample_rate: u32,
+    channels: u16,
+    pcm: Option<SampleFormat>,
+    /// As [`AudioSamples::codec_config`] holds it.
+    codec_config: Option<Vec<u8>>,
+}
+
+/// Reads an audio sample entry, from its size field on: the fields of a
+/// QuickTime version 0, 1 or 2 sound description, then the codec's boxes.
+fn sound_entry(entry: &[u8]) -> Result<SoundEntry> {
+    let fourcc: [u8; 4] = entry[4..8].try_into()?;
+    let version = u16::from_be_bytes(entry[16..18].try_into()?);
+    let mut channels = u16::from_be_bytes(entry[24..26].try_into()?);
+    let mut sample_size = u16::from_be_bytes(entry[26..28].try_into()?);
+    let mut sample_rate = read_u32(&entry[32..36]) >> 16;
+    // Version 1 adds four fields of packet sizes; version 2, which `lpcm`
+    // uses, moves the rate, channel count and sample layout into its own.
+    let mut lpcm_flags = None;
+    let boxes_at = match version {
+        1 => 52,
+        2 => {
+            let fields = entry
+                .get(36..72)
+                .ok_or_else(|| anyhow!("version 2 sound description is truncated"))?;
+            sample_rate = f64::from_bits(u64::from_be_bytes(fields[4..12].try_into()?)) as u32;
+            channels = read_u32(&fields[12..16]) as u16;
+            sample_size = read_u32(&fields[20..24]) as u16;
+            lpcm_flags = Some(read_u32(&fields[24..28]));
+            72
+        }
+        _ => 36,
+    };
+
+    let mut object_type = None;
+    let mut codec_config = None;
+    let mut pcm_config = None;
+    for child in codec_boxes(entry.get(boxes_at..).unwrap_or_default()) {
+        match child.kind.as_str() {
+            "esds" => {
+                if let Some((kind, info)) = es_decoder_config(child.data) {
+                    object_type = Some(kind);
+                    codec_config = Some(info);
+                }
+            }
+            "dOps" => codec_config = opus_head(child.data),
+            "dfLa" => {
+                codec_config = child
+                    .data
+                    .get(4..)
+                    .map(|blocks| [b"fLaC".as_slice(), blocks].concat());
+            }
+            // Its flags' low bit marks little-endian samples.
+            "pcmC" => {
+                pcm_config = child
+                    .data
+                    .get(4..6)
+                    .map(|fields| (fields[0] & 1 == 0, fields[1]))
+            }
+            _ => {}
+        }
+    }
+
+    let pcm = match &fourcc {
+        b"lpcm" => match lpcm_flags {
+            // Bit 0 marks float samples and bit 1 big-endian ones.
+            Some(flags) => pcm_format(sample_size, flags & 1 != 0, flags & 2 != 0),
+            None => Some(SampleFormat::S16 { big_endian: false }),
+        },
+        b"sowt" => Some(SampleFormat::S16 { big_endian: false }),
+        b"twos" => Some(SampleFormat::S16 { big_endian: true }),
+        b"in24" => Some(SampleFormat::S24 { big_endian: true }),
+        b"in32" => Some(SampleFormat::S32 { big_endian: true }),
+        b"fl32" => Some(SampleFormat::F32 { big_endian: true }),
+        b"fl64" => Some(SampleFormat::F64 { big_endian: true }),
+        b"f32 " => Some(SampleFormat::F32 { big_endian: false }),
+        b"raw " => Some(SampleFormat::U8),
+        b"ipcm" | b"fpcm" => pcm_config.and_then(|(big_endian, bits)| {
+            pcm_format(u16::from(bits), &fourcc == b"fpcm", big_endian)
+        }),
+        _ => None,
+    };
+    let codec = match (&fourcc, pcm) {
+        (_, Some(format)) => format.codec(),
+        // The MPEG-4 object types of AAC and of MPEG audio layer III.
+        (b"mp4a", _) => match object_type {
+            Some(0x40 | 0x66 | 0x67 | 0x68) => AudioCodec::Aac,
+            Some(0x69 | 0x6B) => AudioCodec::Mp3,
+            _ => AudioCodec::Unknown,
+        },
+        (b"aac ", _) => AudioCodec::Aac,
+        (b".mp3", _) => AudioCodec::Mp3,
+        (b"Opus", _) => AudioCodec::Opus,
+        (b"fLaC", _) => AudioCodec::Flac,
+        _ => AudioCodec::Unknown,
+    };
+    Ok(SoundEntry {
+        codec,
+        sample_rate,
+        channels,
+        pcm,
+        codec_config,
+    })
+}
+
+/// The boxes after a sound description's fields, with those QuickTime
+/// nests in a `wave` box. Anything unreadable there is left out rather than
+/// failing the file.
+fn codec_boxes(data: &[u8]) -> Vec<Atom<'_>> {
+    let mut boxes = Vec::new();
+    let mut cursor = Cursor::new(data);
+    while let Ok(Some(atom)) = read_atom(&mut cursor) {
+        if atom.kind == "wave" {
+            boxes.extend(codec_boxes(atom.data));
+        } else {
+            boxes.push(atom);
+        }
+    }
+    boxes
+}
+
+/// How PCM samples of `bits` bits are stored.
+fn pcm_format(bits: u16, float: bool, big_endian: bool) -> Option<SampleFormat> {
+    match (bits, float) {
+        (16, false) => Some(SampleFormat::S16 { big_endian }),
+        (24, false) => Some(SampleFormat::S24 { big_endian }),
+        (32, false) => Some(SampleFormat::S32 { big_endian }),
+        (32, true) => Some(SampleFormat::F32 { big_endian }),
+        (64, true) => Some(SampleFormat::F64 { big_endian }),
+        _ => None,
+    }
+}
+
+/// The object type and decoder specific info (for AAC, the
+/// AudioSpecificConfig) in an `esds` box's ES descriptor.
+fn es_decoder_config(esds: &[u8]) -> Option<(u8, Vec<u8>)> {
+    let (3, es) = descriptor(esds.get(4..)?)? else {
+        return None;
+    };
+    // An ES ID, then flags for a depended-on ID, a URL and an OCR ID.
+    let flags = *es.get(2)?;
+    let mut at = 3;
+    if flags & 0x80 != 0 {
+        at += 2;
+    }
+    if flags & 0x40 != 0 {
+        at += 1 + usize::from(*es.get(at)?);
+    }
+    if flags & 0x20 != 0 {
+        at += 2;
+    }
+    let (4, config) = descriptor(es.get(at..)?)? else {
+        return None;
+    };
+    // The object type, stream type, buffer size and bit rates come first.
+    let info = match config.get(13..).and_then(descriptor) {
+        Some((5, info)) => info.to_vec(),
+        _ => Vec::new(),
+    };
+    Some((*config.first()?, info))
+}
+
+/// An MPEG-4 descriptor's tag and payload. Its size is coded seven bits a
+/// byte, in up to four bytes.
+fn descriptor(data: &[u8]) -> Option<(u8, &[u8])> {
+    let tag = *data.first()?;
+    let mut size = 0;
+    let mut at = 1;
+    loop {
+        let byte = *data.get(at)?;
+        at += 1;
+        size = size << 7 | usize::from(byte & 0x7F);
+        if byte & 0x80 == 0 || at == 5 {
+            break;
+        }
+    }
+    Some((tag, data.get(at..at + size)?))
+}
+
+/// The `OpusHead` packet whose fields a `dOps` box holds big-endian.
+fn opus_head(dops: &[u8]) -> Option<Vec<u8>> {
+    let fields = dops.get(..11)?;
+    let mut head = b"OpusHead".to_vec();
+    head.extend([1, fields[1]]);
+    head.extend(u16::from_be_bytes([fields[2], fields[3]]).to_le_bytes());
+    head.extend(u32::from_be_bytes(fields[4..8].try_into().ok()?).to_le_bytes());
+    head.extend(i16::from_be_bytes([fields[8], fields[9]]).to_le_bytes());
+    // The mapping family and any table after it read the same way.
+    head.extend(&dops[10..]);
+    Some(head)
+}
+
+/// The timescale and duration of an `mvhd` or `mdhd` box, whose version 1
+/// widens the duration to 64 bits.
+fn header_fields(data: &[u8], kind: &str) -> Result<(u32, u64)> {
+    match data.first() {
+        Some(1) => {
+            let duration = data
+                .get(24..32)
+                .ok_or_else(|| anyhow!("{kind} atom is truncated"))?;
+            Ok((
+                table_u32(data, 20, kind)?,
+                u64::from_be_bytes(duration.try_into()?),
+            ))
+        }
+        Some(_) => Ok((
+            table_u32(data, 12, kind)?,
+            u64::from(table_u32(data, 16, kind)?),
+        )),
+        None => bail!("{kind} missing version"),
+    }
+}
+
+/// The duration an `mvhd` or `mdhd` box records, unless it is unknown.
+fn header_duration(data: &[u8], kind: &str) -> Result<Option<Duration>> {
+    let (timescale, duration) = header_fields(data, kind)?;
+    // All ones marks a duration the muxer did not know.
+    let known = duration > 0 && duration != u64::MAX && duration != u64::from(u32::MAX);
+    Ok(known.then(|| ticks_to_duration(duration, timescale)))
+}
+
+/// How many samples `stsz` lists, and their total size in bytes.
+fn sample_totals(stsz: &[u8]) -> Result<(u64, u64)> {
+    let uniform_size = table_u32(stsz, 4, "stsz")?;
+    let count = table_u32(stsz, 8, "stsz")?;
+    if uniform_size != 0 {
+        return Ok((u64::from(count), u64::from(count) * u64::from(uniform_size)));
+    }
+    if (stsz.len() - 12) / 4 < count as usize {
+        bail!("stsz atom is truncated");
+    }
+    let total = stsz[12..]
+        .chunks_exact(4)
+        .take(count as usize)
+        .map(|size| u64::from(read_u32(size)))
+        .sum();
+    Ok((u64::from(count), total))
+}
+
+/// Big-endian `u32` at `at`, or an error naming the truncated `kind` box.
+fn table_u32(data: &[u8], at: usize, kind: &str) -> Result<u32> {
+    data.get(at..at + 4)
+        .map(read_u32)
+        .ok_or_else(|| anyhow!("{kind} atom is truncated"))
+}
+
+/// The fields of each entry of a full box's table: the entry count follows
+/// the version and flags, then `count` entries of `N` fields each.
+fn table_entries<const N: usize>(data: &[u8], kind: &str) -> Result<Vec<[u32; N]>> {
+    let count = table_u32(data, 4, kind)? as usize;
+    if (data.len() - 8) / (4 * N) < count {
+        bail!("{kind} atom is truncated");
+    }
+    Ok(data[8..]
+        .chunks_exact(4 * N)
+        .take(count)
+        .map(|entry| std::array::from_fn(|field| read_u32(&entry[field * 4..])))
+        .collect())
+}
+
+/// The media time the first edit of an `elst` box presents from, in the
+/// track's timescale. Empty edits, which only delay the track, are skipped.
+fn edit_list_start(elst: &[u8]) -> Result<u64> {
+    let version = *elst
+        .first()
+        .ok_or_else(|| anyhow!("elst missing version"))?;
+    let count = table_u32(elst, 4, "elst")? as usize;
+    // segment_duration and media_time, 64-bit in version 1, then the rate.
+    let (entry_len, time_len) = if version == 1 { (20, 8) } else { (12, 4) };
+    for index in 0..count {
+        let at = 8 + index * entry_len;
+        let media_time = elst
+            .get(at + time_len..at + 2 * time_len)
+            .ok_or_else(|| anyhow!("elst atom is truncated"))?;
+        let media_time = match *media_time {
+            [a, b, c, d] => i64::from(i32::from_be_bytes([a, b, c, d])),
+            _ => i64::from_be_bytes(media_time.try_into()?),
+        };
+        if media_time >= 0 {
+            return Ok(media_time as u64);
+        }
+    }
+    Ok(0)
+}
+
+/// Each sample's composition offset from `ctts`, in `timescale` units.
+/// Offsets are read as signed, as version 1 defines them and as many
+/// version 0 writers store them anyway.
+fn composition_offsets(ctts: &[u8], count: usize) -> Result<Vec<i64>> {
+    let mut offsets = Vec::with_capacity(count);
+    for [run, offset] in table_entries::<2>(ctts, "ctts")? {
+        let run = (run as usize).min(count - offsets.len());
+        offsets.extend(std::iter::repeat_n(i64::from(offset as i32), run));
+    }
+    // Samples the table does not cover are presented as they are decoded.
+    offsets.resize(count, 0);
+    Ok(offsets)
+}
+
+/// Each sample's decoding time and duration, in `timescale` units.
+fn sample_times(stts: &[u8], count: usize) -> Result<Vec<(u64, u32)>> {
+    let mut times = Vec::with_capacity(count);
+    let mut time = 0u64;
+    let mut last_delta = 0;
+    'runs: for [run, delta] in table_entries::<2>(stts, "stts")? {
+        for _ in 0..run {
+            if times.len() == count {
+                break 'runs;
+            }
+            times.push((time, delta));
+            time += u64::from(delta);
+        }
+        last_delta = delta;
+    }
+    // Samples the table does not cover keep the last duration.
+    while times.len() < count {
+        times.push((time, last_delta));
+        time += u64::from(last_delta);
+    }
+    Ok(times)
+}
+
+fn ticks_to_duration(ticks: u64, timescale: u32) -> Duration {
+    let nanos = u128::from(ticks) * 1_000_000_000 / u128::from(timescale.max(1));
+    Duration::from_nanos(nanos as u64)
+}
+
+/// Locates every sample in a file of `file_len` bytes through the chunk offsets (`stco` or
+/// `co64`), samples per chunk (`stsc`) and sample sizes (`stsz`), and times
+/// it with `stts` and `ctts`, presenting from `media_start`. Samples listed
+/// in `stss`, or all of them without it, are keyframes.
+fn resolve_samples(
+    tables: &SampleTables<'_>,
+    file_len: u64,
+    timescale: u32,
+    media_start: u64,
+) -> Result<Vec<SampleLocation>> {
+    let (Some(stsz), Some(stsc), Some(stts)) = (tables.stsz, tables.stsc, tables.stts) else {
+        return Ok(Vec::new());
+    };
+    let uniform_size = table_u32(stsz, 4, "stsz")?;
+    let count = table_u32(stsz, 8, "stsz")? as usize;
+    let sizes: Vec<u32> = if uniform_size != 0 {
+        if file_len < count as u64 * u64::from(uniform_size) {
+            bail!("stsz lists more sample data than the file holds");
+        }
+        vec![uniform_size; count]
+    } else {
+        if (stsz.len() - 12) / 4 < count {
+            bail!("stsz atom is truncated");
+        }
+        stsz[12..]
+            .chunks_exact(4)
+            .take(count)
+            .map(read_u32)
+            .collect()
+    };
+    let chunk_offsets: Vec<u64> = match (tables.stco, tables.co64) {
+        (Some(stco), _) => table_entries::<1>(stco, "stco")?
+            .into_iter()
+            .map(|[offset]| u64::from(offset))
+            .collect(),
+        (None, Some(co64)) => table_entries::<2>(co64, "co64")?
+            .into_iter()
+            .map(|[high, low]| u64::from(high) << 32 | u64::from(low))
+            .collect(),
+        (None, None) => bail!("sample table has no chunk offsets"),
+    };
+    let stsc = table_entries::<3>(stsc, "stsc")?;
+
+    let mut ranges = Vec::with_capacity(count);
+    let mut run = 0;
+    for (index, &chunk_offset) in chunk_offsets.iter().enumerate() {
+        let chunk = index as u32 + 1;
+        while stsc.get(run + 1).is_some_and(|next| next[0] <= chunk) {
+            run += 1;
+        }
+        let per_chunk = stsc.get(run).map_or(0, |entry| entry[1]);
+        let mut offset = chunk_offset;
+        for _ in 0..per_chunk {
+            let Some(&size) = sizes.get(ranges.len()) else {
+                break;
+            };
+            let end = offset
+                .checked_add(u64::from(size))
+                .filter(|&end| end <= file_len)
+                .ok_or_else(|| anyhow!("sample {} lies outside the file", ranges.len() + 1))?;
+            ranges.push((offset, size));
+            offset = end;
+        }
+    }
+    if ranges.len() < count {
+        bail!(
+            "sample table places only {} of {count} samples in chunks",
+            ranges.len()
+        );
+    }
+
+    let mut keyframes = vec![tables.stss.is_none(); count];
+    if let Some(stss) = tables.stss {
+        for [number] in table_entries::<1>(stss, "stss")? {
+            if let Some(keyframe) = (number as usize)
+                .checked_sub(1)
+                .and_then(|index| keyframes.get_mut(index))
+            {
+                *keyframe = true;
+            }
+        }
+    }
+
+    let times = sample_times(stts, count)?;
+    let offsets = match tables.ctts {
+        Some(ctts) => composition_offsets(ctts, count)?,
+        None => vec![0; count],
+    };
+    Ok(ranges
+        .into_iter()
+        .zip(times)
+        .zip(offsets)
+        .zip(keyframes)
+        .map(
+            |((((offset, size), (time, delta)), composition), keyframe)| {
+                // Samples before the edit list's start are clamped to it.
+                let presentation = time
+                    .saturating_add_signed(composition)
+                    .saturating_sub(media_start);
+                SampleLocation {
+                    offset,
+                    size,
+                    timestamp: ticks_to_duration(presentation, timescale),
+                    decode_time: ticks_to_duration(time, timescale),
+                    duration: ticks_to_duration(u64::from(delta), timescale),
+                    keyframe,
+                }
+            },
+        )
+        .collect())
+}
+
+/// A constant rate when every sample lasts equally long in `stts`, otherwise
+/// variable.
+fn sample_frame_rate(tables: &SampleTables<'_>, timescale: u32) -> Result<FrameRate> {
+    let Some(stts) = tables.stts else {
+        return Ok(FrameRate::Variable);
+    };
+    let runs = table_entries::<2>(stts, "stts")?;
+    let mut deltas = runs
+        .iter()
+        .filter(|[run, _]| *run > 0)
+        .map(|[_, delta]| *delta);
+    Ok(match deltas.next() {
+        Some(delta) if delta > 0 && timescale > 0 && deltas.all(|other| other == delta) => {
+            let divisor = gcd(timescale, delta);
+            FrameRate::Constant {
+                numerator: timescale / divisor,
+                denominator: delta / divisor,
+            }
+        }
+        _ => FrameRate::Variable,
+    })
+}
+
+fn gcd(mut a: u32, mut b: u32) -> u32 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// The clockwise rotation the `a`, `b`, `c` and `d` entries of a `tkhd`
+/// matrix describe; 0 for anything other than a quarter turn.
+fn matrix_rotation([a, b, c, d]: [i32; 4]) -> u16 {
+    const ONE: i32 = 0x1_0000;
+    const MINUS_ONE: i32 = -ONE;
+    match (a, b, c, d) {
+        (0, ONE, MINUS_ONE, 0) => 90,
+        (MINUS_ONE, 0, 0, MINUS_ONE) => 180,
+        (0, MINUS_ONE, ONE, 0) => 270,
+        _ => 0,
     }
 }
 
@@ -294,6 +1138,262 @@ fn read_u32(buf: &[u8]) -> u32 {
     u32::from_be_bytes(bytes)
 }
 
+/// Whether `data` starts with a box an MP4 file opens with.
+pub fn is_mp4(data: &[u8]) -> bool {
+    data.get(4..8).is_some_and(|kind| {
+        matches!(
+            kind,
+            b"ftyp" | b"styp" | b"moov" | b"mdat" | b"free" | b"skip" | b"wide"
+        )
+    })
+}
+
+pub fn probe(data: &[u8]) -> Result<MediaInfo> {
+    Mp4Demuxer::new(data).probe()
+}
+
 pub fn demux_media(data: &[u8]) -> Result<MediaStreams> {
     Mp4Demuxer::new(data).demux()
 }
+
+pub fn video_samples(data: &[u8]) -> Result<Option<VideoSamples<'_>>> {
+    Mp4Demuxer::new(data).video_samples()
+}
+
+pub fn audio_samples(data: &[u8]) -> Result<Option<AudioSamples<'_>>> {
+    Mp4Demuxer::new(data).audio_samples()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full_box(entries: &[u32]) -> Vec<u8> {
+        let mut out = vec![0; 4];
+        out.extend(entries.iter().flat_map(|value| value.to_be_bytes()));
+        out
+    }
+
+    #[test]
+    fn sample_tables_locate_samples_across_chunks() {
+        // Five samples in chunks of two, two and one, at offsets 4, 20 and 40.
+        let mut file = [0u8; 48];
+        for (index, byte) in file.iter_mut().enumerate() {
+            *byte = index as u8;
+        }
+        let stsz = full_box(&[0, 5, 3, 4, 5, 6, 2]);
+        let stsc = full_box(&[2, 1, 2, 1, 3, 1, 1]);
+        let co64 = full_box(&[3, 0, 4, 0, 20, 0, 40]);
+        // Two samples of 10 ticks, then three of 20, at a 100 Hz timescale.
+        let stts = full_box(&[2, 2, 10, 3, 20]);
+        let stss = full_box(&[2, 1, 4]);
+        let tables = SampleTables {
+            stsz: Some(&stsz),
+            stsc: Some(&stsc),
+            co64: Some(&co64),
+            stts: Some(&stts),
+            stss: Some(&stss),
+            ..SampleTables::default()
+        };
+
+        let samples = resolve_samples(&tables, file.len() as u64, 100, 0).unwrap();
+        let data: Vec<&[u8]> = samples
+            .iter()
+            .map(|sample| &file[sample.offset as usize..][..sample.size as usize])
+            .collect();
+        assert_eq!(
+            data,
+            [
+                &file[4..7],
+                &file[7..11],
+                &file[20..25],
+                &file[25..31],
+                &file[40..42]
+            ]
+        );
+        let times: Vec<u64> = samples
+            .iter()
+            .map(|sample| sample.timestamp.as_millis() as u64)
+            .collect();
+        assert_eq!(times, [0, 100, 200, 400, 600]);
+        assert_eq!(samples[4].duration, Duration::from_millis(200));
+        let keyframes: Vec<bool> = samples.iter().map(|sample| sample.keyframe).collect();
+        assert_eq!(keyframes, [true, false, false, true, false]);
+        assert!(matches!(
+            sample_frame_rate(&tables, 100).unwrap(),
+            FrameRate::Variable
+        ));
+
+        // A sample running past the end of the file is an error.
+        assert!(resolve_samples(&tables, 41, 100, 0).is_err());
+    }
+
+    /// A reader counting the bytes read through it.
+    struct CountingReader<R> {
+        inner: R,
+        read: u64,
+    }
+
+    impl<R: Read> Read for CountingReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let count = self.inner.read(buf)?;
+            self.read += count as u64;
+            Ok(count)
+        }
+    }
+
+    impl<R: Seek> Seek for CountingReader<R> {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn streaming_sources_are_read_a_sample_at_a_time() {
+        let sample = |fill: u8, keyframe| crate::video::EncodedSample {
+            data: vec![fill; 256 * 1024],
+            timestamp: Duration::ZERO,
+            duration: Duration::from_millis(40),
+            keyframe,
+        };
+        let file = crate::video::muxer::write_mp4(&crate::video::EncodedVideo {
+            codec: VideoCodec::H264,
+            width: 16,
+            height: 16,
+            frame_rate: FrameRate::Constant {
+                numerator: 25,
+                denominator: 1,
+            },
+            config: vec![1, 0x42, 0xC0, 0x0A, 0xFF, 0xE0, 0],
+            samples: vec![sample(1, true), sample(2, false), sample(3, false)],
+            rotation: 0,
+            hdr: HdrMetadata::default(),
+        })
+        .unwrap();
+
+        let mut demuxer = Mp4Demuxer::from_reader(CountingReader {
+            inner: Cursor::new(&file),
+            read: 0,
+        })
+        .unwrap();
+        let track = demuxer.video_track().unwrap().unwrap();
+        assert_eq!(track.samples.len(), 3);
+        // Only the box headers and moov were read, not mdat.
+        assert!(
+            demuxer.reader.read < 1024,
+            "{} bytes read",
+            demuxer.reader.read
+        );
+
+        let data = demuxer.read_sample(&track.samples[1]).unwrap();
+        assert_eq!(data, vec![2; 256 * 1024]);
+        assert!(demuxer.reader.read < 257 * 1024);
+        assert_eq!(track.samples[2].timestamp, Duration::from_millis(80));
+        assert!(!track.samples[2].keyframe);
+
+        // The same samples, sliced out of the file in memory.
+        let samples = track.samples_in(&file);
+        assert_eq!(samples.samples[0].data, &[1; 256 * 1024][..]);
+    }
+
+    #[test]
+    fn composition_offsets_and_edit_lists_give_presentation_times() {
+        // I P B B decoded at 0, 10, 20, 30 and presented as I B B P, with
+        // the usual one-frame composition delay an edit list takes back.
+        let file = [0u8; 4];
+        let stsz = full_box(&[1, 4]);
+        let stsc = full_box(&[1, 1, 4, 1]);
+        let stco = full_box(&[1, 0]);
+        let stts = full_box(&[1, 4, 10]);
+        let ctts = full_box(&[3, 1, 10, 1, 30, 2, 0]);
+        let tables = SampleTables {
+            stsz: Some(&stsz),
+            stsc: Some(&stsc),
+            stco: Some(&stco),
+            stts: Some(&stts),
+            ctts: Some(&ctts),
+            ..SampleTables::default()
+        };
+        // An empty edit (media time -1), then the track from tick 10.
+        let elst = full_box(&[2, 10, u32::MAX, 0x1_0000, 40, 10, 0x1_0000]);
+        let media_start = edit_list_start(&elst).unwrap();
+        assert_eq!(media_start, 10);
+
+        let samples = resolve_samples(&tables, file.len() as u64, 1000, media_start).unwrap();
+        let times: Vec<(u128, u128)> = samples
+            .iter()
+            .map(|sample| (sample.decode_time.as_millis(), sample.timestamp.as_millis()))
+            .collect();
+        assert_eq!(times, [(0, 0), (10, 30), (20, 10), (30, 20)]);
+    }
+
+    /// An audio sample entry of `kind`, QuickTime `version`, with `fields`
+    /// after the common ones and `boxes` after those.
+    fn sound_entry_bytes(kind: &[u8; 4], version: u16, fields: &[u8], boxes: &[u8]) -> Vec<u8> {
+        let mut entry = vec![0; 8];
+        entry[4..].copy_from_slice(kind);
+        entry.extend([0; 8]);
+        entry.extend(version.to_be_bytes());
+        entry.extend([0; 6]);
+        entry.extend(2u16.to_be_bytes());
+        entry.extend(16u16.to_be_bytes());
+        entry.extend([0; 4]);
+        entry.extend((44_100u32 << 16).to_be_bytes());
+        entry.extend(fields);
+        entry.extend(boxes);
+        let len = entry.len() as u32;
+        entry[..4].copy_from_slice(&len.to_be_bytes());
+        entry
+    }
+
+    fn boxed(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut out = (8 + payload.len() as u32).to_be_bytes().to_vec();
+        out.extend(kind);
+        out.extend(payload);
+        out
+    }
+
+    #[test]
+    fn sound_entries_give_codec_setups_in_matroska_form() {
+        // AAC-LC at 44.1 kHz in stereo, its AudioSpecificConfig nested in
+        // the ES and decoder config descriptors.
+        let mut decoder_config = vec![0x40, 0x15, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        decoder_config.extend([5, 2, 0x12, 0x10]);
+        let mut es = vec![0, 1, 0, 4, decoder_config.len() as u8];
+        es.extend(&decoder_config);
+        let mut esds = vec![0, 0, 0, 0, 3, es.len() as u8];
+        esds.extend(&es);
+        let aac = sound_entry(&sound_entry_bytes(b"mp4a", 0, &[], &boxed(b"esds", &esds))).unwrap();
+        assert!(matches!(aac.codec, AudioCodec::Aac));
+        assert_eq!((aac.sample_rate, aac.channels), (44_100, 2));
+        assert_eq!(aac.codec_config.unwrap(), [0x12, 0x10]);
+
+        let dops = [0, 2, 0x01, 0x38, 0, 0, 0xBB, 0x80, 0, 0, 0];
+        let opus =
+            sound_entry(&sound_entry_bytes(b"Opus", 0, &[], &boxed(b"dOps", &dops))).unwrap();
+        let head = crate::audio::opus::OpusHead::parse(&opus.codec_config.unwrap()).unwrap();
+        assert_eq!((head.channels, head.pre_skip), (2, 312));
+        assert_eq!(head.input_sample_rate, 48_000);
+
+        let mut dfla = vec![0; 4];
+        dfla.extend([0x80, 0, 0, 34]);
+        dfla.extend([0; 34]);
+        let flac =
+            sound_entry(&sound_entry_bytes(b"fLaC", 0, &[], &boxed(b"dfLa", &dfla))).unwrap();
+        assert_eq!(flac.codec_config.unwrap()[..5], *b"fLaC\x80");
+
+        // QuickTime's version 2 lpcm entry: 48 kHz, six channels of 24-bit
+        // little-endian integers.
+        let mut fields = 72u32.to_be_bytes().to_vec();
+        fields.extend(48_000f64.to_bits().to_be_bytes());
+        fields.extend(6u32.to_be_bytes());
+        fields.extend(0x7F00_0000u32.to_be_bytes());
+        fields.extend(24u32.to_be_bytes());
+        fields.extend(4u32.to_be_bytes());
+        fields.extend([0; 8]);
+        let lpcm = sound_entry(&sound_entry_bytes(b"lpcm", 2, &fields, &[])).unwrap();
+        assert_eq!((lpcm.sample_rate, lpcm.channels), (48_000, 6));
+        assert_eq!(lpcm.pcm, Some(SampleFormat::S24 { big_endian: false }));
+        assert!(matches!(lpcm.codec, AudioCodec::PcmS24));
+    }
+}
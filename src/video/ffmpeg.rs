@@ -0,0 +1,368 @@
+//! Decoding through FFmpeg's libavcodec, for codecs bunker-convert has no
+//! native decoder for and for hardware decoding. Only built with a feature
+//! that needs it.
+
+use anyhow::{Context, Result, anyhow, bail};
+use ffmpeg::codec::Id;
+use ffmpeg::color::{Space, TransferCharacteristic};
+use ffmpeg::ffi::AVHWDeviceType;
+use ffmpeg::util::format::Pixel;
+use ffmpeg_next as ffmpeg;
+
+use crate::video::container::VideoSamples;
+use crate::video::{
+    ColorSpace, FramePlanes, MediaStreams, PixelFormat, TransferFunction, VideoCodec, VideoFrame,
+    VideoStream,
+};
+
+/// Which libavcodec decoder to open and how.
+pub(crate) struct DecoderSpec<'a> {
+    pub id: Id,
+    /// A decoder to prefer over libavcodec's default for `id`, such as
+    /// `libdav1d`.
+    pub name: Option<&'static str>,
+    pub codec: VideoCodec,
+    /// Codec configuration passed to the decoder as its extradata.
+    pub extradata: Option<&'a [u8]>,
+}
+
+/// Decodes `track`'s samples with the decoder `spec` describes into
+/// `streams.video`. Each packet carries its sample's index as its
+/// timestamp, so decoded frames take their timing from the sample they
+/// came from.
+#[cfg(any(feature = "vp9", feature = "av1", feature = "hevc"))]
+pub(crate) fn decode_samples(
+    spec: DecoderSpec<'_>,
+    track: &VideoSamples<'_>,
+    streams: &mut MediaStreams,
+) -> Result<()> {
+    decode(spec, None, track, streams).map(|_| ())
+}
+
+/// Like [`decode_samples`], with the decoder attached to a hardware device
+/// of type `device`. Returns whether the device decoded the frames:
+/// libavcodec quietly decodes in software when the device cannot handle
+/// the stream.
+#[cfg(any(feature = "nvdec", feature = "vaapi", feature = "videotoolbox"))]
+pub(crate) fn decode_samples_on(
+    spec: DecoderSpec<'_>,
+    device: AVHWDeviceType,
+    track: &VideoSamples<'_>,
+    streams: &mut MediaStreams,
+) -> Result<bool> {
+    decode(spec, Some(device), track, streams).map(|hardware_frames| hardware_frames > 0)
+}
+
+/// Decodes `track` into `streams.video`, returning how many frames came off
+/// the hardware device.
+fn decode(
+    spec: DecoderSpec<'_>,
+    device: Option<AVHWDeviceType>,
+    track: &VideoSamples<'_>,
+    streams: &mut MediaStreams,
+) -> Result<usize> {
+    let codec = spec.codec;
+    ffmpeg::init().context("failed to initialise FFmpeg")?;
+    let decoder = spec
+        .name
+        .and_then(ffmpeg::decoder::find_by_name)
+        .or_else(|| ffmpeg::decoder::find(spec.id))
+        .ok_or_else(|| anyhow!("the linked FFmpeg has no {codec:?} decoder"))?;
+    let mut context = ffmpeg::codec::Context::new_with_codec(decoder);
+    if let Some(extradata) = spec.extradata.filter(|data| !data.is_empty()) {
+        set_extradata(&mut context, extradata)?;
+    }
+    if let Some(device) = device {
+        attach_device(&mut context, device)?;
+    }
+    let mut decoder = context
+        .decoder()
+        .video()
+        .with_context(|| format!("failed to open the {codec:?} decoder"))?;
+
+    let mut output = DecodedFrames {
+        frames: Vec::new(),
+        color_space: ColorSpace::Unknown,
+        transfer: TransferFunction::Sdr,
+        hardware_frames: 0,
+    };
+    for (index, sample) in track.samples.iter().enumerate() {
+        let mut packet = ffmpeg::Packet::copy(sample.data);
+        packet.set_pts(Some(index as i64));
+        decoder
+            .send_packet(&packet)
+            .with_context(|| format!("sample {} is malformed", index + 1))?;
+        output.receive(&mut decoder, track)?;
+    }
+    decoder.send_eof().context("failed to flush the decoder")?;
+    output.receive(&mut decoder, track)?;
+
+    streams.video = Some(VideoStream {
+        codec,
+        frame_rate: track.frame_rate,
+        frames: output.frames,
+        color_space: output.color_space,
+        transfer: output.transfer,
+        rotation: 0,
+        // Frame side data is not read; the container's metadata stands.
+        hdr: track.hdr,
+    });
+    Ok(output.hardware_frames)
+}
+
+struct DecodedFrames {
+    frames: Vec<VideoFrame>,
+    color_space: ColorSpace,
+    transfer: TransferFunction,
+    /// Frames decoded in device memory and copied back.
+    hardware_frames: usize,
+}
+
+impl DecodedFrames {
+    /// Takes every frame the decoder has ready.
+    fn receive(
+        &mut self,
+        decoder: &mut ffmpeg::decoder::Video,
+        track: &VideoSamples<'_>,
+    ) -> Result<()> {
+        let mut frame = ffmpeg::frame::Video::empty();
+        loop {
+            match decoder.receive_frame(&mut frame) {
+                Ok(()) => {}
+                Err(ffmpeg::Error::Eof) => return Ok(()),
+                Err(ffmpeg::Error::Other { errno }) if errno == ffmpeg::error::EAGAIN => {
+                    return Ok(());
+                }
+                Err(err) => return Err(err).context("failed to decode a frame"),
+            }
+            let sample = frame
+                .pts()
+                .and_then(|pts| usize::try_from(pts).ok())
+                .and_then(|index| track.samples.get(index))
+                .ok_or_else(|| anyhow!("decoded frame does not match any sample"))?;
+            // The copy out of device memory carries only the pixels.
+            let (keyframe, color_space, transfer) = (
+                frame.is_key(),
+                frame.color_space(),
+                frame.color_transfer_characteristic(),
+            );
+            let mut transferred = ffmpeg::frame::Video::empty();
+            let frame = if matches!(
+                frame.format(),
+                Pixel::CUDA | Pixel::VAAPI | Pixel::VIDEOTOOLBOX
+            ) {
+                // SAFETY: both pointers are live frames; the transfer
+                // allocates the destination's buffers in system memory.
+                let status = unsafe {
+                    ffmpeg::ffi::av_hwframe_transfer_data(
+                        transferred.as_mut_ptr(),
+                        frame.as_ptr(),
+                        0,
+                    )
+                };
+                if status < 0 {
+                    return Err(ffmpeg::Error::from(status))
+                        .context("failed to copy a frame out of device memory");
+                }
+                self.hardware_frames += 1;
+                &transferred
+            } else {
+                &frame
+            };
+
+            let (width, height) = (frame.width(), frame.height());
+            let (luma_width, luma_height) = (width as usize, height as usize);
+            let (pixel_format, data) = match frame.format() {
+                Pixel::YUV420P => {
+                    let (chroma_width, chroma_height) =
+                        (luma_width.div_ceil(2), luma_height.div_ceil(2));
+                    (
+                        PixelFormat::Yuv420,
+                        FramePlanes::Yuv420 {
+                            y: plane(frame, 0, luma_width, luma_height),
+                            u: plane(frame, 1, chroma_width, chroma_height),
+                            v: plane(frame, 2, chroma_width, chroma_height),
+                        },
+                    )
+                }
+                // What hardware decoders hand back for 8-bit 4:2:0.
+                Pixel::NV12 => {
+                    let (chroma_width, chroma_height) =
+                        (luma_width.div_ceil(2), luma_height.div_ceil(2));
+                    let (u, v) = interleaved_plane(frame, 1, chroma_width, chroma_height);
+                    (
+                        PixelFormat::Yuv420,
+                        FramePlanes::Yuv420 {
+                            y: plane(frame, 0, luma_width, luma_height),
+                            u,
+                            v,
+                        },
+                    )
+                }
+                Pixel::YUV444P => (
+                    PixelFormat::Yuv444,
+                    FramePlanes::Yuv444 {
+                        y: plane(frame, 0, luma_width, luma_height),
+                        u: plane(frame, 1, luma_width, luma_height),
+                        v: plane(frame, 2, luma_width, luma_height),
+                    },
+                ),
+                // 10-bit video, HDR above all, is kept to its top 8 bits.
+                Pixel::YUV420P10LE => {
+                    let (chroma_width, chroma_height) =
+                        (luma_width.div_ceil(2), luma_height.div_ceil(2));
+                    (
+                        PixelFormat::Yuv420,
+                        FramePlanes::Yuv420 {
+                            y: deep_plane(frame, 0, luma_width, luma_height, 2),
+                            u: deep_plane(frame, 1, chroma_width, chroma_height, 2),
+                            v: deep_plane(frame, 2, chroma_width, chroma_height, 2),
+                        },
+                    )
+                }
+                Pixel::YUV444P10LE => (
+                    PixelFormat::Yuv444,
+                    FramePlanes::Yuv444 {
+                        y: deep_plane(frame, 0, luma_width, luma_height, 2),
+                        u: deep_plane(frame, 1, luma_width, luma_height, 2),
+                        v: deep_plane(frame, 2, luma_width, luma_height, 2),
+                    },
+                ),
+                // NV12's 10-bit sibling, with samples in the high bits.
+                Pixel::P010LE => {
+                    let (chroma_width, chroma_height) =
+                        (luma_width.div_ceil(2), luma_height.div_ceil(2));
+                    let (u, v) = deep_plane(frame, 1, 2 * chroma_width, chroma_height, 8)
+                        .chunks_exact(2)
+                        .map(|pair| (pair[0], pair[1]))
+                        .unzip();
+                    (
+                        PixelFormat::Yuv420,
+                        FramePlanes::Yuv420 {
+                            y: deep_plane(frame, 0, luma_width, luma_height, 8),
+                            u,
+                            v,
+                        },
+                    )
+                }
+                other => bail!(
+                    "decoded frames are {other:?}; only 4:2:0 and 4:4:4 video of 8 or 10 bits \
+                     is supported"
+                ),
+            };
+            match color_space {
+                Space::BT709 => self.color_space = ColorSpace::Bt709,
+                Space::BT470BG | Space::SMPTE170M => self.color_space = ColorSpace::Bt601,
+                Space::BT2020NCL => self.color_space = ColorSpace::Bt2020,
+                _ => {}
+            }
+            match transfer {
+                TransferCharacteristic::SMPTE2084 => self.transfer = TransferFunction::Pq,
+                TransferCharacteristic::ARIB_STD_B67 => self.transfer = TransferFunction::Hlg,
+                _ => {}
+            }
+            self.frames.push(VideoFrame {
+                width,
+                height,
+                pixel_format,
+                data,
+                timestamp: sample.timestamp,
+                duration: sample.duration,
+                keyframe,
+            });
+        }
+    }
+}
+
+/// Copies `data` into the codec context's extradata, which libavcodec
+/// frees with the context.
+fn set_extradata(context: &mut ffmpeg::codec::Context, data: &[u8]) -> Result<()> {
+    let padded = data.len() + ffmpeg::ffi::AV_INPUT_BUFFER_PADDING_SIZE as usize;
+    // SAFETY: the buffer is allocated with av_mallocz, zero-padded as
+    // libavcodec requires and owned by the context from here on.
+    unsafe {
+        let buffer = ffmpeg::ffi::av_mallocz(padded).cast::<u8>();
+        if buffer.is_null() {
+            bail!("failed to allocate decoder extradata");
+        }
+        std::ptr::copy_nonoverlapping(data.as_ptr(), buffer, data.len());
+        let context = context.as_mut_ptr();
+        (*context).extradata = buffer;
+        (*context).extradata_size = data.len() as i32;
+    }
+    Ok(())
+}
+
+/// Opens the default device of type `device` and hands the decoder a
+/// reference to it, so libavcodec decodes into device memory whenever the
+/// device supports the stream.
+fn attach_device(context: &mut ffmpeg::codec::Context, device: AVHWDeviceType) -> Result<()> {
+    let mut device_context = std::ptr::null_mut();
+    // SAFETY: av_hwdevice_ctx_create only writes `device_context` on
+    // success; the decoder takes its own reference and ours is released.
+    unsafe {
+        let status = ffmpeg::ffi::av_hwdevice_ctx_create(
+            &mut device_context,
+            device,
+            std::ptr::null(),
+            std::ptr::null_mut(),
+            0,
+        );
+        if status < 0 {
+            return Err(ffmpeg::Error::from(status))
+                .with_context(|| format!("failed to open a {device:?} device"));
+        }
+        (*context.as_mut_ptr()).hw_device_ctx = ffmpeg::ffi::av_buffer_ref(device_context);
+        ffmpeg::ffi::av_buffer_unref(&mut device_context);
+    }
+    Ok(())
+}
+
+/// Copies a plane's visible `width` x `height` bytes out of its padded rows.
+fn plane(frame: &ffmpeg::frame::Video, index: usize, width: usize, height: usize) -> Vec<u8> {
+    let stride = frame.stride(index);
+    frame
+        .data(index)
+        .chunks(stride)
+        .take(height)
+        .flat_map(|row| &row[..width])
+        .copied()
+        .collect()
+}
+
+/// Copies a plane of 16-bit little-endian samples `width` x `height` out of
+/// `frame`, shifting each right by `shift` bits down to 8.
+fn deep_plane(
+    frame: &ffmpeg::frame::Video,
+    index: usize,
+    width: usize,
+    height: usize,
+    shift: u32,
+) -> Vec<u8> {
+    let stride = frame.stride(index);
+    frame
+        .data(index)
+        .chunks(stride)
+        .take(height)
+        .flat_map(|row| row[..2 * width].chunks_exact(2))
+        .map(|sample| (u16::from_le_bytes([sample[0], sample[1]]) >> shift).min(255) as u8)
+        .collect()
+}
+
+/// Splits an interleaved two-channel plane, such as NV12's chroma, into its
+/// two `width` x `height` planes.
+fn interleaved_plane(
+    frame: &ffmpeg::frame::Video,
+    index: usize,
+    width: usize,
+    height: usize,
+) -> (Vec<u8>, Vec<u8>) {
+    let stride = frame.stride(index);
+    frame
+        .data(index)
+        .chunks(stride)
+        .take(height)
+        .flat_map(|row| row[..2 * width].chunks_exact(2))
+        .map(|pair| (pair[0], pair[1]))
+        .unzip()
+}
@@ -0,0 +1,259 @@
+//! Bit-level access to RBSP payloads (NAL units with emulation prevention
+//! bytes removed), for reading and for writing them.
+
+use anyhow::{Result, bail};
+
+pub(super) struct BitReader<'a> {
+    data: &'a [u8],
+    bit_pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, bit_pos: 0 }
+    }
+
+    fn bits_left(&self) -> usize {
+        (self.data.len() * 8).saturating_sub(self.bit_pos)
+    }
+
+    /// The next `count` (at most 32) bits without consuming them; bits past
+    /// the end read as zero.
+    pub fn peek_bits(&self, count: u32) -> u32 {
+        if count == 0 {
+            return 0;
+        }
+        let byte = self.bit_pos / 8;
+        let mut window = 0u64;
+        for offset in 0..5 {
+            let value = self.data.get(byte + offset).copied().unwrap_or(0);
+            window = (window << 8) | u64::from(value);
+        }
+        let shift = 40 - (self.bit_pos % 8) as u32 - count;
+        ((window >> shift) & ((1u64 << count) - 1)) as u32
+    }
+
+    pub fn skip_bits(&mut self, count: usize) -> Result<()> {
+        if count > self.bits_left() {
+            bail!("bitstream overread");
+        }
+        self.bit_pos += count;
+        Ok(())
+    }
+
+    pub fn read_bits(&mut self, count: u32) -> Result<u32> {
+        let value = self.peek_bits(count);
+        self.skip_bits(count as usize)?;
+        Ok(value)
+    }
+
+    pub fn read_flag(&mut self) -> Result<bool> {
+        Ok(self.read_bits(1)? == 1)
+    }
+
+    /// Unsigned Exp-Golomb, `ue(v)`.
+    pub fn read_ue(&mut self) -> Result<u32> {
+        let mut zeros = 0;
+        while !self.read_flag()? {
+            zeros += 1;
+            if zeros > 31 {
+                bail!("Exp-Golomb code is too long");
+            }
+        }
+        if zeros == 0 {
+            return Ok(0);
+        }
+        let suffix = self.read_bits(zeros)?;
+        Ok(((1u64 << zeros) - 1 + u64::from(suffix)) as u32)
+    }
+
+    /// Signed Exp-Golomb, `se(v)`.
+    pub fn read_se(&mut self) -> Result<i32> {
+        let code = i64::from(self.read_ue()?);
+        let value = if code % 2 == 0 {
+            -(code / 2)
+        } else {
+            (code + 1) / 2
+        };
+        Ok(value as i32)
+    }
+
+    /// Truncated Exp-Golomb, `te(v)`, for a syntax element whose largest
+    /// value is `max`.
+    pub fn read_te(&mut self, max: u32) -> Result<u32> {
+        if max == 1 {
+            Ok(u32::from(!self.read_flag()?))
+        } else {
+            self.read_ue()
+        }
+    }
+
+    /// Counts and consumes zero bits up to and including the next one bit.
+    pub fn read_leading_zeros(&mut self) -> Result<u32> {
+        let mut zeros = 0;
+        while !self.read_flag()? {
+            zeros += 1;
+        }
+        Ok(zeros)
+    }
+
+    pub fn align(&mut self) {
+        self.bit_pos = self.bit_pos.div_ceil(8) * 8;
+    }
+
+    /// Reads a whole byte; the reader must be byte aligned.
+    pub fn read_byte(&mut self) -> Result<u8> {
+        Ok(self.read_bits(8)? as u8)
+    }
+
+    /// `more_rbsp_data()`: whether anything but the stop bit and trailing
+    /// zeros is left.
+    pub fn more_rbsp_data(&self) -> bool {
+        let Some(last) = self.data.iter().rposition(|&byte| byte != 0) else {
+            return false;
+        };
+        let stop_bit = last * 8 + 7 - self.data[last].trailing_zeros() as usize;
+        self.bit_pos < stop_bit
+    }
+}
+
+/// Strips the `0x03` emulation prevention bytes out of a NAL unit payload.
+pub(super) fn unescape_rbsp(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &byte in data {
+        if zeros >= 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        out.push(byte);
+    }
+    out
+}
+
+/// Builds an RBSP most significant bit first.
+#[derive(Default)]
+pub(super) struct BitWriter {
+    bytes: Vec<u8>,
+    current: u8,
+    filled: u32,
+}
+
+impl BitWriter {
+    /// Writes the low `count` (at most 32) bits of `value`.
+    pub fn write_bits(&mut self, value: u32, count: u32) {
+        for shift in (0..count).rev() {
+            self.current = (self.current << 1) | ((value >> shift) & 1) as u8;
+            self.filled += 1;
+            if self.filled == 8 {
+                self.bytes.push(self.current);
+                self.current = 0;
+                self.filled = 0;
+            }
+        }
+    }
+
+    pub fn write_flag(&mut self, flag: bool) {
+        self.write_bits(u32::from(flag), 1);
+    }
+
+    pub fn write_ue(&mut self, value: u32) {
+        let code = u64::from(value) + 1;
+        let length = 64 - code.leading_zeros();
+        self.write_bits(0, length - 1);
+        // `code` needs up to 33 bits for `u32::MAX`.
+        if length > 32 {
+            self.write_bits(1, 1);
+            self.write_bits(code as u32, 32);
+        } else {
+            self.write_bits(code as u32, length);
+        }
+    }
+
+    pub fn write_se(&mut self, value: i32) {
+        let code = if value > 0 {
+            2 * value.unsigned_abs() - 1
+        } else {
+            2 * value.unsigned_abs()
+        };
+        self.write_ue(code);
+    }
+
+    /// Pads with zero bits to the next byte boundary.
+    pub fn align_zero(&mut self) {
+        while self.filled != 0 {
+            self.write_bits(0, 1);
+        }
+    }
+
+    /// Appends `rbsp_trailing_bits` and returns the payload.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.write_bits(1, 1);
+        self.align_zero();
+        self.bytes
+    }
+}
+
+/// Inserts the `0x03` emulation prevention bytes a NAL unit payload needs
+/// so that no start code appears inside it.
+pub(super) fn escape_rbsp(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 64);
+    let mut zeros = 0;
+    for &byte in data {
+        if zeros >= 2 && byte <= 3 {
+            out.push(3);
+            zeros = 0;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        out.push(byte);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_exp_golomb_codes_and_finds_the_stop_bit() {
+        // 1 | 010 | 011 | 00100 | 1 (stop bit)
+        let data = [0b1010_0110, 0b0100_1000];
+        let mut reader = BitReader::new(&data);
+        assert_eq!(reader.read_ue().unwrap(), 0);
+        assert_eq!(reader.read_ue().unwrap(), 1);
+        assert_eq!(reader.read_se().unwrap(), -1);
+        assert!(reader.more_rbsp_data());
+        assert_eq!(reader.read_ue().unwrap(), 3);
+        assert!(!reader.more_rbsp_data());
+        assert_eq!(reader.peek_bits(4), 0b1000);
+    }
+
+    #[test]
+    fn unescaping_drops_only_emulation_prevention_bytes() {
+        assert_eq!(
+            unescape_rbsp(&[0, 0, 3, 1, 0, 0, 3, 0, 3, 5]),
+            [0, 0, 1, 0, 0, 0, 3, 5]
+        );
+        let raw = [0, 0, 1, 0, 0, 0, 3, 5, 0, 0];
+        assert_eq!(unescape_rbsp(&escape_rbsp(&raw)), raw);
+    }
+
+    #[test]
+    fn written_codes_read_back() {
+        let mut writer = BitWriter::default();
+        writer.write_ue(0);
+        writer.write_ue(41);
+        writer.write_se(-7);
+        writer.write_se(7);
+        writer.write_bits(0b101, 3);
+        let data = writer.finish();
+        let mut reader = BitReader::new(&data);
+        assert_eq!(reader.read_ue().unwrap(), 0);
+        assert_eq!(reader.read_ue().unwrap(), 41);
+        assert_eq!(reader.read_se().unwrap(), -7);
+        assert_eq!(reader.read_se().unwrap(), 7);
+        assert_eq!(reader.read_bits(3).unwrap(), 0b101);
+        assert!(!reader.more_rbsp_data());
+    }
+}
@@ -0,0 +1,372 @@
+//! The two framings of H.264 NAL units: Annex B byte streams, which separate
+//! them with start codes and carry the parameter sets in band, and the AVCC
+//! samples of MP4 and Matroska, which prefix each unit with its length and
+//! keep the parameter sets in an `avcC` record ([`AvcConfig`]).
+
+use anyhow::{Context, Result, anyhow, bail};
+
+use super::bits::{BitReader, unescape_rbsp};
+use super::params::HIGH_PROFILES;
+
+/// NAL unit types of the sequence and picture parameter sets.
+const NAL_SPS: u8 = 7;
+const NAL_PPS: u8 = 8;
+
+/// Profiles whose `avcC` records end with the chroma format and bit depths
+/// (ISO/IEC 14496-15, 5.3.3.1.2).
+const RECORD_EXTENSION_PROFILES: [u8; 4] = [100, 110, 122, 144];
+
+/// Splits an Annex B byte stream on its three- and four-byte start codes.
+pub fn split_annex_b(data: &[u8]) -> Result<Vec<&[u8]>> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i..i + 3] == [0, 0, 1] {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    let mut units = Vec::with_capacity(starts.len());
+    for (index, &start) in starts.iter().enumerate() {
+        let mut end = starts.get(index + 1).map_or(data.len(), |next| next - 3);
+        // Trailing zeros belong to the next start code (or are padding).
+        while end > start && data[end - 1] == 0 {
+            end -= 1;
+        }
+        if end > start {
+            units.push(&data[start..end]);
+        }
+    }
+    if units.is_empty() {
+        bail!("no NAL units found");
+    }
+    Ok(units)
+}
+
+/// Splits a sample of NAL units, each prefixed with its big-endian
+/// `length_size`-byte length.
+pub fn split_length_prefixed(mut data: &[u8], length_size: usize) -> Result<Vec<&[u8]>> {
+    let mut units = Vec::new();
+    while !data.is_empty() {
+        let (unit, rest) = split_length_prefix(data, length_size)?;
+        if !unit.is_empty() {
+            units.push(unit);
+        }
+        data = rest;
+    }
+    Ok(units)
+}
+
+/// Rewrites the NAL units of an Annex B stream with `length_size`-byte
+/// length prefixes (1, 2 or 4), keeping every unit, parameter sets
+/// included.
+pub fn annex_b_to_avcc(data: &[u8], length_size: usize) -> Result<Vec<u8>> {
+    let units = split_annex_b(data)?;
+    let mut out = Vec::with_capacity(data.len() + units.len() * length_size);
+    for unit in units {
+        push_length_prefixed(&mut out, unit, length_size)?;
+    }
+    Ok(out)
+}
+
+/// Rewrites a sample of `length_size`-byte length-prefixed NAL units as an
+/// Annex B stream with four-byte start codes.
+pub fn avcc_to_annex_b(data: &[u8], length_size: usize) -> Result<Vec<u8>> {
+    let units = split_length_prefixed(data, length_size)?;
+    let mut out = Vec::with_capacity(data.len() + units.len() * 4);
+    for unit in units {
+        out.extend_from_slice(&[0, 0, 0, 1]);
+        out.extend_from_slice(unit);
+    }
+    Ok(out)
+}
+
+/// Splits `data` into the big-endian `length_size`-byte length it starts
+/// with, the bytes it covers and the rest.
+fn split_length_prefix(data: &[u8], length_size: usize) -> Result<(&[u8], &[u8])> {
+    if data.len() < length_size {
+        bail!("NAL unit length is truncated");
+    }
+    let (prefix, rest) = data.split_at(length_size);
+    let length = prefix
+        .iter()
+        .fold(0usize, |length, &byte| length << 8 | usize::from(byte));
+    if length > rest.len() {
+        bail!(
+            "NAL unit length {length} exceeds the {} bytes left",
+            rest.len()
+        );
+    }
+    Ok(rest.split_at(length))
+}
+
+fn push_length_prefixed(out: &mut Vec<u8>, unit: &[u8], length_size: usize) -> Result<()> {
+    if !matches!(length_size, 1 | 2 | 4) {
+        bail!("NAL unit lengths take 1, 2 or 4 bytes, not {length_size}");
+    }
+    if (unit.len() as u64) >> (8 * length_size) != 0 {
+        bail!(
+            "a {}-byte NAL unit does not fit a {length_size}-byte length",
+            unit.len()
+        );
+    }
+    out.extend_from_slice(&(unit.len() as u32).to_be_bytes()[4 - length_size..]);
+    out.extend_from_slice(unit);
+    Ok(())
+}
+
+/// An `AVCDecoderConfigurationRecord`, the payload of an `avcC` box or of a
+/// Matroska `V_MPEG4/ISO/AVC` track's CodecPrivate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AvcConfig {
+    pub profile_idc: u8,
+    /// The constraint flags byte of the SPS.
+    pub profile_compatibility: u8,
+    pub level_idc: u8,
+    /// Bytes in each sample's NAL unit length prefixes: 1, 2 or 4.
+    pub nal_length_size: u8,
+    /// SPS NAL units, header byte included.
+    pub sps: Vec<Vec<u8>>,
+    /// PPS NAL units, header byte included.
+    pub pps: Vec<Vec<u8>>,
+    /// `chroma_format_idc` and the luma and chroma bit depths, which High
+    /// profile records repeat from the SPS.
+    pub chroma_format: u8,
+    pub bit_depth_luma: u8,
+    pub bit_depth_chroma: u8,
+}
+
+impl AvcConfig {
+    /// A record for samples with four-byte lengths, taking the profile,
+    /// level and chroma format from the first SPS.
+    pub fn from_parameter_sets(sps: Vec<Vec<u8>>, pps: Vec<Vec<u8>>) -> Result<Self> {
+        let first = sps.first().ok_or_else(|| anyhow!("avcC needs an SPS"))?;
+        if first.len() < 4 || first[0] & 0x1F != NAL_SPS {
+            bail!("avcC parameter sets must start with an SPS NAL unit");
+        }
+        if pps.is_empty() {
+            bail!("avcC needs a PPS");
+        }
+        let (chroma_format, bit_depth_luma, bit_depth_chroma) = sps_chroma_format(first)?;
+        Ok(Self {
+            profile_idc: first[1],
+            profile_compatibility: first[2],
+            level_idc: first[3],
+            nal_length_size: 4,
+            chroma_format,
+            bit_depth_luma,
+            bit_depth_chroma,
+            sps,
+            pps,
+        })
+    }
+
+    /// A record for the SPS and PPS NAL units of an Annex B stream; repeated
+    /// parameter sets are listed once.
+    pub fn from_annex_b(data: &[u8]) -> Result<Self> {
+        let (mut sps, mut pps) = (Vec::new(), Vec::<Vec<u8>>::new());
+        for unit in split_annex_b(data)? {
+            let list = match unit[0] & 0x1F {
+                NAL_SPS => &mut sps,
+                NAL_PPS => &mut pps,
+                _ => continue,
+            };
+            if !list.iter().any(|known| known == unit) {
+                list.push(unit.to_vec());
+            }
+        }
+        Self::from_parameter_sets(sps, pps)
+    }
+
+    /// Parses an `AVCDecoderConfigurationRecord`.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < 6 || data[0] != 1 {
+            bail!("unsupported avcC record");
+        }
+        let nal_length_size = (data[4] & 3) + 1;
+        if nal_length_size == 3 {
+            bail!("avcC records 3-byte NAL unit lengths, which are reserved");
+        }
+        let mut rest = &data[5..];
+        let mut sets: [Vec<Vec<u8>>; 2] = Default::default();
+        // SPS count in the low five bits, then a PPS count byte after them.
+        for (list, count_mask) in sets.iter_mut().zip([0x1F, 0xFF]) {
+            let (&count, tail) = rest
+                .split_first()
+                .ok_or_else(|| anyhow!("avcC record is truncated"))?;
+            rest = tail;
+            for _ in 0..count & count_mask {
+                let (unit, tail) = split_length_prefix(rest, 2)?;
+                list.push(unit.to_vec());
+                rest = tail;
+            }
+        }
+        let [sps, pps] = sets;
+        let (chroma_format, bit_depth_luma, bit_depth_chroma) =
+            match (RECORD_EXTENSION_PROFILES.contains(&data[1]), rest) {
+                (true, [chroma, luma, chroma_depth, ..]) => {
+                    (chroma & 3, (luma & 7) + 8, (chroma_depth & 7) + 8)
+                }
+                // Many writers leave the extension out; the SPS says it too.
+                _ => match sps.first() {
+                    Some(first) => sps_chroma_format(first).unwrap_or((1, 8, 8)),
+                    None => (1, 8, 8),
+                },
+            };
+        Ok(Self {
+            profile_idc: data[1],
+            profile_compatibility: data[2],
+            level_idc: data[3],
+            nal_length_size,
+            sps,
+            pps,
+            chroma_format,
+            bit_depth_luma,
+            bit_depth_chroma,
+        })
+    }
+
+    /// The record's bytes, the `avcC` box payload.
+    pub fn to_record(&self) -> Vec<u8> {
+        let mut out = vec![
+            1,
+            self.profile_idc,
+            self.profile_compatibility,
+            self.level_idc,
+            0xFC | (self.nal_length_size - 1),
+            0xE0 | self.sps.len() as u8,
+        ];
+        for sps in &self.sps {
+            out.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+            out.extend_from_slice(sps);
+        }
+        out.push(self.pps.len() as u8);
+        for pps in &self.pps {
+            out.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+            out.extend_from_slice(pps);
+        }
+        if RECORD_EXTENSION_PROFILES.contains(&self.profile_idc) {
+            out.extend_from_slice(&[
+                0xFC | self.chroma_format,
+                0xF8 | (self.bit_depth_luma - 8),
+                0xF8 | (self.bit_depth_chroma - 8),
+                0, // numOfSequenceParameterSetExt
+            ]);
+        }
+        out
+    }
+
+    /// The parameter sets as an Annex B stream, SPS first, for prefixing
+    /// converted samples.
+    pub fn to_annex_b(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for unit in self.sps.iter().chain(&self.pps) {
+            out.extend_from_slice(&[0, 0, 0, 1]);
+            out.extend_from_slice(unit);
+        }
+        out
+    }
+}
+
+/// `chroma_format_idc` and the luma and chroma bit depths of an SPS NAL
+/// unit: 4:2:0 at 8 bits unless a High profile says otherwise.
+fn sps_chroma_format(sps: &[u8]) -> Result<(u8, u8, u8)> {
+    let rbsp = unescape_rbsp(&sps[1..]);
+    let mut reader = BitReader::new(&rbsp);
+    let profile_idc = reader.read_bits(8)?;
+    if !HIGH_PROFILES.contains(&profile_idc) {
+        return Ok((1, 8, 8));
+    }
+    let parse = |reader: &mut BitReader<'_>| -> Result<(u8, u8, u8)> {
+        reader.skip_bits(16)?; // constraint flags, level_idc
+        let _id = reader.read_ue()?;
+        let chroma_format = reader.read_ue()?;
+        if chroma_format == 3 {
+            let _separate_colour_plane = reader.read_flag()?;
+        }
+        let luma = reader.read_ue()? + 8;
+        let chroma = reader.read_ue()? + 8;
+        if chroma_format > 3 || luma > 14 || chroma > 14 {
+            bail!("SPS chroma format or bit depth is out of range");
+        }
+        Ok((chroma_format as u8, luma as u8, chroma as u8))
+    };
+    parse(&mut reader).context("failed to read the SPS chroma format")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn annex_b_split_keeps_every_byte_of_the_last_unit() {
+        let data = [
+            0, 0, 0, 1, 0x67, 1, 2, 0, 0, 1, 0x68, 3, 0, 0, 1, 0x65, 4, 5, 6,
+        ];
+        let units = split_annex_b(&data).unwrap();
+        assert_eq!(
+            units,
+            vec![&[0x67, 1, 2][..], &[0x68, 3][..], &[0x65, 4, 5, 6][..]]
+        );
+        assert!(split_annex_b(&[1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn converts_between_annex_b_and_length_prefixes() {
+        let annex_b = [0, 0, 0, 1, 0x67, 1, 2, 0, 0, 1, 0x65, 4, 5, 6];
+        let avcc = annex_b_to_avcc(&annex_b, 2).unwrap();
+        assert_eq!(avcc, [0, 3, 0x67, 1, 2, 0, 4, 0x65, 4, 5, 6]);
+        assert_eq!(
+            avcc_to_annex_b(&avcc, 2).unwrap(),
+            [0, 0, 0, 1, 0x67, 1, 2, 0, 0, 0, 1, 0x65, 4, 5, 6]
+        );
+        assert!(annex_b_to_avcc(&annex_b, 3).is_err());
+        let long = [&[0, 0, 1, 0x65][..], &[7; 300]].concat();
+        assert!(annex_b_to_avcc(&long, 1).is_err());
+        assert!(avcc_to_annex_b(&[0, 9, 0x65], 2).is_err());
+    }
+
+    #[test]
+    fn builds_and_parses_avcc_records() {
+        // A constrained baseline SPS (id 0) and a PPS, repeated in band.
+        let annex_b = [
+            &[0, 0, 0, 1, 0x67, 0x42, 0xC0, 0x1E, 0xDA][..],
+            &[0, 0, 0, 1, 0x68, 0xCE, 0x38, 0x80],
+            &[0, 0, 0, 1, 0x65, 0x88],
+            &[0, 0, 0, 1, 0x67, 0x42, 0xC0, 0x1E, 0xDA],
+        ]
+        .concat();
+        let config = AvcConfig::from_annex_b(&annex_b).unwrap();
+        assert_eq!(config.sps.len(), 1);
+        let record = config.to_record();
+        assert_eq!(
+            record,
+            [
+                1, 0x42, 0xC0, 0x1E, 0xFF, 0xE1, 0, 5, 0x67, 0x42, 0xC0, 0x1E, 0xDA, 1, 0, 4, 0x68,
+                0xCE, 0x38, 0x80
+            ]
+        );
+        assert_eq!(AvcConfig::parse(&record).unwrap(), config);
+        assert_eq!(config.to_annex_b(), annex_b[..17]);
+
+        // High profile 4:2:2 at 10 bits: profile_idc 122, id 0 (`1`),
+        // chroma_format_idc 2 (`011`), both depths minus 8 equal to 2.
+        let sps = vec![0x67, 122, 0, 0x28, 0b1011_0110, 0b1100_0000];
+        let high = AvcConfig::from_parameter_sets(vec![sps], vec![vec![0x68, 0xCE]]).unwrap();
+        assert_eq!(
+            (
+                high.chroma_format,
+                high.bit_depth_luma,
+                high.bit_depth_chroma
+            ),
+            (2, 10, 10)
+        );
+        let record = high.to_record();
+        assert_eq!(record[record.len() - 4..], [0xFE, 0xFA, 0xFA, 0]);
+        assert_eq!(AvcConfig::parse(&record).unwrap(), high);
+
+        assert!(AvcConfig::from_annex_b(&[0, 0, 1, 0x65, 0x88]).is_err());
+    }
+}
@@ -0,0 +1,534 @@
+//! CAVLC residual coding (9.2): the VLC tables and `residual_block_cavlc`,
+//! read by the decoder and written by the encoder.
+
+use anyhow::{Result, bail};
+
+use super::bits::{BitReader, BitWriter};
+
+/// `coeff_token` code lengths by table (`0 <= nC < 2`, `2 <= nC < 4`,
+/// `4 <= nC < 8`, `8 <= nC`), indexed by `TotalCoeff * 4 + TrailingOnes`.
+/// Zero marks combinations that cannot occur.
+const COEFF_TOKEN_LEN: [[u8; 68]; 4] = [
+    [
+        1, 0, 0, 0, 6, 2, 0, 0, 8, 6, 3, 0, 9, 8, 7, 5, 10, 9, 8, 6, 11, 10, 9, 7, 13, 11, 10, 8,
+        13, 13, 11, 9, 13, 13, 13, 10, 14, 14, 13, 11, 14, 14, 14, 13, 15, 15, 14, 14, 15, 15, 15,
+        14, 16, 15, 15, 15, 16, 16, 16, 15, 16, 16, 16, 16, 16, 16, 16, 16,
+    ],
+    [
+        2, 0, 0, 0, 6, 2, 0, 0, 6, 5, 3, 0, 7, 6, 6, 4, 8, 6, 6, 4, 8, 7, 7, 5, 9, 8, 8, 6, 11, 9,
+        9, 6, 11, 11, 11, 7, 12, 11, 11, 9, 12, 12, 12, 11, 12, 12, 12, 11, 13, 13, 13, 12, 13, 13,
+        13, 13, 13, 14, 13, 13, 14, 14, 14, 13, 14, 14, 14, 14,
+    ],
+    [
+        4, 0, 0, 0, 6, 4, 0, 0, 6, 5, 4, 0, 6, 5, 5, 4, 7, 5, 5, 4, 7, 5, 5, 4, 7, 6, 6, 4, 7, 6,
+        6, 4, 8, 7, 7, 5, 8, 8, 7, 6, 9, 8, 8, 7, 9, 9, 8, 8, 9, 9, 9, 8, 10, 9, 9, 9, 10, 10, 10,
+        10, 10, 10, 10, 10, 10, 10, 10, 10,
+    ],
+    [
+        6, 0, 0, 0, 6, 6, 0, 0, 6, 6, 6, 0, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6,
+        6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6,
+        6, 6, 6, 6, 6, 6, 6, 6,
+    ],
+];
+
+const COEFF_TOKEN_CODE: [[u8; 68]; 4] = [
+    [
+        1, 0, 0, 0, 5, 1, 0, 0, 7, 4, 1, 0, 7, 6, 5, 3, 7, 6, 5, 3, 7, 6, 5, 4, 15, 6, 5, 4, 11,
+        14, 5, 4, 8, 10, 13, 4, 15, 14, 9, 4, 11, 10, 13, 12, 15, 14, 9, 12, 11, 10, 13, 8, 15, 1,
+        9, 12, 11, 14, 13, 8, 7, 10, 9, 12, 4, 6, 5, 8,
+    ],
+    [
+        3, 0, 0, 0, 11, 2, 0, 0, 7, 7, 3, 0, 7, 10, 9, 5, 7, 6, 5, 4, 4, 6, 5, 6, 7, 6, 5, 8, 15,
+        6, 5, 4, 11, 14, 13, 4, 15, 10, 9, 4, 11, 14, 13, 12, 8, 10, 9, 8, 15, 14, 13, 12, 11, 10,
+        9, 12, 7, 11, 6, 8, 9, 8, 10, 1, 7, 6, 5, 4,
+    ],
+    [
+        15, 0, 0, 0, 15, 14, 0, 0, 11, 15, 13, 0, 8, 12, 14, 12, 15, 10, 11, 11, 11, 8, 9, 10, 9,
+        14, 13, 9, 8, 10, 9, 8, 15, 14, 13, 13, 11, 14, 10, 12, 15, 10, 13, 12, 11, 14, 9, 12, 8,
+        10, 13, 8, 13, 7, 9, 12, 9, 12, 11, 10, 5, 8, 7, 6, 1, 4, 3, 2,
+    ],
+    [
+        3, 0, 0, 0, 0, 1, 0, 0, 4, 5, 6, 0, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21,
+        22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44,
+        45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63,
+    ],
+];
+
+/// `coeff_token` for chroma DC (`nC == -1`), up to four coefficients.
+const CHROMA_DC_COEFF_TOKEN_LEN: [u8; 20] =
+    [2, 0, 0, 0, 6, 1, 0, 0, 6, 6, 3, 0, 6, 7, 7, 6, 6, 8, 8, 7];
+const CHROMA_DC_COEFF_TOKEN_CODE: [u8; 20] =
+    [1, 0, 0, 0, 7, 1, 0, 0, 4, 6, 1, 0, 3, 3, 2, 5, 2, 3, 2, 0];
+
+/// `total_zeros` for 4x4 blocks, by `TotalCoeff - 1` then `total_zeros`.
+const TOTAL_ZEROS_LEN: [&[u8]; 15] = [
+    &[1, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 9],
+    &[3, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 6, 6, 6, 6],
+    &[4, 3, 3, 3, 4, 4, 3, 3, 4, 5, 5, 6, 5, 6],
+    &[5, 3, 4, 4, 3, 3, 3, 4, 3, 4, 5, 5, 5],
+    &[4, 4, 4, 3, 3, 3, 3, 3, 4, 5, 4, 5],
+    &[6, 5, 3, 3, 3, 3, 3, 3, 4, 3, 6],
+    &[6, 5, 3, 3, 3, 2, 3, 4, 3, 6],
+    &[6, 4, 5, 3, 2, 2, 3, 3, 6],
+    &[6, 6, 4, 2, 2, 3, 2, 5],
+    &[5, 5, 3, 2, 2, 2, 4],
+    &[4, 4, 3, 3, 1, 3],
+    &[4, 4, 2, 1, 3],
+    &[3, 3, 1, 2],
+    &[2, 2, 1],
+    &[1, 1],
+];
+
+const TOTAL_ZEROS_CODE: [&[u8]; 15] = [
+    &[1, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 1],
+    &[7, 6, 5, 4, 3, 5, 4, 3, 2, 3, 2, 3, 2, 1, 0],
+    &[5, 7, 6, 5, 4, 3, 4, 3, 2, 3, 2, 1, 1, 0],
+    &[3, 7, 5, 4, 6, 5, 4, 3, 3, 2, 2, 1, 0],
+    &[5, 4, 3, 7, 6, 5, 4, 3, 2, 1, 1, 0],
+    &[1, 1, 7, 6, 5, 4, 3, 2, 1, 1, 0],
+    &[1, 1, 5, 4, 3, 3, 2, 1, 1, 0],
+    &[1, 1, 1, 3, 3, 2, 2, 1, 0],
+    &[1, 0, 1, 3, 2, 1, 1, 1],
+    &[1, 0, 1, 3, 2, 1, 1],
+    &[0, 1, 1, 2, 1, 3],
+    &[0, 1, 1, 1, 1],
+    &[0, 1, 1, 1],
+    &[0, 1, 1],
+    &[0, 1],
+];
+
+/// `total_zeros` for 2x2 chroma DC, by `TotalCoeff - 1`.
+const CHROMA_DC_TOTAL_ZEROS_LEN: [&[u8]; 3] = [&[1, 2, 3, 3], &[1, 2, 2], &[1, 1]];
+const CHROMA_DC_TOTAL_ZEROS_CODE: [&[u8]; 3] = [&[1, 1, 1, 0], &[1, 1, 0], &[1, 0]];
+
+/// `run_before` by `Min(zerosLeft, 7) - 1` then `run_before`.
+const RUN_BEFORE_LEN: [&[u8]; 7] = [
+    &[1, 1],
+    &[1, 2, 2],
+    &[2, 2, 2, 2],
+    &[2, 2, 2, 3, 3],
+    &[2, 2, 3, 3, 3, 3],
+    &[2, 3, 3, 3, 3, 3, 3],
+    &[3, 3, 3, 3, 3, 3, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+];
+
+const RUN_BEFORE_CODE: [&[u8]; 7] = [
+    &[1, 0],
+    &[1, 1, 0],
+    &[3, 2, 1, 0],
+    &[3, 2, 1, 1, 0],
+    &[3, 2, 3, 2, 1, 0],
+    &[3, 0, 1, 3, 2, 5, 4],
+    &[7, 6, 5, 4, 3, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1],
+];
+
+/// Reads the code from `lens`/`codes` that prefixes the stream and returns
+/// its index.
+fn read_vlc(reader: &mut BitReader<'_>, lens: &[u8], codes: &[u8], what: &str) -> Result<usize> {
+    let window = reader.peek_bits(16);
+    for (index, (&len, &code)) in lens.iter().zip(codes).enumerate() {
+        if len != 0 && window >> (16 - u32::from(len)) == u32::from(code) {
+            reader.skip_bits(usize::from(len))?;
+            return Ok(index);
+        }
+    }
+    bail!("invalid {what} code")
+}
+
+/// The kind of block being read, which picks the `coeff_token` table.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum BlockContext {
+    /// A luma or chroma AC block, with `nC` from its neighbours.
+    Predicted(u32),
+    ChromaDc,
+}
+
+/// `residual_block_cavlc`: fills `coeffs` (in scan order) with up to
+/// `coeffs.len()` levels and returns `TotalCoeff`.
+pub(super) fn read_residual_block(
+    reader: &mut BitReader<'_>,
+    context: BlockContext,
+    coeffs: &mut [i32],
+) -> Result<u32> {
+    let max_coeffs = coeffs.len();
+    coeffs.fill(0);
+    let token = match context {
+        BlockContext::ChromaDc => read_vlc(
+            reader,
+            &CHROMA_DC_COEFF_TOKEN_LEN,
+            &CHROMA_DC_COEFF_TOKEN_CODE,
+            "coeff_token",
+        )?,
+        BlockContext::Predicted(nc) => {
+            let table = match nc {
+                0..2 => 0,
+                2..4 => 1,
+                4..8 => 2,
+                _ => 3,
+            };
+            read_vlc(
+                reader,
+                &COEFF_TOKEN_LEN[table],
+                &COEFF_TOKEN_CODE[table],
+                "coeff_token",
+            )?
+        }
+    };
+    let total_coeff = token / 4;
+    let trailing_ones = token % 4;
+    if total_coeff == 0 {
+        return Ok(0);
+    }
+    if total_coeff > max_coeffs {
+        bail!("coeff_token has {total_coeff} coefficients for a block of {max_coeffs}");
+    }
+
+    let mut levels = [0i32; 16];
+    let mut suffix_length = u32::from(total_coeff > 10 && trailing_ones < 3);
+    for (i, level) in levels.iter_mut().enumerate().take(total_coeff) {
+        if i < trailing_ones {
+            *level = if reader.read_flag()? { -1 } else { 1 };
+            continue;
+        }
+        let prefix = reader.read_leading_zeros()?;
+        if prefix > 28 {
+            bail!("level_prefix {prefix} is out of range");
+        }
+        let mut level_code = (prefix.min(15) << suffix_length) as i32;
+        if suffix_length > 0 || prefix >= 14 {
+            let suffix_size = match (prefix, suffix_length) {
+                (14, 0) => 4,
+                (15.., _) => prefix - 3,
+                _ => suffix_length,
+            };
+            level_code += reader.read_bits(suffix_size)? as i32;
+        }
+        if prefix >= 15 && suffix_length == 0 {
+            level_code += 15;
+        }
+        if prefix >= 16 {
+            level_code += (1 << (prefix - 3)) - 4096;
+        }
+        if i == trailing_ones && trailing_ones < 3 {
+            level_code += 2;
+        }
+        *level = if level_code % 2 == 0 {
+            (level_code + 2) >> 1
+        } else {
+            (-level_code - 1) >> 1
+        };
+        if suffix_length == 0 {
+            suffix_length = 1;
+        }
+        if level.abs() > (3 << (suffix_length - 1)) && suffix_length < 6 {
+            suffix_length += 1;
+        }
+    }
+
+    let mut zeros_left = if total_coeff < max_coeffs {
+        let (lens, codes) = match context {
+            BlockContext::ChromaDc => (
+                CHROMA_DC_TOTAL_ZEROS_LEN[total_coeff - 1],
+                CHROMA_DC_TOTAL_ZEROS_CODE[total_coeff - 1],
+            ),
+            BlockContext::Predicted(_) => (
+                TOTAL_ZEROS_LEN[total_coeff - 1],
+                TOTAL_ZEROS_CODE[total_coeff - 1],
+            ),
+        };
+        read_vlc(reader, lens, codes, "total_zeros")?
+    } else {
+        0
+    };
+    if zeros_left + total_coeff > max_coeffs {
+        bail!("total_zeros overflows the block");
+    }
+
+    // Levels run from the highest frequency down.
+    let mut position = zeros_left + total_coeff - 1;
+    for (i, &level) in levels.iter().enumerate().take(total_coeff) {
+        coeffs[position] = level;
+        if i + 1 == total_coeff {
+            break;
+        }
+        let run = if zeros_left > 0 {
+            let table = zeros_left.min(7) - 1;
+            let run = read_vlc(
+                reader,
+                RUN_BEFORE_LEN[table],
+                RUN_BEFORE_CODE[table],
+                "run_before",
+            )?;
+            if run > zeros_left {
+                bail!("run_before exceeds the zeros left");
+            }
+            run
+        } else {
+            0
+        };
+        zeros_left -= run;
+        position -= run + 1;
+    }
+    Ok(total_coeff as u32)
+}
+
+fn write_vlc(writer: &mut BitWriter, lens: &[u8], codes: &[u8], index: usize) {
+    writer.write_bits(u32::from(codes[index]), u32::from(lens[index]));
+}
+
+/// Writes `coeffs` (in scan order, one entry per coefficient of the block)
+/// as `residual_block_cavlc` and returns `TotalCoeff`.
+pub(super) fn write_residual_block(
+    writer: &mut BitWriter,
+    context: BlockContext,
+    coeffs: &[i32],
+) -> u32 {
+    // Nonzero levels from the highest frequency down, with their positions.
+    let levels: Vec<(usize, i32)> = coeffs
+        .iter()
+        .enumerate()
+        .rev()
+        .filter(|&(_, &level)| level != 0)
+        .map(|(position, &level)| (position, level))
+        .collect();
+    let total_coeff = levels.len();
+    let trailing_ones = levels
+        .iter()
+        .take(3)
+        .take_while(|(_, level)| level.abs() == 1)
+        .count();
+    let token = total_coeff * 4 + trailing_ones;
+    match context {
+        BlockContext::ChromaDc => write_vlc(
+            writer,
+            &CHROMA_DC_COEFF_TOKEN_LEN,
+            &CHROMA_DC_COEFF_TOKEN_CODE,
+            token,
+        ),
+        BlockContext::Predicted(nc) => {
+            let table = match nc {
+                0..2 => 0,
+                2..4 => 1,
+                4..8 => 2,
+                _ => 3,
+            };
+            write_vlc(
+                writer,
+                &COEFF_TOKEN_LEN[table],
+                &COEFF_TOKEN_CODE[table],
+                token,
+            );
+        }
+    }
+    if total_coeff == 0 {
+        return 0;
+    }
+
+    let mut suffix_length = u32::from(total_coeff > 10 && trailing_ones < 3);
+    for (i, &(_, level)) in levels.iter().enumerate() {
+        if i < trailing_ones {
+            writer.write_flag(level < 0);
+            continue;
+        }
+        let mut level_code = if level > 0 {
+            2 * i64::from(level) - 2
+        } else {
+            -2 * i64::from(level) - 1
+        };
+        if i == trailing_ones && trailing_ones < 3 {
+            level_code -= 2;
+        }
+        write_level(writer, level_code, suffix_length);
+        if suffix_length == 0 {
+            suffix_length = 1;
+        }
+        if level.abs() > (3 << (suffix_length - 1)) && suffix_length < 6 {
+            suffix_length += 1;
+        }
+    }
+
+    let max_coeffs = coeffs.len();
+    let total_zeros = levels[0].0 + 1 - total_coeff;
+    if total_coeff < max_coeffs {
+        match context {
+            BlockContext::ChromaDc => write_vlc(
+                writer,
+                CHROMA_DC_TOTAL_ZEROS_LEN[total_coeff - 1],
+                CHROMA_DC_TOTAL_ZEROS_CODE[total_coeff - 1],
+                total_zeros,
+            ),
+            BlockContext::Predicted(_) => write_vlc(
+                writer,
+                TOTAL_ZEROS_LEN[total_coeff - 1],
+                TOTAL_ZEROS_CODE[total_coeff - 1],
+                total_zeros,
+            ),
+        }
+    }
+    let mut zeros_left = total_zeros;
+    for pair in levels.windows(2) {
+        if zeros_left == 0 {
+            break;
+        }
+        let run = pair[0].0 - pair[1].0 - 1;
+        let table = zeros_left.min(7) - 1;
+        write_vlc(writer, RUN_BEFORE_LEN[table], RUN_BEFORE_CODE[table], run);
+        zeros_left -= run;
+    }
+    total_coeff as u32
+}
+
+/// Writes one `levelCode` as `level_prefix` and `level_suffix`.
+fn write_level(writer: &mut BitWriter, level_code: i64, suffix_length: u32) {
+    let (prefix, suffix, suffix_size) = if suffix_length == 0 && level_code < 14 {
+        (level_code as u32, 0, 0)
+    } else if suffix_length == 0 && level_code < 30 {
+        (14, (level_code - 14) as u32, 4)
+    } else if suffix_length > 0 && level_code < 15 << suffix_length {
+        let mask = (1 << suffix_length) - 1;
+        (
+            (level_code >> suffix_length) as u32,
+            (level_code & mask) as u32,
+            suffix_length,
+        )
+    } else {
+        // Escapes: prefix 15 carries 12 suffix bits, and each longer prefix
+        // doubles the range.
+        let base = (15 << suffix_length) + if suffix_length == 0 { 15 } else { 0 };
+        let mut value = level_code - base;
+        let mut prefix = 15;
+        while value >= 1 << (prefix - 3) {
+            prefix += 1;
+            value = level_code - base - ((1 << (prefix - 3)) - 4096);
+        }
+        (prefix, value as u32, prefix - 3)
+    };
+    writer.write_bits(1, prefix + 1);
+    writer.write_bits(suffix, suffix_size);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that no code is a prefix of another and the code space is not
+    /// oversubscribed.
+    fn assert_prefix_free(lens: &[u8], codes: &[u8], name: &str) {
+        let entries: Vec<(u8, u32)> = lens
+            .iter()
+            .zip(codes)
+            .filter(|(len, _)| **len != 0)
+            .map(|(&len, &code)| (len, u32::from(code)))
+            .collect();
+        let mut kraft = 0.0;
+        for (i, &(len_a, code_a)) in entries.iter().enumerate() {
+            assert!(
+                code_a < 1 << len_a,
+                "{name}: code {code_a} too long for {len_a}"
+            );
+            kraft += 0.5f64.powi(i32::from(len_a));
+            for &(len_b, code_b) in &entries[i + 1..] {
+                let shared = len_a.min(len_b);
+                assert_ne!(
+                    code_a >> (len_a - shared),
+                    code_b >> (len_b - shared),
+                    "{name}: {code_a:0w_a$b} and {code_b:0w_b$b} collide",
+                    w_a = usize::from(len_a),
+                    w_b = usize::from(len_b),
+                );
+            }
+        }
+        assert!(kraft <= 1.0, "{name}: oversubscribed");
+    }
+
+    #[test]
+    fn vlc_tables_are_prefix_codes() {
+        for table in 0..4 {
+            assert_prefix_free(
+                &COEFF_TOKEN_LEN[table],
+                &COEFF_TOKEN_CODE[table],
+                "coeff_token",
+            );
+        }
+        assert_prefix_free(
+            &CHROMA_DC_COEFF_TOKEN_LEN,
+            &CHROMA_DC_COEFF_TOKEN_CODE,
+            "chroma DC coeff_token",
+        );
+        for (lens, codes) in TOTAL_ZEROS_LEN.iter().zip(TOTAL_ZEROS_CODE) {
+            assert_eq!(lens.len(), codes.len());
+            assert_prefix_free(lens, codes, "total_zeros");
+        }
+        for (lens, codes) in CHROMA_DC_TOTAL_ZEROS_LEN
+            .iter()
+            .zip(CHROMA_DC_TOTAL_ZEROS_CODE)
+        {
+            assert_prefix_free(lens, codes, "chroma DC total_zeros");
+        }
+        for (lens, codes) in RUN_BEFORE_LEN.iter().zip(RUN_BEFORE_CODE) {
+            assert_eq!(lens.len(), codes.len());
+            assert_prefix_free(lens, codes, "run_before");
+        }
+    }
+
+    #[test]
+    fn reads_the_standard_example_block() {
+        // Levels 0 3 -1 0 0 -1 1 0 1 in scan order with nC = 0:
+        // coeff_token 0000100 (5 coefficients, 3 trailing ones), trailing
+        // signs 001, levels -1 and 3, total_zeros 110 (4), then runs of 1,
+        // 0, 2 and 0 as 10 11 01 1.
+        let bits = "0000100 001 01 0010 110 10 11 01 1";
+        let mut bytes = Vec::new();
+        let packed: String = bits.chars().filter(|c| !c.is_whitespace()).collect();
+        for chunk in packed.as_bytes().chunks(8) {
+            let mut byte = 0u8;
+            for (i, bit) in chunk.iter().enumerate() {
+                byte |= (bit - b'0') << (7 - i);
+            }
+            bytes.push(byte);
+        }
+        let mut reader = BitReader::new(&bytes);
+        let mut coeffs = [0; 16];
+        let total =
+            read_residual_block(&mut reader, BlockContext::Predicted(0), &mut coeffs).unwrap();
+        assert_eq!(total, 5);
+        assert_eq!(&coeffs[..9], &[0, 3, -1, 0, 0, -1, 1, 0, 1]);
+    }
+
+    #[test]
+    fn written_blocks_read_back() {
+        let blocks: [&[i32]; 6] = [
+            &[0, 3, -1, 0, 0, -1, 1, 0, 1, 0, 0, 0, 0, 0, 0, 0],
+            &[-40, 17, 9, -6, 5, 4, -3, 3, 2, 2, -1, 1, 1, 1, 1, -1],
+            &[2000, -3000, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+            &[0; 15],
+            &[5, 0, -1, 1],
+            &[0, 0, 0, -2],
+        ];
+        for nc in [0, 3, 6, 12] {
+            let mut writer = BitWriter::default();
+            for block in blocks {
+                let context = if block.len() == 4 {
+                    BlockContext::ChromaDc
+                } else {
+                    BlockContext::Predicted(nc)
+                };
+                write_residual_block(&mut writer, context, block);
+            }
+            let data = writer.finish();
+            let mut reader = BitReader::new(&data);
+            for block in blocks {
+                let context = if block.len() == 4 {
+                    BlockContext::ChromaDc
+                } else {
+                    BlockContext::Predicted(nc)
+                };
+                let mut coeffs = vec![0; block.len()];
+                let total = read_residual_block(&mut reader, context, &mut coeffs).unwrap();
+                assert_eq!(coeffs, block, "nC {nc}");
+                assert_eq!(total as usize, block.iter().filter(|&&c| c != 0).count());
+            }
+            assert!(!reader.more_rbsp_data());
+        }
+    }
+}
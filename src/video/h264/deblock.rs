@@ -0,0 +1,321 @@
+//! The in-loop deblocking filter (8.7), run over a whole picture once all
+//! of its slices are decoded.
+
+use super::picture::{Frame, MbInfo};
+
+/// `alpha'` by `indexA` (Table 8-16).
+const ALPHA: [u8; 52] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4, 4, 5, 6, 7, 8, 9, 10, 12, 13, 15, 17, 20,
+    22, 25, 28, 32, 36, 40, 45, 50, 56, 63, 71, 80, 90, 101, 113, 127, 144, 162, 182, 203, 226,
+    255, 255,
+];
+
+/// `beta'` by `indexB` (Table 8-16).
+const BETA: [u8; 52] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 6, 6, 7, 7, 8, 8,
+    9, 9, 10, 10, 11, 11, 12, 12, 13, 13, 14, 14, 15, 15, 16, 16, 17, 17, 18, 18,
+];
+
+/// `tC0'` by `indexA` and `bS - 1` (Table 8-17).
+const TC0: [[u8; 3]; 52] = [
+    [0, 0, 0],
+    [0, 0, 0],
+    [0, 0, 0],
+    [0, 0, 0],
+    [0, 0, 0],
+    [0, 0, 0],
+    [0, 0, 0],
+    [0, 0, 0],
+    [0, 0, 0],
+    [0, 0, 0],
+    [0, 0, 0],
+    [0, 0, 0],
+    [0, 0, 0],
+    [0, 0, 0],
+    [0, 0, 0],
+    [0, 0, 0],
+    [0, 0, 0],
+    [0, 0, 1],
+    [0, 0, 1],
+    [0, 0, 1],
+    [0, 0, 1],
+    [0, 1, 1],
+    [0, 1, 1],
+    [1, 1, 1],
+    [1, 1, 1],
+    [1, 1, 1],
+    [1, 1, 1],
+    [1, 1, 2],
+    [1, 1, 2],
+    [1, 1, 2],
+    [1, 1, 2],
+    [1, 2, 3],
+    [1, 2, 3],
+    [2, 2, 3],
+    [2, 2, 4],
+    [2, 3, 4],
+    [2, 3, 4],
+    [3, 3, 5],
+    [3, 4, 6],
+    [3, 4, 6],
+    [4, 5, 7],
+    [4, 5, 8],
+    [4, 6, 9],
+    [5, 7, 10],
+    [6, 8, 11],
+    [6, 8, 13],
+    [7, 10, 14],
+    [8, 11, 16],
+    [9, 12, 18],
+    [10, 13, 20],
+    [11, 15, 23],
+    [13, 17, 25],
+];
+
+/// Boundary strength between two luma 4x4 blocks (8.7.2.1).
+fn strength(p: &MbInfo, p_block: usize, q: &MbInfo, q_block: usize, mb_edge: bool) -> u8 {
+    if p.is_intra() || q.is_intra() {
+        return if mb_edge { 4 } else { 3 };
+    }
+    if p.luma_coeffs[p_block] != 0 || q.luma_coeffs[q_block] != 0 {
+        return 2;
+    }
+    u8::from(motion_differs(p, p_block, q, q_block))
+}
+
+/// Whether two inter blocks predict from different pictures, or from the
+/// same ones with motion vectors a whole luma sample or more apart. A
+/// bi-predicted pair matches if either pairing of its lists does.
+fn motion_differs(p: &MbInfo, p_block: usize, q: &MbInfo, q_block: usize) -> bool {
+    let moved = |a: [i32; 2], b: [i32; 2]| (a[0] - b[0]).abs() >= 4 || (a[1] - b[1]).abs() >= 4;
+    let motion = |mb: &MbInfo, block: usize| {
+        [0, 1].map(|list| {
+            (mb.ref_idx[list][block] >= 0).then_some((mb.ref_id[list][block], mb.mv[list][block]))
+        })
+    };
+    let same = |a: (u32, [i32; 2]), b: (u32, [i32; 2])| a.0 == b.0 && !moved(a.1, b.1);
+    match (motion(p, p_block), motion(q, q_block)) {
+        ([Some(p), None] | [None, Some(p)], [Some(q), None] | [None, Some(q)]) => !same(p, q),
+        ([Some(p0), Some(p1)], [Some(q0), Some(q1)]) => {
+            !((same(p0, q0) && same(p1, q1)) || (same(p0, q1) && same(p1, q0)))
+        }
+        _ => true,
+    }
+}
+
+/// One edge of a plane: `q0` is the offset of the first sample on the q
+/// side, `across` steps from q0 to q1 and `along` to the next line.
+struct Edge {
+    q0: usize,
+    across: usize,
+    along: usize,
+}
+
+/// Filters `strengths.len()` lines of an edge, each with its `bS`.
+fn filter_edge(
+    plane: &mut [u8],
+    edge: &Edge,
+    strengths: &[u8],
+    qp: i32,
+    offsets: (i32, i32),
+    chroma: bool,
+) {
+    let index_a = (qp + offsets.0).clamp(0, 51) as usize;
+    let index_b = (qp + offsets.1).clamp(0, 51) as usize;
+    let alpha = i32::from(ALPHA[index_a]);
+    let beta = i32::from(BETA[index_b]);
+    if alpha == 0 || beta == 0 {
+        return;
+    }
+    for (line, &bs) in strengths.iter().enumerate() {
+        if bs == 0 {
+            continue;
+        }
+        let q0_at = edge.q0 + line * edge.along;
+        let at = |i: isize| (q0_at as isize + i * edge.across as isize) as usize;
+        let sample = |i: isize| i32::from(plane[at(i)]);
+        let (p0, p1, q0, q1) = (sample(-1), sample(-2), sample(0), sample(1));
+        if (p0 - q0).abs() >= alpha || (p1 - p0).abs() >= beta || (q1 - q0).abs() >= beta {
+            continue;
+        }
+        if chroma {
+            let (new_p0, new_q0) = if bs < 4 {
+                let tc = i32::from(TC0[index_a][usize::from(bs) - 1]) + 1;
+                let delta = ((((q0 - p0) << 2) + (p1 - q1) + 4) >> 3).clamp(-tc, tc);
+                (p0 + delta, q0 - delta)
+            } else {
+                ((2 * p1 + p0 + q1 + 2) >> 2, (2 * q1 + q0 + p1 + 2) >> 2)
+            };
+            plane[at(-1)] = new_p0.clamp(0, 255) as u8;
+            plane[at(0)] = new_q0.clamp(0, 255) as u8;
+            continue;
+        }
+        let (p2, p3, q2, q3) = (sample(-3), sample(-4), sample(2), sample(3));
+        let ap = (p2 - p0).abs();
+        let aq = (q2 - q0).abs();
+        if bs < 4 {
+            let tc0 = i32::from(TC0[index_a][usize::from(bs) - 1]);
+            let tc = tc0 + i32::from(ap < beta) + i32::from(aq < beta);
+            let delta = ((((q0 - p0) << 2) + (p1 - q1) + 4) >> 3).clamp(-tc, tc);
+            plane[at(-1)] = (p0 + delta).clamp(0, 255) as u8;
+            plane[at(0)] = (q0 - delta).clamp(0, 255) as u8;
+            let average = (p0 + q0 + 1) >> 1;
+            if ap < beta {
+                let change = ((p2 + average - (p1 << 1)) >> 1).clamp(-tc0, tc0);
+                plane[at(-2)] = (p1 + change) as u8;
+            }
+            if aq < beta {
+                let change = ((q2 + average - (q1 << 1)) >> 1).clamp(-tc0, tc0);
+                plane[at(1)] = (q1 + change) as u8;
+            }
+            continue;
+        }
+        let strong = (p0 - q0).abs() < (alpha >> 2) + 2;
+        if ap < beta && strong {
+            plane[at(-1)] = ((p2 + 2 * p1 + 2 * p0 + 2 * q0 + q1 + 4) >> 3) as u8;
+            plane[at(-2)] = ((p2 + p1 + p0 + q0 + 2) >> 2) as u8;
+            plane[at(-3)] = ((2 * p3 + 3 * p2 + p1 + p0 + q0 + 4) >> 3) as u8;
+        } else {
+            plane[at(-1)] = ((2 * p1 + p0 + q1 + 2) >> 2) as u8;
+        }
+        if aq < beta && strong {
+            plane[at(0)] = ((p1 + 2 * p0 + 2 * q0 + 2 * q1 + q2 + 4) >> 3) as u8;
+            plane[at(1)] = ((p0 + q0 + q1 + q2 + 2) >> 2) as u8;
+            plane[at(2)] = ((2 * q3 + 3 * q2 + q1 + q0 + p0 + 4) >> 3) as u8;
+        } else {
+            plane[at(0)] = ((2 * q1 + q0 + p1 + 2) >> 2) as u8;
+        }
+    }
+}
+
+/// Deblocks every macroblock of `frame` in decoding order.
+pub(super) fn deblock(frame: &mut Frame, mbs: &[MbInfo], width_in_mbs: usize) {
+    let luma_stride = frame.width;
+    let chroma_stride = frame.width / 2;
+    for (addr, q) in mbs.iter().enumerate() {
+        if q.slice == 0 || q.disable_deblocking_filter_idc == 1 {
+            continue;
+        }
+        let (mb_x, mb_y) = (addr % width_in_mbs, addr / width_in_mbs);
+        let usable = |neighbour: &MbInfo| {
+            neighbour.slice != 0
+                && (q.disable_deblocking_filter_idc != 2 || neighbour.slice == q.slice)
+        };
+        let left = (mb_x > 0).then(|| &mbs[addr - 1]).filter(|mb| usable(mb));
+        let top = (mb_y > 0)
+            .then(|| &mbs[addr - width_in_mbs])
+            .filter(|mb| usable(mb));
+        let offsets = (q.alpha_offset, q.beta_offset);
+
+        // Vertical edges, then horizontal ones; `bs[edge][line block]`.
+        for vertical in [true, false] {
+            let neighbour = if vertical { left } else { top };
+            let mut strengths = [[0u8; 4]; 4];
+            for (edge, edge_strengths) in strengths.iter_mut().enumerate() {
+                for (along, bs) in edge_strengths.iter_mut().enumerate() {
+                    let (q_x, q_y) = if vertical {
+                        (edge, along)
+                    } else {
+                        (along, edge)
+                    };
+                    let q_block = q_y * 4 + q_x;
+                    *bs = if edge == 0 {
+                        let Some(p) = neighbour else { continue };
+                        let p_block = if vertical { q_y * 4 + 3 } else { 12 + q_x };
+                        strength(p, p_block, q, q_block, true)
+                    } else {
+                        let p_block = if vertical { q_block - 1 } else { q_block - 4 };
+                        strength(q, p_block, q, q_block, false)
+                    };
+                }
+            }
+
+            let p = |edge: usize| if edge == 0 { neighbour } else { Some(q) };
+            for (edge, edge_strengths) in strengths.iter().enumerate() {
+                let Some(p_mb) = p(edge) else { continue };
+                // Luma: four lines per block.
+                let mut lines = [0u8; 16];
+                for (line, bs) in lines.iter_mut().enumerate() {
+                    *bs = edge_strengths[line / 4];
+                }
+                let (x, y) = (mb_x * 16, mb_y * 16);
+                let luma_edge = if vertical {
+                    Edge {
+                        q0: y * luma_stride + x + edge * 4,
+                        across: 1,
+                        along: luma_stride,
+                    }
+                } else {
+                    Edge {
+                        q0: (y + edge * 4) * luma_stride + x,
+                        across: luma_stride,
+                        along: 1,
+                    }
+                };
+                let qp = (p_mb.qp + q.qp + 1) >> 1;
+                filter_edge(&mut frame.luma, &luma_edge, &lines, qp, offsets, false);
+
+                // Chroma edges sit on luma edges 0 and 8, two lines per
+                // luma block.
+                if edge % 2 == 1 {
+                    continue;
+                }
+                let mut lines = [0u8; 8];
+                for (line, bs) in lines.iter_mut().enumerate() {
+                    *bs = edge_strengths[line / 2];
+                }
+                let (x, y) = (mb_x * 8, mb_y * 8);
+                let chroma_edge = if vertical {
+                    Edge {
+                        q0: y * chroma_stride + x + edge * 2,
+                        across: 1,
+                        along: chroma_stride,
+                    }
+                } else {
+                    Edge {
+                        q0: (y + edge * 2) * chroma_stride + x,
+                        across: chroma_stride,
+                        along: 1,
+                    }
+                };
+                for component in 0..2 {
+                    let qp = (p_mb.chroma_qp[component] + q.chroma_qp[component] + 1) >> 1;
+                    filter_edge(
+                        frame.chroma_mut(component),
+                        &chroma_edge,
+                        &lines,
+                        qp,
+                        offsets,
+                        true,
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strong_filter_smooths_an_intra_macroblock_edge() {
+        let mut plane = vec![0u8; 16];
+        plane[..8].fill(60);
+        plane[8..].fill(70);
+        let edge = Edge {
+            q0: 8,
+            across: 1,
+            along: 16,
+        };
+        filter_edge(&mut plane, &edge, &[4], 40, (0, 0), false);
+        assert_eq!(&plane[5..11], &[61, 63, 64, 66, 68, 69]);
+
+        // A real edge (a step larger than alpha) is left alone.
+        let mut plane = vec![0u8; 16];
+        plane[8..].fill(200);
+        let before = plane.clone();
+        filter_edge(&mut plane, &edge, &[4], 40, (0, 0), false);
+        assert_eq!(plane, before);
+    }
+}
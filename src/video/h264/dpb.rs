@@ -0,0 +1,324 @@
+//! Reference picture bookkeeping: list initialisation and modification for
+//! P and B slices (8.2.4) and decoded reference picture marking (8.2.5).
+
+use std::cmp::Reverse;
+use std::rc::Rc;
+
+use anyhow::{Result, anyhow};
+
+use super::picture::Frame;
+use super::slice::{ListModification, Mmco, RefPicMarking, SliceHeader, SliceType};
+
+#[derive(Debug, Clone)]
+struct Reference {
+    frame: Rc<Frame>,
+    frame_num: u32,
+    /// `LongTermFrameIdx` once the picture is marked as a long-term
+    /// reference.
+    long_term: Option<u32>,
+}
+
+/// One entry of a reference picture list.
+#[derive(Debug, Clone)]
+pub(super) struct RefPicture {
+    pub frame: Rc<Frame>,
+    pub long_term: bool,
+}
+
+/// `RefPicList0` and `RefPicList1` of a slice; entries the stream leaves
+/// unfilled are `None`.
+pub(super) type RefLists = [Vec<Option<RefPicture>>; 2];
+
+/// The pictures currently marked as used for reference.
+#[derive(Debug, Default)]
+pub(super) struct Dpb {
+    references: Vec<Reference>,
+    /// `MaxLongTermFrameIdx`; `None` means no long-term indices are allowed.
+    max_long_term_idx: Option<u32>,
+}
+
+impl Dpb {
+    pub fn is_empty(&self) -> bool {
+        self.references.is_empty()
+    }
+
+    /// `PicNum` of a short-term reference seen from a picture whose
+    /// `frame_num` is `current` (`FrameNumWrap` for frames).
+    fn pic_num(reference: &Reference, current: u32, max_frame_num: u32) -> i64 {
+        if reference.frame_num > current {
+            i64::from(reference.frame_num) - i64::from(max_frame_num)
+        } else {
+            i64::from(reference.frame_num)
+        }
+    }
+
+    fn short_term(&self, pic_num: i64, current: u32, max_frame_num: u32) -> Option<usize> {
+        self.references.iter().position(|reference| {
+            reference.long_term.is_none()
+                && Self::pic_num(reference, current, max_frame_num) == pic_num
+        })
+    }
+
+    fn long_term(&self, index: u32) -> Option<usize> {
+        self.references
+            .iter()
+            .position(|reference| reference.long_term == Some(index))
+    }
+
+    /// `RefPicList0` and, for B slices, `RefPicList1` of a slice in a
+    /// picture whose order count is `poc`, reordered by the slice's
+    /// modifications and cut to its active entries. P slices order
+    /// short-term references by descending `PicNum`; B slices by distance
+    /// in picture order, the earlier pictures first in list 0 and the later
+    /// ones first in list 1. Long-term references follow by ascending index.
+    pub fn ref_lists(
+        &self,
+        header: &SliceHeader,
+        max_frame_num: u32,
+        poc: i32,
+    ) -> Result<RefLists> {
+        let current = header.frame_num;
+        let mut short: Vec<&Reference> = self
+            .references
+            .iter()
+            .filter(|reference| reference.long_term.is_none())
+            .collect();
+        let mut long: Vec<&Reference> = self
+            .references
+            .iter()
+            .filter(|reference| reference.long_term.is_some())
+            .collect();
+        long.sort_by_key(|reference| reference.long_term);
+
+        let initial = match header.slice_type {
+            SliceType::I => return Ok([Vec::new(), Vec::new()]),
+            SliceType::P => {
+                short.sort_by_key(|reference| -Self::pic_num(reference, current, max_frame_num));
+                vec![short.into_iter().chain(long).collect::<Vec<_>>()]
+            }
+            SliceType::B => {
+                let (mut before, mut after): (Vec<&Reference>, Vec<&Reference>) = short
+                    .into_iter()
+                    .partition(|reference| reference.frame.poc < poc);
+                before.sort_by_key(|reference| Reverse(reference.frame.poc));
+                after.sort_by_key(|reference| reference.frame.poc);
+                let list0: Vec<&Reference> =
+                    before.iter().chain(&after).chain(&long).copied().collect();
+                let mut list1: Vec<&Reference> =
+                    after.iter().chain(&before).chain(&long).copied().collect();
+                if list1.len() > 1
+                    && list1
+                        .iter()
+                        .zip(&list0)
+                        .all(|(a, b)| Rc::ptr_eq(&a.frame, &b.frame))
+                {
+                    list1.swap(0, 1);
+                }
+                vec![list0, list1]
+            }
+        };
+        let mut lists = [Vec::new(), Vec::new()];
+        for (list, initial) in initial.into_iter().enumerate() {
+            lists[list] = self.modify(
+                initial,
+                &header.list_modifications[list],
+                header.num_ref_idx_active[list] as usize,
+                current,
+                max_frame_num,
+            )?;
+        }
+        Ok(lists)
+    }
+
+    /// Applies `ref_pic_list_modification` (8.2.4.3) to an initial list of
+    /// `active` entries.
+    fn modify(
+        &self,
+        initial: Vec<&Reference>,
+        modifications: &[ListModification],
+        active: usize,
+        current: u32,
+        max_frame_num: u32,
+    ) -> Result<Vec<Option<RefPicture>>> {
+        let entry = |reference: &Reference| RefPicture {
+            frame: Rc::clone(&reference.frame),
+            long_term: reference.long_term.is_some(),
+        };
+        let mut list: Vec<Option<RefPicture>> = initial
+            .into_iter()
+            .map(|reference| Some(entry(reference)))
+            .collect();
+        list.resize(active, None);
+
+        let max = i64::from(max_frame_num);
+        let mut predicted = i64::from(current);
+        for (ref_idx, modification) in modifications.iter().enumerate() {
+            let index = match *modification {
+                ListModification::ShortTermSubtract(difference)
+                | ListModification::ShortTermAdd(difference) => {
+                    let difference = i64::from(difference);
+                    predicted = if matches!(modification, ListModification::ShortTermSubtract(_)) {
+                        (predicted - difference).rem_euclid(max)
+                    } else {
+                        (predicted + difference).rem_euclid(max)
+                    };
+                    let pic_num = if predicted > i64::from(current) {
+                        predicted - max
+                    } else {
+                        predicted
+                    };
+                    self.short_term(pic_num, current, max_frame_num)
+                        .ok_or_else(|| {
+                            anyhow!("reference list names missing short-term picture {pic_num}")
+                        })?
+                }
+                ListModification::LongTerm(long_term_pic_num) => {
+                    self.long_term(long_term_pic_num).ok_or_else(|| {
+                        anyhow!(
+                            "reference list names missing long-term picture {long_term_pic_num}"
+                        )
+                    })?
+                }
+            };
+            if ref_idx >= active {
+                break;
+            }
+            let reference = entry(&self.references[index]);
+            let frame = Rc::clone(&reference.frame);
+            list.insert(ref_idx, Some(reference));
+            if let Some(duplicate) = list[ref_idx + 1..].iter().position(|entry| {
+                entry
+                    .as_ref()
+                    .is_some_and(|entry| Rc::ptr_eq(&entry.frame, &frame))
+            }) {
+                list.remove(ref_idx + 1 + duplicate);
+            }
+            list.truncate(active);
+        }
+        Ok(list)
+    }
+
+    /// Marks references after decoding a picture and, if it is itself a
+    /// reference, stores it. After `memory_management_control_operation` 5
+    /// the picture is stored as if its `frame_num` were zero.
+    pub fn mark(
+        &mut self,
+        frame: Rc<Frame>,
+        header: &SliceHeader,
+        max_frame_num: u32,
+        max_num_ref_frames: u32,
+    ) -> Result<()> {
+        let current = header.frame_num;
+        let mut stored = Reference {
+            frame,
+            frame_num: current,
+            long_term: None,
+        };
+        match &header.marking {
+            RefPicMarking::None => return Ok(()),
+            RefPicMarking::Idr { long_term } => {
+                self.references.clear();
+                if *long_term {
+                    stored.long_term = Some(0);
+                    self.max_long_term_idx = Some(0);
+                } else {
+                    self.max_long_term_idx = None;
+                }
+            }
+            // The sliding window is the loop below: with the DPB full, the
+            // short-term picture with the smallest `FrameNumWrap` goes.
+            RefPicMarking::SlidingWindow => {}
+            RefPicMarking::Adaptive(operations) => {
+                for operation in operations {
+                    self.apply(*operation, &mut stored, max_frame_num)?;
+                }
+            }
+        }
+        // After adaptive marking a full DPB is a stream error; it is treated
+        // like the sliding window rather than growing without bound.
+        while self.references.len() >= max_num_ref_frames.max(1) as usize {
+            let Some(index) = self
+                .references
+                .iter()
+                .enumerate()
+                .filter(|(_, r)| r.long_term.is_none())
+                .min_by_key(|(_, r)| Self::pic_num(r, stored.frame_num, max_frame_num))
+                .map(|(index, _)| index)
+            else {
+                break;
+            };
+            self.references.remove(index);
+        }
+        self.references.push(stored);
+        Ok(())
+    }
+
+    fn apply(
+        &mut self,
+        operation: Mmco,
+        current: &mut Reference,
+        max_frame_num: u32,
+    ) -> Result<()> {
+        let current_pic_num = i64::from(current.frame_num);
+        match operation {
+            Mmco::UnmarkShortTerm {
+                difference_of_pic_nums,
+            } => {
+                let pic_num = current_pic_num - i64::from(difference_of_pic_nums);
+                if let Some(index) = self.short_term(pic_num, current.frame_num, max_frame_num) {
+                    self.references.remove(index);
+                }
+            }
+            Mmco::UnmarkLongTerm { long_term_pic_num } => {
+                if let Some(index) = self.long_term(long_term_pic_num) {
+                    self.references.remove(index);
+                }
+            }
+            Mmco::ShortTermToLongTerm {
+                difference_of_pic_nums,
+                long_term_frame_idx,
+            } => {
+                let pic_num = current_pic_num - i64::from(difference_of_pic_nums);
+                let index = self
+                    .short_term(pic_num, current.frame_num, max_frame_num)
+                    .ok_or_else(|| anyhow!("MMCO 3 names missing short-term picture {pic_num}"))?;
+                let frame = Rc::clone(&self.references[index].frame);
+                self.references.retain(|reference| {
+                    reference.long_term != Some(long_term_frame_idx)
+                        || Rc::ptr_eq(&reference.frame, &frame)
+                });
+                if let Some(reference) = self
+                    .references
+                    .iter_mut()
+                    .find(|reference| Rc::ptr_eq(&reference.frame, &frame))
+                {
+                    reference.long_term = Some(long_term_frame_idx);
+                }
+            }
+            Mmco::MaxLongTermIndex {
+                max_long_term_frame_idx_plus1,
+            } => {
+                self.max_long_term_idx = max_long_term_frame_idx_plus1.checked_sub(1);
+                let max = self.max_long_term_idx;
+                self.references.retain(|reference| {
+                    reference
+                        .long_term
+                        .is_none_or(|index| max.is_some_and(|max| index <= max))
+                });
+            }
+            Mmco::UnmarkAll => {
+                self.references.clear();
+                self.max_long_term_idx = None;
+                current.frame_num = 0;
+            }
+            Mmco::CurrentToLongTerm {
+                long_term_frame_idx,
+            } => {
+                self.references
+                    .retain(|reference| reference.long_term != Some(long_term_frame_idx));
+                current.long_term = Some(long_term_frame_idx);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,992 @@
+//! Constrained-baseline, intra-only H.264 encoding. Every picture is an I
+//! slice of Intra16x16 macroblocks entropy coded with CAVLC, each predicted
+//! with whichever of the four 16x16 modes leaves the smallest residual; one
+//! picture per keyframe interval is an IDR, the others plain I pictures.
+//! IDR access units repeat the source's HDR metadata in an SEI NAL unit.
+
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+
+use crate::video::{
+    ColorSpace, EncodedSample, EncodedVideo, FramePlanes, FrameRate, HdrMetadata, TransferFunction,
+    VideoCodec, VideoStream,
+};
+
+use super::AvcConfig;
+use super::bits::{BitWriter, escape_rbsp};
+use super::cavlc::{BlockContext, write_residual_block};
+use super::intra::{self, Edges};
+use super::macroblock::{BLOCK_POSITION, combine_nc, raster};
+use super::sei;
+use super::transform::{self, ZIGZAG};
+
+const LOG2_MAX_FRAME_NUM: u32 = 4;
+
+const NAL_SLICE: u8 = 0x61;
+const NAL_IDR_SLICE: u8 = 0x65;
+const NAL_SEI: u8 = 0x06;
+const NAL_SPS: u8 = 0x67;
+const NAL_PPS: u8 = 0x68;
+
+/// `(level_idc, MaxFS, MaxMBPS)` from Table A-1, lowest level first.
+const LEVELS: [(u8, u32, u64); 18] = [
+    (10, 99, 1_485),
+    (11, 396, 3_000),
+    (12, 396, 6_000),
+    (13, 396, 11_880),
+    (20, 396, 11_880),
+    (21, 792, 19_800),
+    (22, 1_620, 20_250),
+    (30, 1_620, 40_500),
+    (31, 3_600, 108_000),
+    (32, 5_120, 216_000),
+    (40, 8_192, 245_760),
+    (41, 8_192, 245_760),
+    (42, 8_704, 522_240),
+    (50, 22_080, 589_824),
+    (51, 36_864, 983_040),
+    (52, 36_864, 2_073_600),
+    (61, 139_264, 8_355_840),
+    (62, 139_264, 16_711_680),
+];
+
+/// H.264 encoder settings.
+#[derive(Debug, Clone, Copy)]
+pub struct EncoderConfig {
+    /// Quantiser from 0 (best quality) to 51 (smallest output).
+    pub qp: u8,
+    /// Frames from one IDR to the next; 1 makes every frame an IDR.
+    pub keyframe_interval: u32,
+}
+
+impl Default for EncoderConfig {
+    fn default() -> Self {
+        Self {
+            qp: 26,
+            keyframe_interval: 1,
+        }
+    }
+}
+
+/// One coded picture.
+#[derive(Debug, Clone)]
+pub struct EncodedFrame {
+    /// NAL units with their header byte and emulation prevention, without
+    /// start codes or length prefixes.
+    pub nal_units: Vec<Vec<u8>>,
+    pub timestamp: Duration,
+    pub duration: Duration,
+    pub keyframe: bool,
+}
+
+/// An encoded stream and the parameter sets its frames refer to.
+#[derive(Debug, Clone)]
+pub struct EncodedStream {
+    pub width: u32,
+    pub height: u32,
+    pub frame_rate: FrameRate,
+    pub sps: Vec<u8>,
+    pub pps: Vec<u8>,
+    pub frames: Vec<EncodedFrame>,
+    /// The source stream's display rotation.
+    pub rotation: u16,
+    /// The source stream's HDR metadata, also carried in the frames' SEI.
+    pub hdr: HdrMetadata,
+}
+
+impl EncodedFrame {
+    /// The frame as an MP4/Matroska sample: NAL units prefixed with their
+    /// four-byte length.
+    pub fn to_length_prefixed(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for nal in &self.nal_units {
+            out.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+            out.extend_from_slice(nal);
+        }
+        out
+    }
+}
+
+impl EncodedStream {
+    /// The stream's decoder configuration, for samples with four-byte NAL
+    /// unit lengths.
+    pub fn avc_config(&self) -> AvcConfig {
+        AvcConfig {
+            profile_idc: self.sps[1],
+            profile_compatibility: self.sps[2],
+            level_idc: self.sps[3],
+            nal_length_size: 4,
+            sps: vec![self.sps.clone()],
+            pps: vec![self.pps.clone()],
+            // The encoder writes 8-bit 4:2:0.
+            chroma_format: 1,
+            bit_depth_luma: 8,
+            bit_depth_chroma: 8,
+        }
+    }
+
+    /// `AVCDecoderConfigurationRecord` (the `avcC` payload) for samples with
+    /// four-byte NAL unit lengths.
+    pub fn avcc_record(&self) -> Vec<u8> {
+        self.avc_config().to_record()
+    }
+
+    /// The stream as MP4/Matroska samples with its `avcC` record.
+    pub fn to_video(&self) -> EncodedVideo {
+        EncodedVideo {
+            codec: VideoCodec::H264,
+            width: self.width,
+            height: self.height,
+            frame_rate: self.frame_rate,
+            config: self.avcc_record(),
+            samples: self
+                .frames
+                .iter()
+                .map(|frame| EncodedSample {
+                    data: frame.to_length_prefixed(),
+                    timestamp: frame.timestamp,
+                    duration: frame.duration,
+                    keyframe: frame.keyframe,
+                })
+                .collect(),
+            rotation: self.rotation,
+            hdr: self.hdr,
+        }
+    }
+
+    /// The stream as an Annex B byte stream, parameter sets first.
+    pub fn to_annex_b(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let frames = self.frames.iter().flat_map(|frame| &frame.nal_units);
+        for nal in [&self.sps, &self.pps].into_iter().chain(frames) {
+            out.extend_from_slice(&[0, 0, 0, 1]);
+            out.extend_from_slice(nal);
+        }
+        out
+    }
+}
+
+/// Encodes every frame of `stream`, which must be YUV 4:2:0 with even
+/// dimensions that stay the same throughout.
+pub fn encode(stream: &VideoStream, config: &EncoderConfig) -> Result<EncodedStream> {
+    if config.qp > 51 {
+        bail!("H.264 qp must be between 0 and 51, got {}", config.qp);
+    }
+    if config.keyframe_interval == 0 {
+        bail!("H.264 keyframe interval must be at least one frame");
+    }
+    let Some(first) = stream.frames.first() else {
+        bail!("no video frames to encode");
+    };
+    let (width, height) = (first.width, first.height);
+    if width == 0 || height == 0 || width % 2 != 0 || height % 2 != 0 {
+        bail!("H.264 4:2:0 encoding needs even frame dimensions, got {width}x{height}");
+    }
+    let (width_in_mbs, height_in_mbs) = (width.div_ceil(16), height.div_ceil(16));
+    let Some(level_idc) = level_idc(width_in_mbs * height_in_mbs, stream.frame_rate) else {
+        bail!("{width}x{height} is too large for H.264");
+    };
+
+    let sps = nal_unit(
+        NAL_SPS,
+        &write_sps(
+            width,
+            height,
+            level_idc,
+            stream.frame_rate,
+            stream.color_space,
+            stream.transfer,
+        ),
+    );
+    let pps = nal_unit(NAL_PPS, &write_pps(i32::from(config.qp)));
+    let sei = sei::write_hdr(&stream.hdr).map(|rbsp| nal_unit(NAL_SEI, &rbsp));
+    let mut frames = Vec::with_capacity(stream.frames.len());
+    for (index, frame) in stream.frames.iter().enumerate() {
+        if (frame.width, frame.height) != (width, height) {
+            bail!(
+                "frame {index} is {}x{}, but the stream started at {width}x{height}",
+                frame.width,
+                frame.height
+            );
+        }
+        let FramePlanes::Yuv420 { y, u, v } = &frame.data else {
+            bail!("H.264 encoding needs YUV 4:2:0 frames");
+        };
+        let (w, h) = (width as usize, height as usize);
+        if y.len() != w * h || u.len() != w * h / 4 || v.len() != w * h / 4 {
+            bail!("frame {index} planes do not match its {width}x{height} size");
+        }
+        let mut picture = Picture::new(
+            [y, u, v],
+            (w, h),
+            (width_in_mbs as usize, height_in_mbs as usize),
+            i32::from(config.qp),
+        );
+        let interval = config.keyframe_interval as usize;
+        let (gop, position) = (index / interval, index % interval);
+        let (header, slice) = if position == 0 {
+            // Neighbouring IDR pictures need different idr_pic_id values.
+            (
+                NAL_IDR_SLICE,
+                picture.encode_slice(SliceKind::Idr((gop % 2) as u32)),
+            )
+        } else {
+            (
+                NAL_SLICE,
+                picture.encode_slice(SliceKind::NonIdr(position as u32)),
+            )
+        };
+        // The SEI goes with each IDR, so any of them can start playback.
+        let sei = sei.as_ref().filter(|_| position == 0);
+        frames.push(EncodedFrame {
+            nal_units: sei
+                .into_iter()
+                .cloned()
+                .chain([nal_unit(header, &slice)])
+                .collect(),
+            timestamp: frame.timestamp,
+            duration: frame.duration,
+            keyframe: position == 0,
+        });
+    }
+    Ok(EncodedStream {
+        width,
+        height,
+        frame_rate: stream.frame_rate,
+        sps,
+        pps,
+        frames,
+        rotation: stream.rotation,
+        hdr: stream.hdr,
+    })
+}
+
+/// Whether a picture starts a new coded video sequence.
+#[derive(Debug, Clone, Copy)]
+enum SliceKind {
+    /// An IDR picture with its `idr_pic_id`.
+    Idr(u32),
+    /// A non-IDR I picture, this many pictures after the last IDR.
+    NonIdr(u32),
+}
+
+fn nal_unit(header: u8, rbsp: &[u8]) -> Vec<u8> {
+    let mut nal = vec![header];
+    nal.extend(escape_rbsp(rbsp));
+    nal
+}
+
+/// The lowest level whose frame size and macroblock rate limits fit.
+fn level_idc(frame_size_in_mbs: u32, frame_rate: FrameRate) -> Option<u8> {
+    let fps = match frame_rate {
+        FrameRate::Constant {
+            numerator,
+            denominator,
+        } if denominator > 0 => u64::from(numerator).div_ceil(u64::from(denominator)),
+        _ => 30,
+    };
+    let mb_rate = u64::from(frame_size_in_mbs) * fps;
+    LEVELS
+        .iter()
+        .find(|&&(_, max_fs, max_mbps)| frame_size_in_mbs <= max_fs && mb_rate <= max_mbps)
+        .or_else(|| LEVELS.last().filter(|level| frame_size_in_mbs <= level.1))
+        .map(|&(level, _, _)| level)
+}
+
+fn write_sps(
+    width: u32,
+    height: u32,
+    level_idc: u8,
+    frame_rate: FrameRate,
+    color_space: ColorSpace,
+    transfer: TransferFunction,
+) -> Vec<u8> {
+    let (width_in_mbs, height_in_mbs) = (width.div_ceil(16), height.div_ceil(16));
+    let mut writer = BitWriter::default();
+    writer.write_bits(66, 8); // profile_idc: baseline
+    writer.write_bits(0xC0, 8); // constraint_set0/1: constrained baseline
+    writer.write_bits(u32::from(level_idc), 8);
+    writer.write_ue(0); // seq_parameter_set_id
+    writer.write_ue(LOG2_MAX_FRAME_NUM - 4);
+    writer.write_ue(2); // pic_order_cnt_type: output order is decoding order
+    writer.write_ue(1); // max_num_ref_frames
+    writer.write_flag(false); // gaps_in_frame_num_value_allowed_flag
+    writer.write_ue(width_in_mbs - 1);
+    writer.write_ue(height_in_mbs - 1);
+    writer.write_flag(true); // frame_mbs_only_flag
+    writer.write_flag(true); // direct_8x8_inference_flag
+    // Crop offsets count pairs of luma samples for 4:2:0.
+    let (crop_right, crop_bottom) = (width_in_mbs * 16 - width, height_in_mbs * 16 - height);
+    writer.write_flag(crop_right > 0 || crop_bottom > 0);
+    if crop_right > 0 || crop_bottom > 0 {
+        writer.write_ue(0);
+        writer.write_ue(crop_right / 2);
+        writer.write_ue(0);
+        writer.write_ue(crop_bottom / 2);
+    }
+    writer.write_flag(true); // vui_parameters_present_flag
+    write_vui(&mut writer, frame_rate, color_space, transfer);
+    writer.finish()
+}
+
+/// VUI carrying the colour description and, for constant rates, the
+/// frame rate.
+fn write_vui(
+    writer: &mut BitWriter,
+    frame_rate: FrameRate,
+    color_space: ColorSpace,
+    transfer: TransferFunction,
+) {
+    writer.write_flag(false); // aspect_ratio_info_present_flag
+    writer.write_flag(false); // overscan_info_present_flag
+    // colour_primaries, transfer_characteristics, matrix_coefficients
+    let colour = match color_space {
+        ColorSpace::Bt601 => Some([6, 6, 6]),
+        ColorSpace::Bt709 => Some([1, 1, 1]),
+        ColorSpace::Bt2020 => Some([9, 14, 9]),
+        ColorSpace::Srgb | ColorSpace::Unknown => None,
+    }
+    .map(|[primaries, sdr, matrix]| match transfer {
+        TransferFunction::Sdr => [primaries, sdr, matrix],
+        TransferFunction::Pq => [primaries, 16, matrix],
+        TransferFunction::Hlg => [primaries, 18, matrix],
+    });
+    writer.write_flag(colour.is_some()); // video_signal_type_present_flag
+    if let Some(colour) = colour {
+        writer.write_bits(5, 3); // video_format: unspecified
+        writer.write_flag(false); // video_full_range_flag
+        writer.write_flag(true); // colour_description_present_flag
+        for value in colour {
+            writer.write_bits(value, 8);
+        }
+    }
+    writer.write_flag(false); // chroma_loc_info_present_flag
+    // A frame lasts two ticks of time_scale.
+    let timing = match frame_rate {
+        FrameRate::Constant {
+            numerator,
+            denominator,
+        } if denominator > 0 => numerator
+            .checked_mul(2)
+            .filter(|&time_scale| time_scale > 0)
+            .map(|time_scale| (denominator, time_scale)),
+        _ => None,
+    };
+    writer.write_flag(timing.is_some()); // timing_info_present_flag
+    if let Some((num_units_in_tick, time_scale)) = timing {
+        writer.write_bits(num_units_in_tick, 32);
+        writer.write_bits(time_scale, 32);
+        writer.write_flag(true); // fixed_frame_rate_flag
+    }
+    writer.write_flag(false); // nal_hrd_parameters_present_flag
+    writer.write_flag(false); // vcl_hrd_parameters_present_flag
+    writer.write_flag(false); // pic_struct_present_flag
+    writer.write_flag(false); // bitstream_restriction_flag
+}
+
+fn write_pps(qp: i32) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+    writer.write_ue(0); // pic_parameter_set_id
+    writer.write_ue(0); // seq_parameter_set_id
+    writer.write_flag(false); // entropy_coding_mode_flag: CAVLC
+    writer.write_flag(false); // bottom_field_pic_order_in_frame_present_flag
+    writer.write_ue(0); // num_slice_groups_minus1
+    writer.write_ue(0); // num_ref_idx_l0_default_active_minus1
+    writer.write_ue(0); // num_ref_idx_l1_default_active_minus1
+    writer.write_flag(false); // weighted_pred_flag
+    writer.write_bits(0, 2); // weighted_bipred_idc
+    writer.write_se(qp - 26); // pic_init_qp_minus26
+    writer.write_se(0); // pic_init_qs_minus26
+    writer.write_se(0); // chroma_qp_index_offset
+    writer.write_flag(false); // deblocking_filter_control_present_flag
+    writer.write_flag(false); // constrained_intra_pred_flag
+    writer.write_flag(false); // redundant_pic_cnt_present_flag
+    writer.finish()
+}
+
+/// Copies a plane into a `width x height` buffer, repeating its last
+/// column and row into the padding.
+fn pad_plane(plane: &[u8], size: (usize, usize), width: usize, height: usize) -> Vec<u8> {
+    let (plane_width, plane_height) = size;
+    let mut out = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let row = &plane[y.min(plane_height - 1) * plane_width..][..plane_width];
+        out.extend_from_slice(row);
+        out.resize(out.len() + width - plane_width, row[plane_width - 1]);
+    }
+    out
+}
+
+fn sad(a: &[u8], b: &[u8]) -> u32 {
+    a.iter()
+        .zip(b)
+        .map(|(&a, &b)| u32::from(a.abs_diff(b)))
+        .sum()
+}
+
+/// One picture being encoded, with the reconstruction the decoder will
+/// see, which later macroblocks predict from.
+struct Picture {
+    width_in_mbs: usize,
+    height_in_mbs: usize,
+    qp: i32,
+    chroma_qp: i32,
+    /// Luma, Cb and Cr, padded to whole macroblocks.
+    source: [Vec<u8>; 3],
+    recon: [Vec<u8>; 3],
+    /// `TotalCoeff` of every luma and chroma AC block, for CAVLC `nC`.
+    luma_totals: Vec<[u8; 16]>,
+    chroma_totals: Vec<[[u8; 4]; 2]>,
+}
+
+impl Picture {
+    fn new(
+        planes: [&Vec<u8>; 3],
+        (width, height): (usize, usize),
+        (width_in_mbs, height_in_mbs): (usize, usize),
+        qp: i32,
+    ) -> Self {
+        let (padded_width, padded_height) = (width_in_mbs * 16, height_in_mbs * 16);
+        let source = [
+            pad_plane(planes[0], (width, height), padded_width, padded_height),
+            pad_plane(
+                planes[1],
+                (width / 2, height / 2),
+                padded_width / 2,
+                padded_height / 2,
+            ),
+            pad_plane(
+                planes[2],
+                (width / 2, height / 2),
+                padded_width / 2,
+                padded_height / 2,
+            ),
+        ];
+        let recon = source.clone().map(|plane| vec![0; plane.len()]);
+        let total = width_in_mbs * height_in_mbs;
+        Self {
+            width_in_mbs,
+            height_in_mbs,
+            qp,
+            chroma_qp: transform::chroma_qp(qp, 0),
+            source,
+            recon,
+            luma_totals: vec![[0; 16]; total],
+            chroma_totals: vec![[[0; 4]; 2]; total],
+        }
+    }
+
+    fn stride(&self, plane: usize) -> usize {
+        if plane == 0 {
+            self.width_in_mbs * 16
+        } else {
+            self.width_in_mbs * 8
+        }
+    }
+
+    /// The picture as one I slice of an access unit.
+    fn encode_slice(&mut self, kind: SliceKind) -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        writer.write_ue(0); // first_mb_in_slice
+        writer.write_ue(7); // slice_type: I, as is every slice of the picture
+        writer.write_ue(0); // pic_parameter_set_id
+        match kind {
+            SliceKind::Idr(idr_pic_id) => {
+                writer.write_bits(0, LOG2_MAX_FRAME_NUM); // frame_num
+                writer.write_ue(idr_pic_id);
+                writer.write_flag(false); // no_output_of_prior_pics_flag
+                writer.write_flag(false); // long_term_reference_flag
+            }
+            SliceKind::NonIdr(frames_since_idr) => {
+                // Every picture is a reference, so frame_num counts them.
+                writer.write_bits(
+                    frames_since_idr % (1 << LOG2_MAX_FRAME_NUM),
+                    LOG2_MAX_FRAME_NUM,
+                );
+                writer.write_flag(false); // adaptive_ref_pic_marking_mode_flag
+            }
+        }
+        writer.write_se(0); // slice_qp_delta: the PPS carries the QP
+        for addr in 0..self.width_in_mbs * self.height_in_mbs {
+            self.encode_macroblock(&mut writer, addr);
+        }
+        writer.finish()
+    }
+
+    /// Neighbouring reconstructed samples of the `size x size` block at
+    /// `x, y` in `plane`.
+    fn edges(&self, plane: usize, x: usize, y: usize, size: usize) -> Edges {
+        let stride = self.stride(plane);
+        let recon = &self.recon[plane];
+        let (has_left, has_top) = (x > 0, y > 0);
+        let mut edges = Edges {
+            top: [0; 16],
+            left: [0; 16],
+            top_left: 0,
+            has_top,
+            has_left,
+            has_top_left: has_top && has_left,
+        };
+        if has_top {
+            edges.top[..size].copy_from_slice(&recon[(y - 1) * stride + x..][..size]);
+        }
+        if has_left {
+            for row in 0..size {
+                edges.left[row] = recon[(y + row) * stride + x - 1];
+            }
+        }
+        if has_top && has_left {
+            edges.top_left = recon[(y - 1) * stride + x - 1];
+        }
+        edges
+    }
+
+    /// The source samples of the `size x size` block at `x, y`.
+    fn source_block(&self, plane: usize, x: usize, y: usize, size: usize) -> Vec<u8> {
+        let stride = self.stride(plane);
+        (0..size)
+            .flat_map(|row| &self.source[plane][(y + row) * stride + x..][..size])
+            .copied()
+            .collect()
+    }
+
+    fn luma_nc(&self, addr: usize, x: usize, y: usize) -> u32 {
+        let totals = &self.luma_totals;
+        let left = if x > 0 {
+            Some(totals[addr][raster(x - 1, y)])
+        } else {
+            (!addr.is_multiple_of(self.width_in_mbs)).then(|| totals[addr - 1][raster(3, y)])
+        };
+        let top = if y > 0 {
+            Some(totals[addr][raster(x, y - 1)])
+        } else {
+            (addr >= self.width_in_mbs).then(|| totals[addr - self.width_in_mbs][raster(x, 3)])
+        };
+        combine_nc(left, top)
+    }
+
+    fn chroma_nc(&self, addr: usize, component: usize, x: usize, y: usize) -> u32 {
+        let totals =
+            |addr: usize, x: usize, y: usize| self.chroma_totals[addr][component][y * 2 + x];
+        let left = if x > 0 {
+            Some(totals(addr, 0, y))
+        } else {
+            (!addr.is_multiple_of(self.width_in_mbs)).then(|| totals(addr - 1, 1, y))
+        };
+        let top = if y > 0 {
+            Some(totals(addr, x, 0))
+        } else {
+            (addr >= self.width_in_mbs).then(|| totals(addr - self.width_in_mbs, x, 1))
+        };
+        combine_nc(left, top)
+    }
+
+    fn encode_macroblock(&mut self, writer: &mut BitWriter, addr: usize) {
+        let (x0, y0) = (
+            (addr % self.width_in_mbs) * 16,
+            (addr / self.width_in_mbs) * 16,
+        );
+
+        // Luma: the cheapest 16x16 prediction, then the DC levels of the 16
+        // blocks apart from their AC levels.
+        let source = self.source_block(0, x0, y0, 16);
+        let edges = self.edges(0, x0, y0, 16);
+        let mut best: Option<(u8, [u8; 256], u32)> = None;
+        for mode in 0..4 {
+            let mut prediction = [0u8; 256];
+            if intra::predict_16x16(mode, &edges, &mut prediction).is_err() {
+                continue;
+            }
+            let cost = sad(&source, &prediction);
+            if best.is_none_or(|(_, _, best_cost)| cost < best_cost) {
+                best = Some((mode, prediction, cost));
+            }
+        }
+        let (luma_mode, prediction, _) = best.expect("DC prediction is always available");
+        let mut dc = [0i32; 16];
+        let mut ac = [[0i32; 16]; 16];
+        for (index, block) in ac.iter_mut().enumerate() {
+            let (bx, by) = (index % 4 * 4, index / 4 * 4);
+            let residual: [i32; 16] = std::array::from_fn(|i| {
+                let at = (by + i / 4) * 16 + bx + i % 4;
+                i32::from(source[at]) - i32::from(prediction[at])
+            });
+            *block = transform::forward(&residual);
+            dc[index] = block[0];
+            transform::quantize(block, self.qp, true);
+            block[0] = 0;
+        }
+        transform::forward_luma_dc(&mut dc, self.qp);
+        let cbp_luma = if ac.iter().flatten().any(|&level| level != 0) {
+            15
+        } else {
+            0
+        };
+        self.reconstruct_luma(x0, y0, &prediction, &dc, &ac);
+
+        // Chroma: one prediction mode for both components.
+        let (cx, cy) = (x0 / 2, y0 / 2);
+        let chroma_source = [
+            self.source_block(1, cx, cy, 8),
+            self.source_block(2, cx, cy, 8),
+        ];
+        let chroma_edges = [self.edges(1, cx, cy, 8), self.edges(2, cx, cy, 8)];
+        let mut best: Option<(u8, [[u8; 64]; 2], u32)> = None;
+        for mode in 0..4 {
+            let mut predictions = [[0u8; 64]; 2];
+            let mut cost = 0;
+            let mut available = true;
+            for component in 0..2 {
+                available &= intra::predict_chroma(
+                    mode,
+                    &chroma_edges[component],
+                    &mut predictions[component],
+                )
+                .is_ok();
+                cost += sad(&chroma_source[component], &predictions[component]);
+            }
+            if available && best.is_none_or(|(_, _, best_cost)| cost < best_cost) {
+                best = Some((mode, predictions, cost));
+            }
+        }
+        let (chroma_mode, chroma_predictions, _) = best.expect("DC prediction is always available");
+        let mut chroma_dc = [[0i32; 4]; 2];
+        let mut chroma_ac = [[[0i32; 16]; 4]; 2];
+        for component in 0..2 {
+            for (index, block) in chroma_ac[component].iter_mut().enumerate() {
+                let (bx, by) = (index % 2 * 4, index / 2 * 4);
+                let residual: [i32; 16] = std::array::from_fn(|i| {
+                    let at = (by + i / 4) * 8 + bx + i % 4;
+                    i32::from(chroma_source[component][at])
+                        - i32::from(chroma_predictions[component][at])
+                });
+                *block = transform::forward(&residual);
+                chroma_dc[component][index] = block[0];
+                transform::quantize(block, self.chroma_qp, true);
+                block[0] = 0;
+            }
+            transform::forward_chroma_dc(&mut chroma_dc[component], self.chroma_qp);
+        }
+        let cbp_chroma = if chroma_ac.iter().flatten().flatten().any(|&l| l != 0) {
+            2
+        } else if chroma_dc.iter().flatten().any(|&level| level != 0) {
+            1
+        } else {
+            0
+        };
+        for component in 0..2 {
+            self.reconstruct_chroma(
+                component,
+                cx,
+                cy,
+                &chroma_predictions[component],
+                &chroma_dc[component],
+                &chroma_ac[component],
+            );
+        }
+
+        // mb_type 1-24 packs the prediction mode and coded block pattern.
+        let mb_type =
+            1 + u32::from(luma_mode) + 4 * cbp_chroma + if cbp_luma == 15 { 12 } else { 0 };
+        writer.write_ue(mb_type);
+        writer.write_ue(u32::from(chroma_mode));
+        writer.write_se(0); // mb_qp_delta
+        let nc = self.luma_nc(addr, 0, 0);
+        let scan: [i32; 16] = std::array::from_fn(|i| dc[ZIGZAG[i]]);
+        write_residual_block(writer, BlockContext::Predicted(nc), &scan);
+        if cbp_luma != 0 {
+            for &(x, y) in &BLOCK_POSITION {
+                let nc = self.luma_nc(addr, x, y);
+                let block = &ac[raster(x, y)];
+                let scan: [i32; 15] = std::array::from_fn(|i| block[ZIGZAG[i + 1]]);
+                let total = write_residual_block(writer, BlockContext::Predicted(nc), &scan);
+                self.luma_totals[addr][raster(x, y)] = total as u8;
+            }
+        }
+        if cbp_chroma != 0 {
+            for dc in &chroma_dc {
+                write_residual_block(writer, BlockContext::ChromaDc, dc);
+            }
+        }
+        if cbp_chroma == 2 {
+            for (component, blocks) in chroma_ac.iter().enumerate() {
+                for (index, block) in blocks.iter().enumerate() {
+                    let nc = self.chroma_nc(addr, component, index % 2, index / 2);
+                    let scan: [i32; 15] = std::array::from_fn(|i| block[ZIGZAG[i + 1]]);
+                    let total = write_residual_block(writer, BlockContext::Predicted(nc), &scan);
+                    self.chroma_totals[addr][component][index] = total as u8;
+                }
+            }
+        }
+    }
+
+    /// Rebuilds the macroblock exactly as the decoder will.
+    fn reconstruct_luma(
+        &mut self,
+        x0: usize,
+        y0: usize,
+        prediction: &[u8; 256],
+        dc: &[i32; 16],
+        ac: &[[i32; 16]; 16],
+    ) {
+        let stride = self.stride(0);
+        let recon = &mut self.recon[0];
+        for row in 0..16 {
+            recon[(y0 + row) * stride + x0..][..16]
+                .copy_from_slice(&prediction[row * 16..row * 16 + 16]);
+        }
+        let mut dc = *dc;
+        transform::luma_dc(&mut dc, self.qp);
+        for (index, levels) in ac.iter().enumerate() {
+            let mut block = *levels;
+            transform::dequantize(&mut block, self.qp, true);
+            block[0] = dc[index];
+            if block.iter().any(|&coeff| coeff != 0) {
+                let offset = (y0 + index / 4 * 4) * stride + x0 + index % 4 * 4;
+                transform::idct_add(&block, recon, offset, stride);
+            }
+        }
+    }
+
+    fn reconstruct_chroma(
+        &mut self,
+        component: usize,
+        x0: usize,
+        y0: usize,
+        prediction: &[u8; 64],
+        dc: &[i32; 4],
+        ac: &[[i32; 16]; 4],
+    ) {
+        let stride = self.stride(1);
+        let recon = &mut self.recon[1 + component];
+        for row in 0..8 {
+            recon[(y0 + row) * stride + x0..][..8]
+                .copy_from_slice(&prediction[row * 8..row * 8 + 8]);
+        }
+        let mut dc = *dc;
+        transform::chroma_dc(&mut dc, self.chroma_qp);
+        for (index, levels) in ac.iter().enumerate() {
+            let mut block = *levels;
+            transform::dequantize(&mut block, self.chroma_qp, true);
+            block[0] = dc[index];
+            if block.iter().any(|&coeff| coeff != 0) {
+                let offset = (y0 + index / 2 * 4) * stride + x0 + index % 2 * 4;
+                transform::idct_add(&block, recon, offset, stride);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::{
+        ContentLightLevel, MasteringDisplay, MediaStreams, PixelFormat, VideoCodec, VideoFrame,
+    };
+
+    fn psnr(a: &[u8], b: &[u8]) -> f64 {
+        let mse = a
+            .iter()
+            .zip(b)
+            .map(|(&a, &b)| (f64::from(a) - f64::from(b)).powi(2))
+            .sum::<f64>()
+            / a.len() as f64;
+        10.0 * (255.0 * 255.0 / mse.max(1e-9)).log10()
+    }
+
+    fn test_stream() -> VideoStream {
+        // 40x24 is not a whole number of macroblocks, so cropping is used.
+        let (width, height) = (40usize, 24usize);
+        let frames = (0..2)
+            .map(|index| {
+                let y = (0..width * height)
+                    .map(|i| {
+                        let (x, y) = (i % width, i / width);
+                        let ring = ((x as i32 - 20).pow(2) + (y as i32 - 12).pow(2)) < 64;
+                        (x * 4 + y * 2 + index * 10 + if ring { 60 } else { 0 }) as u8
+                    })
+                    .collect();
+                let u = (0..width * height / 4)
+                    .map(|i| (100 + i % 20) as u8)
+                    .collect();
+                let v = vec![150; width * height / 4];
+                VideoFrame {
+                    width: width as u32,
+                    height: height as u32,
+                    pixel_format: PixelFormat::Yuv420,
+                    data: FramePlanes::Yuv420 { y, u, v },
+                    timestamp: Duration::from_millis(40 * index as u64),
+                    duration: Duration::from_millis(40),
+                    keyframe: true,
+                }
+            })
+            .collect();
+        VideoStream {
+            codec: VideoCodec::H264,
+            frame_rate: FrameRate::Constant {
+                numerator: 25,
+                denominator: 1,
+            },
+            frames,
+            color_space: ColorSpace::Bt709,
+            transfer: TransferFunction::Sdr,
+            rotation: 0,
+            hdr: HdrMetadata::default(),
+        }
+    }
+
+    #[test]
+    fn encoded_frames_decode_close_to_the_source() {
+        let source = test_stream();
+        let encoded = encode(
+            &source,
+            &EncoderConfig {
+                qp: 20,
+                ..EncoderConfig::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(encoded.frames.len(), 2);
+        let annex_b = encoded.to_annex_b();
+        assert!(annex_b.len() < 40 * 24 * 3 / 2 * 2);
+
+        let mut media = MediaStreams::default();
+        super::super::decode_annex_b(&annex_b, &mut media).unwrap();
+        let decoded = media.video.unwrap();
+        assert_eq!(decoded.frames.len(), 2);
+        assert!(matches!(
+            decoded.frame_rate,
+            FrameRate::Constant {
+                numerator: 25,
+                denominator: 1
+            }
+        ));
+        for (original, decoded) in source.frames.iter().zip(&decoded.frames) {
+            assert_eq!((decoded.width, decoded.height), (40, 24));
+            assert!(decoded.keyframe);
+            let (
+                FramePlanes::Yuv420 { y, u, v },
+                FramePlanes::Yuv420 {
+                    y: dy,
+                    u: du,
+                    v: dv,
+                },
+            ) = (&original.data, &decoded.data)
+            else {
+                panic!("expected YUV 4:2:0");
+            };
+            assert!(psnr(y, dy) > 38.0, "luma PSNR {}", psnr(y, dy));
+            assert!(psnr(u, du) > 38.0, "Cb PSNR {}", psnr(u, du));
+            assert!(psnr(v, dv) > 38.0, "Cr PSNR {}", psnr(v, dv));
+        }
+
+        // A coarser quantiser gives a smaller stream.
+        let coarse = encode(
+            &source,
+            &EncoderConfig {
+                qp: 40,
+                ..EncoderConfig::default()
+            },
+        )
+        .unwrap();
+        assert!(coarse.to_annex_b().len() < annex_b.len());
+    }
+
+    #[test]
+    fn keyframe_interval_spaces_idr_pictures() {
+        let mut source = test_stream();
+        // 20 frames wrap frame_num, which counts to 15.
+        source.frames = (0..20)
+            .map(|index| {
+                let mut frame = source.frames[index % 2].clone();
+                frame.timestamp = Duration::from_millis(40 * index as u64);
+                frame
+            })
+            .collect();
+        let config = EncoderConfig {
+            keyframe_interval: 18,
+            ..EncoderConfig::default()
+        };
+        let encoded = encode(&source, &config).unwrap();
+        let keyframes: Vec<usize> = (0..20).filter(|&i| encoded.frames[i].keyframe).collect();
+        assert_eq!(keyframes, [0, 18]);
+        assert_eq!(encoded.frames[1].nal_units[0][0], NAL_SLICE);
+
+        let mut media = MediaStreams::default();
+        super::super::decode_annex_b(&encoded.to_annex_b(), &mut media).unwrap();
+        let decoded = media.video.unwrap();
+        assert_eq!(decoded.frames.len(), 20);
+        for (index, (original, decoded)) in source.frames.iter().zip(&decoded.frames).enumerate() {
+            assert_eq!(decoded.keyframe, index % 18 == 0, "frame {index}");
+            let (FramePlanes::Yuv420 { y, .. }, FramePlanes::Yuv420 { y: dy, .. }) =
+                (&original.data, &decoded.data)
+            else {
+                panic!("expected YUV 4:2:0");
+            };
+            assert!(
+                psnr(y, dy) > 30.0,
+                "frame {index} luma PSNR {}",
+                psnr(y, dy)
+            );
+        }
+
+        let config = EncoderConfig {
+            keyframe_interval: 0,
+            ..EncoderConfig::default()
+        };
+        assert!(encode(&source, &config).is_err());
+    }
+
+    #[test]
+    fn hdr_metadata_and_colour_survive_a_round_trip() {
+        let mut source = test_stream();
+        source.color_space = ColorSpace::Bt2020;
+        source.transfer = TransferFunction::Pq;
+        source.hdr = HdrMetadata {
+            mastering_display: Some(MasteringDisplay {
+                primaries: [[35400, 14600], [8500, 39850], [6550, 2300]],
+                white_point: [15635, 16450],
+                max_luminance: 10_000_000,
+                min_luminance: 50,
+            }),
+            content_light: Some(ContentLightLevel {
+                max_cll: 1000,
+                max_fall: 400,
+            }),
+        };
+        let config = EncoderConfig {
+            keyframe_interval: 2,
+            ..EncoderConfig::default()
+        };
+        let encoded = encode(&source, &config).unwrap();
+        assert_eq!(encoded.frames[0].nal_units[0][0], NAL_SEI);
+        assert_eq!(encoded.frames[1].nal_units.len(), 1);
+        assert_eq!(encoded.to_video().hdr, source.hdr);
+
+        let mut media = MediaStreams::default();
+        super::super::decode_annex_b(&encoded.to_annex_b(), &mut media).unwrap();
+        let decoded = media.video.unwrap();
+        assert_eq!(decoded.frames.len(), 2);
+        assert_eq!(decoded.hdr, source.hdr);
+        assert_eq!(decoded.color_space, ColorSpace::Bt2020);
+        assert_eq!(decoded.transfer, TransferFunction::Pq);
+    }
+
+    #[test]
+    fn rejects_odd_sizes_and_out_of_range_qp() {
+        let mut stream = test_stream();
+        assert!(
+            encode(
+                &stream,
+                &EncoderConfig {
+                    qp: 52,
+                    ..EncoderConfig::default()
+                }
+            )
+            .is_err()
+        );
+        stream.frames[0].width = 39;
+        assert!(encode(&stream, &EncoderConfig::default()).is_err());
+    }
+}
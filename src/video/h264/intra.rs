@@ -0,0 +1,303 @@
+//! Intra sample prediction (8.3): 4x4 and 16x16 luma and 8x8 chroma.
+
+use anyhow::{Result, bail};
+
+/// Neighbouring samples of a block: the row above (`top`, including the
+/// above-right samples for 4x4 blocks), the column to the left and the
+/// corner. The flags say which of them may be used.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Edges {
+    pub top: [u8; 16],
+    pub left: [u8; 16],
+    pub top_left: u8,
+    pub has_top: bool,
+    pub has_left: bool,
+    pub has_top_left: bool,
+}
+
+impl Edges {
+    /// `p[x, y]` with `-1` addressing the row above or the column left.
+    fn p(&self, x: i32, y: i32) -> i32 {
+        let sample = match (x, y) {
+            (-1, -1) => self.top_left,
+            (_, -1) => self.top[x as usize],
+            _ => self.left[y as usize],
+        };
+        i32::from(sample)
+    }
+
+    fn require(&self, top: bool, left: bool, top_left: bool) -> Result<()> {
+        if (top && !self.has_top) || (left && !self.has_left) || (top_left && !self.has_top_left) {
+            bail!("intra prediction refers to unavailable samples");
+        }
+        Ok(())
+    }
+
+    fn dc(&self, size: usize) -> u8 {
+        let sum = |samples: &[u8]| samples[..size].iter().map(|&s| u32::from(s)).sum::<u32>();
+        let shift = size.trailing_zeros();
+        let value = match (self.has_top, self.has_left) {
+            (true, true) => (sum(&self.top) + sum(&self.left) + size as u32) >> (shift + 1),
+            (true, false) => (sum(&self.top) + (size as u32 >> 1)) >> shift,
+            (false, true) => (sum(&self.left) + (size as u32 >> 1)) >> shift,
+            (false, false) => 128,
+        };
+        value as u8
+    }
+}
+
+fn avg2(a: i32, b: i32) -> u8 {
+    ((a + b + 1) >> 1) as u8
+}
+
+fn avg3(a: i32, b: i32, c: i32) -> u8 {
+    ((a + 2 * b + c + 2) >> 2) as u8
+}
+
+/// Predicts a 4x4 luma block into `out` (raster order).
+pub(super) fn predict_4x4(mode: u8, edges: &Edges, out: &mut [u8; 16]) -> Result<()> {
+    let e = edges;
+    for y in 0..4i32 {
+        for x in 0..4i32 {
+            let value = match mode {
+                0 => {
+                    e.require(true, false, false)?;
+                    e.p(x, -1) as u8
+                }
+                1 => {
+                    e.require(false, true, false)?;
+                    e.p(-1, y) as u8
+                }
+                2 => e.dc(4),
+                3 => {
+                    e.require(true, false, false)?;
+                    if x == 3 && y == 3 {
+                        avg3(e.p(6, -1), e.p(7, -1), e.p(7, -1))
+                    } else {
+                        avg3(e.p(x + y, -1), e.p(x + y + 1, -1), e.p(x + y + 2, -1))
+                    }
+                }
+                4 => {
+                    e.require(true, true, true)?;
+                    match x.cmp(&y) {
+                        std::cmp::Ordering::Greater => {
+                            avg3(e.p(x - y - 2, -1), e.p(x - y - 1, -1), e.p(x - y, -1))
+                        }
+                        std::cmp::Ordering::Less => {
+                            avg3(e.p(-1, y - x - 2), e.p(-1, y - x - 1), e.p(-1, y - x))
+                        }
+                        std::cmp::Ordering::Equal => avg3(e.p(0, -1), e.p(-1, -1), e.p(-1, 0)),
+                    }
+                }
+                5 => {
+                    e.require(true, true, true)?;
+                    let z = 2 * x - y;
+                    let x1 = x - (y >> 1);
+                    match z {
+                        0 | 2 | 4 | 6 => avg2(e.p(x1 - 1, -1), e.p(x1, -1)),
+                        1 | 3 | 5 => avg3(e.p(x1 - 2, -1), e.p(x1 - 1, -1), e.p(x1, -1)),
+                        -1 => avg3(e.p(-1, 0), e.p(-1, -1), e.p(0, -1)),
+                        _ => avg3(e.p(-1, y - 1), e.p(-1, y - 2), e.p(-1, y - 3)),
+                    }
+                }
+                6 => {
+                    e.require(true, true, true)?;
+                    let z = 2 * y - x;
+                    let y1 = y - (x >> 1);
+                    match z {
+                        0 | 2 | 4 | 6 => avg2(e.p(-1, y1 - 1), e.p(-1, y1)),
+                        1 | 3 | 5 => avg3(e.p(-1, y1 - 2), e.p(-1, y1 - 1), e.p(-1, y1)),
+                        -1 => avg3(e.p(-1, 0), e.p(-1, -1), e.p(0, -1)),
+                        _ => avg3(e.p(x - 1, -1), e.p(x - 2, -1), e.p(x - 3, -1)),
+                    }
+                }
+                7 => {
+                    e.require(true, false, false)?;
+                    let x1 = x + (y >> 1);
+                    if y % 2 == 0 {
+                        avg2(e.p(x1, -1), e.p(x1 + 1, -1))
+                    } else {
+                        avg3(e.p(x1, -1), e.p(x1 + 1, -1), e.p(x1 + 2, -1))
+                    }
+                }
+                8 => {
+                    e.require(false, true, false)?;
+                    let z = x + 2 * y;
+                    let y1 = y + (x >> 1);
+                    match z {
+                        0 | 2 | 4 => avg2(e.p(-1, y1), e.p(-1, y1 + 1)),
+                        1 | 3 => avg3(e.p(-1, y1), e.p(-1, y1 + 1), e.p(-1, y1 + 2)),
+                        5 => avg3(e.p(-1, 2), e.p(-1, 3), e.p(-1, 3)),
+                        _ => e.p(-1, 3) as u8,
+                    }
+                }
+                other => bail!("invalid Intra4x4 prediction mode {other}"),
+            };
+            out[(y * 4 + x) as usize] = value;
+        }
+    }
+    Ok(())
+}
+
+/// Plane prediction shared by 16x16 luma and 8x8 chroma blocks.
+fn plane(edges: &Edges, size: i32, out: &mut [u8]) -> Result<()> {
+    edges.require(true, true, true)?;
+    let half = size / 2;
+    let mut h = 0;
+    let mut v = 0;
+    for i in 0..half {
+        h += (i + 1) * (edges.p(half + i, -1) - edges.p(half - 2 - i, -1));
+        v += (i + 1) * (edges.p(-1, half + i) - edges.p(-1, half - 2 - i));
+    }
+    let a = 16 * (edges.p(-1, size - 1) + edges.p(size - 1, -1));
+    let (b, c) = if size == 16 {
+        ((5 * h + 32) >> 6, (5 * v + 32) >> 6)
+    } else {
+        ((34 * h + 32) >> 6, (34 * v + 32) >> 6)
+    };
+    let center = half - 1;
+    for y in 0..size {
+        for x in 0..size {
+            let value = (a + b * (x - center) + c * (y - center) + 16) >> 5;
+            out[(y * size + x) as usize] = value.clamp(0, 255) as u8;
+        }
+    }
+    Ok(())
+}
+
+/// Predicts a 16x16 luma macroblock into `out` (raster order).
+pub(super) fn predict_16x16(mode: u8, edges: &Edges, out: &mut [u8; 256]) -> Result<()> {
+    match mode {
+        0 => {
+            edges.require(true, false, false)?;
+            for row in out.chunks_exact_mut(16) {
+                row.copy_from_slice(&edges.top);
+            }
+        }
+        1 => {
+            edges.require(false, true, false)?;
+            for (row, &sample) in out.chunks_exact_mut(16).zip(&edges.left) {
+                row.fill(sample);
+            }
+        }
+        2 => out.fill(edges.dc(16)),
+        3 => plane(edges, 16, out)?,
+        other => bail!("invalid Intra16x16 prediction mode {other}"),
+    }
+    Ok(())
+}
+
+/// Predicts one 8x8 chroma block into `out` (raster order).
+pub(super) fn predict_chroma(mode: u8, edges: &Edges, out: &mut [u8; 64]) -> Result<()> {
+    match mode {
+        0 => {
+            for block_y in 0..2 {
+                for block_x in 0..2 {
+                    let value = chroma_dc(edges, block_x, block_y);
+                    for y in 0..4 {
+                        let start = (block_y * 4 + y) * 8 + block_x * 4;
+                        out[start..start + 4].fill(value);
+                    }
+                }
+            }
+        }
+        1 => {
+            edges.require(false, true, false)?;
+            for (row, &sample) in out.chunks_exact_mut(8).zip(&edges.left) {
+                row.fill(sample);
+            }
+        }
+        2 => {
+            edges.require(true, false, false)?;
+            for row in out.chunks_exact_mut(8) {
+                row.copy_from_slice(&edges.top[..8]);
+            }
+        }
+        3 => plane(edges, 8, out)?,
+        other => bail!("invalid chroma prediction mode {other}"),
+    }
+    Ok(())
+}
+
+/// DC of one 4x4 quarter of a chroma block (8.3.4.1-3), which leans on
+/// the edge that is next to it.
+fn chroma_dc(edges: &Edges, block_x: usize, block_y: usize) -> u8 {
+    let sum = |samples: &[u8], start: usize| {
+        samples[start..start + 4]
+            .iter()
+            .map(|&s| u32::from(s))
+            .sum::<u32>()
+    };
+    let top = edges.has_top.then(|| sum(&edges.top, block_x * 4));
+    let left = edges.has_left.then(|| sum(&edges.left, block_y * 4));
+    let one = |sum: u32| (sum + 2) >> 2;
+    let value = match (block_x, block_y) {
+        (1, 0) => top.or(left).map(one),
+        (0, 1) => left.or(top).map(one),
+        _ => match (top, left) {
+            (Some(top), Some(left)) => Some((top + left + 4) >> 3),
+            (top, left) => top.or(left).map(one),
+        },
+    };
+    value.unwrap_or(128) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edges() -> Edges {
+        let mut top = [0u8; 16];
+        let mut left = [0u8; 16];
+        for i in 0..16 {
+            top[i] = 10 * i as u8;
+            left[i] = 5 + 3 * i as u8;
+        }
+        Edges {
+            top,
+            left,
+            top_left: 7,
+            has_top: true,
+            has_left: true,
+            has_top_left: true,
+        }
+    }
+
+    #[test]
+    fn directional_4x4_modes_follow_their_edges() {
+        let edges = edges();
+        let mut out = [0u8; 16];
+        predict_4x4(0, &edges, &mut out).unwrap();
+        assert_eq!(&out[12..], &[0, 10, 20, 30]);
+        predict_4x4(1, &edges, &mut out).unwrap();
+        assert_eq!(&out[4..8], &[8, 8, 8, 8]);
+        predict_4x4(2, &edges, &mut out).unwrap();
+        // (0 + 10 + 20 + 30 + 5 + 8 + 11 + 14 + 4) >> 3
+        assert_eq!(out, [12; 16]);
+        predict_4x4(4, &edges, &mut out).unwrap();
+        // The diagonal smooths the corner: (0 + 2 * 7 + 5 + 2) >> 2.
+        assert_eq!([out[0], out[5], out[10], out[15]], [5; 4]);
+        predict_4x4(3, &edges, &mut out).unwrap();
+        // (60 + 3 * 70 + 2) >> 2 in the bottom-right corner.
+        assert_eq!(out[15], 68);
+
+        let mut no_left = edges;
+        no_left.has_left = false;
+        assert!(predict_4x4(1, &no_left, &mut out).is_err());
+    }
+
+    #[test]
+    fn chroma_dc_leans_on_the_adjacent_edge() {
+        let mut edges = edges();
+        let mut out = [0u8; 64];
+        predict_chroma(0, &edges, &mut out).unwrap();
+        // Top-right quarter uses only the top row: (40+50+60+70+2) >> 2.
+        assert_eq!(out[4], 55);
+        // Bottom-left quarter uses only the left column: (17+20+23+26+2) >> 2.
+        assert_eq!(out[32], 22);
+        edges.has_top = false;
+        edges.has_left = false;
+        predict_chroma(0, &edges, &mut out).unwrap();
+        assert_eq!(out, [128; 64]);
+    }
+}
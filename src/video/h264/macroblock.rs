@@ -0,0 +1,1466 @@
+//! Slice data (7.3.4) and macroblock layer (7.3.5) parsing with CAVLC, and
+//! the reconstruction of each macroblock into the current picture.
+
+use std::rc::Rc;
+
+use anyhow::{Result, anyhow, bail};
+
+use super::bits::BitReader;
+use super::cavlc::{BlockContext, read_residual_block};
+use super::dpb::{RefLists, RefPicture};
+use super::inter::{self, Plane};
+use super::intra::{self, Edges};
+use super::picture::{Frame, MbInfo, MbKind};
+use super::slice::{SliceHeader, SliceType, Weights};
+use super::transform::{self, ZIGZAG};
+
+/// Decoding-order index of a luma 4x4 block to its raster position
+/// `(x, y)` in 4x4 block units.
+pub(super) const BLOCK_POSITION: [(usize, usize); 16] = [
+    (0, 0),
+    (1, 0),
+    (0, 1),
+    (1, 1),
+    (2, 0),
+    (3, 0),
+    (2, 1),
+    (3, 1),
+    (0, 2),
+    (1, 2),
+    (0, 3),
+    (1, 3),
+    (2, 2),
+    (3, 2),
+    (2, 3),
+    (3, 3),
+];
+
+/// `coded_block_pattern` by `me(v)` code number (Table 9-4), for intra
+/// (Intra4x4) and inter macroblocks.
+const CBP_INTRA: [u8; 48] = [
+    47, 31, 15, 0, 23, 27, 29, 30, 7, 11, 13, 14, 39, 43, 45, 46, 16, 3, 5, 10, 12, 19, 21, 26, 28,
+    35, 37, 42, 44, 1, 2, 4, 8, 17, 18, 20, 24, 6, 9, 22, 25, 32, 33, 34, 36, 40, 38, 41,
+];
+const CBP_INTER: [u8; 48] = [
+    0, 16, 1, 2, 4, 8, 32, 3, 5, 10, 12, 15, 47, 7, 11, 13, 14, 6, 9, 31, 35, 37, 42, 44, 33, 34,
+    36, 40, 39, 43, 45, 46, 17, 18, 20, 24, 19, 21, 26, 28, 23, 27, 29, 30, 22, 25, 38, 41,
+];
+
+/// The raster index of a luma 4x4 block.
+pub(super) fn raster(x: usize, y: usize) -> usize {
+    y * 4 + x
+}
+
+/// Decoding-order index of the luma 4x4 block at raster `(x, y)`.
+fn decode_index(x: usize, y: usize) -> usize {
+    (y / 2) * 8 + (x / 2) * 4 + (y % 2) * 2 + (x % 2)
+}
+
+/// The lists an inter partition predicts from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Pred {
+    L0,
+    L1,
+    Bi,
+}
+
+impl Pred {
+    fn uses(self, list: usize) -> bool {
+        match self {
+            Pred::L0 => list == 0,
+            Pred::L1 => list == 1,
+            Pred::Bi => true,
+        }
+    }
+}
+
+/// How a macroblock is split into partitions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Layout {
+    Whole,
+    Split16x8,
+    Split8x16,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum MbType {
+    Intra4x4,
+    Intra16x16 {
+        mode: u8,
+        cbp_luma: u8,
+        cbp_chroma: u8,
+    },
+    Pcm,
+    /// One or two partitions with the lists each predicts from.
+    Inter {
+        layout: Layout,
+        preds: [Pred; 2],
+    },
+    /// Four sub-macroblocks; P_8x8ref0 codes no reference indices.
+    P8x8 {
+        ref0: bool,
+    },
+    B8x8,
+    /// B_Direct_16x16: B_Skip's derived motion, with a residual.
+    Direct,
+}
+
+fn p_mb_type(value: u32) -> Result<MbType> {
+    let inter = |layout| MbType::Inter {
+        layout,
+        preds: [Pred::L0; 2],
+    };
+    Ok(match value {
+        0 => inter(Layout::Whole),
+        1 => inter(Layout::Split16x8),
+        2 => inter(Layout::Split8x16),
+        3 => MbType::P8x8 { ref0: false },
+        4 => MbType::P8x8 { ref0: true },
+        other => intra_mb_type(other - 5)?,
+    })
+}
+
+/// B macroblock types (Table 7-14).
+fn b_mb_type(value: u32) -> Result<MbType> {
+    use Pred::{Bi, L0, L1};
+    const PAIRS: [[Pred; 2]; 9] = [
+        [L0, L0],
+        [L1, L1],
+        [L0, L1],
+        [L1, L0],
+        [L0, Bi],
+        [L1, Bi],
+        [Bi, L0],
+        [Bi, L1],
+        [Bi, Bi],
+    ];
+    Ok(match value {
+        0 => MbType::Direct,
+        1..=3 => MbType::Inter {
+            layout: Layout::Whole,
+            preds: [[L0, L1, Bi][value as usize - 1]; 2],
+        },
+        4..=21 => MbType::Inter {
+            layout: if value.is_multiple_of(2) {
+                Layout::Split16x8
+            } else {
+                Layout::Split8x16
+            },
+            preds: PAIRS[(value as usize - 4) / 2],
+        },
+        22 => MbType::B8x8,
+        other => intra_mb_type(other - 23)?,
+    })
+}
+
+/// A sub-macroblock's lists and its split into 8x8, 8x4, 4x8 or 4x4
+/// partitions (0 to 3); `None` for B_Direct_8x8 (Tables 7-17 and 7-18).
+fn sub_mb_type(slice_type: SliceType, value: u32) -> Result<Option<(Pred, usize)>> {
+    const PREDS: [Pred; 3] = [Pred::L0, Pred::L1, Pred::Bi];
+    let value = value as usize;
+    Ok(match (slice_type, value) {
+        (SliceType::P, 0..=3) => Some((Pred::L0, value)),
+        (SliceType::B, 0) => None,
+        (SliceType::B, 1..=3) => Some((PREDS[value - 1], 0)),
+        (SliceType::B, 4..=9) => Some((PREDS[(value - 4) / 2], 1 + value % 2)),
+        (SliceType::B, 10..=12) => Some((PREDS[value - 10], 3)),
+        _ => bail!("invalid sub_mb_type {value}"),
+    })
+}
+
+fn intra_mb_type(value: u32) -> Result<MbType> {
+    Ok(match value {
+        0 => MbType::Intra4x4,
+        1..=24 => {
+            let index = value - 1;
+            MbType::Intra16x16 {
+                mode: (index % 4) as u8,
+                cbp_chroma: ((index / 4) % 3) as u8,
+                cbp_luma: if index >= 12 { 15 } else { 0 },
+            }
+        }
+        25 => MbType::Pcm,
+        other => bail!("invalid intra mb_type {other}"),
+    })
+}
+
+/// How the neighbouring motion vectors are combined for a partition
+/// (8.4.1.3).
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Shape {
+    Median,
+    Upper16x8,
+    Lower16x8,
+    Left8x16,
+    Right8x16,
+}
+
+/// One motion-compensated partition, in 4x4 block units within the
+/// macroblock.
+#[derive(Debug, Clone, Copy)]
+struct Partition {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    /// Reference index in each list, `None` for a list the partition does
+    /// not predict from.
+    ref_idx: [Option<u32>; 2],
+    mvd: [[i32; 2]; 2],
+    shape: Shape,
+    /// A B_Direct_8x8 sub-macroblock, whose motion is derived rather than
+    /// coded.
+    direct: bool,
+}
+
+/// The motion of a block: its reference index and vector in each list,
+/// with a negative index for a list it does not predict from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Motion {
+    ref_idx: [i32; 2],
+    mv: [[i32; 2]; 2],
+}
+
+impl Default for Motion {
+    fn default() -> Self {
+        Self {
+            ref_idx: [-1; 2],
+            mv: [[0; 2]; 2],
+        }
+    }
+}
+
+/// Residual levels of one macroblock, each 4x4 block in raster order.
+struct Residual {
+    luma: [[i32; 16]; 16],
+    luma_dc: Option<[i32; 16]>,
+    chroma_dc: [[i32; 4]; 2],
+    chroma_ac: [[[i32; 16]; 4]; 2],
+}
+
+impl Residual {
+    fn new() -> Self {
+        Self {
+            luma: [[0; 16]; 16],
+            luma_dc: None,
+            chroma_dc: [[0; 4]; 2],
+            chroma_ac: [[[0; 16]; 4]; 2],
+        }
+    }
+}
+
+/// Everything one slice decodes into.
+pub(super) struct SliceDecoder<'a> {
+    pub header: &'a SliceHeader,
+    /// Distinguishes this slice's macroblocks from other slices'.
+    pub slice_num: u32,
+    pub width_in_mbs: usize,
+    pub height_in_mbs: usize,
+    pub constrained_intra_pred: bool,
+    pub chroma_qp_offsets: [i32; 2],
+    pub direct_8x8_inference: bool,
+    pub ref_lists: &'a RefLists,
+    /// Picture order count of the picture being decoded.
+    pub poc: i32,
+    pub frame: &'a mut Frame,
+    pub mbs: &'a mut [MbInfo],
+}
+
+impl SliceDecoder<'_> {
+    /// `slice_data()`: decodes macroblocks until the payload runs out.
+    pub fn decode(&mut self, reader: &mut BitReader<'_>) -> Result<()> {
+        let total = self.width_in_mbs * self.height_in_mbs;
+        let mut addr = self.header.first_mb as usize;
+        let mut qp = self.header.qp;
+        loop {
+            if self.header.slice_type != SliceType::I {
+                let run = reader.read_ue()? as usize;
+                for _ in 0..run {
+                    if addr >= total {
+                        bail!("mb_skip_run runs past the end of the picture");
+                    }
+                    self.decode_skip(addr, qp)?;
+                    addr += 1;
+                }
+                if run > 0 && !reader.more_rbsp_data() {
+                    return Ok(());
+                }
+            }
+            if addr >= total {
+                bail!("slice data runs past the end of the picture");
+            }
+            self.decode_macroblock(reader, addr, &mut qp)?;
+            addr += 1;
+            if !reader.more_rbsp_data() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn new_info(&self, kind: MbKind, qp: i32) -> MbInfo {
+        MbInfo {
+            slice: self.slice_num,
+            kind,
+            qp,
+            chroma_qp: [
+                transform::chroma_qp(qp, self.chroma_qp_offsets[0]),
+                transform::chroma_qp(qp, self.chroma_qp_offsets[1]),
+            ],
+            disable_deblocking_filter_idc: self.header.disable_deblocking_filter_idc,
+            alpha_offset: self.header.alpha_offset,
+            beta_offset: self.header.beta_offset,
+            ..MbInfo::default()
+        }
+    }
+
+    /// The neighbouring macroblock to the left (A), above (B), above-right
+    /// (C) or above-left (D), if it is decoded and in this slice.
+    fn neighbour(&self, addr: usize, dx: i32, dy: i32) -> Option<usize> {
+        let x = (addr % self.width_in_mbs) as i32 + dx;
+        let y = (addr / self.width_in_mbs) as i32 + dy;
+        if x < 0 || y < 0 || x >= self.width_in_mbs as i32 {
+            return None;
+        }
+        let index = y as usize * self.width_in_mbs + x as usize;
+        (index < addr && self.mbs[index].slice == self.slice_num).then_some(index)
+    }
+
+    /// Whether a neighbouring macroblock's samples may feed intra
+    /// prediction.
+    fn intra_source(&self, neighbour: Option<usize>) -> bool {
+        neighbour.is_some_and(|index| !self.constrained_intra_pred || self.mbs[index].is_intra())
+    }
+
+    fn mb_origin(&self, addr: usize) -> (usize, usize) {
+        (
+            (addr % self.width_in_mbs) * 16,
+            (addr / self.width_in_mbs) * 16,
+        )
+    }
+
+    /// P_Skip, predicted from the first reference with the predicted
+    /// motion vector, or B_Skip, with direct prediction; neither has a
+    /// residual.
+    fn decode_skip(&mut self, addr: usize, qp: i32) -> Result<()> {
+        let mut info = self.new_info(MbKind::Inter, qp);
+        if self.header.slice_type == SliceType::B {
+            let motion = self.direct_motion(addr, &info)?;
+            for quadrant in 0..4 {
+                self.compensate_direct(addr, &mut info, &motion, quadrant)?;
+            }
+            self.mbs[addr] = info;
+            return Ok(());
+        }
+        let a = self.neighbour(addr, -1, 0);
+        let b = self.neighbour(addr, 0, -1);
+        let mv = if a.is_none() || b.is_none() {
+            [0, 0]
+        } else {
+            let zero = |neighbour: Option<(i32, [i32; 2])>| {
+                neighbour.is_some_and(|(ref_idx, mv)| ref_idx == 0 && mv == [0, 0])
+            };
+            let filled = 0;
+            if zero(self.block_motion(addr, &info, filled, 0, -1, 0))
+                || zero(self.block_motion(addr, &info, filled, 0, 0, -1))
+            {
+                [0, 0]
+            } else {
+                self.predict_mv(addr, &info, filled, 0, 0, 0, 4, 0, Shape::Median)
+            }
+        };
+        let motion = Motion {
+            ref_idx: [0, -1],
+            mv: [mv, [0, 0]],
+        };
+        self.motion_compensate(addr, &mut info, (0, 0, 4, 4), motion)?;
+        self.mbs[addr] = info;
+        Ok(())
+    }
+
+    fn decode_macroblock(
+        &mut self,
+        reader: &mut BitReader<'_>,
+        addr: usize,
+        qp: &mut i32,
+    ) -> Result<()> {
+        let mb_type = reader.read_ue()?;
+        let mb_type = match self.header.slice_type {
+            SliceType::I => intra_mb_type(mb_type)?,
+            SliceType::P => p_mb_type(mb_type)?,
+            SliceType::B => b_mb_type(mb_type)?,
+        };
+
+        if let MbType::Pcm = mb_type {
+            return self.decode_pcm(reader, addr);
+        }
+
+        let mut intra_modes = [0u8; 16];
+        let mut chroma_mode = 0;
+        let mut partitions = Vec::new();
+        match mb_type {
+            MbType::Intra4x4 => {
+                for mode in &mut intra_modes {
+                    // Kept as -1 for "predicted" until the neighbours are
+                    // known; otherwise the remaining mode.
+                    *mode = if reader.read_flag()? {
+                        u8::MAX
+                    } else {
+                        reader.read_bits(3)? as u8
+                    };
+                }
+                chroma_mode = self.read_chroma_mode(reader)?;
+            }
+            MbType::Intra16x16 { .. } => chroma_mode = self.read_chroma_mode(reader)?,
+            MbType::Inter { layout, preds } => {
+                partitions = self.read_mb_pred(reader, layout, preds)?
+            }
+            MbType::P8x8 { ref0 } => partitions = self.read_sub_mb_pred(reader, ref0)?,
+            MbType::B8x8 => partitions = self.read_sub_mb_pred(reader, false)?,
+            MbType::Pcm | MbType::Direct => {}
+        }
+
+        let (cbp_luma, cbp_chroma) = match mb_type {
+            MbType::Intra16x16 {
+                cbp_luma,
+                cbp_chroma,
+                ..
+            } => (cbp_luma, cbp_chroma),
+            _ => {
+                let code = reader.read_ue()? as usize;
+                let table = if matches!(mb_type, MbType::Intra4x4) {
+                    &CBP_INTRA
+                } else {
+                    &CBP_INTER
+                };
+                let cbp = *table
+                    .get(code)
+                    .ok_or_else(|| anyhow::anyhow!("invalid coded_block_pattern {code}"))?;
+                (cbp & 15, cbp >> 4)
+            }
+        };
+        let intra16 = matches!(mb_type, MbType::Intra16x16 { .. });
+        if cbp_luma > 0 || cbp_chroma > 0 || intra16 {
+            let delta = reader.read_se()?;
+            if !(-26..=25).contains(&delta) {
+                bail!("mb_qp_delta {delta} is out of range");
+            }
+            *qp = (*qp + delta + 52) % 52;
+        }
+
+        let kind = match mb_type {
+            MbType::Intra4x4 => MbKind::Intra4x4,
+            MbType::Intra16x16 { .. } => MbKind::Intra16x16,
+            _ => MbKind::Inter,
+        };
+        let mut info = self.new_info(kind, *qp);
+        let residual =
+            self.read_residual(reader, addr, &mut info, intra16, cbp_luma, cbp_chroma)?;
+
+        match mb_type {
+            MbType::Intra4x4 => {
+                self.reconstruct_intra4x4(addr, &mut info, &intra_modes, &residual)?
+            }
+            MbType::Intra16x16 { mode, .. } => {
+                self.reconstruct_intra16x16(addr, mode, &residual, *qp)?
+            }
+            MbType::Direct => {
+                let motion = self.direct_motion(addr, &info)?;
+                for quadrant in 0..4 {
+                    self.compensate_direct(addr, &mut info, &motion, quadrant)?;
+                }
+                self.add_luma_residual(addr, &residual, *qp, false);
+            }
+            _ => {
+                // Direct sub-macroblocks take their motion from the
+                // neighbouring macroblocks, so it is derived before any
+                // partition of this one.
+                let direct = if partitions.iter().any(|partition| partition.direct) {
+                    Some(self.direct_motion(addr, &info)?)
+                } else {
+                    None
+                };
+                for partition in &partitions {
+                    if let Some(direct) = direct.as_ref().filter(|_| partition.direct) {
+                        let quadrant = partition.y + partition.x / 2;
+                        self.compensate_direct(addr, &mut info, direct, quadrant)?;
+                        continue;
+                    }
+                    let filled = filled_mask(&info);
+                    let mut motion = Motion::default();
+                    for (list, ref_idx) in partition.ref_idx.iter().enumerate() {
+                        let Some(ref_idx) = *ref_idx else {
+                            continue;
+                        };
+                        let predicted = self.predict_mv(
+                            addr,
+                            &info,
+                            filled,
+                            list,
+                            partition.x,
+                            partition.y,
+                            partition.width,
+                            ref_idx as i32,
+                            partition.shape,
+                        );
+                        let mvd = partition.mvd[list];
+                        motion.ref_idx[list] = ref_idx as i32;
+                        motion.mv[list] = [predicted[0] + mvd[0], predicted[1] + mvd[1]];
+                    }
+                    let area = (partition.x, partition.y, partition.width, partition.height);
+                    self.motion_compensate(addr, &mut info, area, motion)?;
+                }
+                self.add_luma_residual(addr, &residual, *qp, false);
+            }
+        }
+        if kind != MbKind::Inter {
+            self.predict_chroma_intra(addr, chroma_mode)?;
+        }
+        self.add_chroma_residual(addr, &residual, &info);
+        self.mbs[addr] = info;
+        Ok(())
+    }
+
+    fn read_chroma_mode(&self, reader: &mut BitReader<'_>) -> Result<u8> {
+        let mode = reader.read_ue()?;
+        if mode > 3 {
+            bail!("invalid intra_chroma_pred_mode {mode}");
+        }
+        Ok(mode as u8)
+    }
+
+    fn read_ref_idx(&self, reader: &mut BitReader<'_>, list: usize) -> Result<u32> {
+        let active = self.header.num_ref_idx_active[list];
+        if active <= 1 {
+            return Ok(0);
+        }
+        let ref_idx = reader.read_te(active - 1)?;
+        if ref_idx >= active {
+            bail!("ref_idx_l{list} {ref_idx} is out of range");
+        }
+        Ok(ref_idx)
+    }
+
+    fn read_mvd(reader: &mut BitReader<'_>) -> Result<[i32; 2]> {
+        Ok([reader.read_se()?, reader.read_se()?])
+    }
+
+    /// `mb_pred()` of an inter macroblock: the reference indices of every
+    /// partition for list 0, then list 1, then their motion vector
+    /// differences in the same order.
+    fn read_mb_pred(
+        &self,
+        reader: &mut BitReader<'_>,
+        layout: Layout,
+        preds: [Pred; 2],
+    ) -> Result<Vec<Partition>> {
+        let shapes: &[(usize, usize, usize, usize, Shape)] = match layout {
+            Layout::Whole => &[(0, 0, 4, 4, Shape::Median)],
+            Layout::Split16x8 => &[
+                (0, 0, 4, 2, Shape::Upper16x8),
+                (0, 2, 4, 2, Shape::Lower16x8),
+            ],
+            Layout::Split8x16 => &[
+                (0, 0, 2, 4, Shape::Left8x16),
+                (2, 0, 2, 4, Shape::Right8x16),
+            ],
+        };
+        let mut partitions: Vec<Partition> = shapes
+            .iter()
+            .map(|&(x, y, width, height, shape)| Partition {
+                x,
+                y,
+                width,
+                height,
+                ref_idx: [None; 2],
+                mvd: [[0; 2]; 2],
+                shape,
+                direct: false,
+            })
+            .collect();
+        for list in 0..2 {
+            for (partition, pred) in partitions.iter_mut().zip(preds) {
+                if pred.uses(list) {
+                    partition.ref_idx[list] = Some(self.read_ref_idx(reader, list)?);
+                }
+            }
+        }
+        Self::read_mvds(reader, &mut partitions)?;
+        Ok(partitions)
+    }
+
+    /// `sub_mb_pred()`: the four sub-macroblock types, their reference
+    /// indices by list, then the motion vector differences of their
+    /// partitions by list.
+    fn read_sub_mb_pred(&self, reader: &mut BitReader<'_>, ref0: bool) -> Result<Vec<Partition>> {
+        let mut sub_types = [None; 4];
+        for sub_type in &mut sub_types {
+            *sub_type = sub_mb_type(self.header.slice_type, reader.read_ue()?)?;
+        }
+        let mut ref_indices = [[None; 2]; 4];
+        for list in 0..2 {
+            for (sub_type, ref_idx) in sub_types.iter().zip(&mut ref_indices) {
+                if sub_type.is_some_and(|(pred, _)| pred.uses(list)) {
+                    ref_idx[list] = Some(if ref0 {
+                        0
+                    } else {
+                        self.read_ref_idx(reader, list)?
+                    });
+                }
+            }
+        }
+        let mut partitions = Vec::with_capacity(16);
+        for (index, (sub_type, ref_idx)) in sub_types.iter().zip(ref_indices).enumerate() {
+            let (base_x, base_y) = ((index % 2) * 2, (index / 2) * 2);
+            let layout: &[(usize, usize, usize, usize)] = match sub_type {
+                None | Some((_, 0)) => &[(0, 0, 2, 2)],
+                Some((_, 1)) => &[(0, 0, 2, 1), (0, 1, 2, 1)],
+                Some((_, 2)) => &[(0, 0, 1, 2), (1, 0, 1, 2)],
+                Some(_) => &[(0, 0, 1, 1), (1, 0, 1, 1), (0, 1, 1, 1), (1, 1, 1, 1)],
+            };
+            for &(x, y, width, height) in layout {
+                partitions.push(Partition {
+                    x: base_x + x,
+                    y: base_y + y,
+                    width,
+                    height,
+                    ref_idx,
+                    mvd: [[0; 2]; 2],
+                    shape: Shape::Median,
+                    direct: sub_type.is_none(),
+                });
+            }
+        }
+        Self::read_mvds(reader, &mut partitions)?;
+        Ok(partitions)
+    }
+
+    /// Reads the motion vector differences of every partition that predicts
+    /// from list 0, then of those that predict from list 1.
+    fn read_mvds(reader: &mut BitReader<'_>, partitions: &mut [Partition]) -> Result<()> {
+        for list in 0..2 {
+            for partition in partitions.iter_mut() {
+                if partition.ref_idx[list].is_some() {
+                    partition.mvd[list] = Self::read_mvd(reader)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// `nC` for a luma block from the blocks left of and above it.
+    fn luma_nc(&self, addr: usize, info: &MbInfo, x: usize, y: usize) -> u32 {
+        let left = if x > 0 {
+            Some(info.luma_coeffs[raster(x - 1, y)])
+        } else {
+            self.neighbour(addr, -1, 0)
+                .map(|n| self.mbs[n].luma_coeffs[raster(3, y)])
+        };
+        let top = if y > 0 {
+            Some(info.luma_coeffs[raster(x, y - 1)])
+        } else {
+            self.neighbour(addr, 0, -1)
+                .map(|n| self.mbs[n].luma_coeffs[raster(x, 3)])
+        };
+        combine_nc(left, top)
+    }
+
+    fn chroma_nc(&self, addr: usize, info: &MbInfo, component: usize, x: usize, y: usize) -> u32 {
+        let coeffs = |mb: &MbInfo, x: usize, y: usize| mb.chroma_coeffs[component][y * 2 + x];
+        let left = if x > 0 {
+            Some(coeffs(info, 0, y))
+        } else {
+            self.neighbour(addr, -1, 0)
+                .map(|n| coeffs(&self.mbs[n], 1, y))
+        };
+        let top = if y > 0 {
+            Some(coeffs(info, x, 0))
+        } else {
+            self.neighbour(addr, 0, -1)
+                .map(|n| coeffs(&self.mbs[n], x, 1))
+        };
+        combine_nc(left, top)
+    }
+
+    /// `residual()`: reads the coded blocks and records their
+    /// `TotalCoeff` in `info`.
+    fn read_residual(
+        &self,
+        reader: &mut BitReader<'_>,
+        addr: usize,
+        info: &mut MbInfo,
+        intra16: bool,
+        cbp_luma: u8,
+        cbp_chroma: u8,
+    ) -> Result<Residual> {
+        let mut residual = Residual::new();
+        let mut scan = [0i32; 16];
+        if intra16 {
+            let nc = self.luma_nc(addr, info, 0, 0);
+            read_residual_block(reader, BlockContext::Predicted(nc), &mut scan)?;
+            let mut dc = [0i32; 16];
+            for (index, &level) in scan.iter().enumerate() {
+                dc[ZIGZAG[index]] = level;
+            }
+            residual.luma_dc = Some(dc);
+        }
+        for (index, &(x, y)) in BLOCK_POSITION.iter().enumerate() {
+            if cbp_luma & (1 << (index / 4)) == 0 {
+                continue;
+            }
+            let nc = self.luma_nc(addr, info, x, y);
+            let block = &mut residual.luma[raster(x, y)];
+            let total = if intra16 {
+                let total =
+                    read_residual_block(reader, BlockContext::Predicted(nc), &mut scan[..15])?;
+                for (index, &level) in scan[..15].iter().enumerate() {
+                    block[ZIGZAG[index + 1]] = level;
+                }
+                total
+            } else {
+                let total = read_residual_block(reader, BlockContext::Predicted(nc), &mut scan)?;
+                for (index, &level) in scan.iter().enumerate() {
+                    block[ZIGZAG[index]] = level;
+                }
+                total
+            };
+            info.luma_coeffs[raster(x, y)] = total as u8;
+        }
+        if cbp_chroma & 3 != 0 {
+            for component in 0..2 {
+                read_residual_block(
+                    reader,
+                    BlockContext::ChromaDc,
+                    &mut residual.chroma_dc[component],
+                )?;
+            }
+        }
+        if cbp_chroma & 2 != 0 {
+            for component in 0..2 {
+                for index in 0..4 {
+                    let (x, y) = (index % 2, index / 2);
+                    let nc = self.chroma_nc(addr, info, component, x, y);
+                    let total =
+                        read_residual_block(reader, BlockContext::Predicted(nc), &mut scan[..15])?;
+                    let block = &mut residual.chroma_ac[component][index];
+                    for (position, &level) in scan[..15].iter().enumerate() {
+                        block[ZIGZAG[position + 1]] = level;
+                    }
+                    info.chroma_coeffs[component][index] = total as u8;
+                }
+            }
+        }
+        Ok(residual)
+    }
+
+    fn decode_pcm(&mut self, reader: &mut BitReader<'_>, addr: usize) -> Result<()> {
+        reader.align();
+        let (x0, y0) = self.mb_origin(addr);
+        let width = self.frame.width;
+        for y in 0..16 {
+            for x in 0..16 {
+                self.frame.luma[(y0 + y) * width + x0 + x] = reader.read_byte()?;
+            }
+        }
+        let chroma_width = width / 2;
+        for component in 0..2 {
+            let plane = self.frame.chroma_mut(component);
+            for y in 0..8 {
+                for x in 0..8 {
+                    plane[(y0 / 2 + y) * chroma_width + x0 / 2 + x] = reader.read_byte()?;
+                }
+            }
+        }
+        // Deblocking treats I_PCM as QP 0; QPY,PRED for the next macroblock
+        // stays with the slice's running QP.
+        let mut info = self.new_info(MbKind::Pcm, 0);
+        info.luma_coeffs = [16; 16];
+        info.chroma_coeffs = [[16; 4]; 2];
+        self.mbs[addr] = info;
+        Ok(())
+    }
+
+    /// Edge samples for an intra block of `size` luma samples at `x, y`
+    /// within the macroblock; `top_right` says whether the samples above
+    /// and to the right of a 4x4 block exist yet.
+    fn luma_edges(&self, addr: usize, x: usize, y: usize, size: usize) -> Edges {
+        let a = self.intra_source(self.neighbour(addr, -1, 0));
+        let b = self.intra_source(self.neighbour(addr, 0, -1));
+        let d = self.intra_source(self.neighbour(addr, -1, -1));
+        let has_left = x > 0 || a;
+        let has_top = y > 0 || b;
+        let has_top_left = match (x > 0, y > 0) {
+            (true, true) => true,
+            (false, true) => a,
+            (true, false) => b,
+            (false, false) => d,
+        };
+        let (x0, y0) = self.mb_origin(addr);
+        let (px, py) = (x0 + x, y0 + y);
+        let width = self.frame.width;
+        let luma = &self.frame.luma;
+        let mut edges = Edges {
+            top: [0; 16],
+            left: [0; 16],
+            top_left: 0,
+            has_top,
+            has_left,
+            has_top_left,
+        };
+        if has_top {
+            let row = (py - 1) * width;
+            edges.top[..size].copy_from_slice(&luma[row + px..row + px + size]);
+            if size == 4 {
+                if self.top_right_available(addr, x / 4, y / 4) {
+                    edges.top[4..8].copy_from_slice(&luma[row + px + 4..row + px + 8]);
+                } else {
+                    let last = edges.top[3];
+                    edges.top[4..8].fill(last);
+                }
+            }
+        }
+        if has_left {
+            for row in 0..size {
+                edges.left[row] = luma[(py + row) * width + px - 1];
+            }
+        }
+        if has_top_left {
+            edges.top_left = luma[(py - 1) * width + px - 1];
+        }
+        edges
+    }
+
+    /// Whether the 4x4 block above and right of block `(x, y)` is decoded
+    /// and usable for intra prediction.
+    fn top_right_available(&self, addr: usize, x: usize, y: usize) -> bool {
+        if y == 0 {
+            if x < 3 {
+                self.intra_source(self.neighbour(addr, 0, -1))
+            } else {
+                self.intra_source(self.neighbour(addr, 1, -1))
+            }
+        } else {
+            x < 3 && decode_index(x + 1, y - 1) < decode_index(x, y)
+        }
+    }
+
+    fn reconstruct_intra4x4(
+        &mut self,
+        addr: usize,
+        info: &mut MbInfo,
+        coded_modes: &[u8; 16],
+        residual: &Residual,
+    ) -> Result<()> {
+        let a = self.neighbour(addr, -1, 0);
+        let b = self.neighbour(addr, 0, -1);
+        let width = self.frame.width;
+        let (x0, y0) = self.mb_origin(addr);
+        for (index, &coded) in coded_modes.iter().enumerate() {
+            let (x, y) = BLOCK_POSITION[index];
+            let mode_of = |neighbour: Option<usize>, bx: usize, by: usize| -> Option<u8> {
+                let n = neighbour?;
+                let mb = &self.mbs[n];
+                if self.constrained_intra_pred && !mb.is_intra() {
+                    return None;
+                }
+                Some(if mb.kind == MbKind::Intra4x4 {
+                    mb.intra_modes[raster(bx, by)]
+                } else {
+                    2
+                })
+            };
+            let left = if x > 0 {
+                Some(info.intra_modes[raster(x - 1, y)])
+            } else {
+                mode_of(a, 3, y)
+            };
+            let top = if y > 0 {
+                Some(info.intra_modes[raster(x, y - 1)])
+            } else {
+                mode_of(b, x, 3)
+            };
+            let predicted = match (left, top) {
+                (Some(left), Some(top)) => left.min(top),
+                _ => 2,
+            };
+            let mode = match coded {
+                u8::MAX => predicted,
+                rem if rem < predicted => rem,
+                rem => rem + 1,
+            };
+            info.intra_modes[raster(x, y)] = mode;
+
+            let edges = self.luma_edges(addr, x * 4, y * 4, 4);
+            let mut prediction = [0u8; 16];
+            intra::predict_4x4(mode, &edges, &mut prediction)?;
+            let offset = (y0 + y * 4) * width + x0 + x * 4;
+            for row in 0..4 {
+                self.frame.luma[offset + row * width..offset + row * width + 4]
+                    .copy_from_slice(&prediction[row * 4..row * 4 + 4]);
+            }
+            let mut block = residual.luma[raster(x, y)];
+            if block.iter().any(|&c| c != 0) {
+                transform::dequantize(&mut block, info.qp, false);
+                transform::idct_add(&block, &mut self.frame.luma, offset, width);
+            }
+        }
+        Ok(())
+    }
+
+    fn reconstruct_intra16x16(
+        &mut self,
+        addr: usize,
+        mode: u8,
+        residual: &Residual,
+        qp: i32,
+    ) -> Result<()> {
+        let edges = self.luma_edges(addr, 0, 0, 16);
+        let mut prediction = [0u8; 256];
+        intra::predict_16x16(mode, &edges, &mut prediction)?;
+        let width = self.frame.width;
+        let (x0, y0) = self.mb_origin(addr);
+        for row in 0..16 {
+            let offset = (y0 + row) * width + x0;
+            self.frame.luma[offset..offset + 16]
+                .copy_from_slice(&prediction[row * 16..row * 16 + 16]);
+        }
+        self.add_luma_residual(addr, residual, qp, true);
+        Ok(())
+    }
+
+    /// Adds the luma residual of every block; Intra16x16 blocks take their
+    /// DC from the separately coded DC levels.
+    fn add_luma_residual(&mut self, addr: usize, residual: &Residual, qp: i32, intra16: bool) {
+        let mut dc = residual.luma_dc.unwrap_or([0; 16]);
+        if intra16 {
+            transform::luma_dc(&mut dc, qp);
+        }
+        let width = self.frame.width;
+        let (x0, y0) = self.mb_origin(addr);
+        for y in 0..4 {
+            for x in 0..4 {
+                let mut block = residual.luma[raster(x, y)];
+                transform::dequantize(&mut block, qp, intra16);
+                if intra16 {
+                    block[0] = dc[raster(x, y)];
+                }
+                if block.iter().all(|&c| c == 0) {
+                    continue;
+                }
+                let offset = (y0 + y * 4) * width + x0 + x * 4;
+                transform::idct_add(&block, &mut self.frame.luma, offset, width);
+            }
+        }
+    }
+
+    fn predict_chroma_intra(&mut self, addr: usize, mode: u8) -> Result<()> {
+        let a = self.intra_source(self.neighbour(addr, -1, 0));
+        let b = self.intra_source(self.neighbour(addr, 0, -1));
+        let d = self.intra_source(self.neighbour(addr, -1, -1));
+        let width = self.frame.width / 2;
+        let (x0, y0) = self.mb_origin(addr);
+        let (x0, y0) = (x0 / 2, y0 / 2);
+        for component in 0..2 {
+            let plane = self.frame.chroma_mut(component);
+            let mut edges = Edges {
+                top: [0; 16],
+                left: [0; 16],
+                top_left: 0,
+                has_top: b,
+                has_left: a,
+                has_top_left: d,
+            };
+            if b {
+                let row = (y0 - 1) * width;
+                edges.top[..8].copy_from_slice(&plane[row + x0..row + x0 + 8]);
+            }
+            if a {
+                for row in 0..8 {
+                    edges.left[row] = plane[(y0 + row) * width + x0 - 1];
+                }
+            }
+            if d {
+                edges.top_left = plane[(y0 - 1) * width + x0 - 1];
+            }
+            let mut prediction = [0u8; 64];
+            intra::predict_chroma(mode, &edges, &mut prediction)?;
+            for row in 0..8 {
+                let offset = (y0 + row) * width + x0;
+                plane[offset..offset + 8].copy_from_slice(&prediction[row * 8..row * 8 + 8]);
+            }
+        }
+        Ok(())
+    }
+
+    fn add_chroma_residual(&mut self, addr: usize, residual: &Residual, info: &MbInfo) {
+        let width = self.frame.width / 2;
+        let (x0, y0) = self.mb_origin(addr);
+        let (x0, y0) = (x0 / 2, y0 / 2);
+        for component in 0..2 {
+            let qp = info.chroma_qp[component];
+            let mut dc = residual.chroma_dc[component];
+            transform::chroma_dc(&mut dc, qp);
+            let plane = self.frame.chroma_mut(component);
+            for (index, &dc) in dc.iter().enumerate() {
+                let mut block = residual.chroma_ac[component][index];
+                transform::dequantize(&mut block, qp, true);
+                block[0] = dc;
+                if block.iter().all(|&c| c == 0) {
+                    continue;
+                }
+                let offset = (y0 + (index / 2) * 4) * width + x0 + (index % 2) * 4;
+                transform::idct_add(&block, plane, offset, width);
+            }
+        }
+    }
+
+    /// Motion data of the 4x4 block covering luma sample `x, y` relative to
+    /// the current macroblock, as `(refIdxLX, mvLX)` of `list`; `None` when
+    /// it is not available (outside the slice or not decoded yet). Intra
+    /// blocks, and blocks not predicted from `list`, are available with a
+    /// reference index of -1.
+    fn block_motion(
+        &self,
+        addr: usize,
+        info: &MbInfo,
+        filled: u16,
+        list: usize,
+        x: i32,
+        y: i32,
+    ) -> Option<(i32, [i32; 2])> {
+        let (dx, dy) = (
+            if x < 0 {
+                -1
+            } else if x >= 16 {
+                1
+            } else {
+                0
+            },
+            if y < 0 { -1 } else { 0 },
+        );
+        if dx == 1 && dy == 0 {
+            return None;
+        }
+        let block = raster(((x + 16) % 16) as usize / 4, ((y + 16) % 16) as usize / 4);
+        let mb = if dx == 0 && dy == 0 {
+            if filled & (1 << block) == 0 {
+                return None;
+            }
+            info
+        } else {
+            &self.mbs[self.neighbour(addr, dx, dy)?]
+        };
+        Some((mb.ref_idx[list][block], mb.mv[list][block]))
+    }
+
+    /// Motion vector prediction (8.4.1.3) in `list` for the partition at
+    /// block `x, y` that is `width` blocks wide.
+    #[allow(clippy::too_many_arguments)]
+    fn predict_mv(
+        &self,
+        addr: usize,
+        info: &MbInfo,
+        filled: u16,
+        list: usize,
+        x: usize,
+        y: usize,
+        width: usize,
+        ref_idx: i32,
+        shape: Shape,
+    ) -> [i32; 2] {
+        let (a, b, c) = self.neighbour_motion(addr, info, filled, list, x, y, width);
+        let unavailable = (-1, [0, 0]);
+        let (a, b, c) = match (a, b, c) {
+            (Some(a), None, None) => (a, a, a),
+            (a, b, c) => (
+                a.unwrap_or(unavailable),
+                b.unwrap_or(unavailable),
+                c.unwrap_or(unavailable),
+            ),
+        };
+        match shape {
+            Shape::Upper16x8 if b.0 == ref_idx => return b.1,
+            Shape::Lower16x8 if a.0 == ref_idx => return a.1,
+            Shape::Left8x16 if a.0 == ref_idx => return a.1,
+            Shape::Right8x16 if c.0 == ref_idx => return c.1,
+            _ => {}
+        }
+        let matches = [a, b, c].iter().filter(|n| n.0 == ref_idx).count();
+        if matches == 1 {
+            return [a, b, c].into_iter().find(|n| n.0 == ref_idx).unwrap().1;
+        }
+        let median = |p: i32, q: i32, r: i32| p.max(q).min(p.min(q).max(r));
+        [
+            median(a.1[0], b.1[0], c.1[0]),
+            median(a.1[1], b.1[1], c.1[1]),
+        ]
+    }
+
+    /// The motion in `list` of the neighbours left of (A), above (B) and
+    /// above-right of (C) a partition, with the above-left block (D)
+    /// standing in for C when it is unavailable.
+    #[allow(clippy::too_many_arguments, clippy::type_complexity)]
+    fn neighbour_motion(
+        &self,
+        addr: usize,
+        info: &MbInfo,
+        filled: u16,
+        list: usize,
+        x: usize,
+        y: usize,
+        width: usize,
+    ) -> (
+        Option<(i32, [i32; 2])>,
+        Option<(i32, [i32; 2])>,
+        Option<(i32, [i32; 2])>,
+    ) {
+        let (px, py) = (x as i32 * 4, y as i32 * 4);
+        let a = self.block_motion(addr, info, filled, list, px - 1, py);
+        let b = self.block_motion(addr, info, filled, list, px, py - 1);
+        let c = self
+            .block_motion(addr, info, filled, list, px + width as i32 * 4, py - 1)
+            .or_else(|| self.block_motion(addr, info, filled, list, px - 1, py - 1));
+        (a, b, c)
+    }
+
+    /// Direct prediction (8.4.1.2) of every block of the macroblock, for
+    /// B_Skip, B_Direct_16x16 and the B_Direct_8x8 sub-macroblocks, from
+    /// the co-located macroblock of the first list 1 reference. Blocks are
+    /// in raster order.
+    fn direct_motion(&self, addr: usize, info: &MbInfo) -> Result<[Motion; 16]> {
+        let colocated = self.ref_lists[1]
+            .first()
+            .and_then(Option::as_ref)
+            .ok_or_else(|| anyhow!("B slice has no list 1 reference for direct prediction"))?;
+        let col_mb = colocated
+            .frame
+            .mbs
+            .get(addr)
+            .ok_or_else(|| anyhow!("direct prediction's co-located picture has no motion"))?;
+        let mut motion = [Motion::default(); 16];
+        if self.header.direct_spatial_mv_pred {
+            // Each list takes the smallest reference index among the
+            // neighbours, predicted as for a 16x16 partition.
+            let mut ref_idx = [-1; 2];
+            let mut predicted = [[0; 2]; 2];
+            for list in 0..2 {
+                let (a, b, c) = self.neighbour_motion(addr, info, 0, list, 0, 0, 4);
+                ref_idx[list] = [a, b, c]
+                    .into_iter()
+                    .flatten()
+                    .map(|(ref_idx, _)| ref_idx)
+                    .filter(|&ref_idx| ref_idx >= 0)
+                    .min()
+                    .unwrap_or(-1);
+                if ref_idx[list] >= 0 {
+                    predicted[list] =
+                        self.predict_mv(addr, info, 0, list, 0, 0, 4, ref_idx[list], Shape::Median);
+                }
+            }
+            if ref_idx == [-1, -1] {
+                motion.fill(Motion {
+                    ref_idx: [0, 0],
+                    mv: [[0; 2]; 2],
+                });
+                return Ok(motion);
+            }
+            for (block, motion) in motion.iter_mut().enumerate() {
+                // A block whose co-located block barely moves from its
+                // nearest reference keeps still where it uses index 0.
+                let (mv_col, ref_col, _) = self.colocated_motion(col_mb, block);
+                let col_zero = !colocated.long_term
+                    && ref_col == 0
+                    && mv_col[0].abs() <= 1
+                    && mv_col[1].abs() <= 1;
+                motion.ref_idx = ref_idx;
+                for list in 0..2 {
+                    if ref_idx[list] > 0 || (ref_idx[list] == 0 && !col_zero) {
+                        motion.mv[list] = predicted[list];
+                    }
+                }
+            }
+        } else {
+            // The co-located motion, scaled by where this picture lies
+            // between its references.
+            for (block, motion) in motion.iter_mut().enumerate() {
+                let (mv_col, ref_col, ref_id) = self.colocated_motion(col_mb, block);
+                let ref_idx = if ref_col < 0 {
+                    0
+                } else {
+                    self.ref_lists[0]
+                        .iter()
+                        .position(|entry| {
+                            entry.as_ref().is_some_and(|entry| entry.frame.id == ref_id)
+                        })
+                        .ok_or_else(|| {
+                            anyhow!("temporal direct prediction refers to a picture not in list 0")
+                        })?
+                };
+                let Some(Some(reference)) = self.ref_lists[0].get(ref_idx) else {
+                    bail!("temporal direct prediction refers to missing reference {ref_idx}");
+                };
+                let (poc0, poc1) = (reference.frame.poc, colocated.frame.poc);
+                motion.ref_idx = [ref_idx as i32, 0];
+                motion.mv = if reference.long_term || poc0 == poc1 {
+                    [mv_col, [0, 0]]
+                } else {
+                    let scale = distance_scale(self.poc, poc0, poc1);
+                    let mv0 = mv_col.map(|component| (scale * component + 128) >> 8);
+                    [mv0, [mv0[0] - mv_col[0], mv0[1] - mv_col[1]]]
+                };
+            }
+        }
+        Ok(motion)
+    }
+
+    /// `mvCol`, `refIdxCol` and the `Frame::id` of the picture it refers to,
+    /// for a block of the co-located macroblock; intra blocks have no
+    /// motion and a reference index of -1.
+    fn colocated_motion(&self, col_mb: &MbInfo, block: usize) -> ([i32; 2], i32, u32) {
+        let block = if self.direct_8x8_inference {
+            // The outer corner block of the block's 8x8 quadrant.
+            let corner = |position: usize| if position < 2 { 0 } else { 3 };
+            raster(corner(block % 4), corner(block / 4))
+        } else {
+            block
+        };
+        if col_mb.is_intra() {
+            return ([0, 0], -1, 0);
+        }
+        let list = if col_mb.ref_idx[0][block] >= 0 { 0 } else { 1 };
+        (
+            col_mb.mv[list][block],
+            col_mb.ref_idx[list][block],
+            col_mb.ref_id[list][block],
+        )
+    }
+
+    /// Compensates one 8x8 quadrant of direct-predicted blocks, as a whole
+    /// when its four blocks move together.
+    fn compensate_direct(
+        &mut self,
+        addr: usize,
+        info: &mut MbInfo,
+        motion: &[Motion; 16],
+        quadrant: usize,
+    ) -> Result<()> {
+        let (x, y) = ((quadrant % 2) * 2, (quadrant / 2) * 2);
+        let first = motion[raster(x, y)];
+        let blocks = [(x, y), (x + 1, y), (x, y + 1), (x + 1, y + 1)];
+        if blocks.iter().all(|&(x, y)| motion[raster(x, y)] == first) {
+            return self.motion_compensate(addr, info, (x, y, 2, 2), first);
+        }
+        for (x, y) in blocks {
+            self.motion_compensate(addr, info, (x, y, 1, 1), motion[raster(x, y)])?;
+        }
+        Ok(())
+    }
+
+    /// The reference picture at `ref_idx` in `list`.
+    fn reference(&self, list: usize, ref_idx: i32) -> Result<&RefPicture> {
+        match self.ref_lists[list].get(ref_idx as usize) {
+            Some(Some(reference)) => Ok(reference),
+            _ => bail!("inter macroblock refers to missing list {list} reference {ref_idx}"),
+        }
+    }
+
+    /// How a block predicted with `motion` is weighted: luma and chroma
+    /// `log2_denom` and each list's weights, or `None` for the default
+    /// (no weighting, or averaging when bi-predicted).
+    fn weights(&self, motion: &Motion) -> Result<Option<(u32, u32, [Weights; 2])>> {
+        if let Some(table) = &self.header.weights {
+            let default = [
+                (1 << table.luma_log2_denom, 0),
+                (1 << table.chroma_log2_denom, 0),
+                (1 << table.chroma_log2_denom, 0),
+            ];
+            let explicit = [0, 1].map(|list| {
+                usize::try_from(motion.ref_idx[list])
+                    .ok()
+                    .and_then(|ref_idx| table.weights[list].get(ref_idx).copied().flatten())
+            });
+            if explicit.iter().all(Option::is_none) {
+                return Ok(None);
+            }
+            return Ok(Some((
+                table.luma_log2_denom,
+                table.chroma_log2_denom,
+                explicit.map(|weights| weights.unwrap_or(default)),
+            )));
+        }
+        if !self.header.implicit_weights || motion.ref_idx.iter().any(|&ref_idx| ref_idx < 0) {
+            return Ok(None);
+        }
+        // Implicit weights (8.4.2.3.1) split 64 by picture order distance.
+        let reference0 = self.reference(0, motion.ref_idx[0])?;
+        let reference1 = self.reference(1, motion.ref_idx[1])?;
+        let (poc0, poc1) = (reference0.frame.poc, reference1.frame.poc);
+        let mut weight1 = 32;
+        if !reference0.long_term && !reference1.long_term && poc0 != poc1 {
+            let scale = distance_scale(self.poc, poc0, poc1) >> 2;
+            if (-64..=128).contains(&scale) {
+                weight1 = scale;
+            }
+        }
+        let weights = |weight: i32| [(weight, 0); 3];
+        Ok(Some((5, 5, [weights(64 - weight1), weights(weight1)])))
+    }
+
+    /// Predicts the `(x, y, width, height)` area of the macroblock, in 4x4
+    /// blocks, from the references `motion` names and records the motion
+    /// in `info`.
+    fn motion_compensate(
+        &mut self,
+        addr: usize,
+        info: &mut MbInfo,
+        (x, y, width, height): (usize, usize, usize, usize),
+        motion: Motion,
+    ) -> Result<()> {
+        let mut references: [Option<Rc<Frame>>; 2] = [None, None];
+        for (list, reference) in references.iter_mut().enumerate() {
+            if motion.ref_idx[list] >= 0 {
+                *reference = Some(Rc::clone(
+                    &self.reference(list, motion.ref_idx[list])?.frame,
+                ));
+            }
+        }
+        for by in y..y + height {
+            for bx in x..x + width {
+                let block = raster(bx, by);
+                for (list, reference) in references.iter().enumerate() {
+                    info.mv[list][block] = motion.mv[list];
+                    info.ref_idx[list][block] = motion.ref_idx[list];
+                    info.ref_id[list][block] = reference.as_ref().map_or(0, |frame| frame.id);
+                }
+            }
+        }
+        let weights = self.weights(&motion)?;
+        let predictions: Vec<(usize, Rc<Frame>)> = references
+            .into_iter()
+            .enumerate()
+            .filter_map(|(list, reference)| Some((list, reference?)))
+            .collect();
+
+        let (x0, y0) = self.mb_origin(addr);
+        let (px, py) = (x0 + x * 4, y0 + y * 4);
+        let (width, height) = (width * 4, height * 4);
+        let mut buffers = [[0u8; 256]; 2];
+        for (buffer, (list, reference)) in buffers.iter_mut().zip(&predictions) {
+            inter::predict_luma(
+                &reference.luma_plane(),
+                px as i32,
+                py as i32,
+                motion.mv[*list],
+                width,
+                height,
+                &mut buffer[..width * height],
+            );
+        }
+        let samples = combine(
+            &mut buffers,
+            width * height,
+            &predictions,
+            weights.map(|(log2_denom, _, weights)| (log2_denom, weights.map(|w| w[0]))),
+        );
+        store(
+            &mut self.frame.luma,
+            self.frame.width,
+            px,
+            py,
+            width,
+            samples,
+        );
+
+        let (cx, cy) = (px / 2, py / 2);
+        let (chroma_width, chroma_height) = (width / 2, height / 2);
+        let stride = self.frame.width / 2;
+        for component in 0..2 {
+            let mut buffers = [[0u8; 256]; 2];
+            for (buffer, (list, reference)) in buffers.iter_mut().zip(&predictions) {
+                let plane: Plane<'_> = reference.chroma_plane(component);
+                inter::predict_chroma(
+                    &plane,
+                    cx as i32,
+                    cy as i32,
+                    motion.mv[*list],
+                    chroma_width,
+                    chroma_height,
+                    &mut buffer[..chroma_width * chroma_height],
+                );
+            }
+            let samples = combine(
+                &mut buffers,
+                chroma_width * chroma_height,
+                &predictions,
+                weights.map(|(_, log2_denom, weights)| {
+                    (log2_denom, weights.map(|w| w[1 + component]))
+                }),
+            );
+            store(
+                self.frame.chroma_mut(component),
+                stride,
+                cx,
+                cy,
+                chroma_width,
+                samples,
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Combines the one or two predictions of a block, the first `len` samples
+/// of each buffer, with the weights of their lists when there are any.
+fn combine<'a>(
+    buffers: &'a mut [[u8; 256]; 2],
+    len: usize,
+    predictions: &[(usize, Rc<Frame>)],
+    weights: Option<(u32, [(i32, i32); 2])>,
+) -> &'a [u8] {
+    let [first, second] = buffers;
+    let (first, second) = (&mut first[..len], &second[..len]);
+    match (predictions, weights) {
+        ([_, _], None) => inter::average(first, second),
+        ([_, _], Some((log2_denom, [weights0, weights1]))) => {
+            inter::weight_bi(first, second, log2_denom, weights0, weights1)
+        }
+        ([(list, _)], Some((log2_denom, weights))) => {
+            let (weight, offset) = weights[*list];
+            inter::weight(first, log2_denom, weight, offset);
+        }
+        _ => {}
+    }
+    first
+}
+
+/// `DistScaleFactor` (8.4.1.2.3): how far `poc` lies from `poc0` towards
+/// `poc1`, in 256ths.
+fn distance_scale(poc: i32, poc0: i32, poc1: i32) -> i32 {
+    let tb = (poc - poc0).clamp(-128, 127);
+    let td = (poc1 - poc0).clamp(-128, 127);
+    let tx = (16384 + (td / 2).abs()) / td;
+    ((tb * tx + 32) >> 6).clamp(-1024, 1023)
+}
+
+/// Writes a `width`-wide block of samples into a plane at `x, y`.
+fn store(plane: &mut [u8], stride: usize, x: usize, y: usize, width: usize, samples: &[u8]) {
+    for (row, chunk) in samples.chunks_exact(width).enumerate() {
+        let offset = (y + row) * stride + x;
+        plane[offset..offset + width].copy_from_slice(chunk);
+    }
+}
+
+/// The blocks of the current macroblock whose motion is already derived.
+fn filled_mask(info: &MbInfo) -> u16 {
+    (0..16)
+        .filter(|&block| info.ref_idx[0][block] >= 0 || info.ref_idx[1][block] >= 0)
+        .fold(0, |mask, block| mask | (1 << block))
+}
+
+/// `nC` from the available neighbours' `TotalCoeff` (9.2.1).
+pub(super) fn combine_nc(left: Option<u8>, top: Option<u8>) -> u32 {
+    match (left, top) {
+        (Some(left), Some(top)) => (u32::from(left) + u32::from(top) + 1) >> 1,
+        (Some(n), None) | (None, Some(n)) => u32::from(n),
+        (None, None) => 0,
+    }
+}
@@ -0,0 +1,686 @@
+//! H.264 (AVC) decoder and intra-only encoder.
+//!
+//! Annex B byte streams are split into NAL units and decoded into 4:2:0
+//! frames: CAVLC entropy decoding, intra prediction, P- and B-slice motion
+//! compensation with multiple and long-term references, spatial and
+//! temporal direct prediction, weighted prediction, and the in-loop
+//! deblocking filter. Streams using features beyond that (CABAC,
+//! interlacing, 8x8 transforms, FMO) are rejected with an error naming the
+//! feature. Pictures decode in decoding order and come out in display
+//! order, by picture order count within each coded video sequence. SEI
+//! messages supply the stream's HDR metadata and, through pic_timing's
+//! pic_struct, the frames displayed for more than one frame period.
+//!
+//! MP4 tracks are decoded from their samples with [`decode_samples`], or
+//! with [`decode_track`] as they are read from a streaming source; their
+//! frames keep the container's presentation times and come out in that
+//! order. [`probe_annex_b`] describes a
+//! stream from its headers alone.
+//!
+//! [`encode`] writes constrained-baseline streams of IDR pictures, which
+//! the decoder above (or any other) reads back.
+
+mod bits;
+mod bitstream;
+mod cavlc;
+mod deblock;
+mod dpb;
+mod encode;
+mod inter;
+mod intra;
+mod macroblock;
+mod params;
+mod picture;
+mod poc;
+mod sei;
+mod slice;
+mod transform;
+
+use std::collections::HashMap;
+use std::io::{Read, Seek};
+use std::rc::Rc;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail};
+
+use crate::video::container::{Mp4Demuxer, VideoSamples, VideoTrack};
+use crate::video::probe::{self, TrackDetails, TrackInfo};
+use crate::video::{
+    ColorSpace, FramePlanes, FrameRate, HdrMetadata, MediaStreams, PixelFormat, TransferFunction,
+    VideoCodec, VideoFrame, VideoStream,
+};
+
+use bits::{BitReader, unescape_rbsp};
+pub use bitstream::{
+    AvcConfig, annex_b_to_avcc, avcc_to_annex_b, split_annex_b, split_length_prefixed,
+};
+use dpb::Dpb;
+pub use encode::{EncodedFrame, EncodedStream, EncoderConfig, encode};
+use macroblock::SliceDecoder;
+use params::{Pps, Sps};
+use picture::{Frame, MbInfo};
+use poc::PocState;
+use slice::{SliceHeader, SliceType};
+
+const DEFAULT_FRAME_RATE: FrameRate = FrameRate::Constant {
+    numerator: 30,
+    denominator: 1,
+};
+
+/// Decodes an Annex B H.264 byte stream into `streams.video`.
+pub fn decode_annex_b(data: &[u8], streams: &mut MediaStreams) -> Result<()> {
+    let mut decoder = Decoder::default();
+    for nal in split_annex_b(data)? {
+        decoder.decode_nal(nal)?;
+    }
+    streams.video = Some(decoder.finish()?);
+    Ok(())
+}
+
+/// Describes an Annex B stream from its first SPS and its slice NAL units,
+/// without decoding them. Pictures are counted by their first slice, which
+/// starts at macroblock 0; IDR pictures are the keyframes.
+pub fn probe_annex_b(data: &[u8]) -> Result<TrackInfo> {
+    let mut sps = None;
+    let (mut pictures, mut keyframes) = (0u64, 0u64);
+    for nal in split_annex_b(data)? {
+        let nal_unit_type = nal[0] & 0x1F;
+        match nal_unit_type {
+            7 if sps.is_none() => {
+                let rbsp = unescape_rbsp(&nal[1..]);
+                sps = Some(Sps::parse(&mut BitReader::new(&rbsp)).context("failed to parse SPS")?);
+            }
+            // first_mb_in_slice 0 is ue(v) code `1`.
+            1 | 5 if nal.get(1).is_some_and(|byte| byte & 0x80 != 0) => {
+                pictures += 1;
+                keyframes += u64::from(nal_unit_type == 5);
+            }
+            _ => {}
+        }
+    }
+    let sps = sps.ok_or_else(|| anyhow!("H.264 stream has no SPS"))?;
+    let frame_rate = sps.frame_rate.unwrap_or(DEFAULT_FRAME_RATE);
+    let duration = frame_duration(frame_rate).mul_f64(pictures as f64);
+    let (frame_rate, variable_frame_rate) = probe::frame_rate(frame_rate, pictures, Some(duration));
+    Ok(TrackInfo::new(
+        0,
+        VideoCodec::H264.name(),
+        "",
+        (pictures, data.len() as u64),
+        Some(duration),
+        TrackDetails::Video {
+            width: sps.width(),
+            height: sps.height(),
+            frame_rate,
+            variable_frame_rate,
+            keyframe_count: keyframes,
+            rotation: 0,
+        },
+    ))
+}
+
+/// Decodes the samples of an MP4 H.264 track into `streams.video`. Each
+/// sample holds one access unit of NAL units prefixed with their length, and
+/// the parameter sets come from the track's `avcC`.
+pub fn decode_samples(track: &VideoSamples<'_>, streams: &mut MediaStreams) -> Result<()> {
+    let mut decoder = SampleDecoder::new(track.codec_config.as_deref(), track.frame_rate)?;
+    for (index, sample) in track.samples.iter().enumerate() {
+        decoder.decode(index, sample.data, (sample.timestamp, sample.duration))?;
+    }
+    streams.video = Some(decoder.decoder.finish()?);
+    Ok(())
+}
+
+/// Like [`decode_samples`], reading each sample from `demuxer`'s source
+/// only when the decoder gets to it, so one sample is held at a time.
+pub fn decode_track<R: Read + Seek>(
+    track: &VideoTrack,
+    demuxer: &mut Mp4Demuxer<R>,
+    streams: &mut MediaStreams,
+) -> Result<()> {
+    let mut decoder = SampleDecoder::new(track.codec_config.as_deref(), track.frame_rate)?;
+    for (index, sample) in track.samples.iter().enumerate() {
+        let data = demuxer.read_sample(sample)?;
+        decoder.decode(index, &data, (sample.timestamp, sample.duration))?;
+    }
+    streams.video = Some(decoder.decoder.finish()?);
+    Ok(())
+}
+
+/// A [`Decoder`] fed whole samples of length-prefixed NAL units.
+struct SampleDecoder {
+    decoder: Decoder,
+    nal_length_size: usize,
+}
+
+impl SampleDecoder {
+    /// Starts on the parameter sets of the track's `avcC` record.
+    fn new(config: Option<&[u8]>, frame_rate: FrameRate) -> Result<Self> {
+        let config = config.ok_or_else(|| anyhow!("H.264 track has no avcC configuration"))?;
+        let config = AvcConfig::parse(config)?;
+        let mut decoder = Decoder {
+            frame_rate: Some(frame_rate),
+            ..Decoder::default()
+        };
+        for nal in config.sps.iter().chain(&config.pps) {
+            decoder.decode_nal(nal)?;
+        }
+        Ok(Self {
+            decoder,
+            nal_length_size: usize::from(config.nal_length_size),
+        })
+    }
+
+    /// Decodes sample `index`, one access unit, with its presentation time
+    /// and duration.
+    fn decode(&mut self, index: usize, data: &[u8], timing: (Duration, Duration)) -> Result<()> {
+        self.decoder.sample_timing = Some(timing);
+        for nal in split_length_prefixed(data, self.nal_length_size)
+            .with_context(|| format!("sample {} is malformed", index + 1))?
+        {
+            self.decoder.decode_nal(nal)?;
+        }
+        self.decoder.finish_picture()
+    }
+}
+
+/// The picture whose slices are being decoded.
+struct CurrentPicture {
+    /// The first slice's header, compared against later slices to find
+    /// where the next picture starts.
+    header: SliceHeader,
+    sps: Sps,
+    /// Picture order count, as its slices are decoded against.
+    poc: i32,
+    frame: Frame,
+    mbs: Vec<MbInfo>,
+    slices: u32,
+}
+
+/// Turns NAL units into decoded frames.
+#[derive(Default)]
+struct Decoder {
+    sps: HashMap<u32, Sps>,
+    pps: HashMap<u32, Pps>,
+    current: Option<CurrentPicture>,
+    dpb: Dpb,
+    poc: PocState,
+    next_id: u32,
+    frame_rate: Option<FrameRate>,
+    /// From the first picture's SPS.
+    colour: Option<(ColorSpace, TransferFunction)>,
+    /// The SPS of the last slice, or the last one parsed before any slice,
+    /// which SEI messages are read against.
+    active_sps: Option<u32>,
+    /// From the SEI messages; the first of each kind is kept.
+    hdr: HdrMetadata,
+    /// The pic_struct of the pic_timing message for the next picture.
+    pic_struct: Option<u8>,
+    /// Timestamp and duration of the container sample being decoded. Annex B
+    /// streams have none and get their frames timed in `finish`.
+    sample_timing: Option<(Duration, Duration)>,
+    frames: Vec<VideoFrame>,
+    /// Field periods each frame is displayed for, from its pic_struct.
+    field_periods: Vec<u32>,
+    /// Where each frame is displayed: the coded video sequence it belongs
+    /// to, which each IDR picture or memory_management_control_operation 5
+    /// starts, and its picture order count within it.
+    display_order: Vec<(u32, i32)>,
+    /// The coded video sequence being decoded.
+    sequence: u32,
+}
+
+impl Decoder {
+    fn decode_nal(&mut self, nal: &[u8]) -> Result<()> {
+        let header = nal[0];
+        if header & 0x80 != 0 {
+            bail!("NAL unit has forbidden_zero_bit set");
+        }
+        let nal_ref_idc = (header >> 5) & 3;
+        let nal_unit_type = header & 0x1F;
+        let rbsp = unescape_rbsp(&nal[1..]);
+        let mut reader = BitReader::new(&rbsp);
+        match nal_unit_type {
+            1 | 5 => self.decode_slice(&mut reader, nal_unit_type, nal_ref_idc)?,
+            2..=4 => bail!("H.264 data partitioning is not supported"),
+            6 => {
+                // An SEI NAL unit starts the next access unit.
+                self.finish_picture()?;
+                self.read_sei(&rbsp);
+            }
+            7 => {
+                let sps = Sps::parse(&mut reader).context("failed to parse SPS")?;
+                if self.current.is_none() {
+                    self.active_sps = Some(sps.id);
+                }
+                self.sps.insert(sps.id, sps);
+            }
+            8 => {
+                let pps = Pps::parse(&mut reader).context("failed to parse PPS")?;
+                self.pps.insert(pps.id, pps);
+            }
+            // Access unit delimiter, end of sequence and end of stream all
+            // close the current picture.
+            9..=11 => self.finish_picture()?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Keeps the HDR metadata and picture structure of an SEI NAL unit.
+    /// SEI is advisory, so a malformed message is skipped with the rest of
+    /// its NAL unit.
+    fn read_sei(&mut self, rbsp: &[u8]) {
+        let layout = self
+            .active_sps
+            .and_then(|id| self.sps.get(&id))
+            .map(|sps| sps.pic_timing)
+            .unwrap_or_default();
+        match sei::parse(rbsp, layout) {
+            Ok(messages) => {
+                let hdr = &mut self.hdr;
+                hdr.mastering_display = hdr.mastering_display.or(messages.hdr.mastering_display);
+                hdr.content_light = hdr.content_light.or(messages.hdr.content_light);
+                self.pic_struct = messages.pic_struct.or(self.pic_struct);
+            }
+            Err(error) => tracing::debug!(%error, "skipping malformed SEI NAL unit"),
+        }
+    }
+
+    fn decode_slice(
+        &mut self,
+        reader: &mut BitReader<'_>,
+        nal_unit_type: u8,
+        nal_ref_idc: u8,
+    ) -> Result<()> {
+        let (first_mb, slice_type, pps_id) =
+            SliceHeader::parse_start(reader, nal_unit_type, nal_ref_idc)?;
+        let pps = self
+            .pps
+            .get(&pps_id)
+            .ok_or_else(|| anyhow!("slice refers to missing PPS {pps_id}"))?
+            .clone();
+        let sps = self
+            .sps
+            .get(&pps.sps_id)
+            .ok_or_else(|| anyhow!("PPS {pps_id} refers to missing SPS {}", pps.sps_id))?
+            .clone();
+        self.active_sps = Some(sps.id);
+        let header = SliceHeader::parse_rest(
+            reader,
+            nal_unit_type,
+            nal_ref_idc,
+            first_mb,
+            slice_type,
+            &sps,
+            &pps,
+        )?;
+        if header.redundant_pic_cnt > 0 {
+            // Primary pictures are always present in a complete stream.
+            return Ok(());
+        }
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|current| starts_new_picture(&current.header, &header))
+        {
+            self.finish_picture()?;
+        }
+        if self.current.is_none() {
+            if slice_type != SliceType::I && self.dpb.is_empty() {
+                // The stream was cut mid-GOP; wait for the next intra picture.
+                tracing::debug!(
+                    frame_num = header.frame_num,
+                    "skipping inter slice without references"
+                );
+                return Ok(());
+            }
+            self.frame_rate
+                .get_or_insert(sps.frame_rate.unwrap_or(DEFAULT_FRAME_RATE));
+            self.colour = self.colour.or(sps.colour);
+            let frame = Frame::new(self.next_id, sps.width_in_mbs, sps.height_in_mbs);
+            self.next_id += 1;
+            let total = (sps.width_in_mbs * sps.height_in_mbs) as usize;
+            let poc = self.poc.next(&header, &sps);
+            self.current = Some(CurrentPicture {
+                header: header.clone(),
+                sps: sps.clone(),
+                poc,
+                frame,
+                mbs: vec![MbInfo::default(); total],
+                slices: 0,
+            });
+        }
+
+        let current = self.current.as_mut().expect("current picture was just set");
+        let ref_lists = self
+            .dpb
+            .ref_lists(&header, sps.max_frame_num(), current.poc)?;
+        current.slices += 1;
+        let mut slice_decoder = SliceDecoder {
+            header: &header,
+            slice_num: current.slices,
+            width_in_mbs: sps.width_in_mbs as usize,
+            height_in_mbs: sps.height_in_mbs as usize,
+            constrained_intra_pred: pps.constrained_intra_pred,
+            chroma_qp_offsets: pps.chroma_qp_offsets,
+            direct_8x8_inference: sps.direct_8x8_inference,
+            ref_lists: &ref_lists,
+            poc: current.poc,
+            frame: &mut current.frame,
+            mbs: &mut current.mbs,
+        };
+        slice_decoder
+            .decode(reader)
+            .with_context(|| format!("failed to decode slice at macroblock {first_mb}"))
+    }
+
+    /// Deblocks the current picture, updates the reference pictures and
+    /// emits the cropped frame.
+    fn finish_picture(&mut self) -> Result<()> {
+        let Some(mut current) = self.current.take() else {
+            return Ok(());
+        };
+        let sps = &current.sps;
+        deblock::deblock(&mut current.frame, &current.mbs, sps.width_in_mbs as usize);
+        let (y, u, v) = current.frame.cropped(sps.crop);
+        let (timestamp, duration) = self.sample_timing.unwrap_or_default();
+        let header = &current.header;
+        if header.is_idr() || header.unmarks_all() {
+            self.sequence += 1;
+        }
+        // After memory_management_control_operation 5 the picture counts
+        // as the first of a new sequence.
+        current.frame.poc = if header.unmarks_all() { 0 } else { current.poc };
+        self.display_order.push((self.sequence, current.frame.poc));
+        if header.nal_ref_idc != 0 {
+            current.frame.mbs = current.mbs;
+        }
+        let frame = Rc::new(current.frame);
+        self.dpb.mark(
+            Rc::clone(&frame),
+            &current.header,
+            sps.max_frame_num(),
+            sps.max_num_ref_frames,
+        )?;
+        self.frames.push(VideoFrame {
+            width: sps.width(),
+            height: sps.height(),
+            pixel_format: PixelFormat::Yuv420,
+            data: FramePlanes::Yuv420 { y, u, v },
+            timestamp,
+            duration,
+            keyframe: current.header.is_idr(),
+        });
+        self.field_periods
+            .push(self.pic_struct.take().map_or(2, sei::field_periods));
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<VideoStream> {
+        self.finish_picture()?;
+        if self.frames.is_empty() {
+            bail!("no video frames decoded");
+        }
+        let mut frame_rate = self.frame_rate.unwrap_or(DEFAULT_FRAME_RATE);
+        if self.sample_timing.is_some() {
+            // Pictures come out in decoding order; their samples' times
+            // put them in presentation order.
+            self.frames.sort_by_key(|frame| frame.timestamp);
+        } else {
+            // Without container times, picture order counts give the
+            // display order of each coded video sequence.
+            let mut order: Vec<usize> = (0..self.frames.len()).collect();
+            order.sort_by_key(|&index| self.display_order[index]);
+            let mut frames: Vec<Option<VideoFrame>> = self.frames.drain(..).map(Some).collect();
+            self.frames = order
+                .iter()
+                .filter_map(|&index| frames[index].take())
+                .collect();
+            self.field_periods = order
+                .iter()
+                .map(|&index| self.field_periods[index])
+                .collect();
+
+            // A frame lasts two field periods unless its pic_struct repeats
+            // a field or the whole frame.
+            let duration = frame_duration(frame_rate);
+            let mut timestamp = Duration::ZERO;
+            for (frame, &fields) in self.frames.iter_mut().zip(&self.field_periods) {
+                frame.timestamp = timestamp;
+                frame.duration = duration * fields / 2;
+                timestamp += frame.duration;
+            }
+            if self.field_periods.iter().any(|&fields| fields != 2) {
+                frame_rate = FrameRate::Variable;
+            }
+        }
+        let (color_space, transfer) = self
+            .colour
+            .unwrap_or((ColorSpace::Bt709, TransferFunction::Sdr));
+        Ok(VideoStream {
+            codec: VideoCodec::H264,
+            frame_rate,
+            frames: self.frames,
+            color_space,
+            transfer,
+            rotation: 0,
+            hdr: self.hdr,
+        })
+    }
+}
+
+/// Whether `next` is the first slice of a new primary picture (7.4.1.2.4).
+fn starts_new_picture(current: &SliceHeader, next: &SliceHeader) -> bool {
+    current.frame_num != next.frame_num
+        || current.pps_id != next.pps_id
+        || (current.nal_ref_idc == 0) != (next.nal_ref_idc == 0)
+        || current.poc_lsb != next.poc_lsb
+        || current.delta_poc_bottom != next.delta_poc_bottom
+        || current.delta_poc != next.delta_poc
+        || current.is_idr() != next.is_idr()
+        || (current.is_idr() && current.idr_pic_id != next.idr_pic_id)
+        // A slice restarting at the top of the picture cannot belong to it.
+        || next.first_mb == 0
+}
+
+fn frame_duration(frame_rate: FrameRate) -> Duration {
+    match frame_rate {
+        FrameRate::Constant {
+            numerator,
+            denominator,
+        } if numerator > 0 => {
+            let seconds = denominator as f64 / numerator as f64;
+            Duration::from_secs_f64(seconds)
+        }
+        _ => Duration::from_secs_f64(1.0 / 30.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bits::{BitWriter, escape_rbsp};
+
+    /// Side of the 2x2-macroblock test pictures.
+    const SIZE: usize = 32;
+
+    fn nal(stream: &mut Vec<u8>, header: u8, rbsp: &[u8]) {
+        stream.extend([0, 0, 0, 1, header]);
+        stream.extend(escape_rbsp(rbsp));
+    }
+
+    /// Main-profile SPS with `pic_order_cnt_type` 0 and a 4-bit
+    /// `pic_order_cnt_lsb`.
+    fn sps() -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        writer.write_bits(77, 8); // profile_idc: main
+        writer.write_bits(0, 8);
+        writer.write_bits(30, 8);
+        writer.write_ue(0); // seq_parameter_set_id
+        writer.write_ue(0); // log2_max_frame_num_minus4
+        writer.write_ue(0); // pic_order_cnt_type
+        writer.write_ue(0); // log2_max_pic_order_cnt_lsb_minus4
+        writer.write_ue(2); // max_num_ref_frames
+        writer.write_flag(false);
+        writer.write_ue(1); // pic_width_in_mbs_minus1
+        writer.write_ue(1); // pic_height_in_map_units_minus1
+        writer.write_flag(true); // frame_mbs_only_flag
+        writer.write_flag(true); // direct_8x8_inference_flag
+        writer.write_flag(false);
+        writer.write_flag(false);
+        writer.finish()
+    }
+
+    fn pps(id: u32, weighted_bipred_idc: u32) -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        writer.write_ue(id);
+        writer.write_ue(0);
+        writer.write_flag(false); // CAVLC
+        writer.write_flag(false);
+        writer.write_ue(0);
+        writer.write_ue(0); // num_ref_idx_l0_default_active_minus1
+        writer.write_ue(0); // num_ref_idx_l1_default_active_minus1
+        writer.write_flag(false);
+        writer.write_bits(weighted_bipred_idc, 2);
+        writer.write_se(0);
+        writer.write_se(0);
+        writer.write_se(0);
+        writer.write_flag(true); // deblocking_filter_control_present_flag
+        writer.write_flag(false);
+        writer.write_flag(false);
+        writer.finish()
+    }
+
+    /// Writes the slice header fields from `slice_qp_delta` on, with the
+    /// deblocking filter off.
+    fn header_end(writer: &mut BitWriter) {
+        writer.write_se(0); // slice_qp_delta
+        writer.write_ue(1); // disable_deblocking_filter_idc
+    }
+
+    /// An intra picture of I_PCM macroblocks whose samples `sample` gives
+    /// by plane and position.
+    fn pcm_picture(
+        writer: &mut BitWriter,
+        mb_type: u32,
+        sample: impl Fn(usize, usize, usize) -> u8,
+    ) {
+        for addr in 0..4 {
+            let (x0, y0) = ((addr % 2) * 16, (addr / 2) * 16);
+            if mb_type != 25 {
+                writer.write_ue(0); // mb_skip_run
+            }
+            writer.write_ue(mb_type);
+            writer.align_zero();
+            for y in 0..16 {
+                for x in 0..16 {
+                    writer.write_bits(u32::from(sample(0, x0 + x, y0 + y)), 8);
+                }
+            }
+            for plane in 1..3 {
+                for y in 0..8 {
+                    for x in 0..8 {
+                        writer.write_bits(u32::from(sample(plane, x0 / 2 + x, y0 / 2 + y)), 8);
+                    }
+                }
+            }
+        }
+    }
+
+    fn first(plane: usize, x: usize, y: usize) -> u8 {
+        [(x * 4 + y) as u8, 60, 90][plane]
+    }
+
+    fn second(plane: usize, x: usize, y: usize) -> u8 {
+        [200 - (x * 2 + y) as u8, 160, 30][plane]
+    }
+
+    /// A non-reference B picture of B_Skip macroblocks.
+    fn skipped_b_picture(stream: &mut Vec<u8>, pps_id: u32, poc_lsb: u32, spatial: bool) {
+        let mut writer = BitWriter::default();
+        writer.write_ue(0); // first_mb_in_slice
+        writer.write_ue(6); // slice_type: B
+        writer.write_ue(pps_id);
+        writer.write_bits(2, 4); // frame_num
+        writer.write_bits(poc_lsb, 4);
+        writer.write_flag(spatial); // direct_spatial_mv_pred_flag
+        writer.write_flag(false); // num_ref_idx_active_override_flag
+        writer.write_flag(false); // ref_pic_list_modification_flag_l0
+        writer.write_flag(false); // ref_pic_list_modification_flag_l1
+        header_end(&mut writer);
+        writer.write_ue(4); // mb_skip_run
+        nal(stream, 0x01, &writer.finish());
+    }
+
+    #[test]
+    fn b_pictures_are_predicted_from_both_sides_and_output_in_display_order() {
+        let mut stream = Vec::new();
+        nal(&mut stream, 0x67, &sps());
+        nal(&mut stream, 0x68, &pps(0, 0));
+        nal(&mut stream, 0x68, &pps(1, 2));
+
+        // IDR picture, order count 0.
+        let mut writer = BitWriter::default();
+        writer.write_ue(0);
+        writer.write_ue(7); // slice_type: I
+        writer.write_ue(0);
+        writer.write_bits(0, 4); // frame_num
+        writer.write_ue(0); // idr_pic_id
+        writer.write_bits(0, 4); // pic_order_cnt_lsb
+        writer.write_flag(false); // no_output_of_prior_pics_flag
+        writer.write_flag(false); // long_term_reference_flag
+        header_end(&mut writer);
+        pcm_picture(&mut writer, 25, first);
+        nal(&mut stream, 0x65, &writer.finish());
+
+        // Reference P picture of intra macroblocks, order count 8.
+        let mut writer = BitWriter::default();
+        writer.write_ue(0);
+        writer.write_ue(5); // slice_type: P
+        writer.write_ue(0);
+        writer.write_bits(1, 4);
+        writer.write_bits(8, 4);
+        writer.write_flag(false);
+        writer.write_flag(false);
+        writer.write_flag(false); // adaptive_ref_pic_marking_mode_flag
+        header_end(&mut writer);
+        pcm_picture(&mut writer, 5 + 25, second);
+        nal(&mut stream, 0x41, &writer.finish());
+
+        // Between them: spatial direct with default weights at 4, then
+        // temporal direct with implicit weights at 2.
+        skipped_b_picture(&mut stream, 0, 4, true);
+        skipped_b_picture(&mut stream, 1, 2, false);
+
+        let mut streams = MediaStreams::default();
+        decode_annex_b(&stream, &mut streams).unwrap();
+        let video = streams.video.unwrap();
+        assert_eq!(video.frames.len(), 4);
+        let expected: [fn(u8, u8) -> u8; 4] = [
+            |a, _| a,
+            // Order count 2 sits a quarter of the way to the P picture.
+            |a, b| ((u32::from(a) * 48 + u32::from(b) * 16 + 32) >> 6) as u8,
+            |a, b| ((u32::from(a) + u32::from(b) + 1) >> 1) as u8,
+            |_, b| b,
+        ];
+        for (index, (frame, expected)) in video.frames.iter().zip(expected).enumerate() {
+            assert_eq!(
+                frame.timestamp,
+                frame_duration(video.frame_rate) * index as u32
+            );
+            assert_eq!(frame.keyframe, index == 0);
+            let FramePlanes::Yuv420 { y, u, v } = &frame.data else {
+                panic!("expected YUV 4:2:0 planes");
+            };
+            for (plane, samples) in [y, u, v].into_iter().enumerate() {
+                let width = if plane == 0 { SIZE } else { SIZE / 2 };
+                for (index, &sample) in samples.iter().enumerate() {
+                    let (x, y) = (index % width, index / width);
+                    let want = expected(first(plane, x, y), second(plane, x, y));
+                    assert_eq!(sample, want, "plane {plane} at {x},{y}");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,357 @@
+//! Sequence and picture parameter sets (7.3.2.1 and 7.3.2.2).
+
+use anyhow::{Result, bail};
+
+use super::bits::BitReader;
+use super::sei::PicTimingLayout;
+use crate::video::{ColorSpace, FrameRate, TransferFunction};
+
+/// Profiles whose SPS carries chroma format, bit depth and scaling lists.
+pub(super) const HIGH_PROFILES: [u32; 12] =
+    [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134];
+
+#[derive(Debug, Clone)]
+pub(super) struct Sps {
+    pub id: u32,
+    pub log2_max_frame_num: u32,
+    pub pic_order_cnt_type: u32,
+    pub log2_max_poc_lsb: u32,
+    pub delta_pic_order_always_zero: bool,
+    pub offset_for_non_ref_pic: i32,
+    pub offset_for_top_to_bottom_field: i32,
+    pub offsets_for_ref_frame: Vec<i32>,
+    pub max_num_ref_frames: u32,
+    pub width_in_mbs: u32,
+    pub height_in_mbs: u32,
+    /// Whether B slices' direct prediction takes the motion of each 8x8
+    /// quadrant's corner block of the co-located macroblock.
+    pub direct_8x8_inference: bool,
+    /// Luma samples cropped from the left, right, top and bottom edges.
+    pub crop: [u32; 4],
+    /// From the VUI timing info, when the stream signals it.
+    pub frame_rate: Option<FrameRate>,
+    /// From the VUI colour description, when the stream signals it.
+    pub colour: Option<(ColorSpace, TransferFunction)>,
+    /// How the pic_timing SEI messages of the sequence are laid out.
+    pub pic_timing: PicTimingLayout,
+}
+
+impl Sps {
+    pub fn parse(reader: &mut BitReader<'_>) -> Result<Self> {
+        let profile_idc = reader.read_bits(8)?;
+        let _constraint_flags = reader.read_bits(8)?;
+        let _level_idc = reader.read_bits(8)?;
+        let id = reader.read_ue()?;
+        if id > 31 {
+            bail!("SPS id {id} is out of range");
+        }
+        if HIGH_PROFILES.contains(&profile_idc) {
+            let chroma_format_idc = reader.read_ue()?;
+            if chroma_format_idc != 1 {
+                bail!("only 4:2:0 H.264 is supported (chroma_format_idc {chroma_format_idc})");
+            }
+            let bit_depth_luma = reader.read_ue()? + 8;
+            let bit_depth_chroma = reader.read_ue()? + 8;
+            if bit_depth_luma != 8 || bit_depth_chroma != 8 {
+                bail!("only 8-bit H.264 is supported ({bit_depth_luma}-bit luma)");
+            }
+            if reader.read_flag()? {
+                bail!("lossless (transform bypass) H.264 is not supported");
+            }
+            if reader.read_flag()? {
+                bail!("H.264 scaling matrices are not supported");
+            }
+        }
+        let log2_max_frame_num = reader.read_ue()? + 4;
+        if log2_max_frame_num > 16 {
+            bail!("log2_max_frame_num {log2_max_frame_num} is out of range");
+        }
+        let pic_order_cnt_type = reader.read_ue()?;
+        let mut sps = Self {
+            id,
+            log2_max_frame_num,
+            pic_order_cnt_type,
+            log2_max_poc_lsb: 0,
+            delta_pic_order_always_zero: false,
+            offset_for_non_ref_pic: 0,
+            offset_for_top_to_bottom_field: 0,
+            offsets_for_ref_frame: Vec::new(),
+            max_num_ref_frames: 0,
+            width_in_mbs: 0,
+            height_in_mbs: 0,
+            direct_8x8_inference: false,
+            crop: [0; 4],
+            frame_rate: None,
+            colour: None,
+            pic_timing: PicTimingLayout::default(),
+        };
+        match pic_order_cnt_type {
+            0 => {
+                sps.log2_max_poc_lsb = reader.read_ue()? + 4;
+                if sps.log2_max_poc_lsb > 16 {
+                    bail!("log2_max_pic_order_cnt_lsb is out of range");
+                }
+            }
+            1 => {
+                sps.delta_pic_order_always_zero = reader.read_flag()?;
+                sps.offset_for_non_ref_pic = reader.read_se()?;
+                sps.offset_for_top_to_bottom_field = reader.read_se()?;
+                let cycle = reader.read_ue()?;
+                if cycle > 255 {
+                    bail!("num_ref_frames_in_pic_order_cnt_cycle is out of range");
+                }
+                for _ in 0..cycle {
+                    sps.offsets_for_ref_frame.push(reader.read_se()?);
+                }
+            }
+            2 => {}
+            other => bail!("invalid pic_order_cnt_type {other}"),
+        }
+        sps.max_num_ref_frames = reader.read_ue()?;
+        if sps.max_num_ref_frames > 16 {
+            bail!(
+                "max_num_ref_frames {} is out of range",
+                sps.max_num_ref_frames
+            );
+        }
+        let _gaps_in_frame_num_allowed = reader.read_flag()?;
+        sps.width_in_mbs = reader.read_ue()? + 1;
+        let height_in_map_units = reader.read_ue()? + 1;
+        if !reader.read_flag()? {
+            bail!("interlaced (field or MBAFF) H.264 is not supported");
+        }
+        sps.height_in_mbs = height_in_map_units;
+        if sps.width_in_mbs > 1024 || sps.height_in_mbs > 1024 {
+            bail!(
+                "H.264 picture of {}x{} macroblocks is too large",
+                sps.width_in_mbs,
+                sps.height_in_mbs
+            );
+        }
+        sps.direct_8x8_inference = reader.read_flag()?;
+        if reader.read_flag()? {
+            // Crop units are two luma samples for 4:2:0 frames.
+            for edge in &mut sps.crop {
+                *edge = reader.read_ue()?.saturating_mul(2);
+            }
+            if sps.crop[0] + sps.crop[1] >= sps.width_in_mbs * 16
+                || sps.crop[2] + sps.crop[3] >= sps.height_in_mbs * 16
+            {
+                bail!("H.264 frame cropping removes the whole picture");
+            }
+        }
+        if reader.read_flag()? {
+            parse_vui(reader, &mut sps)?;
+        }
+        Ok(sps)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width_in_mbs * 16 - self.crop[0] - self.crop[1]
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height_in_mbs * 16 - self.crop[2] - self.crop[3]
+    }
+
+    pub fn max_frame_num(&self) -> u32 {
+        1 << self.log2_max_frame_num
+    }
+}
+
+/// Reads the VUI (E.1.1) up to its pic_struct_present_flag: the colour
+/// description, the timing info and the HRD delay lengths pic_timing
+/// messages depend on.
+fn parse_vui(reader: &mut BitReader<'_>, sps: &mut Sps) -> Result<()> {
+    if reader.read_flag()? {
+        // aspect_ratio_idc, with an explicit SAR for Extended_SAR.
+        if reader.read_bits(8)? == 255 {
+            reader.skip_bits(32)?;
+        }
+    }
+    if reader.read_flag()? {
+        let _overscan_appropriate = reader.read_flag()?;
+    }
+    if reader.read_flag()? {
+        // video_format, video_full_range_flag
+        reader.skip_bits(4)?;
+        if reader.read_flag()? {
+            let _colour_primaries = reader.read_bits(8)?;
+            let transfer_characteristics = reader.read_bits(8)?;
+            let matrix_coefficients = reader.read_bits(8)?;
+            sps.colour = Some(colour(transfer_characteristics, matrix_coefficients));
+        }
+    }
+    if reader.read_flag()? {
+        let _chroma_sample_loc_top = reader.read_ue()?;
+        let _chroma_sample_loc_bottom = reader.read_ue()?;
+    }
+    if reader.read_flag()? {
+        let num_units_in_tick = reader.read_bits(32)?;
+        let time_scale = reader.read_bits(32)?;
+        let fixed_frame_rate = reader.read_flag()?;
+        sps.frame_rate = frame_rate(num_units_in_tick, time_scale, fixed_frame_rate);
+    }
+    let nal_hrd = reader.read_flag()?;
+    let nal_delay_lengths = if nal_hrd {
+        Some(parse_hrd(reader)?)
+    } else {
+        None
+    };
+    let vcl_hrd = reader.read_flag()?;
+    let vcl_delay_lengths = if vcl_hrd {
+        Some(parse_hrd(reader)?)
+    } else {
+        None
+    };
+    if nal_hrd || vcl_hrd {
+        let _low_delay_hrd = reader.read_flag()?;
+    }
+    sps.pic_timing = PicTimingLayout {
+        delay_lengths: nal_delay_lengths.or(vcl_delay_lengths),
+        pic_struct_present: reader.read_flag()?,
+    };
+    Ok(())
+}
+
+/// The frame rate of the VUI timing info: a frame is two field ticks.
+fn frame_rate(num_units_in_tick: u32, time_scale: u32, fixed: bool) -> Option<FrameRate> {
+    if num_units_in_tick == 0 || time_scale == 0 {
+        return None;
+    }
+    if !fixed {
+        return Some(FrameRate::Variable);
+    }
+    let denominator = u64::from(num_units_in_tick) * 2;
+    let divisor = gcd(u64::from(time_scale), denominator);
+    Some(FrameRate::Constant {
+        numerator: (u64::from(time_scale) / divisor) as u32,
+        denominator: (denominator / divisor) as u32,
+    })
+}
+
+/// The matrix and transfer function of a colour description's
+/// `transfer_characteristics` and `matrix_coefficients` (Tables E-4 and
+/// E-5); unknown matrices are taken as BT.709.
+fn colour(
+    transfer_characteristics: u32,
+    matrix_coefficients: u32,
+) -> (ColorSpace, TransferFunction) {
+    let color_space = match matrix_coefficients {
+        5 | 6 => ColorSpace::Bt601,
+        9 | 10 => ColorSpace::Bt2020,
+        _ => ColorSpace::Bt709,
+    };
+    let transfer = match transfer_characteristics {
+        16 => TransferFunction::Pq,
+        18 => TransferFunction::Hlg,
+        _ => TransferFunction::Sdr,
+    };
+    (color_space, transfer)
+}
+
+/// Skips `hrd_parameters()` (E.1.2), returning the bit lengths of
+/// `cpb_removal_delay` and `dpb_output_delay`.
+fn parse_hrd(reader: &mut BitReader<'_>) -> Result<(u32, u32)> {
+    let cpb_count = reader.read_ue()? + 1;
+    if cpb_count > 32 {
+        bail!("HRD cpb_cnt_minus1 is out of range");
+    }
+    // bit_rate_scale, cpb_size_scale
+    reader.skip_bits(8)?;
+    for _ in 0..cpb_count {
+        let _bit_rate_value = reader.read_ue()?;
+        let _cpb_size_value = reader.read_ue()?;
+        let _cbr = reader.read_flag()?;
+    }
+    let _initial_cpb_removal_delay_length = reader.read_bits(5)?;
+    let cpb_removal_delay_length = reader.read_bits(5)? + 1;
+    let dpb_output_delay_length = reader.read_bits(5)? + 1;
+    let _time_offset_length = reader.read_bits(5)?;
+    Ok((cpb_removal_delay_length, dpb_output_delay_length))
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct Pps {
+    pub id: u32,
+    pub sps_id: u32,
+    pub bottom_field_pic_order_in_frame_present: bool,
+    /// `num_ref_idx_l0_default_active` and `num_ref_idx_l1_default_active`.
+    pub num_ref_idx_default_active: [u32; 2],
+    pub weighted_pred: bool,
+    /// 0 for default, 1 for explicit and 2 for implicit weighting of B
+    /// slices.
+    pub weighted_bipred_idc: u32,
+    pub pic_init_qp: i32,
+    /// `chroma_qp_index_offset` for Cb and Cr.
+    pub chroma_qp_offsets: [i32; 2],
+    pub deblocking_filter_control_present: bool,
+    pub constrained_intra_pred: bool,
+    pub redundant_pic_cnt_present: bool,
+}
+
+impl Pps {
+    pub fn parse(reader: &mut BitReader<'_>) -> Result<Self> {
+        let id = reader.read_ue()?;
+        if id > 255 {
+            bail!("PPS id {id} is out of range");
+        }
+        let sps_id = reader.read_ue()?;
+        if reader.read_flag()? {
+            bail!("CABAC H.264 streams are not supported; only CAVLC decodes");
+        }
+        let bottom_field_pic_order_in_frame_present = reader.read_flag()?;
+        let slice_groups = reader.read_ue()? + 1;
+        if slice_groups > 1 {
+            bail!("H.264 slice groups (FMO) are not supported");
+        }
+        let num_ref_idx_default_active = [reader.read_ue()? + 1, reader.read_ue()? + 1];
+        if num_ref_idx_default_active.iter().any(|&active| active > 32) {
+            bail!("num_ref_idx_default_active is out of range");
+        }
+        let weighted_pred = reader.read_flag()?;
+        let weighted_bipred_idc = reader.read_bits(2)?;
+        if weighted_bipred_idc > 2 {
+            bail!("invalid weighted_bipred_idc {weighted_bipred_idc}");
+        }
+        let pic_init_qp = 26 + reader.read_se()?;
+        let _pic_init_qs = 26 + reader.read_se()?;
+        let chroma_qp_index_offset = reader.read_se()?;
+        if !(0..=51).contains(&pic_init_qp) || !(-12..=12).contains(&chroma_qp_index_offset) {
+            bail!("PPS quantizer parameters are out of range");
+        }
+        let mut pps = Self {
+            id,
+            sps_id,
+            bottom_field_pic_order_in_frame_present,
+            num_ref_idx_default_active,
+            weighted_pred,
+            weighted_bipred_idc,
+            pic_init_qp,
+            chroma_qp_offsets: [chroma_qp_index_offset; 2],
+            deblocking_filter_control_present: reader.read_flag()?,
+            constrained_intra_pred: reader.read_flag()?,
+            redundant_pic_cnt_present: reader.read_flag()?,
+        };
+        if reader.more_rbsp_data() {
+            if reader.read_flag()? {
+                bail!("H.264 8x8 transforms (High profile) are not supported");
+            }
+            if reader.read_flag()? {
+                bail!("H.264 scaling matrices are not supported");
+            }
+            pps.chroma_qp_offsets[1] = reader.read_se()?;
+            if !(-12..=12).contains(&pps.chroma_qp_offsets[1]) {
+                bail!("PPS second_chroma_qp_index_offset is out of range");
+            }
+        }
+        Ok(pps)
+    }
+}
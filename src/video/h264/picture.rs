@@ -0,0 +1,145 @@
+//! Decoded pictures and the per-macroblock state kept while decoding one.
+
+use super::inter::Plane;
+
+/// A decoded picture at its full macroblock-aligned size.
+#[derive(Debug, Clone)]
+pub(super) struct Frame {
+    /// Identifies the picture for reference comparisons in deblocking.
+    pub id: u32,
+    pub width: usize,
+    pub height: usize,
+    pub luma: Vec<u8>,
+    pub cb: Vec<u8>,
+    pub cr: Vec<u8>,
+    /// Picture order count, which orders B slices' references.
+    pub poc: i32,
+    /// The decoded macroblocks of a reference picture, whose motion B
+    /// slices' direct prediction takes over; empty otherwise.
+    pub mbs: Vec<MbInfo>,
+}
+
+impl Frame {
+    pub fn new(id: u32, width_in_mbs: u32, height_in_mbs: u32) -> Self {
+        let width = width_in_mbs as usize * 16;
+        let height = height_in_mbs as usize * 16;
+        Self {
+            id,
+            width,
+            height,
+            luma: vec![0; width * height],
+            cb: vec![128; width * height / 4],
+            cr: vec![128; width * height / 4],
+            poc: 0,
+            mbs: Vec::new(),
+        }
+    }
+
+    pub fn luma_plane(&self) -> Plane<'_> {
+        Plane {
+            data: &self.luma,
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    /// Cb (`0`) or Cr (`1`).
+    pub fn chroma_plane(&self, component: usize) -> Plane<'_> {
+        Plane {
+            data: if component == 0 { &self.cb } else { &self.cr },
+            width: self.width / 2,
+            height: self.height / 2,
+        }
+    }
+
+    pub fn chroma_mut(&mut self, component: usize) -> &mut Vec<u8> {
+        if component == 0 {
+            &mut self.cb
+        } else {
+            &mut self.cr
+        }
+    }
+
+    /// Copies out the visible area after cropping `[left, right, top,
+    /// bottom]` luma samples.
+    pub fn cropped(&self, crop: [u32; 4]) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let [left, right, top, bottom] = crop.map(|edge| edge as usize);
+        let copy = |plane: &[u8], stride: usize, rows: usize, scale: usize| {
+            let (x0, x1) = (left / scale, stride - right / scale);
+            let (y0, y1) = (top / scale, rows - bottom / scale);
+            let mut out = Vec::with_capacity((x1 - x0) * (y1 - y0));
+            for row in y0..y1 {
+                out.extend_from_slice(&plane[row * stride + x0..row * stride + x1]);
+            }
+            out
+        };
+        (
+            copy(&self.luma, self.width, self.height, 1),
+            copy(&self.cb, self.width / 2, self.height / 2, 2),
+            copy(&self.cr, self.width / 2, self.height / 2, 2),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum MbKind {
+    Intra4x4,
+    Intra16x16,
+    Pcm,
+    Inter,
+}
+
+/// What neighbouring macroblocks and the deblocking filter need to know
+/// about a decoded macroblock. Per-block arrays are in raster order.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct MbInfo {
+    /// The slice the macroblock belongs to, counted from one; zero until it
+    /// is decoded.
+    pub slice: u32,
+    pub kind: MbKind,
+    /// `QPY`, which is zero for I_PCM macroblocks.
+    pub qp: i32,
+    /// `QPc` for Cb and Cr.
+    pub chroma_qp: [i32; 2],
+    pub intra_modes: [u8; 16],
+    /// `TotalCoeff` of each luma 4x4 block, for CAVLC `nC`.
+    pub luma_coeffs: [u8; 16],
+    /// `TotalCoeff` of each chroma AC block, Cb then Cr.
+    pub chroma_coeffs: [[u8; 4]; 2],
+    /// Motion vectors of each block in list 0 and list 1.
+    pub mv: [[[i32; 2]; 16]; 2],
+    /// `refIdxL0` and `refIdxL1` of each block, negative for intra blocks
+    /// and for lists a block does not predict from.
+    pub ref_idx: [[i32; 16]; 2],
+    /// `Frame::id` of each block's reference picture in either list.
+    pub ref_id: [[u32; 16]; 2],
+    pub disable_deblocking_filter_idc: u32,
+    pub alpha_offset: i32,
+    pub beta_offset: i32,
+}
+
+impl Default for MbInfo {
+    fn default() -> Self {
+        Self {
+            slice: 0,
+            kind: MbKind::Inter,
+            qp: 0,
+            chroma_qp: [0; 2],
+            intra_modes: [2; 16],
+            luma_coeffs: [0; 16],
+            chroma_coeffs: [[0; 4]; 2],
+            mv: [[[0; 2]; 16]; 2],
+            ref_idx: [[-1; 16]; 2],
+            ref_id: [[0; 16]; 2],
+            disable_deblocking_filter_idc: 0,
+            alpha_offset: 0,
+            beta_offset: 0,
+        }
+    }
+}
+
+impl MbInfo {
+    pub fn is_intra(&self) -> bool {
+        self.kind != MbKind::Inter
+    }
+}
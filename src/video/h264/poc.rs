@@ -0,0 +1,111 @@
+//! Picture order counts (8.2.1): the display order of the pictures of a
+//! coded video sequence, which B slices decode out of.
+
+use super::params::Sps;
+use super::slice::SliceHeader;
+
+/// What the picture order count of the next picture depends on.
+#[derive(Debug, Default)]
+pub(super) struct PocState {
+    /// `prevPicOrderCntMsb` and `prevPicOrderCntLsb`: the counts of the
+    /// last reference picture, for `pic_order_cnt_type` 0.
+    prev_msb: i32,
+    prev_lsb: i32,
+    /// `prevFrameNumOffset` and the `frame_num` of the last picture, for
+    /// types 1 and 2.
+    prev_frame_num_offset: i32,
+    prev_frame_num: u32,
+}
+
+impl PocState {
+    /// The picture order count of the frame `header` starts, the smaller of
+    /// its fields' counts. Called once per picture, in decoding order.
+    pub fn next(&mut self, header: &SliceHeader, sps: &Sps) -> i32 {
+        let unmarks_all = header.unmarks_all();
+        let (top, bottom) = match sps.pic_order_cnt_type {
+            0 => {
+                if header.is_idr() {
+                    (self.prev_msb, self.prev_lsb) = (0, 0);
+                }
+                let max = 1i32 << sps.log2_max_poc_lsb;
+                let lsb = header.poc_lsb as i32;
+                let msb = if lsb < self.prev_lsb && self.prev_lsb - lsb >= max / 2 {
+                    self.prev_msb + max
+                } else if lsb > self.prev_lsb && lsb - self.prev_lsb > max / 2 {
+                    self.prev_msb - max
+                } else {
+                    self.prev_msb
+                };
+                let top = msb + lsb;
+                let bottom = top + header.delta_poc_bottom;
+                if header.nal_ref_idc != 0 {
+                    // After memory_management_control_operation 5 the
+                    // picture counts from zero (8.2.1).
+                    (self.prev_msb, self.prev_lsb) = if unmarks_all {
+                        (0, top - top.min(bottom))
+                    } else {
+                        (msb, lsb)
+                    };
+                }
+                (top, bottom)
+            }
+            pic_order_cnt_type => {
+                let offset = self.frame_num_offset(header, sps);
+                let count = if pic_order_cnt_type == 1 {
+                    let (top, bottom) = Self::type1(header, sps, offset);
+                    top.min(bottom)
+                } else if header.is_idr() {
+                    0
+                } else {
+                    2 * (offset + header.frame_num as i32) - i32::from(header.nal_ref_idc == 0)
+                };
+                (self.prev_frame_num_offset, self.prev_frame_num) = if unmarks_all {
+                    (0, 0)
+                } else {
+                    (offset, header.frame_num)
+                };
+                (count, count)
+            }
+        };
+        top.min(bottom)
+    }
+
+    /// `FrameNumOffset`, which grows by `MaxFrameNum` each time `frame_num`
+    /// wraps.
+    fn frame_num_offset(&self, header: &SliceHeader, sps: &Sps) -> i32 {
+        if header.is_idr() {
+            0
+        } else if self.prev_frame_num > header.frame_num {
+            self.prev_frame_num_offset + sps.max_frame_num() as i32
+        } else {
+            self.prev_frame_num_offset
+        }
+    }
+
+    /// Top and bottom field counts for `pic_order_cnt_type` 1, which
+    /// cycles through the SPS's expected deltas.
+    fn type1(header: &SliceHeader, sps: &Sps, frame_num_offset: i32) -> (i32, i32) {
+        let cycle = &sps.offsets_for_ref_frame;
+        let mut abs_frame_num = if cycle.is_empty() {
+            0
+        } else {
+            frame_num_offset + header.frame_num as i32
+        };
+        if header.nal_ref_idc == 0 && abs_frame_num > 0 {
+            abs_frame_num -= 1;
+        }
+        let mut expected = 0;
+        if abs_frame_num > 0 {
+            let cycle_count = (abs_frame_num - 1) / cycle.len() as i32;
+            let in_cycle = (abs_frame_num - 1) as usize % cycle.len();
+            let delta_per_cycle: i32 = cycle.iter().sum();
+            expected = cycle_count * delta_per_cycle + cycle[..=in_cycle].iter().sum::<i32>();
+        }
+        if header.nal_ref_idc == 0 {
+            expected += sps.offset_for_non_ref_pic;
+        }
+        let top = expected + header.delta_poc[0];
+        let bottom = top + sps.offset_for_top_to_bottom_field + header.delta_poc[1];
+        (top, bottom)
+    }
+}
@@ -0,0 +1,200 @@
+//! Supplemental enhancement information (7.3.2.3 and Annex D): picture
+//! timing, and the mastering display colour volume and content light level
+//! messages that carry static HDR metadata.
+
+use anyhow::{Result, bail};
+
+use super::bits::BitReader;
+use crate::video::{ContentLightLevel, HdrMetadata, MasteringDisplay};
+
+const PIC_TIMING: u32 = 1;
+const MASTERING_DISPLAY_COLOUR_VOLUME: u32 = 137;
+const CONTENT_LIGHT_LEVEL_INFO: u32 = 144;
+
+/// What the active SPS's VUI says a pic_timing message holds.
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct PicTimingLayout {
+    /// Bit lengths of `cpb_removal_delay` and `dpb_output_delay`, present
+    /// when the VUI has HRD parameters.
+    pub delay_lengths: Option<(u32, u32)>,
+    pub pic_struct_present: bool,
+}
+
+/// The messages of one SEI NAL unit that the decoder uses; the others are
+/// skipped.
+#[derive(Debug, Default)]
+pub(super) struct SeiMessages {
+    /// From pic_timing: how the picture is displayed (Table D-1).
+    pub pic_struct: Option<u8>,
+    pub hdr: HdrMetadata,
+}
+
+/// Parses the messages of an SEI RBSP, reading pic_timing as `layout`
+/// describes it.
+pub(super) fn parse(rbsp: &[u8], layout: PicTimingLayout) -> Result<SeiMessages> {
+    let mut messages = SeiMessages::default();
+    let mut rest = rbsp;
+    while more_messages(rest) {
+        let payload_type = read_ff_coded(&mut rest)?;
+        let payload_size = read_ff_coded(&mut rest)? as usize;
+        if payload_size > rest.len() {
+            bail!("SEI message of type {payload_type} is truncated");
+        }
+        let (payload, tail) = rest.split_at(payload_size);
+        rest = tail;
+        match payload_type {
+            PIC_TIMING => messages.pic_struct = pic_struct(payload, layout)?,
+            MASTERING_DISPLAY_COLOUR_VOLUME => {
+                let Some(display) = MasteringDisplay::from_bytes(payload) else {
+                    bail!("mastering display colour volume SEI message is truncated");
+                };
+                messages.hdr.mastering_display = Some(display);
+            }
+            CONTENT_LIGHT_LEVEL_INFO => {
+                let Some(light) = ContentLightLevel::from_bytes(payload) else {
+                    bail!("content light level SEI message is truncated");
+                };
+                messages.hdr.content_light = Some(light);
+            }
+            _ => {}
+        }
+    }
+    Ok(messages)
+}
+
+/// The SEI RBSP carrying `hdr`'s mastering display colour volume and
+/// content light level messages, or `None` when it has neither.
+pub(super) fn write_hdr(hdr: &HdrMetadata) -> Option<Vec<u8>> {
+    if hdr.is_empty() {
+        return None;
+    }
+    let mut rbsp = Vec::new();
+    if let Some(display) = &hdr.mastering_display {
+        rbsp.extend([MASTERING_DISPLAY_COLOUR_VOLUME as u8, 24]);
+        rbsp.extend(display.to_bytes());
+    }
+    if let Some(light) = &hdr.content_light {
+        rbsp.extend([CONTENT_LIGHT_LEVEL_INFO as u8, 4]);
+        rbsp.extend(light.to_bytes());
+    }
+    // rbsp_trailing_bits
+    rbsp.push(0x80);
+    Some(rbsp)
+}
+
+/// Whether anything but the trailing bits is left of the RBSP.
+fn more_messages(rest: &[u8]) -> bool {
+    match rest.iter().rposition(|&byte| byte != 0) {
+        Some(0) => rest[0] != 0x80,
+        Some(_) => true,
+        None => false,
+    }
+}
+
+/// A payload type or size: the sum of its 0xFF bytes and the byte ending
+/// them.
+fn read_ff_coded(rest: &mut &[u8]) -> Result<u32> {
+    let mut value = 0u32;
+    loop {
+        let Some((&byte, tail)) = rest.split_first() else {
+            bail!("SEI message header is truncated");
+        };
+        *rest = tail;
+        value = value.saturating_add(u32::from(byte));
+        if byte != 0xFF {
+            return Ok(value);
+        }
+    }
+}
+
+/// The pic_struct of a pic_timing message (D.1.3), when the SPS says it
+/// is there.
+fn pic_struct(payload: &[u8], layout: PicTimingLayout) -> Result<Option<u8>> {
+    if !layout.pic_struct_present {
+        return Ok(None);
+    }
+    let mut reader = BitReader::new(payload);
+    if let Some((cpb_removal_delay, dpb_output_delay)) = layout.delay_lengths {
+        reader.skip_bits((cpb_removal_delay + dpb_output_delay) as usize)?;
+    }
+    let pic_struct = reader.read_bits(4)? as u8;
+    if pic_struct > 8 {
+        bail!("reserved pic_struct {pic_struct}");
+    }
+    Ok(Some(pic_struct))
+}
+
+/// How many field periods a picture is displayed for, by its pic_struct
+/// (`DeltaTfiDivisor`, Table E-6): two for a frame, three when a field is
+/// repeated, four or six when the frame is doubled or tripled.
+pub(super) fn field_periods(pic_struct: u8) -> u32 {
+    match pic_struct {
+        1 | 2 => 1,
+        5 | 6 => 3,
+        7 => 4,
+        8 => 6,
+        _ => 2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hdr10() -> HdrMetadata {
+        HdrMetadata {
+            mastering_display: Some(MasteringDisplay {
+                // BT.2020 primaries and D65, mastered from 0.005 to 1000 nits.
+                primaries: [[35400, 14600], [8500, 39850], [6550, 2300]],
+                white_point: [15635, 16450],
+                max_luminance: 10_000_000,
+                min_luminance: 50,
+            }),
+            content_light: Some(ContentLightLevel {
+                max_cll: 1000,
+                max_fall: 400,
+            }),
+        }
+    }
+
+    #[test]
+    fn hdr_messages_read_back() {
+        let hdr = hdr10();
+        let rbsp = write_hdr(&hdr).unwrap();
+        // Green comes first in the message.
+        assert_eq!(&rbsp[2..6], &[0x21, 0x34, 0x9B, 0xAA]);
+        let messages = parse(&rbsp, PicTimingLayout::default()).unwrap();
+        assert_eq!(messages.hdr, hdr);
+        assert_eq!(messages.pic_struct, None);
+        assert_eq!(hdr.mastering_display.unwrap().max_nits(), 1000.0);
+
+        assert!(write_hdr(&HdrMetadata::default()).is_none());
+        let mut truncated = rbsp.clone();
+        truncated[1] = 40;
+        assert!(parse(&truncated, PicTimingLayout::default()).is_err());
+    }
+
+    #[test]
+    fn pic_timing_gives_the_picture_structure() {
+        // A 300-byte user data message (type 5), then pic_timing with
+        // 8-bit delays and pic_struct 5 (top, bottom, top repeated).
+        let mut rbsp = vec![5, 0xFF, 45];
+        rbsp.extend([0; 300]);
+        rbsp.extend([1, 3, 0x12, 0x34, 0x50, 0x80]);
+        let layout = PicTimingLayout {
+            delay_lengths: Some((8, 8)),
+            pic_struct_present: true,
+        };
+        let messages = parse(&rbsp, layout).unwrap();
+        assert_eq!(messages.pic_struct, Some(5));
+        assert!(messages.hdr.is_empty());
+        assert_eq!(field_periods(5), 3);
+        assert_eq!(field_periods(0), 2);
+
+        let layout = PicTimingLayout {
+            pic_struct_present: false,
+            ..layout
+        };
+        assert_eq!(parse(&rbsp, layout).unwrap().pic_struct, None);
+    }
+}
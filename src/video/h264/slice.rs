@@ -0,0 +1,339 @@
+//! Slice headers (7.3.3) and the reference list and marking syntax they
+//! carry.
+
+use anyhow::{Result, bail};
+
+use super::bits::BitReader;
+use super::params::{Pps, Sps};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum SliceType {
+    P,
+    B,
+    I,
+}
+
+/// One `modification_of_pic_nums_idc` entry of `ref_pic_list_modification`.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum ListModification {
+    ShortTermSubtract(u32),
+    ShortTermAdd(u32),
+    LongTerm(u32),
+}
+
+/// A memory management control operation from `dec_ref_pic_marking`.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Mmco {
+    UnmarkShortTerm {
+        difference_of_pic_nums: u32,
+    },
+    UnmarkLongTerm {
+        long_term_pic_num: u32,
+    },
+    ShortTermToLongTerm {
+        difference_of_pic_nums: u32,
+        long_term_frame_idx: u32,
+    },
+    MaxLongTermIndex {
+        max_long_term_frame_idx_plus1: u32,
+    },
+    UnmarkAll,
+    CurrentToLongTerm {
+        long_term_frame_idx: u32,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+pub(super) enum RefPicMarking {
+    /// Not a reference picture (`nal_ref_idc` is zero).
+    #[default]
+    None,
+    Idr {
+        long_term: bool,
+    },
+    SlidingWindow,
+    Adaptive(Vec<Mmco>),
+}
+
+/// Explicit weights for one reference: luma then Cb and Cr, each
+/// `(weight, offset)`.
+pub(super) type Weights = [(i32, i32); 3];
+
+#[derive(Debug, Clone)]
+pub(super) struct PredWeightTable {
+    pub luma_log2_denom: u32,
+    pub chroma_log2_denom: u32,
+    /// One entry per active reference of each list; `None` means default
+    /// weighting.
+    pub weights: [Vec<Option<Weights>>; 2],
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct SliceHeader {
+    pub nal_unit_type: u8,
+    pub nal_ref_idc: u8,
+    pub first_mb: u32,
+    pub slice_type: SliceType,
+    pub pps_id: u32,
+    pub frame_num: u32,
+    pub idr_pic_id: u32,
+    pub poc_lsb: u32,
+    pub delta_poc_bottom: i32,
+    pub delta_poc: [i32; 2],
+    pub redundant_pic_cnt: u32,
+    /// Whether B slices derive direct motion spatially rather than
+    /// temporally.
+    pub direct_spatial_mv_pred: bool,
+    /// Active references in list 0 and list 1.
+    pub num_ref_idx_active: [u32; 2],
+    pub list_modifications: [Vec<ListModification>; 2],
+    pub weights: Option<PredWeightTable>,
+    /// Whether bi-predicted blocks are weighted by picture order distance
+    /// (`weighted_bipred_idc` 2).
+    pub implicit_weights: bool,
+    pub marking: RefPicMarking,
+    pub qp: i32,
+    pub disable_deblocking_filter_idc: u32,
+    pub alpha_offset: i32,
+    pub beta_offset: i32,
+}
+
+impl SliceHeader {
+    pub fn is_idr(&self) -> bool {
+        self.nal_unit_type == 5
+    }
+
+    /// Whether the picture's marking includes
+    /// `memory_management_control_operation` 5, after which frame numbers
+    /// and picture order counts start again from zero.
+    pub fn unmarks_all(&self) -> bool {
+        matches!(&self.marking, RefPicMarking::Adaptive(operations)
+            if operations.iter().any(|operation| matches!(operation, Mmco::UnmarkAll)))
+    }
+
+    /// Reads the header up to `pps_id`, which selects the parameter sets the
+    /// rest of it depends on.
+    pub fn parse_start(
+        reader: &mut BitReader<'_>,
+        nal_unit_type: u8,
+        nal_ref_idc: u8,
+    ) -> Result<(u32, SliceType, u32)> {
+        let first_mb = reader.read_ue()?;
+        let slice_type = match reader.read_ue()? % 5 {
+            0 => SliceType::P,
+            2 => SliceType::I,
+            1 => SliceType::B,
+            _ => bail!("H.264 SP/SI slices are not supported"),
+        };
+        if nal_unit_type == 5 && slice_type != SliceType::I {
+            bail!("IDR picture contains a non-intra slice");
+        }
+        if nal_ref_idc == 0 && nal_unit_type == 5 {
+            bail!("IDR picture is not marked as a reference");
+        }
+        let pps_id = reader.read_ue()?;
+        Ok((first_mb, slice_type, pps_id))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn parse_rest(
+        reader: &mut BitReader<'_>,
+        nal_unit_type: u8,
+        nal_ref_idc: u8,
+        first_mb: u32,
+        slice_type: SliceType,
+        sps: &Sps,
+        pps: &Pps,
+    ) -> Result<Self> {
+        let mut header = Self {
+            nal_unit_type,
+            nal_ref_idc,
+            first_mb,
+            slice_type,
+            pps_id: pps.id,
+            frame_num: reader.read_bits(sps.log2_max_frame_num)?,
+            idr_pic_id: 0,
+            poc_lsb: 0,
+            delta_poc_bottom: 0,
+            delta_poc: [0; 2],
+            redundant_pic_cnt: 0,
+            direct_spatial_mv_pred: false,
+            num_ref_idx_active: pps.num_ref_idx_default_active,
+            list_modifications: [Vec::new(), Vec::new()],
+            weights: None,
+            implicit_weights: false,
+            marking: RefPicMarking::None,
+            qp: 0,
+            disable_deblocking_filter_idc: 0,
+            alpha_offset: 0,
+            beta_offset: 0,
+        };
+        if header.is_idr() {
+            header.idr_pic_id = reader.read_ue()?;
+        }
+        if sps.pic_order_cnt_type == 0 {
+            header.poc_lsb = reader.read_bits(sps.log2_max_poc_lsb)?;
+            if pps.bottom_field_pic_order_in_frame_present {
+                header.delta_poc_bottom = reader.read_se()?;
+            }
+        }
+        if sps.pic_order_cnt_type == 1 && !sps.delta_pic_order_always_zero {
+            header.delta_poc[0] = reader.read_se()?;
+            if pps.bottom_field_pic_order_in_frame_present {
+                header.delta_poc[1] = reader.read_se()?;
+            }
+        }
+        if pps.redundant_pic_cnt_present {
+            header.redundant_pic_cnt = reader.read_ue()?;
+        }
+        let lists = match slice_type {
+            SliceType::I => 0,
+            SliceType::P => 1,
+            SliceType::B => 2,
+        };
+        if slice_type == SliceType::B {
+            header.direct_spatial_mv_pred = reader.read_flag()?;
+        }
+        if lists > 0 {
+            if reader.read_flag()? {
+                for active in &mut header.num_ref_idx_active[..lists] {
+                    *active = reader.read_ue()? + 1;
+                    if *active > 16 {
+                        bail!("num_ref_idx_active is out of range");
+                    }
+                }
+            }
+            for modifications in &mut header.list_modifications[..lists] {
+                if reader.read_flag()? {
+                    *modifications = parse_list_modifications(reader)?;
+                }
+            }
+            let explicit = match slice_type {
+                SliceType::B => pps.weighted_bipred_idc == 1,
+                _ => pps.weighted_pred,
+            };
+            if explicit {
+                let refs = &header.num_ref_idx_active[..lists];
+                header.weights = Some(parse_pred_weight_table(reader, refs)?);
+            }
+            header.implicit_weights = slice_type == SliceType::B && pps.weighted_bipred_idc == 2;
+        }
+        if nal_ref_idc != 0 {
+            header.marking = parse_marking(reader, header.is_idr())?;
+        }
+        header.qp = pps.pic_init_qp + reader.read_se()?;
+        if !(0..=51).contains(&header.qp) {
+            bail!("slice QP {} is out of range", header.qp);
+        }
+        if pps.deblocking_filter_control_present {
+            header.disable_deblocking_filter_idc = reader.read_ue()?;
+            if header.disable_deblocking_filter_idc > 2 {
+                bail!("invalid disable_deblocking_filter_idc");
+            }
+            if header.disable_deblocking_filter_idc != 1 {
+                header.alpha_offset = reader.read_se()? * 2;
+                header.beta_offset = reader.read_se()? * 2;
+                if !(-12..=12).contains(&header.alpha_offset)
+                    || !(-12..=12).contains(&header.beta_offset)
+                {
+                    bail!("slice deblocking offsets are out of range");
+                }
+            }
+        }
+        Ok(header)
+    }
+}
+
+fn parse_list_modifications(reader: &mut BitReader<'_>) -> Result<Vec<ListModification>> {
+    let mut modifications = Vec::new();
+    loop {
+        let modification = match reader.read_ue()? {
+            0 => ListModification::ShortTermSubtract(reader.read_ue()? + 1),
+            1 => ListModification::ShortTermAdd(reader.read_ue()? + 1),
+            2 => ListModification::LongTerm(reader.read_ue()?),
+            3 => return Ok(modifications),
+            other => bail!("invalid modification_of_pic_nums_idc {other}"),
+        };
+        if modifications.len() > 32 {
+            bail!("too many reference list modifications");
+        }
+        modifications.push(modification);
+    }
+}
+
+/// Reads the weights for `refs` active references of each list the slice
+/// uses.
+fn parse_pred_weight_table(reader: &mut BitReader<'_>, refs: &[u32]) -> Result<PredWeightTable> {
+    let luma_log2_denom = reader.read_ue()?;
+    let chroma_log2_denom = reader.read_ue()?;
+    if luma_log2_denom > 7 || chroma_log2_denom > 7 {
+        bail!("prediction weight denominators are out of range");
+    }
+    let mut weights = [Vec::new(), Vec::new()];
+    for (list, &refs) in weights.iter_mut().zip(refs) {
+        for _ in 0..refs {
+            let mut entry = [
+                (1 << luma_log2_denom, 0),
+                (1 << chroma_log2_denom, 0),
+                (1 << chroma_log2_denom, 0),
+            ];
+            let mut explicit = false;
+            if reader.read_flag()? {
+                entry[0] = (reader.read_se()?, reader.read_se()?);
+                explicit = true;
+            }
+            if reader.read_flag()? {
+                for component in &mut entry[1..] {
+                    *component = (reader.read_se()?, reader.read_se()?);
+                }
+                explicit = true;
+            }
+            list.push(explicit.then_some(entry));
+        }
+    }
+    Ok(PredWeightTable {
+        luma_log2_denom,
+        chroma_log2_denom,
+        weights,
+    })
+}
+
+fn parse_marking(reader: &mut BitReader<'_>, idr: bool) -> Result<RefPicMarking> {
+    if idr {
+        let _no_output_of_prior_pics = reader.read_flag()?;
+        return Ok(RefPicMarking::Idr {
+            long_term: reader.read_flag()?,
+        });
+    }
+    if !reader.read_flag()? {
+        return Ok(RefPicMarking::SlidingWindow);
+    }
+    let mut operations = Vec::new();
+    loop {
+        let operation = match reader.read_ue()? {
+            0 => return Ok(RefPicMarking::Adaptive(operations)),
+            1 => Mmco::UnmarkShortTerm {
+                difference_of_pic_nums: reader.read_ue()? + 1,
+            },
+            2 => Mmco::UnmarkLongTerm {
+                long_term_pic_num: reader.read_ue()?,
+            },
+            3 => Mmco::ShortTermToLongTerm {
+                difference_of_pic_nums: reader.read_ue()? + 1,
+                long_term_frame_idx: reader.read_ue()?,
+            },
+            4 => Mmco::MaxLongTermIndex {
+                max_long_term_frame_idx_plus1: reader.read_ue()?,
+            },
+            5 => Mmco::UnmarkAll,
+            6 => Mmco::CurrentToLongTerm {
+                long_term_frame_idx: reader.read_ue()?,
+            },
+            other => bail!("invalid memory_management_control_operation {other}"),
+        };
+        if operations.len() > 66 {
+            bail!("too many memory management operations");
+        }
+        operations.push(operation);
+    }
+}
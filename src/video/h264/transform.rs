@@ -0,0 +1,280 @@
+//! Scaling and inverse transforms (8.5) with flat scaling matrices, and
+//! the matching forward transforms and quantisation for the encoder.
+
+/// Zig-zag scan position to raster position within a 4x4 block.
+pub(super) const ZIGZAG: [usize; 16] = [0, 1, 4, 8, 5, 2, 3, 6, 9, 12, 13, 10, 7, 11, 14, 15];
+
+/// `normAdjust4x4` by `qP % 6` for the three coefficient position classes.
+const NORM_ADJUST: [[i32; 3]; 6] = [
+    [10, 16, 13],
+    [11, 18, 14],
+    [13, 20, 16],
+    [14, 23, 18],
+    [16, 25, 20],
+    [18, 29, 23],
+];
+
+/// Forward quantisation multipliers by `qP % 6` for the same position
+/// classes; each is about `2^21 / normAdjust`.
+const QUANT_SCALE: [[i32; 3]; 6] = [
+    [13107, 5243, 8066],
+    [11916, 4660, 7490],
+    [10082, 4194, 6554],
+    [9362, 3647, 5825],
+    [8192, 3355, 5243],
+    [7282, 2893, 4559],
+];
+
+/// The position class of raster position `pos`: even row and column, odd
+/// row and column, or mixed.
+fn class(pos: usize) -> usize {
+    match ((pos / 4) % 2, pos % 2) {
+        (0, 0) => 0,
+        (1, 1) => 1,
+        _ => 2,
+    }
+}
+
+/// `LevelScale4x4` for the raster position `pos` with a flat matrix.
+fn level_scale(qp: i32, pos: usize) -> i32 {
+    16 * NORM_ADJUST[(qp % 6) as usize][class(pos)]
+}
+
+/// `[1 1 1 1; 1 1 -1 -1; 1 -1 -1 1; 1 -1 1 -1]` times a column, the 1-D
+/// Hadamard transform used on luma DC levels in both directions.
+fn hadamard(c: [i32; 4]) -> [i32; 4] {
+    let (s0, s1) = (c[0] + c[1], c[0] - c[1]);
+    let (s2, s3) = (c[2] + c[3], c[2] - c[3]);
+    [s0 + s2, s0 - s2, s1 - s3, s1 + s3]
+}
+
+/// Applies `transform` to the rows, then the columns, of a 4x4 block.
+fn separable(block: &[i32; 16], transform: impl Fn([i32; 4]) -> [i32; 4]) -> [i32; 16] {
+    let mut temp = [0i32; 16];
+    for row in 0..4 {
+        let f = transform([
+            block[row * 4],
+            block[row * 4 + 1],
+            block[row * 4 + 2],
+            block[row * 4 + 3],
+        ]);
+        temp[row * 4..row * 4 + 4].copy_from_slice(&f);
+    }
+    let mut out = [0i32; 16];
+    for column in 0..4 {
+        let f = transform([
+            temp[column],
+            temp[4 + column],
+            temp[8 + column],
+            temp[12 + column],
+        ]);
+        for (row, value) in f.into_iter().enumerate() {
+            out[row * 4 + column] = value;
+        }
+    }
+    out
+}
+
+/// Rounds `value * scale` down by `shift` bits towards zero, with the
+/// intra dead zone of a third.
+fn quantize_one(value: i32, scale: i32, shift: u32) -> i32 {
+    let magnitude = (i64::from(value.abs()) * i64::from(scale) + (1 << shift) / 3) >> shift;
+    value.signum() * magnitude as i32
+}
+
+/// `QPc` for a chroma `qPI` (Table 8-15).
+pub(super) fn chroma_qp(qp: i32, offset: i32) -> i32 {
+    const TABLE: [i32; 22] = [
+        29, 30, 31, 32, 32, 33, 34, 34, 35, 35, 36, 36, 37, 37, 37, 38, 38, 38, 39, 39, 39, 39,
+    ];
+    let qpi = (qp + offset).clamp(0, 51);
+    if qpi < 30 {
+        qpi
+    } else {
+        TABLE[(qpi - 30) as usize]
+    }
+}
+
+/// Scales a 4x4 block in raster order, leaving the DC alone when it was
+/// coded separately (`skip_dc`).
+pub(super) fn dequantize(block: &mut [i32; 16], qp: i32, skip_dc: bool) {
+    let shift = qp / 6;
+    for (pos, coeff) in block.iter_mut().enumerate() {
+        if (pos == 0 && skip_dc) || *coeff == 0 {
+            continue;
+        }
+        *coeff = if shift >= 4 {
+            (*coeff * level_scale(qp, pos)) << (shift - 4)
+        } else {
+            (*coeff * level_scale(qp, pos) + (1 << (3 - shift))) >> (4 - shift)
+        };
+    }
+}
+
+/// Inverse Hadamard transform and scaling of the Intra16x16 luma DC levels
+/// (raster order); entry `i` becomes the DC of the 4x4 block at raster
+/// position `i`.
+pub(super) fn luma_dc(dc: &mut [i32; 16], qp: i32) {
+    let out = separable(dc, hadamard);
+    let scale = level_scale(qp, 0);
+    let shift = qp / 6;
+    for (target, value) in dc.iter_mut().zip(out) {
+        *target = if shift >= 6 {
+            (value * scale) << (shift - 6)
+        } else {
+            (value * scale + (1 << (5 - shift))) >> (6 - shift)
+        };
+    }
+}
+
+/// Inverse transform and scaling of a 2x2 chroma DC block (raster order).
+pub(super) fn chroma_dc(dc: &mut [i32; 4], qp: i32) {
+    let [c0, c1, c2, c3] = *dc;
+    let f = [
+        c0 + c1 + c2 + c3,
+        c0 - c1 + c2 - c3,
+        c0 + c1 - c2 - c3,
+        c0 - c1 - c2 + c3,
+    ];
+    let scale = level_scale(qp, 0);
+    for (target, value) in dc.iter_mut().zip(f) {
+        *target = ((value * scale) << (qp / 6)) >> 5;
+    }
+}
+
+/// Inverse 4x4 transform of scaled coefficients (raster order), adding
+/// the residual to the prediction already in `dst`.
+pub(super) fn idct_add(block: &[i32; 16], dst: &mut [u8], offset: usize, stride: usize) {
+    let mut temp = [0i32; 16];
+    for row in 0..4 {
+        let d = &block[row * 4..row * 4 + 4];
+        let e0 = d[0] + d[2];
+        let e1 = d[0] - d[2];
+        let e2 = (d[1] >> 1) - d[3];
+        let e3 = d[1] + (d[3] >> 1);
+        temp[row * 4] = e0 + e3;
+        temp[row * 4 + 1] = e1 + e2;
+        temp[row * 4 + 2] = e1 - e2;
+        temp[row * 4 + 3] = e0 - e3;
+    }
+    for column in 0..4 {
+        let f = |row: usize| temp[row * 4 + column];
+        let g0 = f(0) + f(2);
+        let g1 = f(0) - f(2);
+        let g2 = (f(1) >> 1) - f(3);
+        let g3 = f(1) + (f(3) >> 1);
+        let residual = [g0 + g3, g1 + g2, g1 - g2, g0 - g3];
+        for (row, value) in residual.into_iter().enumerate() {
+            let sample = &mut dst[offset + row * stride + column];
+            *sample = (i32::from(*sample) + ((value + 32) >> 6)).clamp(0, 255) as u8;
+        }
+    }
+}
+
+/// Forward 4x4 core transform of a residual block (raster order).
+pub(super) fn forward(residual: &[i32; 16]) -> [i32; 16] {
+    separable(residual, |x| {
+        let (a, d) = (x[0] + x[3], x[0] - x[3]);
+        let (b, c) = (x[1] + x[2], x[1] - x[2]);
+        [a + b, 2 * d + c, a - b, d - 2 * c]
+    })
+}
+
+/// Quantises transformed coefficients to levels, leaving the DC alone when
+/// it is coded separately (`skip_dc`).
+pub(super) fn quantize(block: &mut [i32; 16], qp: i32, skip_dc: bool) {
+    let shift = 15 + (qp / 6) as u32;
+    for (pos, coeff) in block.iter_mut().enumerate() {
+        if pos == 0 && skip_dc {
+            continue;
+        }
+        *coeff = quantize_one(*coeff, QUANT_SCALE[(qp % 6) as usize][class(pos)], shift);
+    }
+}
+
+/// Hadamard transform and quantisation of the 16 block DCs of an
+/// Intra16x16 macroblock (entry `i` is the DC of the block at raster
+/// position `i`), the inverse of [`luma_dc`].
+pub(super) fn forward_luma_dc(dc: &mut [i32; 16], qp: i32) {
+    let out = separable(dc, hadamard);
+    let shift = 16 + (qp / 6) as u32;
+    let scale = QUANT_SCALE[(qp % 6) as usize][0];
+    for (target, value) in dc.iter_mut().zip(out) {
+        *target = quantize_one(value >> 1, scale, shift);
+    }
+}
+
+/// Hadamard transform and quantisation of a 2x2 chroma DC block, the
+/// inverse of [`chroma_dc`].
+pub(super) fn forward_chroma_dc(dc: &mut [i32; 4], qp: i32) {
+    let [c0, c1, c2, c3] = *dc;
+    let f = [
+        c0 + c1 + c2 + c3,
+        c0 - c1 + c2 - c3,
+        c0 + c1 - c2 - c3,
+        c0 - c1 - c2 + c3,
+    ];
+    let shift = 16 + (qp / 6) as u32;
+    let scale = QUANT_SCALE[(qp % 6) as usize][0];
+    for (target, value) in dc.iter_mut().zip(f) {
+        *target = quantize_one(value, scale, shift);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dc_only_block_adds_a_flat_offset() {
+        // A DC of 64 after scaling is a residual of one per sample.
+        let mut block = [0; 16];
+        block[0] = 64;
+        let mut samples = [100u8; 16];
+        idct_add(&block, &mut samples, 0, 4);
+        assert_eq!(samples, [101; 16]);
+
+        // Level 1 at QP 28: 16 * 16 << 0 = 256, four per sample.
+        let mut block = [0; 16];
+        block[0] = 1;
+        dequantize(&mut block, 28, false);
+        assert_eq!(block[0], 16 * 16);
+    }
+
+    #[test]
+    fn chroma_qp_follows_the_table() {
+        assert_eq!(chroma_qp(20, 0), 20);
+        assert_eq!(chroma_qp(30, 0), 29);
+        assert_eq!(chroma_qp(51, 0), 39);
+        assert_eq!(chroma_qp(50, 5), 39);
+    }
+
+    #[test]
+    fn forward_and_inverse_transforms_round_trip() {
+        let residual: [i32; 16] = std::array::from_fn(|i| (i as i32 * 7) % 23 - 11);
+        for qp in [0, 12, 26, 40] {
+            let mut levels = forward(&residual);
+            quantize(&mut levels, qp, false);
+            dequantize(&mut levels, qp, false);
+            let mut samples = [128u8; 16];
+            idct_add(&levels, &mut samples, 0, 4);
+            let error: i32 = samples
+                .iter()
+                .zip(residual)
+                .map(|(&sample, r)| (i32::from(sample) - 128 - r).abs())
+                .max()
+                .unwrap();
+            // The step size doubles every six QP; at QP 0 it is lossless.
+            assert!(error <= (1 << (qp / 6)), "qp {qp}: error {error}");
+        }
+
+        // A block DC of 16 * 40 (a flat residual of 40) scales back to
+        // about 64 * 40 for `idct_add`, within a sample's worth.
+        let mut dc = [0; 16];
+        dc[5] = 16 * 40;
+        forward_luma_dc(&mut dc, 20);
+        luma_dc(&mut dc, 20);
+        assert!((dc[5] - 64 * 40).abs() <= 64, "{dc:?}");
+        assert!(dc.iter().enumerate().all(|(i, &c)| i == 5 || c == 0));
+    }
+}
@@ -0,0 +1,40 @@
+//! H.265/HEVC decoding, through FFmpeg's libavcodec when built with the
+//! `hevc` feature.
+
+use anyhow::Result;
+#[cfg(not(feature = "hevc"))]
+use anyhow::bail;
+
+use crate::video::MediaStreams;
+use crate::video::container::VideoSamples;
+
+/// Decodes the samples of an HEVC track (`hvc1`/`hev1` in MP4,
+/// `V_MPEGH/ISO/HEVC` in Matroska) into `streams.video`.
+#[cfg(feature = "hevc")]
+pub fn decode_samples(track: &VideoSamples<'_>, streams: &mut MediaStreams) -> Result<()> {
+    use anyhow::anyhow;
+
+    use crate::video::ffmpeg::{DecoderSpec, decode_samples};
+
+    // The hvcC record holds the parameter sets and the NAL unit length
+    // size, which libavcodec reads from the extradata.
+    let config = track
+        .codec_config
+        .as_deref()
+        .ok_or_else(|| anyhow!("HEVC track has no hvcC configuration"))?;
+    decode_samples(
+        DecoderSpec {
+            id: ffmpeg_next::codec::Id::HEVC,
+            name: None,
+            codec: crate::video::VideoCodec::H265,
+            extradata: Some(config),
+        },
+        track,
+        streams,
+    )
+}
+
+#[cfg(not(feature = "hevc"))]
+pub fn decode_samples(_track: &VideoSamples<'_>, _streams: &mut MediaStreams) -> Result<()> {
+    bail!("HEVC decoding requires building with the hevc feature")
+}
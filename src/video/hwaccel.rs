@@ -0,0 +1,143 @@
+//! Hardware video decoding through FFmpeg's hwaccel API: NVDEC with the
+//! `nvdec` feature, VA-API with `vaapi` and VideoToolbox with
+//! `videotoolbox`. Frames are decoded in device memory and copied back, so
+//! later stages get the same planes the software decoders produce.
+
+use anyhow::{Result, bail};
+
+use crate::video::MediaStreams;
+use crate::video::container::VideoSamples;
+
+/// A hardware decoding backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HwAccel {
+    Nvdec,
+    Vaapi,
+    VideoToolbox,
+}
+
+impl HwAccel {
+    pub const ALL: [Self; 3] = [Self::Nvdec, Self::Vaapi, Self::VideoToolbox];
+
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "nvdec" | "cuda" => Ok(Self::Nvdec),
+            "vaapi" => Ok(Self::Vaapi),
+            "videotoolbox" => Ok(Self::VideoToolbox),
+            other => {
+                bail!("unknown hardware decoder '{other}' (expected nvdec, vaapi or videotoolbox)")
+            }
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Nvdec => "nvdec",
+            Self::Vaapi => "vaapi",
+            Self::VideoToolbox => "videotoolbox",
+        }
+    }
+
+    /// Whether this build includes the backend.
+    pub fn compiled(self) -> bool {
+        match self {
+            Self::Nvdec => cfg!(feature = "nvdec"),
+            Self::Vaapi => cfg!(feature = "vaapi"),
+            Self::VideoToolbox => cfg!(feature = "videotoolbox"),
+        }
+    }
+
+    /// The backends this build includes, in the order they are tried.
+    pub fn available() -> Vec<Self> {
+        Self::ALL
+            .into_iter()
+            .filter(|backend| backend.compiled())
+            .collect()
+    }
+}
+
+/// Decodes the samples of an H.264, HEVC, VP9 or AV1 track on `backend`
+/// into `streams.video`. Returns whether the device did the decoding; when
+/// it cannot handle the stream, libavcodec decodes it in software instead.
+/// Fails when the device cannot be opened.
+#[cfg(any(feature = "nvdec", feature = "vaapi", feature = "videotoolbox"))]
+pub fn decode_samples(
+    backend: HwAccel,
+    track: &VideoSamples<'_>,
+    streams: &mut MediaStreams,
+) -> Result<bool> {
+    use ffmpeg_next::codec::Id;
+    use ffmpeg_next::ffi::AVHWDeviceType;
+
+    use crate::video::VideoCodec;
+    use crate::video::ffmpeg::{DecoderSpec, decode_samples_on};
+
+    if !backend.compiled() {
+        bail!(
+            "{} decoding requires building with the {} feature",
+            backend.name(),
+            backend.name()
+        );
+    }
+    // The avcC, hvcC and av1C records carry what libavcodec reads from the
+    // extradata; VP9 needs none.
+    let (id, extradata) = match track.codec {
+        VideoCodec::H264 => (Id::H264, track.codec_config.as_deref()),
+        VideoCodec::H265 => (Id::HEVC, track.codec_config.as_deref()),
+        VideoCodec::Vp9 => (Id::VP9, None),
+        VideoCodec::Av1 => (Id::AV1, track.codec_config.as_deref()),
+        codec => bail!("no hardware decoder for {codec:?} video tracks"),
+    };
+    let device = match backend {
+        HwAccel::Nvdec => AVHWDeviceType::AV_HWDEVICE_TYPE_CUDA,
+        HwAccel::Vaapi => AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI,
+        HwAccel::VideoToolbox => AVHWDeviceType::AV_HWDEVICE_TYPE_VIDEOTOOLBOX,
+    };
+    // libavcodec's own decoders, not wrappers such as libdav1d, are the ones
+    // with hwaccels.
+    decode_samples_on(
+        DecoderSpec {
+            id,
+            name: None,
+            codec: track.codec,
+            extradata,
+        },
+        device,
+        track,
+        streams,
+    )
+}
+
+#[cfg(not(any(feature = "nvdec", feature = "vaapi", feature = "videotoolbox")))]
+pub fn decode_samples(
+    backend: HwAccel,
+    _track: &VideoSamples<'_>,
+    _streams: &mut MediaStreams,
+) -> Result<bool> {
+    bail!(
+        "{} decoding requires building with the {} feature",
+        backend.name(),
+        backend.name()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_backend_names() {
+        for backend in HwAccel::ALL {
+            assert_eq!(HwAccel::parse(backend.name()).unwrap(), backend);
+        }
+        assert_eq!(HwAccel::parse("CUDA").unwrap(), HwAccel::Nvdec);
+        assert!(HwAccel::parse("d3d11va").is_err());
+    }
+
+    #[cfg(not(any(feature = "nvdec", feature = "vaapi", feature = "videotoolbox")))]
+    #[test]
+    fn software_builds_have_no_backends() {
+        assert!(HwAccel::available().is_empty());
+        assert!(HwAccel::ALL.iter().all(|backend| !backend.compiled()));
+    }
+}
@@ -0,0 +1,1372 @@
+//! Matroska (MKV) and WebM reader and writer.
+//!
+//! Writes an EBML header and one segment holding a seek head, segment info,
+//! the track entries, clusters of `SimpleBlock`s and cues for every cluster
+//! that opens on a video keyframe. Timestamps are in milliseconds.
+//!
+//! Reading walks the same elements to find the first video or audio track
+//! and its blocks, including the unknown-size segments and clusters of live
+//! recordings, or to describe every track for `probe`.
+
+use std::borrow::Cow;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail};
+
+use crate::audio::pcm::SampleFormat;
+use crate::video::container::{AudioSamples, Sample, VideoSamples};
+use crate::video::probe::{MediaInfo, TrackDetails, TrackInfo};
+use crate::video::{
+    AudioCodec, ContentLightLevel, EncodedAudio, EncodedVideo, FrameRate, HdrMetadata,
+    MasteringDisplay, VideoCodec,
+};
+
+const EBML: u32 = 0x1A45_DFA3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42F7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+
+const SEGMENT: u32 = 0x1853_8067;
+const SEEK_HEAD: u32 = 0x114D_9B74;
+const SEEK: u32 = 0x4DBB;
+const SEEK_ID: u32 = 0x53AB;
+const SEEK_POSITION: u32 = 0x53AC;
+
+const INFO: u32 = 0x1549_A966;
+const TIMESTAMP_SCALE: u32 = 0x2A_D7B1;
+const DURATION: u32 = 0x4489;
+const MUXING_APP: u32 = 0x4D80;
+const WRITING_APP: u32 = 0x5741;
+
+const TRACKS: u32 = 0x1654_AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_UID: u32 = 0x73C5;
+const TRACK_TYPE: u32 = 0x83;
+const FLAG_LACING: u32 = 0x9C;
+const DEFAULT_DURATION: u32 = 0x23_E383;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const CODEC_DELAY: u32 = 0x56AA;
+const SEEK_PRE_ROLL: u32 = 0x56BB;
+const VIDEO: u32 = 0xE0;
+const PIXEL_WIDTH: u32 = 0xB0;
+const PIXEL_HEIGHT: u32 = 0xBA;
+const PROJECTION: u32 = 0x7670;
+const PROJECTION_TYPE: u32 = 0x7671;
+const PROJECTION_POSE_ROLL: u32 = 0x7675;
+const COLOUR: u32 = 0x55B0;
+const MAX_CLL: u32 = 0x55BC;
+const MAX_FALL: u32 = 0x55BD;
+const MASTERING_METADATA: u32 = 0x55D0;
+/// Red, green and blue x and y, then the white point's: 0x55D1 to 0x55D8.
+const PRIMARY_R_CHROMATICITY_X: u32 = 0x55D1;
+const LUMINANCE_MAX: u32 = 0x55D9;
+const LUMINANCE_MIN: u32 = 0x55DA;
+const AUDIO: u32 = 0xE1;
+const SAMPLING_FREQUENCY: u32 = 0xB5;
+const CHANNELS: u32 = 0x9F;
+const BIT_DEPTH: u32 = 0x6264;
+
+const CONTENT_ENCODINGS: u32 = 0x6D80;
+
+const CLUSTER: u32 = 0x1F43_B675;
+const CLUSTER_TIMESTAMP: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+const BLOCK_GROUP: u32 = 0xA0;
+const BLOCK: u32 = 0xA1;
+const BLOCK_DURATION: u32 = 0x9B;
+const REFERENCE_BLOCK: u32 = 0xFB;
+
+const CUES: u32 = 0x1C53_BB6B;
+const CUE_POINT: u32 = 0xBB;
+const CUE_TIME: u32 = 0xB3;
+const CUE_TRACK_POSITIONS: u32 = 0xB7;
+const CUE_TRACK: u32 = 0xF7;
+const CUE_CLUSTER_POSITION: u32 = 0xF1;
+
+const CHAPTERS: u32 = 0x1043_A770;
+const TAGS: u32 = 0x1254_C367;
+const ATTACHMENTS: u32 = 0x1941_A469;
+/// The segment's children; an unknown-size cluster ends at the next one.
+const TOP_LEVEL: [u32; 8] = [
+    SEEK_HEAD,
+    INFO,
+    TRACKS,
+    CLUSTER,
+    CUES,
+    CHAPTERS,
+    TAGS,
+    ATTACHMENTS,
+];
+
+const VIDEO_TRACK: u64 = 1;
+const AUDIO_TRACK: u64 = 2;
+/// Nanoseconds per timestamp tick.
+const NANOS_PER_TICK: u64 = 1_000_000;
+/// Clusters that open on a keyframe are started once the current one is
+/// this long (in ticks).
+const CLUSTER_TARGET: u64 = 5_000;
+/// How far before a seek target Opus decoding must start to converge, in
+/// nanoseconds.
+const OPUS_SEEK_PRE_ROLL: u64 = 80_000_000;
+
+/// Which EBML document type to write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DocType {
+    Matroska,
+    /// The WebM subset, which only carries VP8, VP9 and AV1 video and
+    /// Vorbis and Opus audio.
+    WebM,
+}
+
+struct Block {
+    track: u64,
+    /// Milliseconds.
+    time: u64,
+    keyframe: bool,
+    data: Vec<u8>,
+}
+
+/// Writes `video` and, when given, `audio` (float PCM or Opus packets) as
+/// a Matroska or WebM file.
+pub fn write_matroska(
+    video: &EncodedVideo,
+    audio: Option<&EncodedAudio>,
+    doc_type: DocType,
+) -> Result<Vec<u8>> {
+    if video.samples.is_empty() {
+        bail!("no encoded frames to mux");
+    }
+    if doc_type == DocType::WebM {
+        if !matches!(video.codec, VideoCodec::Av1) {
+            bail!(
+                "WebM only carries VP8, VP9 or AV1 video; write {:?} as mkv or mp4 instead",
+                video.codec
+            );
+        }
+        if audio.is_some_and(|audio| !matches!(audio.codec, AudioCodec::Opus)) {
+            bail!("WebM only carries Vorbis or Opus audio; write mkv to keep PCM audio");
+        }
+    }
+
+    let mut blocks: Vec<Block> = video
+        .samples
+        .iter()
+        .map(|sample| Block {
+            track: VIDEO_TRACK,
+            time: sample.timestamp.as_millis() as u64,
+            keyframe: sample.keyframe,
+            data: sample.data.clone(),
+        })
+        .collect();
+    let mut end = video
+        .samples
+        .iter()
+        .map(|sample| (sample.timestamp + sample.duration).as_millis() as u64)
+        .max()
+        .unwrap_or(0);
+
+    let mut track_entries = video_track(video);
+    if let Some(audio) = audio {
+        let (entry, audio_blocks, audio_end) = audio_track(audio)?;
+        track_entries.extend(entry);
+        blocks.extend(audio_blocks);
+        end = end.max(audio_end);
+    }
+    // Stable, so each track's blocks keep their order.
+    blocks.sort_by_key(|block| block.time);
+
+    let mut info = uint_element(TIMESTAMP_SCALE, NANOS_PER_TICK);
+    info.extend(float_element(DURATION, end as f64));
+    info.extend(element(MUXING_APP, b"bunker-convert"));
+    info.extend(element(WRITING_APP, b"bunker-convert"));
+    let info = element(INFO, &info);
+    let tracks = element(TRACKS, &track_entries);
+
+    // Cluster offsets are relative to the first cluster until the size of
+    // everything before it is known.
+    let mut clusters = Vec::new();
+    let mut cue_points = Vec::new();
+    let mut current: Option<(u64, Vec<u8>)> = None;
+    for block in &blocks {
+        let starts_cluster = match &current {
+            None => true,
+            Some((start, _)) => {
+                let elapsed = block.time - start;
+                elapsed > i16::MAX as u64
+                    || (block.track == VIDEO_TRACK && block.keyframe && elapsed >= CLUSTER_TARGET)
+            }
+        };
+        if starts_cluster {
+            if let Some((_, cluster)) = current.take() {
+                clusters.extend(element(CLUSTER, &cluster));
+            }
+            if block.track == VIDEO_TRACK && block.keyframe {
+                cue_points.push((block.time, clusters.len() as u64));
+            }
+            current = Some((block.time, uint_element(CLUSTER_TIMESTAMP, block.time)));
+        }
+        let (start, cluster) = current.as_mut().expect("a cluster was just started");
+        let mut simple_block = size_vint(block.track);
+        simple_block.extend_from_slice(&((block.time - *start) as i16).to_be_bytes());
+        simple_block.push(if block.keyframe { 0x80 } else { 0 });
+        simple_block.extend_from_slice(&block.data);
+        cluster.extend(element(SIMPLE_BLOCK, &simple_block));
+    }
+    if let Some((_, cluster)) = current {
+        clusters.extend(element(CLUSTER, &cluster));
+    }
+
+    // The seek head's positions are fixed-width, so its size does not
+    // depend on them.
+    let seek_head_len = seek_head(0, 0, 0).len() as u64;
+    let clusters_start = seek_head_len + info.len() as u64 + tracks.len() as u64;
+    let mut cues = Vec::new();
+    for (time, offset) in cue_points {
+        let mut positions = uint_element(CUE_TRACK, VIDEO_TRACK);
+        positions.extend(uint_element(CUE_CLUSTER_POSITION, clusters_start + offset));
+        let mut point = uint_element(CUE_TIME, time);
+        point.extend(element(CUE_TRACK_POSITIONS, &positions));
+        cues.extend(element(CUE_POINT, &point));
+    }
+    let cues = element(CUES, &cues);
+    let cues_position = clusters_start + clusters.len() as u64;
+
+    let mut segment = seek_head(
+        seek_head_len,
+        seek_head_len + info.len() as u64,
+        cues_position,
+    );
+    segment.extend(info);
+    segment.extend(tracks);
+    segment.extend(clusters);
+    segment.extend(cues);
+
+    let mut header = uint_element(EBML_VERSION, 1);
+    header.extend(uint_element(EBML_READ_VERSION, 1));
+    header.extend(uint_element(EBML_MAX_ID_LENGTH, 4));
+    header.extend(uint_element(EBML_MAX_SIZE_LENGTH, 8));
+    header.extend(element(
+        DOC_TYPE,
+        match doc_type {
+            DocType::Matroska => b"matroska".as_slice(),
+            DocType::WebM => b"webm".as_slice(),
+        },
+    ));
+    header.extend(uint_element(DOC_TYPE_VERSION, 4));
+    header.extend(uint_element(DOC_TYPE_READ_VERSION, 2));
+    let mut out = element(EBML, &header);
+    out.extend(element(SEGMENT, &segment));
+    Ok(out)
+}
+
+fn video_track(video: &EncodedVideo) -> Vec<u8> {
+    let mut entry = uint_element(TRACK_NUMBER, VIDEO_TRACK);
+    entry.extend(uint_element(TRACK_UID, VIDEO_TRACK));
+    entry.extend(uint_element(TRACK_TYPE, 1));
+    entry.extend(uint_element(FLAG_LACING, 0));
+    if let FrameRate::Constant {
+        numerator,
+        denominator,
+    } = video.frame_rate
+        && numerator > 0
+    {
+        let nanos = u64::from(denominator) * 1_000_000_000 / u64::from(numerator);
+        entry.extend(uint_element(DEFAULT_DURATION, nanos));
+    }
+    let codec_id: &[u8] = match video.codec {
+        VideoCodec::Av1 => b"V_AV1",
+        _ => b"V_MPEG4/ISO/AVC",
+    };
+    entry.extend(element(CODEC_ID, codec_id));
+    entry.extend(element(CODEC_PRIVATE, &video.config));
+    let mut settings = uint_element(PIXEL_WIDTH, u64::from(video.width));
+    settings.extend(uint_element(PIXEL_HEIGHT, u64::from(video.height)));
+    if video.rotation != 0 {
+        // A rectangular projection rolled counter-clockwise, in -180..=180.
+        let roll = match 360 - i32::from(video.rotation) {
+            roll if roll > 180 => roll - 360,
+            roll => roll,
+        };
+        let mut projection = uint_element(PROJECTION_TYPE, 0);
+        projection.extend(float_element(PROJECTION_POSE_ROLL, f64::from(roll)));
+        settings.extend(element(PROJECTION, &projection));
+    }
+    if !video.hdr.is_empty() {
+        settings.extend(element(COLOUR, &colour(&video.hdr)));
+    }
+    entry.extend(element(VIDEO, &settings));
+    element(TRACK_ENTRY, &entry)
+}
+
+/// The `Colour` element carrying `hdr`, whose chromaticities and
+/// luminances Matroska stores as floats in their natural units.
+fn colour(hdr: &HdrMetadata) -> Vec<u8> {
+    let mut colour = Vec::new();
+    if let Some(light) = &hdr.content_light {
+        colour.extend(uint_element(MAX_CLL, u64::from(light.max_cll)));
+        colour.extend(uint_element(MAX_FALL, u64::from(light.max_fall)));
+    }
+    if let Some(display) = &hdr.mastering_display {
+        let coordinates = display.primaries.iter().chain([&display.white_point]);
+        let mut mastering = Vec::new();
+        for (id, &value) in (PRIMARY_R_CHROMATICITY_X..).zip(coordinates.flatten()) {
+            let value = f64::from(value) * MasteringDisplay::CHROMATICITY_UNIT;
+            mastering.extend(float_element(id, value));
+        }
+        for (id, value) in [
+            (LUMINANCE_MAX, display.max_luminance),
+            (LUMINANCE_MIN, display.min_luminance),
+        ] {
+            let nits = f64::from(value) * MasteringDisplay::LUMINANCE_UNIT;
+            mastering.extend(float_element(id, nits));
+        }
+        colour.extend(element(MASTERING_METADATA, &mastering));
+    }
+    colour
+}
+
+/// The audio track entry, its blocks (one packet each) and where it ends.
+fn audio_track(audio: &EncodedAudio) -> Result<(Vec<u8>, Vec<Block>, u64)> {
+    if audio.channels == 0 || audio.sample_rate == 0 {
+        bail!("audio needs at least one channel and a sample rate");
+    }
+    let rate = u64::from(audio.sample_rate);
+    let mut blocks = Vec::with_capacity(audio.packets.len());
+    let mut frames = 0u64;
+    for packet in &audio.packets {
+        blocks.push(Block {
+            track: AUDIO_TRACK,
+            time: frames * 1000 / rate,
+            keyframe: true,
+            data: packet.data.clone(),
+        });
+        frames += u64::from(packet.frames);
+    }
+
+    let mut entry = uint_element(TRACK_NUMBER, AUDIO_TRACK);
+    entry.extend(uint_element(TRACK_UID, AUDIO_TRACK));
+    entry.extend(uint_element(TRACK_TYPE, 2));
+    entry.extend(uint_element(FLAG_LACING, 0));
+    let mut settings = float_element(SAMPLING_FREQUENCY, f64::from(audio.sample_rate));
+    settings.extend(uint_element(CHANNELS, u64::from(audio.channels)));
+    match audio.codec {
+        AudioCodec::PcmF32 => {
+            entry.extend(element(CODEC_ID, b"A_PCM/FLOAT/IEEE"));
+            settings.extend(uint_element(BIT_DEPTH, 32));
+        }
+        AudioCodec::Opus => {
+            entry.extend(element(CODEC_ID, b"A_OPUS"));
+            entry.extend(element(CODEC_PRIVATE, &audio.config));
+            let delay = u64::from(audio.priming) * 1_000_000_000 / rate;
+            entry.extend(uint_element(CODEC_DELAY, delay));
+            entry.extend(uint_element(SEEK_PRE_ROLL, OPUS_SEEK_PRE_ROLL));
+        }
+        other => bail!("Matroska output cannot carry {} audio", other.name()),
+    }
+    entry.extend(element(AUDIO, &settings));
+    let end = frames * 1000 / rate;
+    Ok((element(TRACK_ENTRY, &entry), blocks, end))
+}
+
+/// Seek entries for the info, tracks and cues, at positions relative to
+/// the segment's data.
+fn seek_head(info: u64, tracks: u64, cues: u64) -> Vec<u8> {
+    let mut seeks = Vec::new();
+    for (id, position) in [(INFO, info), (TRACKS, tracks), (CUES, cues)] {
+        let mut seek = element(SEEK_ID, &id_bytes(id));
+        seek.extend(element(SEEK_POSITION, &position.to_be_bytes()));
+        seeks.extend(element(SEEK, &seek));
+    }
+    element(SEEK_HEAD, &seeks)
+}
+
+fn id_bytes(id: u32) -> Vec<u8> {
+    let bytes = id.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&byte| byte == 0).count();
+    bytes[skip..].to_vec()
+}
+
+/// The shortest EBML variable-length integer holding `value`; all ones is
+/// reserved for unknown sizes.
+fn size_vint(value: u64) -> Vec<u8> {
+    let length = (1..=8usize)
+        .find(|&length| value < (1u64 << (7 * length)) - 1)
+        .expect("element sizes fit in 56 bits");
+    let marked = value | 1 << (7 * length);
+    marked.to_be_bytes()[8 - length..].to_vec()
+}
+
+fn element(id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut out = id_bytes(id);
+    out.extend(size_vint(payload.len() as u64));
+    out.extend_from_slice(payload);
+    out
+}
+
+fn uint_element(id: u32, value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&byte| byte == 0).count().min(7);
+    element(id, &bytes[skip..])
+}
+
+fn float_element(id: u32, value: f64) -> Vec<u8> {
+    element(id, &value.to_be_bytes())
+}
+
+/// Whether `data` starts with an EBML header, as Matroska and WebM files do.
+pub fn is_matroska(data: &[u8]) -> bool {
+    data.starts_with(&EBML.to_be_bytes())
+}
+
+/// A block before its track's settings are known.
+struct RawBlock<'a> {
+    cluster_time: u64,
+    /// The block's header and frame.
+    data: &'a [u8],
+    /// `SimpleBlock`s carry a keyframe flag; a `BlockGroup` is a keyframe
+    /// when it references no other block.
+    keyframe: Option<bool>,
+    duration: Option<u64>,
+}
+
+#[derive(Default)]
+struct TrackEntry<'a> {
+    number: u64,
+    kind: u64,
+    codec_id: &'a [u8],
+    codec_private: Option<&'a [u8]>,
+    default_duration: Option<u64>,
+    width: u32,
+    height: u32,
+    rotation: u16,
+    hdr: HdrMetadata,
+    sampling_frequency: f64,
+    channels: u16,
+    bit_depth: Option<u64>,
+}
+
+/// The parts of a Matroska file the readers use.
+struct Segment<'a> {
+    doc_type: &'a [u8],
+    /// Nanoseconds per timestamp tick.
+    timestamp_scale: u64,
+    /// In timestamp ticks, from the segment info.
+    duration: Option<f64>,
+    tracks: Vec<TrackEntry<'a>>,
+    blocks: Vec<RawBlock<'a>>,
+}
+
+fn read_segment(data: &[u8]) -> Result<Segment<'_>> {
+    let (id, size, header) = read_header(data)?;
+    if id != EBML {
+        bail!("not a Matroska file: missing EBML header");
+    }
+    let mut rest = &data[header..];
+    let header_payload = take_payload(&mut rest, size, "EBML header")?;
+    let mut doc_type: &[u8] = b"matroska";
+    for (id, payload) in children(header_payload)? {
+        if id == DOC_TYPE {
+            if !matches!(payload, b"matroska" | b"webm") {
+                bail!(
+                    "unsupported EBML document type '{}'",
+                    String::from_utf8_lossy(payload)
+                );
+            }
+            doc_type = payload;
+        }
+    }
+
+    let (id, size, header) = read_header(rest)?;
+    if id != SEGMENT {
+        bail!("expected a Matroska segment after the EBML header");
+    }
+    rest = &rest[header..];
+    let mut segment = match size {
+        Some(_) => take_payload(&mut rest, size, "segment")?,
+        None => rest,
+    };
+
+    let mut timestamp_scale = NANOS_PER_TICK;
+    let mut duration = None;
+    let mut tracks = Vec::new();
+    let mut blocks = Vec::new();
+    while !segment.is_empty() {
+        let (id, size, header) = read_header(segment)?;
+        segment = &segment[header..];
+        let payload = match (size, id) {
+            (None, CLUSTER) => {
+                let length = unknown_cluster_length(segment)?;
+                let (payload, rest) = segment.split_at(length);
+                segment = rest;
+                payload
+            }
+            _ => take_payload(&mut segment, size, "segment element")?,
+        };
+        match id {
+            INFO => {
+                for (id, payload) in children(payload)? {
+                    match id {
+                        TIMESTAMP_SCALE => timestamp_scale = read_uint(payload)?,
+                        DURATION => duration = Some(read_float(payload)?),
+                        _ => {}
+                    }
+                }
+            }
+            TRACKS if tracks.is_empty() => {
+                for (id, entry) in children(payload)? {
+                    if id == TRACK_ENTRY {
+                        tracks.push(track_entry(entry)?);
+                    }
+                }
+            }
+            CLUSTER => cluster_blocks(payload, &mut blocks)?,
+            _ => {}
+        }
+    }
+    if timestamp_scale == 0 {
+        bail!("Matroska timestamp scale is zero");
+    }
+    Ok(Segment {
+        doc_type,
+        timestamp_scale,
+        duration,
+        tracks,
+        blocks,
+    })
+}
+
+impl<'a> Segment<'a> {
+    /// `track`'s blocks, in file order, timed by their presentation times.
+    /// Laced audio blocks stay whole, one sample per block.
+    fn samples(&self, track: &TrackEntry<'_>) -> Result<Vec<Sample<'a>>> {
+        let ticks_to_nanos = |ticks: u64| ticks.saturating_mul(self.timestamp_scale);
+        let mut timed = Vec::new();
+        for (index, block) in self.blocks.iter().enumerate() {
+            let (number, header) = read_vint(block.data)
+                .with_context(|| format!("block {} is malformed", index + 1))?;
+            if number.value != track.number {
+                continue;
+            }
+            let Some(fields) = block.data.get(header..header + 3) else {
+                bail!("block {} is truncated", index + 1);
+            };
+            let relative = i16::from_be_bytes([fields[0], fields[1]]);
+            let flags = fields[2];
+            if flags & 0x06 != 0 && track.kind == VIDEO_TRACK {
+                bail!("laced video blocks are not supported");
+            }
+            let time = block
+                .cluster_time
+                .saturating_add_signed(i64::from(relative));
+            timed.push((
+                ticks_to_nanos(time),
+                block.duration.map(ticks_to_nanos),
+                block.keyframe.unwrap_or(flags & 0x80 != 0),
+                &block.data[header + 3..],
+            ));
+        }
+
+        // Blocks without a duration last until the next one presented,
+        // which is not the next stored when frames are reordered.
+        let mut presentation: Vec<u64> = timed.iter().map(|&(time, ..)| time).collect();
+        presentation.sort_unstable();
+        presentation.dedup();
+        let next_time = |time: u64| {
+            presentation
+                .get(presentation.partition_point(|&other| other <= time))
+                .copied()
+        };
+        let mut samples = Vec::with_capacity(timed.len());
+        for &(time, duration, keyframe, data) in &timed {
+            let duration = duration
+                .or(track.default_duration)
+                .or_else(|| next_time(time).map(|next| next - time))
+                .or_else(|| {
+                    samples
+                        .last()
+                        .map(|sample: &Sample<'_>| sample.duration.as_nanos() as u64)
+                })
+                .unwrap_or(0);
+            samples.push(Sample {
+                data,
+                timestamp: Duration::from_nanos(time),
+                decode_time: Duration::from_nanos(time),
+                duration: Duration::from_nanos(duration),
+                keyframe,
+            });
+        }
+        Ok(samples)
+    }
+
+    fn video_samples(&self, video: &TrackEntry<'a>) -> Result<VideoSamples<'a>> {
+        let samples = self.samples(video)?;
+        let frame_period = video.default_duration.or_else(|| {
+            let first = samples.first()?.duration;
+            samples
+                .iter()
+                .all(|sample| sample.duration == first)
+                .then_some(first.as_nanos() as u64)
+        });
+        Ok(VideoSamples {
+            codec: match video.codec_id {
+                b"V_MPEG4/ISO/AVC" => VideoCodec::H264,
+                b"V_MPEGH/ISO/HEVC" => VideoCodec::H265,
+                b"V_VP9" => VideoCodec::Vp9,
+                b"V_AV1" => VideoCodec::Av1,
+                _ => VideoCodec::Unknown,
+            },
+            width: video.width,
+            height: video.height,
+            frame_rate: frame_period.map_or(FrameRate::Variable, frame_rate),
+            codec_config: video.codec_private.map(Cow::Borrowed),
+            samples,
+            rotation: video.rotation,
+            hdr: video.hdr,
+        })
+    }
+
+    fn audio_samples(&self, audio: &TrackEntry<'a>) -> Result<AudioSamples<'a>> {
+        let codec = audio_codec(audio.codec_id);
+        let pcm = match (codec, audio.bit_depth) {
+            (AudioCodec::PcmF32 | AudioCodec::PcmF64, depth) => match depth {
+                Some(64) => Some(SampleFormat::F64 { big_endian: false }),
+                Some(32) | None => Some(SampleFormat::F32 { big_endian: false }),
+                Some(depth) => bail!("Matroska float PCM of {depth} bits is not supported"),
+            },
+            (AudioCodec::PcmS16, depth) => {
+                let big_endian = audio.codec_id == b"A_PCM/INT/BIG";
+                match depth.unwrap_or(16) {
+                    8 if !big_endian => Some(SampleFormat::U8),
+                    16 => Some(SampleFormat::S16 { big_endian }),
+                    24 => Some(SampleFormat::S24 { big_endian }),
+                    32 => Some(SampleFormat::S32 { big_endian }),
+                    depth => bail!("Matroska integer PCM of {depth} bits is not supported"),
+                }
+            }
+            _ => None,
+        };
+        Ok(AudioSamples {
+            codec: pcm.map_or(codec, SampleFormat::codec),
+            sample_rate: audio.sampling_frequency.round() as u32,
+            channels: audio.channels,
+            pcm,
+            codec_config: audio.codec_private.map(Cow::Borrowed),
+            samples: self.samples(audio)?,
+        })
+    }
+}
+
+/// The first video track's blocks, in file order, with its codec settings.
+/// Timestamps are the blocks' presentation times.
+pub fn video_samples(data: &[u8]) -> Result<Option<VideoSamples<'_>>> {
+    let segment = read_segment(data)?;
+    segment
+        .tracks
+        .iter()
+        .find(|track| track.kind == VIDEO_TRACK)
+        .map(|video| segment.video_samples(video))
+        .transpose()
+}
+
+/// The first audio track's blocks, in file order, with its codec settings.
+/// Laced blocks stay whole, so their frames come as one sample.
+pub fn audio_samples(data: &[u8]) -> Result<Option<AudioSamples<'_>>> {
+    let segment = read_segment(data)?;
+    segment
+        .tracks
+        .iter()
+        .find(|track| track.kind == AUDIO_TRACK)
+        .map(|audio| segment.audio_samples(audio))
+        .transpose()
+}
+
+/// Describes the file and its video and audio tracks from the track
+/// entries and block headers.
+pub fn probe(data: &[u8]) -> Result<MediaInfo> {
+    let segment = read_segment(data)?;
+    let mut tracks = Vec::new();
+    for track in &segment.tracks {
+        let codec_tag = String::from_utf8_lossy(track.codec_id);
+        let index = tracks.len();
+        match track.kind {
+            VIDEO_TRACK => {
+                let samples = segment.video_samples(track)?;
+                tracks.push(TrackInfo::video(index, &samples, &codec_tag, None));
+            }
+            AUDIO_TRACK => {
+                let samples = segment.samples(track)?;
+                let duration = samples.last().map(|last| {
+                    (last.timestamp + last.duration).saturating_sub(samples[0].timestamp)
+                });
+                let size_bytes = samples.iter().map(|sample| sample.data.len() as u64).sum();
+                tracks.push(TrackInfo::new(
+                    index,
+                    audio_codec(track.codec_id).name(),
+                    &codec_tag,
+                    (samples.len() as u64, size_bytes),
+                    duration,
+                    TrackDetails::Audio {
+                        sample_rate: track.sampling_frequency.round() as u32,
+                        channels: track.channels,
+                    },
+                ));
+            }
+            _ => {}
+        }
+    }
+    let duration = segment
+        .duration
+        .filter(|ticks| ticks.is_finite() && *ticks > 0.0)
+        .map(|ticks| Duration::from_secs_f64(ticks * segment.timestamp_scale as f64 / 1e9));
+    let container = if segment.doc_type == b"webm" {
+        "webm"
+    } else {
+        "matroska"
+    };
+    Ok(MediaInfo::new(container, data.len(), duration, tracks))
+}
+
+fn audio_codec(codec_id: &[u8]) -> AudioCodec {
+    match codec_id {
+        b"A_PCM/FLOAT/IEEE" => AudioCodec::PcmF32,
+        b"A_PCM/INT/LIT" | b"A_PCM/INT/BIG" => AudioCodec::PcmS16,
+        b"A_OPUS" => AudioCodec::Opus,
+        b"A_MPEG/L3" => AudioCodec::Mp3,
+        b"A_FLAC" => AudioCodec::Flac,
+        id if id.starts_with(b"A_AAC") => AudioCodec::Aac,
+        _ => AudioCodec::Unknown,
+    }
+}
+
+fn track_entry(data: &[u8]) -> Result<TrackEntry<'_>> {
+    let mut entry = TrackEntry::default();
+    for (id, payload) in children(data)? {
+        match id {
+            TRACK_NUMBER => entry.number = read_uint(payload)?,
+            TRACK_TYPE => entry.kind = read_uint(payload)?,
+            CODEC_ID => entry.codec_id = payload,
+            CODEC_PRIVATE => entry.codec_private = Some(payload),
+            DEFAULT_DURATION => entry.default_duration = Some(read_uint(payload)?),
+            CONTENT_ENCODINGS => bail!("compressed or encrypted Matroska tracks are not supported"),
+            VIDEO => {
+                for (id, payload) in children(payload)? {
+                    match id {
+                        PIXEL_WIDTH => entry.width = read_uint(payload)? as u32,
+                        PIXEL_HEIGHT => entry.height = read_uint(payload)? as u32,
+                        PROJECTION => entry.rotation = projection_rotation(payload)?,
+                        COLOUR => entry.hdr = colour_hdr(payload)?,
+                        _ => {}
+                    }
+                }
+            }
+            AUDIO => {
+                // The defaults for elements the entry leaves out.
+                (entry.sampling_frequency, entry.channels) = (8000.0, 1);
+                for (id, payload) in children(payload)? {
+                    match id {
+                        SAMPLING_FREQUENCY => entry.sampling_frequency = read_float(payload)?,
+                        CHANNELS => entry.channels = read_uint(payload)? as u16,
+                        BIT_DEPTH => entry.bit_depth = Some(read_uint(payload)?),
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(entry)
+}
+
+fn cluster_blocks<'a>(data: &'a [u8], blocks: &mut Vec<RawBlock<'a>>) -> Result<()> {
+    let mut cluster_time = 0;
+    for (id, payload) in children(data)? {
+        match id {
+            CLUSTER_TIMESTAMP => cluster_time = read_uint(payload)?,
+            SIMPLE_BLOCK => blocks.push(RawBlock {
+                cluster_time,
+                data: payload,
+                keyframe: None,
+                duration: None,
+            }),
+            BLOCK_GROUP => {
+                let group = children(payload)?;
+                let block = group
+                    .iter()
+                    .find(|(id, _)| *id == BLOCK)
+                    .ok_or_else(|| anyhow!("Matroska block group has no block"))?;
+                let duration = group
+                    .iter()
+                    .find(|(id, _)| *id == BLOCK_DURATION)
+                    .map(|(_, payload)| read_uint(payload))
+                    .transpose()?;
+                blocks.push(RawBlock {
+                    cluster_time,
+                    data: block.1,
+                    keyframe: Some(!group.iter().any(|(id, _)| *id == REFERENCE_BLOCK)),
+                    duration,
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// The length of an unknown-size cluster's children, which run until the
+/// next top-level element or the end of the segment.
+fn unknown_cluster_length(data: &[u8]) -> Result<usize> {
+    let mut at = 0;
+    while at < data.len() {
+        let (id, size, header) = read_header(&data[at..])?;
+        if TOP_LEVEL.contains(&id) {
+            break;
+        }
+        let size = size.ok_or_else(|| anyhow!("cluster element {id:#X} has an unknown size"))?;
+        at = usize::try_from(size)
+            .ok()
+            .and_then(|size| (at + header).checked_add(size))
+            .filter(|&end| end <= data.len())
+            .ok_or_else(|| anyhow!("cluster element {id:#X} runs past the end of the file"))?;
+    }
+    Ok(at)
+}
+
+/// The children of a master element, each with its payload.
+fn children(mut data: &[u8]) -> Result<Vec<(u32, &[u8])>> {
+    let mut out = Vec::new();
+    while !data.is_empty() {
+        let (id, size, header) = read_header(data)?;
+        data = &data[header..];
+        let payload = take_payload(&mut data, size, "element")?;
+        out.push((id, payload));
+    }
+    Ok(out)
+}
+
+fn take_payload<'a>(data: &mut &'a [u8], size: Option<u64>, what: &str) -> Result<&'a [u8]> {
+    let size = size.ok_or_else(|| anyhow!("Matroska {what} has an unknown size"))?;
+    let size = usize::try_from(size)
+        .ok()
+        .filter(|&size| size <= data.len())
+        .ok_or_else(|| anyhow!("Matroska {what} runs past the end of the file"))?;
+    let (payload, rest) = data.split_at(size);
+    *data = rest;
+    Ok(payload)
+}
+
+struct Vint {
+    /// The value with its length marker removed.
+    value: u64,
+    /// The raw bytes, marker included, as element IDs are written.
+    raw: u64,
+    /// All value bits set, which marks an unknown size.
+    all_ones: bool,
+}
+
+/// Reads an EBML variable-length integer, returning it and its length.
+fn read_vint(data: &[u8]) -> Result<(Vint, usize)> {
+    let first = *data
+        .first()
+        .ok_or_else(|| anyhow!("unexpected end of Matroska data"))?;
+    if first == 0 {
+        bail!("invalid EBML variable-length integer");
+    }
+    let length = first.leading_zeros() as usize + 1;
+    let bytes = data
+        .get(..length)
+        .ok_or_else(|| anyhow!("unexpected end of Matroska data"))?;
+    let raw = bytes
+        .iter()
+        .fold(0u64, |value, &byte| value << 8 | u64::from(byte));
+    let mask = (1u64 << (7 * length)) - 1;
+    let value = raw & mask;
+    Ok((
+        Vint {
+            value,
+            raw,
+            all_ones: value == mask,
+        },
+        length,
+    ))
+}
+
+/// An element's ID, its size (`None` when unknown) and its header length.
+fn read_header(data: &[u8]) -> Result<(u32, Option<u64>, usize)> {
+    let (id, id_length) = read_vint(data)?;
+    if id_length > 4 {
+        bail!("invalid Matroska element ID");
+    }
+    let (size, size_length) = read_vint(&data[id_length..])?;
+    let size = (!size.all_ones).then_some(size.value);
+    Ok((id.raw as u32, size, id_length + size_length))
+}
+
+/// The clockwise display rotation of a rectangular projection's roll; 0
+/// for other projections and angles.
+fn projection_rotation(data: &[u8]) -> Result<u16> {
+    let mut rectangular = true;
+    let mut roll = 0.0;
+    for (id, payload) in children(data)? {
+        match id {
+            PROJECTION_TYPE => rectangular = read_uint(payload)? == 0,
+            PROJECTION_POSE_ROLL => roll = read_float(payload)?,
+            _ => {}
+        }
+    }
+    let clockwise = (-roll).rem_euclid(360.0);
+    Ok(match clockwise {
+        90.0 | 180.0 | 270.0 if rectangular => clockwise as u16,
+        _ => 0,
+    })
+}
+
+/// The HDR metadata of a `Colour` element. A mastering display missing
+/// any of its coordinates or luminances is left out.
+fn colour_hdr(data: &[u8]) -> Result<HdrMetadata> {
+    let mut hdr = HdrMetadata::default();
+    let (mut max_cll, mut max_fall) = (None, None);
+    for (id, payload) in children(data)? {
+        match id {
+            MAX_CLL => max_cll = Some(read_uint(payload)?),
+            MAX_FALL => max_fall = Some(read_uint(payload)?),
+            MASTERING_METADATA => {
+                let mut values = [None; 10];
+                for (id, payload) in children(payload)? {
+                    if let Some(value) = id
+                        .checked_sub(PRIMARY_R_CHROMATICITY_X)
+                        .and_then(|index| values.get_mut(index as usize))
+                    {
+                        *value = Some(read_float(payload)?);
+                    }
+                }
+                if let Some(values) = values.into_iter().collect::<Option<Vec<f64>>>() {
+                    let coordinate = |index: usize| {
+                        units(values[index], MasteringDisplay::CHROMATICITY_UNIT)
+                            .min(u32::from(u16::MAX)) as u16
+                    };
+                    hdr.mastering_display = Some(MasteringDisplay {
+                        primaries: [0, 2, 4].map(|at| [coordinate(at), coordinate(at + 1)]),
+                        white_point: [coordinate(6), coordinate(7)],
+                        max_luminance: units(values[8], MasteringDisplay::LUMINANCE_UNIT),
+                        min_luminance: units(values[9], MasteringDisplay::LUMINANCE_UNIT),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    if max_cll.is_some() || max_fall.is_some() {
+        hdr.content_light = Some(ContentLightLevel {
+            max_cll: max_cll.unwrap_or(0).min(u64::from(u16::MAX)) as u16,
+            max_fall: max_fall.unwrap_or(0).min(u64::from(u16::MAX)) as u16,
+        });
+    }
+    Ok(hdr)
+}
+
+/// `value` in whole multiples of `unit`, saturating.
+fn units(value: f64, unit: f64) -> u32 {
+    (value / unit).round() as u32
+}
+
+fn read_float(data: &[u8]) -> Result<f64> {
+    match data.len() {
+        0 => Ok(0.0),
+        4 => Ok(f64::from(f32::from_be_bytes(data.try_into()?))),
+        8 => Ok(f64::from_be_bytes(data.try_into()?)),
+        length => bail!("Matroska float of {length} bytes"),
+    }
+}
+
+fn read_uint(data: &[u8]) -> Result<u64> {
+    if data.len() > 8 {
+        bail!("Matroska integer is longer than 8 bytes");
+    }
+    Ok(data
+        .iter()
+        .fold(0u64, |value, &byte| value << 8 | u64::from(byte)))
+}
+
+/// The frame rate of frames `period` nanoseconds apart.
+fn frame_rate(period: u64) -> FrameRate {
+    if period == 0 {
+        return FrameRate::Variable;
+    }
+    let (mut a, mut b) = (1_000_000_000u64, period);
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    match (u32::try_from(1_000_000_000 / a), u32::try_from(period / a)) {
+        (Ok(numerator), Ok(denominator)) => FrameRate::Constant {
+            numerator,
+            denominator,
+        },
+        _ => FrameRate::Variable,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::video::h264::{EncodedFrame, EncodedStream};
+    use crate::video::{AudioBuffer, AudioCodec, AudioPacket, AudioStream, ChannelLayout};
+
+    /// Reads one element at `at`: its ID, payload range and the end.
+    fn read_element(data: &[u8], at: usize) -> (u32, std::ops::Range<usize>) {
+        let id_length = data[at].leading_zeros() as usize + 1;
+        let id = data[at..at + id_length]
+            .iter()
+            .fold(0u32, |id, &byte| id << 8 | u32::from(byte));
+        let size_at = at + id_length;
+        let size_length = data[size_at].leading_zeros() as usize + 1;
+        let size = data[size_at..size_at + size_length]
+            .iter()
+            .enumerate()
+            .fold(0u64, |size, (index, &byte)| {
+                let byte = if index == 0 {
+                    byte & (0xFF >> size_length)
+                } else {
+                    byte
+                };
+                size << 8 | u64::from(byte)
+            });
+        let start = size_at + size_length;
+        (id, start..start + size as usize)
+    }
+
+    fn children(data: &[u8], range: std::ops::Range<usize>) -> Vec<(u32, std::ops::Range<usize>)> {
+        let mut out = Vec::new();
+        let mut at = range.start;
+        while at < range.end {
+            let (id, payload) = read_element(data, at);
+            at = payload.end;
+            out.push((id, payload));
+        }
+        out
+    }
+
+    fn find(elements: &[(u32, std::ops::Range<usize>)], id: u32) -> Vec<std::ops::Range<usize>> {
+        elements
+            .iter()
+            .filter(|(element, _)| *element == id)
+            .map(|(_, range)| range.clone())
+            .collect()
+    }
+
+    #[test]
+    fn writes_tracks_clusters_and_cues() {
+        let frames = (0..3)
+            .map(|index| EncodedFrame {
+                nal_units: vec![vec![0x65, index]],
+                timestamp: Duration::from_millis(40 * u64::from(index)),
+                duration: Duration::from_millis(40),
+                keyframe: true,
+            })
+            .collect();
+        let video = EncodedStream {
+            width: 32,
+            height: 16,
+            frame_rate: FrameRate::Constant {
+                numerator: 25,
+                denominator: 1,
+            },
+            sps: vec![0x67, 0x42, 0xC0, 0x0A],
+            pps: vec![0x68, 0x02],
+            frames,
+            rotation: 0,
+            hdr: HdrMetadata::default(),
+        };
+        let audio = AudioStream {
+            codec: AudioCodec::PcmF32,
+            buffers: vec![AudioBuffer {
+                sample_rate: 8_000,
+                channel_layout: ChannelLayout::Stereo,
+                samples: vec![0.5; 2 * 1500],
+            }],
+        };
+        let audio = crate::audio::pcm::float_packets(&audio).unwrap();
+        let data = write_matroska(&video.to_video(), Some(&audio), DocType::Matroska).unwrap();
+
+        let top = children(&data, 0..data.len());
+        assert_eq!(
+            top.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            [EBML, SEGMENT]
+        );
+        let header = children(&data, top[0].1.clone());
+        let doc_type = &find(&header, DOC_TYPE)[0];
+        assert_eq!(&data[doc_type.clone()], b"matroska");
+
+        let segment_start = top[1].1.start;
+        let segment = children(&data, top[1].1.clone());
+        let ids: Vec<u32> = segment.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [SEEK_HEAD, INFO, TRACKS, CLUSTER, CUES]);
+
+        let entries = children(&data, segment[2].1.clone());
+        assert_eq!(entries.len(), 2);
+        let video_entry = children(&data, entries[0].1.clone());
+        let codec = &find(&video_entry, CODEC_ID)[0];
+        assert_eq!(&data[codec.clone()], b"V_MPEG4/ISO/AVC");
+        let private = &find(&video_entry, CODEC_PRIVATE)[0];
+        assert_eq!(&data[private.clone()], video.avcc_record().as_slice());
+        let audio_entry = children(&data, entries[1].1.clone());
+        let codec = &find(&audio_entry, CODEC_ID)[0];
+        assert_eq!(&data[codec.clone()], b"A_PCM/FLOAT/IEEE");
+
+        // Three video blocks and two audio blocks (1024 + 476 frames).
+        let cluster = children(&data, segment[3].1.clone());
+        let blocks = find(&cluster, SIMPLE_BLOCK);
+        assert_eq!(blocks.len(), 5);
+        let video_blocks: Vec<&[u8]> = blocks
+            .iter()
+            .map(|range| &data[range.clone()])
+            .filter(|block| block[0] == 0x81)
+            .collect();
+        assert_eq!(video_blocks[1], &[0x81, 0, 40, 0x80, 0, 0, 0, 2, 0x65, 1]);
+        let audio_bytes: usize = blocks
+            .iter()
+            .filter(|range| data[range.start] == 0x82)
+            .map(|range| range.len() - 4)
+            .sum();
+        assert_eq!(audio_bytes, 2 * 1500 * 4);
+
+        // The cue points at the cluster, relative to the segment's data.
+        let cues = children(&data, segment[4].1.clone());
+        let point = children(&data, cues[0].1.clone());
+        let positions = children(&data, find(&point, CUE_TRACK_POSITIONS)[0].clone());
+        let position = &find(&positions, CUE_CLUSTER_POSITION)[0];
+        let position = data[position.clone()]
+            .iter()
+            .fold(0usize, |value, &byte| value << 8 | usize::from(byte));
+        assert_eq!(read_element(&data, segment_start + position).0, CLUSTER);
+
+        let info = serde_json::to_value(probe(&data).unwrap()).unwrap();
+        assert_eq!(info["container"], "matroska");
+        assert_eq!(info["size_bytes"], data.len());
+        let [video_track, audio_track] = info["tracks"].as_array().unwrap().as_slice() else {
+            panic!("expected two tracks: {info}");
+        };
+        assert_eq!(video_track["kind"], "video");
+        assert_eq!(video_track["codec"], "h264");
+        assert_eq!(video_track["codec_tag"], "V_MPEG4/ISO/AVC");
+        assert_eq!(video_track["frame_count"], 3);
+        assert_eq!(video_track["keyframe_count"], 3);
+        assert_eq!(video_track["frame_rate"], 25.0);
+        assert_eq!(
+            (video_track["width"].clone(), video_track["height"].clone()),
+            (32.into(), 16.into())
+        );
+        assert_eq!(audio_track["kind"], "audio");
+        assert_eq!(audio_track["codec"], "pcm_f32");
+        assert_eq!(audio_track["sample_rate"], 8_000);
+        assert_eq!(audio_track["channels"], 2);
+        assert_eq!(audio_track["frame_count"], 2);
+        assert_eq!(audio_track["size_bytes"], 2 * 1500 * 4);
+
+        assert!(write_matroska(&video.to_video(), None, DocType::WebM).is_err());
+    }
+
+    #[test]
+    fn webm_carries_opus_audio_only() {
+        let mut video = EncodedVideo {
+            codec: VideoCodec::Av1,
+            width: 16,
+            height: 16,
+            frame_rate: FrameRate::Constant {
+                numerator: 25,
+                denominator: 1,
+            },
+            config: vec![0x81, 0, 0, 0],
+            samples: Vec::new(),
+            rotation: 0,
+            hdr: HdrMetadata::default(),
+        };
+        video.samples.push(crate::video::EncodedSample {
+            data: vec![0x12, 0],
+            timestamp: Duration::ZERO,
+            duration: Duration::from_millis(40),
+            keyframe: true,
+        });
+        let packet = AudioPacket {
+            data: vec![0x08],
+            frames: 960,
+        };
+        let mut audio = EncodedAudio {
+            codec: AudioCodec::Opus,
+            sample_rate: 48_000,
+            channels: 2,
+            config: b"OpusHead\x01\x02\x38\x01\x80\xBB\0\0\0\0\0".to_vec(),
+            priming: 312,
+            packets: vec![packet.clone(), packet],
+        };
+        let data = write_matroska(&video, Some(&audio), DocType::WebM).unwrap();
+
+        let top = children(&data, 0..data.len());
+        let segment = children(&data, top[1].1.clone());
+        let entries = children(&data, segment[2].1.clone());
+        let audio_entry = children(&data, entries[1].1.clone());
+        let codec = &find(&audio_entry, CODEC_ID)[0];
+        assert_eq!(&data[codec.clone()], b"A_OPUS");
+        let private = &find(&audio_entry, CODEC_PRIVATE)[0];
+        assert_eq!(&data[private.clone()], audio.config.as_slice());
+        // 312 frames at 48 kHz, in nanoseconds.
+        let delay = &find(&audio_entry, CODEC_DELAY)[0];
+        assert_eq!(data[delay.clone()], 6_500_000u32.to_be_bytes()[1..]);
+        let info = serde_json::to_value(probe(&data).unwrap()).unwrap();
+        assert_eq!(info["container"], "webm");
+        assert_eq!(info["tracks"][1]["codec"], "opus");
+        assert_eq!(info["tracks"][1]["frame_count"], 2);
+
+        audio.codec = AudioCodec::PcmF32;
+        assert!(write_matroska(&video, Some(&audio), DocType::WebM).is_err());
+    }
+
+    #[test]
+    fn reads_back_the_written_video_track() {
+        let frames = (0..3)
+            .map(|index| EncodedFrame {
+                nal_units: vec![vec![0x65, index]],
+                timestamp: Duration::from_millis(40 * u64::from(index)),
+                duration: Duration::from_millis(40),
+                keyframe: index == 0,
+            })
+            .collect();
+        let video = EncodedStream {
+            width: 32,
+            height: 16,
+            frame_rate: FrameRate::Constant {
+                numerator: 25,
+                denominator: 1,
+            },
+            sps: vec![0x67, 0x42, 0xC0, 0x0A],
+            pps: vec![0x68, 0x02],
+            frames,
+            rotation: 270,
+            hdr: HdrMetadata {
+                mastering_display: Some(MasteringDisplay {
+                    primaries: [[34000, 16000], [13250, 34500], [7500, 3000]],
+                    white_point: [15635, 16450],
+                    max_luminance: 10_000_000,
+                    min_luminance: 50,
+                }),
+                content_light: Some(ContentLightLevel {
+                    max_cll: 1000,
+                    max_fall: 400,
+                }),
+            },
+        };
+        let data = write_matroska(&video.to_video(), None, DocType::Matroska).unwrap();
+        assert!(is_matroska(&data));
+
+        let track = video_samples(&data).unwrap().unwrap();
+        assert!(matches!(track.codec, VideoCodec::H264));
+        assert_eq!((track.width, track.height), (32, 16));
+        // Stored as a 90 degree counter-clockwise roll.
+        assert_eq!(track.rotation, 270);
+        let roll = float_element(PROJECTION_POSE_ROLL, 90.0);
+        assert!(data.windows(roll.len()).any(|window| window == roll));
+        // Mastering luminances are stored in cd/m².
+        assert_eq!(track.hdr, video.hdr);
+        let peak = float_element(LUMINANCE_MAX, 1000.0);
+        assert!(data.windows(peak.len()).any(|window| window == peak));
+        assert!(matches!(
+            track.frame_rate,
+            FrameRate::Constant {
+                numerator: 25,
+                denominator: 1
+            }
+        ));
+        assert_eq!(
+            track.codec_config.as_deref(),
+            Some(video.avcc_record().as_slice())
+        );
+        assert_eq!(track.samples.len(), 3);
+        assert_eq!(track.samples[2].data, [0, 0, 0, 2, 0x65, 2]);
+        assert_eq!(track.samples[2].timestamp, Duration::from_millis(80));
+        assert_eq!(track.samples[2].duration, Duration::from_millis(40));
+        let keyframes: Vec<bool> = track.samples.iter().map(|s| s.keyframe).collect();
+        assert_eq!(keyframes, [true, false, false]);
+    }
+
+    #[test]
+    fn reads_unknown_size_segments_and_clusters() {
+        let mut header = element(DOC_TYPE, b"webm");
+        header.extend(uint_element(DOC_TYPE_VERSION, 4));
+        let mut file = element(EBML, &header);
+
+        let mut entry = uint_element(TRACK_NUMBER, 1);
+        entry.extend(uint_element(TRACK_TYPE, 1));
+        entry.extend(element(CODEC_ID, b"V_VP9"));
+        let mut settings = uint_element(PIXEL_WIDTH, 64);
+        settings.extend(uint_element(PIXEL_HEIGHT, 48));
+        entry.extend(element(VIDEO, &settings));
+        let mut segment = element(INFO, &uint_element(TIMESTAMP_SCALE, 500_000));
+        segment.extend(element(TRACKS, &element(TRACK_ENTRY, &entry)));
+
+        // A live recording's cluster: unknown size, ended by the next one.
+        segment.extend(id_bytes(CLUSTER));
+        segment.push(0xFF);
+        segment.extend(uint_element(CLUSTER_TIMESTAMP, 100));
+        segment.extend(element(SIMPLE_BLOCK, &[0x81, 0, 0, 0x80, 0xAA]));
+        let mut group = element(BLOCK, &[0x81, 0, 60, 0, 0xBB]);
+        group.extend(element(REFERENCE_BLOCK, &[0xC4]));
+        group.extend(uint_element(BLOCK_DURATION, 30));
+        segment.extend(element(BLOCK_GROUP, &group));
+        let mut cluster = uint_element(CLUSTER_TIMESTAMP, 200);
+        cluster.extend(element(SIMPLE_BLOCK, &[0x81, 0, 0, 0x80, 0xCC]));
+        segment.extend(element(CLUSTER, &cluster));
+
+        file.extend(id_bytes(SEGMENT));
+        file.push(0xFF);
+        file.extend(segment);
+
+        let track = video_samples(&file).unwrap().unwrap();
+        assert!(matches!(track.codec, VideoCodec::Vp9));
+        assert_eq!((track.width, track.height), (64, 48));
+        assert!(matches!(track.frame_rate, FrameRate::Variable));
+        let samples: Vec<(&[u8], u64, u64, bool)> = track
+            .samples
+            .iter()
+            .map(|sample| {
+                (
+                    sample.data,
+                    sample.timestamp.as_millis() as u64,
+                    sample.duration.as_millis() as u64,
+                    sample.keyframe,
+                )
+            })
+            .collect();
+        // Half-millisecond ticks; the last block repeats the previous
+        // duration.
+        assert_eq!(
+            samples,
+            [
+                (&[0xAA][..], 50, 30, true),
+                (&[0xBB][..], 80, 15, false),
+                (&[0xCC][..], 100, 15, true),
+            ]
+        );
+
+        // Laced video blocks are rejected.
+        let laced = [&file[..file.len() - 2], &[0x82, 0xCC]].concat();
+        assert!(video_samples(&laced).is_err());
+        assert!(video_samples(b"\x00\x00\x00\x18ftypisom").is_err());
+    }
+
+    #[test]
+    fn sizes_use_the_shortest_vint() {
+        assert_eq!(size_vint(5), [0x85]);
+        assert_eq!(size_vint(126), [0xFE]);
+        // 127 would be all ones, which means an unknown size.
+        assert_eq!(size_vint(127), [0x40, 0x7F]);
+        assert_eq!(size_vint(0x3FFE), [0x7F, 0xFE]);
+        assert_eq!(size_vint(0x3FFF), [0x20, 0x3F, 0xFF]);
+    }
+}
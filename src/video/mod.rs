@@ -6,11 +6,29 @@
 //! audio representations while the heavy lifting codecs are developed in
 //! subsequent milestones.
 
+pub mod av1;
 pub mod container;
+#[cfg(any(
+    feature = "vp9",
+    feature = "av1",
+    feature = "hevc",
+    feature = "nvdec",
+    feature = "vaapi",
+    feature = "videotoolbox"
+))]
+mod ffmpeg;
 pub mod h264;
+pub mod hevc;
+pub mod hwaccel;
+pub mod matroska;
+pub mod muxer;
+pub mod probe;
+pub mod vp9;
 
 use std::time::Duration;
 
+use anyhow::{Result, anyhow, bail};
+use image::{RgbImage, RgbaImage};
 use serde::Serialize;
 
 /// A decoded video frame.
@@ -25,6 +43,109 @@ pub struct VideoFrame {
     pub keyframe: bool,
 }
 
+impl VideoFrame {
+    /// Converts the frame to 8-bit RGB. YUV planes are taken as limited
+    /// range with the matrix of `color_space` (BT.709 when it is unknown);
+    /// 4:2:0 chroma is upsampled by repeating each sample.
+    pub fn to_rgb(&self, color_space: ColorSpace) -> Result<RgbImage> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let image = match &self.data {
+            FramePlanes::Rgb(data) => RgbImage::from_raw(self.width, self.height, data.clone()),
+            FramePlanes::Rgba(data) => RgbImage::from_raw(
+                self.width,
+                self.height,
+                data.chunks_exact(4)
+                    .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+                    .collect(),
+            ),
+            FramePlanes::Yuv420 { y, u, v } | FramePlanes::Yuv444 { y, u, v } => {
+                let subsampled = matches!(self.data, FramePlanes::Yuv420 { .. });
+                let chroma_width = if subsampled { width.div_ceil(2) } else { width };
+                let chroma_len = if subsampled {
+                    chroma_width * height.div_ceil(2)
+                } else {
+                    width * height
+                };
+                if y.len() < width * height || u.len() < chroma_len || v.len() < chroma_len {
+                    bail!("frame planes are smaller than {width}x{height}");
+                }
+                let (kr, kb) = color_space.luma_weights();
+                let kg = 1.0 - kr - kb;
+                let mut rgb = Vec::with_capacity(width * height * 3);
+                for row in 0..height {
+                    let chroma_row = if subsampled { row / 2 } else { row };
+                    for column in 0..width {
+                        let chroma = chroma_row * chroma_width
+                            + if subsampled { column / 2 } else { column };
+                        let luma = (f32::from(y[row * width + column]) - 16.0) * 255.0 / 219.0;
+                        let cb = (f32::from(u[chroma]) - 128.0) * 255.0 / 224.0;
+                        let cr = (f32::from(v[chroma]) - 128.0) * 255.0 / 224.0;
+                        let red = luma + 2.0 * (1.0 - kr) * cr;
+                        let blue = luma + 2.0 * (1.0 - kb) * cb;
+                        let green = (luma - kr * red - kb * blue) / kg;
+                        rgb.extend(
+                            [red, green, blue].map(|value| value.round().clamp(0.0, 255.0) as u8),
+                        );
+                    }
+                }
+                RgbImage::from_raw(self.width, self.height, rgb)
+            }
+            FramePlanes::ExternalHandle => bail!("frame is a device handle, not in memory"),
+        };
+        image.ok_or_else(|| anyhow!("frame data does not match its {width}x{height} size"))
+    }
+
+    /// A limited range YUV 4:2:0 frame of `image` with the matrix of
+    /// `color_space`, the inverse of [`Self::to_rgb`]. Each chroma sample
+    /// averages its 2x2 block; alpha is dropped.
+    pub fn from_rgba(
+        image: &RgbaImage,
+        color_space: ColorSpace,
+        timestamp: Duration,
+        duration: Duration,
+    ) -> Self {
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+        let (kr, kb) = color_space.luma_weights();
+        let kg = 1.0 - kr - kb;
+        let mut y = Vec::with_capacity(width * height);
+        // Sums of B - Y and R - Y over each chroma block, and its pixel count.
+        let mut blocks = vec![[0.0f32, 0.0, 0.0]; chroma_width * chroma_height];
+        for (row, pixels) in image.rows().enumerate() {
+            for (column, pixel) in pixels.enumerate() {
+                let [red, green, blue] = [0, 1, 2].map(|channel| f32::from(pixel[channel]));
+                let luma = kr * red + kg * green + kb * blue;
+                y.push((16.0 + luma * 219.0 / 255.0).round().clamp(0.0, 255.0) as u8);
+                let block = &mut blocks[row / 2 * chroma_width + column / 2];
+                block[0] += blue - luma;
+                block[1] += red - luma;
+                block[2] += 1.0;
+            }
+        }
+        let chroma = |difference: f32, weight: f32, count: f32| {
+            let value = difference / count / (2.0 * (1.0 - weight));
+            (128.0 + value * 224.0 / 255.0).round().clamp(0.0, 255.0) as u8
+        };
+        let u = blocks
+            .iter()
+            .map(|&[cb, _, count]| chroma(cb, kb, count))
+            .collect();
+        let v = blocks
+            .iter()
+            .map(|&[_, cr, count]| chroma(cr, kr, count))
+            .collect();
+        Self {
+            width: image.width(),
+            height: image.height(),
+            pixel_format: PixelFormat::Yuv420,
+            data: FramePlanes::Yuv420 { y, u, v },
+            timestamp,
+            duration,
+            keyframe: false,
+        }
+    }
+}
+
 /// Supported planar buffer layouts.
 #[derive(Debug, Clone, Serialize)]
 pub enum FramePlanes {
@@ -57,7 +178,7 @@ pub struct AudioBuffer {
     pub samples: Vec<f32>,
 }
 
-#[derive(Debug, Clone, Copy, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub enum ChannelLayout {
     Mono,
     Stereo,
@@ -66,6 +187,31 @@ pub enum ChannelLayout {
     Custom(u8),
 }
 
+impl ChannelLayout {
+    /// The layout of `count` interleaved channels, or `None` when there
+    /// are none or more than 255.
+    pub fn from_channel_count(count: u16) -> Option<Self> {
+        match count {
+            0 => None,
+            1 => Some(Self::Mono),
+            2 => Some(Self::Stereo),
+            6 => Some(Self::Surround51),
+            8 => Some(Self::Surround71),
+            count => u8::try_from(count).ok().map(Self::Custom),
+        }
+    }
+
+    pub fn channel_count(self) -> u16 {
+        match self {
+            Self::Mono => 1,
+            Self::Stereo => 2,
+            Self::Surround51 => 6,
+            Self::Surround71 => 8,
+            Self::Custom(count) => u16::from(count),
+        }
+    }
+}
+
 /// A full set of media streams extracted from an input asset.
 #[derive(Debug, Clone, Default, Serialize)]
 pub struct MediaStreams {
@@ -81,6 +227,39 @@ pub struct VideoStream {
     pub frame_rate: FrameRate,
     pub frames: Vec<VideoFrame>,
     pub color_space: ColorSpace,
+    pub transfer: TransferFunction,
+    /// Clockwise rotation in degrees (0, 90, 180 or 270) players apply to
+    /// the frames for display.
+    pub rotation: u16,
+    /// Static HDR metadata, from the bitstream's SEI messages or the
+    /// container, for the muxers and encoders to write back.
+    pub hdr: HdrMetadata,
+}
+
+/// Coded video ready to mux: one sample per frame in the form MP4 and
+/// Matroska store it (length-prefixed NAL units for H.264, OBUs without a
+/// temporal delimiter for AV1).
+#[derive(Debug, Clone)]
+pub struct EncodedVideo {
+    pub codec: VideoCodec,
+    pub width: u32,
+    pub height: u32,
+    pub frame_rate: FrameRate,
+    /// The decoder configuration record: `avcC` for H.264, `av1C` for AV1.
+    pub config: Vec<u8>,
+    pub samples: Vec<EncodedSample>,
+    /// Display rotation the muxers record, as on [`VideoStream`].
+    pub rotation: u16,
+    /// HDR metadata the muxers record, as on [`VideoStream`].
+    pub hdr: HdrMetadata,
+}
+
+#[derive(Debug, Clone)]
+pub struct EncodedSample {
+    pub data: Vec<u8>,
+    pub timestamp: Duration,
+    pub duration: Duration,
+    pub keyframe: bool,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -89,6 +268,28 @@ pub struct AudioStream {
     pub buffers: Vec<AudioBuffer>,
 }
 
+/// An audio stream as packets, the way containers store it.
+#[derive(Debug, Clone)]
+pub struct EncodedAudio {
+    pub codec: AudioCodec,
+    /// The rate packet lengths count in: 48 kHz for Opus, whatever its
+    /// input rate.
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// The codec's setup header: `OpusHead` for Opus, empty for PCM.
+    pub config: Vec<u8>,
+    /// Frames of encoder delay at the start that decoders drop.
+    pub priming: u32,
+    pub packets: Vec<AudioPacket>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AudioPacket {
+    pub data: Vec<u8>,
+    /// Sample frames of audio the packet holds.
+    pub frames: u32,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct SubtitleStream {
     pub codec: SubtitleCodec,
@@ -108,7 +309,7 @@ pub enum FrameRate {
     Variable,
 }
 
-#[derive(Debug, Clone, Copy, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub enum ColorSpace {
     Bt601,
     Bt709,
@@ -117,6 +318,124 @@ pub enum ColorSpace {
     Unknown,
 }
 
+impl ColorSpace {
+    /// The red and blue luma weights (Kr, Kb) of the YUV matrix; BT.709's
+    /// when the space is unknown.
+    pub fn luma_weights(self) -> (f32, f32) {
+        match self {
+            Self::Bt601 => (0.299, 0.114),
+            Self::Bt2020 => (0.2627, 0.0593),
+            Self::Bt709 | Self::Srgb | Self::Unknown => (0.2126, 0.0722),
+        }
+    }
+}
+
+/// How coded sample values map to light.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum TransferFunction {
+    /// The BT.709 / BT.1886 gamma curve of standard dynamic range video.
+    Sdr,
+    /// SMPTE ST 2084 perceptual quantizer (HDR10), absolute up to 10000 nits.
+    Pq,
+    /// ARIB STD-B67 hybrid log-gamma, relative to the display's peak.
+    Hlg,
+}
+
+/// Static HDR metadata (SMPTE ST 2086 and CTA-861.3), in the units H.264
+/// and HEVC SEI messages and the MP4 `mdcv` and `clli` boxes share.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct HdrMetadata {
+    pub mastering_display: Option<MasteringDisplay>,
+    pub content_light: Option<ContentLightLevel>,
+}
+
+impl HdrMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.mastering_display.is_none() && self.content_light.is_none()
+    }
+}
+
+/// The colour volume of the display the content was mastered on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct MasteringDisplay {
+    /// CIE 1931 x and y of the red, green and blue primaries, in units of
+    /// 0.00002.
+    pub primaries: [[u16; 2]; 3],
+    /// CIE 1931 x and y of the white point, in units of 0.00002.
+    pub white_point: [u16; 2],
+    /// Luminance in units of 0.0001 cd/m².
+    pub max_luminance: u32,
+    pub min_luminance: u32,
+}
+
+impl MasteringDisplay {
+    /// Units of the chromaticity coordinates.
+    pub const CHROMATICITY_UNIT: f64 = 0.00002;
+    /// Units of the luminances, in cd/m².
+    pub const LUMINANCE_UNIT: f64 = 0.0001;
+
+    /// Reads the 24-byte payload shared by the H.264/HEVC SEI message and
+    /// the MP4 `mdcv` box, which list the primaries green, blue, red.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        let data = data.get(..24)?;
+        let u16_at = |at: usize| u16::from_be_bytes([data[at], data[at + 1]]);
+        let u32_at = |at: usize| u32::from_be_bytes(data[at..at + 4].try_into().unwrap());
+        let [green, blue, red] = [0, 4, 8].map(|at| [u16_at(at), u16_at(at + 2)]);
+        Some(Self {
+            primaries: [red, green, blue],
+            white_point: [u16_at(12), u16_at(14)],
+            max_luminance: u32_at(16),
+            min_luminance: u32_at(20),
+        })
+    }
+
+    /// The payload [`Self::from_bytes`] reads.
+    pub fn to_bytes(&self) -> [u8; 24] {
+        let [red, green, blue] = self.primaries;
+        let mut out = [0; 24];
+        let values = [green, blue, red, self.white_point].concat();
+        for (chunk, value) in out.chunks_exact_mut(2).zip(values) {
+            chunk.copy_from_slice(&value.to_be_bytes());
+        }
+        out[16..20].copy_from_slice(&self.max_luminance.to_be_bytes());
+        out[20..].copy_from_slice(&self.min_luminance.to_be_bytes());
+        out
+    }
+
+    /// The peak luminance in cd/m² (nits).
+    pub fn max_nits(&self) -> f64 {
+        f64::from(self.max_luminance) * Self::LUMINANCE_UNIT
+    }
+}
+
+/// Content light levels, in cd/m².
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ContentLightLevel {
+    /// MaxCLL: the brightest pixel of the content.
+    pub max_cll: u16,
+    /// MaxFALL: the brightest frame, averaged over its pixels.
+    pub max_fall: u16,
+}
+
+impl ContentLightLevel {
+    /// Reads the 4-byte payload shared by the SEI message and the MP4
+    /// `clli` box.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        let data = data.get(..4)?;
+        Some(Self {
+            max_cll: u16::from_be_bytes([data[0], data[1]]),
+            max_fall: u16::from_be_bytes([data[2], data[3]]),
+        })
+    }
+
+    /// The payload [`Self::from_bytes`] reads.
+    pub fn to_bytes(&self) -> [u8; 4] {
+        let [a, b] = self.max_cll.to_be_bytes();
+        let [c, d] = self.max_fall.to_be_bytes();
+        [a, b, c, d]
+    }
+}
+
 #[derive(Debug, Clone, Copy, Serialize)]
 pub enum VideoCodec {
     Raw,
@@ -127,15 +446,54 @@ pub enum VideoCodec {
     Unknown,
 }
 
+impl VideoCodec {
+    /// The codec's short name, as FFmpeg spells it.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Raw => "rawvideo",
+            Self::H264 => "h264",
+            Self::H265 => "hevc",
+            Self::Vp9 => "vp9",
+            Self::Av1 => "av1",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Serialize)]
 pub enum AudioCodec {
     PcmF32,
+    PcmF64,
+    PcmU8,
     PcmS16,
+    PcmS24,
+    PcmS32,
     Aac,
+    Mp3,
     Opus,
+    Flac,
     Unknown,
 }
 
+impl AudioCodec {
+    /// The codec's short name, after FFmpeg's without the byte order.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::PcmF32 => "pcm_f32",
+            Self::PcmF64 => "pcm_f64",
+            Self::PcmU8 => "pcm_u8",
+            Self::PcmS16 => "pcm_s16",
+            Self::PcmS24 => "pcm_s24",
+            Self::PcmS32 => "pcm_s32",
+            Self::Aac => "aac",
+            Self::Mp3 => "mp3",
+            Self::Opus => "opus",
+            Self::Flac => "flac",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Serialize)]
 pub enum SubtitleCodec {
     Srt,
@@ -143,3 +501,70 @@ pub enum SubtitleCodec {
     Ass,
     Unknown,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(width: u32, height: u32, data: FramePlanes) -> VideoFrame {
+        VideoFrame {
+            width,
+            height,
+            pixel_format: PixelFormat::Yuv420,
+            data,
+            timestamp: Duration::ZERO,
+            duration: Duration::ZERO,
+            keyframe: true,
+        }
+    }
+
+    #[test]
+    fn converts_limited_range_yuv_to_rgb() {
+        // BT.709 white, black and pure red, each filling a 2x2 block.
+        let planes = FramePlanes::Yuv420 {
+            y: [235, 235, 16, 16, 63, 63].repeat(2),
+            u: vec![128, 128, 102],
+            v: vec![128, 128, 240],
+        };
+        let rgb = frame(6, 2, planes).to_rgb(ColorSpace::Bt709).unwrap();
+        assert_eq!(rgb.get_pixel(0, 1).0, [255, 255, 255]);
+        assert_eq!(rgb.get_pixel(3, 0).0, [0, 0, 0]);
+        let red = rgb.get_pixel(5, 1).0;
+        assert!(red[0] >= 253 && red[1] <= 2 && red[2] <= 2, "{red:?}");
+    }
+
+    #[test]
+    fn rgba_round_trips_through_yuv() {
+        let image = RgbaImage::from_fn(5, 3, |x, _| match x {
+            0 | 1 => image::Rgba([255, 255, 255, 255]),
+            2 | 3 => image::Rgba([200, 40, 10, 0]),
+            _ => image::Rgba([0, 0, 0, 255]),
+        });
+        let frame =
+            VideoFrame::from_rgba(&image, ColorSpace::Bt709, Duration::ZERO, Duration::ZERO);
+        let FramePlanes::Yuv420 { y, u, v } = &frame.data else {
+            unreachable!()
+        };
+        assert_eq!((y.len(), u.len(), v.len()), (15, 6, 6));
+        assert_eq!((y[0], u[0], v[0]), (235, 128, 128));
+        let rgb = frame.to_rgb(ColorSpace::Bt709).unwrap();
+        assert_eq!(rgb.get_pixel(1, 2).0, [255, 255, 255]);
+        assert_eq!(rgb.get_pixel(4, 0).0, [0, 0, 0]);
+        let red = rgb.get_pixel(2, 1).0;
+        assert!(
+            red.iter()
+                .zip([200, 40, 10])
+                .all(|(&got, want)| got.abs_diff(want) <= 2)
+        );
+    }
+
+    #[test]
+    fn rejects_planes_smaller_than_the_frame() {
+        let planes = FramePlanes::Yuv444 {
+            y: vec![16; 4],
+            u: vec![128; 3],
+            v: vec![128; 4],
+        };
+        assert!(frame(2, 2, planes).to_rgb(ColorSpace::Bt601).is_err());
+    }
+}
@@ -0,0 +1,785 @@
+//! Minimal ISO-BMFF (MP4) writer for encoded H.264 and AV1 streams.
+//!
+//! Produces a progressive file with one `avc1` or `av01` video track:
+//! `ftyp`, a single `mdat` chunk of samples, then `moov` with the sample
+//! tables pointing into it. The fragmented form instead puts empty sample
+//! tables in `moov` and follows it with `moof`/`mdat` pairs. HDR metadata
+//! goes in `mdcv` and `clli` boxes after the decoder configuration.
+//!
+//! AV1 streams can also be written as an AVIF image sequence: the
+//! progressive layout under the `avis` brand, with a `pict` track whose
+//! repeating edit list says how many times the animation plays.
+
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+
+use crate::video::{EncodedVideo, FrameRate, VideoCodec};
+
+/// Movie header timescale (milliseconds).
+const MOVIE_TIMESCALE: u32 = 1000;
+/// Media timescale for variable frame rate streams.
+const VARIABLE_TIMESCALE: u32 = 90_000;
+
+/// `trun` sample flags of a sync sample, which depends on no other.
+const SYNC_SAMPLE_FLAGS: u32 = 0x0200_0000;
+/// `trun` sample flags of a sample that depends on others and is not sync.
+const NON_SYNC_SAMPLE_FLAGS: u32 = 0x0101_0000;
+
+/// How the movie box describes the track.
+#[derive(Debug, Clone, Copy)]
+enum Layout {
+    Progressive,
+    Fragmented,
+    /// An AVIF image sequence played `plays` times, or forever.
+    ImageSequence {
+        plays: Option<u32>,
+    },
+}
+
+/// Writes `stream` as an MP4 file.
+pub fn write_mp4(stream: &EncodedVideo) -> Result<Vec<u8>> {
+    write_progressive(stream, Layout::Progressive)
+}
+
+/// Writes the AV1 `stream` as an animated AVIF (an `avis` image sequence)
+/// that plays `plays` times, or forever when it is `None`.
+pub fn write_avif_sequence(stream: &EncodedVideo, plays: Option<u32>) -> Result<Vec<u8>> {
+    if !matches!(stream.codec, VideoCodec::Av1) {
+        bail!("AVIF image sequences carry AV1, not {:?}", stream.codec);
+    }
+    if plays == Some(0) {
+        bail!("an AVIF image sequence plays at least once");
+    }
+    write_progressive(stream, Layout::ImageSequence { plays })
+}
+
+fn write_progressive(stream: &EncodedVideo, layout: Layout) -> Result<Vec<u8>> {
+    check_stream(stream)?;
+
+    let mut samples = Vec::new();
+    let mut sample_sizes = Vec::with_capacity(stream.samples.len());
+    for sample in &stream.samples {
+        sample_sizes.push(sample.data.len() as u32);
+        samples.extend_from_slice(&sample.data);
+    }
+
+    let (timescale, deltas) = sample_deltas(stream);
+    let mut out = match layout {
+        // No `avif` or `mif1` brand: those promise a still image in `meta`.
+        Layout::ImageSequence { .. } => ftyp(b"avis", 0, [b"avis", b"msf1", b"iso8", b"av01"]),
+        _ => ftyp(
+            b"isom",
+            0x200,
+            [b"isom", b"iso2", codec_brand(stream), b"mp41"],
+        ),
+    };
+
+    // A payload over 4 GiB needs the 64-bit `largesize` form.
+    let mdat_header = if samples.len() + 8 > u32::MAX as usize {
+        let mut header = 1u32.to_be_bytes().to_vec();
+        header.extend_from_slice(b"mdat");
+        header.extend_from_slice(&(samples.len() as u64 + 16).to_be_bytes());
+        header
+    } else {
+        let mut header = ((samples.len() + 8) as u32).to_be_bytes().to_vec();
+        header.extend_from_slice(b"mdat");
+        header
+    };
+    let chunk_offset = (out.len() + mdat_header.len()) as u32;
+    out.extend_from_slice(&mdat_header);
+    out.extend_from_slice(&samples);
+
+    let stbl = [
+        atom(b"stsd", &stsd(stream, layout)),
+        atom(b"stts", &stts(&deltas)),
+        stss(stream),
+        atom(b"stsz", &stsz(&sample_sizes)),
+        // Every sample lives in the one chunk.
+        atom(
+            b"stsc",
+            &full_box_entries(&[[1, sample_sizes.len() as u32, 1]]),
+        ),
+        atom(b"stco", &full_box_entries(&[[chunk_offset]])),
+    ]
+    .concat();
+    let media_duration: u64 = deltas.iter().map(|&delta| u64::from(delta)).sum();
+    out.extend_from_slice(&moov(stream, timescale, media_duration, &stbl, layout));
+    Ok(out)
+}
+
+/// Writes `stream` as fragmented MP4 (CMAF style): an initialisation
+/// `moov` whose sample tables are empty, then one `moof`/`mdat` pair per
+/// fragment. A new fragment opens on the first keyframe at least
+/// `fragment_duration` after the current one started.
+pub fn write_fragmented_mp4(stream: &EncodedVideo, fragment_duration: Duration) -> Result<Vec<u8>> {
+    check_stream(stream)?;
+
+    let (timescale, deltas) = sample_deltas(stream);
+    let target = (fragment_duration.as_secs_f64() * f64::from(timescale)).round() as u64;
+    let mut fragments: Vec<(u64, std::ops::Range<usize>)> = Vec::new();
+    let mut time = 0u64;
+    for (index, sample) in stream.samples.iter().enumerate() {
+        match fragments.last_mut() {
+            Some((start, range)) if !sample.keyframe || time - *start < target => {
+                range.end = index + 1;
+            }
+            _ => fragments.push((time, index..index + 1)),
+        }
+        time += u64::from(deltas[index]);
+    }
+
+    let mut out = ftyp(b"iso6", 0, [b"iso6", b"cmfc", codec_brand(stream), b"mp41"]);
+    let stbl = [
+        atom(b"stsd", &stsd(stream, Layout::Fragmented)),
+        atom(b"stts", &full_box_entries::<2>(&[])),
+        atom(b"stsc", &full_box_entries::<3>(&[])),
+        atom(b"stsz", &stsz(&[])),
+        atom(b"stco", &full_box_entries::<1>(&[])),
+    ]
+    .concat();
+    out.extend_from_slice(&moov(stream, timescale, time, &stbl, Layout::Fragmented));
+
+    for (sequence, (start, range)) in fragments.into_iter().enumerate() {
+        let samples = &stream.samples[range.clone()];
+        let entries: Vec<[u32; 3]> = range
+            .clone()
+            .zip(samples)
+            .map(|(index, sample)| {
+                let flags = if sample.keyframe {
+                    SYNC_SAMPLE_FLAGS
+                } else {
+                    NON_SYNC_SAMPLE_FLAGS
+                };
+                [deltas[index], sample.data.len() as u32, flags]
+            })
+            .collect();
+        // The data offset counts from the start of `moof`, whose size does
+        // not depend on it.
+        let moof_len = moof(sequence as u32 + 1, start, 0, &entries).len();
+        out.extend_from_slice(&moof(
+            sequence as u32 + 1,
+            start,
+            moof_len as u32 + 8,
+            &entries,
+        ));
+        let data: Vec<u8> = samples
+            .iter()
+            .flat_map(|sample| sample.data.iter().copied())
+            .collect();
+        out.extend_from_slice(&atom(b"mdat", &data));
+    }
+    Ok(out)
+}
+
+fn check_stream(stream: &EncodedVideo) -> Result<()> {
+    if stream.samples.is_empty() {
+        bail!("no encoded frames to mux");
+    }
+    if !matches!(stream.codec, VideoCodec::H264 | VideoCodec::Av1) {
+        bail!(
+            "MP4 output carries H.264 or AV1 video, not {:?}",
+            stream.codec
+        );
+    }
+    if stream.width > u32::from(u16::MAX) || stream.height > u32::from(u16::MAX) {
+        bail!("{}x{} is too large for MP4", stream.width, stream.height);
+    }
+    Ok(())
+}
+
+/// The compatible brand naming the video codec.
+fn codec_brand(stream: &EncodedVideo) -> &'static [u8; 4] {
+    match stream.codec {
+        VideoCodec::Av1 => b"av01",
+        _ => b"avc1",
+    }
+}
+
+fn ftyp(major: &[u8; 4], minor: u32, compatible: [&[u8; 4]; 4]) -> Vec<u8> {
+    let mut payload = major.to_vec();
+    payload.extend_from_slice(&minor.to_be_bytes());
+    for brand in compatible {
+        payload.extend_from_slice(brand);
+    }
+    atom(b"ftyp", &payload)
+}
+
+/// The movie box around the one video track with sample table `stbl`.
+/// Fragmented files add `mvex` with the track's fragment defaults.
+fn moov(
+    stream: &EncodedVideo,
+    timescale: u32,
+    media_duration: u64,
+    stbl: &[u8],
+    layout: Layout,
+) -> Vec<u8> {
+    let movie_duration = media_duration * u64::from(MOVIE_TIMESCALE) / u64::from(timescale);
+    // Version 0 headers hold 32-bit durations.
+    let (media_duration, movie_duration) = (
+        u32::try_from(media_duration).unwrap_or(u32::MAX),
+        u32::try_from(movie_duration).unwrap_or(u32::MAX),
+    );
+    // An image sequence's track lasts every play, all ones meaning forever.
+    let track_duration = match layout {
+        Layout::ImageSequence { plays } => plays.map_or(u32::MAX, |plays| {
+            u32::try_from(u64::from(movie_duration) * u64::from(plays)).unwrap_or(u32::MAX)
+        }),
+        _ => movie_duration,
+    };
+    let handler = match layout {
+        Layout::ImageSequence { .. } => hdlr(b"pict", "PictureHandler"),
+        _ => hdlr(b"vide", "VideoHandler"),
+    };
+    let minf = [
+        atom(b"vmhd", &[0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]),
+        atom(
+            b"dinf",
+            &atom(
+                b"dref",
+                &[full_box_entries(&[[]]), atom(b"url ", &[0, 0, 0, 1])].concat(),
+            ),
+        ),
+        atom(b"stbl", stbl),
+    ]
+    .concat();
+    let mdia = [
+        atom(b"mdhd", &mdhd(timescale, media_duration)),
+        atom(b"hdlr", &handler),
+        atom(b"minf", &minf),
+    ]
+    .concat();
+    let mut trak = atom(b"tkhd", &tkhd(stream, track_duration));
+    if let Layout::ImageSequence { .. } = layout {
+        // One edit covering a single play, repeated (flag 1) for the rest
+        // of the track: segment_duration, media_time 0 and rate 1.
+        let mut elst = vec![0, 0, 0, 1];
+        for value in [1, movie_duration, 0, 0x0001_0000] {
+            elst.extend_from_slice(&value.to_be_bytes());
+        }
+        trak.extend_from_slice(&atom(b"edts", &atom(b"elst", &elst)));
+    }
+    trak.extend_from_slice(&atom(b"mdia", &mdia));
+    let mut moov = [atom(b"mvhd", &mvhd(track_duration)), atom(b"trak", &trak)].concat();
+    if let Layout::Fragmented = layout {
+        let mut mehd = vec![0; 4];
+        mehd.extend_from_slice(&movie_duration.to_be_bytes());
+        // track_ID, then the default description index, duration, size and
+        // flags of its samples.
+        let trex: Vec<u8> = [0u32, 1, 1, 0, 0, 0]
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+        let mvex = [atom(b"mehd", &mehd), atom(b"trex", &trex)].concat();
+        moov.extend_from_slice(&atom(b"mvex", &mvex));
+    }
+    atom(b"moov", &moov)
+}
+
+/// A movie fragment of samples described by `[duration, size, flags]`,
+/// starting at decode time `start`, whose data begins `data_offset` bytes
+/// after the start of the `moof`.
+fn moof(sequence: u32, start: u64, data_offset: u32, entries: &[[u32; 3]]) -> Vec<u8> {
+    let mut mfhd = vec![0; 4];
+    mfhd.extend_from_slice(&sequence.to_be_bytes());
+    // default-base-is-moof, for track 1.
+    let tfhd = [0, 0x02, 0, 0, 0, 0, 0, 1];
+    let mut tfdt = vec![1, 0, 0, 0];
+    tfdt.extend_from_slice(&start.to_be_bytes());
+    // data-offset, sample-duration, sample-size and sample-flags present.
+    let mut trun = vec![0, 0, 0x07, 0x01];
+    trun.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+    trun.extend_from_slice(&data_offset.to_be_bytes());
+    for value in entries.iter().flatten() {
+        trun.extend_from_slice(&value.to_be_bytes());
+    }
+    let traf = [
+        atom(b"tfhd", &tfhd),
+        atom(b"tfdt", &tfdt),
+        atom(b"trun", &trun),
+    ]
+    .concat();
+    atom(
+        b"moof",
+        &[atom(b"mfhd", &mfhd), atom(b"traf", &traf)].concat(),
+    )
+}
+
+fn atom(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 8);
+    out.extend_from_slice(&((payload.len() + 8) as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(payload);
+    out
+}
+
+/// A version 0 full box body: an entry count followed by the entries.
+fn full_box_entries<const N: usize>(entries: &[[u32; N]]) -> Vec<u8> {
+    let mut out = vec![0; 4];
+    out.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+    for value in entries.iter().flatten() {
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+    out
+}
+
+/// The media timescale and each sample's duration in it. Constant rates
+/// count in frame periods; variable ones run from each sample's timestamp
+/// to the next, so the file keeps the source timeline, and the last sample
+/// keeps its own duration.
+fn sample_deltas(stream: &EncodedVideo) -> (u32, Vec<u32>) {
+    match stream.frame_rate {
+        FrameRate::Constant {
+            numerator,
+            denominator,
+        } if numerator > 0 && denominator > 0 => {
+            (numerator, vec![denominator; stream.samples.len()])
+        }
+        _ => {
+            // Rounding each timestamp rather than each delta keeps the
+            // error from adding up over the stream.
+            let ticks = |time: Duration| {
+                (time.as_secs_f64() * f64::from(VARIABLE_TIMESCALE)).round() as u64
+            };
+            let ends = stream
+                .samples
+                .iter()
+                .skip(1)
+                .map(|next| ticks(next.timestamp))
+                .chain(
+                    stream
+                        .samples
+                        .last()
+                        .map(|last| ticks(last.timestamp + last.duration)),
+                );
+            let deltas = stream
+                .samples
+                .iter()
+                .zip(ends)
+                .map(|(sample, end)| {
+                    let delta = end.saturating_sub(ticks(sample.timestamp));
+                    u32::try_from(delta).unwrap_or(u32::MAX).max(1)
+                })
+                .collect();
+            (VARIABLE_TIMESCALE, deltas)
+        }
+    }
+}
+
+/// The identity transformation matrix of `mvhd` and `tkhd`.
+fn unity_matrix() -> Vec<u8> {
+    [0x0001_0000u32, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000]
+        .iter()
+        .flat_map(|value| value.to_be_bytes())
+        .collect()
+}
+
+fn mvhd(duration: u32) -> Vec<u8> {
+    let mut out = vec![0; 12]; // version, flags, creation and modification time
+    out.extend_from_slice(&MOVIE_TIMESCALE.to_be_bytes());
+    out.extend_from_slice(&duration.to_be_bytes());
+    out.extend_from_slice(&0x0001_0000u32.to_be_bytes()); // rate 1.0
+    out.extend_from_slice(&0x0100u16.to_be_bytes()); // volume 1.0
+    out.extend_from_slice(&[0; 10]);
+    out.extend_from_slice(&unity_matrix());
+    out.extend_from_slice(&[0; 24]); // pre_defined
+    out.extend_from_slice(&2u32.to_be_bytes()); // next_track_ID
+    out
+}
+
+/// The `tkhd` matrix turning `stream`'s frames clockwise by its rotation,
+/// translated back into view as players expect.
+fn rotation_matrix(stream: &EncodedVideo) -> Vec<u8> {
+    const ONE: u32 = 0x1_0000;
+    let minus_one = ONE.wrapping_neg();
+    let (width, height) = (stream.width << 16, stream.height << 16);
+    let [a, b, c, d, x, y] = match stream.rotation {
+        90 => [0, ONE, minus_one, 0, height, 0],
+        180 => [minus_one, 0, 0, minus_one, width, height],
+        270 => [0, minus_one, ONE, 0, 0, width],
+        _ => return unity_matrix(),
+    };
+    [a, b, 0, c, d, 0, x, y, 0x4000_0000]
+        .iter()
+        .flat_map(|value| value.to_be_bytes())
+        .collect()
+}
+
+fn tkhd(stream: &EncodedVideo, duration: u32) -> Vec<u8> {
+    // Version 0; flags: enabled, in movie.
+    let mut out = vec![0, 0, 0, 3];
+    out.extend_from_slice(&[0; 8]);
+    out.extend_from_slice(&1u32.to_be_bytes()); // track_ID
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&duration.to_be_bytes());
+    out.extend_from_slice(&[0; 8]);
+    out.extend_from_slice(&[0; 8]); // layer, alternate_group, volume
+    out.extend_from_slice(&rotation_matrix(stream));
+    // The track's display size, after the rotation.
+    let (width, height) = match stream.rotation {
+        90 | 270 => (stream.height, stream.width),
+        _ => (stream.width, stream.height),
+    };
+    out.extend_from_slice(&(width << 16).to_be_bytes());
+    out.extend_from_slice(&(height << 16).to_be_bytes());
+    out
+}
+
+fn mdhd(timescale: u32, duration: u32) -> Vec<u8> {
+    let mut out = vec![0; 12];
+    out.extend_from_slice(&timescale.to_be_bytes());
+    out.extend_from_slice(&duration.to_be_bytes());
+    out.extend_from_slice(&0x55C4u16.to_be_bytes()); // language "und"
+    out.extend_from_slice(&[0; 2]);
+    out
+}
+
+fn hdlr(kind: &[u8; 4], name: &str) -> Vec<u8> {
+    let mut out = vec![0; 8];
+    out.extend_from_slice(kind);
+    out.extend_from_slice(&[0; 12]);
+    out.extend_from_slice(name.as_bytes());
+    out.push(0);
+    out
+}
+
+fn stsd(stream: &EncodedVideo, layout: Layout) -> Vec<u8> {
+    let (entry_kind, config_kind) = match stream.codec {
+        VideoCodec::Av1 => (b"av01", b"av1C"),
+        _ => (b"avc1", b"avcC"),
+    };
+    let mut entry = vec![0; 6]; // reserved
+    entry.extend_from_slice(&1u16.to_be_bytes()); // data_reference_index
+    entry.extend_from_slice(&[0; 16]);
+    entry.extend_from_slice(&(stream.width as u16).to_be_bytes());
+    entry.extend_from_slice(&(stream.height as u16).to_be_bytes());
+    entry.extend_from_slice(&0x0048_0000u32.to_be_bytes()); // 72 dpi
+    entry.extend_from_slice(&0x0048_0000u32.to_be_bytes());
+    entry.extend_from_slice(&[0; 4]);
+    entry.extend_from_slice(&1u16.to_be_bytes()); // frame_count
+    entry.extend_from_slice(&[0; 32]); // compressorname
+    entry.extend_from_slice(&0x0018u16.to_be_bytes()); // depth
+    entry.extend_from_slice(&(-1i16).to_be_bytes());
+    entry.extend_from_slice(&atom(config_kind, &stream.config));
+    // HDR metadata follows the configuration, in the SEI messages' layout.
+    if let Some(display) = &stream.hdr.mastering_display {
+        entry.extend_from_slice(&atom(b"mdcv", &display.to_bytes()));
+    }
+    if let Some(light) = &stream.hdr.content_light {
+        entry.extend_from_slice(&atom(b"clli", &light.to_bytes()));
+    }
+    // Image sequences declare their coding constraints: frames may use
+    // intra prediction and up to 15 reference frames.
+    if let Layout::ImageSequence { .. } = layout {
+        let mut ccst = vec![0; 4];
+        ccst.extend_from_slice(&0x7C00_0000u32.to_be_bytes());
+        entry.extend_from_slice(&atom(b"ccst", &ccst));
+    }
+
+    let mut out = full_box_entries::<0>(&[[]]);
+    out.extend_from_slice(&atom(entry_kind, &entry));
+    out
+}
+
+/// Time-to-sample runs of equal durations.
+fn stts(deltas: &[u32]) -> Vec<u8> {
+    let mut runs: Vec<[u32; 2]> = Vec::new();
+    for &delta in deltas {
+        match runs.last_mut() {
+            Some([count, last]) if *last == delta => *count += 1,
+            _ => runs.push([1, delta]),
+        }
+    }
+    full_box_entries(&runs)
+}
+
+/// Sync samples, left out when every sample is one.
+fn stss(stream: &EncodedVideo) -> Vec<u8> {
+    if stream.samples.iter().all(|sample| sample.keyframe) {
+        return Vec::new();
+    }
+    let sync: Vec<[u32; 1]> = stream
+        .samples
+        .iter()
+        .enumerate()
+        .filter(|(_, sample)| sample.keyframe)
+        .map(|(index, _)| [index as u32 + 1])
+        .collect();
+    atom(b"stss", &full_box_entries(&sync))
+}
+
+fn stsz(sizes: &[u32]) -> Vec<u8> {
+    let mut out = vec![0; 8]; // version, flags and sample_size 0
+    out.extend_from_slice(&(sizes.len() as u32).to_be_bytes());
+    for size in sizes {
+        out.extend_from_slice(&size.to_be_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::h264::{EncodedFrame, EncodedStream};
+    use crate::video::{ContentLightLevel, HdrMetadata, MasteringDisplay};
+
+    fn read_u32(data: &[u8], at: usize) -> u32 {
+        u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    /// Top-level boxes as `(kind, payload offset, payload length)`.
+    fn boxes(data: &[u8]) -> Vec<(String, usize, usize)> {
+        let mut out = Vec::new();
+        let mut at = 0;
+        while at < data.len() {
+            let size = read_u32(data, at) as usize;
+            let kind = String::from_utf8_lossy(&data[at + 4..at + 8]).to_string();
+            out.push((kind, at + 8, size - 8));
+            at += size;
+        }
+        out
+    }
+
+    fn find(data: &[u8], kind: &[u8; 4]) -> usize {
+        data.windows(4).position(|window| window == kind).unwrap() + 4
+    }
+
+    fn stream(frames: Vec<(Vec<Vec<u8>>, bool)>) -> EncodedVideo {
+        EncodedStream {
+            width: 32,
+            height: 16,
+            frame_rate: FrameRate::Constant {
+                numerator: 25,
+                denominator: 1,
+            },
+            sps: vec![0x67, 0x42, 0xC0, 0x0A, 0x01],
+            pps: vec![0x68, 0x02],
+            frames: frames
+                .into_iter()
+                .map(|(nal_units, keyframe)| EncodedFrame {
+                    nal_units,
+                    timestamp: Duration::ZERO,
+                    duration: Duration::from_millis(40),
+                    keyframe,
+                })
+                .collect(),
+            rotation: 0,
+            hdr: HdrMetadata::default(),
+        }
+        .to_video()
+    }
+
+    #[test]
+    fn samples_are_length_prefixed_in_one_chunk() {
+        let stream = stream(vec![
+            (vec![vec![0x65, 1, 2, 3]], true),
+            (vec![vec![0x41, 4], vec![0x41, 5, 6]], false),
+        ]);
+        let data = write_mp4(&stream).unwrap();
+        let top = boxes(&data);
+        let kinds: Vec<&str> = top.iter().map(|(kind, _, _)| kind.as_str()).collect();
+        assert_eq!(kinds, ["ftyp", "mdat", "moov"]);
+
+        let (_, mdat, mdat_len) = top[1];
+        assert_eq!(
+            &data[mdat..mdat + mdat_len],
+            &[
+                0, 0, 0, 4, 0x65, 1, 2, 3, 0, 0, 0, 2, 0x41, 4, 0, 0, 0, 3, 0x41, 5, 6
+            ]
+        );
+        let stco = find(&data, b"stco");
+        assert_eq!(read_u32(&data, stco + 8) as usize, mdat);
+        let stsz = find(&data, b"stsz");
+        assert_eq!(read_u32(&data, stsz + 8), 2);
+        assert_eq!(read_u32(&data, stsz + 12), 8);
+        assert_eq!(read_u32(&data, stsz + 16), 13);
+        // One run of two 1/25 s samples, with only the first one a sync sample.
+        let stts = find(&data, b"stts");
+        assert_eq!(read_u32(&data, stts + 8), 2);
+        assert_eq!(read_u32(&data, stts + 12), 1);
+        let stss = find(&data, b"stss");
+        assert_eq!(read_u32(&data, stss + 4), 1);
+        assert_eq!(read_u32(&data, stss + 8), 1);
+        let avcc = find(&data, b"avcC");
+        assert_eq!(&data[avcc..avcc + 6], &[1, 0x42, 0xC0, 0x0A, 0xFF, 0xE1]);
+    }
+
+    #[test]
+    fn variable_rate_samples_keep_their_timestamps() {
+        let mut video = stream(vec![(vec![vec![0x65, 1]], true); 3]);
+        video.frame_rate = FrameRate::Variable;
+        for (sample, millis) in video.samples.iter_mut().zip([0, 30, 100]) {
+            sample.timestamp = Duration::from_millis(millis);
+        }
+        let data = write_mp4(&video).unwrap();
+        let stts = find(&data, b"stts");
+        assert_eq!(read_u32(&data, stts + 4), 3);
+        let deltas: Vec<u32> = (0..3)
+            .map(|run| read_u32(&data, stts + 12 + run * 8))
+            .collect();
+        assert_eq!(deltas, [2700, 6300, 3600]);
+
+        let track = crate::video::container::video_samples(&data)
+            .unwrap()
+            .unwrap();
+        assert!(matches!(track.frame_rate, FrameRate::Variable));
+        let times: Vec<Duration> = track
+            .samples
+            .iter()
+            .map(|sample| sample.timestamp)
+            .collect();
+        assert_eq!(times, [0, 30, 100].map(Duration::from_millis));
+        assert_eq!(track.samples[2].duration, Duration::from_millis(40));
+    }
+
+    #[test]
+    fn av1_tracks_use_av01_sample_entries() {
+        let mut video = stream(vec![(vec![vec![0x65, 1]], true)]);
+        video.codec = VideoCodec::Av1;
+        video.config = vec![0x81, 0, 0x0C, 0];
+        video.samples[0].data = vec![0x32, 1, 0xAA];
+        let data = write_mp4(&video).unwrap();
+        let top = boxes(&data);
+        assert_eq!(&data[top[0].1 + 16..top[0].1 + 20], b"av01");
+        let (_, mdat, mdat_len) = top[1];
+        assert_eq!(&data[mdat..mdat + mdat_len], &[0x32, 1, 0xAA]);
+        let av1c = find(&data, b"av1C");
+        assert_eq!(&data[av1c..av1c + 4], &[0x81, 0, 0x0C, 0]);
+        assert!(!data.windows(4).any(|window| window == b"avcC"));
+    }
+
+    #[test]
+    fn rotated_tracks_carry_their_matrix_and_display_size() {
+        for rotation in [0, 90, 180, 270] {
+            let mut video = stream(vec![(vec![vec![0x65, 1]], true)]);
+            video.rotation = rotation;
+            let data = write_mp4(&video).unwrap();
+            let tkhd = find(&data, b"tkhd");
+            let (width, height) = if rotation % 180 == 90 {
+                (16, 32)
+            } else {
+                (32, 16)
+            };
+            assert_eq!(read_u32(&data, tkhd + 76), width << 16);
+            assert_eq!(read_u32(&data, tkhd + 80), height << 16);
+            // The sample entry keeps the coded size.
+            let track = crate::video::container::video_samples(&data)
+                .unwrap()
+                .unwrap();
+            assert_eq!((track.width, track.height), (32, 16));
+            assert_eq!(track.rotation, rotation);
+        }
+    }
+
+    #[test]
+    fn hdr_metadata_is_written_to_the_sample_entry() {
+        let mut video = stream(vec![(vec![vec![0x65, 1]], true)]);
+        video.hdr = HdrMetadata {
+            mastering_display: Some(MasteringDisplay {
+                primaries: [[34000, 16000], [13250, 34500], [7500, 3000]],
+                white_point: [15635, 16450],
+                max_luminance: 40_000_000,
+                min_luminance: 10,
+            }),
+            content_light: Some(ContentLightLevel {
+                max_cll: 4000,
+                max_fall: 250,
+            }),
+        };
+        let data = write_mp4(&video).unwrap();
+        let mdcv = find(&data, b"mdcv");
+        assert_eq!(&data[mdcv - 8..mdcv - 4], &32u32.to_be_bytes());
+        assert_eq!(read_u32(&data, mdcv), (13250 << 16) | 34500);
+        let clli = find(&data, b"clli");
+        assert_eq!(&data[clli..clli + 4], &[0x0F, 0xA0, 0, 250]);
+
+        let track = crate::video::container::video_samples(&data)
+            .unwrap()
+            .unwrap();
+        assert_eq!(track.hdr, video.hdr);
+        assert!(track.codec_config.is_some());
+    }
+
+    #[test]
+    fn avif_sequences_repeat_a_picture_track() {
+        let mut video = stream(vec![(vec![vec![0x65, 1]], true); 3]);
+        video.codec = VideoCodec::Av1;
+        video.config = vec![0x81, 0, 0x0C, 0];
+        assert!(write_avif_sequence(&stream(vec![(vec![vec![0x65]], true)]), None).is_err());
+        assert!(write_avif_sequence(&video, Some(0)).is_err());
+
+        let data = write_avif_sequence(&video, Some(2)).unwrap();
+        let top = boxes(&data);
+        assert_eq!(&data[top[0].1..top[0].1 + 4], b"avis");
+        assert!(
+            !data[..top[0].1 + top[0].2]
+                .windows(4)
+                .any(|brand| brand == b"mif1")
+        );
+        let hdlr = find(&data, b"hdlr");
+        assert_eq!(&data[hdlr + 8..hdlr + 12], b"pict");
+        assert!(data.windows(4).any(|window| window == b"ccst"));
+        // Three 40 ms frames played twice.
+        let elst = find(&data, b"elst");
+        assert_eq!(read_u32(&data, elst), 1);
+        assert_eq!(read_u32(&data, elst + 8), 120);
+        assert_eq!(read_u32(&data, find(&data, b"tkhd") + 20), 240);
+        assert_eq!(read_u32(&data, find(&data, b"mvhd") + 16), 240);
+
+        let forever = write_avif_sequence(&video, None).unwrap();
+        assert_eq!(read_u32(&forever, find(&forever, b"tkhd") + 20), u32::MAX);
+        let track = crate::video::container::video_samples(&forever)
+            .unwrap()
+            .unwrap();
+        assert_eq!(track.samples.len(), 3);
+        assert!(
+            !write_mp4(&video)
+                .unwrap()
+                .windows(4)
+                .any(|window| window == b"edts")
+        );
+    }
+
+    #[test]
+    fn fragments_open_on_keyframes_after_the_target_duration() {
+        let stream = stream(vec![
+            (vec![vec![0x65, 1]], true),
+            // Only 40 ms into the first fragment, so it stays there.
+            (vec![vec![0x65, 2]], true),
+            (vec![vec![0x41, 3]], false),
+            (vec![vec![0x65, 4]], true),
+            (vec![vec![0x41, 5]], false),
+        ]);
+        let data = write_fragmented_mp4(&stream, Duration::from_millis(80)).unwrap();
+        let top = boxes(&data);
+        let kinds: Vec<&str> = top.iter().map(|(kind, _, _)| kind.as_str()).collect();
+        assert_eq!(kinds, ["ftyp", "moov", "moof", "mdat", "moof", "mdat"]);
+        assert_eq!(&data[top[0].1..top[0].1 + 4], b"iso6");
+        let (_, moov, moov_len) = top[1];
+        let moov = &data[moov..moov + moov_len];
+        assert_eq!(read_u32(moov, find(moov, b"stsz") + 8), 0);
+        let trex = find(moov, b"trex");
+        assert_eq!(read_u32(moov, trex + 4), 1);
+
+        for (fragment, (sequence, start, count)) in [(1, 0, 3), (2, 3, 2)].into_iter().enumerate() {
+            let (_, moof, moof_len) = top[2 + fragment * 2];
+            let (_, mdat, mdat_len) = top[3 + fragment * 2];
+            let moof_data = &data[moof..moof + moof_len];
+            assert_eq!(read_u32(moof_data, find(moof_data, b"mfhd") + 4), sequence);
+            let tfdt = find(moof_data, b"tfdt");
+            assert_eq!(read_u32(moof_data, tfdt + 8), start);
+            let trun = find(moof_data, b"trun");
+            assert_eq!(read_u32(moof_data, trun + 4), count);
+            // The data offset counts from the moof's size field.
+            let offset = read_u32(moof_data, trun + 8) as usize;
+            assert_eq!(moof - 8 + offset, mdat);
+            // Each entry: duration, size, flags.
+            assert_eq!(read_u32(moof_data, trun + 12), 1);
+            assert_eq!(read_u32(moof_data, trun + 16), 6);
+            assert_eq!(read_u32(moof_data, trun + 20), SYNC_SAMPLE_FLAGS);
+            let last_flags = trun + 12 + 12 * (count as usize - 1) + 8;
+            assert_eq!(read_u32(moof_data, last_flags), NON_SYNC_SAMPLE_FLAGS);
+            assert_eq!(mdat_len, 6 * count as usize);
+        }
+    }
+}
@@ -0,0 +1,306 @@
+//! Describes a media input, ffprobe style, from its container headers and
+//! sample tables alone: no frame is decoded.
+
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use serde::Serialize;
+
+use crate::video::container::{self, VideoSamples};
+use crate::video::{FrameRate, h264, matroska};
+
+/// What [`probe`] finds out about an input.
+#[derive(Debug, Clone, Serialize)]
+pub struct MediaInfo {
+    /// `mp4`, `matroska`, `webm`, or `h264` for a raw Annex B stream.
+    pub container: &'static str,
+    pub size_bytes: u64,
+    /// Seconds; the container's own when it records one, otherwise the
+    /// longest track's.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    /// Bits per second over the whole file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bit_rate: Option<u64>,
+    pub tracks: Vec<TrackInfo>,
+}
+
+/// One track of a [`MediaInfo`].
+#[derive(Debug, Clone, Serialize)]
+pub struct TrackInfo {
+    /// Position among the described tracks, from 0.
+    pub index: usize,
+    pub codec: &'static str,
+    /// The codec as the container names it: a sample entry fourcc for MP4,
+    /// a codec ID for Matroska.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub codec_tag: String,
+    /// Seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bit_rate: Option<u64>,
+    /// Coded frames for video, packets for audio.
+    pub frame_count: u64,
+    pub size_bytes: u64,
+    #[serde(flatten)]
+    pub details: TrackDetails,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum TrackDetails {
+    Video {
+        width: u32,
+        height: u32,
+        /// Frames per second: the signalled rate, or the average over the
+        /// track when frames are not evenly spaced.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        frame_rate: Option<f64>,
+        variable_frame_rate: bool,
+        keyframe_count: u64,
+        /// Clockwise display rotation in degrees.
+        rotation: u16,
+    },
+    Audio {
+        sample_rate: u32,
+        channels: u16,
+    },
+}
+
+impl MediaInfo {
+    pub(crate) fn new(
+        container: &'static str,
+        size_bytes: usize,
+        duration: Option<Duration>,
+        tracks: Vec<TrackInfo>,
+    ) -> Self {
+        let duration = duration.map(|duration| duration.as_secs_f64()).or_else(|| {
+            tracks
+                .iter()
+                .filter_map(|track| track.duration)
+                .reduce(f64::max)
+        });
+        let size_bytes = size_bytes as u64;
+        Self {
+            container,
+            size_bytes,
+            duration,
+            bit_rate: bit_rate(size_bytes, duration),
+            tracks,
+        }
+    }
+}
+
+impl TrackInfo {
+    pub(crate) fn new(
+        index: usize,
+        codec: &'static str,
+        codec_tag: &str,
+        (frame_count, size_bytes): (u64, u64),
+        duration: Option<Duration>,
+        details: TrackDetails,
+    ) -> Self {
+        let duration = duration.map(|duration| duration.as_secs_f64());
+        Self {
+            index,
+            codec,
+            codec_tag: codec_tag.to_string(),
+            duration,
+            bit_rate: bit_rate(size_bytes, duration),
+            frame_count,
+            size_bytes,
+            details,
+        }
+    }
+
+    /// A video track described by its samples. `duration` defaults to the
+    /// span the samples cover.
+    pub(crate) fn video(
+        index: usize,
+        track: &VideoSamples<'_>,
+        codec_tag: &str,
+        duration: Option<Duration>,
+    ) -> Self {
+        let samples = &track.samples;
+        let duration = duration.or_else(|| {
+            let start = samples.iter().map(|sample| sample.timestamp).min()?;
+            let end = samples
+                .iter()
+                .map(|sample| sample.timestamp + sample.duration)
+                .max()?;
+            Some(end.saturating_sub(start))
+        });
+        let frame_count = samples.len() as u64;
+        let size_bytes = samples.iter().map(|sample| sample.data.len() as u64).sum();
+        let (frame_rate, variable_frame_rate) = frame_rate(track.frame_rate, frame_count, duration);
+        Self::new(
+            index,
+            track.codec.name(),
+            codec_tag,
+            (frame_count, size_bytes),
+            duration,
+            TrackDetails::Video {
+                width: track.width,
+                height: track.height,
+                frame_rate,
+                variable_frame_rate,
+                keyframe_count: samples.iter().filter(|sample| sample.keyframe).count() as u64,
+                rotation: track.rotation,
+            },
+        )
+    }
+}
+
+/// Frames per second and whether the rate varies: the signalled constant
+/// rate, or the average of `frame_count` frames over `duration`.
+pub(crate) fn frame_rate(
+    frame_rate: FrameRate,
+    frame_count: u64,
+    duration: Option<Duration>,
+) -> (Option<f64>, bool) {
+    match frame_rate {
+        FrameRate::Constant {
+            numerator,
+            denominator,
+        } if denominator > 0 => (Some(f64::from(numerator) / f64::from(denominator)), false),
+        _ => (
+            duration
+                .filter(|duration| !duration.is_zero())
+                .map(|duration| frame_count as f64 / duration.as_secs_f64()),
+            true,
+        ),
+    }
+}
+
+fn bit_rate(size_bytes: u64, seconds: Option<f64>) -> Option<u64> {
+    seconds
+        .filter(|seconds| *seconds > 0.0)
+        .map(|seconds| (size_bytes as f64 * 8.0 / seconds).round() as u64)
+}
+
+/// Major brands of HEIF and AVIF images, which open with an MP4 `ftyp` box.
+const IMAGE_BRANDS: [&[u8]; 7] = [
+    b"avif", b"avis", b"heic", b"heix", b"heim", b"heis", b"mif1",
+];
+
+/// Whether `head`, the first bytes of a file, opens a video input by its
+/// magic bytes: Matroska/WebM, MP4 other than HEIF/AVIF images, or a raw
+/// H.264 stream whose first NAL unit follows a start code.
+pub fn is_video(head: &[u8]) -> bool {
+    if matroska::is_matroska(head) {
+        return true;
+    }
+    if container::is_mp4(head) {
+        let image = &head[4..8] == b"ftyp"
+            && head
+                .get(8..12)
+                .is_some_and(|brand| IMAGE_BRANDS.contains(&brand));
+        return !image;
+    }
+    let nal_header = if head.starts_with(&[0, 0, 0, 1]) {
+        head.get(4)
+    } else if head.starts_with(&[0, 0, 1]) {
+        head.get(3)
+    } else {
+        None
+    };
+    // Slices, SEI, parameter sets or an access unit delimiter; other bytes
+    // behind a start code (ICO, colour-mapped TGA) are images.
+    nal_header.is_some_and(|&header| header & 0x80 == 0 && matches!(header & 0x1F, 1 | 5..=9))
+}
+
+/// Describes `data`, an MP4, Matroska/WebM or raw H.264 input, from its
+/// headers and sample tables.
+pub fn probe(data: &[u8]) -> Result<MediaInfo> {
+    if matroska::is_matroska(data) {
+        matroska::probe(data)
+    } else if container::is_mp4(data) {
+        container::probe(data)
+    } else if data.starts_with(&[0, 0, 1]) || data.starts_with(&[0, 0, 0, 1]) {
+        Ok(MediaInfo::new(
+            "h264",
+            data.len(),
+            None,
+            vec![h264::probe_annex_b(data)?],
+        ))
+    } else {
+        bail!("not an MP4, Matroska/WebM or raw H.264 input")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::container::Sample;
+    use crate::video::{HdrMetadata, VideoCodec};
+
+    #[test]
+    fn describes_tracks_from_their_samples() {
+        let data = [0u8; 1000];
+        let track = VideoSamples {
+            codec: VideoCodec::H264,
+            width: 64,
+            height: 48,
+            frame_rate: FrameRate::Variable,
+            codec_config: None,
+            samples: (0..4)
+                .map(|index| Sample {
+                    data: &data[..250],
+                    timestamp: Duration::from_millis(index * 500),
+                    decode_time: Duration::from_millis(index * 500),
+                    duration: Duration::from_millis(500),
+                    keyframe: index == 0,
+                })
+                .collect(),
+            rotation: 90,
+            hdr: HdrMetadata::default(),
+        };
+        let info = MediaInfo::new(
+            "mp4",
+            2000,
+            None,
+            vec![TrackInfo::video(0, &track, "avc1", None)],
+        );
+        assert_eq!(info.duration, Some(2.0));
+        assert_eq!(info.bit_rate, Some(8000));
+        let value = serde_json::to_value(&info).unwrap();
+        assert_eq!(
+            value["tracks"][0],
+            serde_json::json!({
+                "index": 0,
+                "kind": "video",
+                "codec": "h264",
+                "codec_tag": "avc1",
+                "duration": 2.0,
+                "bit_rate": 4000,
+                "frame_count": 4,
+                "size_bytes": 1000,
+                "width": 64,
+                "height": 48,
+                "frame_rate": 2.0,
+                "variable_frame_rate": true,
+                "keyframe_count": 1,
+                "rotation": 90,
+            })
+        );
+
+        assert!(probe(b"GIF89a").is_err());
+    }
+
+    #[test]
+    fn sniffs_video_inputs_from_their_magic_bytes() {
+        assert!(is_video(b"\0\0\0\x20ftypisom\0\0\x02\0"));
+        assert!(is_video(b"\0\0\0\x08free\0\0\0\x10moov"));
+        assert!(is_video(&[0x1A, 0x45, 0xDF, 0xA3, 0x9F]));
+        assert!(is_video(&[0, 0, 0, 1, 0x67, 0x42]));
+        assert!(is_video(&[0, 0, 1, 0x09, 0xF0]));
+
+        assert!(!is_video(b"\0\0\0\x1cftypavif\0\0\0\0"));
+        assert!(!is_video(b"\0\0\0\x18ftypheic\0\0\0\0"));
+        // An icon directory: reserved 0, type 1.
+        assert!(!is_video(&[0, 0, 1, 0, 1, 0]));
+        assert!(!is_video(b"\x89PNG\r\n\x1a\n"));
+        assert!(!is_video(&[]));
+    }
+}
@@ -0,0 +1,34 @@
+//! VP9 decoding, through FFmpeg's libavcodec when built with the `vp9`
+//! feature.
+
+use anyhow::Result;
+#[cfg(not(feature = "vp9"))]
+use anyhow::bail;
+
+use crate::video::MediaStreams;
+use crate::video::container::VideoSamples;
+
+/// Decodes the samples of a VP9 track (from WebM, Matroska or MP4) into
+/// `streams.video`.
+#[cfg(feature = "vp9")]
+pub fn decode_samples(track: &VideoSamples<'_>, streams: &mut MediaStreams) -> Result<()> {
+    use crate::video::ffmpeg::{DecoderSpec, decode_samples};
+
+    // A vpcC box only repeats what each frame header says, so libavcodec
+    // takes no extradata for VP9.
+    decode_samples(
+        DecoderSpec {
+            id: ffmpeg_next::codec::Id::VP9,
+            name: None,
+            codec: crate::video::VideoCodec::Vp9,
+            extradata: None,
+        },
+        track,
+        streams,
+    )
+}
+
+#[cfg(not(feature = "vp9"))]
+pub fn decode_samples(_track: &VideoSamples<'_>, _streams: &mut MediaStreams) -> Result<()> {
+    bail!("VP9 decoding requires building with the vp9 feature")
+}
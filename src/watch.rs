@@ -0,0 +1,212 @@
+//! Re-running a recipe as its inputs change.
+//!
+//! `run --watch` watches the literal base directory of every input glob
+//! (recursively when the glob can match below it) and collects the files
+//! that are created or modified. Once no further changes arrive for the
+//! debounce interval, the batch is matched against the recipe's inputs and
+//! only those inputs are converted again. Changes under the output directory
+//! are ignored, so outputs that match an input glob do not retrigger a run.
+
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use ::notify::event::{EventKind, ModifyKind};
+use ::notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use anyhow::{Context, Result, anyhow};
+
+use crate::cancel::CancellationToken;
+use crate::discovery::watch_root;
+use crate::recipe::Recipe;
+
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// How often a blocked [`InputWatcher::wait`] checks for cancellation.
+const CANCEL_POLL: Duration = Duration::from_millis(200);
+
+pub struct InputWatcher {
+    // Dropping the watcher stops the event stream.
+    _watcher: RecommendedWatcher,
+    events: Receiver<::notify::Result<Event>>,
+    roots: Vec<PathBuf>,
+    output_dir: PathBuf,
+    debounce: Duration,
+}
+
+impl InputWatcher {
+    pub fn new(recipe: &Recipe, debounce: Duration) -> Result<Self> {
+        let (tx, events) = mpsc::channel();
+        let mut watcher =
+            ::notify::recommended_watcher(tx).context("Failed to start filesystem watcher")?;
+        let mut roots: Vec<(PathBuf, bool)> = Vec::new();
+        for input in &recipe.inputs {
+            let (root, recursive) = watch_root(&input.path);
+            let root = resolve(&root)?;
+            match roots.iter_mut().find(|(known, _)| *known == root) {
+                Some((_, known_recursive)) => *known_recursive |= recursive,
+                None => roots.push((root, recursive)),
+            }
+        }
+        for (root, recursive) in &roots {
+            let mode = if *recursive {
+                RecursiveMode::Recursive
+            } else {
+                RecursiveMode::NonRecursive
+            };
+            watcher
+                .watch(root, mode)
+                .with_context(|| format!("Failed to watch input directory: {}", root.display()))?;
+        }
+        Ok(Self {
+            _watcher: watcher,
+            events,
+            roots: roots.into_iter().map(|(root, _)| root).collect(),
+            output_dir: resolve(&recipe.output.directory)?,
+            debounce,
+        })
+    }
+
+    /// Directories being watched.
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    /// Blocks until input files change and then stay quiet for the debounce
+    /// interval, returning the changed paths (absolute, sorted). Returns
+    /// `None` once `cancellation` is cancelled.
+    pub fn wait(&self, cancellation: &CancellationToken) -> Result<Option<Vec<PathBuf>>> {
+        let mut changed: Vec<PathBuf> = Vec::new();
+        let mut quiet_since = Instant::now();
+        loop {
+            if cancellation.is_cancelled() {
+                return Ok(None);
+            }
+            let timeout = if changed.is_empty() {
+                CANCEL_POLL
+            } else {
+                self.debounce
+                    .saturating_sub(quiet_since.elapsed())
+                    .min(CANCEL_POLL)
+            };
+            match self.events.recv_timeout(timeout) {
+                Ok(event) => {
+                    let event = event.context("Filesystem watcher failed")?;
+                    if !is_change(&event.kind) {
+                        continue;
+                    }
+                    let before = changed.len();
+                    changed.extend(
+                        event
+                            .paths
+                            .into_iter()
+                            .filter(|path| !path.starts_with(&self.output_dir)),
+                    );
+                    if changed.len() > before {
+                        quiet_since = Instant::now();
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if !changed.is_empty() && quiet_since.elapsed() >= self.debounce {
+                        changed.sort();
+                        changed.dedup();
+                        return Ok(Some(changed));
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(anyhow!("Filesystem watcher stopped unexpectedly"));
+                }
+            }
+        }
+    }
+}
+
+/// The entries of `inputs` that `changed` touches: the changed files
+/// themselves and members of changed archives, in input order.
+pub fn changed_inputs(inputs: &[PathBuf], changed: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut matched = Vec::new();
+    for input in inputs {
+        let path = resolve(input)?;
+        if changed.iter().any(|changed| path.starts_with(changed)) {
+            matched.push(input.clone());
+        }
+    }
+    Ok(matched)
+}
+
+fn is_change(kind: &EventKind) -> bool {
+    match kind {
+        EventKind::Create(_) => true,
+        EventKind::Modify(ModifyKind::Metadata(_)) => false,
+        EventKind::Modify(_) => true,
+        _ => false,
+    }
+}
+
+/// `path` with symlinks resolved where it exists, so it compares equal to the
+/// paths the watcher reports.
+fn resolve(path: &Path) -> Result<PathBuf> {
+    path.canonicalize()
+        .or_else(|_| std::path::absolute(path))
+        .with_context(|| format!("Failed to resolve path: {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::thread;
+
+    use super::*;
+    use crate::pipeline::OutputSpec;
+    use crate::recipe::InputSpec;
+
+    #[test]
+    fn batches_changed_inputs_and_ignores_outputs() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = &temp.path().canonicalize().unwrap();
+        fs::create_dir_all(root.join("out")).unwrap();
+        let recipe = Recipe {
+            version: 1,
+            inputs: vec![InputSpec {
+                path: format!("{}/**/*.png", root.display()),
+                members: None,
+                include_hidden: false,
+            }],
+            pipeline: Vec::new(),
+            output: OutputSpec {
+                directory: root.join("out"),
+                structure: "{stem}.{ext}".to_string(),
+            },
+            quality_gates: Vec::new(),
+            notify: None,
+            overwrite: None,
+        };
+        let watcher = InputWatcher::new(&recipe, Duration::from_millis(100)).unwrap();
+        assert_eq!(watcher.roots(), [root.to_path_buf()]);
+
+        let writer = {
+            let root = root.to_path_buf();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                fs::write(root.join("out/a.png"), b"output").unwrap();
+                fs::write(root.join("a.png"), b"first").unwrap();
+                fs::write(root.join("a.png"), b"second").unwrap();
+            })
+        };
+        let changed = watcher
+            .wait(&CancellationToken::new())
+            .unwrap()
+            .expect("not cancelled");
+        writer.join().unwrap();
+        assert_eq!(changed, vec![root.join("a.png")]);
+
+        let inputs = [root.join("a.png"), root.join("b.png")];
+        assert_eq!(
+            changed_inputs(&inputs, &changed).unwrap(),
+            vec![root.join("a.png")]
+        );
+
+        let cancelled = CancellationToken::new();
+        cancelled.cancel();
+        assert!(watcher.wait(&cancelled).unwrap().is_none());
+    }
+}
@@ -0,0 +1,446 @@
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use bunker_convert::audio::pcm;
+use bunker_convert::cancel::CancellationToken;
+use bunker_convert::collision::OutputClaims;
+use bunker_convert::overwrite::OverwritePolicy;
+use bunker_convert::pipeline::{
+    Artifact, OutputSpec, PipelineContext, StageParameters, StageRegistry,
+};
+use bunker_convert::scheduler::StageDevice;
+use bunker_convert::stages;
+use bunker_convert::video::matroska::{self, DocType};
+use bunker_convert::video::{
+    AudioBuffer, AudioCodec, AudioStream, ChannelLayout, EncodedSample, EncodedVideo, FrameRate,
+    HdrMetadata, VideoCodec,
+};
+use serde_json::{Value, json};
+
+fn registry() -> StageRegistry {
+    let mut registry = StageRegistry::new();
+    stages::register_defaults(&mut registry);
+    registry
+}
+
+fn params(value: Value) -> StageParameters {
+    serde_json::from_value(value).unwrap()
+}
+
+fn context(directory: &Path) -> PipelineContext {
+    PipelineContext {
+        output: OutputSpec {
+            directory: directory.to_path_buf(),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        quality_gates_enabled: false,
+        cancellation: CancellationToken::new(),
+        outputs: OutputClaims::default(),
+        overwrite: OverwritePolicy::default(),
+    }
+}
+
+/// A 16-bit stereo WAV file of `frames` sample frames at 8 kHz: a sawtooth
+/// on the left channel, silence on the right.
+fn wav_file(frames: u16) -> Vec<u8> {
+    let mut data = Vec::new();
+    for frame in 0..frames {
+        data.extend(((frame % 512) as i16 * 64).to_le_bytes());
+        data.extend(0i16.to_le_bytes());
+    }
+    let mut file = b"RIFF".to_vec();
+    file.extend((36 + data.len() as u32).to_le_bytes());
+    file.extend(b"WAVEfmt ");
+    file.extend(16u32.to_le_bytes());
+    file.extend(1u16.to_le_bytes());
+    file.extend(2u16.to_le_bytes());
+    file.extend(8000u32.to_le_bytes());
+    file.extend(32000u32.to_le_bytes());
+    file.extend(4u16.to_le_bytes());
+    file.extend(16u16.to_le_bytes());
+    file.extend(b"data");
+    file.extend((data.len() as u32).to_le_bytes());
+    file.extend(data);
+    file
+}
+
+#[test]
+fn audio_decode_reads_wav_files() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let input = tempdir.path().join("tone.wav");
+    std::fs::write(&input, wav_file(4000))?;
+    let mut artifact = Artifact::load(&input)?;
+
+    let stage = registry().create("audio_decode", StageParameters::new())?;
+    stage.run(&mut artifact, &context(tempdir.path()), StageDevice::Cpu)?;
+
+    let audio = artifact.media().audio.as_ref().expect("audio stream");
+    assert_eq!(audio.codec.name(), "pcm_s16");
+    let buffer = &audio.buffers[0];
+    assert_eq!(buffer.samples.len(), 8000);
+    assert_eq!(buffer.samples[2], 64.0 / 32768.0);
+    assert_eq!(buffer.samples[3], 0.0);
+    assert_eq!(artifact.media().duration.unwrap().as_millis(), 500);
+    assert_eq!(artifact.metadata["audio.sample_rate"], 8000);
+    assert_eq!(artifact.metadata["audio.channels"], 2);
+    assert_eq!(artifact.metadata["audio.frame_count"], 4000);
+    assert_eq!(artifact.metadata["audio.duration"], 0.5);
+    Ok(())
+}
+
+#[test]
+fn audio_decode_reads_raw_pcm_as_configured() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let input = tempdir.path().join("tone.pcm");
+    let samples: Vec<u8> = [0.5f32, -0.5, 0.25]
+        .iter()
+        .flat_map(|sample| sample.to_be_bytes())
+        .collect();
+    std::fs::write(&input, samples)?;
+    let mut artifact = Artifact::load(&input)?;
+
+    let raw =
+        json!({ "format": "raw", "sample_format": "f32be", "sample_rate": 48000, "channels": 1 });
+    let stage = registry().create("audio_decode", params(raw))?;
+    stage.run(&mut artifact, &context(tempdir.path()), StageDevice::Cpu)?;
+    let audio = artifact.media().audio.as_ref().expect("audio stream");
+    assert_eq!(audio.codec.name(), "pcm_f32");
+    assert_eq!(audio.buffers[0].samples, [0.5, -0.5, 0.25]);
+
+    // Headerless input needs the raw format spelled out.
+    let wav = registry().create("audio_decode", StageParameters::new())?;
+    let error = wav
+        .run(&mut artifact, &context(tempdir.path()), StageDevice::Cpu)
+        .unwrap_err();
+    assert!(error.to_string().contains("format: raw"));
+    assert!(
+        registry()
+            .create("audio_decode", params(json!({ "format": "raw" })))
+            .is_err()
+    );
+    assert!(
+        registry()
+            .create(
+                "audio_decode",
+                params(json!({ "format": "raw", "sample_rate": 8000, "sample_format": "s12le" }))
+            )
+            .is_err()
+    );
+    Ok(())
+}
+
+#[test]
+fn audio_encode_writes_wav_files() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let input = tempdir.path().join("tone.wav");
+    std::fs::write(&input, wav_file(100))?;
+    let mut artifact = Artifact::load(&input)?;
+    let ctx = context(&tempdir.path().join("out"));
+
+    let decode = registry().create("audio_decode", StageParameters::new())?;
+    decode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    let encode = registry().create(
+        "audio_encode",
+        params(json!({ "format": "wav", "sample_format": "f32le" })),
+    )?;
+    encode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+
+    let output = tempdir.path().join("out").join("tone.wav");
+    assert_eq!(
+        artifact.metadata["output_path"],
+        output.to_string_lossy().as_ref()
+    );
+    assert_eq!(artifact.metadata["audio.output.codec"], "pcm_f32");
+    let written = std::fs::read(&output)?;
+    assert_eq!(written.len(), 44 + 100 * 2 * 4);
+    assert_eq!(artifact.metadata["output.size_bytes"], written.len());
+    // The second frame's left sample, after the header and the first frame.
+    assert_eq!(written[52..56], (64.0f32 / 32768.0).to_le_bytes());
+
+    assert!(
+        registry()
+            .create("audio_encode", params(json!({ "sample_format": "s16be" })))
+            .is_err()
+    );
+    assert!(
+        registry()
+            .create(
+                "audio_encode",
+                params(json!({ "format": "mp3", "bitrate": 1000 }))
+            )
+            .is_err()
+    );
+    Ok(())
+}
+
+#[test]
+fn audio_encode_round_trips_opus_files() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let input = tempdir.path().join("voice.wav");
+    std::fs::write(&input, wav_file(4000))?;
+    let mut artifact = Artifact::load(&input)?;
+    let ctx = context(&tempdir.path().join("out"));
+
+    let decode = registry().create("audio_decode", StageParameters::new())?;
+    decode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    let encode = registry().create(
+        "audio_encode",
+        params(json!({ "format": "opus", "bitrate": 24 })),
+    )?;
+    let result = encode.run(&mut artifact, &ctx, StageDevice::Cpu);
+    if !cfg!(feature = "opus") {
+        let error = format!("{:#}", result.unwrap_err());
+        assert!(error.contains("opus feature"));
+        return Ok(());
+    }
+    result?;
+    assert_eq!(artifact.metadata["audio.output.codec"], "opus");
+    assert_eq!(artifact.metadata["audio.output.bitrate"], 24);
+
+    // Opus decodes at 48 kHz whatever the input rate, trimmed to its length.
+    let output = tempdir.path().join("out").join("voice.opus");
+    let mut decoded = Artifact::load(&output)?;
+    decode.run(&mut decoded, &ctx, StageDevice::Cpu)?;
+    assert_eq!(decoded.metadata["audio.codec"], "opus");
+    assert_eq!(decoded.metadata["audio.sample_rate"], 48000);
+    assert_eq!(decoded.metadata["audio.frame_count"], 24000);
+    Ok(())
+}
+
+#[test]
+fn audio_encode_checks_bit_rates_per_format() {
+    let create = |value| registry().create("audio_encode", params(value));
+    assert!(create(json!({ "format": "opus", "bitrate": 400 })).is_ok());
+    assert!(create(json!({ "format": "mp3", "bitrate": 400 })).is_err());
+    assert!(create(json!({ "format": "opus", "bitrate": 4 })).is_err());
+}
+
+#[test]
+fn audio_encode_writes_lossless_flac_files() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let input = tempdir.path().join("master.wav");
+    std::fs::write(&input, wav_file(3000))?;
+    let mut artifact = Artifact::load(&input)?;
+    let ctx = context(&tempdir.path().join("out"));
+
+    let decode = registry().create("audio_decode", StageParameters::new())?;
+    decode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    let encode = registry().create(
+        "audio_encode",
+        params(json!({ "format": "flac", "compression_level": 8 })),
+    )?;
+    encode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    assert_eq!(artifact.metadata["audio.output.codec"], "flac");
+    assert_eq!(artifact.metadata["audio.output.bit_depth"], 16);
+    assert_eq!(artifact.metadata["audio.output.compression_level"], 8);
+
+    let output = tempdir.path().join("out").join("master.flac");
+    assert!(std::fs::metadata(&output)?.len() < 3000 * 4);
+    let mut decoded = Artifact::load(&output)?;
+    decode.run(&mut decoded, &ctx, StageDevice::Cpu)?;
+    assert_eq!(decoded.metadata["audio.codec"], "flac");
+    assert_eq!(
+        decoded.media().audio.as_ref().unwrap().buffers[0].samples,
+        artifact.media().audio.as_ref().unwrap().buffers[0].samples
+    );
+
+    let create = |value| registry().create("audio_encode", params(value));
+    assert!(create(json!({ "format": "flac", "compression_level": 9 })).is_err());
+    assert!(create(json!({ "format": "flac", "bit_depth": 32 })).is_err());
+    Ok(())
+}
+
+#[test]
+fn audio_resample_converts_to_the_target_rate() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let input = tempdir.path().join("voice.wav");
+    std::fs::write(&input, wav_file(4000))?;
+    let mut artifact = Artifact::load(&input)?;
+    let ctx = context(tempdir.path());
+
+    let decode = registry().create("audio_decode", StageParameters::new())?;
+    decode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    let resample = registry().create(
+        "audio_resample",
+        params(json!({ "sample_rate": 48000, "quality": "medium" })),
+    )?;
+    resample.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+
+    let buffer = &artifact.media().audio.as_ref().unwrap().buffers[0];
+    assert_eq!(buffer.sample_rate, 48000);
+    assert_eq!(buffer.samples.len(), 2 * 24000);
+    // The silent right channel stays silent.
+    assert!(buffer.samples.iter().skip(1).step_by(2).all(|s| *s == 0.0));
+    assert_eq!(artifact.metadata["audio.sample_rate"], 48000);
+    assert_eq!(artifact.metadata["audio.frame_count"], 24000);
+    assert_eq!(artifact.metadata["audio_resample.source_rate"], 8000);
+    assert_eq!(artifact.metadata["audio_resample.quality"], "medium");
+
+    let create = |value| registry().create("audio_resample", params(value));
+    assert!(create(json!({})).is_err());
+    assert!(create(json!({ "sample_rate": 48000, "quality": "best" })).is_err());
+    Ok(())
+}
+
+#[test]
+fn audio_channels_downmixes_and_records_the_matrix() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let input = tempdir.path().join("tone.wav");
+    std::fs::write(&input, wav_file(100))?;
+    let mut artifact = Artifact::load(&input)?;
+    let ctx = context(tempdir.path());
+
+    let decode = registry().create("audio_decode", StageParameters::new())?;
+    decode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    let left = artifact.media().audio.as_ref().unwrap().buffers[0].samples[2];
+    let mono = registry().create("audio_channels", params(json!({ "layout": "mono" })))?;
+    mono.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+
+    let buffer = &artifact.media().audio.as_ref().unwrap().buffers[0];
+    assert_eq!(buffer.channel_layout.channel_count(), 1);
+    assert_eq!(buffer.samples.len(), 100);
+    assert_eq!(buffer.samples[1], left / 2.0);
+    assert_eq!(artifact.metadata["audio.channels"], 1);
+    assert_eq!(artifact.metadata["audio_channels.source_layout"], "stereo");
+    assert_eq!(
+        artifact.metadata["audio_channels.matrix"],
+        json!([{ "channel": "FC", "mix": { "FL": 0.5, "FR": 0.5 } }])
+    );
+
+    // Back up to stereo, the mono signal in both speakers.
+    let stereo = registry().create("audio_channels", params(json!({ "layout": "stereo" })))?;
+    stereo.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    let buffer = &artifact.media().audio.as_ref().unwrap().buffers[0];
+    assert_eq!(buffer.samples[2..4], [left / 2.0, left / 2.0]);
+
+    assert!(
+        registry()
+            .create("audio_channels", params(json!({ "layout": "quad" })))
+            .is_err()
+    );
+    Ok(())
+}
+
+#[test]
+fn audio_trim_and_fade_cut_a_clip() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let input = tempdir.path().join("episode.wav");
+    std::fs::write(&input, wav_file(4000))?;
+    let mut artifact = Artifact::load(&input)?;
+    let ctx = context(tempdir.path());
+
+    let decode = registry().create("audio_decode", StageParameters::new())?;
+    decode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    let trim = registry().create(
+        "audio_trim",
+        params(json!({ "start": 0.1, "duration": "50%" })),
+    )?;
+    trim.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+
+    let buffer = &artifact.media().audio.as_ref().unwrap().buffers[0];
+    assert_eq!(buffer.samples.len(), 2 * 2000);
+    // Frame 800 of the sawtooth comes first.
+    assert_eq!(buffer.samples[0], (288 * 64) as f32 / 32768.0);
+    assert_eq!(artifact.metadata["audio.frame_count"], 2000);
+    assert_eq!(artifact.metadata["audio.duration"], 0.25);
+    assert_eq!(artifact.metadata["audio_trim.start"], 0.1);
+    assert_eq!(artifact.metadata["audio_trim.end"], 0.35);
+    assert_eq!(artifact.media().duration.unwrap().as_millis(), 250);
+
+    let fade = registry().create(
+        "audio_fade",
+        params(json!({ "fade_in": 0.05, "fade_out": "0:00.05", "curve": "sine" })),
+    )?;
+    fade.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    let buffer = &artifact.media().audio.as_ref().unwrap().buffers[0];
+    assert_eq!(buffer.samples[0], 0.0);
+    assert_eq!(buffer.samples[2 * 1999], 0.0);
+    // Past the fade in, the audio is untouched.
+    assert_eq!(buffer.samples[2 * 400], (176 * 64) as f32 / 32768.0);
+    assert_eq!(artifact.metadata["audio_fade.in"], 0.05);
+    assert_eq!(artifact.metadata["audio_fade.curve"], "sine");
+
+    let create = |name, value| registry().create(name, params(value));
+    assert!(create("audio_trim", json!({})).is_err());
+    assert!(create("audio_trim", json!({ "end": 2, "duration": 1 })).is_err());
+    assert!(create("audio_fade", json!({ "fade_in": 1, "curve": "cubic" })).is_err());
+    let past_the_end = create("audio_trim", json!({ "start": 10 }))?;
+    assert!(
+        past_the_end
+            .run(&mut artifact, &ctx, StageDevice::Cpu)
+            .is_err()
+    );
+    Ok(())
+}
+
+#[test]
+fn audio_extract_writes_the_audio_track_of_a_video() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    // The video track is never decoded, so its one sample can be anything.
+    let video = EncodedVideo {
+        codec: VideoCodec::H264,
+        width: 16,
+        height: 16,
+        frame_rate: FrameRate::Constant {
+            numerator: 25,
+            denominator: 1,
+        },
+        config: vec![1, 0x42, 0xC0, 0x0A, 0xFF, 0xE0, 0],
+        samples: vec![EncodedSample {
+            data: vec![0, 0, 0, 1, 0x65],
+            timestamp: Duration::ZERO,
+            duration: Duration::from_millis(40),
+            keyframe: true,
+        }],
+        rotation: 0,
+        hdr: HdrMetadata::default(),
+    };
+    let samples: Vec<f32> = (0..3000).map(|n| (n % 256 - 128) as f32 / 256.0).collect();
+    let audio = AudioStream {
+        codec: AudioCodec::PcmF32,
+        buffers: vec![AudioBuffer {
+            sample_rate: 8000,
+            channel_layout: ChannelLayout::Stereo,
+            samples: samples.clone(),
+        }],
+    };
+    let packets = pcm::float_packets(&audio)?;
+    let input = tempdir.path().join("interview.mkv");
+    std::fs::write(
+        &input,
+        matroska::write_matroska(&video, Some(&packets), DocType::Matroska)?,
+    )?;
+    let mut artifact = Artifact::load(&input)?;
+    let ctx = context(&tempdir.path().join("out"));
+
+    let extract = registry().create("audio_extract", StageParameters::new())?;
+    extract.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    assert_eq!(artifact.metadata["audio.codec"], "pcm_f32");
+    assert_eq!(artifact.metadata["audio.frame_count"], 1500);
+    assert_eq!(artifact.metadata["audio.output.codec"], "pcm_s16");
+    let output = tempdir.path().join("out/interview.wav");
+    assert_eq!(
+        artifact.metadata["output_path"],
+        output.to_string_lossy().as_ref()
+    );
+
+    // The WAV holds the track's samples.
+    let mut written = Artifact::load(&output)?;
+    let decode = registry().create("audio_decode", StageParameters::new())?;
+    decode.run(&mut written, &ctx, StageDevice::Cpu)?;
+    let buffer = &written.media().audio.as_ref().unwrap().buffers[0];
+    assert_eq!(buffer.sample_rate, 8000);
+    assert_eq!(buffer.samples, samples);
+
+    let wav = tempdir.path().join("tone.wav");
+    std::fs::write(&wav, wav_file(100))?;
+    let mut not_a_video = Artifact::load(&wav)?;
+    assert!(
+        extract
+            .run(&mut not_a_video, &ctx, StageDevice::Cpu)
+            .is_err()
+    );
+    Ok(())
+}
@@ -83,9 +83,11 @@ fn quick_convert_supports_custom_output_directory() {
     assert!(output_dir.join("image.webp").is_file());
 }
 
+/// A one-macroblock (16x16) baseline IDR picture, DC predicted with no
+/// residual.
 const ANNEX_B_SAMPLE: &[u8] = &[
-    0x00, 0x00, 0x01, 0x67, 0x42, 0xE0, 0x1E, 0x8D, 0x68, 0x50, 0x1E, 0xD8, 0x08, 0x80, 0x00, 0x00,
-    0x01, 0x68, 0xCE, 0x06, 0xE2, 0x00, 0x00, 0x01, 0x65, 0x88, 0x84, 0x21, 0xA0,
+    0x00, 0x00, 0x00, 0x01, 0x67, 0x42, 0xC0, 0x1E, 0xDA, 0x79, 0x00, 0x00, 0x00, 0x01, 0x68, 0xCE,
+    0x3C, 0x80, 0x00, 0x00, 0x00, 0x01, 0x65, 0x88, 0x84, 0xF2, 0x78,
 ];
 
 #[test]
@@ -109,3 +111,102 @@ fn quick_convert_handles_h264_inputs() {
 
     assert!(temp.path().join("clip.mp4").is_file());
 }
+
+#[test]
+fn quick_convert_sniffs_video_inputs_by_content() {
+    let temp = tempdir().unwrap();
+    // A raw stream under a name that says nothing about it.
+    std::fs::write(temp.path().join("clip.dat"), ANNEX_B_SAMPLE).unwrap();
+
+    Command::cargo_bin("bunker-convert")
+        .expect("binary present")
+        .current_dir(temp.path())
+        .args(["clip.dat", "to", "mp4"])
+        .assert()
+        .success();
+    assert!(temp.path().join("clip.mp4").is_file());
+
+    Command::cargo_bin("bunker-convert")
+        .expect("binary present")
+        .current_dir(temp.path())
+        .args(["clip.mp4", "to", "h264", "to", "raw"])
+        .assert()
+        .success();
+    let raw = std::fs::read(temp.path().join("raw").join("clip.h264")).unwrap();
+    assert!(raw.starts_with(&[0, 0, 0, 1]));
+}
+
+#[test]
+fn quick_convert_handles_audio_only_inputs() {
+    let temp = tempdir().unwrap();
+    // Two silent MPEG-1 Layer III frames: 128 kbps, 44.1 kHz, mono.
+    let mut frame = vec![0u8; 417];
+    frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x90, 0xC0]);
+    std::fs::write(temp.path().join("podcast.mp3"), frame.repeat(2)).unwrap();
+
+    Command::cargo_bin("bunker-convert")
+        .expect("binary present")
+        .current_dir(temp.path())
+        .args(["podcast.mp3", "to", "wav"])
+        .assert()
+        .success();
+    let wav = std::fs::read(temp.path().join("podcast.wav")).unwrap();
+    assert!(wav.starts_with(b"RIFF"));
+    // 2304 16-bit samples after the 44-byte header.
+    assert_eq!(wav.len(), 44 + 2 * 2304);
+
+    let to_mp3 = Command::cargo_bin("bunker-convert")
+        .expect("binary present")
+        .current_dir(temp.path())
+        .args(["podcast.wav", "to", "mp3", "to", "out"])
+        .assert();
+    if cfg!(feature = "mp3lame") {
+        to_mp3.success();
+        let mp3 = std::fs::read(temp.path().join("out").join("podcast.mp3")).unwrap();
+        assert_eq!(mp3[0], 0xFF);
+    } else {
+        let stderr = String::from_utf8_lossy(&to_mp3.failure().get_output().stderr).into_owned();
+        assert!(stderr.contains("mp3lame"), "{stderr}");
+    }
+}
+
+#[test]
+fn compare_reports_metrics_and_writes_heatmap() {
+    let temp = tempdir().unwrap();
+    let reference = temp.path().join("reference.png");
+    write_sample_image(&reference);
+    let candidate = temp.path().join("candidate.png");
+    let mut altered = image::open(&reference).unwrap().to_rgba8();
+    altered.put_pixel(3, 3, Rgba([0, 0, 0, 255]));
+    altered.save(&candidate).unwrap();
+    let heatmap = temp.path().join("diff/heatmap.png");
+
+    let output = Command::cargo_bin("bunker-convert")
+        .expect("binary present")
+        .args(["compare", "--json", "--heatmap"])
+        .arg(&heatmap)
+        .arg(&reference)
+        .arg(&candidate)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["reference"]["width"], 16);
+    assert_eq!(report["candidate"]["format"], "png");
+    assert!(report["metrics"]["mse"].as_f64().unwrap() > 0.0);
+    assert!(report["metrics"]["ssim"].as_f64().unwrap() < 1.0);
+    assert_eq!(image::open(&heatmap).unwrap().width(), 16);
+}
+
+#[test]
+fn run_rejects_jobs_with_encode_workers() {
+    let output = Command::cargo_bin("bunker-convert")
+        .expect("binary present")
+        .args(["run", "recipe.yaml", "--jobs", "2", "--encode-workers", "2"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("cannot be used with"), "{stderr}");
+}
@@ -4,6 +4,7 @@ use bunker_convert::pipeline::{
     OutputSpec, StageParameters, StageRegistry, StageSpec, build_pipeline,
 };
 use bunker_convert::scheduler::DevicePolicy;
+use bunker_convert::security::compute_sha256;
 use bunker_convert::stages;
 use image::{ImageBuffer, Rgba};
 use serde_json::Value;
@@ -23,6 +24,7 @@ fn stage(name: &str, params: &[(&str, Value)]) -> StageSpec {
     StageSpec {
         stage: name.to_string(),
         params: Some(map),
+        ..StageSpec::default()
     }
 }
 
@@ -226,3 +228,468 @@ fn encode_png_records_filter_and_compression() {
         Some("paeth")
     );
 }
+
+#[test]
+fn encode_skips_verification_without_quality_gates() {
+    let temp = tempdir().unwrap();
+    let input_path = temp.path().join("input.png");
+    write_gradient(&input_path);
+
+    let output_spec = OutputSpec {
+        directory: temp.path().join("verify"),
+        structure: "{stem}.{ext}".into(),
+    };
+
+    let auto_stages = vec![
+        stage("decode", &[]),
+        stage("encode", &[("format", Value::String("png".into()))]),
+    ];
+    let executor = build_pipeline(
+        &registry(),
+        &auto_stages,
+        output_spec.clone(),
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+    let results = executor.execute(std::slice::from_ref(&input_path)).unwrap();
+    let metadata = &results[0].metadata;
+    assert_eq!(
+        metadata.get("output.verified").and_then(Value::as_bool),
+        Some(false)
+    );
+    assert!(metadata.get("output.decode_supported").is_none());
+
+    let always_stages = vec![
+        stage("decode", &[]),
+        stage(
+            "encode",
+            &[
+                ("format", Value::String("png".into())),
+                ("verify_output", Value::String("always".into())),
+            ],
+        ),
+    ];
+    let executor = build_pipeline(
+        &registry(),
+        &always_stages,
+        output_spec,
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+    let results = executor.execute(std::slice::from_ref(&input_path)).unwrap();
+    let metadata = &results[0].metadata;
+    assert_eq!(
+        metadata.get("output.verified").and_then(Value::as_bool),
+        Some(true)
+    );
+    assert_eq!(
+        metadata
+            .get("output.decode_supported")
+            .and_then(Value::as_bool),
+        Some(true)
+    );
+}
+
+#[test]
+fn encode_rejects_unknown_verify_mode() {
+    let mut params = StageParameters::default();
+    params.insert("verify_output".into(), Value::String("sometimes".into()));
+    assert!(registry().create("encode", params).is_err());
+}
+
+#[test]
+fn encode_streams_output_with_digest() {
+    let temp = tempdir().unwrap();
+    let input_path = temp.path().join("input.png");
+    write_gradient(&input_path);
+
+    let output_dir = temp.path().join("stream");
+    let output_spec = OutputSpec {
+        directory: output_dir.clone(),
+        structure: "{stem}.{ext}".into(),
+    };
+
+    let stages = vec![
+        stage("decode", &[]),
+        stage(
+            "encode",
+            &[
+                ("format", Value::String("jpeg".into())),
+                ("stream", Value::Bool(true)),
+                ("verify_output", Value::String("always".into())),
+            ],
+        ),
+    ];
+
+    let executor = build_pipeline(
+        &registry(),
+        &stages,
+        output_spec,
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+    let results = executor.execute(std::slice::from_ref(&input_path)).unwrap();
+    let metadata = &results[0].metadata;
+    let output_path = output_dir.join("input.jpg");
+    assert_eq!(
+        metadata.get("output.streamed").and_then(Value::as_bool),
+        Some(true)
+    );
+    assert_eq!(
+        metadata.get("output.size_bytes").and_then(Value::as_u64),
+        Some(std::fs::metadata(&output_path).unwrap().len())
+    );
+    assert_eq!(
+        metadata.get("output.sha256").and_then(Value::as_str),
+        Some(compute_sha256(&output_path).unwrap().as_str())
+    );
+    assert_eq!(
+        metadata
+            .get("output.decode_supported")
+            .and_then(Value::as_bool),
+        Some(true)
+    );
+}
+
+#[test]
+fn encode_passes_through_untouched_same_format_inputs() {
+    let temp = tempdir().unwrap();
+    let gradient = temp.path().join("gradient.png");
+    write_gradient(&gradient);
+    let input_path = temp.path().join("input.jpg");
+    image::open(&gradient)
+        .unwrap()
+        .to_rgb8()
+        .save(&input_path)
+        .unwrap();
+
+    let run = |name: &str, stages: Vec<StageSpec>| {
+        let executor = build_pipeline(
+            &registry(),
+            &stages,
+            OutputSpec {
+                directory: temp.path().join(name),
+                structure: "{stem}.{ext}".into(),
+            },
+            Vec::new(),
+            DevicePolicy::CpuOnly,
+        )
+        .unwrap();
+        executor.execute(std::slice::from_ref(&input_path)).unwrap()
+    };
+    let encode = || {
+        stage(
+            "encode",
+            &[
+                ("format", Value::String("jpeg".into())),
+                ("quality", Value::from(40)),
+                ("passthrough_if_same_format", Value::Bool(true)),
+            ],
+        )
+    };
+
+    let results = run("copied", vec![stage("decode", &[]), encode()]);
+    let metadata = &results[0].metadata;
+    assert_eq!(
+        metadata.get("output.passthrough").and_then(Value::as_bool),
+        Some(true)
+    );
+    assert!(metadata.get("output.encoder.quality").is_none());
+    assert_eq!(
+        std::fs::read(&results[0].output).unwrap(),
+        std::fs::read(&input_path).unwrap()
+    );
+
+    // JPEGs are not re-encoded; their Huffman tables are rebuilt, which
+    // shrinks the file without changing a pixel.
+    let optimize = stage(
+        "encode",
+        &[
+            ("format", Value::String("jpeg".into())),
+            ("passthrough_if_same_format", Value::Bool(true)),
+            ("passthrough_optimize", Value::Bool(true)),
+        ],
+    );
+    let results = run("optimized", vec![stage("decode", &[]), optimize]);
+    let optimized = std::fs::read(&results[0].output).unwrap();
+    assert!(optimized.len() < std::fs::metadata(&input_path).unwrap().len() as usize);
+    assert_eq!(
+        image::load_from_memory(&optimized).unwrap().to_rgb8(),
+        image::open(&input_path).unwrap().to_rgb8()
+    );
+
+    let results = run(
+        "resized",
+        vec![
+            stage("decode", &[]),
+            stage(
+                "resize",
+                &[("width", Value::from(12)), ("height", Value::from(12))],
+            ),
+            encode(),
+        ],
+    );
+    assert_eq!(
+        results[0]
+            .metadata
+            .get("output.passthrough")
+            .and_then(Value::as_bool),
+        Some(false)
+    );
+    assert_ne!(
+        std::fs::read(&results[0].output).unwrap(),
+        std::fs::read(&input_path).unwrap()
+    );
+}
+
+#[test]
+fn passthrough_applies_pixel_changing_encoder_options() {
+    let temp = tempdir().unwrap();
+    let input_path = temp.path().join("input.png");
+    write_gradient(&input_path);
+    let deep_path = temp.path().join("deep.png");
+    image::DynamicImage::ImageRgb16(ImageBuffer::from_fn(16, 16, |x, y| {
+        image::Rgb([(x * 4000) as u16, (y * 4000) as u16, 1001])
+    }))
+    .save(&deep_path)
+    .unwrap();
+    let run = |name: &str, input: &PathBuf, options: &[(&str, Value)]| {
+        let mut params = vec![
+            ("format", Value::String("png".into())),
+            ("passthrough_if_same_format", Value::Bool(true)),
+        ];
+        params.extend_from_slice(options);
+        let executor = build_pipeline(
+            &registry(),
+            &[stage("decode", &[]), stage("encode", &params)],
+            OutputSpec {
+                directory: temp.path().join(name),
+                structure: "{stem}.{ext}".into(),
+            },
+            Vec::new(),
+            DevicePolicy::CpuOnly,
+        )
+        .unwrap();
+        let results = executor.execute(std::slice::from_ref(input)).unwrap();
+        let result = results.into_iter().next().unwrap();
+        let written = image::open(&result.output).unwrap();
+        (result.metadata, written)
+    };
+    let passthrough = |metadata: &StageParameters| {
+        metadata
+            .get("output.passthrough")
+            .and_then(Value::as_bool)
+            .unwrap()
+    };
+
+    let (metadata, gray) = run("gray", &input_path, &[("gray_bits", Value::from(1))]);
+    assert!(!passthrough(&metadata));
+    assert!(
+        gray.to_rgb8()
+            .pixels()
+            .all(|pixel| pixel[0] == pixel[1] && pixel[1] == pixel[2])
+    );
+    assert!(
+        gray.to_luma8()
+            .pixels()
+            .all(|pixel| pixel[0] == 0 || pixel[0] == 255)
+    );
+
+    let (metadata, indexed) = run("indexed", &input_path, &[("colors", Value::from(4))]);
+    assert!(!passthrough(&metadata));
+    let mut colors: Vec<_> = indexed.to_rgba8().pixels().map(|pixel| pixel.0).collect();
+    colors.sort_unstable();
+    colors.dedup();
+    assert!(colors.len() <= 4, "{} colors", colors.len());
+
+    let (metadata, narrowed) = run("narrowed", &deep_path, &[("bit_depth", Value::from(8))]);
+    assert!(!passthrough(&metadata));
+    assert_eq!(metadata["output.bit_depth"], Value::from(8));
+    let color = narrowed.color();
+    assert_eq!(color.bytes_per_pixel() / color.channel_count(), 1);
+
+    // A copied 16-bit PNG reports the depth it was stored with.
+    let (metadata, copied) = run("copied", &deep_path, &[]);
+    assert!(passthrough(&metadata));
+    assert_eq!(metadata["output.bit_depth"], Value::from(16));
+    assert_eq!(copied.color(), image::ColorType::Rgb16);
+}
+
+#[test]
+fn animated_gif_converts_to_animated_webp() {
+    use image::codecs::gif::GifEncoder;
+    use image::codecs::webp::WebPDecoder;
+    use image::{AnimationDecoder, Delay, Frame};
+
+    let temp = tempdir().unwrap();
+    let input_path = temp.path().join("spinner.gif");
+    {
+        let file = std::fs::File::create(&input_path).unwrap();
+        let mut encoder = GifEncoder::new(file);
+        let frames = [[255, 0, 0], [0, 255, 0], [0, 0, 255]].map(|[r, g, b]| {
+            Frame::from_parts(
+                ImageBuffer::from_pixel(16, 16, Rgba([r, g, b, 255])),
+                0,
+                0,
+                Delay::from_numer_denom_ms(80, 1),
+            )
+        });
+        encoder.encode_frames(frames).unwrap();
+    }
+
+    let stages = vec![
+        stage("decode", &[("frames", Value::String("all".into()))]),
+        stage(
+            "resize",
+            &[("width", Value::from(8)), ("height", Value::from(8))],
+        ),
+        stage(
+            "encode",
+            &[
+                ("format", Value::String("webp".into())),
+                ("lossless", Value::Bool(true)),
+                ("repeat", Value::from(2)),
+            ],
+        ),
+    ];
+    let executor = build_pipeline(
+        &registry(),
+        &stages,
+        OutputSpec {
+            directory: temp.path().join("out"),
+            structure: "{stem}.{ext}".into(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+    let results = executor.execute(std::slice::from_ref(&input_path)).unwrap();
+    assert_eq!(
+        results[0]
+            .metadata
+            .get("output.frame_count")
+            .and_then(Value::as_u64),
+        Some(3)
+    );
+
+    let data = std::fs::read(&results[0].output).unwrap();
+    let decoder = WebPDecoder::new(std::io::Cursor::new(data)).unwrap();
+    assert!(decoder.has_animation());
+    let frames: Vec<_> = decoder.into_frames().collect::<Result<_, _>>().unwrap();
+    assert_eq!(frames.len(), 3);
+    assert_eq!(frames[0].buffer().dimensions(), (8, 8));
+    // GIF palettes quantize the source colors slightly.
+    let last = frames[2].buffer().get_pixel(4, 4);
+    assert!(
+        last[2] > 240 && last[0] < 16,
+        "unexpected last frame color {last:?}"
+    );
+    assert_eq!(frames[0].delay().numer_denom_ms(), (80, 1));
+}
+
+#[test]
+fn animated_avif_output_writes_an_image_sequence() {
+    let temp = tempdir().unwrap();
+    let input_path = temp.path().join("blink.gif");
+    {
+        let file = std::fs::File::create(&input_path).unwrap();
+        let mut encoder = image::codecs::gif::GifEncoder::new(file);
+        encoder
+            .encode_frames((0..2u8).map(|index| {
+                image::Frame::new(ImageBuffer::from_pixel(
+                    16,
+                    16,
+                    Rgba([index * 200, 0, 0, 255]),
+                ))
+            }))
+            .unwrap();
+    }
+    let stages = vec![
+        stage("decode", &[("frames", Value::String("all".into()))]),
+        stage(
+            "encode",
+            &[
+                ("format", Value::String("avif".into())),
+                ("speed", Value::from(10)),
+            ],
+        ),
+    ];
+    let executor = build_pipeline(
+        &registry(),
+        &stages,
+        OutputSpec {
+            directory: temp.path().join("out"),
+            structure: "{stem}.{ext}".into(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+    let result = executor.execute(std::slice::from_ref(&input_path));
+    if cfg!(not(feature = "rav1e")) {
+        assert!(format!("{:#}", result.unwrap_err()).contains("rav1e feature"));
+        return;
+    }
+    let artifact = &result.unwrap()[0];
+    assert_eq!(artifact.metadata["output.frame_count"], 2);
+    let data = std::fs::read(temp.path().join("out/blink.avif")).unwrap();
+    assert_eq!(&data[8..12], b"avis");
+}
+
+#[test]
+fn pdf_combine_assembles_run_into_one_document() {
+    let temp = tempdir().unwrap();
+    let inputs: Vec<PathBuf> = ["page2.png", "page1.png", "page3.png"]
+        .iter()
+        .map(|name| {
+            let path = temp.path().join(name);
+            write_gradient(&path);
+            path
+        })
+        .collect();
+    let stages = vec![
+        stage("decode", &[]),
+        stage(
+            "encode",
+            &[
+                ("format", Value::String("pdf".into())),
+                ("combine", Value::Bool(true)),
+                ("document", Value::String("scans".into())),
+                ("page_size", Value::String("a4".into())),
+                ("dpi", Value::from(150)),
+            ],
+        ),
+    ];
+    let executor = build_pipeline(
+        &registry(),
+        &stages,
+        OutputSpec {
+            directory: temp.path().join("out"),
+            structure: "{stem}.{ext}".into(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+    let results = executor.execute(&inputs).unwrap();
+    let document = temp.path().join("out").join("scans.pdf");
+    assert!(results.iter().all(|result| result.output == document));
+    assert_eq!(
+        results[0]
+            .metadata
+            .get("output.pages")
+            .and_then(Value::as_u64),
+        Some(1)
+    );
+
+    let data = std::fs::read(&document).unwrap();
+    assert!(data.starts_with(b"%PDF-1.4"));
+    assert!(data.ends_with(b"%%EOF\n"));
+    let count = |needle: &[u8]| data.windows(needle.len()).filter(|w| *w == needle).count();
+    assert_eq!(count(b"/Type /Page "), 3);
+    assert_eq!(count(b"/Count 3"), 1);
+    assert_eq!(count(b"/MediaBox [0 0 595.28 841.89]"), 3);
+}
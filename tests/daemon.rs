@@ -0,0 +1,61 @@
+#![cfg(unix)]
+
+use std::path::PathBuf;
+
+use bunker_convert::daemon::{Daemon, DaemonRequest, submit};
+use bunker_convert::pipeline::StageRegistry;
+use bunker_convert::scheduler::DevicePolicy;
+use bunker_convert::stages;
+use tempfile::tempdir;
+
+#[test]
+fn daemon_runs_submitted_jobs_until_shutdown() {
+    let temp = tempdir().unwrap();
+    let input = temp.path().join("in.png");
+    image::RgbaImage::from_pixel(4, 4, image::Rgba([1, 2, 3, 255]))
+        .save(&input)
+        .unwrap();
+    std::fs::write(
+        temp.path().join("recipe.yaml"),
+        "version: 1\ninputs:\n  - path: \"*.png\"\npipeline:\n  - stage: decode\n  - stage: encode\n    params:\n      format: webp\noutput:\n  directory: out\n",
+    )
+    .unwrap();
+
+    let socket = temp.path().join("daemon.sock");
+    let mut registry = StageRegistry::new();
+    stages::register_defaults(&mut registry);
+    let daemon = Daemon::bind(registry, &socket).unwrap();
+    let server = std::thread::spawn(move || daemon.serve());
+
+    assert!(submit(&socket, &DaemonRequest::Ping).unwrap().ok);
+    let response = submit(
+        &socket,
+        &DaemonRequest::Run {
+            recipe: PathBuf::from("recipe.yaml"),
+            cwd: temp.path().to_path_buf(),
+            device_policy: DevicePolicy::CpuOnly,
+            encode_workers: None,
+        },
+    )
+    .unwrap();
+    assert!(response.ok, "{:?}", response.error);
+    assert_eq!(response.outputs.len(), 1);
+    assert_eq!(response.outputs[0].output, PathBuf::from("out/in.webp"));
+    assert!(temp.path().join("out/in.webp").is_file());
+
+    let missing = submit(
+        &socket,
+        &DaemonRequest::Run {
+            recipe: PathBuf::from("missing.yaml"),
+            cwd: temp.path().to_path_buf(),
+            device_policy: DevicePolicy::CpuOnly,
+            encode_workers: None,
+        },
+    )
+    .unwrap();
+    assert!(!missing.ok);
+
+    assert!(submit(&socket, &DaemonRequest::Shutdown).unwrap().ok);
+    server.join().unwrap().unwrap();
+    assert!(!socket.exists());
+}
@@ -1,12 +1,29 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
+use anyhow::{Result, bail};
+use bunker_convert::buffers;
+use bunker_convert::cache::{self, OutputCache};
+use bunker_convert::cancel::CancellationToken;
+use bunker_convert::collision::{CollisionStrategy, OutputClaims};
+use bunker_convert::dedup::{DedupPlan, DuplicateMode, Similarity};
+use bunker_convert::manifest::{ManifestFormat, PartialManifest, RunManifest};
+use bunker_convert::overwrite::{self, OverwritePolicy};
+use bunker_convert::perceptual::{self, HashAlgorithm};
 use bunker_convert::pipeline::{
-    OutputSpec, StageParameters, StageRegistry, StageSpec, build_pipeline,
+    Artifact, OutputSpec, PipelineContext, Stage, StageParameters, StageRegistry, StageSpec,
+    build_pipeline, hardware_key,
 };
-use bunker_convert::scheduler::DevicePolicy;
-use bunker_convert::stages;
+use bunker_convert::plan::RunPlan;
+use bunker_convert::recipe::Recipe;
+use bunker_convert::resume::{self, LEDGER_FILE, ResumeLedger, ResumeMode};
+use bunker_convert::retry::RetryPolicy;
+use bunker_convert::scheduler::{DevicePolicy, StageDevice};
+use bunker_convert::security::compute_sha256;
+use bunker_convert::stages::{self, THUMBNAILS_KEY};
 use image::{ImageBuffer, Rgba};
-use serde_json::Value;
+use serde_json::{Value, json};
 use tempfile::tempdir;
 
 fn build_registry() -> StageRegistry {
@@ -23,6 +40,7 @@ fn build_stage_spec(name: &str, params: &[(&str, Value)]) -> StageSpec {
     StageSpec {
         stage: name.to_string(),
         params: Some(map),
+        ..StageSpec::default()
     }
 }
 
@@ -89,3 +107,2833 @@ fn pipeline_executes_and_writes_output() {
     assert!(prom.contains("bunker_stage_calls_total{stage=\"decode\"}"));
     assert!(prom.contains("bunker_quality_passes_total"));
 }
+
+#[test]
+fn execute_iter_yields_results_per_input() {
+    let temp = tempdir().unwrap();
+    let mut inputs = Vec::new();
+    for name in ["first.png", "second.png"] {
+        let path = temp.path().join(name);
+        let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+            ImageBuffer::from_pixel(4, 4, Rgba([0, 128, 255, 255]));
+        image.save(&path).expect("failed to save test image");
+        inputs.push(path);
+    }
+
+    let output_dir = temp.path().join("out");
+    let output_spec = OutputSpec {
+        directory: output_dir.clone(),
+        structure: "{stem}.{ext}".to_string(),
+    };
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        build_stage_spec("encode", &[("format", Value::String("png".to_string()))]),
+    ];
+    let executor = build_pipeline(
+        &build_registry(),
+        &stages,
+        output_spec,
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+
+    let mut results = executor.execute_iter(&inputs);
+    assert_eq!(results.size_hint(), (2, Some(2)));
+    let first = results.next().unwrap().unwrap();
+    assert_eq!(first.output, output_dir.join("first.png"));
+    assert!(output_dir.join("first.png").exists());
+    assert!(!output_dir.join("second.png").exists());
+
+    let second = results.next().unwrap().unwrap();
+    assert_eq!(second.output, output_dir.join("second.png"));
+    assert!(results.next().is_none());
+
+    let snapshot = executor.metrics().snapshot();
+    assert_eq!(snapshot.stages.get("encode").unwrap().calls, 2);
+}
+
+#[test]
+fn pipeline_reuses_pooled_buffers_across_inputs() {
+    let temp = tempdir().unwrap();
+    let mut inputs = Vec::new();
+    for index in 0..3 {
+        let path = temp.path().join(format!("input{index}.png"));
+        let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+            ImageBuffer::from_pixel(16, 16, Rgba([index * 40, 0, 0, 255]));
+        image.save(&path).expect("failed to save test image");
+        inputs.push(path);
+    }
+
+    let output_spec = OutputSpec {
+        directory: temp.path().join("out"),
+        structure: "{stem}.{ext}".to_string(),
+    };
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        build_stage_spec("encode", &[("format", Value::String("png".to_string()))]),
+    ];
+    let executor = build_pipeline(
+        &build_registry(),
+        &stages,
+        output_spec,
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+
+    let before = buffers::stats();
+    executor.execute(&inputs).unwrap();
+    let after = buffers::stats();
+    assert!(after.hits > before.hits);
+    assert!(after.pooled_buffers > 0);
+}
+
+#[test]
+fn memory_limit_gates_oversized_inputs() {
+    let temp = tempdir().unwrap();
+    let input_path = temp.path().join("large.png");
+    let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+        ImageBuffer::from_pixel(64, 64, Rgba([10, 20, 30, 255]));
+    image.save(&input_path).expect("failed to save test image");
+
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        build_stage_spec("encode", &[("format", Value::String("png".to_string()))]),
+    ];
+    let output_spec = OutputSpec {
+        directory: temp.path().join("out"),
+        structure: "{stem}.{ext}".to_string(),
+    };
+
+    let tight = build_pipeline(
+        &build_registry(),
+        &stages,
+        output_spec.clone(),
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap()
+    .with_memory_limit(Some(1024));
+    let err = tight
+        .execute(std::slice::from_ref(&input_path))
+        .unwrap_err();
+    assert!(format!("{err:#}").contains("memory ceiling"));
+
+    let roomy = build_pipeline(
+        &build_registry(),
+        &stages,
+        output_spec,
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap()
+    .with_memory_limit(Some(64 << 20));
+    let results = roomy.execute(std::slice::from_ref(&input_path)).unwrap();
+    let estimated = results[0]
+        .metadata
+        .get("memory.estimated_bytes")
+        .and_then(Value::as_u64)
+        .unwrap();
+    assert!(estimated >= 64 * 64 * 4 * 2);
+}
+
+#[test]
+fn memory_estimate_covers_stages_that_enlarge_the_image() {
+    let temp = tempdir().unwrap();
+    let input_path = temp.path().join("small.png");
+    let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+        ImageBuffer::from_pixel(32, 32, Rgba([10, 20, 30, 255]));
+    image.save(&input_path).expect("failed to save test image");
+
+    let output_spec = OutputSpec {
+        directory: temp.path().join("out"),
+        structure: "{stem}.{ext}".to_string(),
+    };
+    let estimate = |stages: &[StageSpec]| {
+        let results = build_pipeline(
+            &build_registry(),
+            stages,
+            output_spec.clone(),
+            Vec::new(),
+            DevicePolicy::CpuOnly,
+        )
+        .unwrap()
+        .with_memory_limit(Some(64 << 20))
+        .execute(std::slice::from_ref(&input_path))
+        .unwrap();
+        results[0]
+            .metadata
+            .get("memory.estimated_bytes")
+            .and_then(Value::as_u64)
+            .unwrap()
+    };
+
+    let plain = estimate(&[
+        build_stage_spec("decode", &[]),
+        build_stage_spec("encode", &[("format", Value::String("png".to_string()))]),
+    ]);
+    let resized = estimate(&[
+        build_stage_spec("decode", &[]),
+        build_stage_spec("resize", &[("width", json!(256)), ("height", json!(256))]),
+        build_stage_spec("encode", &[("format", Value::String("png".to_string()))]),
+    ]);
+    assert!(plain < 32 * 32 * 4 * 3);
+    assert!(resized >= 256 * 256 * 4);
+}
+
+#[test]
+fn output_structure_placeholders_build_directory_trees() {
+    let temp = tempdir().unwrap();
+    let input_path = temp.path().join("shoot").join("beach.png");
+    std::fs::create_dir_all(input_path.parent().unwrap()).unwrap();
+    let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+        ImageBuffer::from_pixel(8, 8, Rgba([10, 20, 30, 255]));
+    image.save(&input_path).expect("failed to save test image");
+    let digest = compute_sha256(&input_path).unwrap();
+
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        build_stage_spec(
+            "resize",
+            &[
+                ("width", json!(4)),
+                ("height", json!(2)),
+                ("fit", json!("exact")),
+            ],
+        ),
+        build_stage_spec("encode", &[("format", Value::String("png".to_string()))]),
+    ];
+    let executor = build_pipeline(
+        &build_registry(),
+        &stages,
+        OutputSpec {
+            directory: temp.path().join("out"),
+            structure: "{parent}/{seq:3}-{stem}-{width}x{height}-{hash:6}.{ext}".to_string(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+    let results = executor.execute(std::slice::from_ref(&input_path)).unwrap();
+    let expected = temp
+        .path()
+        .join("out/shoot")
+        .join(format!("001-beach-4x2-{}.png", &digest[..6]));
+    assert_eq!(results[0].output, expected);
+    assert!(expected.is_file());
+
+    let err = build_pipeline(
+        &build_registry(),
+        &stages,
+        OutputSpec {
+            directory: temp.path().join("out"),
+            structure: "{stem}-{hash:99}.{ext}".to_string(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .err()
+    .expect("invalid structure accepted");
+    assert!(format!("{err:#}").contains("1 to 64 hex digits"));
+}
+
+#[test]
+fn dry_run_plan_predicts_outputs_without_writing() {
+    let temp = tempdir().unwrap();
+    let mut inputs = Vec::new();
+    for (dir, size) in [("a", 300), ("b", 40), ("c", 40)] {
+        let path = temp.path().join(dir).join("photo.png");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+            ImageBuffer::from_pixel(size, size / 2, Rgba([10, 20, 30, 255]));
+        image.save(&path).expect("failed to save test image");
+        inputs.push(path);
+    }
+    let mut resize = build_stage_spec("resize", &[("width", json!(100)), ("height", json!(100))]);
+    resize.when = Some("image.width > 50".to_string());
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        resize,
+        build_stage_spec("encode", &[("format", json!("webp"))]),
+    ];
+    let output_dir = temp.path().join("out");
+    let executor = build_pipeline(
+        &build_registry(),
+        &stages,
+        OutputSpec {
+            directory: output_dir.clone(),
+            structure: "{stem}-{width}x{height}.{ext}".to_string(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+
+    let plan = RunPlan::build(Path::new("recipe.yaml"), &stages, &executor, &inputs).unwrap();
+    assert_eq!(plan.stages.len(), 3);
+    assert_eq!(plan.stages[1].params["width"], json!(100));
+    assert_eq!(plan.stages[1].when.as_deref(), Some("image.width > 50"));
+    assert_eq!(
+        plan.inputs[0].outputs,
+        vec![output_dir.join("photo-100x50.webp")]
+    );
+    assert!(plan.inputs[0].skipped_stages.is_empty());
+    assert_eq!(
+        plan.inputs[1].outputs,
+        vec![output_dir.join("photo-40x20.webp")]
+    );
+    assert_eq!(plan.inputs[1].skipped_stages, vec!["resize".to_string()]);
+    let collision = plan.inputs[2].error.as_deref().unwrap();
+    assert!(collision.contains("collides with the output of"));
+    assert_eq!(plan.errors(), 1);
+    assert!(plan.render().contains("-> "));
+    assert!(!output_dir.exists());
+
+    let json = serde_json::to_value(&plan).unwrap();
+    assert_eq!(json["stages"][0]["device"], "cpu");
+}
+
+#[test]
+fn colliding_outputs_fail_or_get_distinct_paths() {
+    let temp = tempdir().unwrap();
+    let mut inputs = Vec::new();
+    for dir in ["a", "b"] {
+        let path = temp.path().join(dir).join("photo.png");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+            ImageBuffer::from_pixel(4, 4, Rgba([10, 20, 30, 255]));
+        image.save(&path).expect("failed to save test image");
+        inputs.push(path);
+    }
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        build_stage_spec("encode", &[("format", Value::String("png".to_string()))]),
+    ];
+    let executor = |strategy: CollisionStrategy| {
+        build_pipeline(
+            &build_registry(),
+            &stages,
+            OutputSpec {
+                directory: temp.path().join(strategy.as_str()),
+                structure: "{stem}.{ext}".to_string(),
+            },
+            Vec::new(),
+            DevicePolicy::CpuOnly,
+        )
+        .unwrap()
+        .with_jobs(Some(1))
+        .with_collisions(strategy)
+    };
+
+    let err = executor(CollisionStrategy::Error)
+        .execute(&inputs)
+        .unwrap_err();
+    assert!(format!("{err:#}").contains("collides with the output of"));
+
+    let results = executor(CollisionStrategy::Suffix)
+        .execute(&inputs)
+        .unwrap();
+    assert_eq!(results[0].output, temp.path().join("suffix/photo.png"));
+    assert_eq!(results[1].output, temp.path().join("suffix/photo-1.png"));
+    assert!(results[1].output.is_file());
+    assert_eq!(
+        results[1].metadata["output.collision.requested_path"],
+        json!(temp.path().join("suffix/photo.png"))
+    );
+    assert!(
+        !results[0]
+            .metadata
+            .contains_key("output.collision.strategy")
+    );
+}
+
+#[test]
+fn auto_orient_and_rotate_straighten_sideways_photos() {
+    use image::ImageEncoder;
+    use image::codecs::png::PngEncoder;
+
+    // A 4x2 photo whose EXIF orientation (6) says to turn it 90 degrees
+    // clockwise; its left column is red.
+    let temp = tempdir().unwrap();
+    let input = temp.path().join("phone.png");
+    let mut image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+        ImageBuffer::from_pixel(4, 2, Rgba([0, 0, 255, 255]));
+    for y in 0..2 {
+        image.put_pixel(0, y, Rgba([255, 0, 0, 255]));
+    }
+    let exif = [
+        b'M', b'M', 0, 42, 0, 0, 0, 8, // big-endian TIFF header, IFD at 8
+        0, 1, // one entry
+        0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0, // Orientation = 6
+        0, 0, 0, 0, // no next IFD
+    ];
+    let mut png = Vec::new();
+    let mut encoder = PngEncoder::new(&mut png);
+    encoder.set_exif_metadata(exif.to_vec()).unwrap();
+    encoder
+        .write_image(&image, 4, 2, image::ExtendedColorType::Rgba8)
+        .unwrap();
+    std::fs::write(&input, png).unwrap();
+
+    let run = |stages: Vec<StageSpec>, dir: &str| {
+        build_pipeline(
+            &build_registry(),
+            &stages,
+            OutputSpec {
+                directory: temp.path().join(dir),
+                structure: "{stem}.{ext}".to_string(),
+            },
+            Vec::new(),
+            DevicePolicy::CpuOnly,
+        )
+        .unwrap()
+        .execute(std::slice::from_ref(&input))
+        .unwrap()
+        .remove(0)
+    };
+    let encode = || build_stage_spec("encode", &[("format", json!("png"))]);
+
+    let upright = run(
+        vec![
+            build_stage_spec("decode", &[("auto_orient", json!(true))]),
+            encode(),
+        ],
+        "upright",
+    );
+    assert_eq!(upright.metadata["image.orientation"], 6);
+    let output = image::open(&upright.output).unwrap().to_rgba8();
+    assert_eq!(output.dimensions(), (2, 4));
+    // Turned clockwise, the red left column becomes the top row.
+    assert_eq!(output.get_pixel(1, 0).0, [255, 0, 0, 255]);
+    assert_eq!(output.get_pixel(1, 3).0, [0, 0, 255, 255]);
+
+    let rotated = run(
+        vec![
+            build_stage_spec("decode", &[]),
+            build_stage_spec(
+                "rotate",
+                &[("angle", json!(270)), ("flip", json!("vertical"))],
+            ),
+            encode(),
+        ],
+        "rotated",
+    );
+    assert!(!rotated.metadata.contains_key("image.orientation"));
+    assert_eq!(rotated.metadata["image.width"], 2);
+    let output = image::open(&rotated.output).unwrap().to_rgba8();
+    assert_eq!(output.dimensions(), (2, 4));
+    // Counter-clockwise puts the red column at the bottom; the flip brings it
+    // back to the top.
+    assert_eq!(output.get_pixel(0, 0).0, [255, 0, 0, 255]);
+    assert_eq!(output.get_pixel(0, 3).0, [0, 0, 255, 255]);
+}
+
+#[test]
+fn color_convert_uses_the_embedded_profile() {
+    use image::ImageEncoder;
+    use image::codecs::png::PngEncoder;
+
+    // Saturated green tagged as Adobe RGB lies outside sRGB, so converting
+    // it drops the red that the untagged values would show.
+    let temp = tempdir().unwrap();
+    let input = temp.path().join("adobe.png");
+    let icc = moxcms::ColorProfile::new_adobe_rgb().encode().unwrap();
+    let mut png = Vec::new();
+    let mut encoder = PngEncoder::new(&mut png);
+    encoder.set_icc_profile(icc).unwrap();
+    encoder
+        .write_image(&[40, 200, 40], 1, 1, image::ExtendedColorType::Rgb8)
+        .unwrap();
+    std::fs::write(&input, png).unwrap();
+
+    let result = build_pipeline(
+        &build_registry(),
+        &[
+            build_stage_spec("decode", &[]),
+            build_stage_spec("color_convert", &[("intent", json!("relative"))]),
+            build_stage_spec("encode", &[("format", json!("png"))]),
+        ],
+        OutputSpec {
+            directory: temp.path().join("out"),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap()
+    .execute(std::slice::from_ref(&input))
+    .unwrap()
+    .remove(0);
+    assert_eq!(result.metadata["color_convert.from"], "embedded");
+    assert_eq!(result.metadata["color_convert.to"], "srgb");
+    let pixel = image::open(&result.output)
+        .unwrap()
+        .to_rgb8()
+        .get_pixel(0, 0)
+        .0;
+    assert!(pixel[0] < 20 && pixel[1] >= 195, "{pixel:?}");
+}
+
+#[test]
+fn png_colors_writes_an_indexed_png() {
+    let temp = tempdir().unwrap();
+    let input = temp.path().join("screenshot.png");
+    // A flat UI mock: a few solid panels and a translucent overlay.
+    let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+        ImageBuffer::from_fn(120, 80, |x, y| match (x / 40, y / 40) {
+            (0, _) => Rgba([32, 33, 36, 255]),
+            (1, 0) => Rgba([66, 133, 244, 255]),
+            (1, 1) => Rgba([0, 0, 0, 128]),
+            _ => Rgba([250, 250, 250, 255]),
+        });
+    image.save(&input).unwrap();
+
+    let run = |encode: &[(&str, Value)], dir: &str| {
+        build_pipeline(
+            &build_registry(),
+            &[
+                build_stage_spec("decode", &[]),
+                build_stage_spec("encode", encode),
+            ],
+            OutputSpec {
+                directory: temp.path().join(dir),
+                structure: "{stem}.{ext}".to_string(),
+            },
+            Vec::new(),
+            DevicePolicy::CpuOnly,
+        )
+        .unwrap()
+        .execute(std::slice::from_ref(&input))
+        .unwrap()
+        .remove(0)
+    };
+
+    let rgba = run(&[("format", json!("png"))], "rgba");
+    let indexed = run(
+        &[
+            ("format", json!("png")),
+            ("colors", json!(16)),
+            ("dither", json!("none")),
+        ],
+        "indexed",
+    );
+    assert_eq!(indexed.metadata["output.encoder.colors"], json!(16));
+    assert_eq!(indexed.metadata["output.encoder.dither"], "none");
+    let output = std::fs::read(&indexed.output).unwrap();
+    // IHDR color type 3: palette.
+    assert_eq!(output[25], 3);
+    assert!(output.len() < std::fs::metadata(&rgba.output).unwrap().len() as usize);
+    // Four colors fit the palette, so the pixels come back unchanged.
+    let decoded = image::load_from_memory(&output).unwrap().to_rgba8();
+    assert_eq!(decoded, image);
+
+    // A bad palette size fails when the recipe loads, before any input.
+    let invalid = build_pipeline(
+        &build_registry(),
+        &[
+            build_stage_spec("decode", &[]),
+            build_stage_spec("encode", &[("format", json!("png")), ("colors", json!(1))]),
+        ],
+        OutputSpec {
+            directory: temp.path().join("invalid"),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    );
+    assert!(invalid.is_err());
+}
+
+#[test]
+fn gray_bits_writes_dithered_low_bit_grayscale() {
+    let temp = tempdir().unwrap();
+    let input = temp.path().join("receipt.png");
+    let image: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_fn(64, 32, |x, y| {
+        Rgba([(x * 4) as u8, (x * 4) as u8, (y * 8) as u8, 255])
+    });
+    image.save(&input).unwrap();
+
+    let run = |encode: &[(&str, Value)], dir: &str| {
+        build_pipeline(
+            &build_registry(),
+            &[
+                build_stage_spec("decode", &[]),
+                build_stage_spec("encode", encode),
+            ],
+            OutputSpec {
+                directory: temp.path().join(dir),
+                structure: "{stem}.{ext}".to_string(),
+            },
+            Vec::new(),
+            DevicePolicy::CpuOnly,
+        )
+        .and_then(|executor| executor.execute(std::slice::from_ref(&input)))
+    };
+
+    let one = run(&[("format", json!("png")), ("gray_bits", json!(1))], "one")
+        .unwrap()
+        .remove(0);
+    assert_eq!(one.metadata["output.encoder.gray_bits"], json!(1));
+    let output = std::fs::read(&one.output).unwrap();
+    // IHDR bit depth 1, color type 0: grayscale.
+    assert_eq!((output[24], output[25]), (1, 0));
+    let decoded = image::load_from_memory(&output).unwrap().to_luma8();
+    assert!(decoded.pixels().all(|pixel| matches!(pixel[0], 0 | 255)));
+    let white = decoded.pixels().filter(|pixel| pixel[0] == 255).count();
+    assert!((800..1300).contains(&white), "{white} white pixels");
+
+    // Other formats get the same levels in 8-bit samples.
+    let ordered = run(
+        &[
+            ("format", json!("bmp")),
+            ("gray_bits", json!(2)),
+            ("dither", json!("ordered")),
+        ],
+        "ordered",
+    )
+    .unwrap()
+    .remove(0);
+    let decoded = image::open(&ordered.output).unwrap().to_luma8();
+    assert!(decoded.pixels().all(|pixel| pixel[0] % 85 == 0));
+
+    assert!(
+        run(
+            &[("format", json!("png")), ("gray_bits", json!(3))],
+            "invalid"
+        )
+        .is_err()
+    );
+    assert!(
+        run(
+            &[
+                ("format", json!("png")),
+                ("colors", json!(8)),
+                ("dither", json!("ordered")),
+            ],
+            "invalid"
+        )
+        .is_err()
+    );
+}
+
+#[test]
+fn sixteen_bit_inputs_stay_sixteen_bit_through_resize_and_encode() {
+    let temp = tempdir().unwrap();
+    let input = temp.path().join("scan.png");
+    // A ramp finer than 8 bits can hold.
+    let image: ImageBuffer<image::Rgb<u16>, Vec<u16>> =
+        ImageBuffer::from_fn(64, 16, |x, _| image::Rgb([x as u16 * 3, 1000, 65535]));
+    image.save(&input).unwrap();
+
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        build_stage_spec(
+            "resize",
+            &[
+                ("width", json!(32)),
+                ("height", json!(8)),
+                ("method", json!("nearest")),
+            ],
+        ),
+        build_stage_spec("encode", &[("format", json!("png"))]),
+    ];
+    let executor = build_pipeline(
+        &build_registry(),
+        &stages,
+        OutputSpec {
+            directory: temp.path().join("out"),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+    let results = executor.execute(std::slice::from_ref(&input)).unwrap();
+    assert_eq!(results[0].metadata["image.bit_depth"], json!(16));
+    assert_eq!(results[0].metadata["output.bit_depth"], json!(16));
+
+    let written = image::open(&results[0].output).unwrap();
+    let image::DynamicImage::ImageRgb16(written) = written else {
+        panic!("expected a 16-bit RGB PNG, got {:?}", written.color());
+    };
+    assert_eq!((written.width(), written.height()), (32, 8));
+    let reds: std::collections::BTreeSet<u16> = written.pixels().map(|pixel| pixel[0]).collect();
+    assert_eq!(reds.len(), 32);
+    assert!(reds.iter().all(|red| red % 3 == 0 && *red < 192));
+    assert_eq!(written.get_pixel(5, 5)[1], 1000);
+}
+
+#[test]
+fn exr_and_hdr_inputs_tonemap_to_sdr_outputs() {
+    let temp = tempdir().unwrap();
+    // Linear light up to 8x display white.
+    let scene = image::Rgb32FImage::from_fn(16, 8, |x, y| {
+        let value = x as f32 / 2.0;
+        image::Rgb([value, value * 0.5, y as f32 / 8.0])
+    });
+    let exr = temp.path().join("render.exr");
+    let hdr = temp.path().join("probe.hdr");
+    image::DynamicImage::ImageRgba32F(image::DynamicImage::ImageRgb32F(scene.clone()).to_rgba32f())
+        .save(&exr)
+        .unwrap();
+    image::DynamicImage::ImageRgb32F(scene).save(&hdr).unwrap();
+
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        build_stage_spec(
+            "tonemap",
+            &[("operator", json!("aces")), ("exposure", json!(-1))],
+        ),
+        build_stage_spec("encode", &[("format", json!("png"))]),
+    ];
+    let executor = build_pipeline(
+        &build_registry(),
+        &stages,
+        OutputSpec {
+            directory: temp.path().join("out"),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+    let results = executor.execute(&[exr, hdr]).unwrap();
+    for result in &results {
+        assert_eq!(result.metadata["tonemap.operator"], json!("aces"));
+        assert_eq!(result.metadata["tonemap.exposure"], json!(-1.0));
+        assert_eq!(result.metadata["output.bit_depth"], json!(8));
+        let written = image::open(&result.output).unwrap().to_rgb8();
+        assert_eq!(written.dimensions(), (16, 8));
+        let reds: Vec<u8> = (0..16).map(|x| written.get_pixel(x, 0)[0]).collect();
+        assert_eq!(reds[0], 0);
+        assert!(reds.windows(2).all(|pair| pair[0] <= pair[1]), "{reds:?}");
+        assert!(reds[1] < reds[8] && reds[15] < 255, "{reds:?}");
+    }
+}
+
+#[test]
+fn auto_format_writes_the_smallest_candidate_above_min_ssim() {
+    let temp = tempdir().unwrap();
+    let input = temp.path().join("photo.png");
+    let image: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_fn(64, 48, |x, y| {
+        let noise = ((x * 7919 + y * 104_729) % 23) as u8;
+        Rgba([(x * 3) as u8 + noise, (y * 5) as u8, 128 + noise, 255])
+    });
+    image.save(&input).unwrap();
+
+    let run = |min_ssim: f64, directory: &str| {
+        let stages = vec![
+            build_stage_spec("decode", &[]),
+            build_stage_spec(
+                "encode",
+                &[
+                    ("format", json!("auto")),
+                    (
+                        "candidates",
+                        json!(["png", { "format": "jpeg", "quality": 85 }, "webp"]),
+                    ),
+                    ("min_ssim", json!(min_ssim)),
+                    ("quality", json!(60)),
+                ],
+            ),
+        ];
+        let executor = build_pipeline(
+            &build_registry(),
+            &stages,
+            OutputSpec {
+                directory: temp.path().join(directory),
+                structure: "{stem}.{ext}".to_string(),
+            },
+            Vec::new(),
+            DevicePolicy::CpuOnly,
+        )
+        .unwrap();
+        executor.execute(std::slice::from_ref(&input)).unwrap()
+    };
+
+    let lossy = run(0.8, "lossy");
+    let metadata = &lossy[0].metadata;
+    let candidates = metadata["output.auto.candidates"].as_array().unwrap();
+    assert_eq!(candidates.len(), 3);
+    let smallest = candidates
+        .iter()
+        .filter(|candidate| candidate["passed"] == json!(true))
+        .min_by_key(|candidate| candidate["size_bytes"].as_u64().unwrap())
+        .unwrap();
+    assert_ne!(smallest["format"], json!("png"));
+    assert_eq!(metadata["output.auto.format"], smallest["format"]);
+    assert_eq!(metadata["output.format"], smallest["format"]);
+    assert_eq!(metadata["output.size_bytes"], smallest["size_bytes"]);
+    assert!(
+        lossy[0]
+            .output
+            .to_string_lossy()
+            .ends_with(smallest["format"].as_str().unwrap())
+    );
+    // Per-candidate options override the shared ones.
+    let jpeg = candidates
+        .iter()
+        .find(|candidate| candidate["format"] == json!("jpg"))
+        .unwrap();
+    assert!(jpeg["ssim"].as_f64().unwrap() > 0.8);
+
+    // Only the lossless candidate keeps every pixel.
+    let exact = run(0.999_999, "exact");
+    assert_eq!(exact[0].metadata["output.auto.format"], json!("png"));
+    assert_eq!(exact[0].output, temp.path().join("exact/photo.png"));
+    assert_eq!(image::open(&exact[0].output).unwrap().to_rgba8(), image);
+}
+
+#[test]
+fn encode_fallbacks_write_every_format_from_one_decode() {
+    let temp = tempdir().unwrap();
+    let input = temp.path().join("hero.png");
+    let image: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_fn(32, 24, |x, y| {
+        Rgba([(x * 8) as u8, (y * 10) as u8, 90, 255])
+    });
+    image.save(&input).unwrap();
+
+    let run = |fallbacks: Value| {
+        let stages = vec![
+            build_stage_spec("decode", &[]),
+            build_stage_spec(
+                "encode",
+                &[
+                    ("format", json!("avif")),
+                    ("quality", json!(50)),
+                    ("fallbacks", fallbacks),
+                ],
+            ),
+        ];
+        let executor = build_pipeline(
+            &build_registry(),
+            &stages,
+            OutputSpec {
+                directory: temp.path().join("out"),
+                structure: "{stem}.{ext}".to_string(),
+            },
+            Vec::new(),
+            DevicePolicy::CpuOnly,
+        )
+        .unwrap();
+        executor.execute(std::slice::from_ref(&input))
+    };
+
+    let results = run(json!(["webp", { "format": "jpeg", "quality": 90 }])).unwrap();
+    assert_eq!(results[0].output, temp.path().join("out/hero.avif"));
+    assert!(results[0].output.exists());
+    let fallbacks = results[0].metadata["output.fallbacks"].as_array().unwrap();
+    let formats: Vec<_> = fallbacks.iter().map(|entry| &entry["format"]).collect();
+    assert_eq!(formats, [&json!("webp"), &json!("jpg")]);
+    for (entry, extension) in fallbacks.iter().zip(["webp", "jpg"]) {
+        let path = PathBuf::from(entry["path"].as_str().unwrap());
+        assert_eq!(path, temp.path().join(format!("out/hero.{extension}")));
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            entry["size_bytes"].as_u64().unwrap()
+        );
+        assert_eq!(image::open(&path).unwrap().width(), 32);
+    }
+
+    // A fallback in the main output's format would overwrite it.
+    let err = run(json!(["webp", "avif"])).unwrap_err();
+    assert!(format!("{err:#}").contains("would overwrite"), "{err:#}");
+}
+
+#[test]
+fn jxl_round_trips_losslessly_through_encode_and_decode() {
+    let temp = tempdir().unwrap();
+    let input = temp.path().join("scan.png");
+    let image: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_fn(48, 32, |x, y| {
+        Rgba([
+            (x * 5) as u8,
+            (y * 7) as u8,
+            ((x + y) * 3) as u8,
+            255 - (x as u8),
+        ])
+    });
+    image.save(&input).unwrap();
+
+    let run = |input: &Path, encode: &[(&str, Value)], dir: &str| {
+        build_pipeline(
+            &build_registry(),
+            &[
+                build_stage_spec("decode", &[]),
+                build_stage_spec("encode", encode),
+            ],
+            OutputSpec {
+                directory: temp.path().join(dir),
+                structure: "{stem}.{ext}".to_string(),
+            },
+            Vec::new(),
+            DevicePolicy::CpuOnly,
+        )
+        .unwrap()
+        .execute(&[input.to_path_buf()])
+        .unwrap()
+        .remove(0)
+    };
+
+    let archived = run(
+        &input,
+        &[
+            ("format", json!("jxl")),
+            ("effort", json!(3)),
+            ("verify_output", json!("always")),
+        ],
+        "archive",
+    );
+    assert_eq!(archived.output.extension().unwrap(), "jxl");
+    assert_eq!(archived.metadata["output.format"], "jxl");
+    assert_eq!(archived.metadata["output.encoder.lossless"], json!(true));
+    assert_eq!(archived.metadata["output.encoder.effort"], json!(3));
+    assert_eq!(archived.metadata["output.verified"], json!(true));
+
+    let restored = run(&archived.output, &[("format", json!("png"))], "restored");
+    assert_eq!(restored.metadata["image.width"], json!(48));
+    let decoded = image::open(&restored.output).unwrap().to_rgba8();
+    assert_eq!(decoded, image);
+}
+
+/// A PDF whose pages are `(width, height, rgb)` in points, each filled with
+/// one colour.
+fn solid_color_pdf(pages: &[(u32, u32, [f32; 3])]) -> Vec<u8> {
+    let page_ids: Vec<usize> = (0..pages.len()).map(|index| 3 + index * 2).collect();
+    let kids: Vec<String> = page_ids.iter().map(|id| format!("{id} 0 R")).collect();
+    let mut objects = vec![
+        "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+        format!(
+            "<< /Type /Pages /Kids [{}] /Count {} >>",
+            kids.join(" "),
+            pages.len()
+        ),
+    ];
+    for (&(width, height, [r, g, b]), id) in pages.iter().zip(&page_ids) {
+        let content = format!("{r} {g} {b} rg 0 0 {width} {height} re f");
+        objects.push(format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {width} {height}] /Contents {} 0 R >>",
+            id + 1
+        ));
+        objects.push(format!(
+            "<< /Length {} >>\nstream\n{content}\nendstream",
+            content.len()
+        ));
+    }
+    let mut pdf = b"%PDF-1.4\n".to_vec();
+    let mut offsets = Vec::new();
+    for (index, object) in objects.iter().enumerate() {
+        offsets.push(pdf.len());
+        pdf.extend(format!("{} 0 obj\n{object}\nendobj\n", index + 1).bytes());
+    }
+    let xref = pdf.len();
+    pdf.extend(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).bytes());
+    for offset in offsets {
+        pdf.extend(format!("{offset:010} 00000 n \n").bytes());
+    }
+    pdf.extend(
+        format!(
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{xref}\n%%EOF\n",
+            objects.len() + 1
+        )
+        .bytes(),
+    );
+    pdf
+}
+
+#[test]
+fn pdf_rasterize_writes_one_output_per_page() {
+    let temp = tempdir().unwrap();
+    let input = temp.path().join("report.pdf");
+    std::fs::write(
+        &input,
+        solid_color_pdf(&[
+            (72, 36, [1.0, 0.0, 0.0]),
+            (36, 72, [0.0, 0.0, 1.0]),
+            (72, 72, [0.0, 1.0, 0.0]),
+        ]),
+    )
+    .unwrap();
+
+    let stages = |pages: Value| {
+        vec![
+            build_stage_spec("pdf_rasterize", &[("pages", pages), ("dpi", json!(144))]),
+            build_stage_spec("encode", &[]),
+        ]
+    };
+    let executor = |stages: &[StageSpec], dir: &str, structure: &str| {
+        build_pipeline(
+            &build_registry(),
+            stages,
+            OutputSpec {
+                directory: temp.path().join(dir),
+                structure: structure.to_string(),
+            },
+            Vec::new(),
+            DevicePolicy::CpuOnly,
+        )
+        .unwrap()
+    };
+
+    let selected = stages(json!("1-2"));
+    let pages = executor(&selected, "pages", "{stem}/page-{page}.{ext}");
+    let plan = RunPlan::build(
+        Path::new("recipe.yaml"),
+        &selected,
+        &pages,
+        std::slice::from_ref(&input),
+    )
+    .unwrap();
+    let pages_dir = temp.path().join("pages").join("report");
+    assert_eq!(
+        plan.inputs[0].outputs,
+        vec![pages_dir.join("page-1.png"), pages_dir.join("page-2.png")]
+    );
+
+    let results = pages.execute(std::slice::from_ref(&input)).unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].output, pages_dir.join("page-1.png"));
+    assert_eq!(results[1].metadata["page"], "2");
+    assert_eq!(results[1].metadata["pdf.page_count"], json!(3));
+    let first = image::open(&results[0].output).unwrap().to_rgb8();
+    assert_eq!(first.dimensions(), (144, 72));
+    assert_eq!(first.get_pixel(72, 36).0, [255, 0, 0]);
+    let second = image::open(&results[1].output).unwrap().to_rgb8();
+    assert_eq!(second.dimensions(), (72, 144));
+    assert_eq!(second.get_pixel(36, 72).0, [0, 0, 255]);
+
+    // Without {page} in the structure, pages keep apart through their stems.
+    let last = executor(&stages(json!(3)), "last", "{stem}.{ext}")
+        .execute(&[input])
+        .unwrap();
+    assert_eq!(last.len(), 1);
+    assert_eq!(
+        last[0].output,
+        temp.path().join("last").join("report-3.png")
+    );
+    let green = image::open(&last[0].output).unwrap().to_rgb8();
+    assert_eq!(green.get_pixel(10, 10).0, [0, 255, 0]);
+}
+
+fn tiff_page_count(path: &Path) -> usize {
+    let mut decoder = tiff::decoder::Decoder::new(std::fs::File::open(path).unwrap()).unwrap();
+    let mut count = 1;
+    while decoder.more_images() {
+        decoder.next_image().unwrap();
+        count += 1;
+    }
+    count
+}
+
+#[test]
+fn multi_page_tiffs_split_into_pages_or_stay_together() {
+    use tiff::encoder::{TiffEncoder, colortype};
+
+    let temp = tempdir().unwrap();
+    let input = temp.path().join("fax.tiff");
+    let mut encoder = TiffEncoder::new(std::fs::File::create(&input).unwrap()).unwrap();
+    for (width, height, shade) in [(16, 8, 30u8), (8, 16, 120), (12, 12, 220)] {
+        let pixels = vec![shade; (width * height) as usize];
+        encoder
+            .write_image::<colortype::Gray8>(width, height, &pixels)
+            .unwrap();
+    }
+    drop(encoder);
+
+    let run = |decode: &[(&str, Value)], encode: &[(&str, Value)], dir: &str| {
+        build_pipeline(
+            &build_registry(),
+            &[
+                build_stage_spec("decode", decode),
+                build_stage_spec("encode", encode),
+            ],
+            OutputSpec {
+                directory: temp.path().join(dir),
+                structure: "{stem}-p{page}.{ext}".to_string(),
+            },
+            Vec::new(),
+            DevicePolicy::CpuOnly,
+        )
+        .unwrap()
+        .execute(std::slice::from_ref(&input))
+        .unwrap()
+    };
+
+    let pages = run(
+        &[("pages", json!("2-3"))],
+        &[("format", json!("png"))],
+        "pages",
+    );
+    assert_eq!(pages.len(), 2);
+    assert_eq!(
+        pages[0].output,
+        temp.path().join("pages").join("fax-p2.png")
+    );
+    assert_eq!(pages[1].metadata["image.page_count"], json!(3));
+    let second = image::open(&pages[0].output).unwrap().to_luma8();
+    assert_eq!(second.dimensions(), (8, 16));
+    assert_eq!(second.get_pixel(0, 0).0, [120]);
+    let third = image::open(&pages[1].output).unwrap().to_luma8();
+    assert_eq!(third.dimensions(), (12, 12));
+    assert_eq!(third.get_pixel(5, 5).0, [220]);
+
+    let merged = run(
+        &[("frames", json!("all"))],
+        &[("format", json!("tiff"))],
+        "merged",
+    );
+    assert_eq!(merged.len(), 1);
+    assert_eq!(merged[0].metadata["image.frame_count"], json!(3));
+    assert_eq!(merged[0].metadata["output.frame_count"], json!(3));
+    assert_eq!(tiff_page_count(&merged[0].output), 3);
+}
+
+#[test]
+fn montage_combine_writes_a_sprite_sheet_and_map() {
+    let temp = tempdir().unwrap();
+    let mut inputs = Vec::new();
+    for (name, size, color) in [
+        ("coin", 4, [255, 200, 0, 255]),
+        ("gem", 6, [0, 0, 255, 255]),
+        ("heart", 4, [255, 0, 0, 255]),
+    ] {
+        let path = temp.path().join(format!("{name}.png"));
+        let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+            ImageBuffer::from_pixel(size, size, Rgba(color));
+        image.save(&path).unwrap();
+        inputs.push(path);
+    }
+    let output_dir = temp.path().join("atlas");
+    let results = build_pipeline(
+        &build_registry(),
+        &[
+            build_stage_spec("decode", &[]),
+            build_stage_spec(
+                "montage",
+                &[
+                    ("combine", json!(true)),
+                    ("columns", json!(2)),
+                    ("padding", json!(1)),
+                    ("map", json!(true)),
+                ],
+            ),
+        ],
+        OutputSpec {
+            directory: output_dir.clone(),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap()
+    .execute(&inputs)
+    .unwrap();
+
+    let sheet_path = output_dir.join("sprites.png");
+    assert_eq!(results.len(), 3);
+    assert!(results.iter().all(|result| result.output == sheet_path));
+    let sheet = image::open(&sheet_path).unwrap().to_rgba8();
+    // Two 6x6 cells per row, two rows, 1px padding around every cell.
+    assert_eq!(sheet.dimensions(), (15, 15));
+
+    let map: Value =
+        serde_json::from_slice(&std::fs::read(output_dir.join("sprites.json")).unwrap()).unwrap();
+    assert_eq!(map["image"], "sprites.png");
+    let sprites = map["sprites"].as_array().unwrap();
+    let names: Vec<&str> = sprites
+        .iter()
+        .map(|sprite| sprite["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["coin", "gem", "heart"]);
+    assert_eq!(
+        (&sprites[1]["x"], &sprites[1]["y"], &sprites[1]["width"]),
+        (&json!(8), &json!(1), &json!(6))
+    );
+    let heart = &sprites[2];
+    let (x, y) = (
+        heart["x"].as_u64().unwrap() as u32,
+        heart["y"].as_u64().unwrap() as u32,
+    );
+    assert_eq!((x, y), (2, 9));
+    assert_eq!(sheet.get_pixel(x, y).0, [255, 0, 0, 255]);
+    assert_eq!(sheet.get_pixel(0, 0).0[3], 0);
+}
+
+#[test]
+fn tile_stage_writes_dzi_and_iiif_pyramids() {
+    let temp = tempdir().unwrap();
+    let input = temp.path().join("map.png");
+    let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+        ImageBuffer::from_fn(20, 10, |x, _| Rgba([(x * 12) as u8, 80, 160, 255]));
+    image.save(&input).unwrap();
+
+    let run = |params: &[(&str, Value)], dir: &str| {
+        build_pipeline(
+            &build_registry(),
+            &[
+                build_stage_spec("decode", &[]),
+                build_stage_spec("tile", params),
+            ],
+            OutputSpec {
+                directory: temp.path().join(dir),
+                structure: "{stem}.{ext}".to_string(),
+            },
+            Vec::new(),
+            DevicePolicy::CpuOnly,
+        )
+        .unwrap()
+        .execute(std::slice::from_ref(&input))
+        .unwrap()
+        .remove(0)
+    };
+
+    let dzi = run(&[("tile_size", json!(8)), ("format", json!("png"))], "dzi");
+    let dir = temp.path().join("dzi");
+    assert_eq!(dzi.output, dir.join("map.dzi"));
+    assert_eq!(dzi.metadata["tiles.levels"], json!(6));
+    // 3x2 + 2x1 + 1 + 1 + 1 + 1 tiles from 20x10 down to 1x1.
+    assert_eq!(dzi.metadata["tiles.count"], json!(12));
+    let descriptor = std::fs::read_to_string(&dzi.output).unwrap();
+    assert!(descriptor.contains("Overlap=\"1\" TileSize=\"8\""));
+    assert!(descriptor.contains("<Size Width=\"20\" Height=\"10\"/>"));
+    let tiles = dir.join("map_files");
+    let corner = image::open(tiles.join("5").join("2_1.png")).unwrap();
+    assert_eq!((corner.width(), corner.height()), (5, 3));
+    assert_eq!(corner.to_rgba8().get_pixel(0, 0), image.get_pixel(15, 7));
+    let interior = image::open(tiles.join("5").join("1_0.png")).unwrap();
+    assert_eq!((interior.width(), interior.height()), (10, 9));
+    let top = image::open(tiles.join("0").join("0_0.png")).unwrap();
+    assert_eq!((top.width(), top.height()), (1, 1));
+
+    let iiif = run(
+        &[
+            ("layout", json!("iiif")),
+            ("tile_size", json!(8)),
+            ("base_url", json!("https://tiles.example.com/iiif/")),
+        ],
+        "iiif",
+    );
+    let root = temp.path().join("iiif").join("map");
+    assert_eq!(iiif.output, root.join("info.json"));
+    let info: Value = serde_json::from_slice(&std::fs::read(&iiif.output).unwrap()).unwrap();
+    assert_eq!(info["id"], "https://tiles.example.com/iiif/map");
+    assert_eq!(info["tiles"][0]["scaleFactors"], json!([1, 2, 4]));
+    assert!(root.join("8,0,8,8/8,8/0/default.jpg").is_file());
+    assert!(root.join("full/5,3/0/default.jpg").is_file());
+}
+
+#[test]
+fn optimize_stage_shrinks_outputs_losslessly_and_reports_savings() {
+    use std::io::Cursor;
+
+    let temp = tempdir().unwrap();
+    let image: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_fn(48, 32, |x, y| {
+        Rgba([(x * 5) as u8, (y * 7) as u8, ((x + y) % 3 * 60) as u8, 255])
+    });
+    let png_input = temp.path().join("chart.png");
+    image.save(&png_input).unwrap();
+
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        build_stage_spec(
+            "encode",
+            &[("format", json!("png")), ("compression", json!("fast"))],
+        ),
+        build_stage_spec("optimize", &[]),
+    ];
+    let executor = build_pipeline(
+        &build_registry(),
+        &stages,
+        OutputSpec {
+            directory: temp.path().join("png"),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+    let results = executor.execute(std::slice::from_ref(&png_input)).unwrap();
+    let metadata = &results[0].metadata;
+    let written = std::fs::read(&results[0].output).unwrap();
+    let saved = metadata["optimize.bytes_saved"].as_u64().unwrap();
+    assert!(saved > 0);
+    assert_eq!(
+        metadata["optimize.bytes_before"].as_u64().unwrap() - saved,
+        written.len() as u64
+    );
+    assert_eq!(metadata["output.size_bytes"], json!(written.len()));
+    assert_eq!(image::load_from_memory(&written).unwrap().to_rgba8(), image);
+    let snapshot = executor.metrics().snapshot();
+    assert_eq!(snapshot.outputs_optimized, 1);
+    assert_eq!(snapshot.optimize_bytes_saved, saved);
+
+    // Without an encode stage the input file itself is optimized.
+    let jpeg_input = temp.path().join("photo.jpg");
+    let mut jpeg = Vec::new();
+    image::DynamicImage::ImageRgba8(image.clone())
+        .into_rgb8()
+        .write_to(&mut Cursor::new(&mut jpeg), image::ImageFormat::Jpeg)
+        .unwrap();
+    // A comment segment right after SOI, as cameras and editors leave them.
+    let mut commented = vec![0xFF, 0xD8, 0xFF, 0xFE, 0x00, 0x0C];
+    commented.extend_from_slice(b"edited-by");
+    commented.push(0);
+    commented.extend_from_slice(&jpeg[2..]);
+    std::fs::write(&jpeg_input, &commented).unwrap();
+
+    let executor = build_pipeline(
+        &build_registry(),
+        &[build_stage_spec("optimize", &[])],
+        OutputSpec {
+            directory: temp.path().join("jpeg"),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+    let results = executor.execute(std::slice::from_ref(&jpeg_input)).unwrap();
+    assert_eq!(results[0].output, temp.path().join("jpeg/photo.jpg"));
+    assert_eq!(results[0].metadata["output.format"], json!("jpeg"));
+    let optimized = std::fs::read(&results[0].output).unwrap();
+    assert!(optimized.len() < commented.len());
+    assert!(!optimized.windows(9).any(|window| window == b"edited-by"));
+    assert_eq!(
+        image::load_from_memory(&optimized).unwrap().to_rgb8(),
+        image::load_from_memory(&commented).unwrap().to_rgb8()
+    );
+}
+
+#[test]
+fn palette_writes_a_sidecar_next_to_the_output() {
+    let temp = tempdir().unwrap();
+    let input = temp.path().join("flag.png");
+    let image: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_fn(40, 20, |x, _| {
+        if x < 30 {
+            Rgba([200, 20, 20, 255])
+        } else {
+            Rgba([255, 255, 255, 255])
+        }
+    });
+    image.save(&input).unwrap();
+
+    let run = |sidecar: Value, directory: &str| {
+        let stages = vec![
+            build_stage_spec("decode", &[]),
+            build_stage_spec("palette", &[("colors", json!(2)), ("sidecar", sidecar)]),
+            build_stage_spec("encode", &[("format", json!("webp"))]),
+        ];
+        build_pipeline(
+            &build_registry(),
+            &stages,
+            OutputSpec {
+                directory: temp.path().join(directory),
+                structure: "{stem}.{ext}".to_string(),
+            },
+            Vec::new(),
+            DevicePolicy::CpuOnly,
+        )
+        .unwrap()
+        .execute(std::slice::from_ref(&input))
+        .unwrap()
+        .remove(0)
+    };
+
+    let result = run(json!(true), "default");
+    let sidecar = temp.path().join("default/flag.palette.json");
+    assert_eq!(
+        result.metadata["palette.sidecar"],
+        json!(sidecar.to_string_lossy())
+    );
+    let document: Value = serde_json::from_slice(&std::fs::read(&sidecar).unwrap()).unwrap();
+    assert_eq!(document["dominant"], json!("#c81414"));
+    assert_eq!(document["colors"], result.metadata["palette.colors"]);
+    assert!(document["average_luminance"].as_f64().unwrap() > 0.0);
+
+    let custom = run(json!("colors/{stem}.{ext}"), "custom");
+    assert_eq!(
+        custom.metadata["palette.sidecar"],
+        json!(
+            temp.path()
+                .join("custom/colors/flag.json")
+                .to_string_lossy()
+        )
+    );
+    assert!(temp.path().join("custom/colors/flag.json").exists());
+}
+
+#[test]
+fn decorate_frames_and_rounds_the_image() {
+    let temp = tempdir().unwrap();
+    let input = temp.path().join("banner.jpg");
+    let image: ImageBuffer<image::Rgb<u8>, Vec<u8>> =
+        ImageBuffer::from_pixel(60, 40, image::Rgb([40, 120, 200]));
+    image.save(&input).unwrap();
+
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        build_stage_spec(
+            "decorate",
+            &[
+                ("border", json!(5)),
+                ("border_color", json!("#ffffff")),
+                ("radius", json!(12)),
+                ("vignette", json!(0.5)),
+            ],
+        ),
+        build_stage_spec("encode", &[("format", json!("png"))]),
+    ];
+    let executor = build_pipeline(
+        &build_registry(),
+        &stages,
+        OutputSpec {
+            directory: temp.path().join("out"),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+    let results = executor.execute(std::slice::from_ref(&input)).unwrap();
+    let metadata = &results[0].metadata;
+    assert_eq!(metadata["image.width"], json!(70));
+    assert_eq!(metadata["image.height"], json!(50));
+    assert_eq!(metadata["decorate.radius"], json!(12));
+
+    let decorated = image::open(&results[0].output).unwrap().to_rgba8();
+    assert_eq!(decorated.dimensions(), (70, 50));
+    assert_eq!(decorated.get_pixel(0, 0)[3], 0);
+    assert_eq!(decorated.get_pixel(35, 1).0, [255, 255, 255, 255]);
+    // The vignette darkens the image's corners more than its center.
+    let center = decorated.get_pixel(35, 25);
+    let corner = decorated.get_pixel(6, 6);
+    assert!(corner[2] < center[2], "{corner:?} vs {center:?}");
+}
+
+#[test]
+fn redact_hides_parameter_and_sidecar_regions() {
+    let temp = tempdir().unwrap();
+    let image: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_fn(32, 24, |x, y| {
+        if (x + y) % 2 == 0 {
+            Rgba([255, 255, 255, 255])
+        } else {
+            Rgba([0, 0, 0, 255])
+        }
+    });
+    let first = temp.path().join("first.png");
+    let second = temp.path().join("second.png");
+    image.save(&first).unwrap();
+    image.save(&second).unwrap();
+    // Only `first` has a sidecar; the region past the right edge is clipped.
+    std::fs::write(
+        temp.path().join("first.regions.json"),
+        r#"{"regions": [{"x": 24, "y": 16, "width": 20, "height": 20}]}"#,
+    )
+    .unwrap();
+
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        build_stage_spec(
+            "redact",
+            &[
+                ("method", json!("pixelate")),
+                ("block_size", json!(4)),
+                (
+                    "regions",
+                    json!([{ "x": 0, "y": 0, "width": 8, "height": 8 }]),
+                ),
+                ("sidecar", json!(true)),
+            ],
+        ),
+        build_stage_spec("encode", &[("format", json!("png"))]),
+    ];
+    let executor = build_pipeline(
+        &build_registry(),
+        &stages,
+        OutputSpec {
+            directory: temp.path().join("out"),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+    let results = executor.execute(&[first, second]).unwrap();
+
+    let hidden = |x: u32, y: u32| (x < 8 && y < 8) || (x >= 24 && y >= 16);
+    let redacted = image::open(&results[0].output).unwrap().to_rgba8();
+    for (x, y, pixel) in redacted.enumerate_pixels() {
+        if hidden(x, y) {
+            assert!((127..=128).contains(&pixel[0]), "({x}, {y}) still readable");
+        } else {
+            assert_eq!(pixel, image.get_pixel(x, y));
+        }
+    }
+    assert_eq!(
+        results[0].metadata["redact.regions"],
+        json!([
+            { "x": 0, "y": 0, "width": 8, "height": 8, "source": "params" },
+            { "x": 24, "y": 16, "width": 8, "height": 8, "source": "sidecar" },
+        ])
+    );
+    assert_eq!(
+        results[1].metadata["redact.regions"]
+            .as_array()
+            .unwrap()
+            .len(),
+        1
+    );
+    let untouched = image::open(&results[1].output).unwrap().to_rgba8();
+    assert_eq!(untouched.get_pixel(30, 20), image.get_pixel(30, 20));
+}
+
+#[test]
+fn encode_preserves_exif_and_xmp_when_asked() {
+    use bunker_convert::embedded::EmbeddedMetadata;
+    use image::ImageFormat;
+
+    // A sideways photo (EXIF orientation 6) carrying a copyright notice.
+    let exif = vec![
+        b'I', b'I', 42, 0, 8, 0, 0, 0, // little-endian TIFF header, IFD at 8
+        1, 0, // one entry
+        0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0, // Orientation = 6
+        0, 0, 0, 0, // no next IFD
+    ];
+    let xmp = b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">(c) Example</x:xmpmeta>".to_vec();
+    let source = EmbeddedMetadata {
+        exif: Some(exif),
+        xmp: Some(xmp.clone()),
+    };
+    let temp = tempdir().unwrap();
+    let input = temp.path().join("photo.jpg");
+    let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+        ImageBuffer::from_pixel(8, 4, Rgba([120, 80, 40, 255]));
+    let mut jpeg = std::io::Cursor::new(Vec::new());
+    image::DynamicImage::ImageRgba8(image)
+        .to_rgb8()
+        .write_to(&mut jpeg, ImageFormat::Jpeg)
+        .unwrap();
+    std::fs::write(
+        &input,
+        source.embed(ImageFormat::Jpeg, jpeg.into_inner()).unwrap(),
+    )
+    .unwrap();
+
+    let run = |encode: &[(&str, Value)], dir: &str| {
+        build_pipeline(
+            &build_registry(),
+            &[
+                build_stage_spec("decode", &[("auto_orient", json!(true))]),
+                build_stage_spec("encode", encode),
+            ],
+            OutputSpec {
+                directory: temp.path().join(dir),
+                structure: "{stem}.{ext}".to_string(),
+            },
+            Vec::new(),
+            DevicePolicy::CpuOnly,
+        )
+        .unwrap()
+        .execute(std::slice::from_ref(&input))
+        .unwrap()
+        .remove(0)
+    };
+
+    let stripped = run(&[("format", json!("png"))], "stripped");
+    assert_eq!(stripped.metadata["image.embedded"], json!(["exif", "xmp"]));
+    let output = std::fs::read(&stripped.output).unwrap();
+    assert!(EmbeddedMetadata::read(ImageFormat::Png, &output).is_empty());
+
+    for (format, image_format) in [
+        ("png", ImageFormat::Png),
+        ("jpeg", ImageFormat::Jpeg),
+        ("webp", ImageFormat::WebP),
+    ] {
+        let kept = run(
+            &[
+                ("format", json!(format)),
+                ("preserve_metadata", json!(true)),
+                ("stream", json!(true)),
+            ],
+            format,
+        );
+        assert_eq!(kept.metadata["output.embedded"], json!(["exif", "xmp"]));
+        let output = std::fs::read(&kept.output).unwrap();
+        let carried = EmbeddedMetadata::read(image_format, &output);
+        assert_eq!(carried.xmp.as_deref(), Some(xmp.as_slice()), "{format}");
+        // The pixels were turned upright, so the orientation now says so.
+        assert_eq!(&carried.exif.unwrap()[18..20], &[1, 0], "{format}");
+        let decoded = image::load_from_memory_with_format(&output, image_format).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (4, 8), "{format}");
+    }
+
+    let exif_only = run(
+        &[
+            ("format", json!("png")),
+            ("preserve_metadata", json!("exif")),
+        ],
+        "exif-only",
+    );
+    assert_eq!(exif_only.metadata["output.embedded"], json!(["exif"]));
+}
+
+#[test]
+fn thumbnails_stage_writes_every_size_from_one_decode() {
+    let temp = tempdir().unwrap();
+    let input = temp.path().join("photo.png");
+    let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+        ImageBuffer::from_pixel(64, 32, Rgba([200, 100, 50, 255]));
+    image.save(&input).expect("failed to save test image");
+
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        build_stage_spec(
+            "thumbnails",
+            &[
+                ("format", json!("png")),
+                ("structure", json!("thumbs/{stem}-{size}.{ext}")),
+                (
+                    "sizes",
+                    json!([32, 128, { "size": 8, "structure": "icons/{stem}-{width}x{height}.{ext}" }]),
+                ),
+            ],
+        ),
+        build_stage_spec("encode", &[("format", json!("png"))]),
+    ];
+    let executor = build_pipeline(
+        &build_registry(),
+        &stages,
+        OutputSpec {
+            directory: temp.path().join("out"),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+
+    let plan = RunPlan::build(
+        Path::new("recipe.yaml"),
+        &stages,
+        &executor,
+        std::slice::from_ref(&input),
+    )
+    .unwrap();
+    let out = temp.path().join("out");
+    assert_eq!(
+        plan.inputs[0].outputs,
+        vec![
+            out.join("photo.png"),
+            out.join("thumbs/photo-32.png"),
+            out.join("thumbs/photo-128.png"),
+            out.join("icons/photo-8x4.png"),
+        ]
+    );
+
+    let result = executor
+        .execute(std::slice::from_ref(&input))
+        .unwrap()
+        .remove(0);
+    // Thumbnails leave the working image alone.
+    assert_eq!(
+        image::open(&result.output).unwrap().to_rgba8().dimensions(),
+        (64, 32)
+    );
+    for (path, dimensions) in [
+        ("thumbs/photo-32.png", (32, 16)),
+        ("thumbs/photo-128.png", (64, 32)),
+        ("icons/photo-8x4.png", (8, 4)),
+    ] {
+        let thumbnail = image::open(out.join(path)).unwrap().to_rgba8();
+        assert_eq!(thumbnail.dimensions(), dimensions, "{path}");
+    }
+    let recorded = result.metadata[THUMBNAILS_KEY].as_array().unwrap();
+    assert_eq!(recorded.len(), 3);
+    assert_eq!(recorded[0]["width"], 32);
+    assert!(recorded[0]["sha256"].is_string());
+}
+
+#[test]
+fn overwrite_policy_keeps_existing_outputs() {
+    let temp = tempdir().unwrap();
+    let input = temp.path().join("photo.png");
+    let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+        ImageBuffer::from_pixel(4, 4, Rgba([10, 20, 30, 255]));
+    image.save(&input).expect("failed to save test image");
+    let output = temp.path().join("out/photo.png");
+    std::fs::create_dir_all(output.parent().unwrap()).unwrap();
+    std::fs::write(&output, b"earlier output").unwrap();
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        build_stage_spec("encode", &[("format", Value::String("png".to_string()))]),
+    ];
+    let executor = |policy: OverwritePolicy| {
+        build_pipeline(
+            &build_registry(),
+            &stages,
+            OutputSpec {
+                directory: temp.path().join("out"),
+                structure: "{stem}.{ext}".to_string(),
+            },
+            Vec::new(),
+            DevicePolicy::CpuOnly,
+        )
+        .unwrap()
+        .with_overwrite(policy)
+    };
+
+    let never = executor(OverwritePolicy::Never);
+    let plan = RunPlan::build(
+        Path::new("recipe.yaml"),
+        &stages,
+        &never,
+        std::slice::from_ref(&input),
+    )
+    .unwrap();
+    assert_eq!(plan.inputs[0].kept_outputs, vec![output.clone()]);
+    assert!(plan.render().contains("(kept)"));
+    let results = never.execute(std::slice::from_ref(&input)).unwrap();
+    assert_eq!(results[0].output, output);
+    assert_eq!(results[0].metadata[overwrite::SKIPPED_KEY], "exists");
+    assert_eq!(never.metrics().snapshot().outputs_skipped, 1);
+    assert_eq!(std::fs::read(&output).unwrap(), b"earlier output");
+
+    // The output was written after the input, so it is up to date.
+    let results = executor(OverwritePolicy::IfNewer)
+        .execute(std::slice::from_ref(&input))
+        .unwrap();
+    assert_eq!(results[0].metadata[overwrite::SKIPPED_KEY], "up-to-date");
+
+    let always = executor(OverwritePolicy::Always);
+    let results = always.execute(std::slice::from_ref(&input)).unwrap();
+    assert!(!results[0].metadata.contains_key(overwrite::SKIPPED_KEY));
+    assert_eq!(always.metrics().snapshot().outputs_skipped, 0);
+    assert!(image::open(&output).is_ok());
+}
+
+#[test]
+fn decode_keeps_original_only_for_quality_gates() {
+    let temp = tempdir().unwrap();
+    let input_path = temp.path().join("input.png");
+    let image: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_pixel(8, 8, Rgba([1, 2, 3, 255]));
+    image.save(&input_path).expect("failed to save test image");
+
+    let registry = build_registry();
+    let decode = registry.create("decode", StageParameters::new()).unwrap();
+    let resize = registry
+        .create(
+            "resize",
+            build_stage_spec(
+                "resize",
+                &[("width", Value::from(8)), ("height", Value::from(8))],
+            )
+            .params
+            .unwrap(),
+        )
+        .unwrap();
+
+    for quality_gates_enabled in [false, true] {
+        let ctx = PipelineContext {
+            output: OutputSpec {
+                directory: temp.path().join("out"),
+                structure: "{stem}.{ext}".to_string(),
+            },
+            quality_gates_enabled,
+            cancellation: CancellationToken::new(),
+            outputs: OutputClaims::default(),
+            overwrite: OverwritePolicy::default(),
+        };
+        let mut artifact = Artifact::load(&input_path).unwrap();
+        decode.run(&mut artifact, &ctx, StageDevice::Cpu).unwrap();
+        resize.run(&mut artifact, &ctx, StageDevice::Cpu).unwrap();
+        assert_eq!(artifact.original_image.is_some(), quality_gates_enabled);
+        if let Some(original) = &artifact.original_image {
+            assert!(Arc::ptr_eq(original, artifact.image.as_ref().unwrap()));
+        }
+        let resized = artifact.image.as_ref().unwrap().to_rgba8();
+        assert_eq!(resized.as_raw(), image.as_raw());
+    }
+}
+
+#[test]
+fn artifact_clones_share_buffers_until_modified() {
+    let temp = tempdir().unwrap();
+    let input_path = temp.path().join("input.png");
+    let image: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_pixel(4, 4, Rgba([9, 9, 9, 255]));
+    image.save(&input_path).expect("failed to save test image");
+
+    let mut artifact = Artifact::load(&input_path).unwrap();
+    artifact.set_image(image::DynamicImage::ImageRgba8(image));
+    let mut branch = artifact.clone();
+    assert!(Arc::ptr_eq(&artifact.data, &branch.data));
+    assert!(Arc::ptr_eq(
+        artifact.image.as_ref().unwrap(),
+        branch.image.as_ref().unwrap()
+    ));
+
+    branch.image_mut().unwrap().as_mut_rgba8().unwrap()[(0, 0)] = Rgba([0, 0, 0, 0]);
+    assert!(!Arc::ptr_eq(
+        artifact.image.as_ref().unwrap(),
+        branch.image.as_ref().unwrap()
+    ));
+    assert_eq!(
+        artifact.image.as_ref().unwrap().as_rgba8().unwrap()[(0, 0)],
+        Rgba([9, 9, 9, 255])
+    );
+}
+
+#[test]
+fn encode_worker_pool_preserves_input_order() {
+    let temp = tempdir().unwrap();
+    let mut inputs = Vec::new();
+    for index in 0..6u8 {
+        let path = temp.path().join(format!("input{index}.png"));
+        let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+            ImageBuffer::from_pixel(8, 8, Rgba([index * 30, 0, 0, 255]));
+        image.save(&path).expect("failed to save test image");
+        inputs.push(path);
+    }
+
+    let output_dir = temp.path().join("out");
+    let output_spec = OutputSpec {
+        directory: output_dir.clone(),
+        structure: "{stem}.{ext}".to_string(),
+    };
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        build_stage_spec("encode", &[("format", Value::String("png".to_string()))]),
+    ];
+    let executor = build_pipeline(
+        &build_registry(),
+        &stages,
+        output_spec,
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap()
+    .with_encode_workers(Some(3));
+
+    let mut encode_events = 0;
+    let results = executor
+        .execute_with_progress(&inputs, |progress| {
+            if progress.stage_name == "encode" {
+                encode_events += 1;
+            }
+        })
+        .unwrap();
+    assert_eq!(encode_events, inputs.len());
+    assert_eq!(results.len(), inputs.len());
+    for (index, result) in results.iter().enumerate() {
+        assert_eq!(result.input, inputs[index]);
+        assert_eq!(result.output, output_dir.join(format!("input{index}.png")));
+        assert!(result.output.exists());
+    }
+    let snapshot = executor.metrics().snapshot();
+    assert_eq!(snapshot.stages.get("encode").unwrap().calls, 6);
+}
+
+#[test]
+fn encode_worker_pool_surfaces_encode_errors() {
+    let temp = tempdir().unwrap();
+    let input_path = temp.path().join("input.png");
+    let image: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_pixel(4, 4, Rgba([0, 0, 0, 255]));
+    image.save(&input_path).expect("failed to save test image");
+
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        build_stage_spec(
+            "encode",
+            &[
+                ("format", Value::String("png".to_string())),
+                ("compression", Value::String("extreme".to_string())),
+            ],
+        ),
+    ];
+    let executor = build_pipeline(
+        &build_registry(),
+        &stages,
+        OutputSpec {
+            directory: temp.path().join("out"),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap()
+    .with_encode_workers(Some(2));
+
+    let err = executor
+        .execute(std::slice::from_ref(&input_path))
+        .unwrap_err();
+    assert!(format!("{err:#}").contains("compression"));
+}
+
+#[test]
+fn parallel_jobs_preserve_order_and_aggregate_metrics() {
+    let temp = tempdir().unwrap();
+    let mut inputs = Vec::new();
+    for index in 0..8u8 {
+        let path = temp.path().join(format!("input{index}.png"));
+        let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+            ImageBuffer::from_pixel(8, 8, Rgba([0, index * 30, 0, 255]));
+        image.save(&path).expect("failed to save test image");
+        inputs.push(path);
+    }
+
+    let output_dir = temp.path().join("out");
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        build_stage_spec(
+            "resize",
+            &[("width", Value::from(4)), ("height", Value::from(4))],
+        ),
+        build_stage_spec("encode", &[("format", Value::String("png".to_string()))]),
+    ];
+    let executor = build_pipeline(
+        &build_registry(),
+        &stages,
+        OutputSpec {
+            directory: output_dir.clone(),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap()
+    .with_jobs(Some(4));
+
+    let mut stage_events = 0;
+    let results = executor
+        .execute_with_progress(&inputs, |_| stage_events += 1)
+        .unwrap();
+    assert_eq!(stage_events, inputs.len() * stages.len());
+    for (index, result) in results.iter().enumerate() {
+        assert_eq!(result.input, inputs[index]);
+        assert_eq!(result.output, output_dir.join(format!("input{index}.png")));
+        assert!(result.output.exists());
+    }
+    let snapshot = executor.metrics().snapshot();
+    for stage in ["decode", "resize", "encode"] {
+        assert_eq!(snapshot.stages.get(stage).unwrap().calls, 8);
+    }
+
+    // A failing input stops the run and surfaces its error.
+    std::fs::write(&inputs[5], b"not a png").unwrap();
+    assert!(executor.execute(&inputs).is_err());
+}
+
+#[test]
+fn resume_skips_inputs_converted_by_an_earlier_run() {
+    let temp = tempdir().unwrap();
+    let mut inputs = Vec::new();
+    for index in 0..3u8 {
+        let path = temp.path().join(format!("input{index}.png"));
+        let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+            ImageBuffer::from_pixel(4, 4, Rgba([index * 60, 10, 10, 255]));
+        image.save(&path).expect("failed to save test image");
+        inputs.push(path);
+    }
+    let output = OutputSpec {
+        directory: temp.path().join("out"),
+        structure: "{stem}.{ext}".to_string(),
+    };
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        build_stage_spec("encode", &[("format", Value::String("png".to_string()))]),
+    ];
+    let ledger_path = output.directory.join(LEDGER_FILE);
+    let fingerprint = resume::fingerprint(&stages, &output, &[]).unwrap();
+    let executor = |mode| {
+        build_pipeline(
+            &build_registry(),
+            &stages,
+            output.clone(),
+            Vec::new(),
+            DevicePolicy::CpuOnly,
+        )
+        .unwrap()
+        .with_resume(Some(
+            ResumeLedger::open(&ledger_path, mode, fingerprint.clone()).unwrap(),
+        ))
+    };
+
+    let first = executor(ResumeMode::Hash);
+    first.execute(&inputs[..2]).unwrap();
+    assert_eq!(first.metrics().snapshot().inputs_processed, 2);
+
+    let second = executor(ResumeMode::Hash);
+    let results = second.execute(&inputs).unwrap();
+    let snapshot = second.metrics().snapshot();
+    assert_eq!(snapshot.inputs_skipped, 2);
+    assert_eq!(snapshot.inputs_processed, 1);
+    assert_eq!(snapshot.stages.get("encode").unwrap().calls, 1);
+    assert_eq!(
+        results[0].metadata.get("resume.skipped"),
+        Some(&Value::Bool(true))
+    );
+    assert_eq!(results[2].output, output.directory.join("input2.png"));
+}
+
+#[test]
+fn output_cache_serves_identical_conversions_across_output_directories() {
+    let temp = tempdir().unwrap();
+    let input = temp.path().join("photo.png");
+    let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+        ImageBuffer::from_pixel(4, 4, Rgba([30, 90, 150, 255]));
+    image.save(&input).expect("failed to save test image");
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        build_stage_spec("encode", &[("format", Value::String("png".to_string()))]),
+    ];
+    let cache_dir = temp.path().join("cache");
+    let executor = |directory: &str| {
+        let cache = OutputCache::open(
+            &cache_dir,
+            cache::pipeline_key(&stages, &[]).unwrap(),
+            cache::DEFAULT_MAX_BYTES,
+        )
+        .unwrap();
+        build_pipeline(
+            &build_registry(),
+            &stages,
+            OutputSpec {
+                directory: temp.path().join(directory),
+                structure: "{stem}.{ext}".to_string(),
+            },
+            Vec::new(),
+            DevicePolicy::CpuOnly,
+        )
+        .unwrap()
+        .with_cache(Some(cache))
+    };
+
+    let first = executor("first");
+    let converted = first.execute(std::slice::from_ref(&input)).unwrap();
+    assert_eq!(first.metrics().snapshot().cache_misses, 1);
+
+    let second = executor("second");
+    let results = second.execute(std::slice::from_ref(&input)).unwrap();
+    let snapshot = second.metrics().snapshot();
+    assert_eq!(snapshot.cache_hits, 1);
+    assert!(!snapshot.stages.contains_key("encode"));
+    assert_eq!(results[0].output, temp.path().join("second/photo.png"));
+    assert_eq!(
+        results[0].metadata.get("cache.hit"),
+        Some(&Value::Bool(true))
+    );
+    assert_eq!(
+        compute_sha256(&results[0].output).unwrap(),
+        compute_sha256(&converted[0].output).unwrap()
+    );
+}
+
+#[test]
+fn branching_pipeline_decodes_once_and_encodes_each_branch() {
+    let temp = tempdir().unwrap();
+    let input = temp.path().join("photo.png");
+    let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+        ImageBuffer::from_pixel(4, 4, Rgba([200, 100, 50, 255]));
+    image.save(&input).expect("failed to save test image");
+
+    let mut decode = build_stage_spec("decode", &[]);
+    decode.id = Some("decoded".to_string());
+    let mut webp = build_stage_spec("encode", &[("format", Value::String("webp".to_string()))]);
+    webp.after = Some("decoded".to_string());
+    webp.output = Some(OutputSpec {
+        directory: temp.path().join("webp"),
+        structure: "{stem}.{ext}".to_string(),
+    });
+    let stages = vec![
+        decode,
+        build_stage_spec("encode", &[("format", Value::String("png".to_string()))]),
+        webp,
+    ];
+    let executor = build_pipeline(
+        &build_registry(),
+        &stages,
+        OutputSpec {
+            directory: temp.path().join("out"),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap()
+    .with_jobs(Some(2));
+    assert!(executor.is_branched());
+
+    let inputs = vec![input];
+    let results = executor.execute(&inputs).unwrap();
+    let outputs: Vec<_> = results.iter().map(|result| result.output.clone()).collect();
+    assert_eq!(
+        outputs,
+        vec![
+            temp.path().join("out/photo.png"),
+            temp.path().join("webp/photo.webp")
+        ]
+    );
+    assert!(outputs.iter().all(|output| output.is_file()));
+    let snapshot = executor.metrics().snapshot();
+    assert_eq!(snapshot.stages.get("decode").unwrap().calls, 1);
+    assert_eq!(snapshot.stages.get("encode").unwrap().calls, 2);
+
+    let mut iter = executor.execute_iter(&inputs);
+    assert_eq!(iter.size_hint(), (1, None));
+    assert_eq!(iter.by_ref().filter_map(Result::ok).count(), 2);
+}
+
+#[test]
+fn recipe_variants_produce_one_output_each() {
+    let temp = tempdir().unwrap();
+    let input = temp.path().join("photo.png");
+    let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+        ImageBuffer::from_pixel(8, 8, Rgba([10, 20, 30, 255]));
+    image.save(&input).expect("failed to save test image");
+    let recipe_path = temp.path().join("recipe.yaml");
+    std::fs::write(
+        &recipe_path,
+        format!(
+            r#"
+version: 1
+inputs:
+  - path: "{input}"
+pipeline:
+  - stage: decode
+variants:
+  - name: full
+    pipeline:
+      - stage: encode
+        params: {{ format: webp }}
+  - name: thumb
+    structure: "{{stem}}_{{variant}}.{{ext}}"
+    pipeline:
+      - stage: resize
+        params: {{ width: 2, height: 2 }}
+      - stage: encode
+        params: {{ format: png }}
+output:
+  directory: "{output}"
+"#,
+            input = input.display(),
+            output = temp.path().join("out").display()
+        ),
+    )
+    .unwrap();
+
+    let recipe = Recipe::load(&recipe_path).unwrap();
+    assert_eq!(recipe.pipeline.len(), 6);
+    let executor = build_pipeline(
+        &build_registry(),
+        &recipe.pipeline,
+        recipe.output.clone(),
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+    let results = executor.execute(&recipe.expand_inputs().unwrap()).unwrap();
+    let outputs: Vec<_> = results.iter().map(|result| result.output.clone()).collect();
+    assert_eq!(
+        outputs,
+        vec![
+            temp.path().join("out/photo.webp"),
+            temp.path().join("out/photo_thumb.png")
+        ]
+    );
+    assert_eq!(image::image_dimensions(&outputs[1]).unwrap(), (2, 2));
+    assert_eq!(results[0].metadata.get("variant"), Some(&json!("full")));
+    assert_eq!(
+        executor
+            .metrics()
+            .snapshot()
+            .stages
+            .get("decode")
+            .unwrap()
+            .calls,
+        1
+    );
+}
+
+#[test]
+fn when_clause_skips_stages_per_input() {
+    let temp = tempdir().unwrap();
+    let mut inputs = Vec::new();
+    for (name, size) in [("small.png", 4), ("large.png", 8)] {
+        let path = temp.path().join(name);
+        let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+            ImageBuffer::from_pixel(size, size, Rgba([10, 20, 30, 255]));
+        image.save(&path).expect("failed to save test image");
+        inputs.push(path);
+    }
+    let mut resize = build_stage_spec(
+        "resize",
+        &[("width", Value::from(2)), ("height", Value::from(2))],
+    );
+    resize.when = Some("image.width > 6 && format == 'png'".to_string());
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        resize,
+        build_stage_spec("encode", &[("format", Value::String("png".to_string()))]),
+    ];
+    let executor = build_pipeline(
+        &build_registry(),
+        &stages,
+        OutputSpec {
+            directory: temp.path().join("out"),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+
+    let results = executor.execute(&inputs).unwrap();
+    let dimensions: Vec<_> = results
+        .iter()
+        .map(|result| image::image_dimensions(&result.output).unwrap())
+        .collect();
+    assert_eq!(dimensions, vec![(4, 4), (2, 2)]);
+    assert_eq!(
+        results[0].metadata.get("pipeline.skipped_stages"),
+        Some(&serde_json::json!(["resize"]))
+    );
+    assert!(!results[1].metadata.contains_key("pipeline.skipped_stages"));
+    assert_eq!(
+        executor
+            .metrics()
+            .snapshot()
+            .stages
+            .get("resize")
+            .unwrap()
+            .calls,
+        1
+    );
+}
+
+/// Fails its first `failures` runs after tagging the artifact, so a retry
+/// that did not start from a clean copy would be visible.
+struct FlakyStage {
+    failures: usize,
+    runs: Arc<AtomicUsize>,
+}
+
+impl Stage for FlakyStage {
+    fn name(&self) -> &'static str {
+        "flaky"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        device == StageDevice::Cpu
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        _ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let run = self.runs.fetch_add(1, Ordering::SeqCst);
+        if artifact.metadata.contains_key("flaky.touched") {
+            bail!("retry saw the artifact from a failed attempt");
+        }
+        artifact
+            .metadata
+            .insert("flaky.touched".to_string(), Value::Bool(true));
+        if run < self.failures {
+            bail!("transient failure {}", run + 1);
+        }
+        Ok(())
+    }
+}
+
+/// Reports running on hardware for its first `hardware_runs` calls and in
+/// software after that.
+struct HardwareStage {
+    hardware_runs: usize,
+    runs: AtomicUsize,
+}
+
+impl Stage for HardwareStage {
+    fn name(&self) -> &'static str {
+        "hardware"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        device == StageDevice::Cpu
+    }
+
+    fn run(
+        &self,
+        artifact: &mut Artifact,
+        _ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        let key = hardware_key(self.name());
+        if self.runs.fetch_add(1, Ordering::SeqCst) < self.hardware_runs {
+            artifact.metadata.insert(key, Value::from("nvdec"));
+        } else {
+            artifact.metadata.remove(&key);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn stage_metrics_count_calls_that_ran_on_hardware() {
+    let temp = tempdir().unwrap();
+    let inputs: Vec<PathBuf> = (0..3)
+        .map(|index| {
+            let input = temp.path().join(format!("input-{index}.png"));
+            let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+                ImageBuffer::from_pixel(4, 4, Rgba([10, 20, 30, 255]));
+            image.save(&input).expect("failed to save test image");
+            input
+        })
+        .collect();
+
+    let mut registry = build_registry();
+    registry.register("hardware", |_| {
+        Ok(Box::new(HardwareStage {
+            hardware_runs: 2,
+            runs: AtomicUsize::new(0),
+        }) as Box<dyn Stage>)
+    });
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        build_stage_spec("hardware", &[]),
+        build_stage_spec("encode", &[("format", Value::String("png".to_string()))]),
+    ];
+    let executor = build_pipeline(
+        &registry,
+        &stages,
+        OutputSpec {
+            directory: temp.path().join("out"),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+
+    let results = executor.execute(&inputs).unwrap();
+    assert_eq!(
+        results[0].metadata.get("hardware.hardware"),
+        Some(&Value::from("nvdec"))
+    );
+    assert!(!results[2].metadata.contains_key("hardware.hardware"));
+    let snapshot = executor.metrics().snapshot();
+    let stage = snapshot.stages.get("hardware").unwrap();
+    assert_eq!((stage.calls, stage.hardware_calls), (3, 2));
+    assert!(
+        snapshot
+            .to_prometheus()
+            .contains("bunker_stage_hardware_calls_total{stage=\"hardware\"} 2")
+    );
+}
+
+#[test]
+fn retry_policy_reruns_failed_stages_from_a_clean_artifact() {
+    let temp = tempdir().unwrap();
+    let input = temp.path().join("input.png");
+    let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+        ImageBuffer::from_pixel(4, 4, Rgba([10, 20, 30, 255]));
+    image.save(&input).expect("failed to save test image");
+
+    let runs = Arc::new(AtomicUsize::new(0));
+    let mut registry = build_registry();
+    let stage_runs = Arc::clone(&runs);
+    registry.register("flaky", move |_| {
+        Ok(Box::new(FlakyStage {
+            failures: 2,
+            runs: Arc::clone(&stage_runs),
+        }) as Box<dyn Stage>)
+    });
+    let build = |max_attempts: u32| {
+        let mut flaky = build_stage_spec("flaky", &[]);
+        flaky.retry = Some(RetryPolicy {
+            max_attempts,
+            backoff_ms: 1,
+            ..RetryPolicy::default()
+        });
+        let stages = vec![
+            build_stage_spec("decode", &[]),
+            flaky,
+            build_stage_spec("encode", &[("format", Value::String("png".to_string()))]),
+        ];
+        build_pipeline(
+            &registry,
+            &stages,
+            OutputSpec {
+                directory: temp.path().join("out"),
+                structure: "{stem}.{ext}".to_string(),
+            },
+            Vec::new(),
+            DevicePolicy::CpuOnly,
+        )
+        .unwrap()
+    };
+
+    let executor = build(3);
+    let results = executor.execute(std::slice::from_ref(&input)).unwrap();
+    assert_eq!(runs.load(Ordering::SeqCst), 3);
+    assert_eq!(
+        results[0].metadata.get("retry.flaky.attempts"),
+        Some(&Value::from(3))
+    );
+    let snapshot = executor.metrics().snapshot();
+    let flaky = snapshot.stages.get("flaky").unwrap();
+    assert_eq!((flaky.calls, flaky.retries), (1, 2));
+
+    runs.store(0, Ordering::SeqCst);
+    let error = build(2).execute(&[input]).unwrap_err();
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+    assert!(format!("{error:#}").contains("failed after 2 attempt(s)"));
+}
+
+/// Cancels the run from inside the first input, as Ctrl+C would mid-stage.
+struct CancellingStage;
+
+impl Stage for CancellingStage {
+    fn name(&self) -> &'static str {
+        "cancel"
+    }
+
+    fn supports_device(&self, device: StageDevice) -> bool {
+        device == StageDevice::Cpu
+    }
+
+    fn run(
+        &self,
+        _artifact: &mut Artifact,
+        ctx: &PipelineContext,
+        _device: StageDevice,
+    ) -> Result<()> {
+        ctx.cancellation.cancel();
+        Ok(())
+    }
+}
+
+#[test]
+fn cancellation_finishes_in_flight_inputs_and_leaves_the_rest_pending() {
+    let temp = tempdir().unwrap();
+    let mut inputs = Vec::new();
+    for name in ["a.png", "b.png", "c.png"] {
+        let path = temp.path().join(name);
+        let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+            ImageBuffer::from_pixel(4, 4, Rgba([10, 20, 30, 255]));
+        image.save(&path).expect("failed to save test image");
+        inputs.push(path);
+    }
+    let mut registry = build_registry();
+    registry.register(
+        "cancel",
+        |_| Ok(Box::new(CancellingStage) as Box<dyn Stage>),
+    );
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        build_stage_spec("cancel", &[]),
+        build_stage_spec("encode", &[("format", Value::String("png".to_string()))]),
+    ];
+    let output_dir = temp.path().join("out");
+    let token = CancellationToken::new();
+    let executor = build_pipeline(
+        &registry,
+        &stages,
+        OutputSpec {
+            directory: output_dir.clone(),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap()
+    .with_cancellation(token.clone());
+
+    let results = executor.execute(&inputs).unwrap();
+    assert!(token.is_cancelled());
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].input, inputs[0]);
+    assert!(results[0].output.is_file());
+    assert!(!output_dir.join("b.png").exists());
+
+    let partial = PartialManifest::new(&inputs, &results, None);
+    assert_eq!(partial.pending, inputs[1..].to_vec());
+}
+
+#[test]
+fn run_manifest_lists_outputs_with_digests_and_totals() {
+    let temp = tempdir().unwrap();
+    let mut inputs = Vec::new();
+    for index in 0..2u8 {
+        let path = temp.path().join(format!("input{index}.png"));
+        let image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+            ImageBuffer::from_pixel(4, 4, Rgba([index * 100, 20, 40, 255]));
+        image.save(&path).expect("failed to save test image");
+        inputs.push(path);
+    }
+
+    let output_spec = OutputSpec {
+        directory: temp.path().join("out"),
+        structure: "{stem}.{ext}".to_string(),
+    };
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        build_stage_spec("encode", &[("format", Value::String("png".to_string()))]),
+    ];
+    let executor = build_pipeline(
+        &build_registry(),
+        &stages,
+        output_spec,
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+    let results = executor.execute(&inputs).unwrap();
+
+    let manifest = RunManifest::from_results(&results, None).unwrap();
+    assert_eq!(manifest.entries.len(), 2);
+    for (entry, result) in manifest.entries.iter().zip(&results) {
+        assert_eq!(entry.output, result.output);
+        assert_eq!(
+            entry.output_sha256.as_deref(),
+            Some(compute_sha256(&result.output).unwrap().as_str())
+        );
+        assert_eq!(
+            entry.output_bytes,
+            Some(std::fs::metadata(&result.output).unwrap().len())
+        );
+        assert_eq!(entry.quality.status, "not_configured");
+    }
+    assert_eq!(manifest.totals.inputs, 2);
+    assert_eq!(manifest.totals.outputs, 2);
+    assert_eq!(
+        manifest.totals.output_bytes,
+        manifest
+            .entries
+            .iter()
+            .filter_map(|entry| entry.output_bytes)
+            .sum::<u64>()
+    );
+
+    let json_path = temp.path().join("reports/manifest.json");
+    manifest
+        .write(&json_path, ManifestFormat::from_path(&json_path))
+        .unwrap();
+    let json: Value = serde_json::from_str(&std::fs::read_to_string(&json_path).unwrap()).unwrap();
+    assert_eq!(json["entries"].as_array().unwrap().len(), 2);
+    assert_eq!(json["totals"]["outputs"], 2);
+    assert_eq!(json["entries"][0]["metadata"]["image.width"], 4);
+
+    let jsonl_path = temp.path().join("reports/manifest.jsonl");
+    manifest
+        .write(&jsonl_path, ManifestFormat::from_path(&jsonl_path))
+        .unwrap();
+    let jsonl = std::fs::read_to_string(&jsonl_path).unwrap();
+    let rows: Vec<Value> = jsonl
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[1]["input"], json!(inputs[1]));
+    assert_eq!(rows[1]["metadata"]["output.format"], "png");
+
+    let csv_path = temp.path().join("reports/manifest.csv");
+    manifest
+        .write(&csv_path, ManifestFormat::from_path(&csv_path))
+        .unwrap();
+    let csv = std::fs::read_to_string(&csv_path).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].starts_with("input,output,"));
+    assert!(lines[3].starts_with("TOTAL (2 inputs)"));
+}
+
+#[test]
+fn duplicate_inputs_are_converted_once() {
+    let temp = tempdir().unwrap();
+    let image: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_pixel(4, 4, Rgba([9, 8, 7, 255]));
+    let mut inputs = Vec::new();
+    for folder in ["a", "b"] {
+        let dir = temp.path().join(folder);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("{folder}_logo.png"));
+        image.save(&path).expect("failed to save test image");
+        inputs.push(path);
+    }
+    let other = temp.path().join("other.png");
+    ImageBuffer::<Rgba<u8>, Vec<u8>>::from_pixel(4, 4, Rgba([1, 2, 3, 255]))
+        .save(&other)
+        .unwrap();
+    inputs.push(other);
+
+    let plan = DedupPlan::from_inputs(&inputs).unwrap();
+    assert_eq!(plan.unique.len(), 2);
+    assert_eq!(plan.duplicates.len(), 1);
+    assert_eq!(plan.duplicates[0].input, inputs[1]);
+
+    let output_spec = OutputSpec {
+        directory: temp.path().join("out"),
+        structure: "{stem}.{ext}".to_string(),
+    };
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        build_stage_spec("encode", &[("format", Value::String("png".to_string()))]),
+    ];
+    let executor = build_pipeline(
+        &build_registry(),
+        &stages,
+        output_spec.clone(),
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+    let results = executor.execute(&plan.unique).unwrap();
+    let results = plan
+        .expand(results, &output_spec, DuplicateMode::Copy)
+        .unwrap();
+
+    assert_eq!(results.len(), 3);
+    for (result, input) in results.iter().zip(&inputs) {
+        assert_eq!(&result.input, input);
+        assert!(result.output.is_file());
+    }
+    assert_eq!(results[1].output, temp.path().join("out/b_logo.png"));
+    assert_eq!(
+        std::fs::read(&results[0].output).unwrap(),
+        std::fs::read(&results[1].output).unwrap()
+    );
+
+    let manifest = RunManifest::from_results(&results, None).unwrap();
+    assert_eq!(manifest.entries[1].duplicate_of.as_ref(), Some(&inputs[0]));
+    assert_eq!(manifest.totals.duplicates, 1);
+    assert_eq!(manifest.totals.outputs, 3);
+
+    let aliased = plan
+        .expand(
+            executor.execute(&plan.unique).unwrap(),
+            &output_spec,
+            DuplicateMode::Alias,
+        )
+        .unwrap();
+    assert_eq!(aliased[1].output, aliased[0].output);
+    let manifest = RunManifest::from_results(&aliased, None).unwrap();
+    assert_eq!(manifest.totals.outputs, 2);
+}
+
+#[test]
+fn near_duplicate_inputs_are_matched_by_perceptual_hash() {
+    let temp = tempdir().unwrap();
+    let photo = ImageBuffer::from_fn(64, 48, |x, y| {
+        let block = ((x / 16) * 3 + (y / 12) * 7) % 5;
+        let shade = (block * 50 + x) as u8;
+        Rgba([shade, 255 - shade, shade / 2, 255])
+    });
+    let original = temp.path().join("photo.png");
+    photo.save(&original).unwrap();
+    let smaller = temp.path().join("photo_small.png");
+    image::imageops::resize(&photo, 32, 24, image::imageops::FilterType::Triangle)
+        .save(&smaller)
+        .unwrap();
+    let mirrored = temp.path().join("mirrored.png");
+    image::imageops::flip_horizontal(&photo)
+        .save(&mirrored)
+        .unwrap();
+    let inputs = vec![original.clone(), smaller.clone(), mirrored.clone()];
+
+    let output_spec = OutputSpec {
+        directory: temp.path().join("out"),
+        structure: "{stem}.{ext}".to_string(),
+    };
+    let stages = vec![
+        build_stage_spec("decode", &[]),
+        build_stage_spec("phash", &[("algorithms", json!(["phash", "dhash"]))]),
+        build_stage_spec("encode", &[("format", json!("png"))]),
+    ];
+    let executor = build_pipeline(
+        &build_registry(),
+        &stages,
+        output_spec.clone(),
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+    let hashed = executor.execute(&inputs).unwrap();
+    let hash = |index: usize, key: &str| {
+        let hex = hashed[index].metadata[key].as_str().unwrap();
+        assert_eq!(hex.len(), 16);
+        u64::from_str_radix(hex, 16).unwrap()
+    };
+    assert!(!hashed[0].metadata.contains_key("hash.ahash"));
+    assert!(perceptual::distance(hash(0, "hash.phash"), hash(1, "hash.phash")) <= 4);
+    assert!(perceptual::distance(hash(0, "hash.dhash"), hash(2, "hash.dhash")) > 16);
+
+    let exact = DedupPlan::from_inputs(&inputs).unwrap();
+    assert!(exact.duplicates.is_empty());
+    let similarity = Similarity {
+        algorithm: HashAlgorithm::Phash,
+        distance: 6,
+    };
+    let plan = DedupPlan::from_similar_inputs(&inputs, similarity).unwrap();
+    assert_eq!(plan.unique, vec![original.clone(), mirrored]);
+    assert_eq!(plan.duplicates.len(), 1);
+    assert_eq!(plan.duplicates[0].input, smaller);
+    assert!(plan.duplicates[0].distance.unwrap() <= 4);
+
+    let results = plan
+        .expand(
+            executor.execute(&plan.unique).unwrap(),
+            &output_spec,
+            DuplicateMode::Alias,
+        )
+        .unwrap();
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[1].output, results[0].output);
+    assert_eq!(
+        results[1].metadata["dedup.source"],
+        json!(original.to_string_lossy())
+    );
+    assert!(results[1].metadata["dedup.distance"].is_u64());
+}
+
+#[test]
+fn archive_members_are_expanded_and_mirrored_in_outputs() {
+    use std::io::{Cursor, Write};
+
+    use bunker_convert::Recipe;
+    use zip::write::SimpleFileOptions;
+
+    let temp = tempdir().unwrap();
+    let archive_path = temp.path().join("photos.zip");
+    {
+        let mut png = Vec::new();
+        ImageBuffer::from_pixel(4, 4, Rgba([0u8, 128, 255, 255]))
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&archive_path).unwrap());
+        let options = SimpleFileOptions::default();
+        zip.add_directory("2024/", options).unwrap();
+        for name in ["2024/beach.png", "cover.png"] {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(&png).unwrap();
+        }
+        zip.start_file("readme.txt", options).unwrap();
+        zip.write_all(b"not an image").unwrap();
+        zip.finish().unwrap();
+    }
+
+    let recipe_path = temp.path().join("recipe.yaml");
+    std::fs::write(
+        &recipe_path,
+        format!(
+            "version: 1\ninputs:\n  - path: \"{}\"\n    members: \"*.png\"\npipeline: []\noutput:\n  directory: out\n",
+            temp.path().join("*.zip").display()
+        ),
+    )
+    .unwrap();
+    let recipe = Recipe::load(&recipe_path).unwrap();
+    let inputs = recipe.expand_inputs().unwrap();
+    assert_eq!(
+        inputs,
+        vec![
+            archive_path.join("2024/beach.png"),
+            archive_path.join("cover.png")
+        ]
+    );
+
+    let output_dir = temp.path().join("out");
+    let executor = build_pipeline(
+        &build_registry(),
+        &[
+            build_stage_spec("decode", &[]),
+            build_stage_spec("encode", &[("format", Value::String("png".into()))]),
+        ],
+        OutputSpec {
+            directory: output_dir.clone(),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+    let results = executor.execute(&inputs).unwrap();
+    assert_eq!(results[0].output, output_dir.join("photos/2024/beach.png"));
+    assert_eq!(results[1].output, output_dir.join("photos/cover.png"));
+    assert!(results.iter().all(|result| result.output.is_file()));
+    assert_eq!(
+        results[0]
+            .metadata
+            .get("archive.member")
+            .and_then(Value::as_str),
+        Some("2024/beach.png")
+    );
+}
+
+#[test]
+fn outputs_package_into_zip_with_sidecars() {
+    use std::io::Read;
+
+    use bunker_convert::archive::{self, PackageEntry, PackageSource};
+
+    let temp = tempdir().unwrap();
+    let inputs: Vec<PathBuf> = ["a.png", "b.png"]
+        .iter()
+        .map(|name| {
+            let path = temp.path().join(name);
+            ImageBuffer::from_pixel(4, 4, Rgba([9u8, 9, 9, 255]))
+                .save(&path)
+                .unwrap();
+            path
+        })
+        .collect();
+    let output_dir = temp.path().join("out");
+    let executor = build_pipeline(
+        &build_registry(),
+        &[
+            build_stage_spec("decode", &[]),
+            build_stage_spec("encode", &[("format", Value::String("png".into()))]),
+        ],
+        OutputSpec {
+            directory: output_dir.clone(),
+            structure: "renders/{stem}.{ext}".to_string(),
+        },
+        Vec::new(),
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+    let results = executor.execute(&inputs).unwrap();
+
+    let mut entries = archive::output_entries(&results, &output_dir, true).unwrap();
+    entries.push(PackageEntry {
+        name: "manifest.json".to_string(),
+        source: PackageSource::Bytes(b"{}".to_vec()),
+    });
+    let bundle = temp.path().join("delivery.zip");
+    archive::package(&bundle, &entries).unwrap();
+
+    let mut zip = zip::ZipArchive::new(std::fs::File::open(&bundle).unwrap()).unwrap();
+    let names: Vec<&str> = zip.file_names().collect();
+    assert_eq!(names.len(), 5);
+    for name in [
+        "renders/a.png",
+        "renders/a.png.json",
+        "renders/b.png",
+        "manifest.json",
+    ] {
+        assert!(names.contains(&name), "missing {name} in {names:?}");
+    }
+    let mut packaged = Vec::new();
+    zip.by_name("renders/b.png")
+        .unwrap()
+        .read_to_end(&mut packaged)
+        .unwrap();
+    assert_eq!(
+        packaged,
+        std::fs::read(output_dir.join("renders/b.png")).unwrap()
+    );
+    let mut sidecar = String::new();
+    zip.by_name("renders/a.png.json")
+        .unwrap()
+        .read_to_string(&mut sidecar)
+        .unwrap();
+    let sidecar: Value = serde_json::from_str(&sidecar).unwrap();
+    assert_eq!(sidecar["output.format"], Value::String("png".into()));
+}
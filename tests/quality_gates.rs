@@ -4,6 +4,10 @@ use bunker_convert::pipeline::{
 use bunker_convert::recipe::QualityGateSpec;
 use bunker_convert::scheduler::DevicePolicy;
 use bunker_convert::stages;
+use bunker_convert::video::{
+    ColorSpace, FramePlanes, FrameRate, HdrMetadata, PixelFormat, TransferFunction, VideoCodec,
+    VideoFrame, VideoStream, h264,
+};
 use image::{ImageBuffer, Rgba};
 use serde_json::Value;
 use tempfile::tempdir;
@@ -22,6 +26,7 @@ fn stage(name: &str, params: &[(&str, Value)]) -> StageSpec {
     StageSpec {
         stage: name.to_string(),
         params: Some(map),
+        ..StageSpec::default()
     }
 }
 
@@ -36,6 +41,48 @@ fn save_test_image(path: &std::path::Path) {
     img.save(path).expect("failed to save fixture image");
 }
 
+/// Writes four 32x32 frames of a drifting gradient as a raw H.264 stream.
+fn save_test_video(path: &std::path::Path) {
+    let frames = (0..4u32)
+        .map(|index| {
+            let y = (0..32 * 32)
+                .map(|i| (i % 32 * 6 + i / 32 * 2 + index * 8) as u8)
+                .collect();
+            VideoFrame {
+                width: 32,
+                height: 32,
+                pixel_format: PixelFormat::Yuv420,
+                data: FramePlanes::Yuv420 {
+                    y,
+                    u: vec![110; 16 * 16],
+                    v: vec![140; 16 * 16],
+                },
+                timestamp: std::time::Duration::from_millis(u64::from(index) * 40),
+                duration: std::time::Duration::from_millis(40),
+                keyframe: index == 0,
+            }
+        })
+        .collect();
+    let stream = VideoStream {
+        codec: VideoCodec::H264,
+        frame_rate: FrameRate::Constant {
+            numerator: 25,
+            denominator: 1,
+        },
+        frames,
+        color_space: ColorSpace::Bt709,
+        transfer: TransferFunction::Sdr,
+        rotation: 0,
+        hdr: HdrMetadata::default(),
+    };
+    let config = h264::EncoderConfig {
+        qp: 4,
+        ..h264::EncoderConfig::default()
+    };
+    let encoded = h264::encode(&stream, &config).unwrap();
+    std::fs::write(path, encoded.to_annex_b()).expect("failed to save fixture video");
+}
+
 #[test]
 fn quality_gate_passes_for_lossless_pipeline() {
     let temp = tempdir().unwrap();
@@ -53,6 +100,7 @@ fn quality_gate_passes_for_lossless_pipeline() {
         min_ssim: Some(0.999),
         min_psnr: Some(60.0),
         max_mse: Some(1e-6),
+        ..QualityGateSpec::default()
     }];
 
     let stages = vec![
@@ -90,6 +138,7 @@ fn quality_gate_fails_for_lossy_output() {
         min_ssim: Some(0.9999999),
         min_psnr: Some(50.0),
         max_mse: None,
+        ..QualityGateSpec::default()
     }];
 
     let stages = vec![
@@ -125,3 +174,65 @@ fn quality_gate_fails_for_lossy_output() {
     assert_eq!(snapshot.quality_passes, 0);
     assert_eq!(snapshot.quality_failures, 1);
 }
+
+#[test]
+fn video_quality_gates_compare_every_frame() {
+    let temp = tempdir().unwrap();
+    let input = temp.path().join("input.h264");
+    save_test_video(&input);
+    let output = OutputSpec {
+        directory: temp.path().join("out"),
+        structure: "{stem}.{ext}".to_string(),
+    };
+    let stages = |qp: u64| {
+        vec![
+            stage("video_decode", &[]),
+            stage("video_encode", &[("qp", Value::from(qp))]),
+        ]
+    };
+
+    let gates = vec![QualityGateSpec {
+        label: Some("video".into()),
+        min_frame_psnr: Some(35.0),
+        min_mean_ssim: Some(0.99),
+        ..QualityGateSpec::default()
+    }];
+    let executor = build_pipeline(
+        &registry(),
+        &stages(10),
+        output.clone(),
+        gates,
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+    let results = executor
+        .execute(std::slice::from_ref(&input))
+        .expect("video quality gate should pass");
+    let metadata = &results[0].metadata;
+    assert_eq!(metadata["quality.status"], "passed");
+    assert_eq!(metadata["output.decode_supported"], true);
+    assert_eq!(metadata["quality.video.frames"], 4);
+    let min_psnr = metadata["quality.video.min_psnr"].as_f64().unwrap();
+    let mean_psnr = metadata["quality.video.mean_psnr"].as_f64().unwrap();
+    assert!(min_psnr >= 35.0 && mean_psnr >= min_psnr);
+    assert!(metadata.get("quality.psnr").is_none());
+
+    // The coarsest quantiser leaves every frame far below 60 dB.
+    let gates = vec![QualityGateSpec {
+        min_frame_psnr: Some(60.0),
+        ..QualityGateSpec::default()
+    }];
+    let executor = build_pipeline(
+        &registry(),
+        &stages(51),
+        output,
+        gates,
+        DevicePolicy::CpuOnly,
+    )
+    .unwrap();
+    let err = executor
+        .execute(&[input])
+        .expect_err("video quality gate should fail");
+    assert!(format!("{err:#}").contains("Quality gate 'frame_psnr' failed: PSNR"));
+    assert_eq!(executor.metrics().snapshot().quality_failures, 1);
+}
@@ -20,6 +20,8 @@ fn base_recipe(output_dir: PathBuf) -> Recipe {
         version: 1,
         inputs: vec![InputSpec {
             path: "./examples/input/*.png".to_string(),
+            members: None,
+            include_hidden: false,
         }],
         pipeline: Vec::new(),
         output: OutputSpec {
@@ -27,6 +29,8 @@ fn base_recipe(output_dir: PathBuf) -> Recipe {
             structure: "{stem}.{ext}".to_string(),
         },
         quality_gates: Vec::new(),
+        notify: None,
+        overwrite: None,
     }
 }
 
@@ -38,6 +42,7 @@ fn stage_spec(name: &str, params: &[(&str, serde_json::Value)]) -> StageSpec {
     StageSpec {
         stage: name.to_string(),
         params: Some(map),
+        ..StageSpec::default()
     }
 }
 
@@ -48,6 +53,7 @@ fn validation_catches_missing_params() {
     recipe.pipeline.push(StageSpec {
         stage: "resize".to_string(),
         params: Some(StageParameters::default()),
+        ..StageSpec::default()
     });
 
     let registry = build_registry();
@@ -81,3 +87,30 @@ fn lockfile_generates_expected_yaml() {
     assert!(content.contains("stages"));
     assert!(content.contains("params_hash"));
 }
+
+#[test]
+fn validation_follows_pipeline_branches() {
+    let temp = tempdir().unwrap();
+    let mut recipe = base_recipe(temp.path().join("out"));
+    let mut decode = stage_spec("decode", &[]);
+    decode.id = Some("decoded".to_string());
+    let mut webp = stage_spec("encode", &[("format", json!("webp"))]);
+    webp.after = Some("decoded".to_string());
+    recipe.pipeline = vec![
+        decode,
+        stage_spec("encode", &[("format", json!("png"))]),
+        webp,
+    ];
+    let registry = build_registry();
+    let report = validate_recipe(&recipe, &registry);
+    assert!(report.is_ok(), "unexpected errors: {:?}", report.errors);
+
+    recipe.pipeline[2].after = Some("missing".to_string());
+    let report = validate_recipe(&recipe, &registry);
+    assert!(
+        report
+            .errors
+            .iter()
+            .any(|error| error.contains("unknown stage id 'missing'"))
+    );
+}
@@ -3,22 +3,153 @@ use std::path::Path;
 
 use anyhow::Result;
 
+use bunker_convert::cancel::CancellationToken;
+use bunker_convert::collision::OutputClaims;
+use bunker_convert::overwrite::OverwritePolicy;
 use bunker_convert::pipeline::{
     Artifact, OutputSpec, PipelineContext, StageParameters, StageRegistry,
 };
 use bunker_convert::scheduler::StageDevice;
 use bunker_convert::stages;
 
-const ANNEX_B_SAMPLE: &[u8] = &[
-    0x00, 0x00, 0x01, 0x67, 0x42, 0xE0, 0x1E, 0x8D, 0x68, 0x50, 0x1E, 0xD8, 0x08, 0x80, 0x00, 0x00,
-    0x01, 0x68, 0xCE, 0x06, 0xE2, 0x00, 0x00, 0x01, 0x65, 0x88, 0x84, 0x21, 0xA0,
-];
+use bunker_convert::video::container::Mp4Demuxer;
+use bunker_convert::video::{
+    ColorSpace, FramePlanes, FrameRate, MediaStreams, TransferFunction, VideoCodec, h264,
+};
+
+/// Writes H.264 syntax elements most significant bit first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u32,
+    filled: u32,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u32, count: u32) -> &mut Self {
+        for shift in (0..count).rev() {
+            self.bits = (self.bits << 1) | ((value >> shift) & 1);
+            self.filled += 1;
+            if self.filled == 8 {
+                self.bytes.push(self.bits as u8);
+                self.bits = 0;
+                self.filled = 0;
+            }
+        }
+        self
+    }
+
+    fn ue(&mut self, value: u32) -> &mut Self {
+        let code = value + 1;
+        let length = 32 - code.leading_zeros();
+        self.bits(0, length - 1).bits(code, length)
+    }
+
+    fn se(&mut self, value: i32) -> &mut Self {
+        let code = if value > 0 {
+            2 * value as u32 - 1
+        } else {
+            2 * value.unsigned_abs()
+        };
+        self.ue(code)
+    }
+
+    fn align(&mut self) -> &mut Self {
+        while self.filled != 0 {
+            self.bits(0, 1);
+        }
+        self
+    }
+
+    fn bytes(&mut self, value: u8, count: usize) -> &mut Self {
+        for _ in 0..count {
+            self.bits(u32::from(value), 8);
+        }
+        self
+    }
+
+    /// Appends `rbsp_trailing_bits` and the NAL unit, with its start code
+    /// and emulation prevention, to `stream`.
+    fn finish_nal(&mut self, header: u8, stream: &mut Vec<u8>) {
+        self.bits(1, 1).align();
+        stream.extend_from_slice(&[0, 0, 0, 1, header]);
+        let mut zeros = 0;
+        for &byte in &self.bytes {
+            if zeros == 2 && byte <= 3 {
+                stream.push(3);
+                zeros = 0;
+            }
+            stream.push(byte);
+            zeros = if byte == 0 { zeros + 1 } else { 0 };
+        }
+    }
+}
+
+/// I_PCM macroblock with flat planes.
+fn pcm(slice: &mut BitWriter, luma: u8, cb: u8, cr: u8) {
+    slice
+        .ue(25)
+        .align()
+        .bytes(luma, 256)
+        .bytes(cb, 64)
+        .bytes(cr, 64);
+}
+
+/// A 28x32 baseline stream (2x2 macroblocks, 4 columns cropped): an IDR
+/// picture mixing I_PCM and Intra16x16 macroblocks, then a P picture that
+/// skips three macroblocks and moves the fourth half a macroblock left.
+fn annex_b_sample() -> Vec<u8> {
+    let mut stream = Vec::new();
+
+    let mut sps = BitWriter::default();
+    sps.bits(66, 8).bits(0xC0, 8).bits(30, 8).ue(0); // profile, constraints, level, id
+    sps.ue(0).ue(2).ue(1).bits(0, 1); // log2_max_frame_num, POC type 2, one reference
+    sps.ue(1).ue(1).bits(1, 1).bits(1, 1); // 2x2 macroblocks, frames only
+    sps.bits(1, 1).ue(0).ue(2).ue(0).ue(0); // crop 4 luma columns on the right
+    sps.bits(0, 1); // no VUI
+    sps.finish_nal(0x67, &mut stream);
+
+    let mut pps = BitWriter::default();
+    pps.ue(0).ue(0).bits(0, 1).bits(0, 1).ue(0).ue(0).ue(0);
+    pps.bits(0, 1).bits(0, 2).se(0).se(0).se(0);
+    pps.bits(1, 1).bits(0, 1).bits(0, 1); // deblocking control present
+    pps.finish_nal(0x68, &mut stream);
+
+    let mut idr = BitWriter::default();
+    idr.ue(0).ue(7).ue(0).bits(0, 4).ue(0); // first_mb, I, PPS, frame_num, idr_pic_id
+    idr.bits(0, 1).bits(0, 1).se(0).ue(1); // marking, QP delta, deblocking off
+    pcm(&mut idr, 200, 90, 160);
+    // Intra16x16 DC from the left, chroma DC, no residual: the DC
+    // coeff_token for nC = 16 (next to I_PCM) is 0000 11.
+    idr.ue(3).ue(0).se(0).bits(0b11, 6);
+    // Intra16x16 vertical from above, chroma vertical.
+    idr.ue(1).ue(2).se(0).bits(0b11, 6);
+    pcm(&mut idr, 50, 30, 220);
+    idr.finish_nal(0x65, &mut stream);
+
+    let mut p = BitWriter::default();
+    p.ue(0).ue(5).ue(0).bits(1, 4); // first_mb, P, PPS, frame_num
+    p.bits(0, 1).bits(0, 1).bits(0, 1); // no overrides, modifications or MMCOs
+    p.se(0).ue(1);
+    // Skip three macroblocks, then P_L0_16x16 with mvd (-8, 0) in full
+    // samples and no residual.
+    p.ue(3).ue(0).se(-32).se(0).ue(0);
+    p.finish_nal(0x41, &mut stream);
+    stream
+}
+
+fn planes(plane: &FramePlanes) -> (&[u8], &[u8], &[u8]) {
+    match plane {
+        FramePlanes::Yuv420 { y, u, v } => (y, u, v),
+        other => panic!("expected YUV 4:2:0 planes, got {other:?}"),
+    }
+}
 
 #[test]
 fn video_decode_stage_produces_frames_from_annex_b() -> Result<()> {
     let tempdir = tempfile::tempdir()?;
     let mut temp_file = tempfile::NamedTempFile::new()?;
-    temp_file.write_all(ANNEX_B_SAMPLE)?;
+    temp_file.write_all(&annex_b_sample())?;
 
     let mut artifact = Artifact::load(temp_file.path())?;
 
@@ -31,6 +162,10 @@ fn video_decode_stage_produces_frames_from_annex_b() -> Result<()> {
             directory: tempdir.path().to_path_buf(),
             structure: "{stem}.bin".to_string(),
         },
+        quality_gates_enabled: false,
+        cancellation: CancellationToken::new(),
+        outputs: OutputClaims::default(),
+        overwrite: OverwritePolicy::default(),
     };
 
     stage.run(&mut artifact, &ctx, StageDevice::Cpu)?;
@@ -40,12 +175,75 @@ fn video_decode_stage_produces_frames_from_annex_b() -> Result<()> {
         .video
         .as_ref()
         .expect("video stream present");
-    assert!(!video.frames.is_empty());
+    assert_eq!(video.frames.len(), 2);
     assert_eq!(
         artifact.metadata.get("video.codec").unwrap().as_str(),
         Some("H264")
     );
+    assert_eq!(artifact.metadata.get("video.width").unwrap(), 28);
+    assert!(!artifact.metadata.contains_key("hardware.video_decode"));
+
+    let idr = &video.frames[0];
+    assert!(idr.keyframe);
+    assert_eq!((idr.width, idr.height), (28, 32));
+    let (y, u, v) = planes(&idr.data);
+    assert_eq!((y.len(), u.len(), v.len()), (28 * 32, 14 * 16, 14 * 16));
+    // Both predicted macroblocks copy the I_PCM one above or to their left.
+    assert!(y[..16 * 28].iter().all(|&sample| sample == 200));
+    assert_eq!(
+        &y[16 * 28..16 * 28 + 28],
+        &[[200; 16], [50; 16]].concat()[..28]
+    );
+    assert!(u[..8 * 14].iter().all(|&sample| sample == 90));
+    assert_eq!(
+        &v[8 * 14..8 * 14 + 14],
+        &[[160; 8], [220; 8]].concat()[..14]
+    );
+
+    let inter = &video.frames[1];
+    assert!(!inter.keyframe);
+    assert!(inter.timestamp > idr.timestamp);
+    let (y, u, v) = planes(&inter.data);
+    let row = &y[20 * 28..21 * 28];
+    assert!(row[..24].iter().all(|&sample| sample == 200));
+    assert!(row[24..].iter().all(|&sample| sample == 50));
+    assert_eq!(&u[12 * 14 + 8..13 * 14], &[90, 90, 90, 90, 30, 30]);
+    assert_eq!(&v[12 * 14 + 8..13 * 14], &[160, 160, 160, 160, 220, 220]);
+
+    Ok(())
+}
+
+#[test]
+fn video_decode_stage_checks_hardware_backends() -> Result<()> {
+    let mut registry = StageRegistry::new();
+    stages::register_defaults(&mut registry);
+    let hwaccel = |name: &str| {
+        let mut params = StageParameters::new();
+        params.insert("hwaccel".into(), name.into());
+        params
+    };
+
+    let software = registry.create("video_decode", hwaccel("none"))?;
+    assert!(software.supports_device(StageDevice::Cpu));
+    assert!(!software.supports_device(StageDevice::Gpu));
+    assert!(
+        registry
+            .create("video_decode", hwaccel("quicksync"))
+            .is_err()
+    );
 
+    // Without a hardware feature, naming a backend fails and the stage
+    // stays on the CPU.
+    #[cfg(not(any(feature = "nvdec", feature = "vaapi", feature = "videotoolbox")))]
+    {
+        let error = registry
+            .create("video_decode", hwaccel("nvdec"))
+            .err()
+            .unwrap();
+        assert!(format!("{error:#}").contains("nvdec feature"));
+        let default = registry.create("video_decode", StageParameters::new())?;
+        assert!(!default.supports_device(StageDevice::Gpu));
+    }
     Ok(())
 }
 
@@ -53,12 +251,60 @@ fn video_decode_stage_produces_frames_from_annex_b() -> Result<()> {
 fn video_encode_stage_writes_output_file() -> Result<()> {
     let tempdir = tempfile::tempdir()?;
     let mut temp_file = tempfile::NamedTempFile::new()?;
-    temp_file.write_all(ANNEX_B_SAMPLE)?;
+    temp_file.write_all(&annex_b_sample())?;
+
+    let mut artifact = Artifact::load(temp_file.path())?;
+
+    let mut registry = StageRegistry::new();
+    stages::register_defaults(&mut registry);
+    let decode = registry.create("video_decode", StageParameters::new())?;
+    let encode = registry.create("video_encode", StageParameters::new())?;
+
+    let ctx = PipelineContext {
+        output: OutputSpec {
+            directory: tempdir.path().to_path_buf(),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        quality_gates_enabled: false,
+        cancellation: CancellationToken::new(),
+        outputs: OutputClaims::default(),
+        overwrite: OverwritePolicy::default(),
+    };
+
+    decode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    encode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+
+    let output_path = artifact
+        .metadata
+        .get("video.output_path")
+        .and_then(|value| value.as_str())
+        .expect("output path recorded");
+    assert!(output_path.ends_with(".mp4"));
+    let written = std::fs::read(output_path)?;
+    assert_eq!(&written[4..8], b"ftyp");
+    assert_ne!(written, annex_b_sample());
+    assert_eq!(
+        artifact.metadata.get("video.output.size_bytes").unwrap(),
+        written.len()
+    );
+    assert_eq!(
+        artifact.metadata.get("video.output.frame_count").unwrap(),
+        2
+    );
+    Ok(())
+}
+
+#[test]
+fn probe_stage_describes_inputs_without_decoding() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let mut temp_file = tempfile::NamedTempFile::new()?;
+    temp_file.write_all(&annex_b_sample())?;
 
     let mut artifact = Artifact::load(temp_file.path())?;
 
     let mut registry = StageRegistry::new();
     stages::register_defaults(&mut registry);
+    let probe = registry.create("probe", StageParameters::new())?;
     let decode = registry.create("video_decode", StageParameters::new())?;
     let encode = registry.create("video_encode", StageParameters::new())?;
 
@@ -67,16 +313,880 @@ fn video_encode_stage_writes_output_file() -> Result<()> {
             directory: tempdir.path().to_path_buf(),
             structure: "{stem}.{ext}".to_string(),
         },
+        quality_gates_enabled: false,
+        cancellation: CancellationToken::new(),
+        outputs: OutputClaims::default(),
+        overwrite: OverwritePolicy::default(),
+    };
+
+    // A raw stream: an IDR picture and a P picture at the default 30 fps.
+    probe.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    assert!(artifact.media().video.is_none());
+    assert_eq!(artifact.metadata.get("probe.container").unwrap(), "h264");
+    let tracks = artifact.metadata.get("probe.tracks").unwrap().clone();
+    assert_eq!(tracks.as_array().map(Vec::len), Some(1));
+    assert_eq!(tracks[0]["kind"], "video");
+    assert_eq!(tracks[0]["codec"], "h264");
+    assert_eq!(
+        (tracks[0]["width"].clone(), tracks[0]["height"].clone()),
+        (28.into(), 32.into())
+    );
+    assert_eq!(tracks[0]["frame_count"], 2);
+    assert_eq!(tracks[0]["keyframe_count"], 1);
+    assert_eq!(tracks[0]["frame_rate"], 30.0);
+
+    decode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    encode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    let output_path = artifact
+        .metadata
+        .get("video.output_path")
+        .and_then(|value| value.as_str())
+        .expect("output path recorded");
+    let mut mp4 = Artifact::load(Path::new(output_path))?;
+    probe.run(&mut mp4, &ctx, StageDevice::Cpu)?;
+    assert_eq!(mp4.metadata.get("probe.container").unwrap(), "mp4");
+    let size = mp4
+        .metadata
+        .get("probe.size_bytes")
+        .unwrap()
+        .as_u64()
+        .unwrap();
+    assert_eq!(size, mp4.data.len() as u64);
+    let duration = mp4
+        .metadata
+        .get("probe.duration")
+        .unwrap()
+        .as_f64()
+        .unwrap();
+    assert!((duration - 2.0 / 30.0).abs() < 1e-3, "{duration}");
+    let bit_rate = mp4
+        .metadata
+        .get("probe.bit_rate")
+        .unwrap()
+        .as_f64()
+        .unwrap();
+    assert!((bit_rate - size as f64 * 8.0 / duration).abs() / bit_rate < 0.01);
+    let track = &mp4.metadata.get("probe.tracks").unwrap()[0];
+    assert_eq!(track["codec_tag"], "avc1");
+    assert_eq!(track["frame_count"], 2);
+    // Intra-only output: every frame is a keyframe.
+    assert_eq!(track["keyframe_count"], 2);
+    assert!(track["size_bytes"].as_u64().unwrap() < size);
+
+    let mut image = tempfile::NamedTempFile::new()?;
+    image.write_all(b"GIF89a")?;
+    let mut image = Artifact::load(image.path())?;
+    assert!(probe.run(&mut image, &ctx, StageDevice::Cpu).is_err());
+    Ok(())
+}
+
+#[test]
+fn video_encode_stage_re_encodes_annex_b_that_decodes_back() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let mut temp_file = tempfile::NamedTempFile::new()?;
+    temp_file.write_all(&annex_b_sample())?;
+
+    let mut artifact = Artifact::load(temp_file.path())?;
+
+    let mut registry = StageRegistry::new();
+    stages::register_defaults(&mut registry);
+    let decode = registry.create("video_decode", StageParameters::new())?;
+    let mut params = StageParameters::new();
+    params.insert("format".into(), "h264".into());
+    params.insert("qp".into(), 12.into());
+    let encode = registry.create("video_encode", params)?;
+
+    let ctx = PipelineContext {
+        output: OutputSpec {
+            directory: tempdir.path().to_path_buf(),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        quality_gates_enabled: false,
+        cancellation: CancellationToken::new(),
+        outputs: OutputClaims::default(),
+        overwrite: OverwritePolicy::default(),
     };
 
     decode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
     encode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    let source = artifact.media().video.clone().expect("decoded source");
+
+    let output_path = artifact
+        .metadata
+        .get("video.output_path")
+        .and_then(|value| value.as_str())
+        .expect("output path recorded");
+    assert!(output_path.ends_with(".h264"));
+    assert_eq!(artifact.metadata.get("video.output.codec").unwrap(), "h264");
+
+    let mut reencoded = Artifact::load(Path::new(output_path))?;
+    decode.run(&mut reencoded, &ctx, StageDevice::Cpu)?;
+    let video = reencoded
+        .media()
+        .video
+        .as_ref()
+        .expect("video stream present");
+    assert_eq!(video.frames.len(), 2);
+    for (original, decoded) in source.frames.iter().zip(&video.frames) {
+        // Every re-encoded picture is an IDR.
+        assert!(decoded.keyframe);
+        assert_eq!((decoded.width, decoded.height), (28, 32));
+        let (y, u, v) = planes(&original.data);
+        let (dy, du, dv) = planes(&decoded.data);
+        for (plane, decoded) in [(y, dy), (u, du), (v, dv)] {
+            let worst = plane
+                .iter()
+                .zip(decoded)
+                .map(|(&a, &b)| a.abs_diff(b))
+                .max()
+                .unwrap();
+            assert!(worst <= 8, "sample off by {worst}");
+        }
+    }
+
+    let mut params = StageParameters::new();
+    params.insert("format".into(), "avi".into());
+    assert!(registry.create("video_encode", params).is_err());
+    let mut params = StageParameters::new();
+    params.insert("qp".into(), 52.into());
+    assert!(registry.create("video_encode", params).is_err());
+    Ok(())
+}
+
+#[test]
+fn video_resize_stage_scales_frames_before_encode() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let mut temp_file = tempfile::NamedTempFile::new()?;
+    temp_file.write_all(&annex_b_sample())?;
+
+    let mut artifact = Artifact::load(temp_file.path())?;
+
+    let mut registry = StageRegistry::new();
+    stages::register_defaults(&mut registry);
+    let decode = registry.create("video_decode", StageParameters::new())?;
+    let mut params = StageParameters::new();
+    params.insert("width".into(), 16.into());
+    params.insert("height".into(), 16.into());
+    let resize = registry.create("video_resize", params)?;
+    let mut params = StageParameters::new();
+    params.insert("format".into(), "h264".into());
+    let encode = registry.create("video_encode", params)?;
+
+    let ctx = PipelineContext {
+        output: OutputSpec {
+            directory: tempdir.path().to_path_buf(),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        quality_gates_enabled: false,
+        cancellation: CancellationToken::new(),
+        outputs: OutputClaims::default(),
+        overwrite: OverwritePolicy::default(),
+    };
+
+    decode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    resize.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    // 28x32 fits 16x16 at 14x16.
+    assert_eq!(artifact.metadata.get("video.width").unwrap(), 14);
+    assert_eq!(artifact.metadata.get("video.height").unwrap(), 16);
+    assert_eq!(
+        artifact.metadata.get("video_resize.mode").unwrap(),
+        "inside"
+    );
+    let video = artifact.media().video.as_ref().expect("video stream");
+    assert_eq!(video.frames.len(), 2);
+    for frame in &video.frames {
+        assert_eq!((frame.width, frame.height), (14, 16));
+        let (y, u, v) = planes(&frame.data);
+        assert_eq!((y.len(), u.len(), v.len()), (14 * 16, 7 * 8, 7 * 8));
+    }
 
+    encode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
     let output_path = artifact
         .metadata
         .get("video.output_path")
         .and_then(|value| value.as_str())
         .expect("output path recorded");
-    assert!(Path::new(output_path).exists());
+    let mut reencoded = Artifact::load(Path::new(output_path))?;
+    decode.run(&mut reencoded, &ctx, StageDevice::Cpu)?;
+    let video = reencoded.media().video.as_ref().expect("video stream");
+    assert!(
+        video
+            .frames
+            .iter()
+            .all(|frame| (frame.width, frame.height) == (14, 16))
+    );
+    Ok(())
+}
+
+#[test]
+fn video_transform_stage_crops_turns_and_honours_display_rotation() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let mut temp_file = tempfile::NamedTempFile::new()?;
+    temp_file.write_all(&annex_b_sample())?;
+
+    let mut artifact = Artifact::load(temp_file.path())?;
+
+    let mut registry = StageRegistry::new();
+    stages::register_defaults(&mut registry);
+    let decode = registry.create("video_decode", StageParameters::new())?;
+    let mut params = StageParameters::new();
+    params.insert(
+        "crop".into(),
+        serde_json::json!({ "x": 0, "y": 0, "width": 28, "height": 16 }),
+    );
+    params.insert("angle".into(), 90.into());
+    let transform = registry.create("video_transform", params)?;
+    let upright = registry.create("video_transform", StageParameters::new())?;
+    let encode = registry.create("video_encode", StageParameters::new())?;
+
+    let ctx = |structure: &str| PipelineContext {
+        output: OutputSpec {
+            directory: tempdir.path().to_path_buf(),
+            structure: structure.to_string(),
+        },
+        quality_gates_enabled: false,
+        cancellation: CancellationToken::new(),
+        outputs: OutputClaims::default(),
+        overwrite: OverwritePolicy::default(),
+    };
+    let output_path = |artifact: &Artifact| {
+        artifact
+            .metadata
+            .get("video.output_path")
+            .and_then(|value| value.as_str())
+            .expect("output path recorded")
+            .to_string()
+    };
+
+    decode.run(&mut artifact, &ctx("{stem}.{ext}"), StageDevice::Cpu)?;
+    transform.run(&mut artifact, &ctx("{stem}.{ext}"), StageDevice::Cpu)?;
+    // The top 28x16 of the 28x32 frames, turned a quarter clockwise.
+    assert_eq!(artifact.metadata.get("video.width").unwrap(), 16);
+    assert_eq!(artifact.metadata.get("video.height").unwrap(), 28);
+    let video = artifact.media().video.as_ref().expect("video stream");
+    let (y, u, _) = planes(&video.frames[0].data);
+    assert_eq!((y.len(), u.len()), (16 * 28, 8 * 14));
+    assert!(y.iter().all(|&sample| sample == 200));
+
+    // A stream tagged to display a quarter turn clockwise keeps its stored
+    // frames and gets the tag written to the MP4 track header.
+    artifact.media_mut().video.as_mut().unwrap().rotation = 90;
+    encode.run(&mut artifact, &ctx("{stem}-tagged.{ext}"), StageDevice::Cpu)?;
+    let mut tagged = Artifact::load(Path::new(&output_path(&artifact)))?;
+    decode.run(&mut tagged, &ctx("{stem}.{ext}"), StageDevice::Cpu)?;
+    assert_eq!(tagged.metadata.get("video.rotation").unwrap(), 90);
+    assert_eq!(tagged.metadata.get("video.width").unwrap(), 16);
+
+    // Turning it upright bakes the tag into the frames.
+    upright.run(&mut tagged, &ctx("{stem}.{ext}"), StageDevice::Cpu)?;
+    assert_eq!(tagged.metadata.get("video.rotation").unwrap(), 0);
+    let video = tagged.media().video.as_ref().expect("video stream");
+    assert_eq!(video.rotation, 0);
+    assert!(
+        video
+            .frames
+            .iter()
+            .all(|frame| (frame.width, frame.height) == (28, 16))
+    );
+    Ok(())
+}
+
+#[test]
+fn video_color_stage_tone_maps_hdr_to_sdr() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let mut temp_file = tempfile::NamedTempFile::new()?;
+    temp_file.write_all(&annex_b_sample())?;
+
+    let mut artifact = Artifact::load(temp_file.path())?;
+
+    let mut registry = StageRegistry::new();
+    stages::register_defaults(&mut registry);
+    let decode = registry.create("video_decode", StageParameters::new())?;
+    let to_sdr = registry.create("video_color", StageParameters::new())?;
+    let mut params = StageParameters::new();
+    params.insert("tonemap".into(), "filmic".into());
+    params.insert("peak".into(), 4000.into());
+    let filmic = registry.create("video_color", params)?;
+    let mut params = StageParameters::new();
+    params.insert("to".into(), "xyz".into());
+    assert!(registry.create("video_color", params).is_err());
+
+    let ctx = PipelineContext {
+        output: OutputSpec {
+            directory: tempdir.path().to_path_buf(),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        quality_gates_enabled: false,
+        cancellation: CancellationToken::new(),
+        outputs: OutputClaims::default(),
+        overwrite: OverwritePolicy::default(),
+    };
+
+    decode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    let sdr = artifact.media().video.clone().expect("video stream");
+    // The decoded SDR BT.709 stream is already what the stage produces.
+    to_sdr.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    let video = artifact.media().video.as_ref().expect("video stream");
+    assert_eq!(planes(&video.frames[0].data), planes(&sdr.frames[0].data));
+    assert!(artifact.metadata.get("video_color.tonemap").is_none());
+
+    // Tagged as HDR10, the same samples are read as PQ light and rolled off.
+    let video = artifact.media_mut().video.as_mut().expect("video stream");
+    (video.color_space, video.transfer) = (ColorSpace::Bt2020, TransferFunction::Pq);
+    filmic.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    assert_eq!(artifact.metadata.get("video.color_space").unwrap(), "bt709");
+    assert_eq!(artifact.metadata.get("video.transfer").unwrap(), "sdr");
+    assert_eq!(artifact.metadata.get("video_color.from").unwrap(), "bt2020");
+    assert_eq!(artifact.metadata.get("video_color.transfer").unwrap(), "pq");
+    assert_eq!(
+        artifact.metadata.get("video_color.tonemap").unwrap(),
+        "aces"
+    );
+    let video = artifact.media().video.as_ref().expect("video stream");
+    assert_eq!(
+        (video.color_space, video.transfer),
+        (ColorSpace::Bt709, TransferFunction::Sdr)
+    );
+    for (frame, original) in video.frames.iter().zip(&sdr.frames) {
+        let ((y, u, v), (original_y, ..)) = (planes(&frame.data), planes(&original.data));
+        assert_eq!(y.len(), original_y.len());
+        assert!(
+            y.iter()
+                .chain(u)
+                .chain(v)
+                .all(|&sample| (16..=240).contains(&sample))
+        );
+        assert_ne!(y, original_y);
+    }
+    Ok(())
+}
+
+#[test]
+fn video_thumbnail_stage_writes_poster_frames() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let mut temp_file = tempfile::NamedTempFile::new()?;
+    temp_file.write_all(&annex_b_sample())?;
+
+    let mut artifact = Artifact::load(temp_file.path())?;
+
+    let mut registry = StageRegistry::new();
+    stages::register_defaults(&mut registry);
+    let decode = registry.create("video_decode", StageParameters::new())?;
+    let mut params = StageParameters::new();
+    params.insert("at".into(), serde_json::json!([0, "100%"]));
+    params.insert("format".into(), "png".into());
+    params.insert("structure".into(), "{stem}-{index}-{time}.{ext}".into());
+    let thumbnail = registry.create("video_thumbnail", params)?;
+
+    let ctx = PipelineContext {
+        output: OutputSpec {
+            directory: tempdir.path().to_path_buf(),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        quality_gates_enabled: false,
+        cancellation: CancellationToken::new(),
+        outputs: OutputClaims::default(),
+        overwrite: OverwritePolicy::default(),
+    };
+
+    decode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    thumbnail.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+
+    let video = artifact.media().video.as_ref().expect("video stream");
+    assert_eq!(video.frames.len(), 2);
+    let entries = artifact
+        .metadata
+        .get(stages::VIDEO_THUMBNAILS_KEY)
+        .and_then(|value| value.as_array())
+        .expect("poster frames recorded");
+    assert_eq!(entries.len(), 2);
+    for (entry, frame) in entries.iter().zip(&video.frames) {
+        let path = entry["path"].as_str().expect("poster path");
+        let expected = format!("-{}.png", frame.timestamp.as_millis());
+        assert!(path.ends_with(&expected), "{path}");
+        let poster = image::open(path)?.to_rgb8();
+        assert_eq!(poster.dimensions(), (28, 32));
+        // PNG is lossless, so the poster is exactly the converted frame.
+        assert_eq!(poster, frame.to_rgb(video.color_space)?);
+    }
+    assert_eq!(entries[1]["frame"], 1);
+    Ok(())
+}
+
+#[test]
+fn storyboard_stage_lays_sampled_frames_out_for_encode() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let mut temp_file = tempfile::NamedTempFile::new()?;
+    temp_file.write_all(&annex_b_sample())?;
+
+    let mut artifact = Artifact::load(temp_file.path())?;
+
+    let mut registry = StageRegistry::new();
+    stages::register_defaults(&mut registry);
+    let decode = registry.create("video_decode", StageParameters::new())?;
+    let mut params = StageParameters::new();
+    params.insert("count".into(), 6.into());
+    params.insert("width".into(), 14.into());
+    params.insert("gutter".into(), 2.into());
+    params.insert("timestamps".into(), false.into());
+    let storyboard = registry.create("storyboard", params)?;
+    let mut params = StageParameters::new();
+    params.insert("format".into(), "png".into());
+    let encode = registry.create("encode", params)?;
+
+    let ctx = PipelineContext {
+        output: OutputSpec {
+            directory: tempdir.path().to_path_buf(),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        quality_gates_enabled: false,
+        cancellation: CancellationToken::new(),
+        outputs: OutputClaims::default(),
+        overwrite: OverwritePolicy::default(),
+    };
+
+    decode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    storyboard.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    // Two frames give two 14x16 tiles side by side.
+    assert_eq!(artifact.metadata.get("storyboard.columns").unwrap(), 2);
+    assert_eq!(artifact.metadata.get("storyboard.rows").unwrap(), 1);
+    assert_eq!(
+        artifact.metadata.get("image.width").unwrap(),
+        2 * 14 + 3 * 2
+    );
+    assert_eq!(artifact.metadata.get("image.height").unwrap(), 16 + 2 * 2);
+    assert!(artifact.media().video.is_some());
+
+    encode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    let sheet = image::open(tempdir.path().join(format!("{}.png", artifact.stem)))?;
+    assert_eq!((sheet.width(), sheet.height()), (34, 20));
+    assert_eq!(sheet.to_rgba8().get_pixel(0, 0).0, [0, 0, 0, 255]);
+    Ok(())
+}
+
+#[test]
+fn gif_from_video_stage_writes_an_animation() -> Result<()> {
+    use image::AnimationDecoder;
+    use image::codecs::gif::GifDecoder;
+
+    let tempdir = tempfile::tempdir()?;
+    let mut temp_file = tempfile::NamedTempFile::new()?;
+    temp_file.write_all(&annex_b_sample())?;
+
+    let mut artifact = Artifact::load(temp_file.path())?;
+
+    let mut registry = StageRegistry::new();
+    stages::register_defaults(&mut registry);
+    let decode = registry.create("video_decode", StageParameters::new())?;
+    let mut params = StageParameters::new();
+    params.insert("fps".into(), 50.into());
+    params.insert("width".into(), 14.into());
+    params.insert("colors".into(), 16.into());
+    let gif = registry.create("gif_from_video", params)?;
+
+    let ctx = PipelineContext {
+        output: OutputSpec {
+            directory: tempdir.path().to_path_buf(),
+            structure: "{stem}-{width}.{ext}".to_string(),
+        },
+        quality_gates_enabled: false,
+        cancellation: CancellationToken::new(),
+        outputs: OutputClaims::default(),
+        overwrite: OverwritePolicy::default(),
+    };
+
+    decode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    gif.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+
+    let output_path = artifact
+        .metadata
+        .get("output_path")
+        .and_then(|value| value.as_str())
+        .expect("output path recorded");
+    assert!(output_path.ends_with("-14.gif"), "{output_path}");
+    assert_eq!(artifact.metadata.get("output.frame_count").unwrap(), 2);
+    let decoder = GifDecoder::new(std::io::BufReader::new(std::fs::File::open(output_path)?))?;
+    let frames = decoder.into_frames().collect_frames()?;
+    assert_eq!(frames.len(), 2);
+    for frame in &frames {
+        assert_eq!(frame.buffer().dimensions(), (14, 16));
+    }
+    Ok(())
+}
+
+#[test]
+fn video_decode_stage_reads_frames_from_mp4_samples() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let mut temp_file = tempfile::NamedTempFile::new()?;
+    temp_file.write_all(&annex_b_sample())?;
+
+    let mut artifact = Artifact::load(temp_file.path())?;
+
+    let mut registry = StageRegistry::new();
+    stages::register_defaults(&mut registry);
+    let decode = registry.create("video_decode", StageParameters::new())?;
+    let encode = registry.create("video_encode", StageParameters::new())?;
+
+    let ctx = PipelineContext {
+        output: OutputSpec {
+            directory: tempdir.path().to_path_buf(),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        quality_gates_enabled: false,
+        cancellation: CancellationToken::new(),
+        outputs: OutputClaims::default(),
+        overwrite: OverwritePolicy::default(),
+    };
+
+    decode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    encode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    let source = artifact.media().video.clone().expect("decoded source");
+    let output_path = artifact
+        .metadata
+        .get("video.output_path")
+        .and_then(|value| value.as_str())
+        .expect("output path recorded");
+
+    let mut mp4 = Artifact::load(Path::new(output_path))?;
+    decode.run(&mut mp4, &ctx, StageDevice::Cpu)?;
+    let video = mp4.media().video.as_ref().expect("video stream present");
+    assert!(matches!(
+        video.frame_rate,
+        FrameRate::Constant {
+            numerator: 30,
+            denominator: 1
+        }
+    ));
+    assert_eq!(video.frames.len(), 2);
+    assert_eq!(mp4.metadata.get("video.width").unwrap(), 28);
+    for (original, decoded) in source.frames.iter().zip(&video.frames) {
+        assert_eq!(decoded.timestamp, original.timestamp);
+        assert_eq!(decoded.duration, original.duration);
+        assert!(decoded.keyframe);
+        assert_eq!(planes(&decoded.data).0.len(), 28 * 32);
+    }
+
+    // Streamed from the file a sample at a time, the track decodes alike.
+    let mut demuxer = Mp4Demuxer::from_reader(std::fs::File::open(output_path)?)?;
+    let track = demuxer.video_track()?.expect("video track present");
+    assert_eq!(track.samples.len(), 2);
+    let mut streamed = MediaStreams::default();
+    h264::decode_track(&track, &mut demuxer, &mut streamed)?;
+    let streamed = streamed.video.expect("streamed video decoded");
+    assert_eq!(streamed.frames.len(), 2);
+    for (frame, decoded) in streamed.frames.iter().zip(&video.frames) {
+        assert_eq!(frame.timestamp, decoded.timestamp);
+        assert_eq!(planes(&frame.data), planes(&decoded.data));
+    }
+    Ok(())
+}
+
+#[test]
+fn video_encode_stage_writes_matroska() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let mut temp_file = tempfile::NamedTempFile::new()?;
+    temp_file.write_all(&annex_b_sample())?;
+
+    let mut artifact = Artifact::load(temp_file.path())?;
+
+    let mut registry = StageRegistry::new();
+    stages::register_defaults(&mut registry);
+    let decode = registry.create("video_decode", StageParameters::new())?;
+    let mut params = StageParameters::new();
+    params.insert("format".into(), "mkv".into());
+    let encode = registry.create("video_encode", params)?;
+
+    let ctx = PipelineContext {
+        output: OutputSpec {
+            directory: tempdir.path().to_path_buf(),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        quality_gates_enabled: false,
+        cancellation: CancellationToken::new(),
+        outputs: OutputClaims::default(),
+        overwrite: OverwritePolicy::default(),
+    };
+
+    decode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    encode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    let output_path = artifact
+        .metadata
+        .get("video.output_path")
+        .and_then(|value| value.as_str())
+        .expect("output path recorded");
+    assert!(output_path.ends_with(".mkv"));
+    let written = std::fs::read(output_path)?;
+    assert_eq!(&written[..4], &[0x1A, 0x45, 0xDF, 0xA3]);
+    assert!(
+        written
+            .windows(b"V_MPEG4/ISO/AVC".len())
+            .any(|window| window == b"V_MPEG4/ISO/AVC")
+    );
+    assert_eq!(artifact.metadata.get("video.output.format").unwrap(), "mkv");
+
+    // The Matroska file decodes back through its blocks.
+    let source = artifact.media().video.clone().expect("decoded source");
+    let mut mkv = Artifact::load(Path::new(output_path))?;
+    decode.run(&mut mkv, &ctx, StageDevice::Cpu)?;
+    let video = mkv.media().video.as_ref().expect("video stream present");
+    assert_eq!(video.frames.len(), source.frames.len());
+    // Matroska timestamps are whole milliseconds.
+    for (original, decoded) in source.frames.iter().zip(&video.frames) {
+        assert_eq!(
+            decoded.timestamp.as_millis(),
+            original.timestamp.as_millis()
+        );
+        assert_eq!(planes(&decoded.data).0.len(), 28 * 32);
+    }
+
+    // WebM cannot carry H.264.
+    let mut params = StageParameters::new();
+    params.insert("format".into(), "webm".into());
+    params.insert("codec".into(), "h264".into());
+    assert!(registry.create("video_encode", params).is_err());
+    Ok(())
+}
+
+#[test]
+fn video_encode_stage_writes_av1_webm() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let mut temp_file = tempfile::NamedTempFile::new()?;
+    temp_file.write_all(&annex_b_sample())?;
+
+    let mut artifact = Artifact::load(temp_file.path())?;
+
+    let mut registry = StageRegistry::new();
+    stages::register_defaults(&mut registry);
+    let decode = registry.create("video_decode", StageParameters::new())?;
+    let mut params = StageParameters::new();
+    params.insert("format".into(), "webm".into());
+    params.insert("speed".into(), 10.into());
+    params.insert("quantizer".into(), 80.into());
+    params.insert("tile_cols".into(), 2.into());
+    let encode = registry.create("video_encode", params)?;
+
+    let ctx = PipelineContext {
+        output: OutputSpec {
+            directory: tempdir.path().to_path_buf(),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        quality_gates_enabled: false,
+        cancellation: CancellationToken::new(),
+        outputs: OutputClaims::default(),
+        overwrite: OverwritePolicy::default(),
+    };
+
+    decode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    let result = encode.run(&mut artifact, &ctx, StageDevice::Cpu);
+    if !cfg!(feature = "rav1e") {
+        assert!(format!("{:#}", result.unwrap_err()).contains("rav1e feature"));
+        return Ok(());
+    }
+    result?;
+    let output_path = artifact
+        .metadata
+        .get("video.output_path")
+        .and_then(|value| value.as_str())
+        .expect("output path recorded");
+    assert!(output_path.ends_with(".webm"));
+    assert_eq!(artifact.metadata.get("video.output.codec").unwrap(), "av1");
+    let written = std::fs::read(output_path)?;
+    let track = bunker_convert::video::matroska::video_samples(&written)?.expect("video track");
+    assert!(matches!(track.codec, VideoCodec::Av1));
+    assert_eq!(track.samples.len(), 2);
+    assert!(track.samples[0].keyframe);
+    Ok(())
+}
+
+#[test]
+fn video_encode_stage_validates_codec_settings() {
+    let mut registry = StageRegistry::new();
+    stages::register_defaults(&mut registry);
+    let create = |pairs: &[(&str, serde_json::Value)]| {
+        let mut params = StageParameters::new();
+        for (key, value) in pairs {
+            params.insert((*key).into(), value.clone());
+        }
+        registry.create("video_encode", params)
+    };
+
+    assert!(create(&[("codec", "av1".into()), ("speed", 4.into())]).is_ok());
+    assert!(create(&[("codec", "av1".into()), ("speed", 11.into())]).is_err());
+    assert!(create(&[("codec", "av1".into()), ("quantizer", 256.into())]).is_err());
+    assert!(create(&[("codec", "av1".into()), ("tile_rows", 3.into())]).is_err());
+    assert!(create(&[("codec", "av1".into()), ("qp", 20.into())]).is_err());
+    assert!(create(&[("codec", "av1".into()), ("format", "h264".into())]).is_err());
+    assert!(create(&[("quantizer", 80.into())]).is_err());
+    assert!(create(&[("codec", "vp8".into())]).is_err());
+
+    assert!(create(&[("keyframe_interval", 60.into()), ("bframes", 0.into())]).is_ok());
+    assert!(create(&[("keyframe_interval", 0.into())]).is_err());
+    assert!(create(&[("bframes", 2.into())]).is_err());
+    assert!(create(&[("closed_gop", true.into())]).is_ok());
+    assert!(create(&[("closed_gop", false.into())]).is_err());
+    assert!(create(&[("codec", "av1".into()), ("bframes", 0.into())]).is_ok());
+    assert!(create(&[("codec", "av1".into()), ("bframes", 3.into())]).is_ok());
+    assert!(create(&[("codec", "av1".into()), ("bframes", 2.into())]).is_err());
+}
+
+#[test]
+fn video_encode_stage_spaces_keyframes() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let mut temp_file = tempfile::NamedTempFile::new()?;
+    temp_file.write_all(&annex_b_sample())?;
+
+    let mut artifact = Artifact::load(temp_file.path())?;
+
+    let mut registry = StageRegistry::new();
+    stages::register_defaults(&mut registry);
+    let decode = registry.create("video_decode", StageParameters::new())?;
+    let mut params = StageParameters::new();
+    params.insert("keyframe_interval".into(), 2.into());
+    params.insert("closed_gop".into(), true.into());
+    let encode = registry.create("video_encode", params)?;
+
+    let ctx = PipelineContext {
+        output: OutputSpec {
+            directory: tempdir.path().to_path_buf(),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        quality_gates_enabled: false,
+        cancellation: CancellationToken::new(),
+        outputs: OutputClaims::default(),
+        overwrite: OverwritePolicy::default(),
+    };
+
+    decode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    encode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    let metadata = &artifact.metadata;
+    assert_eq!(metadata.get("video.output.keyframe_interval").unwrap(), 2);
+    assert_eq!(metadata.get("video.output.bframes").unwrap(), 0);
+    assert_eq!(metadata.get("video.output.closed_gop").unwrap(), true);
+    assert_eq!(metadata.get("video.output.keyframe_count").unwrap(), 1);
+
+    let output_path = metadata
+        .get("video.output_path")
+        .and_then(|value| value.as_str())
+        .expect("output path recorded");
+    let mut reencoded = Artifact::load(Path::new(output_path))?;
+    decode.run(&mut reencoded, &ctx, StageDevice::Cpu)?;
+    let video = reencoded.media().video.as_ref().expect("video stream");
+    let keyframes: Vec<bool> = video.frames.iter().map(|frame| frame.keyframe).collect();
+    assert_eq!(keyframes, [true, false]);
+    Ok(())
+}
+
+#[test]
+fn video_encode_stage_writes_fragmented_mp4() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let mut temp_file = tempfile::NamedTempFile::new()?;
+    temp_file.write_all(&annex_b_sample())?;
+
+    let mut artifact = Artifact::load(temp_file.path())?;
+
+    let mut registry = StageRegistry::new();
+    stages::register_defaults(&mut registry);
+    let decode = registry.create("video_decode", StageParameters::new())?;
+    let mut params = StageParameters::new();
+    params.insert("fragmented".into(), true.into());
+    params.insert("fragment_duration".into(), 0.01.into());
+    let encode = registry.create("video_encode", params)?;
+
+    let ctx = PipelineContext {
+        output: OutputSpec {
+            directory: tempdir.path().to_path_buf(),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        quality_gates_enabled: false,
+        cancellation: CancellationToken::new(),
+        outputs: OutputClaims::default(),
+        overwrite: OverwritePolicy::default(),
+    };
+
+    decode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    encode.run(&mut artifact, &ctx, StageDevice::Cpu)?;
+    let output_path = artifact
+        .metadata
+        .get("video.output_path")
+        .and_then(|value| value.as_str())
+        .expect("output path recorded");
+    let written = std::fs::read(output_path)?;
+    // Both re-encoded frames are keyframes, so each opens its own fragment.
+    let count = |kind: &[u8]| written.windows(4).filter(|window| *window == kind).count();
+    assert_eq!(count(b"moof"), 2);
+    assert_eq!(count(b"mvex"), 1);
+    assert_eq!(
+        artifact
+            .metadata
+            .get("video.output.fragment_duration")
+            .unwrap(),
+        0.01
+    );
+
+    let mut params = StageParameters::new();
+    params.insert("fragmented".into(), true.into());
+    params.insert("format".into(), "mkv".into());
+    assert!(registry.create("video_encode", params).is_err());
+    let mut params = StageParameters::new();
+    params.insert("fragment_duration".into(), 4.into());
+    assert!(registry.create("video_encode", params).is_err());
+    Ok(())
+}
+
+/// An EBML element with a one-byte size.
+fn ebml(id: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut out = id.to_vec();
+    out.push(0x80 | payload.len() as u8);
+    out.extend_from_slice(payload);
+    out
+}
+
+/// A WebM file with one video track of `codec_id` and one keyframe block.
+fn webm_with_codec(codec_id: &[u8]) -> Vec<u8> {
+    let mut file = ebml(&[0x1A, 0x45, 0xDF, 0xA3], &ebml(&[0x42, 0x82], b"webm"));
+    let mut entry = ebml(&[0xD7], &[1]);
+    entry.extend(ebml(&[0x83], &[1]));
+    entry.extend(ebml(&[0x86], codec_id));
+    let mut segment = ebml(&[0x16, 0x54, 0xAE, 0x6B], &ebml(&[0xAE], &entry));
+    let mut cluster = ebml(&[0xE7], &[0]);
+    cluster.extend(ebml(&[0xA3], &[0x81, 0, 0, 0x80, 0x82, 0x49, 0x83]));
+    segment.extend(ebml(&[0x1F, 0x43, 0xB6, 0x75], &cluster));
+    file.extend(ebml(&[0x18, 0x53, 0x80, 0x67], &segment));
+    file
+}
+
+#[test]
+fn video_decode_stage_names_the_feature_a_webm_codec_needs() -> Result<()> {
+    let mut registry = StageRegistry::new();
+    stages::register_defaults(&mut registry);
+    let decode = registry.create("video_decode", StageParameters::new())?;
+    let ctx = PipelineContext {
+        output: OutputSpec {
+            directory: std::env::temp_dir(),
+            structure: "{stem}.{ext}".to_string(),
+        },
+        quality_gates_enabled: false,
+        cancellation: CancellationToken::new(),
+        outputs: OutputClaims::default(),
+        overwrite: OverwritePolicy::default(),
+    };
+
+    let codecs: [(&[u8], &str, bool); 3] = [
+        (b"V_VP9", "vp9 feature", cfg!(feature = "vp9")),
+        (b"V_AV1", "av1 feature", cfg!(feature = "av1")),
+        (b"V_MPEGH/ISO/HEVC", "hevc feature", cfg!(feature = "hevc")),
+    ];
+    for (codec_id, message, built) in codecs {
+        if built {
+            continue;
+        }
+        let mut temp_file = tempfile::NamedTempFile::new()?;
+        temp_file.write_all(&webm_with_codec(codec_id))?;
+        let mut artifact = Artifact::load(temp_file.path())?;
+        let error = decode
+            .run(&mut artifact, &ctx, StageDevice::Cpu)
+            .unwrap_err();
+        assert!(format!("{error:#}").contains(message));
+    }
     Ok(())
 }